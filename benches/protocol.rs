@@ -0,0 +1,45 @@
+// SOCKS5协议基准：握手协商与请求解析
+use anybls::protocol::handle_socks5_handshake;
+use anybls::Socks5Request;
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+const DOMAIN_REQUEST: &[u8] = b"\x05\x01\x00\x03\x0bexample.com\x01\xbb";
+const IPV4_REQUEST: &[u8] = b"\x05\x01\x00\x01\x7f\x00\x00\x01\x1f\x90";
+
+fn bench_request_parsing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("socks5_request");
+    group.bench_function("parse_domain", |b| {
+        b.iter(|| {
+            let mut buf = Bytes::from_static(DOMAIN_REQUEST);
+            black_box(Socks5Request::from_bytes(&mut buf).unwrap())
+        })
+    });
+    group.bench_function("parse_ipv4", |b| {
+        b.iter(|| {
+            let mut buf = Bytes::from_static(IPV4_REQUEST);
+            black_box(Socks5Request::from_bytes(&mut buf).unwrap())
+        })
+    });
+    group.finish();
+}
+
+fn bench_handshake(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    c.bench_function("socks5_handshake_and_request", |b| {
+        b.to_async(&rt).iter(|| async {
+            let (mut client, mut server) = duplex(512);
+            client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+            handle_socks5_handshake(&mut server).await.unwrap();
+            let mut reply = [0u8; 2];
+            client.read_exact(&mut reply).await.unwrap();
+
+            client.write_all(DOMAIN_REQUEST).await.unwrap();
+            black_box(Socks5Request::read_from(&mut server).await.unwrap())
+        })
+    });
+}
+
+criterion_group!(benches, bench_request_parsing, bench_handshake);
+criterion_main!(benches);
@@ -0,0 +1,92 @@
+// 中继吞吐基准：内存双工流上不同写入块大小的单向转发，以及短连接频繁建立时缓冲区池的效果
+use anybls::buffer_pool::BufferPool;
+use anybls::zero_copy::{relay_one_way, relay_one_way_with_pool, RelayOptions};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+/// 每次迭代转发的总字节数
+const TRANSFER_BYTES: usize = 4 * 1024 * 1024;
+
+async fn relay_transfer(chunk_size: usize, options: RelayOptions) {
+    let (mut writer, source) = duplex(256 * 1024);
+    let (dest, mut sink) = duplex(256 * 1024);
+
+    let feed = async move {
+        let chunk = vec![0xA5u8; chunk_size];
+        for _ in 0..TRANSFER_BYTES / chunk_size {
+            writer.write_all(&chunk).await.unwrap();
+        }
+    };
+    let drain = async move {
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut received = 0;
+        while received < TRANSFER_BYTES {
+            received += sink.read(&mut buf).await.unwrap();
+        }
+    };
+    let relay = async move { relay_one_way(source, dest, options).await.unwrap() };
+    tokio::join!(feed, drain, relay);
+}
+
+fn bench_relay(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_multi_thread().build().unwrap();
+    let mut group = c.benchmark_group("relay_throughput");
+    group.throughput(Throughput::Bytes(TRANSFER_BYTES as u64));
+    group.sample_size(20);
+
+    for chunk_size in [512, 4 * 1024, 16 * 1024, 64 * 1024] {
+        for adaptive_buffers in [true, false] {
+            let options = RelayOptions {
+                adaptive_buffers,
+                ..RelayOptions::default()
+            };
+            let label = if adaptive_buffers { "adaptive" } else { "fixed" };
+            group.bench_with_input(BenchmarkId::new(label, chunk_size), &chunk_size, |b, &chunk_size| {
+                b.to_async(&rt).iter(|| relay_transfer(chunk_size, options))
+            });
+        }
+    }
+    group.finish();
+}
+
+/// 每次迭代建立的短连接数
+const CHURN_CONNECTIONS: usize = 256;
+
+/// 一条短连接：发一个小请求，转发完即关闭
+async fn short_connection(pool: &BufferPool, options: RelayOptions) {
+    let (mut writer, source) = duplex(4096);
+    let (dest, mut sink) = duplex(4096);
+    writer.write_all(&[0x5Au8; 1024]).await.unwrap();
+    drop(writer);
+    relay_one_way_with_pool(source, dest, options, pool).await.unwrap();
+    let mut received = Vec::new();
+    sink.read_to_end(&mut received).await.unwrap();
+}
+
+fn bench_connection_churn(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_multi_thread().build().unwrap();
+    let mut group = c.benchmark_group("connection_churn");
+    group.throughput(Throughput::Elements(CHURN_CONNECTIONS as u64));
+
+    for adaptive_buffers in [true, false] {
+        let options = RelayOptions {
+            adaptive_buffers,
+            ..RelayOptions::default()
+        };
+        let pooled = BufferPool::new(options.buffer_size, 1024);
+        let unpooled = BufferPool::disabled();
+        let sizing = if adaptive_buffers { "adaptive" } else { "fixed" };
+        for (label, pool) in [("pooled", &pooled), ("unpooled", &unpooled)] {
+            group.bench_function(BenchmarkId::new(label, sizing), |b| {
+                b.to_async(&rt).iter(|| async {
+                    let connections = (0..CHURN_CONNECTIONS).map(|_| short_connection(pool, options));
+                    futures::future::join_all(connections).await;
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_relay, bench_connection_churn);
+criterion_main!(benches);
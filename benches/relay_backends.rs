@@ -0,0 +1,93 @@
+// 中继后端对比：本机回环上的大流量单向传输，以及大量短连接频繁建立；
+// 每个后端各跑一组：splice 仅 Linux，io_uring 需要 `--features io-uring` 且内核支持
+use anybls::zero_copy::{available_relay_backends, RelayOptions, ZeroCopyRelay};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// 每次迭代大流量传输的总字节数
+const TRANSFER_BYTES: usize = 16 * 1024 * 1024;
+/// 每次迭代建立的短连接数
+const CHURN_CONNECTIONS: usize = 128;
+/// 并发建连的任务数；每个任务独占一个监听端口，接受到的连接不会错配
+const CHURN_TASKS: usize = 16;
+
+/// 两对回环连接：(客户端, 中继的客户端侧)，(目标, 中继的目标侧)
+async fn relayed_pair(listener: &TcpListener, options: RelayOptions) -> (TcpStream, TcpStream) {
+    let addr = listener.local_addr().unwrap();
+    let (client, client_side) = tokio::join!(TcpStream::connect(addr), listener.accept());
+    let (target, target_side) = tokio::join!(TcpStream::connect(addr), listener.accept());
+    let relay = ZeroCopyRelay::with_options(client_side.unwrap().0, target_side.unwrap().0, options);
+    tokio::spawn(relay.start());
+    (client.unwrap(), target.unwrap())
+}
+
+async fn bulk_transfer(listener: &TcpListener, options: RelayOptions) {
+    let (mut client, mut target) = relayed_pair(listener, options).await;
+    let feed = async move {
+        let chunk = vec![0xA5u8; 64 * 1024];
+        for _ in 0..TRANSFER_BYTES / chunk.len() {
+            client.write_all(&chunk).await.unwrap();
+        }
+        client.shutdown().await.unwrap();
+        client
+    };
+    let drain = async move {
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut received = 0;
+        while received < TRANSFER_BYTES {
+            received += target.read(&mut buf).await.unwrap();
+        }
+        target
+    };
+    let _ = tokio::join!(feed, drain);
+}
+
+/// 一条短连接：发 1KB 请求，收 1KB 响应，然后关闭
+async fn short_connection(listener: &TcpListener, options: RelayOptions) {
+    let (mut client, mut target) = relayed_pair(listener, options).await;
+    let mut buf = [0u8; 1024];
+    client.write_all(&[0x5Au8; 1024]).await.unwrap();
+    target.read_exact(&mut buf).await.unwrap();
+    target.write_all(&buf).await.unwrap();
+    client.read_exact(&mut buf).await.unwrap();
+}
+
+fn bench_backends(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+    let listeners: Vec<_> = (0..CHURN_TASKS)
+        .map(|_| rt.block_on(TcpListener::bind("127.0.0.1:0")).unwrap())
+        .collect();
+    let backends = available_relay_backends();
+
+    let mut group = c.benchmark_group("relay_backend_bulk");
+    group.throughput(Throughput::Bytes(TRANSFER_BYTES as u64));
+    group.sample_size(20);
+    for &backend in &backends {
+        let options = RelayOptions { backend, ..RelayOptions::default() };
+        group.bench_function(BenchmarkId::from_parameter(backend), |b| {
+            b.to_async(&rt).iter(|| bulk_transfer(&listeners[0], options))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("relay_backend_churn");
+    group.throughput(Throughput::Elements(CHURN_CONNECTIONS as u64));
+    for &backend in &backends {
+        let options = RelayOptions { backend, ..RelayOptions::default() };
+        group.bench_function(BenchmarkId::from_parameter(backend), |b| {
+            b.to_async(&rt).iter(|| {
+                let tasks = listeners.iter().map(|listener| async move {
+                    for _ in 0..CHURN_CONNECTIONS / CHURN_TASKS {
+                        short_connection(listener, options).await;
+                    }
+                });
+                futures::future::join_all(tasks)
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_backends);
+criterion_main!(benches);
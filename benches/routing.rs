@@ -0,0 +1,150 @@
+// 路由与匹配器基准：大规模（geosite量级）规则集下的查询与构建开销
+use anybls::config::RouteAction;
+use anybls::routing::matchers::DomainMatcher;
+use anybls::{DomainRuleSet, HighPerformanceRouter, IpRuleSet, RouteRule, RuleSetManager};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use std::net::{IpAddr, Ipv4Addr};
+
+const SUFFIX_COUNT: usize = 50_000;
+const EXACT_COUNT: usize = 5_000;
+const KEYWORD_COUNT: usize = 100;
+const CIDR_COUNT: usize = 5_000;
+/// 查询样本数量，超过匹配缓存容量以保证"冷"查询每次都未命中缓存
+const QUERY_COUNT: usize = 20_000;
+
+fn suffixes() -> Vec<String> {
+    (0..SUFFIX_COUNT).map(|i| format!("site{}.example{}.com", i, i % 97)).collect()
+}
+
+fn exact() -> Vec<String> {
+    (0..EXACT_COUNT).map(|i| format!("host{}.exact.net", i)).collect()
+}
+
+fn keywords() -> Vec<String> {
+    (0..KEYWORD_COUNT).map(|i| format!("kw{}tracker", i)).collect()
+}
+
+fn cidrs() -> Vec<String> {
+    (0..CIDR_COUNT)
+        .map(|i| format!("{}.{}.{}.0/24", 1 + i / 65536, (i / 256) % 256, i % 256))
+        .collect()
+}
+
+fn fixture_router() -> HighPerformanceRouter {
+    let mut manager = RuleSetManager::new();
+    manager.add_domain_set(DomainRuleSet {
+        id: "geosite".to_string(),
+        domain: exact(),
+        domain_suffix: suffixes(),
+        domain_keyword: keywords(),
+        domain_regex: vec![r"^ads\d+\.".to_string()],
+    });
+    manager.add_ip_set(IpRuleSet {
+        id: "geoip".to_string(),
+        ip_cidr: cidrs(),
+    });
+
+    let mut router = HighPerformanceRouter::new("direct".to_string());
+    router.set_rule_manager(manager);
+    router.add_rule(RouteRule {
+        rule_sets: vec!["geosite".to_string()],
+        outbound: "proxy".to_string(),
+        outbound_chain: Vec::new(),
+        dscp: None,
+        latency_mode: false,
+        rewrite_to: None,
+        port: Vec::new(),
+        port_range: Vec::new(),
+        network: None,
+        source_ip_cidr: Vec::new(),
+        inbound: Vec::new(),
+        ip_geoip: Vec::new(),
+        action: RouteAction::Forward,
+    });
+    router.add_rule(RouteRule {
+        rule_sets: vec!["geoip".to_string()],
+        outbound: "proxy".to_string(),
+        outbound_chain: Vec::new(),
+        dscp: None,
+        latency_mode: false,
+        rewrite_to: None,
+        port: Vec::new(),
+        port_range: Vec::new(),
+        network: None,
+        source_ip_cidr: Vec::new(),
+        inbound: Vec::new(),
+        ip_geoip: Vec::new(),
+        action: RouteAction::Forward,
+    });
+    // 预热：构建匹配器
+    router.select_outbound_for_domain("warmup.invalid");
+    router.select_outbound_for_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    router
+}
+
+fn domain_queries() -> Vec<String> {
+    (0..QUERY_COUNT)
+        .map(|i| match i % 4 {
+            0 => format!("host{}.exact.net", i % EXACT_COUNT),
+            1 => format!("site{}.example{}.com", i, i % 97),
+            2 => format!("cdn.kw{}tracker.org", i % KEYWORD_COUNT),
+            _ => format!("miss{}.unknown.org", i),
+        })
+        .collect()
+}
+
+fn ip_queries() -> Vec<IpAddr> {
+    (0..QUERY_COUNT as u32)
+        .map(|i| IpAddr::V4(Ipv4Addr::from(0x0100_0000u32.wrapping_add(i.wrapping_mul(2_654_435_761)))))
+        .collect()
+}
+
+fn bench_router(c: &mut Criterion) {
+    let router = fixture_router();
+    let domains = domain_queries();
+    let ips = ip_queries();
+
+    let mut group = c.benchmark_group("router");
+    let mut next = 0usize;
+    group.bench_function("domain_cold", |b| {
+        b.iter(|| {
+            next = (next + 1) % domains.len();
+            black_box(router.select_outbound_for_domain(&domains[next]))
+        })
+    });
+    group.bench_function("domain_cached", |b| {
+        router.select_outbound_for_domain("site42.example42.com");
+        b.iter(|| black_box(router.select_outbound_for_domain("site42.example42.com")))
+    });
+    group.bench_function("ip_cold", |b| {
+        b.iter(|| {
+            next = (next + 1) % ips.len();
+            black_box(router.select_outbound_for_ip(ips[next]))
+        })
+    });
+    group.bench_function("ip_cached", |b| {
+        let ip = ips[0];
+        router.select_outbound_for_ip(ip);
+        b.iter(|| black_box(router.select_outbound_for_ip(ip)))
+    });
+    group.finish();
+}
+
+fn bench_matcher_build(c: &mut Criterion) {
+    let (exact, suffixes, keywords) = (exact(), suffixes(), keywords());
+    let mut group = c.benchmark_group("domain_matcher");
+    group.sample_size(10);
+    group.bench_function("build_geosite", |b| {
+        b.iter_batched(
+            || (exact.clone(), suffixes.clone(), keywords.clone()),
+            |(exact, suffixes, keywords)| {
+                DomainMatcher::new(exact, suffixes, keywords, vec![r"^ads\d+\.".to_string()]).unwrap()
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_router, bench_matcher_build);
+criterion_main!(benches);
@@ -1,3 +1,4 @@
+use anybls::RuleSetManager;
 use reqwest::header::{ACCEPT, ACCEPT_ENCODING, CONTENT_ENCODING, ETAG};
 use reqwest::Client;
 use tokio;
@@ -44,6 +45,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // 获取文本数据
     println!("\nDownloaded {} bytes", body.len());
     println!("Decompressed {} characters", content.len());
+
+    // sing-box source 格式，可直接载入为规则集
+    let mut manager = RuleSetManager::new();
+    manager.load_sing_box_source_json("geosite-google", &content)?;
+    if let Some(set) = manager.get_domain_set(&"geosite-google".to_string()) {
+        println!("Loaded {} domain entries", set.len());
+    }
     // println!("Content Json: {}", content);
 
     // 打印更多响应头信息
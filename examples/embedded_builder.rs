@@ -0,0 +1,96 @@
+// 嵌入示例：用 ConfigBuilder 构建配置，启动 SOCKS5 入站并经模拟上游代理转发一个连接
+use anybls::config::Config;
+use anybls::routing::build_router;
+use anybls::{InboundContext, OutboundManager, Socks5Proxy};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let target = echo_server().await?;
+    let upstream = mock_socks5_server().await?;
+
+    // 发往回显服务器的流量走上游代理，其余直连
+    let config = Config::builder()
+        .listen("127.0.0.1:1080".parse()?)
+        .add_outbound_socks5("upstream", upstream)
+        .add_outbound_direct("lan")
+        .default_outbound("lan")
+        .add_ip_rule("upstream", &[&format!("{}/32", target.ip())])
+        .add_domain_rule("lan", &["internal.example"])
+        .done()?;
+
+    let router = Arc::new(build_router(&config).await?);
+    let outbounds = OutboundManager::from_configs(&config.outbounds)?;
+    let context = InboundContext {
+        router: Some(router),
+        ..InboundContext::new(Box::leak(Box::new(config)), Arc::new(outbounds))
+    };
+    // 示例中绑定临时端口，避免与本机已有服务冲突
+    let running = Socks5Proxy::new("127.0.0.1:0".parse()?).bind(context).await?;
+    println!("SOCKS5 入站监听于 {}", running.local_addr());
+
+    let mut client = TcpStream::connect(running.local_addr()).await?;
+    client.write_all(&[0x05, 0x01, 0x00]).await?;
+    let mut method = [0u8; 2];
+    client.read_exact(&mut method).await?;
+    let SocketAddr::V4(v4) = target else { unreachable!() };
+    let mut request = vec![0x05, 0x01, 0x00, 0x01];
+    request.extend_from_slice(&v4.ip().octets());
+    request.extend_from_slice(&v4.port().to_be_bytes());
+    client.write_all(&request).await?;
+    let mut response = [0u8; 10];
+    client.read_exact(&mut response).await?;
+    assert_eq!(response[1], 0x00, "CONNECT failed");
+
+    client.write_all(b"hello").await?;
+    let mut reply = [0u8; 5];
+    client.read_exact(&mut reply).await?;
+    assert_eq!(&reply, b"hello");
+    println!("经上游代理 {} 收到回显: {}", upstream, String::from_utf8_lossy(&reply));
+    Ok(())
+}
+
+/// 回显每个连接收到的数据
+async fn echo_server() -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// 只支持无认证和 IPv4 CONNECT 的模拟 SOCKS5 上游
+async fn mock_socks5_server() -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut client, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut greeting = [0u8; 2];
+                client.read_exact(&mut greeting).await?;
+                let mut methods = vec![0u8; greeting[1] as usize];
+                client.read_exact(&mut methods).await?;
+                client.write_all(&[0x05, 0x00]).await?;
+                let mut request = [0u8; 10];
+                client.read_exact(&mut request).await?;
+                let ip = std::net::Ipv4Addr::new(request[4], request[5], request[6], request[7]);
+                let port = u16::from_be_bytes([request[8], request[9]]);
+                println!("上游代理收到 CONNECT {}", SocketAddr::from((ip, port)));
+                let mut upstream = TcpStream::connect((ip, port)).await?;
+                client.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
+                tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+                Ok::<_, std::io::Error>(())
+            });
+        }
+    });
+    Ok(addr)
+}
@@ -14,13 +14,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut rule_manager = RuleSetManager::new();
 
     // 添加域名规则集合
-    let google_domains = DomainRuleSet {
-        id: "google_domains".to_string(),
-        domain: vec!["google.com".to_string()],
-        domain_suffix: vec!["google.com".to_string(), "youtube.com".to_string()],
-        domain_keyword: vec!["google".to_string(), "youtube".to_string()],
-        domain_regex: vec![r"^.*\.google\.com$".to_string()],
-    };
+    let google_domains = DomainRuleSet::builder("google_domains")
+        .domain("google.com")
+        .suffix("google.com")
+        .suffix("youtube.com")
+        .keyword("google")
+        .keyword("youtube")
+        .regex(r"^.*\.google\.com$")
+        .build();
     rule_manager.add_domain_set(google_domains);
 
     // 添加IP规则集合
@@ -34,10 +35,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     router.set_rule_manager(rule_manager);
 
     // 添加路由规则
-    let google_rule = RouteRule {
-        rule_sets: vec!["google_domains".to_string(), "google_ips".to_string()],
-        outbound: "proxy".to_string(),
-    };
+    let google_rule = RouteRule::builder("proxy").rule_set("google_domains").rule_set("google_ips").build();
     router.add_rule(google_rule);
 
     // 测试域名匹配
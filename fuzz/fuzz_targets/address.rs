@@ -0,0 +1,16 @@
+// 各协议的地址编码：SOCKS5、VLESS、UoT
+#![no_main]
+use anybls::protocol::{Address, AddressFormat};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&format, data)) = data.split_first() else {
+        return;
+    };
+    let format = match format % 3 {
+        0 => AddressFormat::SOCKS5,
+        1 => AddressFormat::VLESS,
+        _ => AddressFormat::UOT,
+    };
+    anybls_fuzz::run(data, async |stream| Address::read_from(stream, format).await);
+});
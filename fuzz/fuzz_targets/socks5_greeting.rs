@@ -0,0 +1,18 @@
+// SOCKS5 方法协商与用户名/密码子协商
+#![no_main]
+use anybls::protocol::{negotiate_socks5_auth, MethodPolicy};
+use libfuzzer_sys::fuzz_target;
+use std::collections::HashMap;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&policy, data)) = data.split_first() else {
+        return;
+    };
+    let users = HashMap::from([("user".to_string(), "pass".to_string())]);
+    let policy = match policy % 3 {
+        0 => MethodPolicy::NoAuth,
+        1 => MethodPolicy::UsernameHint,
+        _ => MethodPolicy::Required(&users),
+    };
+    anybls_fuzz::run(data, async |stream| negotiate_socks5_auth(stream, policy).await);
+});
@@ -0,0 +1,13 @@
+// 方法协商之后的 SOCKS5 请求
+#![no_main]
+use anybls::protocol::Socks5Request;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    anybls_fuzz::run(data, async |stream| {
+        if let Ok(request) = Socks5Request::read_from(stream).await {
+            // 解析出的请求能重新编码
+            let _ = request.to_bytes();
+        }
+    });
+});
@@ -0,0 +1,13 @@
+// UDP-over-TCP 会话头和之后的数据报帧
+#![no_main]
+use anybls::uot::{read_frame, UotRequest};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    anybls_fuzz::run(data, async |stream| {
+        let Ok(request) = UotRequest::read_from(stream).await else {
+            return;
+        };
+        while let Ok(Some(_)) = read_frame(stream, request.is_connect).await {}
+    });
+});
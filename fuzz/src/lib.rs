@@ -0,0 +1,120 @@
+// 模糊测试公共部分：计数分配器、分块交付输入的内存流，以及检查读取次数和内存上限的运行器
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Most bytes a parser may hold allocated at once
+///
+/// The largest legitimate buffer is a 64KB UoT datagram.
+pub const MAX_LIVE_BYTES: usize = 256 * 1024;
+
+/// Reads of the exhausted input a parser may make before it counts as hanging
+pub const MAX_READS_AFTER_EOF: usize = 4;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// System allocator recording the live and peak allocated bytes
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let live = LIVE.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(live, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// In-memory client handing out its input at most `chunk` bytes per read
+///
+/// Writes are accepted and discarded. Reading past the end returns EOF, and
+/// doing so more than [`MAX_READS_AFTER_EOF`] times panics.
+pub struct ScriptedStream<'a> {
+    input: &'a [u8],
+    chunk: usize,
+    reads: usize,
+    eof_reads: usize,
+}
+
+impl<'a> ScriptedStream<'a> {
+    pub fn new(input: &'a [u8], chunk: usize) -> Self {
+        Self { input, chunk: chunk.max(1), reads: 0, eof_reads: 0 }
+    }
+
+    /// Reads made so far
+    pub fn reads(&self) -> usize {
+        self.reads
+    }
+}
+
+impl AsyncRead for ScriptedStream<'_> {
+    fn poll_read(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.reads += 1;
+        if this.input.is_empty() {
+            this.eof_reads += 1;
+            assert!(this.eof_reads <= MAX_READS_AFTER_EOF, "parser kept reading after EOF");
+            return Poll::Ready(Ok(()));
+        }
+        let len = this.chunk.min(this.input.len()).min(buf.remaining());
+        buf.put_slice(&this.input[..len]);
+        this.input = &this.input[len..];
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ScriptedStream<'_> {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Run `parse` on a stream of `data`
+///
+/// The first byte picks how many bytes each read delivers (1-8), the rest is
+/// the stream content. Whatever `parse` returns is fine; it must not panic,
+/// must finish within one read per input byte (plus the EOF allowance), and
+/// must stay under [`MAX_LIVE_BYTES`].
+pub fn run<T>(data: &[u8], parse: impl AsyncFnOnce(&mut ScriptedStream<'_>) -> T) {
+    let Some((&chunk, input)) = data.split_first() else {
+        return;
+    };
+    let mut stream = ScriptedStream::new(input, chunk as usize % 8 + 1);
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+    let before = LIVE.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    runtime.block_on(async {
+        parse(&mut stream).await;
+    });
+    let peak = PEAK.load(Ordering::Relaxed).saturating_sub(before);
+    assert!(peak <= MAX_LIVE_BYTES, "parser held {} bytes at once", peak);
+    assert!(
+        stream.reads() <= input.len() + MAX_READS_AFTER_EOF,
+        "{} reads for {} input bytes",
+        stream.reads(),
+        input.len()
+    );
+}
@@ -0,0 +1,415 @@
+// 接受循环容错：fd 耗尽时暂停并借备用 fd 排空队列，瞬时错误指数退避，监听套接字失效时重新绑定
+use crate::error::Result;
+use crate::listener::bind_tcp_listener;
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use std::io;
+use std::net::SocketAddr;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Pause after hitting the file descriptor limit
+pub const FD_LIMIT_BACKOFF: Duration = Duration::from_millis(100);
+/// First delay after a transient accept error
+pub const TRANSIENT_INITIAL_BACKOFF: Duration = Duration::from_millis(5);
+/// Upper bound for the transient error backoff
+pub const TRANSIENT_MAX_BACKOFF: Duration = Duration::from_secs(1);
+/// How long to wait for a queued connection when shedding one at the fd limit
+const SHED_ACCEPT_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// How an accept error is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptErrorKind {
+    /// The client went away before we accepted it; retried at once
+    Aborted,
+    /// EMFILE/ENFILE: out of file descriptors
+    FdLimit,
+    /// Resource shortage or other errors that may clear up; retried with backoff
+    Transient,
+    /// The listening socket itself is broken; it is re-bound
+    Fatal,
+}
+
+impl AcceptErrorKind {
+    pub fn classify(error: &io::Error) -> Self {
+        match error.raw_os_error() {
+            Some(libc::EMFILE | libc::ENFILE) => return AcceptErrorKind::FdLimit,
+            Some(libc::EBADF | libc::EINVAL | libc::ENOTSOCK | libc::EOPNOTSUPP) => return AcceptErrorKind::Fatal,
+            _ => {}
+        }
+        match error.kind() {
+            io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock => AcceptErrorKind::Aborted,
+            _ => AcceptErrorKind::Transient,
+        }
+    }
+}
+
+/// Accept error counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AcceptStats {
+    pub aborted: u64,
+    pub fd_limit: u64,
+    pub transient: u64,
+    pub fatal: u64,
+    /// Listener re-binds after fatal errors
+    pub rebinds: u64,
+    /// Connections accepted and closed at once to drain the queue at the fd limit
+    pub shed: u64,
+}
+
+struct AcceptCounters {
+    aborted: AtomicU64,
+    fd_limit: AtomicU64,
+    transient: AtomicU64,
+    fatal: AtomicU64,
+    rebinds: AtomicU64,
+    shed: AtomicU64,
+}
+
+impl AcceptCounters {
+    const fn new() -> Self {
+        Self {
+            aborted: AtomicU64::new(0),
+            fd_limit: AtomicU64::new(0),
+            transient: AtomicU64::new(0),
+            fatal: AtomicU64::new(0),
+            rebinds: AtomicU64::new(0),
+            shed: AtomicU64::new(0),
+        }
+    }
+
+    fn error(&self, kind: AcceptErrorKind) -> &AtomicU64 {
+        match kind {
+            AcceptErrorKind::Aborted => &self.aborted,
+            AcceptErrorKind::FdLimit => &self.fd_limit,
+            AcceptErrorKind::Transient => &self.transient,
+            AcceptErrorKind::Fatal => &self.fatal,
+        }
+    }
+
+    fn snapshot(&self) -> AcceptStats {
+        AcceptStats {
+            aborted: self.aborted.load(Ordering::Relaxed),
+            fd_limit: self.fd_limit.load(Ordering::Relaxed),
+            transient: self.transient.load(Ordering::Relaxed),
+            fatal: self.fatal.load(Ordering::Relaxed),
+            rebinds: self.rebinds.load(Ordering::Relaxed),
+            shed: self.shed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+static ACCEPT_COUNTERS: AcceptCounters = AcceptCounters::new();
+
+/// Accept error counters of all listeners
+pub fn accept_stats() -> AcceptStats {
+    ACCEPT_COUNTERS.snapshot()
+}
+
+/// Connections accepted by one listener shard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShardAcceptStats {
+    pub addr: SocketAddr,
+    pub shard: usize,
+    pub accepted: u64,
+}
+
+static SHARD_ACCEPTS: Mutex<BTreeMap<(SocketAddr, usize), Arc<AtomicU64>>> = Mutex::new(BTreeMap::new());
+
+/// Accept counter of shard `shard` listening on `addr`; kept across inbound restarts
+pub fn shard_accept_counter(addr: SocketAddr, shard: usize) -> Arc<AtomicU64> {
+    SHARD_ACCEPTS.lock().unwrap().entry((addr, shard)).or_default().clone()
+}
+
+/// Per-shard accept counts of all listeners, so uneven kernel balancing is visible
+pub fn shard_accept_stats() -> Vec<ShardAcceptStats> {
+    SHARD_ACCEPTS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(&(addr, shard), accepted)| ShardAcceptStats {
+            addr,
+            shard,
+            accepted: accepted.load(Ordering::Relaxed),
+        })
+        .collect()
+}
+
+/// Source of inbound connections
+#[async_trait]
+pub trait Accept: Send {
+    type Stream: Send;
+
+    async fn accept(&mut self) -> io::Result<(Self::Stream, SocketAddr)>;
+
+    /// Replace a broken listening socket with a fresh one
+    async fn rebind(&mut self) -> Result<()>;
+}
+
+/// TCP listener that re-binds its own address when it breaks
+pub struct BoundListener {
+    /// None only while re-binding
+    listener: Option<TcpListener>,
+    addr: SocketAddr,
+}
+
+impl BoundListener {
+    /// Wrap a bound listener; re-binds reuse its actual address, so a port
+    /// picked by the OS is kept
+    pub fn new(listener: TcpListener) -> io::Result<Self> {
+        let addr = listener.local_addr()?;
+        Ok(Self {
+            listener: Some(listener),
+            addr,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+#[async_trait]
+impl Accept for BoundListener {
+    type Stream = TcpStream;
+
+    async fn accept(&mut self) -> io::Result<(TcpStream, SocketAddr)> {
+        match &self.listener {
+            Some(listener) => listener.accept().await,
+            None => Err(io::Error::from_raw_os_error(libc::EBADF)),
+        }
+    }
+
+    async fn rebind(&mut self) -> Result<()> {
+        // 先释放旧套接字，否则端口仍被占用
+        self.listener = None;
+        self.listener = Some(bind_tcp_listener(self.addr).await?);
+        Ok(())
+    }
+}
+
+/// Accept loop that backs off instead of spinning on errors
+///
+/// At the fd limit it closes a spare descriptor to accept and immediately
+/// close one queued connection, so clients are refused promptly instead of
+/// waiting in the kernel queue, then pauses. Transient errors back off
+/// exponentially up to `TRANSIENT_MAX_BACKOFF`. A broken listener is re-bound
+/// with the bind retry settings; only when that fails does `next` return an
+/// error.
+pub struct AcceptLoop<A> {
+    acceptor: A,
+    backoff: Duration,
+    spare_fd: Option<std::fs::File>,
+}
+
+impl<A: Accept> AcceptLoop<A> {
+    pub fn new(acceptor: A) -> Self {
+        Self {
+            acceptor,
+            backoff: TRANSIENT_INITIAL_BACKOFF,
+            spare_fd: open_spare_fd(),
+        }
+    }
+
+    pub fn acceptor(&self) -> &A {
+        &self.acceptor
+    }
+
+    /// Next accepted connection
+    pub async fn next(&mut self) -> Result<(A::Stream, SocketAddr)> {
+        loop {
+            let error = match self.acceptor.accept().await {
+                Ok(accepted) => {
+                    self.backoff = TRANSIENT_INITIAL_BACKOFF;
+                    return Ok(accepted);
+                }
+                Err(e) => e,
+            };
+            let kind = AcceptErrorKind::classify(&error);
+            ACCEPT_COUNTERS.error(kind).fetch_add(1, Ordering::Relaxed);
+
+            match kind {
+                AcceptErrorKind::Aborted => debug!("Accept aborted: {}", error),
+                AcceptErrorKind::FdLimit => {
+                    warn!("Out of file descriptors, pausing accept for {:?}: {}", FD_LIMIT_BACKOFF, error);
+                    self.shed_one().await;
+                    tokio::time::sleep(FD_LIMIT_BACKOFF).await;
+                }
+                AcceptErrorKind::Transient => {
+                    warn!("Accept failed, retrying in {:?}: {}", self.backoff, error);
+                    tokio::time::sleep(self.backoff).await;
+                    self.backoff = (self.backoff * 2).min(TRANSIENT_MAX_BACKOFF);
+                }
+                AcceptErrorKind::Fatal => {
+                    error!("Listener failed, re-binding: {}", error);
+                    self.acceptor.rebind().await?;
+                    ACCEPT_COUNTERS.rebinds.fetch_add(1, Ordering::Relaxed);
+                    info!("Listener re-bound");
+                }
+            }
+        }
+    }
+
+    /// Free the spare descriptor, accept and drop one queued connection, then
+    /// take the spare back
+    async fn shed_one(&mut self) {
+        let Some(spare) = self.spare_fd.take() else {
+            self.spare_fd = open_spare_fd();
+            return;
+        };
+        drop(spare);
+        if let Ok(Ok((_stream, peer))) = tokio::time::timeout(SHED_ACCEPT_TIMEOUT, self.acceptor.accept()).await {
+            ACCEPT_COUNTERS.shed.fetch_add(1, Ordering::Relaxed);
+            debug!("Closed connection from {} to drain the accept queue", peer);
+        }
+        self.spare_fd = open_spare_fd();
+    }
+}
+
+fn open_spare_fd() -> Option<std::fs::File> {
+    std::fs::File::open("/dev/null").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use tokio::time::Instant;
+
+    fn os_error(code: i32) -> io::Error {
+        io::Error::from_raw_os_error(code)
+    }
+
+    /// Scripted acceptor; once the script runs out it keeps failing with `repeat`
+    struct MockAcceptor {
+        script: VecDeque<io::Result<u32>>,
+        repeat: i32,
+        accepts: Arc<Mutex<Vec<Instant>>>,
+        rebind_ok: bool,
+        rebinds: u32,
+    }
+
+    impl MockAcceptor {
+        fn new(script: Vec<io::Result<u32>>, repeat: i32) -> Self {
+            Self {
+                script: script.into(),
+                repeat,
+                accepts: Arc::default(),
+                rebind_ok: true,
+                rebinds: 0,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Accept for MockAcceptor {
+        type Stream = u32;
+
+        async fn accept(&mut self) -> io::Result<(u32, SocketAddr)> {
+            self.accepts.lock().unwrap().push(Instant::now());
+            let next = self.script.pop_front().unwrap_or_else(|| Err(os_error(self.repeat)));
+            next.map(|id| (id, SocketAddr::from(([192, 0, 2, 1], 40000 + id as u16))))
+        }
+
+        async fn rebind(&mut self) -> Result<()> {
+            self.rebinds += 1;
+            if self.rebind_ok {
+                Ok(())
+            } else {
+                Err(os_error(libc::EADDRINUSE).into())
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_classification() {
+        assert_eq!(AcceptErrorKind::classify(&os_error(libc::EMFILE)), AcceptErrorKind::FdLimit);
+        assert_eq!(AcceptErrorKind::classify(&os_error(libc::ENFILE)), AcceptErrorKind::FdLimit);
+        assert_eq!(AcceptErrorKind::classify(&os_error(libc::ENOBUFS)), AcceptErrorKind::Transient);
+        assert_eq!(AcceptErrorKind::classify(&os_error(libc::ECONNABORTED)), AcceptErrorKind::Aborted);
+        assert_eq!(AcceptErrorKind::classify(&os_error(libc::EBADF)), AcceptErrorKind::Fatal);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fd_limit_does_not_spin() {
+        let acceptor = MockAcceptor::new(Vec::new(), libc::EMFILE);
+        let accepts = acceptor.accepts.clone();
+        let mut incoming = AcceptLoop::new(acceptor);
+
+        let started = Instant::now();
+        assert!(tokio::time::timeout(Duration::from_secs(1), incoming.next()).await.is_err());
+        // 每次退避前最多两次 accept（一次失败、一次借备用fd排空）
+        let calls = accepts.lock().unwrap().len();
+        assert!(calls <= 2 * (1000 / FD_LIMIT_BACKOFF.as_millis() as usize) + 2, "{} accepts in 1s", calls);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fd_limit_sheds_queued_connection() {
+        // EMFILE，备用fd释放后接受并关闭连接1，退避后正常接受连接2
+        let acceptor = MockAcceptor::new(vec![Err(os_error(libc::EMFILE)), Ok(1), Ok(2)], libc::EMFILE);
+        let mut incoming = AcceptLoop::new(acceptor);
+        let before = accept_stats().shed;
+
+        let started = Instant::now();
+        let (id, _) = incoming.next().await.unwrap();
+        assert_eq!(id, 2);
+        assert!(started.elapsed() >= FD_LIMIT_BACKOFF);
+        assert!(accept_stats().shed > before);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_transient_errors_back_off_exponentially() {
+        let mut script: Vec<io::Result<u32>> = (0..10).map(|_| Err(os_error(libc::ENOBUFS))).collect();
+        script.push(Ok(7));
+        let acceptor = MockAcceptor::new(script, libc::ENOBUFS);
+        let accepts = acceptor.accepts.clone();
+        let mut incoming = AcceptLoop::new(acceptor);
+
+        let (id, _) = incoming.next().await.unwrap();
+        assert_eq!(id, 7);
+        let accepts = accepts.lock().unwrap();
+        let gaps: Vec<Duration> = accepts.windows(2).map(|w| w[1] - w[0]).collect();
+        let expected: Vec<Duration> = [5, 10, 20, 40, 80, 160, 320, 640, 1000, 1000]
+            .into_iter()
+            .map(Duration::from_millis)
+            .collect();
+        assert_eq!(gaps, expected);
+
+        // 成功后退避重置
+        assert_eq!(incoming.backoff, TRANSIENT_INITIAL_BACKOFF);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fatal_error_rebinds_then_gives_up() {
+        let acceptor = MockAcceptor::new(vec![Err(os_error(libc::EBADF)), Ok(3)], libc::EBADF);
+        let mut incoming = AcceptLoop::new(acceptor);
+        assert_eq!(incoming.next().await.unwrap().0, 3);
+        assert_eq!(incoming.acceptor().rebinds, 1);
+
+        let mut acceptor = MockAcceptor::new(Vec::new(), libc::EBADF);
+        acceptor.rebind_ok = false;
+        let mut incoming = AcceptLoop::new(acceptor);
+        assert!(incoming.next().await.is_err());
+        assert_eq!(incoming.acceptor().rebinds, 1);
+    }
+
+    #[tokio::test]
+    async fn test_bound_listener_rebinds_same_port() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut bound = BoundListener::new(listener).unwrap();
+        let addr = bound.local_addr();
+        bound.rebind().await.unwrap();
+
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (_stream, peer) = bound.accept().await.unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+    }
+}
@@ -0,0 +1,593 @@
+// 访问日志：连接结束时生成一条记录，经有界队列交给写入任务；成功连接可抽样，客户端地址可脱敏
+use crate::config::{AccessLogConfig, AccessLogFormat, LoggingConfig};
+use crate::connection_registry::{ConnectionPhase, ConnectionSnapshot, TrackedConnection};
+use crate::error::{ProxyError, Result};
+use crate::log_file::{BackgroundWriter, RotatingFile, RotationPolicy};
+use crate::protocol::{LogSafe, TargetAddr};
+use crate::tasks::{get_global_task_tracker, TaskGroup};
+use crate::zero_copy::RelayResult;
+use log::{info, warn};
+use ring::hmac;
+use std::fmt::{self, Write};
+use std::io::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+/// How a logged connection ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Termination {
+    /// Served until both sides closed, or answered without dialing
+    Ok,
+    /// Failed before a target was requested
+    HandshakeFailed,
+    /// Stuck before the relay longer than `server.handshake_timeout_secs`
+    HandshakeTimeout,
+    /// Refused by routing, or the target could not be dialed
+    ConnectFailed,
+    /// A peer reset its side or stopped reading during the relay
+    RelayError,
+    /// No bytes moved for `performance.relay_idle_timeout_secs`
+    IdleTimeout,
+    /// Closed through the connection registry
+    Killed,
+}
+
+impl Termination {
+    /// Classify a finished connection from its last phase and how the relay ended
+    pub fn of(phase: ConnectionPhase, relay_result: Option<&RelayResult>, failed: bool) -> Self {
+        match (failed, phase, relay_result) {
+            (true, ConnectionPhase::Greeting | ConnectionPhase::Auth | ConnectionPhase::Request, _) => Termination::HandshakeFailed,
+            (true, ConnectionPhase::Connecting, _) => Termination::ConnectFailed,
+            (true, ConnectionPhase::Relaying, _) => Termination::RelayError,
+            (false, _, Some(RelayResult::PeerAborted(_))) => Termination::RelayError,
+            (false, _, Some(RelayResult::IdleTimeout(_))) => Termination::IdleTimeout,
+            (false, _, Some(RelayResult::Aborted(_))) => Termination::Killed,
+            (false, _, Some(RelayResult::Completed) | None) => Termination::Ok,
+        }
+    }
+}
+
+impl fmt::Display for Termination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Termination::Ok => "ok",
+            Termination::HandshakeFailed => "handshake_failed",
+            Termination::HandshakeTimeout => "handshake_timeout",
+            Termination::ConnectFailed => "connect_failed",
+            Termination::RelayError => "relay_error",
+            Termination::IdleTimeout => "idle_timeout",
+            Termination::Killed => "killed",
+        })
+    }
+}
+
+/// One finished connection
+#[derive(Debug, Clone)]
+pub struct AccessRecord {
+    pub client: SocketAddr,
+    pub user: Option<String>,
+    /// Whether the client authenticated as `user`; otherwise the username
+    /// is only a routing hint or absent
+    pub authenticated: bool,
+    pub target: Option<String>,
+    /// Destination actually dialed when a rule rewrote `target`
+    pub rewritten_to: Option<String>,
+    /// Address that would have been dialed, when the connection was served in dry-run mode
+    pub dry_run: Option<TargetAddr>,
+    /// A client probe answered without dialing
+    pub probe: bool,
+    /// Routing profile of the inbound; None for `[router]`
+    pub profile: Option<String>,
+    pub outbound: Option<String>,
+    /// Time from accept until the target connected
+    pub connect_latency: Option<Duration>,
+    pub duration: Duration,
+    pub upload: u64,
+    pub download: u64,
+    /// None when the connection succeeded
+    pub error: Option<String>,
+    /// Phase the client was stuck in when the handshake timed out
+    pub handshake_timeout: Option<ConnectionPhase>,
+    pub termination: Termination,
+    /// When the connection was closed
+    pub ended_at: SystemTime,
+}
+
+impl AccessRecord {
+    pub fn new(snapshot: ConnectionSnapshot, error: Option<String>) -> Self {
+        Self {
+            termination: Termination::of(snapshot.phase, snapshot.relay_result.as_ref(), error.is_some()),
+            ended_at: SystemTime::now(),
+            client: snapshot.client,
+            user: snapshot.user,
+            authenticated: snapshot.authenticated,
+            target: snapshot.target,
+            rewritten_to: snapshot.rewritten_to,
+            dry_run: snapshot.dry_run,
+            probe: snapshot.probe,
+            profile: snapshot.profile,
+            outbound: snapshot.outbound,
+            connect_latency: snapshot.connect_latency,
+            duration: snapshot.age,
+            upload: snapshot.upload,
+            download: snapshot.download,
+            error,
+            handshake_timeout: None,
+        }
+    }
+}
+
+/// Access log counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccessLogStats {
+    /// Records handed to the writer
+    pub queued: u64,
+    /// Successful connections left out by sampling
+    pub sampled_out: u64,
+    /// Records dropped because the queue was full
+    pub dropped: u64,
+}
+
+/// Producer side of the access log
+///
+/// `record_connection` never waits: records that do not fit in the queue
+/// are counted as dropped.
+pub struct AccessLogger {
+    sender: Option<mpsc::Sender<AccessRecord>>,
+    sample_rate: u64,
+    slow_threshold: Option<Duration>,
+    successes: AtomicU64,
+    queued: AtomicU64,
+    sampled_out: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl AccessLogger {
+    pub fn disabled() -> Self {
+        Self {
+            sender: None,
+            sample_rate: 1,
+            slow_threshold: None,
+            successes: AtomicU64::new(0),
+            queued: AtomicU64::new(0),
+            sampled_out: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Logger and the receiving end of its queue
+    pub fn channel(config: &AccessLogConfig) -> (Self, mpsc::Receiver<AccessRecord>) {
+        let (sender, receiver) = mpsc::channel(config.queue_size.max(1));
+        let logger = Self {
+            sender: Some(sender),
+            sample_rate: u64::from(config.sample_rate.max(1)),
+            slow_threshold: (config.slow_threshold_ms > 0).then(|| Duration::from_millis(config.slow_threshold_ms)),
+            ..Self::disabled()
+        };
+        (logger, receiver)
+    }
+
+    /// Logger whose records `writer` writes from a task in the access log group
+    pub fn spawn(config: &AccessLogConfig, writer: AccessLogWriter) -> Self {
+        let (logger, receiver) = Self::channel(config);
+        match get_global_task_tracker().spawn(TaskGroup::AccessLog, writer.run(receiver)) {
+            Ok(_) => logger,
+            Err(e) => {
+                warn!("Access log not started: {}", e);
+                Self::disabled()
+            }
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    /// Log a finished connection, subject to sampling
+    pub fn record_connection(&self, connection: &TrackedConnection, error: Option<&ProxyError>) {
+        if self.is_enabled() && self.keep(error.is_some(), connection.connect_latency()) {
+            let mut record = AccessRecord::new(connection.snapshot(), error.map(ToString::to_string));
+            if let Some(ProxyError::HandshakeTimeout(phase)) = error {
+                record.handshake_timeout = Some(*phase);
+                record.termination = Termination::HandshakeTimeout;
+            }
+            self.enqueue(record);
+        }
+    }
+
+    /// Log a record, subject to sampling
+    pub fn submit(&self, record: AccessRecord) {
+        if self.is_enabled() && self.keep(record.error.is_some(), record.connect_latency) {
+            self.enqueue(record);
+        }
+    }
+
+    /// Failures and slow connections are always kept, other connections one in `sample_rate`
+    fn keep(&self, failed: bool, connect_latency: Option<Duration>) -> bool {
+        let slow = connect_latency.zip(self.slow_threshold).is_some_and(|(latency, threshold)| latency >= threshold);
+        if failed || slow || self.successes.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.sample_rate) {
+            return true;
+        }
+        self.sampled_out.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    fn enqueue(&self, record: AccessRecord) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        match sender.try_send(record) {
+            Ok(()) => self.queued.fetch_add(1, Ordering::Relaxed),
+            Err(TrySendError::Full(_) | TrySendError::Closed(_)) => self.dropped.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub fn stats(&self) -> AccessLogStats {
+        AccessLogStats {
+            queued: self.queued.load(Ordering::Relaxed),
+            sampled_out: self.sampled_out.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Consumer side: formats records and writes them to the access log file,
+/// or to the "access" log target without one
+pub struct AccessLogWriter {
+    drop_client_port: bool,
+    ip_key: Option<hmac::Key>,
+    format: AccessLogFormat,
+    file: Option<BackgroundWriter>,
+}
+
+impl AccessLogWriter {
+    pub fn new(config: &AccessLogConfig) -> Self {
+        Self {
+            drop_client_port: config.drop_client_port,
+            ip_key: config
+                .hash_client_ip
+                .then(|| hmac::Key::new(hmac::HMAC_SHA256, config.ip_hash_salt.as_bytes())),
+            format: config.format,
+            file: None,
+        }
+    }
+
+    /// Write records to `file` instead of the log
+    pub fn with_file(mut self, file: BackgroundWriter) -> Self {
+        self.file = Some(file);
+        self
+    }
+
+    /// Client address after redaction
+    fn client(&self, addr: SocketAddr) -> String {
+        let ip = match &self.ip_key {
+            // HMAC 的前 8 字节足以区分客户端；盐保密时无法由哈希反推 IP
+            Some(key) => hmac::sign(key, addr.ip().to_string().as_bytes()).as_ref()[..8]
+                .iter()
+                .fold(String::with_capacity(16), |mut hex, b| {
+                    let _ = write!(hex, "{:02x}", b);
+                    hex
+                }),
+            None if self.drop_client_port => return addr.ip().to_string(),
+            None => return addr.to_string(),
+        };
+        if self.drop_client_port {
+            ip
+        } else {
+            format!("{}:{}", ip, addr.port())
+        }
+    }
+
+    /// One record in the configured format, without a trailing newline
+    pub fn format(&self, record: &AccessRecord) -> String {
+        match self.format {
+            AccessLogFormat::Text => self.format_text(record),
+            AccessLogFormat::Json => self.format_json(record),
+            AccessLogFormat::Tsv => self.format_tsv(record),
+        }
+    }
+
+    fn format_text(&self, record: &AccessRecord) -> String {
+        let mut line = format!(
+            "client={} user={} auth={} target={} outbound={} connect_ms={} duration_ms={} up={} down={}",
+            self.client(record.client),
+            LogSafe(record.user.as_deref().unwrap_or("-")),
+            if record.authenticated { "password" } else { "none" },
+            LogSafe(record.target.as_deref().unwrap_or("-")),
+            record.outbound.as_deref().unwrap_or("-"),
+            record.connect_latency.map_or("-".to_string(), |latency| latency.as_millis().to_string()),
+            record.duration.as_millis(),
+            record.upload,
+            record.download,
+        );
+        if let Some(rewritten) = &record.rewritten_to {
+            let _ = write!(line, " rewritten_to={}", LogSafe(rewritten.as_str()));
+        }
+        if let Some(profile) = &record.profile {
+            let _ = write!(line, " profile={}", profile);
+        }
+        if let Some(would_dial) = &record.dry_run {
+            let _ = write!(line, " dry_run=true would_dial={}", would_dial);
+        }
+        if record.probe {
+            line.push_str(" probe=true");
+        }
+        let _ = write!(line, " result={}", record.termination);
+        match (&record.error, record.handshake_timeout) {
+            (_, Some(phase)) => {
+                let _ = write!(line, " phase={}", phase);
+            }
+            (Some(error), None) => {
+                let _ = write!(line, " error=\"{}\"", LogSafe(error));
+            }
+            (None, None) => {}
+        }
+        line
+    }
+
+    fn format_json(&self, record: &AccessRecord) -> String {
+        serde_json::json!({
+            "timestamp": unix_millis(record.ended_at) as f64 / 1000.0,
+            "client": self.client(record.client),
+            "user": record.user,
+            "authenticated": record.authenticated,
+            "target": record.target,
+            "rewritten_to": record.rewritten_to,
+            "outbound": record.outbound,
+            "profile": record.profile,
+            "connect_ms": record.connect_latency.map(|latency| latency.as_millis() as u64),
+            "duration_ms": record.duration.as_millis() as u64,
+            "up": record.upload,
+            "down": record.download,
+            "result": record.termination.to_string(),
+            "error": record.error,
+            "phase": record.handshake_timeout.map(|phase| phase.to_string()),
+            "dry_run": record.dry_run.as_ref().map(ToString::to_string),
+            "probe": record.probe,
+        })
+        .to_string()
+    }
+
+    /// Columns: timestamp, client, user, target, outbound, connect_ms,
+    /// duration_ms, up, down, result, error; "-" when absent
+    fn format_tsv(&self, record: &AccessRecord) -> String {
+        // LogSafe 转义控制字符，字段里的制表符不会错列
+        let text = |value: Option<&str>| value.map_or("-".to_string(), |value| LogSafe(value).to_string());
+        [
+            timestamp(record.ended_at),
+            self.client(record.client),
+            text(record.user.as_deref()),
+            text(record.target.as_deref()),
+            text(record.outbound.as_deref()),
+            record.connect_latency.map_or("-".to_string(), |latency| latency.as_millis().to_string()),
+            record.duration.as_millis().to_string(),
+            record.upload.to_string(),
+            record.download.to_string(),
+            record.termination.to_string(),
+            text(record.error.as_deref()),
+        ]
+        .join("\t")
+    }
+
+    pub async fn run(mut self, mut receiver: mpsc::Receiver<AccessRecord>) {
+        while let Some(record) = receiver.recv().await {
+            let line = self.format(&record);
+            let Some(file) = &mut self.file else {
+                info!(target: "access", "{}", line);
+                continue;
+            };
+            // 日志框架会加时间戳，单独的文件里由这里补上
+            let line = match self.format {
+                AccessLogFormat::Text => format!("{} {}\n", timestamp(record.ended_at), line),
+                AccessLogFormat::Json | AccessLogFormat::Tsv => line + "\n",
+            };
+            if let Err(e) = file.write_all(line.as_bytes()) {
+                warn!("Failed to write access log: {}", e);
+            }
+        }
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Unix seconds with milliseconds, as in `1760000000.123`
+fn timestamp(time: SystemTime) -> String {
+    let millis = unix_millis(time);
+    format!("{}.{:03}", millis / 1000, millis % 1000)
+}
+
+static GLOBAL_ACCESS_LOG: OnceLock<AccessLogger> = OnceLock::new();
+
+/// Initialize the global access log and start its writer when enabled
+///
+/// A separate access log file is rotated with the `[logging]` rotation settings.
+pub fn init_global_access_log(config: &LoggingConfig) -> Result<()> {
+    let access = &config.access_log;
+    let logger = if access.enabled {
+        let mut writer = AccessLogWriter::new(access);
+        if let Some(path) = access.file.as_deref().filter(|path| !path.is_empty()) {
+            let file = RotatingFile::open(path, RotationPolicy::from_config(config))?;
+            writer = writer.with_file(BackgroundWriter::spawn(file)?);
+        }
+        AccessLogger::spawn(access, writer)
+    } else {
+        AccessLogger::disabled()
+    };
+    let _ = GLOBAL_ACCESS_LOG.set(logger);
+    Ok(())
+}
+
+/// Get the global access log (disabled when not initialized)
+pub fn get_global_access_log() -> &'static AccessLogger {
+    GLOBAL_ACCESS_LOG.get_or_init(AccessLogger::disabled)
+}
+
+/// Counters of the global access log
+pub fn access_log_stats() -> AccessLogStats {
+    get_global_access_log().stats()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(sample_rate: u32, queue_size: usize) -> AccessLogConfig {
+        AccessLogConfig {
+            enabled: true,
+            sample_rate,
+            queue_size,
+            ..AccessLogConfig::default()
+        }
+    }
+
+    fn record(id: u16, error: Option<&str>, connect_ms: u64) -> AccessRecord {
+        AccessRecord {
+            client: SocketAddr::from(([192, 0, 2, 7], 40000 + id)),
+            user: None,
+            authenticated: false,
+            target: Some(format!("host{}.example:443", id)),
+            rewritten_to: None,
+            dry_run: None,
+            probe: false,
+            profile: None,
+            outbound: Some("direct".to_string()),
+            connect_latency: Some(Duration::from_millis(connect_ms)),
+            duration: Duration::from_secs(1),
+            upload: 100,
+            download: 200,
+            error: error.map(str::to_string),
+            handshake_timeout: None,
+            termination: if error.is_some() { Termination::ConnectFailed } else { Termination::Ok },
+            ended_at: UNIX_EPOCH + Duration::from_millis(1_760_000_000_123),
+        }
+    }
+
+    fn drain(receiver: &mut mpsc::Receiver<AccessRecord>) -> Vec<AccessRecord> {
+        std::iter::from_fn(|| receiver.try_recv().ok()).collect()
+    }
+
+    #[test]
+    fn test_sampling_keeps_all_failures() {
+        let (logger, mut receiver) = AccessLogger::channel(&config(10, 1000));
+        for id in 0..100 {
+            logger.submit(record(id, None, 5));
+            if id % 5 == 0 {
+                logger.submit(record(id, Some("connection refused"), 5));
+            }
+        }
+
+        let records = drain(&mut receiver);
+        let successes = records.iter().filter(|r| r.error.is_none()).count();
+        let failures = records.iter().filter(|r| r.error.is_some()).count();
+        assert_eq!((successes, failures), (10, 20));
+        assert_eq!(logger.stats(), AccessLogStats { queued: 30, sampled_out: 90, dropped: 0 });
+    }
+
+    #[test]
+    fn test_slow_connections_always_logged() {
+        let (logger, mut receiver) = AccessLogger::channel(&config(1000, 16));
+        logger.submit(record(1, None, 5));
+        for id in 2..6 {
+            logger.submit(record(id, None, 2500));
+        }
+        logger.submit(record(6, None, 5));
+        assert_eq!(drain(&mut receiver).len(), 5);
+        assert_eq!(logger.stats().sampled_out, 1);
+    }
+
+    #[test]
+    fn test_full_queue_drops_without_blocking() {
+        let (logger, mut receiver) = AccessLogger::channel(&config(1, 4));
+        let started = std::time::Instant::now();
+        for id in 0..10 {
+            logger.submit(record(id, Some("timed out"), 5));
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(logger.stats(), AccessLogStats { queued: 4, sampled_out: 0, dropped: 6 });
+        assert_eq!(drain(&mut receiver).len(), 4);
+
+        // 写入端消费后恢复入队
+        logger.submit(record(10, Some("timed out"), 5));
+        assert_eq!(logger.stats().queued, 5);
+    }
+
+    #[test]
+    fn test_client_redaction() {
+        let plain = AccessLogWriter::new(&AccessLogConfig::default());
+        assert!(plain.format(&record(1, None, 5)).starts_with("client=192.0.2.7:40001 "));
+
+        let authenticated = AccessRecord { user: Some("alice".to_string()), authenticated: true, ..record(1, None, 5) };
+        assert!(plain.format(&authenticated).contains(" user=alice auth=password "));
+        assert!(plain.format(&record(1, None, 5)).contains(" user=- auth=none "));
+
+        let no_port = AccessLogWriter::new(&AccessLogConfig { drop_client_port: true, ..AccessLogConfig::default() });
+        assert!(no_port.format(&record(1, None, 5)).starts_with("client=192.0.2.7 "));
+
+        let hashed = |salt: &str| {
+            AccessLogWriter::new(&AccessLogConfig {
+                drop_client_port: true,
+                hash_client_ip: true,
+                ip_hash_salt: salt.to_string(),
+                ..AccessLogConfig::default()
+            })
+        };
+        let line = hashed("pepper").format(&record(1, Some("refused\n"), 5));
+        assert!(!line.contains("192.0.2.7") && !line.contains("40001"), "{}", line);
+        assert!(line.ends_with("result=connect_failed error=\"refused\\n\""), "{}", line);
+        // 同一 IP 在同一盐下哈希稳定，换盐后不同
+        let client = |writer: &AccessLogWriter, id| writer.format(&record(id, None, 5))[..23].to_string();
+        assert_eq!(client(&hashed("pepper"), 1), client(&hashed("pepper"), 2));
+        assert_ne!(client(&hashed("pepper"), 1), client(&hashed("salt"), 1));
+    }
+
+    #[test]
+    fn test_termination_of() {
+        use ConnectionPhase::*;
+        let idle = RelayResult::IdleTimeout(Duration::from_secs(300));
+        let cases = [
+            (Auth, None, true, Termination::HandshakeFailed),
+            (Connecting, None, true, Termination::ConnectFailed),
+            (Relaying, None, true, Termination::RelayError),
+            (Relaying, Some(RelayResult::PeerAborted("reset".to_string())), false, Termination::RelayError),
+            (Relaying, Some(idle), false, Termination::IdleTimeout),
+            (Relaying, Some(RelayResult::Aborted("killed".to_string())), false, Termination::Killed),
+            (Relaying, Some(RelayResult::Completed), false, Termination::Ok),
+            // 探测应答与 dry-run 不进入转发
+            (Connecting, None, false, Termination::Ok),
+        ];
+        for (phase, relay_result, failed, expected) in cases {
+            assert_eq!(Termination::of(phase, relay_result.as_ref(), failed), expected, "{:?} {:?}", phase, relay_result);
+        }
+    }
+
+    #[test]
+    fn test_json_and_tsv_formats() {
+        let writer = |format| AccessLogWriter::new(&AccessLogConfig { format, ..AccessLogConfig::default() });
+        let failed = AccessRecord { upload: 0, download: 0, connect_latency: None, ..record(1, Some("refused\t"), 5) };
+
+        let json: serde_json::Value = serde_json::from_str(&writer(AccessLogFormat::Json).format(&failed)).unwrap();
+        assert_eq!(json["timestamp"].to_string(), "1760000000.123");
+        assert_eq!(json["client"], "192.0.2.7:40001");
+        assert_eq!(json["target"], "host1.example:443");
+        assert_eq!(json["outbound"], "direct");
+        assert_eq!((json["up"].as_u64(), json["down"].as_u64()), (Some(0), Some(0)));
+        assert_eq!(json["duration_ms"], 1000);
+        assert_eq!(json["result"], "connect_failed");
+        assert_eq!(json["error"], "refused\t");
+        assert!(json["connect_ms"].is_null() && json["user"].is_null());
+
+        let tsv = writer(AccessLogFormat::Tsv).format(&record(2, None, 5));
+        assert_eq!(
+            tsv.split('\t').collect::<Vec<_>>(),
+            ["1760000000.123", "192.0.2.7:40002", "-", "host2.example:443", "direct", "5", "1000", "100", "200", "ok", "-"]
+        );
+        // 字段中的制表符被转义，列数不变
+        assert_eq!(writer(AccessLogFormat::Tsv).format(&failed).split('\t').count(), 11);
+    }
+}
@@ -0,0 +1,200 @@
+// 拦截统计：按域名（LRU 有界）和规则统计被黑洞出站拒绝的连接，可定期输出摘要
+use crate::config::BlockedConfig;
+use crate::tasks::{get_global_task_tracker, TaskGroup};
+use log::{info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+/// Domains named in the periodic summary
+const SUMMARY_TOP_DOMAINS: usize = 5;
+
+struct DomainEntry {
+    blocked: u64,
+    /// 最近一次拦截的序号，对应 `order` 中的键
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    total: u64,
+    domains: HashMap<String, DomainEntry>,
+    /// 拦截序号 -> 域名，最小的即最久未被拦截
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    /// 规则下标 -> 拦截数，None 为默认出站
+    rules: BTreeMap<Option<usize>, u64>,
+}
+
+/// Snapshot of the blocked-traffic counters
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockedStats {
+    /// Connections blocked since startup
+    pub total: u64,
+    /// Domains (or IPs) currently tracked
+    pub domains: usize,
+    /// Most blocked domains, highest count first
+    pub top: Vec<(String, u64)>,
+    /// Blocked connections per rule index (None for the default outbound), by rule
+    pub rules: Vec<(Option<usize>, u64)>,
+}
+
+impl fmt::Display for BlockedStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "blocked {} connections to {} domains", self.total, self.domains)?;
+        if !self.top.is_empty() {
+            let top: Vec<String> = self.top.iter().map(|(domain, count)| format!("{} ({})", domain, count)).collect();
+            write!(f, ", top: {}", top.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Per-domain and per-rule counts of blocked connections
+pub struct BlockedTraffic {
+    max_domains: usize,
+    quiet: bool,
+    counters: Mutex<Counters>,
+}
+
+impl BlockedTraffic {
+    pub fn new(config: &BlockedConfig) -> Self {
+        Self {
+            max_domains: config.max_domains,
+            quiet: config.quiet,
+            counters: Mutex::new(Counters::default()),
+        }
+    }
+
+    /// Count a connection to `target` (domain or IP) blocked by `rule`
+    pub fn record(&self, target: &str, rule: Option<usize>) {
+        if self.quiet {
+            log::trace!("Blocked connection to {} (rule {:?})", target, rule);
+        } else {
+            info!("Blocked connection to {} (rule {:?})", target, rule);
+        }
+
+        let mut counters = self.counters.lock().unwrap();
+        let Counters { total, domains, order, tick, rules } = &mut *counters;
+        *total += 1;
+        *rules.entry(rule).or_default() += 1;
+        if self.max_domains == 0 {
+            return;
+        }
+        *tick += 1;
+        match domains.get_mut(target) {
+            Some(entry) => {
+                order.remove(&entry.last_used);
+                entry.last_used = *tick;
+                entry.blocked += 1;
+            }
+            None => {
+                if domains.len() >= self.max_domains {
+                    if let Some((_, evicted)) = order.pop_first() {
+                        domains.remove(&evicted);
+                    }
+                }
+                domains.insert(target.to_string(), DomainEntry { blocked: 1, last_used: *tick });
+            }
+        }
+        order.insert(*tick, target.to_string());
+    }
+
+    /// The `n` most blocked domains, highest count first
+    pub fn top(&self, n: usize) -> Vec<(String, u64)> {
+        let counters = self.counters.lock().unwrap();
+        let mut all: Vec<(String, u64)> =
+            counters.domains.iter().map(|(domain, entry)| (domain.clone(), entry.blocked)).collect();
+        all.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        all.truncate(n);
+        all
+    }
+
+    /// Counters with the `top_n` most blocked domains
+    pub fn stats(&self, top_n: usize) -> BlockedStats {
+        let top = self.top(top_n);
+        let counters = self.counters.lock().unwrap();
+        BlockedStats {
+            total: counters.total,
+            domains: counters.domains.len(),
+            top,
+            rules: counters.rules.iter().map(|(rule, count)| (*rule, *count)).collect(),
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counters.lock().unwrap().total
+    }
+}
+
+static GLOBAL_BLOCKED_TRAFFIC: OnceLock<BlockedTraffic> = OnceLock::new();
+
+/// Initialize the global blocked-traffic counters
+pub fn init_global_blocked_traffic(config: &BlockedConfig) {
+    let _ = GLOBAL_BLOCKED_TRAFFIC.set(BlockedTraffic::new(config));
+}
+
+/// Get the global blocked-traffic counters (default settings when not initialized)
+pub fn get_global_blocked_traffic() -> &'static BlockedTraffic {
+    GLOBAL_BLOCKED_TRAFFIC.get_or_init(|| BlockedTraffic::new(&BlockedConfig::default()))
+}
+
+/// Counters of the global blocked-traffic table with the `top_n` most blocked domains
+pub fn blocked_stats(top_n: usize) -> BlockedStats {
+    get_global_blocked_traffic().stats(top_n)
+}
+
+/// Log a summary of blocked traffic every `summary_interval_secs`, skipping
+/// intervals in which nothing was blocked
+pub fn start_blocked_summary(config: &BlockedConfig) {
+    if config.summary_interval_secs == 0 {
+        return;
+    }
+    let period = Duration::from_secs(config.summary_interval_secs);
+    let spawned = get_global_task_tracker().spawn(TaskGroup::HealthChecks, async move {
+        let blocked = get_global_blocked_traffic();
+        let mut interval = tokio::time::interval(period);
+        interval.tick().await;
+        let mut reported = 0;
+        loop {
+            interval.tick().await;
+            let stats = blocked.stats(SUMMARY_TOP_DOMAINS);
+            if stats.total != reported {
+                reported = stats.total;
+                info!("{}", stats);
+            }
+        }
+    });
+    if let Err(e) = spawned {
+        warn!("Failed to start blocked-traffic summary: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_per_domain_and_rule() {
+        let blocked = BlockedTraffic::new(&BlockedConfig { max_domains: 2, ..BlockedConfig::default() });
+        for _ in 0..3 {
+            blocked.record("ads.example", Some(0));
+        }
+        blocked.record("tracker.example", Some(1));
+        blocked.record("203.0.113.9", None);
+
+        // 容量为2：最久未被拦截的 ads.example 被淘汰，总数不受影响
+        let stats = blocked.stats(10);
+        assert_eq!(stats.total, 5);
+        assert_eq!(stats.top, vec![("203.0.113.9".to_string(), 1), ("tracker.example".to_string(), 1)]);
+        assert_eq!(stats.rules, vec![(None, 1), (Some(0), 3), (Some(1), 1)]);
+
+        blocked.record("tracker.example", Some(1));
+        assert_eq!(blocked.top(1), vec![("tracker.example".to_string(), 2)]);
+        assert_eq!(
+            blocked.stats(1).to_string(),
+            "blocked 6 connections to 2 domains, top: tracker.example (2)"
+        );
+    }
+}
@@ -0,0 +1,253 @@
+// 缓冲区池：按尺寸分级的分片空闲链表，减少高并发建连时中继缓冲区的分配与缺页
+use crate::config::PerformanceConfig;
+use bytes::BytesMut;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+/// Smallest size class; matches the adaptive relay's starting buffer
+pub const MIN_POOLED_BUFFER_SIZE: usize = 4 * 1024;
+/// Idle buffers kept when `performance.max_pooled_buffers` is not set
+pub const DEFAULT_MAX_POOLED_BUFFERS: usize = 256;
+/// Free lists per size class, so threads rarely contend on one lock
+const SHARDS: usize = 8;
+
+/// Buffer pool counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    /// Requests served from a free list
+    pub hits: u64,
+    /// Requests that had to allocate
+    pub misses: u64,
+    /// Buffers handed out and not yet returned
+    pub outstanding: usize,
+    /// Idle buffers waiting in the pool
+    pub pooled: usize,
+}
+
+/// Pool of reusable byte buffers in fixed size classes
+///
+/// Size classes double from `MIN_POOLED_BUFFER_SIZE` up to the configured
+/// buffer size (which is always a class itself), covering every size the
+/// adaptive relay uses. Requests for other sizes are allocated and dropped
+/// as if pooling were off. At most `max_pooled` idle buffers are kept, so
+/// idle memory stays below `max_pooled * buffer_size`.
+///
+/// Returned buffers are cleared but not zeroed. This is sound because a
+/// `BytesMut` only exposes `[..len]`: reads fill the spare capacity through
+/// `read_buf`, which advances `len` by exactly the bytes written, so the next
+/// user can only ever observe bytes it wrote itself. Stale data from another
+/// connection stays in unreachable spare capacity and never leaves the
+/// process.
+pub struct BufferPool {
+    enabled: bool,
+    /// 升序的尺寸分级
+    classes: Vec<usize>,
+    max_pooled: usize,
+    /// shards[分片][分级]
+    shards: Vec<Mutex<Vec<Vec<BytesMut>>>>,
+    pooled: AtomicUsize,
+    outstanding: AtomicUsize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BufferPool {
+    pub fn new(buffer_size: usize, max_pooled: usize) -> Self {
+        let buffer_size = buffer_size.max(1);
+        let mut classes = Vec::new();
+        let mut size = MIN_POOLED_BUFFER_SIZE;
+        while size < buffer_size {
+            classes.push(size);
+            size *= 2;
+        }
+        classes.push(buffer_size);
+
+        Self {
+            enabled: true,
+            shards: (0..SHARDS).map(|_| Mutex::new(vec![Vec::new(); classes.len()])).collect(),
+            classes,
+            max_pooled,
+            pooled: AtomicUsize::new(0),
+            outstanding: AtomicUsize::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Pool that allocates every buffer and frees it on return, for debugging
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            ..Self::new(MIN_POOLED_BUFFER_SIZE, 0)
+        }
+    }
+
+    pub fn from_config(config: &PerformanceConfig) -> Self {
+        if config.buffer_pool {
+            Self::new(config.buffer_size, config.max_pooled_buffers)
+        } else {
+            Self::disabled()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn class_of(&self, size: usize) -> Option<usize> {
+        self.classes.iter().position(|&class| class == size)
+    }
+
+    /// Empty buffer with at least `size` bytes of capacity
+    pub fn get(&self, size: usize) -> BytesMut {
+        self.outstanding.fetch_add(1, Ordering::Relaxed);
+        if let Some(class) = self.class_of(size).filter(|_| self.enabled) {
+            let home = shard_index();
+            for i in 0..SHARDS {
+                let shard = &self.shards[(home + i) % SHARDS];
+                if let Some(buf) = shard.lock().unwrap()[class].pop() {
+                    self.pooled.fetch_sub(1, Ordering::Relaxed);
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return buf;
+                }
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        BytesMut::with_capacity(size)
+    }
+
+    /// Return a buffer obtained from `get`
+    ///
+    /// The buffer is kept for reuse when the pool has room and its full
+    /// capacity can be reclaimed without allocating; otherwise it is freed.
+    pub fn put(&self, mut buf: BytesMut) {
+        self.outstanding.fetch_sub(1, Ordering::Relaxed);
+        if !self.enabled {
+            return;
+        }
+        buf.clear();
+        // write_buf 推进后视图偏移，容量变小；收回整块分配再按实际容量归类
+        let Some(class) = (0..self.classes.len()).rev().find(|&class| buf.try_reclaim(self.classes[class])) else {
+            return;
+        };
+        let reserved = self
+            .pooled
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |pooled| {
+                (pooled < self.max_pooled).then_some(pooled + 1)
+            });
+        if reserved.is_ok() {
+            self.shards[shard_index()].lock().unwrap()[class].push(buf);
+        }
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            outstanding: self.outstanding.load(Ordering::Relaxed),
+            pooled: self.pooled.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::from_config(&PerformanceConfig::default())
+    }
+}
+
+/// Free list used by the current thread, assigned round-robin on first use
+fn shard_index() -> usize {
+    static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static SHARD: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+    SHARD.with(|shard| {
+        *shard.get().get_or_insert_with(|| NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % SHARDS)
+    })
+}
+
+static GLOBAL_BUFFER_POOL: OnceLock<BufferPool> = OnceLock::new();
+
+/// Initialize the global buffer pool from the performance settings
+pub fn init_global_buffer_pool(config: &PerformanceConfig) {
+    let _ = GLOBAL_BUFFER_POOL.set(BufferPool::from_config(config));
+}
+
+/// Get the global buffer pool (default settings when not initialized)
+pub fn get_global_buffer_pool() -> &'static BufferPool {
+    GLOBAL_BUFFER_POOL.get_or_init(BufferPool::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{Buf, BufMut};
+
+    #[test]
+    fn test_size_classes() {
+        let pool = BufferPool::new(64 * 1024, 4);
+        assert_eq!(pool.classes, [4096, 8192, 16384, 32768, 65536]);
+        let pool = BufferPool::new(10_000, 4);
+        assert_eq!(pool.classes, [4096, 8192, 10_000]);
+        let pool = BufferPool::new(1024, 4);
+        assert_eq!(pool.classes, [1024]);
+    }
+
+    #[test]
+    fn test_reuse_returns_cleared_buffer() {
+        let pool = BufferPool::new(8192, 4);
+        let mut buf = pool.get(8192);
+        buf.put_slice(b"secret from the previous connection");
+        // 模拟中继写出后推进了视图
+        buf.advance(7);
+        let ptr = buf.as_ptr() as usize - 7;
+        pool.put(buf);
+
+        let reused = pool.get(8192);
+        assert!(reused.is_empty());
+        assert!(reused.capacity() >= 8192);
+        assert_eq!(reused.as_ptr() as usize, ptr, "allocation was not reused");
+        assert_eq!(pool.stats(), BufferPoolStats { hits: 1, misses: 1, outstanding: 1, pooled: 0 });
+    }
+
+    #[test]
+    fn test_pool_is_bounded() {
+        let pool = BufferPool::new(8192, 2);
+        let bufs: Vec<BytesMut> = (0..5).map(|_| pool.get(4096)).collect();
+        assert_eq!(pool.stats().outstanding, 5);
+        for buf in bufs {
+            pool.put(buf);
+        }
+        let stats = pool.stats();
+        assert_eq!((stats.outstanding, stats.pooled, stats.misses), (0, 2, 5));
+
+        // 只有池中的两个能命中
+        let bufs: Vec<BytesMut> = (0..3).map(|_| pool.get(4096)).collect();
+        assert_eq!(pool.stats().hits, 2);
+        assert!(bufs.iter().all(|b| b.capacity() >= 4096));
+    }
+
+    #[test]
+    fn test_classes_kept_apart_and_odd_sizes_unpooled() {
+        let pool = BufferPool::new(16384, 8);
+        pool.put(pool.get(16384));
+        assert!(pool.get(4096).capacity() < 16384, "small request took a large buffer");
+        assert_eq!(pool.get(16384).capacity(), 16384);
+
+        let misses = pool.stats().misses;
+        pool.put(pool.get(5000));
+        pool.get(5000);
+        assert_eq!(pool.stats().misses, misses + 2);
+    }
+
+    #[test]
+    fn test_disabled_pool_never_reuses() {
+        let pool = BufferPool::disabled();
+        pool.put(pool.get(4096));
+        pool.get(4096);
+        let stats = pool.stats();
+        assert_eq!((stats.hits, stats.misses, stats.pooled), (0, 2, 0));
+    }
+}
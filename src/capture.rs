@@ -0,0 +1,622 @@
+//! Opt-in capture of relayed bytes for debugging protocol issues
+//!
+//! An armed filter (a client IP or a destination domain) flags matching new
+//! connections; their relay tees both directions into a capture directory
+//! `<dir>/<started_ms>-<id>/` holding `client_to_target.bin`,
+//! `target_to_client.bin` and a `meta.json` sidecar with the client, target,
+//! outbound and rule. Each direction stops at the filter's byte cap, and the
+//! number of concurrent captures and the disk space of all captures under
+//! `dir` are capped globally. [`replay`] sends a capture's client bytes to a
+//! target again.
+
+use crate::config::CaptureConfig;
+use crate::error::{ProxyError, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+pub const CLIENT_TO_TARGET_FILE: &str = "client_to_target.bin";
+pub const TARGET_TO_CLIENT_FILE: &str = "target_to_client.bin";
+pub const META_FILE: &str = "meta.json";
+
+/// Which connections to capture
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaptureFilter {
+    /// Connections from this client address
+    Client(IpAddr),
+    /// Connections to this domain or its subdomains
+    Domain(String),
+}
+
+impl FromStr for CaptureFilter {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(CaptureFilter::Client(ip));
+        }
+        let domain = s.trim_end_matches('.').to_ascii_lowercase();
+        if domain.is_empty() || domain.contains(['/', ':', ' ']) {
+            return Err(ProxyError::Protocol(format!("Invalid capture filter {:?}: expected a client IP or a domain", s)));
+        }
+        Ok(CaptureFilter::Domain(domain))
+    }
+}
+
+impl std::fmt::Display for CaptureFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CaptureFilter::Client(ip) => write!(f, "client {}", ip),
+            CaptureFilter::Domain(domain) => write!(f, "domain {}", domain),
+        }
+    }
+}
+
+impl CaptureFilter {
+    fn matches(&self, meta: &CaptureMeta) -> bool {
+        match self {
+            CaptureFilter::Client(ip) => meta.client.ip().to_canonical() == ip.to_canonical(),
+            CaptureFilter::Domain(domain) => meta.domain.as_deref().is_some_and(|target| {
+                let target = target.trim_end_matches('.').to_ascii_lowercase();
+                target == *domain || target.strip_suffix(domain.as_str()).is_some_and(|rest| rest.ends_with('.'))
+            }),
+        }
+    }
+}
+
+/// The connection a capture belongs to
+#[derive(Debug, Clone)]
+pub struct CaptureMeta {
+    pub client: SocketAddr,
+    /// Requested target as `host:port`
+    pub target: String,
+    /// Requested domain, None for IP targets
+    pub domain: Option<String>,
+    pub outbound: String,
+    /// Index of the routing rule that matched, None for the default outbound
+    pub rule: Option<usize>,
+}
+
+/// Contents of `meta.json`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CaptureRecord {
+    pub client: SocketAddr,
+    pub target: String,
+    pub outbound: String,
+    pub rule: Option<usize>,
+    /// Byte cap of each direction
+    pub max_bytes: u64,
+    pub started_at_ms: u64,
+    /// None while the connection is still being captured
+    pub ended_at_ms: Option<u64>,
+    pub client_to_target_bytes: u64,
+    pub target_to_client_bytes: u64,
+    /// Some bytes were not captured because a cap was reached
+    pub truncated: bool,
+}
+
+/// Direction of captured bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureDirection {
+    ClientToTarget,
+    TargetToClient,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CaptureStats {
+    pub armed: usize,
+    pub active: usize,
+    pub started: u64,
+    /// Matching connections not captured because `max_concurrent` were running
+    pub skipped_concurrency: u64,
+    /// Matching connections not captured because `max_total_bytes` was used up
+    pub skipped_disk: u64,
+    pub disk_used: u64,
+}
+
+/// Armed filters and the global caps
+pub struct CaptureManager {
+    config: CaptureConfig,
+    filters: Mutex<Vec<(CaptureFilter, u64)>>,
+    active: AtomicUsize,
+    disk_used: AtomicU64,
+    next_id: AtomicU64,
+    started: AtomicU64,
+    skipped_concurrency: AtomicU64,
+    skipped_disk: AtomicU64,
+}
+
+impl CaptureManager {
+    /// Create the manager, arming `config.filters`
+    ///
+    /// Captures already under `dir` count towards `max_total_bytes`.
+    pub fn new(config: &CaptureConfig) -> Result<Self> {
+        let filters = config
+            .filters
+            .iter()
+            .map(|filter| Ok((filter.parse()?, config.max_bytes)))
+            .collect::<Result<Vec<_>>>()?;
+        let disk_used = if config.enabled {
+            std::fs::create_dir_all(&config.dir)?;
+            dir_size(Path::new(&config.dir))
+        } else {
+            0
+        };
+        Ok(Self {
+            config: config.clone(),
+            filters: Mutex::new(filters),
+            active: AtomicUsize::new(0),
+            disk_used: AtomicU64::new(disk_used),
+            next_id: AtomicU64::new(1),
+            started: AtomicU64::new(0),
+            skipped_concurrency: AtomicU64::new(0),
+            skipped_disk: AtomicU64::new(0),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Capture future connections matching `filter`, up to `max_bytes` per
+    /// direction (`capture.max_bytes` when None)
+    pub fn arm(&self, filter: CaptureFilter, max_bytes: Option<u64>) -> Result<()> {
+        if !self.config.enabled {
+            return Err(ProxyError::Protocol("capture is disabled (capture.enabled = false)".to_string()));
+        }
+        let max_bytes = max_bytes.unwrap_or(self.config.max_bytes);
+        if max_bytes == 0 {
+            return Err(ProxyError::Protocol("capture max_bytes must be > 0".to_string()));
+        }
+        info!("Capture armed for {} (max {} bytes per direction)", filter, max_bytes);
+        let mut filters = self.filters.lock().unwrap();
+        filters.retain(|(armed, _)| *armed != filter);
+        filters.push((filter, max_bytes));
+        Ok(())
+    }
+
+    /// Stop flagging new connections for `filter`; running captures go on
+    pub fn disarm(&self, filter: &CaptureFilter) -> bool {
+        let mut filters = self.filters.lock().unwrap();
+        let before = filters.len();
+        filters.retain(|(armed, _)| armed != filter);
+        filters.len() != before
+    }
+
+    pub fn armed(&self) -> Vec<(CaptureFilter, u64)> {
+        self.filters.lock().unwrap().clone()
+    }
+
+    /// Start capturing the connection described by `meta` if a filter matches
+    /// and the global caps allow it
+    pub fn begin(self: &Arc<Self>, meta: CaptureMeta) -> Option<Arc<Capture>> {
+        if !self.config.enabled {
+            return None;
+        }
+        let max_bytes = self
+            .filters
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(filter, _)| filter.matches(&meta))
+            .map(|(_, max_bytes)| *max_bytes)?;
+
+        if self.disk_used.load(Ordering::Relaxed) >= self.config.max_total_bytes {
+            self.skipped_disk.fetch_add(1, Ordering::Relaxed);
+            warn!("Not capturing {} from {}: capture.max_total_bytes used up", meta.target, meta.client);
+            return None;
+        }
+        let reserved = self
+            .active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < self.config.max_concurrent).then_some(active + 1)
+            });
+        if reserved.is_err() {
+            self.skipped_concurrency.fetch_add(1, Ordering::Relaxed);
+            warn!("Not capturing {} from {}: {} captures running", meta.target, meta.client, self.config.max_concurrent);
+            return None;
+        }
+
+        let (target, client) = (meta.target.clone(), meta.client);
+        match Capture::create(self.clone(), meta, max_bytes) {
+            Ok(capture) => {
+                self.started.fetch_add(1, Ordering::Relaxed);
+                info!("Capturing {} from {} into {}", target, client, capture.dir.display());
+                Some(Arc::new(capture))
+            }
+            Err(e) => {
+                self.active.fetch_sub(1, Ordering::AcqRel);
+                warn!("Failed to start capture: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Take up to `wanted` bytes of the disk budget, returning how many were granted
+    fn reserve(&self, wanted: u64) -> u64 {
+        let mut granted = 0;
+        let _ = self.disk_used.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+            granted = wanted.min(self.config.max_total_bytes.saturating_sub(used));
+            Some(used + granted)
+        });
+        granted
+    }
+
+    pub fn stats(&self) -> CaptureStats {
+        CaptureStats {
+            armed: self.filters.lock().unwrap().len(),
+            active: self.active.load(Ordering::Relaxed),
+            started: self.started.load(Ordering::Relaxed),
+            skipped_concurrency: self.skipped_concurrency.load(Ordering::Relaxed),
+            skipped_disk: self.skipped_disk.load(Ordering::Relaxed),
+            disk_used: self.disk_used.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// One output file of a capture
+struct Sink {
+    /// None once the cap is reached or writing failed
+    file: Option<BufWriter<File>>,
+    written: u64,
+}
+
+/// Capture of one connection, finished when dropped
+pub struct Capture {
+    manager: Arc<CaptureManager>,
+    dir: PathBuf,
+    record: Mutex<CaptureRecord>,
+    client_to_target: Mutex<Sink>,
+    target_to_client: Mutex<Sink>,
+}
+
+impl Capture {
+    fn create(manager: Arc<CaptureManager>, meta: CaptureMeta, max_bytes: u64) -> Result<Self> {
+        let started_at_ms = unix_ms();
+        let id = manager.next_id.fetch_add(1, Ordering::Relaxed);
+        let dir = Path::new(&manager.config.dir).join(format!("{}-{}", started_at_ms, id));
+        std::fs::create_dir_all(&dir)?;
+        let sink = |name: &str| -> Result<Mutex<Sink>> {
+            let file = File::create(dir.join(name))?;
+            Ok(Mutex::new(Sink { file: Some(BufWriter::new(file)), written: 0 }))
+        };
+        let capture = Self {
+            client_to_target: sink(CLIENT_TO_TARGET_FILE)?,
+            target_to_client: sink(TARGET_TO_CLIENT_FILE)?,
+            record: Mutex::new(CaptureRecord {
+                client: meta.client,
+                target: meta.target,
+                outbound: meta.outbound,
+                rule: meta.rule,
+                max_bytes,
+                started_at_ms,
+                ended_at_ms: None,
+                client_to_target_bytes: 0,
+                target_to_client_bytes: 0,
+                truncated: false,
+            }),
+            manager,
+            dir,
+        };
+        capture.write_meta()?;
+        Ok(capture)
+    }
+
+    /// Directory holding this capture's files
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Append relayed bytes, up to the byte cap and the global disk budget
+    pub fn record(&self, direction: CaptureDirection, data: &[u8]) {
+        let sink = match direction {
+            CaptureDirection::ClientToTarget => &self.client_to_target,
+            CaptureDirection::TargetToClient => &self.target_to_client,
+        };
+        let mut sink = sink.lock().unwrap();
+        let Sink { file: Some(file), written } = &mut *sink else {
+            return;
+        };
+        let max_bytes = self.record.lock().unwrap().max_bytes;
+        let granted = self.manager.reserve((data.len() as u64).min(max_bytes - *written));
+        if let Err(e) = file.write_all(&data[..granted as usize]) {
+            warn!("Capture {}: write failed, stopping: {}", self.dir.display(), e);
+            sink.file = None;
+            return;
+        }
+        *written += granted;
+        if granted < data.len() as u64 {
+            // 达到上限后不再写入，已写内容立即刷盘
+            debug!("Capture {}: {:?} reached its cap at {} bytes", self.dir.display(), direction, written);
+            let _ = file.flush();
+            sink.file = None;
+            self.record.lock().unwrap().truncated = true;
+        }
+    }
+
+    fn write_meta(&self) -> Result<()> {
+        let json = serde_json::to_vec_pretty(&*self.record.lock().unwrap())
+            .map_err(|e| ProxyError::Protocol(format!("Failed to serialize capture metadata: {}", e)))?;
+        std::fs::write(self.dir.join(META_FILE), json)?;
+        Ok(())
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        let mut written = [0; 2];
+        for (sink, written) in [&self.client_to_target, &self.target_to_client].into_iter().zip(&mut written) {
+            let mut sink = sink.lock().unwrap();
+            if let Some(mut file) = sink.file.take() {
+                let _ = file.flush();
+            }
+            *written = sink.written;
+        }
+        {
+            let mut record = self.record.lock().unwrap();
+            record.client_to_target_bytes = written[0];
+            record.target_to_client_bytes = written[1];
+            record.ended_at_ms = Some(unix_ms());
+        }
+        if let Err(e) = self.write_meta() {
+            warn!("Capture {}: failed to write metadata: {}", self.dir.display(), e);
+        }
+        self.manager.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn unix_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// Bytes of all files under `path`
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(meta) if meta.is_dir() => dir_size(&entry.path()),
+            Ok(meta) => meta.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+static GLOBAL_CAPTURE: OnceLock<Arc<CaptureManager>> = OnceLock::new();
+
+/// Initialize the global capture manager when `capture.enabled` is set
+pub fn init_global_capture(config: &CaptureConfig) -> Result<()> {
+    if !config.enabled {
+        return Ok(());
+    }
+    let manager = CaptureManager::new(config)?;
+    info!("Traffic capture enabled into {} ({} filters armed)", config.dir, config.filters.len());
+    let _ = GLOBAL_CAPTURE.set(Arc::new(manager));
+    Ok(())
+}
+
+/// The global capture manager, None unless capture is enabled
+pub fn get_global_capture() -> Option<&'static Arc<CaptureManager>> {
+    GLOBAL_CAPTURE.get()
+}
+
+/// Outcome of [`replay`]
+#[derive(Debug, Clone)]
+pub struct ReplayReport {
+    pub target: String,
+    pub sent: u64,
+    /// Bytes the target sent back
+    pub received: Vec<u8>,
+    /// Bytes the target sent in the captured connection
+    pub recorded: u64,
+}
+
+impl std::fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "replayed to {}: sent {} bytes, received {} bytes (captured connection received {})",
+            self.target,
+            self.sent,
+            self.received.len(),
+            self.recorded
+        )
+    }
+}
+
+/// Send the client bytes of the capture in `dir` to `target` (the captured
+/// target when None) and collect the answer until the target closes or stays
+/// silent for `idle_timeout`
+pub async fn replay(dir: &Path, target: Option<&str>, idle_timeout: Duration) -> Result<ReplayReport> {
+    let meta = std::fs::read(dir.join(META_FILE))?;
+    let record: CaptureRecord = serde_json::from_slice(&meta)
+        .map_err(|e| ProxyError::Protocol(format!("Invalid {}: {}", dir.join(META_FILE).display(), e)))?;
+    let payload = std::fs::read(dir.join(CLIENT_TO_TARGET_FILE))?;
+    let target = target.unwrap_or(&record.target).to_string();
+
+    let mut stream = TcpStream::connect(&target).await?;
+    stream.write_all(&payload).await?;
+    stream.shutdown().await?;
+
+    let mut received = Vec::new();
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        match tokio::time::timeout(idle_timeout, stream.read(&mut buf)).await {
+            Ok(Ok(0)) | Err(_) => break,
+            Ok(Ok(n)) => received.extend_from_slice(&buf[..n]),
+            // 对端复位时保留已收到的数据
+            Ok(Err(e)) => {
+                debug!("Replay to {} ended: {}", target, e);
+                break;
+            }
+        }
+    }
+    Ok(ReplayReport { target, sent: payload.len() as u64, received, recorded: record.target_to_client_bytes })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zero_copy::ZeroCopyRelay;
+    use tokio::net::TcpListener;
+
+    fn test_config(name: &str, max_bytes: u64) -> CaptureConfig {
+        let dir = std::env::temp_dir().join(format!("anybls-capture-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        CaptureConfig {
+            enabled: true,
+            dir: dir.to_string_lossy().into_owned(),
+            filters: vec!["example.com".to_string()],
+            max_bytes,
+            ..CaptureConfig::default()
+        }
+    }
+
+    fn meta(client: &str, domain: &str) -> CaptureMeta {
+        CaptureMeta {
+            client: client.parse().unwrap(),
+            target: format!("{}:443", domain),
+            domain: Some(domain.to_string()),
+            outbound: "proxy".to_string(),
+            rule: Some(2),
+        }
+    }
+
+    async fn tcp_pair(listener: &TcpListener) -> (TcpStream, TcpStream) {
+        let connect = TcpStream::connect(listener.local_addr().unwrap());
+        let (accepted, connected) = tokio::join!(listener.accept(), connect);
+        (accepted.unwrap().0, connected.unwrap())
+    }
+
+    /// Relay `upload` from a client and `download` from a target through a
+    /// captured relay, returning the capture directory
+    async fn captured_relay(manager: &Arc<CaptureManager>, upload: &[u8], download: &[u8]) -> PathBuf {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (client_side, mut client) = tcp_pair(&listener).await;
+        let (target_side, mut target) = tcp_pair(&listener).await;
+
+        let capture = manager.begin(meta("192.0.2.1:50000", "www.example.com")).unwrap();
+        let dir = capture.dir().to_path_buf();
+        let relay = tokio::spawn(ZeroCopyRelay::new(client_side, target_side).with_capture(Some(capture)).start());
+
+        let response = download.to_vec();
+        let target_task = tokio::spawn(async move {
+            target.write_all(&response).await.unwrap();
+            target.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            target.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        client.write_all(upload).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(target_task.await.unwrap(), upload);
+        assert_eq!(received, download);
+        relay.await.unwrap().unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_capture_files_equal_relayed_bytes() {
+        let config = test_config("relay", 1 << 20);
+        let manager = Arc::new(CaptureManager::new(&config).unwrap());
+        let upload: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+        let download = b"HTTP/1.1 200 OK\r\n\r\nhello".repeat(100);
+
+        let dir = captured_relay(&manager, &upload, &download).await;
+        assert_eq!(std::fs::read(dir.join(CLIENT_TO_TARGET_FILE)).unwrap(), upload);
+        assert_eq!(std::fs::read(dir.join(TARGET_TO_CLIENT_FILE)).unwrap(), download);
+
+        let record: CaptureRecord = serde_json::from_slice(&std::fs::read(dir.join(META_FILE)).unwrap()).unwrap();
+        assert_eq!(record.target, "www.example.com:443");
+        assert_eq!((record.outbound.as_str(), record.rule), ("proxy", Some(2)));
+        assert_eq!(record.client_to_target_bytes, upload.len() as u64);
+        assert_eq!(record.target_to_client_bytes, download.len() as u64);
+        assert!(!record.truncated && record.ended_at_ms.is_some());
+        assert_eq!(manager.stats().active, 0);
+        std::fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_capture_stops_at_byte_cap() {
+        let config = test_config("cap", 1000);
+        let manager = Arc::new(CaptureManager::new(&config).unwrap());
+        let upload = vec![7u8; 64 * 1024];
+
+        let dir = captured_relay(&manager, &upload, b"ok").await;
+        assert_eq!(std::fs::read(dir.join(CLIENT_TO_TARGET_FILE)).unwrap(), upload[..1000]);
+        assert_eq!(std::fs::read(dir.join(TARGET_TO_CLIENT_FILE)).unwrap(), b"ok");
+        let record: CaptureRecord = serde_json::from_slice(&std::fs::read(dir.join(META_FILE)).unwrap()).unwrap();
+        assert!(record.truncated);
+        assert_eq!(manager.stats().disk_used, 1002);
+        std::fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn test_filters_and_global_caps() {
+        let mut config = test_config("caps", 100);
+        config.max_concurrent = 1;
+        config.max_total_bytes = 150;
+        let manager = Arc::new(CaptureManager::new(&config).unwrap());
+
+        assert!(manager.begin(meta("192.0.2.1:1", "example.org")).is_none());
+        assert!(manager.begin(meta("192.0.2.1:1", "notexample.com")).is_none());
+        let first = manager.begin(meta("192.0.2.1:1", "API.Example.com")).unwrap();
+        assert!(manager.begin(meta("192.0.2.1:2", "example.com")).is_none());
+        assert_eq!(manager.stats().skipped_concurrency, 1);
+
+        first.record(CaptureDirection::ClientToTarget, &[1; 100]);
+        first.record(CaptureDirection::TargetToClient, &[2; 100]);
+        drop(first);
+        assert_eq!(manager.stats().disk_used, 150);
+        assert!(manager.begin(meta("192.0.2.1:3", "example.com")).is_none());
+        assert_eq!(manager.stats().skipped_disk, 1);
+
+        manager.arm("198.51.100.9".parse().unwrap(), Some(10)).unwrap();
+        assert!(manager.disarm(&CaptureFilter::Domain("example.com".to_string())));
+        assert_eq!(manager.armed(), vec![(CaptureFilter::Client("198.51.100.9".parse().unwrap()), 10)]);
+        assert!("a/b".parse::<CaptureFilter>().is_err());
+
+        let disabled = CaptureManager::new(&CaptureConfig::default()).unwrap();
+        assert!(disabled.arm(CaptureFilter::Domain("example.com".to_string()), None).is_err());
+        std::fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replay_sends_client_bytes() {
+        let config = test_config("replay", 1 << 20);
+        let manager = Arc::new(CaptureManager::new(&config).unwrap());
+        let upload = b"GET / HTTP/1.1\r\nHost: www.example.com\r\n\r\n".to_vec();
+        let response = b"HTTP/1.1 204 No Content\r\n\r\n";
+        let dir = captured_relay(&manager, &upload, response).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            stream.read_to_end(&mut request).await.unwrap();
+            stream.write_all(b"pong").await.unwrap();
+            request
+        });
+
+        let report = replay(&dir, Some(&target), Duration::from_secs(5)).await.unwrap();
+        assert_eq!(server.await.unwrap(), upload);
+        assert_eq!((report.sent, report.received.as_slice(), report.recorded), (upload.len() as u64, &b"pong"[..], response.len() as u64));
+        std::fs::remove_dir_all(&config.dir).unwrap();
+    }
+}
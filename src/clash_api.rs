@@ -0,0 +1,564 @@
+// Clash 兼容的 REST API：列出活动连接、出站与路由规则，按 id 关闭连接，
+// 供 yacd 等面板使用；另可在运行中布设、撤下流量抓取过滤器。
+// 与 PAC、健康检查一样经独立的小型 HTTP 监听器提供
+use crate::capture::{get_global_capture, CaptureFilter, CaptureManager};
+use crate::config::{ClashApiConfig, Config};
+use crate::connection_registry::{get_global_connection_registry, ConnectionRegistry, ConnectionSnapshot};
+use crate::error::{ProxyError, Result};
+use crate::outbound::{get_global_outbound_manager, OutboundGroup, OutboundSource};
+use crate::pac::{read_request_head, write_response_with_headers, RequestHead, RouterSource};
+use crate::protocol::{Address, TargetAddr};
+use crate::routing::{get_global_router, RouteRule};
+use crate::tasks::{get_global_task_tracker, TaskGroup};
+use log::{debug, info, warn};
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Serves the API from the connection registry, outbound manager and router
+pub struct ClashApi {
+    secret: Option<String>,
+    allow_origins: Vec<String>,
+    registry: &'static ConnectionRegistry,
+    outbounds: OutboundSource,
+    router: RouterSource,
+    /// None when capture is disabled
+    capture: Option<Arc<CaptureManager>>,
+}
+
+impl ClashApi {
+    /// API over the global registry, outbounds and router
+    pub fn new(config: &ClashApiConfig) -> Self {
+        Self::with_sources(config, get_global_connection_registry(), Box::new(get_global_outbound_manager), Box::new(get_global_router))
+            .with_capture(get_global_capture().cloned())
+    }
+
+    pub fn with_sources(
+        config: &ClashApiConfig,
+        registry: &'static ConnectionRegistry,
+        outbounds: OutboundSource,
+        router: RouterSource,
+    ) -> Self {
+        Self {
+            secret: config.secret.clone(),
+            allow_origins: config.access_control_allow_origin.clone(),
+            registry,
+            outbounds,
+            router,
+            capture: None,
+        }
+    }
+
+    /// Arm and disarm capture filters of `capture` through `/capture`
+    pub fn with_capture(mut self, capture: Option<Arc<CaptureManager>>) -> Self {
+        self.capture = capture;
+        self
+    }
+
+    /// `GET /connections`: live connections and the traffic totals
+    pub fn connections(&self) -> Value {
+        let (upload_total, download_total) = self.registry.traffic_totals();
+        let now = SystemTime::now();
+        let mut connections: Vec<ConnectionSnapshot> = self.registry.connections().iter().map(|c| c.snapshot()).collect();
+        connections.sort_by_key(|c| c.id);
+        json!({
+            "downloadTotal": download_total,
+            "uploadTotal": upload_total,
+            "connections": connections.iter().map(|c| connection_json(c, now)).collect::<Vec<_>>(),
+        })
+    }
+
+    /// `GET /proxies`: every outbound and group by name
+    pub fn proxies(&self) -> Value {
+        let outbounds = (self.outbounds)();
+        let mut proxies = Map::new();
+        for name in outbounds.names() {
+            let proxy = match outbounds.group(name) {
+                Some(group) => json!({
+                    "name": name,
+                    "type": match group {
+                        OutboundGroup::Selector(_) => "Selector",
+                        OutboundGroup::UrlTest(_) => "URLTest",
+                    },
+                    "now": group.selected(),
+                    "all": group.members(),
+                    "history": [],
+                }),
+                None => {
+                    let kind = outbounds.get(name).map_or("Unknown", |connector| clash_type(connector.name()));
+                    json!({"name": name, "type": kind, "history": []})
+                }
+            };
+            proxies.insert(name.to_string(), proxy);
+        }
+        json!({ "proxies": proxies })
+    }
+
+    /// `GET /rules`: routing rules in match order, then the default outbound
+    pub fn rules(&self) -> Value {
+        let router = (self.router)();
+        let mut rules: Vec<Value> = router
+            .rules()
+            .iter()
+            .map(|rule| {
+                let (kind, payload) = rule_payload(rule);
+                json!({"type": kind, "payload": payload, "proxy": rule.outbound})
+            })
+            .collect();
+        rules.push(json!({"type": "Match", "payload": "", "proxy": router.default_outbound()}));
+        json!({ "rules": rules })
+    }
+
+    /// Close a connection; false when no live connection has that id
+    pub fn close(&self, id: u64) -> bool {
+        match self.registry.get(id) {
+            Some(connection) => {
+                connection.kill();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// `GET /capture`: armed capture filters and the capture counters
+    pub fn capture(&self) -> Value {
+        let Some(capture) = &self.capture else {
+            return json!({"enabled": false, "armed": []});
+        };
+        let armed: Vec<Value> = capture
+            .armed()
+            .iter()
+            .map(|(filter, max_bytes)| {
+                let (kind, value) = match filter {
+                    CaptureFilter::Client(ip) => ("client", ip.to_string()),
+                    CaptureFilter::Domain(domain) => ("domain", domain.clone()),
+                };
+                json!({"type": kind, "value": value, "maxBytes": max_bytes})
+            })
+            .collect();
+        let stats = capture.stats();
+        json!({
+            "enabled": true,
+            "armed": armed,
+            "active": stats.active,
+            "started": stats.started,
+            "diskUsed": stats.disk_used,
+        })
+    }
+
+    /// `PUT /capture/<client ip or domain>[?max_bytes=N]`: capture new
+    /// matching connections, replacing the cap of a filter already armed
+    pub fn arm_capture(&self, filter: &str, max_bytes: Option<&str>) -> Result<()> {
+        let capture = self
+            .capture
+            .as_ref()
+            .ok_or_else(|| ProxyError::Protocol("capture is disabled (capture.enabled = false)".to_string()))?;
+        let max_bytes = max_bytes
+            .map(|value| value.parse::<u64>().map_err(|_| ProxyError::Protocol(format!("Invalid max_bytes {:?}", value))))
+            .transpose()?;
+        capture.arm(filter.parse()?, max_bytes)
+    }
+
+    /// `DELETE /capture/<client ip or domain>`: stop flagging new connections;
+    /// false when the filter was not armed
+    pub fn disarm_capture(&self, filter: &str) -> bool {
+        let (Some(capture), Ok(filter)) = (&self.capture, filter.parse::<CaptureFilter>()) else {
+            return false;
+        };
+        capture.disarm(&filter)
+    }
+
+    fn authorized(&self, request: &RequestHead) -> bool {
+        let Some(secret) = &self.secret else {
+            return true;
+        };
+        let token = request.header("Authorization").and_then(|value| value.strip_prefix("Bearer "));
+        token.is_some_and(|token| secret_matches(token.as_bytes(), secret.as_bytes()))
+    }
+
+    /// CORS headers for a browser request from an allowed origin
+    fn cors_headers(&self, request: &RequestHead) -> Vec<(&'static str, String)> {
+        let Some(origin) = request.header("Origin") else {
+            return Vec::new();
+        };
+        let allowed = if self.allow_origins.iter().any(|o| o == "*") {
+            "*".to_string()
+        } else if self.allow_origins.iter().any(|o| o == origin) {
+            origin.to_string()
+        } else {
+            return Vec::new();
+        };
+        vec![("Access-Control-Allow-Origin", allowed), ("Vary", "Origin".to_string())]
+    }
+
+    async fn handle(&self, mut stream: TcpStream) -> Result<()> {
+        let Some(request) = read_request_head(&mut stream).await? else {
+            return Ok(());
+        };
+        let mut headers = self.cors_headers(&request);
+        let method = request.method.as_str();
+        if method == "OPTIONS" {
+            headers.push(("Access-Control-Allow-Methods", "GET, PUT, DELETE, OPTIONS".to_string()));
+            headers.push(("Access-Control-Allow-Headers", "Authorization, Content-Type".to_string()));
+            return write_response_with_headers(&mut stream, method, "204 No Content", "text/plain", &headers, "").await;
+        }
+        if !self.authorized(&request) {
+            let body = json!({"message": "Unauthorized"}).to_string();
+            return write_response_with_headers(&mut stream, method, "401 Unauthorized", "application/json", &headers, &body)
+                .await;
+        }
+
+        let path = request.path.as_str();
+        let (status, body) = match (method, path) {
+            ("GET", "/") => ("200 OK", json!({"hello": "clash"})),
+            ("GET", "/version") => ("200 OK", json!({"version": env!("CARGO_PKG_VERSION")})),
+            ("GET", "/connections") => ("200 OK", self.connections()),
+            ("DELETE", "/connections") => {
+                for connection in self.registry.connections() {
+                    connection.kill();
+                }
+                ("204 No Content", Value::Null)
+            }
+            ("GET", "/proxies") => ("200 OK", self.proxies()),
+            ("GET", "/rules") => ("200 OK", self.rules()),
+            ("DELETE", _) if path.starts_with("/connections/") => {
+                let id = path["/connections/".len()..].parse::<u64>().ok();
+                match id.is_some_and(|id| self.close(id)) {
+                    true => ("204 No Content", Value::Null),
+                    false => ("404 Not Found", json!({"message": "connection not found"})),
+                }
+            }
+            ("GET", "/capture") => ("200 OK", self.capture()),
+            ("PUT", _) if path.starts_with("/capture/") => {
+                match self.arm_capture(&path["/capture/".len()..], request.query_param("max_bytes")) {
+                    Ok(()) => ("204 No Content", Value::Null),
+                    Err(e) => ("400 Bad Request", json!({"message": e.to_string()})),
+                }
+            }
+            ("DELETE", _) if path.starts_with("/capture/") => match self.disarm_capture(&path["/capture/".len()..]) {
+                true => ("204 No Content", Value::Null),
+                false => ("404 Not Found", json!({"message": "capture filter not armed"})),
+            },
+            (_, "/" | "/version" | "/connections" | "/proxies" | "/rules" | "/capture") => {
+                ("405 Method Not Allowed", json!({"message": "method not allowed"}))
+            }
+            _ if path.starts_with("/connections/") || path.starts_with("/capture/") => {
+                ("405 Method Not Allowed", json!({"message": "method not allowed"}))
+            }
+            _ => ("404 Not Found", json!({"message": "not found"})),
+        };
+        let body = if body.is_null() { String::new() } else { body.to_string() };
+        write_response_with_headers(&mut stream, method, status, "application/json", &headers, &body).await
+    }
+}
+
+/// One entry of `GET /connections`
+fn connection_json(connection: &ConnectionSnapshot, now: SystemTime) -> Value {
+    // 目标为域名时填 host，为IP时填 destinationIP
+    let target = connection.target.as_deref().and_then(|t| t.parse::<TargetAddr>().ok());
+    let (host, destination_ip, destination_port) = match &target {
+        Some(TargetAddr { address: Address::Domain(domain), port }) => (domain.clone(), String::new(), port.to_string()),
+        Some(target) => {
+            let ip = target.socket_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+            (String::new(), ip, target.port.to_string())
+        }
+        None => (String::new(), String::new(), String::new()),
+    };
+    json!({
+        "id": connection.id.to_string(),
+        "metadata": {
+            "network": "tcp",
+            "type": "SOCKS5",
+            "sourceIP": connection.client.ip().to_string(),
+            "sourcePort": connection.client.port().to_string(),
+            "destinationIP": destination_ip,
+            "destinationPort": destination_port,
+            "host": host,
+        },
+        "upload": connection.upload,
+        "download": connection.download,
+        "start": rfc3339(now.checked_sub(connection.age).unwrap_or(now)),
+        "chains": connection.outbound.iter().collect::<Vec<_>>(),
+    })
+}
+
+/// Clash's name for an outbound protocol
+fn clash_type(protocol: &str) -> &'static str {
+    match protocol {
+        "direct" => "Direct",
+        "blackhole" | "disabled" => "Reject",
+        "socks5" => "Socks5",
+        "http" => "Http",
+        "shadowsocks" => "Shadowsocks",
+        "vless" => "Vless",
+        _ => "Unknown",
+    }
+}
+
+/// Compare a presented token with the secret without stopping at the first difference
+/// Clash rule type and payload: rule sets, or the first other condition of a rule without any
+fn rule_payload(rule: &RouteRule) -> (&'static str, String) {
+    if !rule.rule_sets.is_empty() {
+        return ("RuleSet", rule.rule_sets.join(","));
+    }
+    let sources: Vec<String> = rule.source_ip_cidr.iter().map(ToString::to_string).collect();
+    let conditions = [
+        ("GeoIP", rule.ip_geoip.iter().map(|code| code.to_ascii_uppercase()).collect()),
+        ("DstPort", rule.port_labels()),
+        ("SrcIPCIDR", sources),
+        ("InName", rule.inbound.clone()),
+        ("Network", rule.network.iter().map(ToString::to_string).collect()),
+    ];
+    conditions
+        .into_iter()
+        .find(|(_, values)| !values.is_empty())
+        .map_or(("RuleSet", String::new()), |(kind, values)| (kind, values.join(",")))
+}
+
+fn secret_matches(token: &[u8], secret: &[u8]) -> bool {
+    token.len() == secret.len() && token.iter().zip(secret).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// UTC timestamp with milliseconds, e.g. "2024-05-01T12:00:00.250Z"
+fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+    // 公历日期换算（Howard Hinnant 的 civil_from_days）
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Accept loop of the API listener
+pub async fn serve_clash_api(listener: TcpListener, api: Arc<ClashApi>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Clash API listener accept failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let api = api.clone();
+        tokio::spawn(async move {
+            if let Err(e) = api.handle(stream).await {
+                debug!("Clash API request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Bind `clash_api.listen` and serve the API when `clash_api.enabled`
+pub async fn start_clash_api(config: &Config) -> Result<()> {
+    let api = &config.clash_api;
+    if !api.enabled {
+        return Ok(());
+    }
+    let listener = TcpListener::bind(api.listen).await?;
+    let local_addr = listener.local_addr()?;
+    info!("Serving Clash API at http://{}", local_addr);
+    if api.secret.is_none() && !local_addr.ip().is_loopback() {
+        warn!("Clash API on {} has no secret; anyone who can reach it can list and close connections", local_addr);
+    }
+    get_global_task_tracker().spawn(TaskGroup::Listeners, serve_clash_api(listener, Arc::new(ClashApi::new(api))))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{CaptureDirection, CaptureMeta, CLIENT_TO_TARGET_FILE};
+    use crate::config::{CaptureConfig, OutboundConfig, OutboundType};
+    use crate::outbound::OutboundManager;
+    use crate::routing::{HighPerformanceRouter, RouteRule};
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn outbounds() -> OutboundSource {
+        let configs = [
+            OutboundConfig { kind: OutboundType::Socks5 { address: "127.0.0.1:1080".to_string() }, ..OutboundConfig::direct("proxy") },
+            OutboundConfig {
+                kind: OutboundType::Selector { outbounds: vec!["proxy".to_string(), "direct".to_string()], default: None },
+                ..OutboundConfig::direct("auto")
+            },
+        ];
+        let manager = Arc::new(OutboundManager::from_configs(&configs).unwrap());
+        Box::new(move || manager.clone())
+    }
+
+    async fn serve(registry: &'static ConnectionRegistry, capture: Option<Arc<CaptureManager>>) -> SocketAddr {
+        let config = ClashApiConfig {
+            secret: Some("s3cret".to_string()),
+            access_control_allow_origin: vec!["http://dashboard.test".to_string()],
+            ..ClashApiConfig::default()
+        };
+        let mut router = HighPerformanceRouter::new("direct".to_string());
+        router.add_rule(RouteRule::builder("auto").rule_set("streaming").build());
+        router.add_rule(RouteRule::builder("block").port(25).port_range(465..=587).build());
+        let router = Arc::new(router);
+        let api = ClashApi::with_sources(&config, registry, outbounds(), Box::new(move || router.clone())).with_capture(capture);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_clash_api(listener, Arc::new(api)));
+        addr
+    }
+
+    /// Send a request and return the status line, the head and the body
+    async fn request(addr: SocketAddr, method: &str, path: &str, headers: &str) -> (String, String, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(format!("{} {} HTTP/1.1\r\nHost: api\r\n{}\r\n", method, path, headers).as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), head.to_string(), body.to_string())
+    }
+
+    const AUTH: &str = "Authorization: Bearer s3cret\r\n";
+
+    #[tokio::test]
+    async fn test_lists_and_closes_connections() {
+        let registry: &'static ConnectionRegistry = Box::leak(Box::new(ConnectionRegistry::new()));
+        let addr = serve(registry, None).await;
+        let guard = registry.register("192.0.2.7:40000".parse().unwrap());
+        guard.set_target("example.com:443");
+        guard.set_outbound("proxy");
+        guard.add_upload(10);
+        guard.add_download(20);
+
+        let (status, _, _) = request(addr, "GET", "/connections", "").await;
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+        let (status, _, _) = request(addr, "GET", "/connections", "Authorization: Bearer wrong\r\n").await;
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+
+        let (status, _, body) = request(addr, "GET", "/connections", AUTH).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["uploadTotal"], 10);
+        let connection = &body["connections"][0];
+        assert_eq!(connection["id"], guard.id().to_string());
+        assert_eq!(connection["metadata"]["sourceIP"], "192.0.2.7");
+        assert_eq!(connection["metadata"]["host"], "example.com");
+        assert_eq!(connection["metadata"]["destinationPort"], "443");
+        assert_eq!((connection["upload"].as_u64(), connection["download"].as_u64()), (Some(10), Some(20)));
+        assert_eq!(connection["chains"], json!(["proxy"]));
+
+        let (status, _, _) = request(addr, "DELETE", &format!("/connections/{}", guard.id()), AUTH).await;
+        assert_eq!(status, "HTTP/1.1 204 No Content");
+        assert!(guard.is_killed());
+        let (status, _, _) = request(addr, "DELETE", "/connections/999999", AUTH).await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+        let (status, _, _) = request(addr, "POST", "/connections", AUTH).await;
+        assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+    }
+
+    #[tokio::test]
+    async fn test_lists_proxies_and_rules() {
+        let addr = serve(Box::leak(Box::new(ConnectionRegistry::new())), None).await;
+
+        let (_, _, body) = request(addr, "GET", "/proxies", AUTH).await;
+        let proxies: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(proxies["proxies"]["proxy"]["type"], "Socks5");
+        assert_eq!(proxies["proxies"]["direct"]["type"], "Direct");
+        assert_eq!(proxies["proxies"]["block"]["type"], "Reject");
+        let group = &proxies["proxies"]["auto"];
+        assert_eq!((group["type"].as_str(), group["now"].as_str()), (Some("Selector"), Some("proxy")));
+        assert_eq!(group["all"], json!(["proxy", "direct"]));
+
+        let (_, _, body) = request(addr, "GET", "/rules", AUTH).await;
+        let rules: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            rules["rules"],
+            json!([
+                {"type": "RuleSet", "payload": "streaming", "proxy": "auto"},
+                {"type": "DstPort", "payload": "25,465-587", "proxy": "block"},
+                {"type": "Match", "payload": "", "proxy": "direct"},
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cors_for_allowed_origins_only() {
+        let addr = serve(Box::leak(Box::new(ConnectionRegistry::new())), None).await;
+
+        // 预检请求不带令牌
+        let (status, head, _) = request(addr, "OPTIONS", "/connections", "Origin: http://dashboard.test\r\n").await;
+        assert_eq!(status, "HTTP/1.1 204 No Content");
+        assert!(head.contains("Access-Control-Allow-Origin: http://dashboard.test"), "{}", head);
+        assert!(head.contains("Access-Control-Allow-Headers: Authorization"), "{}", head);
+
+        let (_, head, _) = request(addr, "GET", "/version", &format!("{}Origin: http://dashboard.test\r\n", AUTH)).await;
+        assert!(head.contains("Access-Control-Allow-Origin: http://dashboard.test"), "{}", head);
+        let (status, head, _) = request(addr, "GET", "/version", &format!("{}Origin: http://evil.test\r\n", AUTH)).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(!head.contains("Access-Control-Allow-Origin"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn test_capture_armed_at_runtime() {
+        let dir = std::env::temp_dir().join(format!("anybls-clash-capture-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = CaptureConfig { enabled: true, dir: dir.to_string_lossy().into_owned(), ..CaptureConfig::default() };
+        let manager = Arc::new(CaptureManager::new(&config).unwrap());
+        let addr = serve(Box::leak(Box::new(ConnectionRegistry::new())), Some(manager.clone())).await;
+        let meta = |client: &str, domain: &str| CaptureMeta {
+            client: client.parse().unwrap(),
+            target: format!("{}:443", domain),
+            domain: Some(domain.to_string()),
+            outbound: "direct".to_string(),
+            rule: None,
+        };
+        assert!(manager.begin(meta("192.0.2.7:40000", "example.com")).is_none());
+
+        // 运行中布设过滤器，之后的新连接才开始抓取
+        let (status, _, _) = request(addr, "PUT", "/capture/192.0.2.7?max_bytes=4", AUTH).await;
+        assert_eq!(status, "HTTP/1.1 204 No Content");
+        let (status, _, body) = request(addr, "PUT", "/capture/not/a/filter", AUTH).await;
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+        assert!(body.contains("Invalid capture filter"), "{}", body);
+        let (_, _, body) = request(addr, "GET", "/capture", AUTH).await;
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["armed"], json!([{"type": "client", "value": "192.0.2.7", "maxBytes": 4}]));
+
+        let captured = manager.begin(meta("192.0.2.7:40001", "example.com")).unwrap();
+        assert!(manager.begin(meta("198.51.100.1:40002", "example.com")).is_none());
+        captured.record(CaptureDirection::ClientToTarget, b"hello");
+        let captured_dir = captured.dir().to_path_buf();
+        drop(captured);
+        let written: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(written, vec![captured_dir.clone()]);
+        assert_eq!(std::fs::read(captured_dir.join(CLIENT_TO_TARGET_FILE)).unwrap(), b"hell");
+
+        let (status, _, _) = request(addr, "DELETE", "/capture/192.0.2.7", AUTH).await;
+        assert_eq!(status, "HTTP/1.1 204 No Content");
+        assert!(manager.begin(meta("192.0.2.7:40003", "example.com")).is_none());
+        let (status, _, _) = request(addr, "DELETE", "/capture/192.0.2.7", AUTH).await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(rfc3339(UNIX_EPOCH + Duration::from_millis(951_827_696_789)), "2000-02-29T12:34:56.789Z");
+        assert_eq!(rfc3339(UNIX_EPOCH + Duration::from_secs(4_107_542_400)), "2100-03-01T00:00:00.000Z");
+    }
+}
@@ -1,10 +1,27 @@
+pub use crate::config_builder::ConfigBuilder;
 use crate::error::{ProxyError, Result};
 use crate::routing::rule_sets::RuleSetId;
-use log::info;
+use crate::rule_set_downloader::{DownloadLimits, DEFAULT_MAX_DOWNLOAD_BYTES, DEFAULT_MAX_REDIRECTS};
+use crate::scope::ScopedIp;
+use crate::endpoint::{parse_server_address, PortStrategy};
+use crate::inbound::InboundKind;
+use crate::integrity::{PublicKey, SignatureSource};
+use crate::protocol::Address;
+use crate::outbound::UrlTestTarget;
+use crate::protocols::{
+    BlackholeProtocol, DirectProtocol, HttpProtocol, OutboundCapabilities, ShadowsocksCipher, ShadowsocksProtocol,
+    Socks5Protocol, VlessProtocol,
+};
+use crate::tls_fragment::TlsFragmentConfig;
+use crate::traffic_mark::{validate_dscp, validate_tcp_mss, LingerPolicy};
+use ipnet::{IpNet, Ipv4Net};
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::Path;
+use std::sync::OnceLock;
 use std::time::Duration;
 
 /// Configuration for the SOCKS5 proxy server
@@ -12,6 +29,9 @@ use std::time::Duration;
 pub struct Config {
     /// Server configuration
     pub server: ServerConfig,
+    /// Listeners to start; when empty, `server.host`/`server.port` is a SOCKS5 inbound
+    #[serde(default)]
+    pub inbounds: Vec<InboundConfig>,
     /// Connection pool configuration
     pub connection_pool: ConnectionPoolConfig,
     /// DNS configuration
@@ -26,26 +46,249 @@ pub struct Config {
     /// Outbound configurations
     pub outbounds: Vec<OutboundConfig>,
 
+    /// What to do when an outbound cannot be built at startup
+    #[serde(default)]
+    pub on_outbound_error: OutboundErrorPolicy,
+
+    /// Rule sets that routing rules reference by tag
+    #[serde(default)]
+    pub rule_sets: Vec<RuleSetConfig>,
+
     /// Router configuration
     pub router: RouterConfig,
 
+    /// Named routing profiles that inbounds bind to instead of `[router]`
+    #[serde(default)]
+    pub profiles: HashMap<String, RoutingProfileConfig>,
+
     /// High-performance router configuration
     pub high_performance_router: HighPerformanceRouterConfig,
+
+    /// Slow-connection watchdog configuration
+    #[serde(default)]
+    pub watchdog: WatchdogConfig,
+
+    /// Forwarding loop protection
+    #[serde(default)]
+    pub loop_protection: LoopProtectionConfig,
+
+    /// DNS rebinding protection
+    #[serde(default)]
+    pub rebinding_protection: RebindingProtectionConfig,
+
+    /// Fast failure for direct connects to recently unreachable addresses
+    #[serde(default)]
+    pub negative_cache: NegativeCacheConfig,
+
+    /// Proxy auto-config (PAC) file generated from the routing table
+    #[serde(default)]
+    pub pac: PacConfig,
+
+    /// Opt-in capture of relayed bytes for debugging
+    #[serde(default)]
+    pub capture: CaptureConfig,
+
+    /// Reporting of connections refused by blackhole outbounds
+    #[serde(default)]
+    pub blocked: BlockedConfig,
+
+    /// Liveness and readiness endpoints for process supervisors
+    #[serde(default)]
+    pub health: HealthConfig,
+
+    /// Clash-compatible REST API for dashboards
+    #[serde(default)]
+    pub clash_api: ClashApiConfig,
+}
+
+/// Syntax of a configuration file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    /// sing-box layout in RON
+    Ron,
+    /// sing-box JSON
+    Json,
+}
+
+impl ConfigFormat {
+    /// By the `.toml`, `.ron` or `.json` extension, otherwise by how the
+    /// content starts: `{` is JSON, `(`, `//` or `#![` is RON, anything
+    /// else TOML
+    pub fn detect(path: &Path, content: &str) -> Self {
+        let extension = path.extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("toml") => return Self::Toml,
+            Some("ron") => return Self::Ron,
+            Some("json") => return Self::Json,
+            _ => {}
+        }
+        let content = content.trim_start_matches('\u{feff}').trim_start();
+        if content.starts_with('{') {
+            Self::Json
+        } else if content.starts_with('(') || content.starts_with("//") || content.starts_with("#![") {
+            Self::Ron
+        } else {
+            Self::Toml
+        }
+    }
+}
+
+/// A listener (`[[inbounds]]`)
+///
+/// Authentication, SO_LINGER and the other `[server]` settings apply to it
+/// as well.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InboundConfig {
+    /// Name of the inbound in logs and in rules' `inbound` condition
+    pub tag: String,
+    #[serde(rename = "type")]
+    pub kind: InboundKind,
+    /// Host to bind to
+    pub listen: ScopedIp,
+    pub port: u16,
+    /// Routing profile of this inbound; None uses `server.profile`
+    #[serde(default)]
+    pub profile: Option<String>,
+}
+
+impl InboundConfig {
+    pub fn bind_addr(&self) -> SocketAddr {
+        self.listen.socket_addr(self.port)
+    }
 }
 
 /// Server configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
-    /// Host to bind to
-    pub host: IpAddr,
+    /// Host to bind to; IPv6 link-local addresses take a zone ("fe80::1%eth0")
+    pub host: ScopedIp,
     /// Port to listen on
     pub port: u16,
     /// Maximum number of concurrent connections
     pub max_connections: usize,
+    /// At `max_connections`, refuse new clients right away (SOCKS5 reply
+    /// 0x01) instead of leaving them queued until a connection ends
+    #[serde(default)]
+    pub reject_when_full: bool,
     /// Connection timeout
     pub connection_timeout_secs: u64,
     /// Keep-alive timeout
     pub keep_alive_timeout_secs: u64,
+    /// Longest a client may spend in any phase before the relay (greeting,
+    /// auth, request); connecting is allowed at least `connection_timeout_secs`.
+    /// 0 disables the limit
+    #[serde(default = "default_handshake_timeout_secs")]
+    pub handshake_timeout_secs: u64,
+    /// How long to keep retrying when the listen address is in use
+    #[serde(default)]
+    pub bind_retry_secs: u64,
+    /// SOCKS5 username -> outbound; the username picks the egress for that connection
+    #[serde(default)]
+    pub user_routing: HashMap<String, String>,
+    /// Let clients force an outbound with an `outbound=<name>` token in the SOCKS5 username
+    #[serde(default)]
+    pub allow_client_outbound_selection: bool,
+    /// SOCKS5 authentication of the listener
+    #[serde(default)]
+    pub auth: SocksAuthConfig,
+    /// Route and resolve every request but never dial: log the decision and
+    /// reply `dry_run_reply` instead
+    #[serde(default)]
+    pub dry_run: bool,
+    /// SOCKS5 REP code sent to clients in dry-run mode
+    #[serde(default = "default_dry_run_reply")]
+    pub dry_run_reply: u8,
+    /// Answer client probes (CONNECT to 0.0.0.0 or ::) with success without dialing
+    #[serde(default = "default_answer_probe_requests")]
+    pub answer_probe_requests: bool,
+    /// Routing profile of the listener; None routes with `[router]`
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// SO_LINGER of accepted client connections
+    #[serde(default)]
+    pub linger: LingerPolicy,
+    /// New upstream connections per second across all inbounds; None is unlimited
+    #[serde(default)]
+    pub max_new_connections_per_sec: Option<u32>,
+    /// What happens to a connection over the global or an outbound's rate
+    #[serde(default)]
+    pub connection_rate_policy: ConnectionRatePolicy,
+    /// Longest a connection is held back by `Delay` before it is rejected
+    #[serde(default = "default_max_connection_queue_delay_ms")]
+    pub max_connection_queue_delay_ms: u64,
+}
+
+/// Handling of connections over a connection rate limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionRatePolicy {
+    /// Hold the connect back until the bucket refills, up to `max_connection_queue_delay_ms`
+    #[default]
+    Delay,
+    /// Refuse the connection with SOCKS5 REP 0x01
+    Reject,
+}
+
+fn default_max_connection_queue_delay_ms() -> u64 {
+    1000
+}
+
+fn default_handshake_timeout_secs() -> u64 {
+    10
+}
+
+fn default_dry_run_reply() -> u8 {
+    // connection not allowed by ruleset
+    0x02
+}
+
+fn default_answer_probe_requests() -> bool {
+    true
+}
+
+/// SOCKS5 authentication of an inbound
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SocksAuthConfig {
+    pub mode: SocksAuthMode,
+    /// Username -> password
+    pub users: HashMap<String, String>,
+    /// Source networks allowed without credentials on a `password` inbound
+    pub no_auth_source_cidrs: Vec<String>,
+}
+
+/// Whether an inbound requires credentials
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SocksAuthMode {
+    /// No credentials needed; usernames are only routing hints
+    #[default]
+    None,
+    /// Username and password checked against `users`
+    Password,
+}
+
+impl SocksAuthConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.mode == SocksAuthMode::Password && self.users.is_empty() {
+            return Err(ProxyError::Protocol("auth mode \"password\" requires at least one user".to_string()));
+        }
+        if let Some(user) = self.users.keys().find(|user| user.is_empty() || user.len() > 255) {
+            return Err(ProxyError::Protocol(format!("auth user {:?} must be 1-255 bytes long", user)));
+        }
+        if let Some(user) = self.users.iter().find(|(_, password)| password.is_empty() || password.len() > 255) {
+            return Err(ProxyError::Protocol(format!("auth user {:?}: password must be 1-255 bytes long", user.0)));
+        }
+        for cidr in &self.no_auth_source_cidrs {
+            cidr.parse::<IpNet>()
+                .map_err(|e| ProxyError::Protocol(format!("Invalid no_auth_source_cidrs entry {}: {}", cidr, e)))?;
+        }
+        if self.mode == SocksAuthMode::None && !self.no_auth_source_cidrs.is_empty() {
+            warn!("no_auth_source_cidrs has no effect without auth mode \"password\"");
+        }
+        Ok(())
+    }
 }
 
 /// Connection pool configuration
@@ -61,6 +304,68 @@ pub struct ConnectionPoolConfig {
     pub idle_timeout_secs: u64,
     /// Cleanup interval
     pub cleanup_interval_secs: u64,
+    /// What a new connection does when max_total_connections are in use
+    #[serde(default)]
+    pub on_exhausted: OnExhausted,
+    /// Longest wait for a free slot with `on_exhausted = "wait_timeout"`
+    #[serde(default = "default_pool_wait_timeout_ms")]
+    pub wait_timeout_ms: u64,
+    /// Adjust the idle timeout per target from how pooled connections fare
+    #[serde(default)]
+    pub auto_tune: PoolAutoTuneConfig,
+    /// Keep the outbound connection open when a client closes first and hand it
+    /// to the next client for the same outbound and target
+    #[serde(default)]
+    pub reuse_outbound_connections: bool,
+}
+
+/// Per-target idle timeout tuning
+///
+/// After every `window` reported first exchanges with a target, its idle
+/// timeout is halved when more than `max_doa_rate` of the pooled connections
+/// were dead on arrival, and doubled when none were but fewer than
+/// `min_reuse_rate` of the checkouts came from the pool. The result stays
+/// within `min_idle_timeout_secs` and `max_idle_timeout_secs`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PoolAutoTuneConfig {
+    pub enabled: bool,
+    pub min_idle_timeout_secs: u64,
+    pub max_idle_timeout_secs: u64,
+    /// First exchanges observed per target before each adjustment
+    pub window: u32,
+    pub max_doa_rate: f64,
+    pub min_reuse_rate: f64,
+}
+
+impl Default for PoolAutoTuneConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_idle_timeout_secs: 5,
+            max_idle_timeout_secs: 900,
+            window: 20,
+            max_doa_rate: 0.1,
+            min_reuse_rate: 0.5,
+        }
+    }
+}
+
+/// Connection pool behavior when every connection slot is taken
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnExhausted {
+    /// Queue until a slot frees up
+    #[default]
+    Wait,
+    /// Queue for at most `wait_timeout_ms`
+    WaitTimeout,
+    /// Fail immediately
+    Reject,
+}
+
+fn default_pool_wait_timeout_ms() -> u64 {
+    1000
 }
 
 /// DNS configuration
@@ -74,6 +379,111 @@ pub struct DnsConfig {
     pub enable_ipv6: bool,
     /// Cache TTL
     pub cache_ttl_secs: u64,
+    /// Behaviour when every server in the chain fails
+    #[serde(default)]
+    pub on_failure: DnsFailurePolicy,
+    /// Maximum age of an answer returned under `serve_stale`
+    #[serde(default = "default_serve_stale_max_secs")]
+    pub serve_stale_max_secs: u64,
+    /// Log every query and keep per-domain resolution statistics
+    #[serde(default)]
+    pub query_log: bool,
+    /// Domains tracked by the statistics before the least recent is dropped
+    #[serde(default = "default_dns_stats_max_domains")]
+    pub stats_max_domains: usize,
+    /// Refresh popular domains in the background before their answers expire
+    #[serde(default)]
+    pub prefetch: DnsPrefetchConfig,
+    /// EDNS Client Subnet (e.g. "203.0.113.0/24") attached to outgoing queries,
+    /// so CDNs answer for that network rather than for the resolver
+    #[serde(default)]
+    pub client_subnet: Option<String>,
+    /// Answer `dns` inbound queries with addresses from a reserved pool
+    #[serde(default)]
+    pub fake_ip: FakeIpConfig,
+}
+
+/// DNS prefetch settings
+///
+/// The resolver counts lookups per domain in a bounded top-K table; domains
+/// with at least `min_hits` lookups are re-resolved shortly before their
+/// cached answer expires, so connections keep hitting a warm cache.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DnsPrefetchConfig {
+    pub enabled: bool,
+    /// Domains tracked by the popularity table
+    pub top_k: usize,
+    /// Lookups a domain needs before it is prefetched
+    pub min_hits: u64,
+    /// Refreshes running at the same time
+    pub concurrency: usize,
+}
+
+impl Default for DnsPrefetchConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            top_k: 256,
+            min_hits: 3,
+            concurrency: 4,
+        }
+    }
+}
+
+/// Fake-IP mode of the `dns` inbound
+///
+/// Every A query is answered with an address from `inet4_range` that stands
+/// for the queried domain. When a transparent (tproxy or redirect) or SOCKS5
+/// connection arrives for such an address, the domain is looked up again, so
+/// domain rules apply and the outbound connects to the domain's real address.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FakeIpConfig {
+    pub enabled: bool,
+    /// Pool the fake addresses are taken from
+    pub inet4_range: Ipv4Net,
+    /// How long a mapping is kept after its last query or connection
+    pub ttl_secs: u64,
+    /// Domain suffixes answered with their real addresses instead
+    pub exclude_domains: Vec<String>,
+    /// File the mappings are saved to and restored from across restarts
+    pub store_path: Option<String>,
+}
+
+impl Default for FakeIpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            inet4_range: Ipv4Net::new(Ipv4Addr::new(198, 18, 0, 0), 15).expect("valid prefix"),
+            ttl_secs: 600,
+            exclude_domains: Vec::new(),
+            store_path: None,
+        }
+    }
+}
+
+/// Response to a query that no configured DNS server could answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsFailurePolicy {
+    /// Report the resolution failure to the client
+    #[default]
+    Fail,
+    /// Answer from the last known good result if it is recent enough
+    ServeStale,
+}
+
+fn default_serve_stale_max_secs() -> u64 {
+    3600
+}
+
+fn default_dns_stats_max_domains() -> usize {
+    crate::dns_stats::DEFAULT_MAX_DOMAINS
+}
+
+fn default_keep_log_files() -> usize {
+    7
 }
 
 /// Logging configuration
@@ -85,8 +495,79 @@ pub struct LoggingConfig {
     pub structured: bool,
     /// Log file path (optional)
     pub file: Option<String>,
+    /// Rotate the log file once it reaches this many MB (0 = never)
+    #[serde(default, alias = "max_size_mb")]
+    pub rotate_size_mb: u64,
+    /// Also rotate the log file every day at this UTC hour (0-23)
+    #[serde(default)]
+    pub rotate_daily: Option<u8>,
+    /// Rotated log files kept
+    #[serde(default = "default_keep_log_files", alias = "max_files")]
+    pub keep_files: usize,
+    /// Gzip rotated log files
+    #[serde(default)]
+    pub compress: bool,
     /// Enable performance metrics
     pub enable_metrics: bool,
+    /// Per-connection access log
+    #[serde(default)]
+    pub access_log: AccessLogConfig,
+}
+
+/// Access log settings
+///
+/// Records go through a bounded queue to a writer task; when the queue is
+/// full they are dropped and counted instead of slowing connections down.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessLogConfig {
+    pub enabled: bool,
+    /// Log one in every N successful connections (1 logs all); failures and
+    /// slow connections are always logged
+    pub sample_rate: u32,
+    /// Connections whose target took longer than this to connect count as slow (0 disables)
+    pub slow_threshold_ms: u64,
+    /// Leave the client port out of records
+    pub drop_client_port: bool,
+    /// Replace the client IP with a salted hash
+    pub hash_client_ip: bool,
+    /// Salt for `hash_client_ip`
+    pub ip_hash_salt: String,
+    /// Records waiting for the writer before new ones are dropped
+    pub queue_size: usize,
+    /// Write records to this file, rotated like `logging.file`, instead of
+    /// the main log
+    pub file: Option<String>,
+    pub format: AccessLogFormat,
+}
+
+/// `logging.access_log.format`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// `key=value` pairs
+    #[default]
+    Text,
+    /// One JSON object per record
+    Json,
+    /// Tab-separated columns in a fixed order
+    Tsv,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sample_rate: 1,
+            slow_threshold_ms: 1000,
+            drop_client_port: false,
+            hash_client_ip: false,
+            ip_hash_salt: String::new(),
+            queue_size: 1024,
+            file: None,
+            format: AccessLogFormat::Text,
+        }
+    }
 }
 
 /// Performance configuration
@@ -94,14 +575,88 @@ pub struct LoggingConfig {
 pub struct PerformanceConfig {
     /// Buffer size for zero-copy operations
     pub buffer_size: usize,
+    /// Start relay buffers small and grow them up to `buffer_size` under load
+    #[serde(default = "default_adaptive_buffers")]
+    pub adaptive_buffers: bool,
+    /// Reuse relay buffers across connections instead of allocating per connection
+    #[serde(default = "default_buffer_pool")]
+    pub buffer_pool: bool,
+    /// Idle buffers kept in the pool
+    #[serde(default = "default_max_pooled_buffers")]
+    pub max_pooled_buffers: usize,
     /// Enable TCP_NODELAY
     pub tcp_nodelay: bool,
     /// Enable SO_REUSEADDR
     pub reuse_addr: bool,
+    /// Enable SO_REUSEPORT on listeners (Linux only)
+    #[serde(default)]
+    pub reuse_port: bool,
+    /// Listening sockets per inbound, bound with SO_REUSEPORT so the kernel
+    /// spreads accepts across independent accept loops (Linux only)
+    #[serde(default = "default_listener_shards")]
+    pub listener_shards: usize,
     /// Enable SO_KEEPALIVE
     pub keep_alive: bool,
+    /// TCP_USER_TIMEOUT for outbound sockets (Linux only)
+    #[serde(default)]
+    pub tcp_user_timeout_secs: Option<u64>,
+    /// Abort a relay when a write has been blocked this long
+    #[serde(default)]
+    pub write_stall_secs: Option<u64>,
+    /// End a relay when neither direction has moved a byte for this long
+    /// (disabled when unset)
+    #[serde(default)]
+    pub relay_idle_timeout_secs: Option<u64>,
     /// Worker thread count (0 for auto)
     pub worker_threads: usize,
+    /// Copy loop used by relays: a fixed backend, or `auto` to take the first
+    /// available one in `relay_backend_order`
+    #[serde(default)]
+    pub relay_backend: RelayBackendMode,
+    /// Preference order of `auto`; the buffered loop is the final fallback
+    #[serde(default = "default_relay_backend_order")]
+    pub relay_backend_order: Vec<RelayBackend>,
+    /// Threads of the io_uring relay runtime, each with its own ring
+    #[serde(default = "default_uring_threads")]
+    pub uring_threads: usize,
+}
+
+/// Implementation of the relay copy loops
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayBackend {
+    /// io_uring read/write submissions on a dedicated thread pool (Linux,
+    /// built with the `io-uring` feature)
+    Uring,
+    /// splice(2) through a pipe per direction, bytes never leave the kernel
+    /// (Linux)
+    Splice,
+    /// tokio reads and writes through a per-direction buffer
+    Buffered,
+}
+
+impl std::fmt::Display for RelayBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RelayBackend::Uring => f.write_str("uring"),
+            RelayBackend::Splice => f.write_str("splice"),
+            RelayBackend::Buffered => f.write_str("buffered"),
+        }
+    }
+}
+
+/// `performance.relay_backend`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayBackendMode {
+    /// First available backend of `relay_backend_order`
+    #[default]
+    Auto,
+    /// io_uring when available, buffered otherwise
+    Uring,
+    /// splice when available, buffered otherwise
+    Splice,
+    Buffered,
 }
 
 /// Traffic marking configuration
@@ -113,18 +668,258 @@ pub struct TrafficMarkConfig {
     pub net_service_type: u32,
 }
 
+/// Slow-connection watchdog configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WatchdogConfig {
+    /// Scan interval (0 to disable)
+    pub interval_secs: u64,
+    /// Report connections open this long without transferring any bytes
+    pub no_traffic_secs: u64,
+    /// Report connections with no bytes in either direction for this long
+    pub idle_secs: u64,
+    /// Kill connections idle for this long (disabled when unset)
+    pub stall_kill_secs: Option<u64>,
+}
+
+/// Forwarding loop protection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoopProtectionConfig {
+    /// Refuse connections routed back to one of our own listeners
+    pub enabled: bool,
+    /// Listener addresses that may deliberately be reached through the
+    /// proxy (hairpin setups)
+    pub allow: Vec<SocketAddr>,
+}
+
+/// DNS rebinding protection
+///
+/// A domain resolving to a loopback, private, link-local or shared (CGNAT)
+/// address would let clients reach the local network through the proxy.
+/// Domains that legitimately live there go in `internal_domains`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RebindingProtectionConfig {
+    /// Refuse domains outside `internal_domains` that resolve to internal addresses
+    pub block_private_resolution_for_public_domains: bool,
+    /// Domains allowed to resolve to internal addresses
+    pub internal_domains: DomainLists,
+    /// Handling of answers that mix public and internal addresses
+    pub strictness: RebindingStrictness,
+}
+
+/// Handling of answers that mix public and internal addresses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RebindingStrictness {
+    /// Refuse the connection when any address is internal
+    #[default]
+    RejectAny,
+    /// Connect to the public addresses only; refuse when none is left
+    DropInternal,
+}
+
+/// Negative connection cache of the direct outbound
+///
+/// Addresses whose connect timed out fail immediately for `ttl_secs` instead
+/// of waiting out the connect timeout again. Refused connections are never
+/// cached.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NegativeCacheConfig {
+    /// How long a timed-out address fails fast (0 to disable)
+    pub ttl_secs: u64,
+    /// Addresses remembered at most; the oldest is forgotten first
+    pub max_entries: usize,
+}
+
+/// PAC file served over HTTP for clients that cannot route per domain
+///
+/// Domains the router sends through a non-direct outbound get the SOCKS5
+/// listener, everything else goes `DIRECT`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PacConfig {
+    /// Serve the PAC file on `listen`
+    pub enabled: bool,
+    pub listen: SocketAddr,
+    /// URL path of the PAC file
+    pub path: String,
+    /// SOCKS5 address written into the script; defaults to the listener's
+    /// address, with loopback in place of an unspecified host
+    pub proxy: Option<String>,
+    /// Cap on the script size; rules past it are left to the proxy
+    pub max_bytes: usize,
+}
+
+impl Default for PacConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: SocketAddr::from(([127, 0, 0, 1], 8090)),
+            path: "/proxy.pac".to_string(),
+            proxy: None,
+            max_bytes: 512 * 1024,
+        }
+    }
+}
+
+/// Capture of relayed bytes for debugging protocol issues
+///
+/// Connections from an armed client IP or to an armed domain have both
+/// directions written to files under `dir`, see [`crate::capture`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CaptureConfig {
+    /// Compiled in, but nothing is captured unless this is set
+    pub enabled: bool,
+    pub dir: String,
+    /// Client IPs or domains armed at startup
+    pub filters: Vec<String>,
+    /// Bytes kept per direction of one connection, unless a filter sets its own
+    pub max_bytes: u64,
+    /// Connections captured at the same time
+    pub max_concurrent: usize,
+    /// Disk space all captures under `dir` may use together
+    pub max_total_bytes: u64,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: "captures".to_string(),
+            filters: Vec::new(),
+            max_bytes: 1024 * 1024,
+            max_concurrent: 4,
+            max_total_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// Blocked-traffic reporting
+///
+/// Connections routed to a blackhole outbound are counted per domain (or IP)
+/// and per rule; the domain table keeps the most recently blocked entries.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BlockedConfig {
+    /// Domains tracked before the least recently blocked is dropped
+    pub max_domains: usize,
+    /// Log a summary of blocked traffic this often (0 to disable)
+    pub summary_interval_secs: u64,
+    /// Log each blocked connection at trace instead of info level
+    pub quiet: bool,
+}
+
+impl Default for BlockedConfig {
+    fn default() -> Self {
+        Self {
+            max_domains: 1024,
+            summary_interval_secs: 0,
+            quiet: false,
+        }
+    }
+}
+
+/// HTTP endpoint for liveness (`/healthz`) and readiness (`/readyz`) probes
+///
+/// `/healthz` passes when the inbounds accept connections, DNS resolves
+/// `dns_probe_domain` and every critical outbound is reachable; `/readyz`
+/// also needs the rule sets loaded. Each check must finish within `timeout_ms`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// Serve the endpoints on `listen`
+    pub enabled: bool,
+    pub listen: SocketAddr,
+    /// Resolve `dns_probe_domain` as part of the check
+    pub dns_check: bool,
+    pub dns_probe_domain: String,
+    /// Outbounds that must be reachable; proxy outbounds are checked by
+    /// connecting to their server
+    pub critical_outbounds: Vec<String>,
+    /// Target connected to through critical outbounds without a server of
+    /// their own, such as direct ones; those pass unchecked without it
+    pub outbound_probe_target: Option<SocketAddr>,
+    pub timeout_ms: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: SocketAddr::from(([127, 0, 0, 1], 8091)),
+            dns_check: true,
+            dns_probe_domain: "example.com".to_string(),
+            critical_outbounds: Vec::new(),
+            outbound_probe_target: None,
+            timeout_ms: 2000,
+        }
+    }
+}
+
+/// Clash-compatible REST API
+///
+/// Lists live connections, outbounds and routing rules, and closes
+/// connections by id. With `secret` set, requests must carry it as a bearer
+/// token. Browsers at the listed origins may call the API (`*` for any).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClashApiConfig {
+    /// Serve the API on `listen`
+    pub enabled: bool,
+    pub listen: SocketAddr,
+    pub secret: Option<String>,
+    pub access_control_allow_origin: Vec<String>,
+}
+
+impl Default for ClashApiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: SocketAddr::from(([127, 0, 0, 1], 9090)),
+            secret: None,
+            access_control_allow_origin: Vec::new(),
+        }
+    }
+}
+
+impl Default for NegativeCacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 10,
+            max_entries: 4096,
+        }
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
             server: ServerConfig::default(),
+            inbounds: Vec::new(),
             connection_pool: ConnectionPoolConfig::default(),
             dns: DnsConfig::default(),
             logging: LoggingConfig::default(),
             performance: PerformanceConfig::default(),
             traffic_mark: TrafficMarkConfig::default(),
             outbounds: vec![OutboundConfig::direct("direct")],
+            on_outbound_error: OutboundErrorPolicy::default(),
+            rule_sets: Vec::new(),
             router: RouterConfig::default(),
+            profiles: HashMap::new(),
             high_performance_router: HighPerformanceRouterConfig::default(),
+            watchdog: WatchdogConfig::default(),
+            loop_protection: LoopProtectionConfig::default(),
+            rebinding_protection: RebindingProtectionConfig::default(),
+            negative_cache: NegativeCacheConfig::default(),
+            pac: PacConfig::default(),
+            capture: CaptureConfig::default(),
+            blocked: BlockedConfig::default(),
+            health: HealthConfig::default(),
+            clash_api: ClashApiConfig::default(),
         }
     }
 }
@@ -132,11 +927,25 @@ impl Default for Config {
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
-            host: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            host: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)).into(),
             port: 1080,
             max_connections: 1000,
+            reject_when_full: false,
             connection_timeout_secs: 30,
             keep_alive_timeout_secs: 300,
+            handshake_timeout_secs: default_handshake_timeout_secs(),
+            bind_retry_secs: 0,
+            user_routing: HashMap::new(),
+            allow_client_outbound_selection: false,
+            auth: SocksAuthConfig::default(),
+            dry_run: false,
+            dry_run_reply: default_dry_run_reply(),
+            answer_probe_requests: default_answer_probe_requests(),
+            profile: None,
+            linger: LingerPolicy::default(),
+            max_new_connections_per_sec: None,
+            connection_rate_policy: ConnectionRatePolicy::default(),
+            max_connection_queue_delay_ms: default_max_connection_queue_delay_ms(),
         }
     }
 }
@@ -149,6 +958,10 @@ impl Default for ConnectionPoolConfig {
             connection_timeout_secs: 10,
             idle_timeout_secs: 300,
             cleanup_interval_secs: 60,
+            on_exhausted: OnExhausted::default(),
+            wait_timeout_ms: default_pool_wait_timeout_ms(),
+            auto_tune: PoolAutoTuneConfig::default(),
+            reuse_outbound_connections: false,
         }
     }
 }
@@ -164,6 +977,13 @@ impl Default for DnsConfig {
             timeout_secs: 5,
             enable_ipv6: true,
             cache_ttl_secs: 300,
+            on_failure: DnsFailurePolicy::Fail,
+            serve_stale_max_secs: default_serve_stale_max_secs(),
+            query_log: false,
+            stats_max_domains: default_dns_stats_max_domains(),
+            prefetch: DnsPrefetchConfig::default(),
+            client_subnet: None,
+            fake_ip: FakeIpConfig::default(),
         }
     }
 }
@@ -174,7 +994,12 @@ impl Default for LoggingConfig {
             level: "info".to_string(),
             structured: false,
             file: None,
+            rotate_size_mb: 0,
+            rotate_daily: None,
+            keep_files: default_keep_log_files(),
+            compress: false,
             enable_metrics: false,
+            access_log: AccessLogConfig::default(),
         }
     }
 }
@@ -183,73 +1008,921 @@ impl Default for PerformanceConfig {
     fn default() -> Self {
         Self {
             buffer_size: 65536, // 64KB
+            adaptive_buffers: true,
+            buffer_pool: true,
+            max_pooled_buffers: crate::buffer_pool::DEFAULT_MAX_POOLED_BUFFERS,
             tcp_nodelay: true,
             reuse_addr: true,
+            reuse_port: false,
+            listener_shards: 1,
             keep_alive: true,
+            tcp_user_timeout_secs: None,
+            write_stall_secs: None,
+            relay_idle_timeout_secs: None,
             worker_threads: 0, // Auto-detect
+            relay_backend: RelayBackendMode::Auto,
+            relay_backend_order: default_relay_backend_order(),
+            uring_threads: default_uring_threads(),
+        }
+    }
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 30,
+            no_traffic_secs: 30,
+            idle_secs: 300,
+            stall_kill_secs: None,
+        }
+    }
+}
+
+impl Default for LoopProtectionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            allow: Vec::new(),
+        }
+    }
+}
+
+fn default_adaptive_buffers() -> bool {
+    true
+}
+
+fn default_buffer_pool() -> bool {
+    true
+}
+
+fn default_max_pooled_buffers() -> usize {
+    crate::buffer_pool::DEFAULT_MAX_POOLED_BUFFERS
+}
+
+fn default_listener_shards() -> usize {
+    1
+}
+
+fn default_relay_backend_order() -> Vec<RelayBackend> {
+    vec![RelayBackend::Splice, RelayBackend::Uring, RelayBackend::Buffered]
+}
+
+fn default_uring_threads() -> usize {
+    1
+}
+
+fn default_tls_session_cache_size() -> usize {
+    crate::tls::DEFAULT_SESSION_CACHE_SIZE
+}
+
+impl Default for TrafficMarkConfig {
+    fn default() -> Self {
+        Self {
+            so_mark: 0, // Disabled by default
+            net_service_type: 0, // Disabled by default
+        }
+    }
+}
+
+/// Handling of outbounds that fail to build
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutboundErrorPolicy {
+    /// Refuse to start
+    #[default]
+    Fail,
+    /// Start without the broken outbound; connections routed to it are refused
+    Disable,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum OutboundType {
+    Direct,
+    Socks5 { address: String },
+    Http {
+        address: String,
+        /// Host header sent with CONNECT instead of the target address
+        #[serde(default)]
+        override_host_header: Option<String>,
+    },
+    /// Shadowsocks AEAD server
+    Shadowsocks {
+        address: String,
+        /// aes-128-gcm, aes-256-gcm or chacha20-ietf-poly1305
+        method: String,
+        password: String,
+    },
+    Vless {
+        address: String,
+        uuid: String,
+        tls: bool,
+        /// Name used for SNI and certificate verification
+        #[serde(default)]
+        server_name: Option<String>,
+        /// SNI presented instead of `server_name` (domain fronting)
+        #[serde(default)]
+        override_sni: Option<String>,
+        /// Host header sent on HTTP upgrades instead of the server name
+        #[serde(default)]
+        override_host_header: Option<String>,
+        /// Skip certificate verification
+        #[serde(default)]
+        insecure: bool,
+        /// TLS sessions cached for resumption (0 disables resumption)
+        #[serde(default = "default_tls_session_cache_size")]
+        session_cache_size: usize,
+        /// Send TLS 1.3 early data (0-RTT) on resumed sessions
+        #[serde(default)]
+        early_data: bool,
+    },
+    Blackhole,
+    /// Group that routes through one of its member outbounds
+    Selector {
+        outbounds: Vec<String>,
+        /// Selected member (defaults to the first one)
+        #[serde(default)]
+        default: Option<String>,
+    },
+    /// Group that routes through the member answering `url` fastest
+    UrlTest {
+        outbounds: Vec<String>,
+        /// Probed through every member each `interval_secs`
+        #[serde(default = "default_url_test_url")]
+        url: String,
+        #[serde(default = "default_url_test_interval_secs")]
+        interval_secs: u64,
+        /// Latency gain needed to leave the current member
+        #[serde(default = "default_url_test_tolerance_ms")]
+        tolerance_ms: u64,
+    },
+}
+
+pub(crate) fn default_url_test_url() -> String {
+    "https://www.gstatic.com/generate_204".to_string()
+}
+
+pub(crate) fn default_url_test_interval_secs() -> u64 {
+    180
+}
+
+pub(crate) fn default_url_test_tolerance_ms() -> u64 {
+    50
+}
+
+/// Outbounds that exist implicitly unless a user outbound reuses the name
+pub const BUILTIN_OUTBOUNDS: &[(&str, OutboundType)] = &[
+    ("direct", OutboundType::Direct),
+    ("block", OutboundType::Blackhole),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboundConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: OutboundType,
+    /// Overrides `performance.tcp_user_timeout_secs` for this outbound
+    #[serde(default)]
+    pub tcp_user_timeout_secs: Option<u64>,
+    /// Carry UDP through the upstream's TCP stream (sing-box UoT v2)
+    #[serde(default)]
+    pub udp_over_tcp: bool,
+    /// DSCP code point (0-63) for connections through this outbound
+    #[serde(default)]
+    pub dscp: Option<u8>,
+    /// Interactive traffic: TCP_NODELAY on both sockets, TCP_QUICKACK and no
+    /// relay write coalescing for connections through this outbound
+    #[serde(default)]
+    pub latency_mode: bool,
+    /// Client subnet sent with DNS queries for domains routed to this outbound,
+    /// instead of `dns.client_subnet`
+    #[serde(default)]
+    pub egress_hint_subnet: Option<String>,
+    /// Linux SO_MARK for connections through this outbound, instead of `traffic_mark.so_mark`
+    #[serde(default)]
+    pub routing_mark: Option<u32>,
+    /// TCP_MAXSEG clamp for connections through this outbound, for paths
+    /// where full-size segments are lost (PMTU blackholes)
+    #[serde(default)]
+    pub tcp_mss: Option<u16>,
+    /// Server ports to pick from, instead of a port in `address`
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    /// How the server port is picked when several are configured
+    #[serde(default)]
+    pub port_strategy: PortStrategy,
+    /// Split the first TLS ClientHello sent through this outbound
+    #[serde(default)]
+    pub tls_fragment: TlsFragmentConfig,
+    /// SO_LINGER of connections through this outbound
+    #[serde(default)]
+    pub linger: LingerPolicy,
+    /// New connections per second through this outbound; health checks are not counted
+    #[serde(default)]
+    pub max_connections_per_sec: Option<u32>,
+}
+
+impl OutboundConfig {
+    pub fn direct(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: OutboundType::Direct,
+            tcp_user_timeout_secs: None,
+            udp_over_tcp: false,
+            dscp: None,
+            latency_mode: false,
+            egress_hint_subnet: None,
+            routing_mark: None,
+            tcp_mss: None,
+            ports: Vec::new(),
+            port_strategy: PortStrategy::default(),
+            tls_fragment: TlsFragmentConfig::default(),
+            linger: LingerPolicy::default(),
+            max_connections_per_sec: None,
+        }
+    }
+
+    /// Outbounds referenced by this one (group members)
+    pub fn members(&self) -> &[String] {
+        match &self.kind {
+            OutboundType::Selector { outbounds, .. } | OutboundType::UrlTest { outbounds, .. } => outbounds,
+            _ => &[],
         }
     }
+
+    /// Capabilities of the connector built for this outbound, `None` for groups
+    pub fn capabilities(&self) -> Option<OutboundCapabilities> {
+        self.kind.capabilities(self.udp_over_tcp)
+    }
+}
+
+impl OutboundType {
+    /// Capabilities of the connector built for this type, `None` for groups
+    pub fn capabilities(&self, udp_over_tcp: bool) -> Option<OutboundCapabilities> {
+        Some(match self {
+            OutboundType::Direct => DirectProtocol::CAPABILITIES,
+            OutboundType::Blackhole => BlackholeProtocol::CAPABILITIES,
+            OutboundType::Socks5 { .. } => Socks5Protocol::capabilities_with(udp_over_tcp),
+            OutboundType::Http { .. } => HttpProtocol::CAPABILITIES,
+            OutboundType::Shadowsocks { .. } => ShadowsocksProtocol::CAPABILITIES,
+            OutboundType::Vless { .. } => VlessProtocol::capabilities_with(udp_over_tcp),
+            OutboundType::Selector { .. } | OutboundType::UrlTest { .. } => return None,
+        })
+    }
+}
+
+/// Capabilities of the outbound or built-in `name`, groups promising only what all members can do
+///
+/// Like [`crate::outbound::OutboundManager::capabilities`], but from the
+/// config alone; `outbounds` must have passed [`validate_outbound_graph`].
+pub fn outbound_capabilities(name: &str, outbounds: &[OutboundConfig]) -> Option<OutboundCapabilities> {
+    match outbounds.iter().find(|o| o.name == name) {
+        Some(outbound) => outbound.capabilities().or_else(|| {
+            OutboundCapabilities::of_group(outbound.members().iter().filter_map(|member| outbound_capabilities(member, outbounds)))
+        }),
+        None => BUILTIN_OUTBOUNDS.iter().find(|(builtin, _)| *builtin == name)?.1.capabilities(false),
+    }
+}
+
+/// Check `udp_over_tcp` against what the outbound can do
+///
+/// Fails when the outbound cannot carry UDP even over UoT; returns a warning
+/// when it carries UDP natively already, so the flag changes nothing.
+fn check_udp_over_tcp(outbound: &OutboundConfig, outbounds: &[OutboundConfig]) -> Result<Option<String>> {
+    if !outbound.udp_over_tcp {
+        return Ok(None);
+    }
+    // 组本身不承载连接，看成员各自的能力
+    let native = match outbound.kind.capabilities(false) {
+        Some(native) => native,
+        None => outbound_capabilities(&outbound.name, outbounds).unwrap_or_default(),
+    };
+    if native.supports_udp {
+        return Ok(Some(format!(
+            "Outbound {}: udp_over_tcp has no effect, it already supports UDP natively",
+            outbound.name
+        )));
+    }
+    let with_uot = outbound.capabilities().unwrap_or(native);
+    if !with_uot.supports_udp {
+        return Err(ProxyError::Protocol(match native.is_group {
+            true => format!(
+                "Outbound group {}: udp_over_tcp set but not all members can carry UDP; set it on the members",
+                outbound.name
+            ),
+            false => format!("Outbound {}: udp_over_tcp set but it cannot carry UDP", outbound.name),
+        }));
+    }
+    Ok(None)
+}
+
+/// Prefix a validation error with the config item it came from
+fn prefixed(prefix: String, e: ProxyError) -> ProxyError {
+    match e {
+        ProxyError::Protocol(msg) => ProxyError::Protocol(format!("{}: {}", prefix, msg)),
+        other => other,
+    }
+}
+
+/// Check outbound names and group references
+///
+/// Names must be unique, group members must name an outbound or a built-in,
+/// and groups may nest but not form a cycle.
+pub fn validate_outbound_graph(outbounds: &[OutboundConfig]) -> Result<()> {
+    let mut by_name: HashMap<&str, &OutboundConfig> = HashMap::new();
+    for outbound in outbounds {
+        if by_name.insert(outbound.name.as_str(), outbound).is_some() {
+            return Err(ProxyError::Protocol(format!("Duplicate outbound name: {}", outbound.name)));
+        }
+    }
+    let exists = |name: &str| by_name.contains_key(name) || is_builtin_outbound(name);
+
+    for outbound in outbounds {
+        if matches!(outbound.kind, OutboundType::Selector { .. } | OutboundType::UrlTest { .. })
+            && outbound.members().is_empty()
+        {
+            return Err(ProxyError::Protocol(format!("Outbound group {} has no members", outbound.name)));
+        }
+        if let OutboundType::UrlTest { url, interval_secs, .. } = &outbound.kind {
+            UrlTestTarget::parse(url).map_err(|e| prefixed(format!("Outbound group {}", outbound.name), e))?;
+            if *interval_secs == 0 {
+                return Err(ProxyError::Protocol(format!("Outbound group {}: interval_secs must be positive", outbound.name)));
+            }
+        }
+        if let OutboundType::Selector { outbounds: members, default: Some(default) } = &outbound.kind {
+            if !members.contains(default) {
+                return Err(ProxyError::Protocol(format!(
+                    "Outbound group {}: default {} is not a member",
+                    outbound.name, default
+                )));
+            }
+        }
+        for member in outbound.members() {
+            if !exists(member) {
+                return Err(ProxyError::Protocol(format!(
+                    "Outbound group {} references unknown outbound {}",
+                    outbound.name, member
+                )));
+            }
+        }
+    }
+
+    // 深度优先检测组之间的循环引用
+    fn visit<'a>(
+        name: &'a str,
+        by_name: &HashMap<&'a str, &'a OutboundConfig>,
+        path: &mut Vec<&'a str>,
+        done: &mut HashSet<&'a str>,
+    ) -> Result<()> {
+        if let Some(start) = path.iter().position(|n| *n == name) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(name);
+            return Err(ProxyError::Protocol(format!("Outbound group cycle: {}", cycle.join(" -> "))));
+        }
+        if done.contains(name) {
+            return Ok(());
+        }
+        if let Some(outbound) = by_name.get(name) {
+            path.push(name);
+            for member in outbound.members() {
+                visit(member, by_name, path, done)?;
+            }
+            path.pop();
+        }
+        done.insert(name);
+        Ok(())
+    }
+
+    let mut done = HashSet::new();
+    for outbound in outbounds {
+        visit(&outbound.name, &by_name, &mut Vec::new(), &mut done)?;
+    }
+    Ok(())
+}
+
+/// Whether `name` is one of the implicit built-in outbounds
+pub fn is_builtin_outbound(name: &str) -> bool {
+    BUILTIN_OUTBOUNDS.iter().any(|(builtin, _)| *builtin == name)
+}
+
+/// Check a `reject` or `reject-drop` rule: it connects nowhere, so it takes no outbound settings
+fn validate_rejecting_rule(rule: &RouterRuleConfig, action: RouteAction) -> Result<()> {
+    let outbound_set = !rule.outbound.is_empty() && rule.outbound != action.as_str();
+    if outbound_set || !rule.outbound_chain.is_empty() || rule.rewrite_to.is_some() {
+        return Err(ProxyError::Protocol(format!(
+            "Rule with action {} must not set outbound, outbound_chain or rewrite_to",
+            action.as_str()
+        )));
+    }
+    Ok(())
+}
+
+/// Check a rule's `outbound_chain` (hops are known to exist)
+///
+/// The chain replaces `outbound` and holds at most `max_len` hops. Hops after
+/// the first are reached through a tunnel, so they must be socks5 or http
+/// outbounds, and no two hops may end up on the same outbound, also through
+/// groups, or the chain would loop through itself.
+fn validate_outbound_chain(rule: &RouterRuleConfig, max_len: usize, outbounds: &[OutboundConfig]) -> Result<()> {
+    let hops = &rule.outbound_chain;
+    if hops.is_empty() {
+        return if rule.outbound.is_empty() {
+            Err(ProxyError::Protocol("Rule needs an outbound or an outbound_chain".to_string()))
+        } else {
+            Ok(())
+        };
+    }
+    let label = chain_label(hops);
+    if !rule.outbound.is_empty() {
+        return Err(ProxyError::Protocol(format!(
+            "Rule for {} sets both outbound and outbound_chain ({})",
+            rule.outbound, label
+        )));
+    }
+    if hops.len() > max_len {
+        return Err(ProxyError::Protocol(format!(
+            "Outbound chain {} has {} hops, at most {} allowed (router.max_chain_length)",
+            label,
+            hops.len(),
+            max_len
+        )));
+    }
+
+    // 展开出站组，得到每一跳可能落到的出站及其能力
+    fn members<'a>(name: &'a str, outbounds: &'a [OutboundConfig], found: &mut Vec<(&'a str, OutboundCapabilities)>) {
+        if found.iter().any(|(seen, _)| *seen == name) {
+            return;
+        }
+        match outbounds.iter().find(|o| o.name == name) {
+            Some(outbound) => match outbound.capabilities() {
+                Some(capabilities) => found.push((name, capabilities)),
+                None => {
+                    for member in outbound.members() {
+                        members(member, outbounds, found);
+                    }
+                }
+            },
+            None => found.extend(outbound_capabilities(name, outbounds).map(|capabilities| (name, capabilities))),
+        }
+    }
+
+    let mut visited: Vec<&str> = Vec::new();
+    for (position, hop) in hops.iter().enumerate() {
+        let mut found = Vec::new();
+        members(hop, outbounds, &mut found);
+        for (name, capabilities) in found {
+            if visited.contains(&name) {
+                return Err(ProxyError::Protocol(format!("Outbound chain {} may pass {} twice", label, name)));
+            }
+            visited.push(name);
+            if capabilities.blocks {
+                return Err(ProxyError::Protocol(format!("Outbound chain {}: {} is a blackhole", label, name)));
+            }
+            if position > 0 && !capabilities.tunnelable {
+                return Err(ProxyError::Protocol(format!(
+                    "Outbound chain {}: {} cannot be reached through a tunnel, only socks5 and http outbounds can follow the first hop",
+                    label, name
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct DomainLists {
+    pub domain: Vec<String>,
+    pub domain_suffix: Vec<String>,
+    pub domain_keyword: Vec<String>,
+    pub domain_regex: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouterRuleConfig {
+    /// Outbound for matching connections; leave empty when `outbound_chain` is set.
+    /// `"reject"` and `"reject-drop"` are shorthands for the `action` of that name.
+    #[serde(default)]
+    pub outbound: String,
+    /// What to do with matching connections, `"route"` through the outbound by default
+    #[serde(default, skip_serializing_if = "RouteAction::is_forward")]
+    pub action: RouteAction,
+    /// One-off proxy chain used instead of `outbound`: the first hop is dialed,
+    /// each following hop is reached through the previous one and the last hop
+    /// connects to the target
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outbound_chain: Vec<String>,
+    /// Tags of `[[rule_sets]]` entries; the rule matches if any set or inline list does
+    #[serde(default)]
+    pub rule_sets: Vec<String>,
+    #[serde(default)]
+    pub domains: DomainLists,
+    #[serde(default)]
+    pub ip_cidr: Vec<String>,
+    /// DSCP code point (0-63), overrides the outbound's
+    #[serde(default)]
+    pub dscp: Option<u8>,
+    /// Latency mode for connections matching this rule, see `OutboundConfig::latency_mode`
+    #[serde(default)]
+    pub latency_mode: bool,
+    /// Send matching connections to this destination instead, through the rule's outbound
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rewrite_to: Option<RewriteTarget>,
+    /// Destination ports the rule is limited to; with no rule sets or inline
+    /// lists the rule matches any destination on these ports
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub port: Vec<u16>,
+    /// Destination port ranges as `first-last`, e.g. `"6881-6889"`; combined with `port`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub port_range: Vec<String>,
+    /// Transport the rule is limited to; unset matches both
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<Network>,
+    /// Client addresses the rule is limited to, e.g. one device or subnet of a LAN
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub source_ip_cidr: Vec<String>,
+    /// Tags of the inbounds whose connections the rule is limited to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inbound: Vec<String>,
+    /// Countries (ISO 3166 codes such as `"cn"`) whose IP addresses match, looked up
+    /// in `router.geoip_path`; `"private"` matches LAN, loopback and link-local
+    /// addresses without a database
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ip_geoip: Vec<String>,
+}
+
+/// What a route rule does with the connections it matches
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RouteAction {
+    /// Connect through the rule's outbound or outbound chain
+    #[default]
+    #[serde(rename = "route")]
+    Forward,
+    /// Refuse at once: SOCKS5 reply 0x02, transparent connections are reset
+    #[serde(rename = "reject")]
+    Reject,
+    /// Never answer: read and discard what the client sends until it gives up
+    #[serde(rename = "reject-drop")]
+    Drop,
+}
+
+impl RouteAction {
+    pub fn is_forward(&self) -> bool {
+        *self == RouteAction::Forward
+    }
+
+    /// Name of the action in configs, logs and stats
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RouteAction::Forward => "route",
+            RouteAction::Reject => "reject",
+            RouteAction::Drop => "reject-drop",
+        }
+    }
+}
+
+/// Transport protocol of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    Tcp,
+    Udp,
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Network::Tcp => "tcp",
+            Network::Udp => "udp",
+        })
+    }
+}
+
+/// Parse a `first-last` port range
+pub fn parse_port_range(range: &str) -> Result<std::ops::RangeInclusive<u16>> {
+    let invalid = || ProxyError::Protocol(format!("Invalid port range {:?}, expected first-last", range));
+    let (first, last) = range.split_once('-').ok_or_else(invalid)?;
+    let first: u16 = first.trim().parse().map_err(|_| invalid())?;
+    let last: u16 = last.trim().parse().map_err(|_| invalid())?;
+    if first > last {
+        return Err(invalid());
+    }
+    Ok(first..=last)
+}
+
+/// New destination of a rule's connections; parts left out keep the client's
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RewriteTarget {
+    /// Domain or IP address
+    pub host: Option<String>,
+    pub port: Option<u16>,
+}
+
+impl RewriteTarget {
+    pub fn validate(&self) -> Result<()> {
+        if self.host.is_none() && self.port.is_none() {
+            return Err(ProxyError::Protocol("rewrite_to needs a host or a port".to_string()));
+        }
+        if self.port == Some(0) {
+            return Err(ProxyError::Protocol("rewrite_to.port must not be 0".to_string()));
+        }
+        if let Some(host) = &self.host {
+            Address::from_domain_bytes(host.as_bytes())
+                .map_err(|e| ProxyError::Protocol(format!("rewrite_to.host: {}", e)))?;
+        }
+        Ok(())
+    }
+
+    /// The destination replacing `address:port`
+    pub fn apply(&self, address: &Address, port: u16) -> Result<(Address, u16)> {
+        let address = match &self.host {
+            Some(host) => Address::from_domain_bytes(host.as_bytes())?,
+            None => address.clone(),
+        };
+        Ok((address, self.port.unwrap_or(port)))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouterConfig {
+    pub default_outbound: String,
+    #[serde(default)]
+    pub rules: Vec<RouterRuleConfig>,
+    /// Where remote rule sets are downloaded to
+    #[serde(default = "default_rule_set_cache_dir")]
+    pub rule_set_cache_dir: String,
+    /// What to do when a rule set cannot be loaded or compiled
+    #[serde(default)]
+    pub on_rule_set_error: RuleSetErrorPolicy,
+    /// Most hops allowed in a rule's `outbound_chain`
+    #[serde(default = "default_max_chain_length")]
+    pub max_chain_length: usize,
+    /// Largest remote rule set body accepted, in bytes
+    #[serde(default = "default_rule_set_max_bytes")]
+    pub rule_set_max_bytes: u64,
+    /// Most redirects followed when downloading a remote rule set
+    #[serde(default = "default_rule_set_max_redirects")]
+    pub rule_set_max_redirects: usize,
+    /// MaxMind DB (`.mmdb`) country database for `ip_geoip` rules
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geoip_path: Option<String>,
+}
+
+impl RouterRuleConfig {
+    /// The rule's action, with `outbound = "reject"` or `"reject-drop"` read as that action
+    pub fn route_action(&self) -> RouteAction {
+        match (self.action, self.outbound.as_str()) {
+            (RouteAction::Forward, "reject") => RouteAction::Reject,
+            (RouteAction::Forward, "reject-drop") => RouteAction::Drop,
+            (action, _) => action,
+        }
+    }
+
+    /// Name the rule routes to in logs and stats: the outbound, the chain's
+    /// hops joined by `>`, or the action when it does not forward
+    pub fn outbound_label(&self) -> String {
+        match self.route_action() {
+            RouteAction::Forward if self.outbound_chain.is_empty() => self.outbound.clone(),
+            RouteAction::Forward => chain_label(&self.outbound_chain),
+            action => action.as_str().to_string(),
+        }
+    }
+
+    /// Outbounds the rule references, the chain's hops included; none when it does not forward
+    pub fn outbounds(&self) -> impl Iterator<Item = &String> {
+        let hops: &[String] = if self.route_action().is_forward() { &self.outbound_chain } else { &[] };
+        let single = (self.route_action().is_forward() && hops.is_empty()).then_some(&self.outbound);
+        single.into_iter().chain(hops)
+    }
+
+    /// The parsed `port_range` entries
+    pub fn port_ranges(&self) -> Result<Vec<std::ops::RangeInclusive<u16>>> {
+        self.port_range.iter().map(|range| parse_port_range(range)).collect()
+    }
+
+    /// The parsed `source_ip_cidr` entries
+    pub fn source_networks(&self) -> Result<Vec<IpNet>> {
+        self.source_ip_cidr
+            .iter()
+            .map(|cidr| {
+                cidr.parse()
+                    .map_err(|_| ProxyError::Protocol(format!("Invalid source_ip_cidr {:?}", cidr)))
+            })
+            .collect()
+    }
+}
+
+/// Check a rule's `ip_geoip` codes; country codes need a GeoIP database
+fn validate_geoip_codes(rule: &RouterRuleConfig, geoip_path: Option<&str>) -> Result<()> {
+    for code in &rule.ip_geoip {
+        let is_private = code.eq_ignore_ascii_case("private");
+        let is_country = code.len() == 2 && code.bytes().all(|b| b.is_ascii_alphabetic());
+        if !is_private && !is_country {
+            return Err(ProxyError::Protocol(format!(
+                "Rule for {}: invalid ip_geoip {:?}, expected a two-letter country code or \"private\"",
+                rule.outbound_label(),
+                code
+            )));
+        }
+        if !is_private && geoip_path.is_none() {
+            return Err(ProxyError::Protocol(format!(
+                "Rule for {}: ip_geoip {:?} needs router.geoip_path",
+                rule.outbound_label(),
+                code
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Label of an outbound chain, e.g. `proxy-a>proxy-b`
+pub fn chain_label(hops: &[String]) -> String {
+    hops.join(">")
+}
+
+/// Rules of a named routing profile, sharing `[[rule_sets]]` with `[router]`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoutingProfileConfig {
+    pub default_outbound: String,
+    #[serde(default)]
+    pub rules: Vec<RouterRuleConfig>,
+}
+
+/// Handling of rule sets that fail to load or compile
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleSetErrorPolicy {
+    /// Refuse to start, reporting every failing rule set
+    #[default]
+    Fail,
+    /// Start without the broken rule sets; rules referencing them never match
+    Skip,
 }
 
-impl Default for TrafficMarkConfig {
+fn default_rule_set_cache_dir() -> String {
+    "cache/rule_sets".to_string()
+}
+
+fn default_max_chain_length() -> usize {
+    4
+}
+
+fn default_rule_set_max_bytes() -> u64 {
+    DEFAULT_MAX_DOWNLOAD_BYTES
+}
+
+fn default_rule_set_max_redirects() -> usize {
+    DEFAULT_MAX_REDIRECTS
+}
+
+impl Default for RouterConfig {
     fn default() -> Self {
         Self {
-            so_mark: 0, // Disabled by default
-            net_service_type: 0, // Disabled by default
+            default_outbound: "direct".to_string(),
+            rules: Vec::new(),
+            rule_set_cache_dir: default_rule_set_cache_dir(),
+            on_rule_set_error: RuleSetErrorPolicy::default(),
+            max_chain_length: default_max_chain_length(),
+            rule_set_max_bytes: default_rule_set_max_bytes(),
+            rule_set_max_redirects: default_rule_set_max_redirects(),
+            geoip_path: None,
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type", rename_all = "lowercase")]
-pub enum OutboundType {
-    Direct,
-    Socks5 { address: String },
-    Vless { address: String, uuid: String, tls: bool },
-    Blackhole,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct OutboundConfig {
-    pub name: String,
-    #[serde(flatten)]
-    pub kind: OutboundType,
+impl RouterConfig {
+    /// Size and redirect limits of remote rule set downloads
+    pub fn download_limits(&self) -> DownloadLimits {
+        DownloadLimits { max_bytes: self.rule_set_max_bytes, max_redirects: self.rule_set_max_redirects }
+    }
 }
 
-impl OutboundConfig {
-    pub fn direct(name: &str) -> Self {
-        Self { name: name.to_string(), kind: OutboundType::Direct }
-    }
+/// Where a rule set comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleSetType {
+    /// Read from `path`
+    Local,
+    /// Downloaded from `url` into `router.rule_set_cache_dir`
+    Remote,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
-pub struct DomainLists {
-    pub domain: Vec<String>,
-    pub domain_suffix: Vec<String>,
-    pub domain_keyword: Vec<String>,
-    pub domain_regex: Vec<String>,
+/// Rule set file format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleSetFormat {
+    /// sing-box binary rule set; rules with conditions other than domains and
+    /// destination IPs are skipped
+    Srs,
+    /// sing-box source JSON: `{"version": 1, "rules": [{"domain_suffix": [...]}]}`
+    Source,
+    /// Clash rule provider payload (domain, ipcidr or classical behavior)
+    Clash,
+    /// One domain per line, matching the domain and its subdomains
+    Domains,
 }
 
+/// A `[[rule_sets]]` entry
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct RouterRuleConfig {
-    pub outbound: String,
+pub struct RuleSetConfig {
+    pub tag: String,
+    #[serde(rename = "type")]
+    pub kind: RuleSetType,
+    /// File to read, for local rule sets
     #[serde(default)]
-    pub domains: DomainLists,
+    pub path: Option<String>,
+    /// Download URL, for remote rule sets
     #[serde(default)]
-    pub ip_cidr: Vec<String>,
+    pub url: Option<String>,
+    pub format: RuleSetFormat,
+    /// How often a remote rule set is downloaded again (default: daily)
+    #[serde(default)]
+    pub update_interval_secs: Option<u64>,
+    /// Detached ed25519 signature of the downloaded content, for remote rule sets
+    #[serde(default)]
+    pub signature_url: Option<String>,
+    /// Key `signature_url` must verify against (hex or base64)
+    #[serde(default)]
+    pub public_key: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct RouterConfig {
-    pub default_outbound: String,
-    #[serde(default)]
-    pub rules: Vec<RouterRuleConfig>,
+/// Remote rule sets without `update_interval_secs` are refreshed daily
+pub const DEFAULT_RULE_SET_UPDATE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+impl RuleSetConfig {
+    pub fn update_interval(&self) -> Duration {
+        self.update_interval_secs.map(Duration::from_secs).unwrap_or(DEFAULT_RULE_SET_UPDATE_INTERVAL)
+    }
+
+    /// Where the signature comes from and which key checks it, when signing is configured
+    pub fn signature(&self) -> Result<Option<SignatureSource>> {
+        match (&self.signature_url, &self.public_key) {
+            (None, None) => Ok(None),
+            (Some(url), Some(key)) => Ok(Some(SignatureSource {
+                url: url.clone(),
+                key: PublicKey::parse(key).map_err(|e| ProxyError::Protocol(format!("Rule set {}: {}", self.tag, e)))?,
+            })),
+            _ => Err(ProxyError::Protocol(format!(
+                "Rule set {}: signature_url and public_key must be set together",
+                self.tag
+            ))),
+        }
+    }
 }
 
-impl Default for RouterConfig {
-    fn default() -> Self {
-        Self { default_outbound: "direct".to_string(), rules: Vec::new() }
+/// Prefix of the ids given to rules' inline domain/ip lists; tags may not use it
+pub const INLINE_RULE_SET_PREFIX: &str = "inline#";
+
+/// Check tags are unique and every tag a rule references exists
+pub fn validate_rule_sets<'a>(
+    rule_sets: &[RuleSetConfig],
+    rules: impl IntoIterator<Item = &'a RouterRuleConfig>,
+) -> Result<()> {
+    let mut tags = HashSet::new();
+    for rule_set in rule_sets {
+        let tag = rule_set.tag.as_str();
+        if tag.is_empty() {
+            return Err(ProxyError::Protocol("Rule set tag must not be empty".to_string()));
+        }
+        if tag.starts_with(INLINE_RULE_SET_PREFIX) {
+            return Err(ProxyError::Protocol(format!(
+                "Rule set {}: tags starting with {:?} are reserved",
+                tag, INLINE_RULE_SET_PREFIX
+            )));
+        }
+        if !tags.insert(tag) {
+            return Err(ProxyError::Protocol(format!("Duplicate rule set tag: {}", tag)));
+        }
+        match rule_set.kind {
+            RuleSetType::Local if rule_set.path.is_none() => {
+                return Err(ProxyError::Protocol(format!("Rule set {}: local rule sets need a path", tag)));
+            }
+            RuleSetType::Remote if rule_set.url.is_none() => {
+                return Err(ProxyError::Protocol(format!("Rule set {}: remote rule sets need a url", tag)));
+            }
+            RuleSetType::Local if rule_set.update_interval_secs.is_some() => {
+                warn!("Rule set {}: update_interval_secs has no effect on local rule sets", tag);
+            }
+            _ => {}
+        }
+        if rule_set.signature()?.is_some() && rule_set.kind == RuleSetType::Local {
+            warn!("Rule set {}: signatures are only checked on remote rule sets", tag);
+        }
+        if rule_set.update_interval_secs == Some(0) {
+            return Err(ProxyError::Protocol(format!("Rule set {}: update_interval_secs must be > 0", tag)));
+        }
     }
+
+    for rule in rules {
+        if let Some(unknown) = rule.rule_sets.iter().find(|tag| !tags.contains(tag.as_str())) {
+            return Err(ProxyError::Protocol(format!(
+                "Rule for {} references unknown rule set: {}",
+                rule.outbound_label(), unknown
+            )));
+        }
+    }
+    Ok(())
 }
 
 /// 高性能路由器配置
@@ -276,16 +1949,40 @@ pub struct HighPerformanceRouteRule {
 
     /// 出站名称
     pub outbound: String,
+
+    /// DSCP（0-63），覆盖出站上的设置
+    #[serde(default)]
+    pub dscp: Option<u8>,
+
+    /// 低延迟模式（TCP_NODELAY、TCP_QUICKACK、不合并写）
+    #[serde(default)]
+    pub latency_mode: bool,
 }
 
 /// 缓存配置
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CacheConfig {
-    /// 最大缓存条目数
+    /// 最大缓存条目数（域名和 IP 各自的上限）
     pub max_size: usize,
 
+    /// 域名缓存的最大条目数，未设置时使用 max_size
+    #[serde(default)]
+    pub domain_max_size: Option<usize>,
+
+    /// IP 缓存的最大条目数，未设置时使用 max_size
+    #[serde(default)]
+    pub ip_max_size: Option<usize>,
+
     /// 是否启用缓存
     pub enabled: bool,
+
+    /// 条目有效期（秒）
+    #[serde(default = "default_cache_ttl_secs")]
+    pub ttl_secs: u64,
+}
+
+fn default_cache_ttl_secs() -> u64 {
+    300
 }
 
 /// 规则集合文件配置
@@ -313,7 +2010,10 @@ impl Default for CacheConfig {
     fn default() -> Self {
         Self {
             max_size: 10000,
+            domain_max_size: None,
+            ip_max_size: None,
             enabled: true,
+            ttl_secs: default_cache_ttl_secs(),
         }
     }
 }
@@ -328,13 +2028,31 @@ impl Default for RuleSetFilesConfig {
 }
 
 impl Config {
-    /// Load configuration from a TOML file
+    /// Start building a configuration from the defaults, see [`ConfigBuilder`]
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::new()
+    }
+
+    /// Load configuration from a file
+    ///
+    /// RON and JSON files use the sing-box layout of
+    /// [`RonConfig`](crate::ron_config::RonConfig) and are converted, logging
+    /// the fields that have no equivalent; see [`ConfigFormat::detect`].
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
         let content = fs::read_to_string(path)
             .map_err(ProxyError::Io)?;
-        
-        let config: Config = toml::from_str(&content)
-            .map_err(|e| ProxyError::Protocol(format!("Invalid configuration: {}", e)))?;
+
+        let format = ConfigFormat::detect(path, &content);
+        let config: Config = match format {
+            ConfigFormat::Toml => toml::from_str(&content)
+                .map_err(|e| ProxyError::Protocol(format!("Invalid configuration: {}", e)))?,
+            ConfigFormat::Ron => crate::ron_config::RonConfig::from_ron_str(&content)?.to_internal_config()?,
+            ConfigFormat::Json => crate::ron_config::RonConfig::from_json_str(&content)?.to_internal_config()?,
+        };
+        if format != ConfigFormat::Toml {
+            info!("Configuration converted from {:?} file", format);
+        }
 
         info!("Configuration loaded from file");
         Ok(config)
@@ -365,13 +2083,136 @@ impl Config {
             return Err(ProxyError::Protocol("max_total_connections must be > 0".to_string()));
         }
 
+        let auto_tune = &self.connection_pool.auto_tune;
+        if auto_tune.enabled {
+            if auto_tune.window == 0 {
+                return Err(ProxyError::Protocol("connection_pool.auto_tune.window must be > 0".to_string()));
+            }
+            if auto_tune.min_idle_timeout_secs == 0 || auto_tune.min_idle_timeout_secs > auto_tune.max_idle_timeout_secs {
+                return Err(ProxyError::Protocol(
+                    "connection_pool.auto_tune needs 0 < min_idle_timeout_secs <= max_idle_timeout_secs".to_string(),
+                ));
+            }
+        }
+
         if self.performance.buffer_size == 0 {
             return Err(ProxyError::Protocol("buffer_size must be > 0".to_string()));
         }
 
+        if self.performance.relay_idle_timeout_secs == Some(0) {
+            return Err(ProxyError::Protocol(
+                "relay_idle_timeout_secs must be > 0 (leave it unset to disable)".to_string(),
+            ));
+        }
+
+        if self.performance.uring_threads == 0 {
+            return Err(ProxyError::Protocol("uring_threads must be > 0".to_string()));
+        }
+        if let Some(duplicate) = self
+            .performance
+            .relay_backend_order
+            .iter()
+            .enumerate()
+            .find_map(|(i, backend)| self.performance.relay_backend_order[..i].contains(backend).then_some(backend))
+        {
+            return Err(ProxyError::Protocol(format!("relay_backend_order lists {} twice", duplicate)));
+        }
+        if self.performance.listener_shards == 0 {
+            return Err(ProxyError::Protocol("listener_shards must be > 0".to_string()));
+        }
+        if self.performance.listener_shards > 1 && !cfg!(target_os = "linux") {
+            return Err(ProxyError::Protocol(
+                "listener_shards > 1 requires SO_REUSEPORT load balancing (Linux only)".to_string(),
+            ));
+        }
+
+        if self.health.enabled && self.health.timeout_ms == 0 {
+            return Err(ProxyError::Protocol("health.timeout_ms must be > 0".to_string()));
+        }
+
+        if self.clash_api.secret.as_deref() == Some("") {
+            return Err(ProxyError::Protocol("clash_api.secret must not be empty; omit it to serve without authentication".to_string()));
+        }
+
+        if !self.pac.path.starts_with('/') {
+            return Err(ProxyError::Protocol(format!("pac.path {:?} must start with '/'", self.pac.path)));
+        }
+
+        if self.capture.enabled {
+            if self.capture.max_bytes == 0 || self.capture.max_concurrent == 0 || self.capture.max_total_bytes == 0 {
+                return Err(ProxyError::Protocol(
+                    "capture max_bytes, max_concurrent and max_total_bytes must be > 0".to_string(),
+                ));
+            }
+            for filter in &self.capture.filters {
+                filter.parse::<crate::capture::CaptureFilter>()?;
+            }
+        } else if !self.capture.filters.is_empty() {
+            warn!("capture.filters has no effect unless capture.enabled is set");
+        }
+
+        let prefetch = &self.dns.prefetch;
+        if prefetch.enabled {
+            if prefetch.top_k == 0 || prefetch.concurrency == 0 {
+                return Err(ProxyError::Protocol("dns.prefetch top_k and concurrency must be > 0".to_string()));
+            }
+            if self.dns.cache_ttl_secs == 0 {
+                return Err(ProxyError::Protocol("dns.prefetch requires dns.cache_ttl_secs > 0".to_string()));
+            }
+        }
+        if let Some(subnet) = &self.dns.client_subnet {
+            subnet
+                .parse::<IpNet>()
+                .map_err(|e| ProxyError::Protocol(format!("Invalid dns.client_subnet {}: {}", subnet, e)))?;
+        }
+        let fake_ip = &self.dns.fake_ip;
+        if fake_ip.enabled {
+            if fake_ip.ttl_secs == 0 {
+                return Err(ProxyError::Protocol("dns.fake_ip.ttl_secs must be > 0".to_string()));
+            }
+            // 至少要有两个可分配地址（网络地址与广播地址不分配）
+            if fake_ip.inet4_range.prefix_len() > 30 {
+                return Err(ProxyError::Protocol(format!(
+                    "dns.fake_ip.inet4_range {} is too small, use a /30 or larger",
+                    fake_ip.inet4_range
+                )));
+            }
+            if !self.inbounds.iter().any(|inbound| inbound.kind == InboundKind::Dns) {
+                warn!("dns.fake_ip has no effect without an inbound of type dns");
+            }
+        }
+
+        self.server.auth.validate()?;
+        self.server.linger.validate().map_err(|e| prefixed("server".to_string(), e))?;
+        if !(0x01..=0x08).contains(&self.server.dry_run_reply) {
+            return Err(ProxyError::Protocol(format!(
+                "dry_run_reply {:#04x} is not a SOCKS5 failure code (0x01-0x08)",
+                self.server.dry_run_reply
+            )));
+        }
+        if self.server.max_new_connections_per_sec == Some(0) {
+            return Err(ProxyError::Protocol("server.max_new_connections_per_sec must be greater than 0".to_string()));
+        }
+
+        if self.logging.rotate_daily.is_some_and(|hour| hour > 23) {
+            return Err(ProxyError::Protocol("logging.rotate_daily must be an hour between 0 and 23".to_string()));
+        }
+        let access_log = &self.logging.access_log;
+        if access_log.enabled {
+            if access_log.sample_rate == 0 {
+                return Err(ProxyError::Protocol("access_log.sample_rate must be > 0".to_string()));
+            }
+            if access_log.queue_size == 0 {
+                return Err(ProxyError::Protocol("access_log.queue_size must be > 0".to_string()));
+            }
+            if access_log.hash_client_ip && access_log.ip_hash_salt.is_empty() {
+                warn!("access_log.hash_client_ip without ip_hash_salt: hashes of known IPs can be precomputed");
+            }
+        }
+
         // Validate log level
         match self.logging.level.as_str() {
-            "trace" | "debug" | "info" | "warn" | "error" => {}
+            "trace" | "debug" | "info" | "warn" | "error" | "off" => {}
             _ => return Err(ProxyError::Protocol("Invalid log level".to_string())),
         }
 
@@ -379,15 +2220,174 @@ impl Config {
         if self.outbounds.is_empty() {
             return Err(ProxyError::Protocol("At least one outbound must be configured".to_string()));
         }
+        for outbound in &self.outbounds {
+            if let OutboundType::Vless { tls: false, override_sni: Some(sni), .. } = &outbound.kind {
+                warn!("Outbound {}: override_sni {} has no effect with tls disabled", outbound.name, sni);
+            }
+            outbound.linger.validate().map_err(|e| prefixed(format!("Outbound {}", outbound.name), e))?;
+            if is_builtin_outbound(&outbound.name) {
+                warn!("Outbound {} overrides the built-in outbound of the same name", outbound.name);
+            }
+            if let Some(dscp) = outbound.dscp {
+                validate_dscp(dscp).map_err(|e| prefixed(format!("Outbound {}", outbound.name), e))?;
+            }
+            if let Some(mss) = outbound.tcp_mss {
+                validate_tcp_mss(mss).map_err(|e| prefixed(format!("Outbound {}", outbound.name), e))?;
+            }
+            if outbound.max_connections_per_sec == Some(0) {
+                return Err(ProxyError::Protocol(format!(
+                    "Outbound {}: max_connections_per_sec must be greater than 0",
+                    outbound.name
+                )));
+            }
+            if let Some(subnet) = &outbound.egress_hint_subnet {
+                subnet.parse::<IpNet>().map_err(|e| {
+                    ProxyError::Protocol(format!("Outbound {}: invalid egress_hint_subnet {}: {}", outbound.name, subnet, e))
+                })?;
+            }
+            if let OutboundType::Socks5 { address }
+            | OutboundType::Http { address, .. }
+            | OutboundType::Shadowsocks { address, .. }
+            | OutboundType::Vless { address, .. } = &outbound.kind
+            {
+                match parse_server_address(address, &outbound.ports) {
+                    Ok((_, ports)) if ports.len() > 1 && matches!(outbound.kind, OutboundType::Vless { .. }) => {
+                        warn!("Outbound {}: vless outbounds only use the first of {} ports", outbound.name, ports.len());
+                    }
+                    Ok(_) => {}
+                    // 降级启动时由 OutboundManager 禁用该出站
+                    Err(e) if self.on_outbound_error == OutboundErrorPolicy::Disable => {
+                        warn!("Outbound {}: {}", outbound.name, e);
+                    }
+                    Err(e) => return Err(prefixed(format!("Outbound {}", outbound.name), e)),
+                }
+            } else if !outbound.ports.is_empty() {
+                warn!("Outbound {}: ports has no effect on this outbound type", outbound.name);
+            }
+            if let OutboundType::Shadowsocks { method, password, .. } = &outbound.kind {
+                let checked = ShadowsocksCipher::from_method(method).and_then(|_| match password.is_empty() {
+                    true => Err(ProxyError::Protocol("shadowsocks password must not be empty".to_string())),
+                    false => Ok(()),
+                });
+                match checked {
+                    Ok(()) => {}
+                    Err(e) if self.on_outbound_error == OutboundErrorPolicy::Disable => {
+                        warn!("Outbound {}: {}", outbound.name, e);
+                    }
+                    Err(e) => return Err(prefixed(format!("Outbound {}", outbound.name), e)),
+                }
+            }
+            if outbound.tls_fragment.enabled {
+                outbound.tls_fragment.validate().map_err(|e| prefixed(format!("Outbound {}", outbound.name), e))?;
+                if matches!(outbound.kind, OutboundType::Blackhole | OutboundType::Selector { .. } | OutboundType::UrlTest { .. }) {
+                    warn!("Outbound {}: tls_fragment has no effect on this outbound type", outbound.name);
+                }
+            }
+        }
+        validate_outbound_graph(&self.outbounds)?;
+        for outbound in &self.outbounds {
+            if let Some(warning) = check_udp_over_tcp(outbound, &self.outbounds)? {
+                warn!("{}", warning);
+            }
+        }
+
+        // 规则可以引用任意出站或出站组
+        let exists = |name: &str| {
+            is_builtin_outbound(name) || self.outbounds.iter().any(|o| o.name == name)
+        };
+        if let Some(profile) = &self.server.profile {
+            if !self.profiles.contains_key(profile) {
+                return Err(ProxyError::Protocol(format!("server.profile references unknown profile: {}", profile)));
+            }
+        }
+        if self.profiles.keys().any(|name| name.is_empty()) {
+            return Err(ProxyError::Protocol("Profile name must not be empty".to_string()));
+        }
+        let mut inbound_tags = HashSet::new();
+        for inbound in &self.inbounds {
+            if inbound.tag.is_empty() {
+                return Err(ProxyError::Protocol("Inbound tag must not be empty".to_string()));
+            }
+            if !inbound_tags.insert(inbound.tag.as_str()) {
+                return Err(ProxyError::Protocol(format!("Duplicate inbound tag: {}", inbound.tag)));
+            }
+            if let Some(profile) = inbound.profile.as_ref().filter(|p| !self.profiles.contains_key(*p)) {
+                return Err(ProxyError::Protocol(format!("Inbound {} references unknown profile: {}", inbound.tag, profile)));
+            }
+        }
+        let references = std::iter::once(&self.router.default_outbound)
+            .chain(self.router.rules.iter().flat_map(RouterRuleConfig::outbounds))
+            .chain(self.profiles.values().flat_map(|p| {
+                std::iter::once(&p.default_outbound).chain(p.rules.iter().flat_map(RouterRuleConfig::outbounds))
+            }))
+            .chain(std::iter::once(&self.high_performance_router.default_outbound))
+            .chain(self.high_performance_router.rules.iter().map(|r| &r.outbound))
+            .chain(self.server.user_routing.values())
+            .chain(&self.health.critical_outbounds);
+        for name in references {
+            if !exists(name) {
+                return Err(ProxyError::Protocol(format!("Route references unknown outbound: {}", name)));
+            }
+        }
+
+        let profile_rules = self.profiles.values().flat_map(|p| &p.rules);
+        validate_rule_sets(&self.rule_sets, self.router.rules.iter().chain(profile_rules.clone()))?;
+        if self.router.rule_set_max_bytes == 0 {
+            return Err(ProxyError::Protocol("router.rule_set_max_bytes must be greater than 0".to_string()));
+        }
+        for rule in self.router.rules.iter().chain(profile_rules.clone()) {
+            match rule.route_action() {
+                RouteAction::Forward => validate_outbound_chain(rule, self.router.max_chain_length, &self.outbounds)?,
+                action => validate_rejecting_rule(rule, action)?,
+            }
+            if let Some(rewrite) = &rule.rewrite_to {
+                rewrite.validate().map_err(|e| prefixed(format!("Rule for {}", rule.outbound_label()), e))?;
+            }
+            rule.port_ranges().map_err(|e| prefixed(format!("Rule for {}", rule.outbound_label()), e))?;
+            rule.source_networks().map_err(|e| prefixed(format!("Rule for {}", rule.outbound_label()), e))?;
+            validate_geoip_codes(rule, self.router.geoip_path.as_deref())?;
+        }
+        if let Some(path) = &self.router.geoip_path {
+            std::fs::File::open(path)
+                .map_err(|e| ProxyError::Protocol(format!("router.geoip_path {}: {}", path, e)))?;
+        }
+
+        let rule_dscp = self.router.rules.iter().chain(profile_rules).map(|r| (r.outbound_label(), r.dscp))
+            .chain(self.high_performance_router.rules.iter().map(|r| (r.outbound.clone(), r.dscp)));
+        for (outbound, dscp) in rule_dscp {
+            if let Some(dscp) = dscp {
+                validate_dscp(dscp).map_err(|e| prefixed(format!("Rule for {}", outbound), e))?;
+            }
+        }
 
         Ok(())
     }
 
+    /// The inbounds to start: `[[inbounds]]`, or when there are none the
+    /// legacy `server.host`/`server.port` as a SOCKS5 inbound tagged "socks"
+    pub fn inbound_configs(&self) -> Vec<InboundConfig> {
+        if !self.inbounds.is_empty() {
+            return self.inbounds.clone();
+        }
+        vec![InboundConfig {
+            tag: "socks".to_string(),
+            kind: InboundKind::Socks5,
+            listen: self.server.host,
+            port: self.server.port,
+            profile: None,
+        }]
+    }
+
     /// Get connection timeout as Duration
     pub fn connection_timeout(&self) -> Duration {
         Duration::from_secs(self.server.connection_timeout_secs)
     }
 
+    /// Get the pre-relay phase limit as Duration (None when disabled)
+    pub fn handshake_timeout(&self) -> Option<Duration> {
+        (self.server.handshake_timeout_secs > 0).then(|| Duration::from_secs(self.server.handshake_timeout_secs))
+    }
+
     /// Get keep-alive timeout as Duration
     pub fn keep_alive_timeout(&self) -> Duration {
         Duration::from_secs(self.server.keep_alive_timeout_secs)
@@ -408,6 +2408,11 @@ impl Config {
         Duration::from_secs(self.connection_pool.idle_timeout_secs)
     }
 
+    /// Get connection pool wait timeout as Duration
+    pub fn pool_wait_timeout(&self) -> Duration {
+        Duration::from_millis(self.connection_pool.wait_timeout_ms)
+    }
+
     /// Get cleanup interval as Duration
     pub fn cleanup_interval(&self) -> Duration {
         Duration::from_secs(self.connection_pool.cleanup_interval_secs)
@@ -415,35 +2420,54 @@ impl Config {
 }
 
 /// Global configuration
-static mut GLOBAL_CONFIG: Option<Config> = None;
+static GLOBAL_CONFIG: OnceLock<Config> = OnceLock::new();
 
-/// Initialize global configuration
+/// Initialize global configuration; it can be set only once
 pub fn init_global_config(config: Config) -> Result<()> {
     config.validate()?;
-    unsafe {
-        GLOBAL_CONFIG = Some(config);
-    }
+    GLOBAL_CONFIG.set(config).map_err(|_| ProxyError::AlreadyInitialized("Global configuration"))?;
     info!("Global configuration initialized");
     Ok(())
 }
 
+/// Get global configuration, or an error before `init_global_config`
+pub fn try_get_global_config() -> Result<&'static Config> {
+    GLOBAL_CONFIG.get().ok_or(ProxyError::NotInitialized("Global configuration"))
+}
+
 /// Get global configuration
+///
+/// Panics before `init_global_config`; use `try_get_global_config` where
+/// that can happen.
 pub fn get_global_config() -> &'static Config {
-    unsafe {
-        GLOBAL_CONFIG.as_ref()
-            .expect("Global configuration not initialized")
-    }
+    try_get_global_config().unwrap_or_else(|e| panic!("{}", e))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_global_config_initialized_once() {
+        assert!(matches!(try_get_global_config(), Err(ProxyError::NotInitialized(_))));
+        let mut invalid = Config::default();
+        invalid.performance.uring_threads = 0;
+        assert!(init_global_config(invalid).is_err());
+        assert!(try_get_global_config().is_err());
+
+        init_global_config(Config::default()).unwrap();
+        let mut other = Config::default();
+        other.server.port = 2080;
+        let err = init_global_config(other).unwrap_err();
+        assert!(matches!(err, ProxyError::AlreadyInitialized(_)), "{}", err);
+        assert_eq!(get_global_config().server.port, 1080);
+    }
+
     #[test]
     fn test_default_config() {
         let config = Config::default();
         assert_eq!(config.server.port, 1080);
-        assert_eq!(config.server.host, IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        assert_eq!(config.server.host, IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)).into());
     }
 
     #[test]
@@ -453,6 +2477,494 @@ mod tests {
 
         config.server.port = 0;
         assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.server.user_routing.insert("jp-node".to_string(), "jp".to_string());
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("unknown outbound: jp"), "{}", err);
+    }
+
+    #[test]
+    fn test_config_format_detected() {
+        let detect = |path: &str, content: &str| ConfigFormat::detect(Path::new(path), content);
+        assert_eq!(detect("anybls.toml", "{"), ConfigFormat::Toml);
+        assert_eq!(detect("sing-box.JSON", ""), ConfigFormat::Json);
+        assert_eq!(detect("config.ron", ""), ConfigFormat::Ron);
+        assert_eq!(detect("/etc/anybls/config", "\u{feff}\n  {\"outbounds\": []}"), ConfigFormat::Json);
+        assert_eq!(detect("config", "#![enable(implicit_some)]\n("), ConfigFormat::Ron);
+        assert_eq!(detect("config", "// sing-box\n("), ConfigFormat::Ron);
+        assert_eq!(detect("config", "# comment\n[server]"), ConfigFormat::Toml);
+    }
+
+    #[test]
+    fn test_server_is_the_implicit_inbound() {
+        let config = Config::default();
+        let inbounds = config.inbound_configs();
+        assert_eq!(inbounds.len(), 1);
+        assert_eq!((inbounds[0].tag.as_str(), inbounds[0].kind), ("socks", InboundKind::Socks5));
+        assert_eq!(inbounds[0].bind_addr(), "127.0.0.1:1080".parse().unwrap());
+
+        let nat: InboundConfig = toml::from_str("tag = \"nat\"\ntype = \"redirect\"\nlisten = \"::\"\nport = 12346").unwrap();
+        assert_eq!(nat.kind, InboundKind::Redirect);
+        let mut config = Config { inbounds: vec![nat], ..Config::default() };
+        assert_eq!(config.inbound_configs(), config.inbounds);
+        assert!(config.validate().is_ok());
+
+        config.inbounds.push(config.inbounds[0].clone());
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("Duplicate inbound tag: nat"), "{}", err);
+    }
+
+    #[test]
+    fn test_listener_shards_validated() {
+        let mut config = Config::default();
+        config.performance.listener_shards = 0;
+        assert!(config.validate().is_err());
+
+        config.performance.listener_shards = 4;
+        #[cfg(target_os = "linux")]
+        assert!(config.validate().is_ok());
+        #[cfg(not(target_os = "linux"))]
+        {
+            let err = config.validate().unwrap_err().to_string();
+            assert!(err.contains("listener_shards > 1"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_relay_backend_settings() {
+        let performance: PerformanceConfig =
+            toml::from_str("buffer_size = 65536\ntcp_nodelay = true\nreuse_addr = true\nkeep_alive = true\nworker_threads = 0")
+                .unwrap();
+        assert_eq!(performance.relay_backend, RelayBackendMode::Auto);
+        assert_eq!(performance.relay_backend_order, [RelayBackend::Splice, RelayBackend::Uring, RelayBackend::Buffered]);
+        assert_eq!(performance.uring_threads, 1);
+        let performance: PerformanceConfig = toml::from_str(
+            "buffer_size = 65536\ntcp_nodelay = true\nreuse_addr = true\nkeep_alive = true\nworker_threads = 0\n\
+             relay_backend = \"splice\"\nrelay_backend_order = [\"buffered\", \"splice\", \"uring\"]",
+        )
+        .unwrap();
+        assert_eq!(performance.relay_backend, RelayBackendMode::Splice);
+        assert_eq!(performance.relay_backend_order, [RelayBackend::Buffered, RelayBackend::Splice, RelayBackend::Uring]);
+
+        let mut config = Config::default();
+        config.performance.relay_backend_order = vec![RelayBackend::Buffered, RelayBackend::Uring, RelayBackend::Buffered];
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("relay_backend_order lists buffered twice"), "{}", err);
+
+        config.performance.relay_backend_order = vec![RelayBackend::Buffered];
+        config.performance.uring_threads = 0;
+        assert!(config.validate().is_err());
+
+        config.performance.uring_threads = 1;
+        config.performance.relay_idle_timeout_secs = Some(0);
+        assert!(config.validate().unwrap_err().to_string().contains("relay_idle_timeout_secs must be > 0"));
+    }
+
+    fn selector(name: &str, members: &[&str]) -> OutboundConfig {
+        OutboundConfig {
+            name: name.to_string(),
+            kind: OutboundType::Selector {
+                outbounds: members.iter().map(|m| m.to_string()).collect(),
+                default: None,
+            },
+            tcp_user_timeout_secs: None,
+            udp_over_tcp: false,
+            dscp: None,
+            egress_hint_subnet: None,
+            latency_mode: false,
+            routing_mark: None,
+            tcp_mss: None,
+            ports: Vec::new(),
+            port_strategy: PortStrategy::default(),
+            tls_fragment: TlsFragmentConfig::default(),
+            linger: LingerPolicy::default(),
+            max_connections_per_sec: None,
+        }
+    }
+
+    #[test]
+    fn test_dns_prefetch_validated() {
+        let mut config = Config::default();
+        config.dns.prefetch.enabled = true;
+        config.validate().unwrap();
+
+        config.dns.prefetch.concurrency = 0;
+        assert!(config.validate().is_err());
+        config.dns.prefetch.concurrency = 4;
+        config.dns.cache_ttl_secs = 0;
+        assert!(config.validate().is_err());
+
+        let config: Config = toml::from_str(&toml::to_string(&Config::default()).unwrap()).unwrap();
+        assert_eq!(config.dns.prefetch, DnsPrefetchConfig::default());
+    }
+
+    #[test]
+    fn test_fake_ip_validated() {
+        let mut config: Config = toml::from_str(&toml::to_string(&Config::default()).unwrap()).unwrap();
+        assert_eq!(config.dns.fake_ip, FakeIpConfig::default());
+        let inbound: InboundConfig = toml::from_str("tag = \"dns-in\"\ntype = \"dns\"\nlisten = \"127.0.0.1\"\nport = 5353").unwrap();
+        assert_eq!(inbound.kind, InboundKind::Dns);
+        config.inbounds.push(inbound);
+        config.dns.fake_ip.enabled = true;
+        config.validate().unwrap();
+
+        config.dns.fake_ip.inet4_range = "198.18.0.0/31".parse().unwrap();
+        assert!(config.validate().is_err());
+        config.dns.fake_ip.inet4_range = "198.18.0.0/30".parse().unwrap();
+        config.dns.fake_ip.ttl_secs = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_builtin_outbounds_always_referenceable() {
+        let mut config = Config {
+            outbounds: vec![selector("proxy", &["block", "direct"])],
+            ..Config::default()
+        };
+        config.high_performance_router.rules.push(HighPerformanceRouteRule {
+            rule_sets: vec!["ads".to_string()],
+            outbound: "block".to_string(),
+            dscp: None,
+            latency_mode: false,
+        });
+        assert!(config.validate().is_ok());
+
+        config.high_performance_router.default_outbound = "missing".to_string();
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("unknown outbound: missing"), "{}", err);
+    }
+
+    #[test]
+    fn test_password_auth_requires_users() {
+        let mut config = Config::default();
+        config.server.auth.mode = SocksAuthMode::Password;
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("requires at least one user"), "{}", err);
+
+        config.server.auth.users.insert("alice".to_string(), "secret".to_string());
+        config.server.auth.no_auth_source_cidrs.push("192.168.0.0/16".to_string());
+        assert!(config.validate().is_ok());
+
+        config.server.auth.no_auth_source_cidrs.push("192.168.0.0/33".to_string());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_nested_groups_validate() {
+        let outbounds = vec![
+            OutboundConfig::direct("wan"),
+            selector("inner", &["wan", "block"]),
+            selector("outer", &["inner", "direct"]),
+        ];
+        assert!(validate_outbound_graph(&outbounds).is_ok());
+
+        let outbounds = vec![selector("outer", &["inner"])];
+        let err = validate_outbound_graph(&outbounds).unwrap_err().to_string();
+        assert!(err.contains("unknown outbound inner"), "{}", err);
+    }
+
+    #[test]
+    fn test_outbound_chain_validation() {
+        let socks = |name: &str| OutboundConfig {
+            kind: OutboundType::Socks5 { address: "127.0.0.1:1080".to_string() },
+            ..selector(name, &["direct"])
+        };
+        let chained = |hops: &[&str]| {
+            let mut config = Config {
+                outbounds: vec![socks("a"), socks("b"), socks("c"), selector("pool", &["a", "b"])],
+                ..Config::default()
+            };
+            config.router.rules.push(RouterRuleConfig {
+                outbound: String::new(),
+                action: RouteAction::Forward,
+                outbound_chain: hops.iter().map(|h| h.to_string()).collect(),
+                rule_sets: Vec::new(),
+                domains: DomainLists::default(),
+                ip_cidr: vec!["192.0.2.0/24".to_string()],
+                dscp: None,
+                latency_mode: false,
+                rewrite_to: None,
+                port: Vec::new(),
+                port_range: Vec::new(),
+                network: None,
+                source_ip_cidr: Vec::new(),
+                inbound: Vec::new(),
+                ip_geoip: Vec::new(),
+            });
+            config.validate().map_err(|e| e.to_string())
+        };
+
+        assert!(chained(&["a", "b"]).is_ok());
+        assert!(chained(&["direct", "pool"]).is_ok());
+        assert!(chained(&["a", "b", "a"]).unwrap_err().contains("a twice"));
+        // 组可能选中链上已有的出站
+        assert!(chained(&["a", "pool"]).unwrap_err().contains("a twice"));
+        assert!(chained(&["a", "direct"]).unwrap_err().contains("cannot be reached through a tunnel"));
+        assert!(chained(&["a", "block"]).unwrap_err().contains("blackhole"));
+        assert!(chained(&["a", "unknown"]).unwrap_err().contains("unknown outbound"));
+        assert!(chained(&["direct", "a", "b", "c", "pool"]).unwrap_err().contains("at most 4"));
+    }
+
+    #[test]
+    fn test_udp_over_tcp_checked_against_capabilities() {
+        let uot = |name: &str, kind: OutboundType| OutboundConfig { kind, udp_over_tcp: true, ..OutboundConfig::direct(name) };
+        let socks = || OutboundType::Socks5 { address: "127.0.0.1:1080".to_string() };
+        let http = || OutboundType::Http { address: "127.0.0.1:8080".to_string(), override_host_header: None };
+        let outbounds = vec![
+            uot("socks", socks()),
+            OutboundConfig { kind: http(), ..OutboundConfig::direct("http") },
+            OutboundConfig { udp_over_tcp: true, ..selector("udp-pool", &["socks", "direct"]) },
+            OutboundConfig { udp_over_tcp: true, ..selector("mixed", &["socks", "http"]) },
+        ];
+        let check = |outbound: &OutboundConfig| check_udp_over_tcp(outbound, &outbounds).map_err(|e| e.to_string());
+
+        assert_eq!(check(&outbounds[0]), Ok(None));
+        assert_eq!(check(&outbounds[1]), Ok(None));
+        let warning = check(&uot("direct-uot", OutboundType::Direct)).unwrap().unwrap();
+        assert!(warning.contains("already supports UDP natively"), "{}", warning);
+        assert!(check(&uot("http-uot", http())).unwrap_err().contains("cannot carry UDP"));
+        assert!(check(&uot("block-uot", OutboundType::Blackhole)).unwrap_err().contains("cannot carry UDP"));
+        // 组看成员：全部能承载UDP时开关多余，否则无法满足
+        assert!(check(&outbounds[2]).unwrap().unwrap().contains("udp-pool"));
+        assert!(check(&outbounds[3]).unwrap_err().contains("not all members"));
+
+        let config = Config { outbounds: vec![uot("http-uot", http())], ..Config::default() };
+        assert!(config.validate().unwrap_err().to_string().contains("http-uot: udp_over_tcp set but it cannot carry UDP"));
+    }
+
+    #[test]
+    fn test_group_capabilities_are_conservative() {
+        let outbounds = vec![
+            OutboundConfig { kind: OutboundType::Socks5 { address: "127.0.0.1:1080".to_string() }, ..OutboundConfig::direct("socks") },
+            selector("pool", &["socks", "direct"]),
+            selector("outer", &["pool", "block"]),
+        ];
+        let pool = outbound_capabilities("pool", &outbounds).unwrap();
+        assert!(pool.is_group && !pool.accepts_domain_targets && !pool.supports_udp && !pool.tunnelable);
+        assert!(pool.needs_resolved_target, "a member that dials targets itself needs them resolved");
+        let outer = outbound_capabilities("outer", &outbounds).unwrap();
+        assert!(outer.is_group && !outer.blocks);
+        assert!(outbound_capabilities("block", &outbounds).unwrap().blocks);
+        assert_eq!(outbound_capabilities("missing", &outbounds), None);
+    }
+
+    #[test]
+    fn test_group_cycle_reports_path() {
+        let outbounds = vec![
+            selector("a", &["b"]),
+            selector("b", &["c", "direct"]),
+            selector("c", &["a"]),
+        ];
+        let err = validate_outbound_graph(&outbounds).unwrap_err().to_string();
+        assert!(err.contains("a -> b -> c -> a"), "{}", err);
+    }
+
+    #[test]
+    fn test_user_outbound_may_override_builtin() {
+        let mut config = Config {
+            outbounds: vec![OutboundConfig {
+                name: "block".to_string(),
+                kind: OutboundType::Socks5 { address: "127.0.0.1:1081".to_string() },
+                tcp_user_timeout_secs: None,
+                udp_over_tcp: false,
+                dscp: None,
+                egress_hint_subnet: None,
+                latency_mode: false,
+                routing_mark: None,
+                tcp_mss: None,
+                ports: Vec::new(),
+                port_strategy: PortStrategy::default(),
+                tls_fragment: TlsFragmentConfig::default(),
+            linger: LingerPolicy::default(),
+            max_connections_per_sec: None,
+            }],
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+
+        config.outbounds.push(OutboundConfig::direct("block"));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_tcp_mss_out_of_range_rejected() {
+        for (mss, ok) in [(1360, true), (400, false), (1500, false)] {
+            let config = Config {
+                outbounds: vec![OutboundConfig { tcp_mss: Some(mss), ..OutboundConfig::direct("vpn") }],
+                ..Config::default()
+            };
+            match config.validate() {
+                Ok(()) => assert!(ok, "{} accepted", mss),
+                Err(e) => assert!(!ok && e.to_string().contains("Outbound vpn: Invalid tcp_mss"), "{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_dscp_out_of_range_rejected() {
+        let mut outbound = OutboundConfig::direct("wan");
+        outbound.dscp = Some(64);
+        let config = Config {
+            outbounds: vec![outbound],
+            ..Config::default()
+        };
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("Outbound wan"), "{}", err);
+
+        let mut config = Config::default();
+        config.high_performance_router.rules.push(HighPerformanceRouteRule {
+            rule_sets: vec!["voip".to_string()],
+            outbound: "direct".to_string(),
+            dscp: Some(255),
+            latency_mode: false,
+        });
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("Invalid DSCP value 255"), "{}", err);
+
+        config.high_performance_router.rules[0].dscp = Some(46);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_reject_rules_parsed_and_validated() {
+        let rules = |toml: &str| -> std::result::Result<Vec<(RouteAction, String)>, String> {
+            let mut config = Config::default();
+            config.router.rules = toml::from_str::<HashMap<String, Vec<RouterRuleConfig>>>(toml).unwrap().remove("rules").unwrap();
+            config.validate().map_err(|e| e.to_string())?;
+            Ok(config.router.rules.iter().map(|r| (r.route_action(), r.outbound_label())).collect())
+        };
+
+        let parsed = rules(
+            r#"
+            [[rules]]
+            outbound = "reject"
+            domains = { domain_suffix = ["ads.example"] }
+            [[rules]]
+            action = "reject-drop"
+            ip_cidr = ["203.0.113.0/24"]
+            [[rules]]
+            action = "route"
+            outbound = "direct"
+            port = [53]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            parsed,
+            [
+                (RouteAction::Reject, "reject".to_string()),
+                (RouteAction::Drop, "reject-drop".to_string()),
+                (RouteAction::Forward, "direct".to_string()),
+            ]
+        );
+
+        let err = rules("[[rules]]\naction = \"reject\"\noutbound = \"direct\"\nport = [25]").unwrap_err();
+        assert!(err.contains("must not set outbound"), "{}", err);
+        let err = rules("[[rules]]\naction = \"reject\"\noutbound_chain = [\"direct\"]\nport = [25]").unwrap_err();
+        assert!(err.contains("must not set outbound"), "{}", err);
+        assert!(toml::from_str::<RouterRuleConfig>("action = \"drop\"").is_err());
+    }
+
+    #[test]
+    fn test_rewrite_to_validated() {
+        let rewrite = |host: Option<&str>, port: Option<u16>| {
+            let mut config = Config::default();
+            config.router.rules.push(RouterRuleConfig {
+                outbound: "direct".to_string(),
+                action: RouteAction::Forward,
+                outbound_chain: Vec::new(),
+                rule_sets: Vec::new(),
+                domains: DomainLists { domain: vec!["api.example.com".to_string()], ..DomainLists::default() },
+                ip_cidr: Vec::new(),
+                dscp: None,
+                latency_mode: false,
+                rewrite_to: Some(RewriteTarget { host: host.map(str::to_string), port }),
+                port: Vec::new(),
+                port_range: Vec::new(),
+                network: None,
+                source_ip_cidr: Vec::new(),
+                inbound: Vec::new(),
+                ip_geoip: Vec::new(),
+            });
+            config.validate().map_err(|e| e.to_string())
+        };
+
+        assert!(rewrite(Some("staging.example.com"), None).is_ok());
+        assert!(rewrite(Some("10.0.0.8"), Some(8443)).is_ok());
+        assert!(rewrite(None, Some(8080)).is_ok());
+        assert!(rewrite(None, None).unwrap_err().contains("needs a host or a port"));
+        assert!(rewrite(Some(""), None).unwrap_err().contains("rewrite_to.host"));
+        assert!(rewrite(None, Some(0)).unwrap_err().contains("must not be 0"));
+
+        let target = RewriteTarget { host: None, port: Some(8080) };
+        let (address, port) = target.apply(&Address::Domain("api.example.com".to_string()), 443).unwrap();
+        assert_eq!((address.to_string(), port), ("api.example.com".to_string(), 8080));
+        let target = RewriteTarget { host: Some("::1".to_string()), port: None };
+        let (address, port) = target.apply(&Address::Domain("api.example.com".to_string()), 443).unwrap();
+        assert_eq!((address, port), (Address::V6("::1".parse().unwrap(), 0), 443));
+    }
+
+    #[test]
+    fn test_outbound_port_ranges_validated() {
+        let socks = |address: &str, ports: Vec<u16>| OutboundConfig {
+            kind: OutboundType::Socks5 { address: address.to_string() },
+            ports,
+            ..OutboundConfig::direct("upstream")
+        };
+        let valid = [
+            socks("203.0.113.1:20000-21000", vec![]),
+            socks("203.0.113.1:443,8443", vec![]),
+            socks("203.0.113.1", vec![443, 8443, 2053]),
+        ];
+        for outbound in valid {
+            let config = Config { outbounds: vec![outbound], ..Config::default() };
+            assert!(config.validate().is_ok());
+        }
+
+        for outbound in [socks("203.0.113.1:21000-20000", vec![]), socks("203.0.113.1:443", vec![8443])] {
+            let config = Config { outbounds: vec![outbound], ..Config::default() };
+            let err = config.validate().unwrap_err().to_string();
+            assert!(err.contains("Outbound upstream: Invalid server address"), "{}", err);
+        }
+
+        // 降级启动模式下地址错误只告警，由 OutboundManager 禁用该出站
+        let config = Config {
+            outbounds: vec![socks("203.0.113.1:21000-20000", vec![])],
+            on_outbound_error: OutboundErrorPolicy::Disable,
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_shadowsocks_method_and_password_validated() {
+        let shadowsocks = |method: &str, password: &str| OutboundConfig {
+            kind: OutboundType::Shadowsocks {
+                address: "203.0.113.1:8388".to_string(),
+                method: method.to_string(),
+                password: password.to_string(),
+            },
+            ..OutboundConfig::direct("ss")
+        };
+        for method in ["aes-128-gcm", "aes-256-gcm", "chacha20-ietf-poly1305"] {
+            let config = Config { outbounds: vec![shadowsocks(method, "secret")], ..Config::default() };
+            assert!(config.validate().is_ok(), "{}", method);
+        }
+
+        let config = Config { outbounds: vec![shadowsocks("rc4-md5", "secret")], ..Config::default() };
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("Outbound ss: unsupported shadowsocks method rc4-md5"), "{}", err);
+        let config = Config { outbounds: vec![shadowsocks("aes-256-gcm", "")], ..Config::default() };
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("password must not be empty"), "{}", err);
+
+        let parsed: OutboundConfig = toml::from_str(
+            "name = \"ss\"\ntype = \"shadowsocks\"\naddress = \"203.0.113.1:8388\"\nmethod = \"aes-256-gcm\"\npassword = \"secret\"",
+        )
+        .unwrap();
+        assert!(matches!(parsed.kind, OutboundType::Shadowsocks { ref method, .. } if method == "aes-256-gcm"));
+        assert_eq!(parsed.capabilities(), Some(ShadowsocksProtocol::CAPABILITIES));
     }
 
     #[test]
@@ -0,0 +1,215 @@
+//! Fluent construction of a [`Config`] for embedding the proxy in other programs
+//!
+//! Every setting not touched keeps its default, so the result equals what
+//! the corresponding TOML would parse to.
+//!
+//! ```
+//! use anybls::config::Config;
+//!
+//! let config = Config::builder()
+//!     .listen("127.0.0.1:1080".parse().unwrap())
+//!     .add_outbound_socks5("proxy", "192.0.2.10:1080".parse().unwrap())
+//!     .add_domain_rule("proxy", &["example.com"])
+//!     .add_ip_rule("direct", &["10.0.0.0/8"])
+//!     .dns_servers(&["1.1.1.1:53"])
+//!     .done()
+//!     .unwrap();
+//! assert_eq!(config.router.rules.len(), 2);
+//! ```
+
+use crate::config::{Config, DomainLists, OutboundConfig, OutboundType, RouteAction, RouterRuleConfig};
+use crate::error::Result;
+use std::net::SocketAddr;
+
+/// Builder for [`Config`], see [`Config::builder`]
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+    config: Config,
+    /// 第一次添加出站时替换默认列表，与 TOML 中写出 `[[outbounds]]` 一致
+    outbounds_added: bool,
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        Self { config: Config::default(), outbounds_added: false }
+    }
+
+    /// Address the SOCKS5 listener binds to (`[server] host`, `port`)
+    pub fn listen(mut self, addr: SocketAddr) -> Self {
+        self.config.server.host = addr.ip().into();
+        self.config.server.port = addr.port();
+        self
+    }
+
+    /// Add a `type = "socks5"` outbound
+    pub fn add_outbound_socks5(self, name: &str, server: SocketAddr) -> Self {
+        let outbound = OutboundConfig {
+            kind: OutboundType::Socks5 { address: server.to_string() },
+            ..OutboundConfig::direct(name)
+        };
+        self.add_outbound(outbound)
+    }
+
+    /// Add a `type = "direct"` outbound
+    pub fn add_outbound_direct(self, name: &str) -> Self {
+        self.add_outbound(OutboundConfig::direct(name))
+    }
+
+    /// Add an outbound of any type
+    ///
+    /// The first outbound added replaces the default list; the built-in
+    /// `direct` and `block` outbounds stay usable either way.
+    pub fn add_outbound(mut self, outbound: OutboundConfig) -> Self {
+        if !std::mem::replace(&mut self.outbounds_added, true) {
+            self.config.outbounds.clear();
+        }
+        self.config.outbounds.push(outbound);
+        self
+    }
+
+    /// Outbound for traffic no rule matches (`[router] default_outbound`)
+    pub fn default_outbound(mut self, name: &str) -> Self {
+        self.config.router.default_outbound = name.to_string();
+        self
+    }
+
+    /// Route domains ending in any of `suffixes` to `outbound`
+    pub fn add_domain_rule(self, outbound: &str, suffixes: &[&str]) -> Self {
+        let domains = DomainLists { domain_suffix: to_strings(suffixes), ..DomainLists::default() };
+        self.add_rule(RouterRuleConfig { domains, ..rule(outbound) })
+    }
+
+    /// Route addresses in any of `cidrs` to `outbound`
+    pub fn add_ip_rule(self, outbound: &str, cidrs: &[&str]) -> Self {
+        self.add_rule(RouterRuleConfig { ip_cidr: to_strings(cidrs), ..rule(outbound) })
+    }
+
+    /// Append a `[[router.rules]]` entry; rules are tried in the order added
+    pub fn add_rule(mut self, rule: RouterRuleConfig) -> Self {
+        self.config.router.rules.push(rule);
+        self
+    }
+
+    /// DNS servers, e.g. `"1.1.1.1:53"` (`[dns] servers`)
+    pub fn dns_servers(mut self, servers: &[&str]) -> Self {
+        self.config.dns.servers = to_strings(servers);
+        self
+    }
+
+    /// Validate and return the configuration
+    pub fn done(self) -> Result<Config> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rule with no conditions yet, as `[[router.rules]]` with only `outbound` set
+fn rule(outbound: &str) -> RouterRuleConfig {
+    RouterRuleConfig {
+        outbound: outbound.to_string(),
+        action: RouteAction::Forward,
+        outbound_chain: Vec::new(),
+        rule_sets: Vec::new(),
+        domains: DomainLists::default(),
+        ip_cidr: Vec::new(),
+        dscp: None,
+        latency_mode: false,
+        rewrite_to: None,
+        port: Vec::new(),
+        port_range: Vec::new(),
+        network: None,
+        source_ip_cidr: Vec::new(),
+        inbound: Vec::new(),
+        ip_geoip: Vec::new(),
+    }
+}
+
+fn to_strings(items: &[&str]) -> Vec<String> {
+    items.iter().map(|item| item.to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse `snippet` on top of the defaults, the way a config file that only
+    /// sets those keys is meant to read
+    fn parse_with_defaults(snippet: &str) -> Config {
+        fn merge(base: &mut toml::Table, overlay: toml::Table) {
+            for (key, value) in overlay {
+                match (base.get_mut(&key), value) {
+                    (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => merge(base, overlay),
+                    (_, value) => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        let mut table = toml::Table::try_from(Config::default()).unwrap();
+        merge(&mut table, snippet.parse().unwrap());
+        table.try_into().unwrap()
+    }
+
+    fn assert_same(built: &Config, parsed: &Config) {
+        assert_eq!(serde_json::to_value(built).unwrap(), serde_json::to_value(parsed).unwrap());
+    }
+
+    #[test]
+    fn test_builder_matches_toml() {
+        let built = Config::builder()
+            .listen("0.0.0.0:1081".parse().unwrap())
+            .add_outbound_socks5("proxy", "192.0.2.10:1080".parse().unwrap())
+            .add_outbound_direct("lan")
+            .default_outbound("proxy")
+            .add_domain_rule("lan", &["corp.example", "intranet.example"])
+            .add_ip_rule("lan", &["10.0.0.0/8"])
+            .dns_servers(&["1.1.1.1:53", "9.9.9.9:53"])
+            .done()
+            .unwrap();
+
+        let parsed = parse_with_defaults(
+            r#"
+            [server]
+            host = "0.0.0.0"
+            port = 1081
+
+            [dns]
+            servers = ["1.1.1.1:53", "9.9.9.9:53"]
+
+            [[outbounds]]
+            name = "proxy"
+            type = "socks5"
+            address = "192.0.2.10:1080"
+
+            [[outbounds]]
+            name = "lan"
+            type = "direct"
+
+            [router]
+            default_outbound = "proxy"
+
+            [[router.rules]]
+            outbound = "lan"
+            domains = { domain_suffix = ["corp.example", "intranet.example"] }
+
+            [[router.rules]]
+            outbound = "lan"
+            ip_cidr = ["10.0.0.0/8"]
+            "#,
+        );
+        assert_same(&built, &parsed);
+    }
+
+    #[test]
+    fn test_done_validates() {
+        let err = Config::builder().add_domain_rule("missing", &["example.com"]).done().unwrap_err();
+        assert!(err.to_string().contains("unknown outbound: missing"), "{}", err);
+        assert_same(&Config::builder().done().unwrap(), &parse_with_defaults(""));
+    }
+}
@@ -1,13 +1,60 @@
+use crate::config::{OnExhausted, PoolAutoTuneConfig};
 use crate::error::{ProxyError, Result};
-use log::{debug, info};
+use crate::protocol::TargetAddr;
+use crate::stream::ProxyStream;
+use crate::tasks::{get_global_task_tracker, TaskGroup};
+use crate::traffic_mark::{dial_tcp, DialOptions};
+use log::{debug, info, warn};
 use std::collections::HashMap;
+use std::fmt;
+use std::io::ErrorKind;
+use std::mem::MaybeUninit;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::{Duration, Instant};
-use tokio::net::TcpStream;
-use tokio::sync::{RwLock, Semaphore};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
 use tokio::time::timeout;
 
+/// Upper bounds of the wait-time histogram buckets; the last bucket is unbounded
+pub const WAIT_BUCKETS: [Duration; 4] = [
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+/// Targets whose checkout statistics are kept; the least used one makes room
+const MAX_TRACKED_TARGETS: usize = 1024;
+
+/// What a pooled connection leads to: the outbound that opened it and the target
+///
+/// A tunnel through a proxy outbound (socks5) is bound to the target it was
+/// opened for, so connections are only reused for the same outbound and target.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    pub outbound: String,
+    pub target: TargetAddr,
+}
+
+impl PoolKey {
+    pub fn new(outbound: impl Into<String>, target: TargetAddr) -> Self {
+        Self { outbound: outbound.into(), target }
+    }
+
+    /// Key of connections the pool dials itself
+    pub fn direct(target_addr: SocketAddr) -> Self {
+        Self::new("direct", target_addr.into())
+    }
+}
+
+impl fmt::Display for PoolKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} via {}", self.target, self.outbound)
+    }
+}
+
 /// Connection pool for managing TCP connections
 pub struct ConnectionPool {
     /// Maximum number of connections per target
@@ -16,31 +63,69 @@ pub struct ConnectionPool {
     connection_timeout: Duration,
     /// Idle timeout for connections
     idle_timeout: Duration,
+    /// Maximum number of connections overall
+    max_total_connections: usize,
     /// Semaphore to limit total connections
     semaphore: Arc<Semaphore>,
-    /// Pool of connections by target address
-    pools: Arc<RwLock<HashMap<SocketAddr, Vec<PooledConnection>>>>,
+    /// What to do when every permit is taken
+    on_exhausted: OnExhausted,
+    /// Longest wait for a permit with `OnExhausted::WaitTimeout`
+    wait_timeout: Duration,
+    /// Pool of connections by outbound and target
+    pools: Arc<RwLock<HashMap<PoolKey, Vec<PooledConnection>>>>,
+    /// 正在等待许可的请求数
+    queued: AtomicUsize,
+    rejected: AtomicU64,
+    timed_out: AtomicU64,
+    wait_histogram: [AtomicU64; WAIT_BUCKETS.len() + 1],
+    /// 按目标统计复用效果，以及自动调整后的空闲超时
+    targets: Mutex<HashMap<PoolKey, TargetState>>,
+    auto_tune: Option<PoolAutoTuneConfig>,
 }
 
 /// A pooled TCP connection with metadata
+///
+/// Connections created by the pool hold one of its permits until dropped, so
+/// `max_total_connections` bounds checked-out and idle pooled connections alike.
+/// A connection opened elsewhere takes a permit when it is returned.
 pub struct PooledConnection {
-    stream: TcpStream,
+    stream: ProxyStream,
     created_at: Instant,
     last_used: Instant,
-    target_addr: SocketAddr,
+    key: PoolKey,
+    permit: Option<OwnedSemaphorePermit>,
+    /// Handed out from the pool rather than opened for the current checkout
+    reused: bool,
+    /// Set by `get_connection` until the first exchange is recorded
+    checkout: Option<Checkout>,
+}
+
+/// How a connection was handed out
+#[derive(Debug, Clone, Copy)]
+struct Checkout {
+    pooled: bool,
+    requested_at: Instant,
 }
 
 impl PooledConnection {
-    pub fn new(stream: TcpStream, target_addr: SocketAddr) -> Self {
+    pub fn new(stream: impl Into<ProxyStream>, key: PoolKey) -> Self {
         let now = Instant::now();
         Self {
-            stream,
+            stream: stream.into(),
             created_at: now,
             last_used: now,
-            target_addr,
+            key,
+            permit: None,
+            reused: false,
+            checkout: None,
         }
     }
 
+    fn with_permit(mut self, permit: OwnedSemaphorePermit) -> Self {
+        self.permit = Some(permit);
+        self
+    }
+
     pub fn is_expired(&self, idle_timeout: Duration) -> bool {
         self.last_used.elapsed() > idle_timeout
     }
@@ -49,12 +134,40 @@ impl PooledConnection {
         self.last_used = Instant::now();
     }
 
-    pub fn into_stream(self) -> TcpStream {
+    /// Take the stream out of pool management, releasing its permit
+    pub fn into_stream(self) -> ProxyStream {
         self.stream
     }
 
-    pub fn target_addr(&self) -> SocketAddr {
-        self.target_addr
+    pub fn key(&self) -> &PoolKey {
+        &self.key
+    }
+
+    /// The stream, for callers doing their own first exchange before
+    /// `ConnectionPool::record_first_exchange`
+    pub fn stream_mut(&mut self) -> &mut ProxyStream {
+        &mut self.stream
+    }
+
+    /// Whether the connection came out of the pool rather than being opened
+    /// for this checkout
+    pub fn is_reused(&self) -> bool {
+        self.reused
+    }
+
+    /// Whether the connection can be handed out again: still open and with
+    /// nothing unread
+    ///
+    /// A FIN, a reset or bytes the target sent while the connection sat idle
+    /// all rule it out. Only plain TCP streams can be checked; wrapped streams
+    /// may hold buffered data and are never reused.
+    pub fn is_reusable(&self) -> bool {
+        let ProxyStream::Tcp(socket) = &self.stream else {
+            return false;
+        };
+        // 非阻塞 peek：没有数据可读（WouldBlock）才说明连接空闲且仍然打开
+        let mut probe = [MaybeUninit::<u8>::uninit(); 1];
+        matches!(socket2::SockRef::from(socket).peek(&mut probe), Err(e) if e.kind() == ErrorKind::WouldBlock)
     }
 }
 
@@ -70,75 +183,286 @@ impl ConnectionPool {
             max_connections_per_target,
             connection_timeout,
             idle_timeout,
+            max_total_connections,
             semaphore: Arc::new(Semaphore::new(max_total_connections)),
+            on_exhausted: OnExhausted::default(),
+            wait_timeout: Duration::ZERO,
             pools: Arc::new(RwLock::new(HashMap::new())),
+            queued: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+            timed_out: AtomicU64::new(0),
+            wait_histogram: Default::default(),
+            targets: Mutex::new(HashMap::new()),
+            auto_tune: None,
         }
     }
 
+    /// Adjust the idle timeout per target from the recorded first exchanges
+    pub fn with_auto_tune(mut self, auto_tune: PoolAutoTuneConfig) -> Self {
+        self.auto_tune = auto_tune.enabled.then_some(auto_tune);
+        self
+    }
+
+    /// Set the behavior when all `max_total_connections` permits are in use
+    pub fn with_exhaustion_policy(mut self, on_exhausted: OnExhausted, wait_timeout: Duration) -> Self {
+        self.on_exhausted = on_exhausted;
+        self.wait_timeout = wait_timeout;
+        self
+    }
+
     /// Get a connection from the pool or create a new one
     pub async fn get_connection(&self, target_addr: SocketAddr) -> Result<PooledConnection> {
+        let requested_at = Instant::now();
+        let key = PoolKey::direct(target_addr);
         // First, try to get an existing connection from the pool
-        if let Some(connection) = self.get_from_pool(target_addr).await? {
-            debug!("Reusing pooled connection to {}", target_addr);
+        if let Some(mut connection) = self.checkout(&key).await {
+            connection.checkout = Some(Checkout { pooled: true, requested_at });
             return Ok(connection);
         }
 
         // If no pooled connection available, create a new one
         debug!("Creating new connection to {}", target_addr);
-        let _permit = self.semaphore.acquire().await
-            .map_err(|_| ProxyError::ConnectionFailed("Connection pool exhausted".to_string()))?;
+        let permit = self.acquire_permit(target_addr).await?;
 
         let stream = timeout(
             self.connection_timeout,
-            TcpStream::connect(target_addr)
+            dial_tcp(target_addr, &DialOptions::default())
         ).await
         .map_err(|_| ProxyError::ConnectionFailed("Connection timeout".to_string()))?
         .map_err(|e| ProxyError::ConnectionFailed(e.to_string()))?;
 
-        Ok(PooledConnection::new(stream, target_addr))
+        let mut connection = PooledConnection::new(stream, key).with_permit(permit);
+        connection.checkout = Some(Checkout { pooled: false, requested_at });
+        Ok(connection)
+    }
+
+    /// Take an idle connection for `key` that is still open, if there is one
+    pub async fn checkout(&self, key: &PoolKey) -> Option<PooledConnection> {
+        let mut connection = self.get_from_pool(key).await?;
+        debug!("Reusing pooled connection to {}", key);
+        connection.reused = true;
+        connection.checkout = Some(Checkout { pooled: true, requested_at: Instant::now() });
+        Some(connection)
+    }
+
+    /// Take an idle connection through `outbound` to the first of `targets` that has one
+    pub async fn checkout_any(&self, outbound: &str, targets: &[TargetAddr]) -> Option<PooledConnection> {
+        for target in targets {
+            if let Some(connection) = self.checkout(&PoolKey::new(outbound, target.clone())).await {
+                return Some(connection);
+            }
+        }
+        None
+    }
+
+    /// Send `request` on a checked-out connection and read the first bytes of
+    /// the answer into `response`, recording how the exchange went
+    ///
+    /// A pooled connection failing here was dead on arrival; drop it rather
+    /// than returning it.
+    pub async fn first_exchange(
+        &self,
+        connection: &mut PooledConnection,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize> {
+        let exchange = async {
+            connection.stream.write_all(request).await?;
+            match connection.stream.read(response).await? {
+                0 => Err(ErrorKind::UnexpectedEof.into()),
+                read => Ok(read),
+            }
+        };
+        let result: std::io::Result<usize> = exchange.await;
+        self.record_first_exchange(connection, result.is_ok());
+        Ok(result?)
+    }
+
+    /// Record whether the first write and read on a checked-out connection
+    /// succeeded; later calls for the same checkout are ignored
+    pub fn record_first_exchange(&self, connection: &mut PooledConnection, ok: bool) {
+        let Some(checkout) = connection.checkout.take() else {
+            return;
+        };
+        self.record_exchange(&connection.key, checkout, ok.then(Instant::now));
+    }
+
+    /// Record the first exchange of a connection that was handed to a relay
+    /// instead: taken from the pool or dialed after `requested_at`, answered
+    /// at `answered_at`, or never answered
+    pub fn record_relayed_exchange(&self, key: &PoolKey, pooled: bool, requested_at: Instant, answered_at: Option<Instant>) {
+        self.record_exchange(key, Checkout { pooled, requested_at }, answered_at);
+    }
+
+    fn record_exchange(&self, key: &PoolKey, checkout: Checkout, answered_at: Option<Instant>) {
+        let mut targets = self.targets.lock().unwrap();
+        if !targets.contains_key(key) && targets.len() >= MAX_TRACKED_TARGETS {
+            let least_used = targets.iter().min_by_key(|(_, state)| state.pooled + state.fresh).map(|(key, _)| key.clone());
+            if let Some(least_used) = least_used {
+                targets.remove(&least_used);
+            }
+        }
+        let state = targets.entry(key.clone()).or_default();
+        state.record(checkout, answered_at);
+
+        if let Some(auto_tune) = &self.auto_tune {
+            if state.window.exchanges >= auto_tune.window as u64 {
+                self.tune_idle_timeout(key, state, auto_tune);
+            }
+        }
+    }
+
+    /// 根据一个窗口内的首次交互结果调整目标的空闲超时：死连接多则减半，无死连接但复用少则加倍
+    fn tune_idle_timeout(&self, key: &PoolKey, state: &mut TargetState, auto_tune: &PoolAutoTuneConfig) {
+        let window = std::mem::take(&mut state.window);
+        let doa_rate = ratio(window.dead_on_arrival, window.pooled);
+        let reuse_rate = ratio(window.pooled, window.exchanges);
+        let current = state.idle_timeout.unwrap_or(self.idle_timeout);
+
+        let tuned = if doa_rate > auto_tune.max_doa_rate {
+            current / 2
+        } else if window.dead_on_arrival == 0 && reuse_rate < auto_tune.min_reuse_rate {
+            current.saturating_mul(2)
+        } else {
+            current
+        };
+        let tuned = tuned.clamp(
+            Duration::from_secs(auto_tune.min_idle_timeout_secs),
+            Duration::from_secs(auto_tune.max_idle_timeout_secs),
+        );
+        if tuned != current {
+            info!(
+                "Connection pool idle timeout for {} changed from {:?} to {:?} (dead on arrival {:.0}%, reused {:.0}% of the last {} checkouts)",
+                key,
+                current,
+                tuned,
+                doa_rate * 100.0,
+                reuse_rate * 100.0,
+                window.exchanges
+            );
+        }
+        state.idle_timeout = Some(tuned);
+    }
+
+    /// Idle timeout in effect for `key`
+    pub fn idle_timeout_for(&self, key: &PoolKey) -> Duration {
+        self.targets
+            .lock()
+            .unwrap()
+            .get(key)
+            .and_then(|state| state.idle_timeout)
+            .unwrap_or(self.idle_timeout)
+    }
+
+    /// Take a permit for a new connection according to the exhaustion policy
+    async fn acquire_permit(&self, target_addr: SocketAddr) -> Result<OwnedSemaphorePermit> {
+        if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+            self.record_wait(Duration::ZERO);
+            return Ok(permit);
+        }
+        if self.on_exhausted == OnExhausted::Reject {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            warn!("Connection pool exhausted, rejecting connection to {}", target_addr);
+            return Err(ProxyError::PoolExhausted(format!(
+                "all {} connection slots in use",
+                self.max_total_connections
+            )));
+        }
+
+        let started = Instant::now();
+        let acquired = {
+            let _queued = QueuedGuard::new(&self.queued);
+            let acquire = self.semaphore.clone().acquire_owned();
+            match self.on_exhausted {
+                OnExhausted::WaitTimeout => timeout(self.wait_timeout, acquire).await.ok(),
+                _ => Some(acquire.await),
+            }
+        };
+        self.record_wait(started.elapsed());
+
+        match acquired {
+            Some(Ok(permit)) => Ok(permit),
+            Some(Err(_)) => Err(ProxyError::PoolExhausted("connection pool closed".to_string())),
+            None => {
+                self.timed_out.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Connection pool exhausted, no slot for {} within {:?}",
+                    target_addr, self.wait_timeout
+                );
+                Err(ProxyError::PoolExhausted(format!(
+                    "no connection slot free within {:?}",
+                    self.wait_timeout
+                )))
+            }
+        }
+    }
+
+    fn record_wait(&self, waited: Duration) {
+        let bucket = WAIT_BUCKETS.iter().position(|bound| waited < *bound).unwrap_or(WAIT_BUCKETS.len());
+        self.wait_histogram[bucket].fetch_add(1, Ordering::Relaxed);
     }
 
     /// Return a connection to the pool
+    ///
+    /// A connection opened outside the pool (by an outbound) is kept only
+    /// while a permit is free; it never waits for one.
     pub async fn return_connection(&self, mut connection: PooledConnection) {
-        let target_addr = connection.target_addr();
+        let key = connection.key.clone();
 
         // Check if the connection is still valid
-        if connection.is_expired(self.idle_timeout) {
-            debug!("Connection to {} expired, dropping", target_addr);
+        if connection.is_expired(self.idle_timeout_for(&key)) {
+            debug!("Connection to {} expired, dropping", key);
             return;
         }
+        if !connection.is_reusable() {
+            debug!("Connection to {} closed or has unread data, dropping", key);
+            return;
+        }
+        if connection.permit.is_none() {
+            match self.semaphore.clone().try_acquire_owned() {
+                Ok(permit) => connection.permit = Some(permit),
+                Err(_) => {
+                    debug!("No connection slot free to pool {}, dropping", key);
+                    return;
+                }
+            }
+        }
 
         // Update last used time
         connection.update_last_used();
+        connection.checkout = None;
 
         // Add to pool if there's space
         let mut pools = self.pools.write().await;
-        let pool = pools.entry(target_addr).or_insert_with(Vec::new);
+        let pool = pools.entry(key).or_insert_with(Vec::new);
 
         if pool.len() < self.max_connections_per_target {
-            debug!("Returning connection to pool for {}", target_addr);
+            debug!("Returning connection to pool for {}", connection.key);
             pool.push(connection);
         } else {
-            debug!("Pool for {} is full, dropping connection", target_addr);
+            debug!("Pool for {} is full, dropping connection", connection.key);
         }
     }
 
-    /// Get a connection from the pool for a specific target
-    async fn get_from_pool(&self, target_addr: SocketAddr) -> Result<Option<PooledConnection>> {
+    /// Get a connection from the pool for a specific target, skipping ones
+    /// that expired or were closed while idle
+    async fn get_from_pool(&self, key: &PoolKey) -> Option<PooledConnection> {
+        let idle_timeout = self.idle_timeout_for(key);
         let mut pools = self.pools.write().await;
+        let pool = pools.get_mut(key)?;
 
-        if let Some(pool) = pools.get_mut(&target_addr) {
-            // Remove expired connections
-            pool.retain(|conn| !conn.is_expired(self.idle_timeout));
+        // Remove expired connections
+        pool.retain(|conn| !conn.is_expired(idle_timeout));
 
-            // Return the first available connection
-            if let Some(connection) = pool.pop() {
-                debug!("Found pooled connection to {}", target_addr);
-                return Ok(Some(connection));
+        // Return the most recently used connection that is still open
+        while let Some(connection) = pool.pop() {
+            if connection.is_reusable() {
+                debug!("Found pooled connection to {}", key);
+                return Some(connection);
             }
+            debug!("Pooled connection to {} was closed while idle, dropping", key);
         }
-
-        Ok(None)
+        None
     }
 
     /// Clean up expired connections
@@ -146,9 +470,10 @@ impl ConnectionPool {
         let mut pools = self.pools.write().await;
         let mut total_cleaned = 0;
 
-        for (_target_addr, pool) in pools.iter_mut() {
+        for (key, pool) in pools.iter_mut() {
+            let idle_timeout = self.idle_timeout_for(key);
             let before = pool.len();
-            pool.retain(|conn| !conn.is_expired(self.idle_timeout));
+            pool.retain(|conn| !conn.is_expired(idle_timeout));
             let after = pool.len();
             total_cleaned += before - after;
         }
@@ -175,8 +500,119 @@ impl ConnectionPool {
             total_connections,
             targets,
             available_permits: self.semaphore.available_permits(),
+            queued: self.queued.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+            wait_histogram: std::array::from_fn(|i| self.wait_histogram[i].load(Ordering::Relaxed)),
+            per_target: self.target_stats(),
         }
     }
+
+    /// Reuse statistics of every target with a recorded first exchange, by target and outbound
+    pub fn target_stats(&self) -> Vec<TargetPoolStats> {
+        let targets = self.targets.lock().unwrap();
+        let mut stats: Vec<_> = targets
+            .iter()
+            .map(|(target, state)| TargetPoolStats {
+                target: target.clone(),
+                pooled: state.pooled,
+                fresh: state.fresh,
+                dead_on_arrival: state.dead_on_arrival,
+                pooled_latency: state.pooled_latency.mean(),
+                fresh_latency: state.fresh_latency.mean(),
+                idle_timeout: state.idle_timeout.unwrap_or(self.idle_timeout),
+            })
+            .collect();
+        stats.sort_by_cached_key(|stats| stats.target.to_string());
+        stats
+    }
+}
+
+/// Checkouts of one target and how their first exchange went
+#[derive(Debug, Default)]
+struct TargetState {
+    pooled: u64,
+    fresh: u64,
+    dead_on_arrival: u64,
+    pooled_latency: LatencySum,
+    fresh_latency: LatencySum,
+    /// Set once auto-tuning looked at the target
+    idle_timeout: Option<Duration>,
+    /// Exchanges since the last auto-tune step
+    window: TuneWindow,
+}
+
+impl TargetState {
+    fn record(&mut self, checkout: Checkout, answered_at: Option<Instant>) {
+        self.window.exchanges += 1;
+        if checkout.pooled {
+            self.pooled += 1;
+            self.window.pooled += 1;
+        } else {
+            self.fresh += 1;
+        }
+        let Some(answered_at) = answered_at else {
+            if checkout.pooled {
+                self.dead_on_arrival += 1;
+                self.window.dead_on_arrival += 1;
+            }
+            return;
+        };
+        let latency = answered_at.saturating_duration_since(checkout.requested_at);
+        if checkout.pooled {
+            self.pooled_latency.add(latency);
+        } else {
+            self.fresh_latency.add(latency);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct TuneWindow {
+    exchanges: u64,
+    pooled: u64,
+    dead_on_arrival: u64,
+}
+
+#[derive(Debug, Default)]
+struct LatencySum {
+    total: Duration,
+    count: u32,
+}
+
+impl LatencySum {
+    fn add(&mut self, latency: Duration) {
+        self.total = self.total.saturating_add(latency);
+        self.count = self.count.saturating_add(1);
+    }
+
+    fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| self.total / self.count)
+    }
+}
+
+fn ratio(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 / whole as f64
+    }
+}
+
+/// Counts a request as queued for as long as it waits for a permit
+struct QueuedGuard<'a>(&'a AtomicUsize);
+
+impl<'a> QueuedGuard<'a> {
+    fn new(queued: &'a AtomicUsize) -> Self {
+        queued.fetch_add(1, Ordering::Relaxed);
+        Self(queued)
+    }
+}
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 /// Connection pool statistics
@@ -185,54 +621,150 @@ pub struct PoolStats {
     pub total_connections: usize,
     pub targets: usize,
     pub available_permits: usize,
+    /// Requests currently waiting for a permit
+    pub queued: usize,
+    /// Requests refused with `OnExhausted::Reject`
+    pub rejected: u64,
+    /// Requests that gave up with `OnExhausted::WaitTimeout`
+    pub timed_out: u64,
+    /// Permit wait times, bucketed by `WAIT_BUCKETS` plus one overflow bucket
+    pub wait_histogram: [u64; WAIT_BUCKETS.len() + 1],
+    /// How well pooling works for each target
+    pub per_target: Vec<TargetPoolStats>,
+}
+
+impl fmt::Display for PoolStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} pooled connections to {} targets, {} permits free, {} queued, {} rejected, {} timed out",
+            self.total_connections, self.targets, self.available_permits, self.queued, self.rejected, self.timed_out
+        )?;
+        for target in &self.per_target {
+            write!(f, "\n  {}", target)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checkouts of one target, counting only those whose first exchange was recorded
+#[derive(Debug, Clone, PartialEq)]
+pub struct TargetPoolStats {
+    pub target: PoolKey,
+    /// Checkouts served by a pooled connection
+    pub pooled: u64,
+    /// Checkouts that opened a new connection
+    pub fresh: u64,
+    /// Pooled connections whose first write or read failed
+    pub dead_on_arrival: u64,
+    /// Mean time from requesting a pooled connection to its first response byte
+    pub pooled_latency: Option<Duration>,
+    /// The same for new connections, including the TCP handshake
+    pub fresh_latency: Option<Duration>,
+    /// Idle timeout in effect for the target
+    pub idle_timeout: Duration,
+}
+
+impl TargetPoolStats {
+    /// Share of checkouts served from the pool
+    pub fn reuse_rate(&self) -> f64 {
+        ratio(self.pooled, self.pooled + self.fresh)
+    }
+
+    /// Share of pooled connections that were dead on arrival
+    pub fn doa_rate(&self) -> f64 {
+        ratio(self.dead_on_arrival, self.pooled)
+    }
+
+    /// How much sooner a pooled connection delivers its first byte
+    pub fn latency_saving(&self) -> Option<Duration> {
+        Some(self.fresh_latency?.saturating_sub(self.pooled_latency?))
+    }
+}
+
+impl fmt::Display for TargetPoolStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} pooled / {} fresh (reuse {:.0}%, dead on arrival {:.0}%)",
+            self.target,
+            self.pooled,
+            self.fresh,
+            self.reuse_rate() * 100.0,
+            self.doa_rate() * 100.0
+        )?;
+        if let Some(saving) = self.latency_saving() {
+            write!(f, ", first byte {:?} sooner when pooled", saving)?;
+        }
+        write!(f, ", idle timeout {:?}", self.idle_timeout)
+    }
 }
 
 /// Global connection pool
-static mut GLOBAL_CONNECTION_POOL: Option<ConnectionPool> = None;
+static GLOBAL_CONNECTION_POOL: OnceLock<ConnectionPool> = OnceLock::new();
 
-/// Initialize the global connection pool
+/// Initialize the global connection pool; it can be set only once
 pub fn init_global_connection_pool(
     max_connections_per_target: usize,
     max_total_connections: usize,
     connection_timeout: Duration,
     idle_timeout: Duration,
+    on_exhausted: OnExhausted,
+    wait_timeout: Duration,
+    auto_tune: PoolAutoTuneConfig,
 ) -> Result<()> {
-    unsafe {
-        GLOBAL_CONNECTION_POOL = Some(ConnectionPool::new(
-            max_connections_per_target,
-            max_total_connections,
-            connection_timeout,
-            idle_timeout,
-        ));
-    }
-    Ok(())
+    let pool = ConnectionPool::new(max_connections_per_target, max_total_connections, connection_timeout, idle_timeout)
+        .with_exhaustion_policy(on_exhausted, wait_timeout)
+        .with_auto_tune(auto_tune);
+    GLOBAL_CONNECTION_POOL.set(pool).map_err(|_| ProxyError::AlreadyInitialized("Global connection pool"))
+}
+
+/// Get the global connection pool, or an error before `init_global_connection_pool`
+pub fn try_get_global_connection_pool() -> Result<&'static ConnectionPool> {
+    GLOBAL_CONNECTION_POOL.get().ok_or(ProxyError::NotInitialized("Global connection pool"))
 }
 
 /// Get the global connection pool
+///
+/// Panics before `init_global_connection_pool`.
 pub fn get_global_connection_pool() -> &'static ConnectionPool {
-    unsafe {
-        GLOBAL_CONNECTION_POOL.as_ref()
-            .expect("Global connection pool not initialized")
-    }
+    try_get_global_connection_pool().unwrap_or_else(|e| panic!("{}", e))
 }
 
 /// Start the connection pool cleanup task
 pub async fn start_connection_pool_cleanup(interval: Duration) {
     let pool = get_global_connection_pool();
 
-    tokio::spawn(async move {
+    let spawned = get_global_task_tracker().spawn(TaskGroup::PoolCleanup, async move {
         let mut interval = tokio::time::interval(interval);
         loop {
             interval.tick().await;
             pool.cleanup_expired().await;
         }
     });
+    if let Err(e) = spawned {
+        warn!("Connection pool cleanup not started: {}", e);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::net::{IpAddr, Ipv4Addr};
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn test_global_pool_initialized_once() {
+        assert!(matches!(try_get_global_connection_pool(), Err(ProxyError::NotInitialized(_))));
+        let init = |max_total| {
+            let timeout = Duration::from_secs(5);
+            init_global_connection_pool(10, max_total, timeout, timeout, OnExhausted::default(), timeout, PoolAutoTuneConfig::default())
+        };
+        init(100).unwrap();
+        let err = init(200).unwrap_err();
+        assert!(matches!(err, ProxyError::AlreadyInitialized(_)), "{}", err);
+        assert_eq!(get_global_connection_pool().stats().await.available_permits, 100);
+    }
 
     #[tokio::test]
     async fn test_connection_pool_creation() {
@@ -248,4 +780,242 @@ mod tests {
         let stats = pool.stats().await;
         assert_eq!(stats.available_permits, 50);
     }
+
+    /// A pool with two connection slots and a listener to connect to
+    async fn small_pool(on_exhausted: OnExhausted) -> (ConnectionPool, SocketAddr, tokio::net::TcpListener) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let pool = ConnectionPool::new(2, 2, Duration::from_secs(5), Duration::from_secs(30))
+            .with_exhaustion_policy(on_exhausted, Duration::from_millis(50));
+        (pool, target, listener)
+    }
+
+    #[tokio::test]
+    async fn test_permits_live_with_connections() {
+        let (pool, target, _listener) = small_pool(OnExhausted::Reject).await;
+        let first = pool.get_connection(target).await.unwrap();
+        let second = pool.get_connection(target).await.unwrap();
+        assert_eq!(pool.stats().await.available_permits, 0);
+
+        // 归还到池中的连接仍占用许可，复用时不再申请
+        pool.return_connection(first).await;
+        assert_eq!(pool.stats().await.available_permits, 0);
+        let reused = pool.get_connection(target).await.unwrap();
+        assert_eq!(pool.stats().await.total_connections, 0);
+
+        drop(second);
+        drop(reused);
+        assert_eq!(pool.stats().await.available_permits, 2);
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_pool_waits() {
+        let (pool, target, _listener) = small_pool(OnExhausted::Wait).await;
+        let pool = Arc::new(pool);
+        let first = pool.get_connection(target).await.unwrap();
+        let _second = pool.get_connection(target).await.unwrap();
+
+        let waiter = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.get_connection(target).await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(pool.stats().await.queued, 1);
+        assert!(!waiter.is_finished());
+
+        drop(first);
+        let third = waiter.await.unwrap().unwrap();
+        assert_eq!(third.key(), &PoolKey::direct(target));
+        let stats = pool.stats().await;
+        assert_eq!((stats.queued, stats.available_permits, stats.rejected, stats.timed_out), (0, 0, 0, 0));
+        // 两次立即获得许可，一次排队超过10ms
+        assert_eq!(stats.wait_histogram[0], 2);
+        assert_eq!(stats.wait_histogram.iter().sum::<u64>(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_pool_wait_timeout() {
+        let (pool, target, _listener) = small_pool(OnExhausted::WaitTimeout).await;
+        let _held = [pool.get_connection(target).await.unwrap(), pool.get_connection(target).await.unwrap()];
+
+        let started = Instant::now();
+        let err = pool.get_connection(target).await.err().unwrap();
+        assert!(matches!(err, ProxyError::PoolExhausted(_)), "{}", err);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(err.socks5_reply_code(), 0x01);
+
+        let stats = pool.stats().await;
+        assert_eq!((stats.queued, stats.timed_out, stats.rejected), (0, 1, 0));
+        assert_eq!(stats.available_permits, 0);
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_pool_rejects() {
+        let (pool, target, _listener) = small_pool(OnExhausted::Reject).await;
+        let _held = [pool.get_connection(target).await.unwrap(), pool.get_connection(target).await.unwrap()];
+
+        let started = Instant::now();
+        let err = pool.get_connection(target).await.err().unwrap();
+        assert!(matches!(err, ProxyError::PoolExhausted(_)), "{}", err);
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        let stats = pool.stats().await;
+        assert_eq!((stats.queued, stats.timed_out, stats.rejected), (0, 0, 1));
+    }
+
+    /// Answers each request on a connection with "ok"; with `reply_once` only
+    /// the first one, closing the connection when a second request arrives, like
+    /// a server that forgot the idle session without telling the client
+    async fn reply_server(reply_once: bool) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 16];
+                    let mut replied = false;
+                    while matches!(stream.read(&mut buf).await, Ok(n) if n > 0) {
+                        if (reply_once && replied) || stream.write_all(b"ok").await.is_err() {
+                            break;
+                        }
+                        replied = true;
+                    }
+                });
+            }
+        });
+        target
+    }
+
+    fn auto_tune(min_secs: u64, max_secs: u64, window: u32) -> PoolAutoTuneConfig {
+        PoolAutoTuneConfig {
+            enabled: true,
+            min_idle_timeout_secs: min_secs,
+            max_idle_timeout_secs: max_secs,
+            window,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dead_on_arrival_shrinks_idle_timeout() {
+        let target = reply_server(true).await;
+        let pool = ConnectionPool::new(2, 10, Duration::from_secs(5), Duration::from_secs(40))
+            .with_auto_tune(auto_tune(5, 900, 2));
+
+        let mut timeouts = Vec::new();
+        for _ in 0..6 {
+            let mut connection = pool.get_connection(target).await.unwrap();
+            let mut response = [0u8; 2];
+            if pool.first_exchange(&mut connection, b"ping", &mut response).await.is_ok() {
+                // 放回时连接仍然打开，下次取出后的请求才失败
+                pool.return_connection(connection).await;
+            } else {
+                assert!(connection.is_reused());
+            }
+            timeouts.push(pool.idle_timeout_for(&PoolKey::direct(target)).as_secs());
+        }
+        assert_eq!(timeouts, [40, 20, 20, 10, 10, 5]);
+
+        let stats = pool.stats().await.per_target;
+        assert_eq!(stats.len(), 1);
+        assert_eq!((stats[0].pooled, stats[0].fresh, stats[0].dead_on_arrival), (3, 3, 3));
+        assert_eq!(stats[0].doa_rate(), 1.0);
+        assert_eq!(stats[0].idle_timeout, Duration::from_secs(5));
+        assert!(stats[0].fresh_latency.is_some() && stats[0].pooled_latency.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_low_reuse_grows_idle_timeout_to_cap() {
+        let target = reply_server(false).await;
+        let pool = ConnectionPool::new(2, 10, Duration::from_secs(5), Duration::from_secs(60))
+            .with_auto_tune(PoolAutoTuneConfig { min_reuse_rate: 0.75, ..auto_tune(5, 240, 4) });
+
+        // 每轮需要两个连接但只放回一个：没有死连接，复用率只有一半
+        let mut timeouts = Vec::new();
+        for _ in 0..6 {
+            let mut first = pool.get_connection(target).await.unwrap();
+            let mut second = pool.get_connection(target).await.unwrap();
+            let mut response = [0u8; 2];
+            pool.first_exchange(&mut first, b"ping", &mut response).await.unwrap();
+            pool.first_exchange(&mut second, b"ping", &mut response).await.unwrap();
+            pool.return_connection(first).await;
+            timeouts.push(pool.idle_timeout_for(&PoolKey::direct(target)).as_secs());
+        }
+        assert_eq!(timeouts, [60, 120, 120, 240, 240, 240]);
+
+        let stats = pool.stats().await;
+        let target_stats = &stats.per_target[0];
+        assert_eq!((target_stats.pooled, target_stats.fresh, target_stats.dead_on_arrival), (5, 7, 0));
+        assert!(target_stats.latency_saving().is_some());
+        assert!(stats.to_string().contains("5 pooled / 7 fresh"), "{}", stats);
+    }
+
+    #[tokio::test]
+    async fn test_closed_idle_connections_not_reused() {
+        let (pool, target, listener) = small_pool(OnExhausted::Reject).await;
+        let key = PoolKey::direct(target);
+
+        let connection = pool.get_connection(target).await.unwrap();
+        let (server_side, _) = listener.accept().await.unwrap();
+        pool.return_connection(connection).await;
+        assert_eq!(pool.stats().await.total_connections, 1);
+        // 空闲期间对端关闭（FIN）
+        drop(server_side);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(pool.checkout(&key).await.is_none());
+        assert_eq!(pool.stats().await.total_connections, 0);
+
+        // 空闲期间收到的数据属于上一个使用者，同样不能复用
+        let connection = pool.get_connection(target).await.unwrap();
+        let (mut server_side, _) = listener.accept().await.unwrap();
+        pool.return_connection(connection).await;
+        server_side.write_all(b"late").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(pool.checkout(&key).await.is_none());
+
+        // 放回时已关闭的连接直接丢弃
+        let connection = pool.get_connection(target).await.unwrap();
+        drop(listener.accept().await.unwrap());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        pool.return_connection(connection).await;
+        assert_eq!(pool.stats().await.total_connections, 0);
+        assert_eq!(pool.stats().await.available_permits, 2);
+    }
+
+    #[tokio::test]
+    async fn test_returned_outbound_connection_takes_a_permit() {
+        let (pool, target, listener) = small_pool(OnExhausted::Reject).await;
+        let key = PoolKey::new("proxy-a", "example.com:443".parse().unwrap());
+        let mut accepted = Vec::new();
+        for _ in 0..3 {
+            let stream = TcpStream::connect(target).await.unwrap();
+            accepted.push(listener.accept().await.unwrap());
+            pool.return_connection(PooledConnection::new(stream, key.clone())).await;
+        }
+        // 两个许可都被占用，第三个连接不入池
+        let stats = pool.stats().await;
+        assert_eq!((stats.total_connections, stats.available_permits), (2, 0));
+
+        assert!(pool.checkout(&PoolKey::new("proxy-b", key.target.clone())).await.is_none());
+        let reused = pool.checkout(&key).await.unwrap();
+        assert!(reused.is_reused());
+        assert_eq!(reused.key().to_string(), "example.com:443 via proxy-a");
+    }
+
+    #[tokio::test]
+    async fn test_stats_without_auto_tune() {
+        let target = reply_server(false).await;
+        let pool = ConnectionPool::new(2, 10, Duration::from_secs(5), Duration::from_secs(30));
+        for _ in 0..3 {
+            let mut connection = pool.get_connection(target).await.unwrap();
+            let mut response = [0u8; 2];
+            pool.first_exchange(&mut connection, b"ping", &mut response).await.unwrap();
+            // 只记录首次交互
+            pool.record_first_exchange(&mut connection, false);
+            pool.return_connection(connection).await;
+        }
+        let stats = pool.target_stats();
+        assert_eq!((stats[0].pooled, stats[0].fresh, stats[0].dead_on_arrival), (2, 1, 0));
+        assert_eq!(pool.idle_timeout_for(&PoolKey::direct(target)), Duration::from_secs(30));
+    }
 }
@@ -0,0 +1,307 @@
+// 新建连接速率限制：按连接数（不是字节）的令牌桶，全局一个、每个出站一个，所有入站共享
+use crate::config::{Config, ConnectionRatePolicy};
+use crate::error::{ProxyError, Result};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::time::Instant;
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Counters of one connection rate bucket
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionRateStats {
+    /// Configured connections per second
+    pub limit: u32,
+    /// Connections admitted during the last full second
+    pub rate: u64,
+    /// Connections held back until the bucket refilled
+    pub throttled: u64,
+    /// Connections refused over the limit
+    pub rejected: u64,
+}
+
+/// Counters of the global bucket and of each limited outbound
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionRateReport {
+    pub global: Option<ConnectionRateStats>,
+    /// Limited outbounds, sorted by name
+    pub outbounds: Vec<(String, ConnectionRateStats)>,
+}
+
+struct BucketState {
+    /// 可为负：已预约但尚未到时间的连接
+    tokens: f64,
+    refilled: Instant,
+    window_start: Instant,
+    window_admitted: u64,
+    last_rate: u64,
+}
+
+/// Token bucket of `limit` connections per second, holding at most one second's worth
+struct RateBucket {
+    limit: u32,
+    state: Mutex<BucketState>,
+    throttled: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl RateBucket {
+    fn new(limit: u32) -> Self {
+        let now = Instant::now();
+        Self {
+            limit,
+            state: Mutex::new(BucketState {
+                tokens: f64::from(limit),
+                refilled: now,
+                window_start: now,
+                window_admitted: 0,
+                last_rate: 0,
+            }),
+            throttled: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Take a token, possibly one that is only available after the returned
+    /// wait; None when that wait would exceed `max_delay`
+    fn reserve(&self, now: Instant, max_delay: Duration) -> Option<Duration> {
+        let rate = f64::from(self.limit);
+        let mut state = self.state.lock().unwrap();
+        let elapsed = now.saturating_duration_since(state.refilled).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * rate).min(rate);
+        state.refilled = now;
+
+        let wait = if state.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - state.tokens) / rate)
+        };
+        if wait > max_delay {
+            return None;
+        }
+        state.tokens -= 1.0;
+
+        let since = now.saturating_duration_since(state.window_start);
+        if since >= RATE_WINDOW {
+            state.last_rate = if since < RATE_WINDOW * 2 { state.window_admitted } else { 0 };
+            state.window_start = now;
+            state.window_admitted = 0;
+        }
+        state.window_admitted += 1;
+        Some(wait)
+    }
+
+    /// Give back a token reserved for a connection another bucket refused
+    fn refund(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.tokens = (state.tokens + 1.0).min(f64::from(self.limit));
+        state.window_admitted = state.window_admitted.saturating_sub(1);
+    }
+
+    fn stats(&self, now: Instant) -> ConnectionRateStats {
+        let state = self.state.lock().unwrap();
+        let since = now.saturating_duration_since(state.window_start);
+        let rate = if since < RATE_WINDOW {
+            state.last_rate
+        } else if since < RATE_WINDOW * 2 {
+            state.window_admitted
+        } else {
+            0
+        };
+        ConnectionRateStats {
+            limit: self.limit,
+            rate,
+            throttled: self.throttled.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Rate limits on new upstream connections: `server.max_new_connections_per_sec`
+/// and each outbound's `max_connections_per_sec`
+///
+/// Only connections dialed for clients are counted; health checks dial the
+/// outbound directly and probe requests are answered without dialing.
+pub struct ConnectionRateLimiter {
+    global: Option<RateBucket>,
+    outbounds: HashMap<String, RateBucket>,
+    policy: ConnectionRatePolicy,
+    max_delay: Duration,
+}
+
+impl ConnectionRateLimiter {
+    pub fn new(config: &Config) -> Self {
+        let server = &config.server;
+        Self {
+            global: server.max_new_connections_per_sec.map(RateBucket::new),
+            outbounds: config
+                .outbounds
+                .iter()
+                .filter_map(|outbound| Some((outbound.name.clone(), RateBucket::new(outbound.max_connections_per_sec?))))
+                .collect(),
+            policy: server.connection_rate_policy,
+            max_delay: Duration::from_millis(server.max_connection_queue_delay_ms),
+        }
+    }
+
+    /// Limiter that never holds back a connection
+    pub fn unlimited() -> Self {
+        Self {
+            global: None,
+            outbounds: HashMap::new(),
+            policy: ConnectionRatePolicy::default(),
+            max_delay: Duration::ZERO,
+        }
+    }
+
+    /// Wait for a token of the global bucket and of the first of `outbounds`
+    /// that has a limit, or fail when the policy does not allow waiting that long
+    ///
+    /// Pass the outbound that is dialed followed by the group member it selects.
+    pub async fn acquire(&self, outbounds: &[&str]) -> Result<()> {
+        let outbound = outbounds.iter().find_map(|name| Some((*name, self.outbounds.get(*name)?)));
+        let buckets = outbound
+            .map(|(name, bucket)| (format!("outbound {}", name), bucket))
+            .into_iter()
+            .chain(self.global.as_ref().map(|bucket| ("global limit".to_string(), bucket)));
+        let max_delay = match self.policy {
+            ConnectionRatePolicy::Delay => self.max_delay,
+            ConnectionRatePolicy::Reject => Duration::ZERO,
+        };
+
+        let now = Instant::now();
+        let mut reserved: Vec<(&RateBucket, Duration)> = Vec::with_capacity(2);
+        for (label, bucket) in buckets {
+            match bucket.reserve(now, max_delay) {
+                Some(wait) => reserved.push((bucket, wait)),
+                None => {
+                    bucket.rejected.fetch_add(1, Ordering::Relaxed);
+                    for (bucket, _) in reserved {
+                        bucket.refund();
+                    }
+                    return Err(ProxyError::RateLimited(format!("{} of {} connections/s", label, bucket.limit)));
+                }
+            }
+        }
+
+        let mut wait = Duration::ZERO;
+        for (bucket, bucket_wait) in reserved {
+            if !bucket_wait.is_zero() {
+                bucket.throttled.fetch_add(1, Ordering::Relaxed);
+                wait = wait.max(bucket_wait);
+            }
+        }
+        if !wait.is_zero() {
+            tokio::time::sleep_until(now + wait).await;
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> ConnectionRateReport {
+        let now = Instant::now();
+        let mut outbounds: Vec<_> = self.outbounds.iter().map(|(name, bucket)| (name.clone(), bucket.stats(now))).collect();
+        outbounds.sort_by(|a, b| a.0.cmp(&b.0));
+        ConnectionRateReport {
+            global: self.global.as_ref().map(|bucket| bucket.stats(now)),
+            outbounds,
+        }
+    }
+}
+
+static GLOBAL_CONNECTION_RATE_LIMITER: OnceLock<ConnectionRateLimiter> = OnceLock::new();
+
+/// Initialize the connection rate limits shared by all inbounds
+pub fn init_global_connection_rate_limiter(config: &Config) {
+    let _ = GLOBAL_CONNECTION_RATE_LIMITER.set(ConnectionRateLimiter::new(config));
+}
+
+/// Get the global connection rate limiter (unlimited when not initialized)
+pub fn get_global_connection_rate_limiter() -> &'static ConnectionRateLimiter {
+    GLOBAL_CONNECTION_RATE_LIMITER.get_or_init(ConnectionRateLimiter::unlimited)
+}
+
+/// Counters of the global connection rate limiter
+pub fn connection_rate_stats() -> ConnectionRateReport {
+    get_global_connection_rate_limiter().stats()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OutboundConfig;
+
+    fn limiter(policy: ConnectionRatePolicy, global: Option<u32>, outbound: Option<u32>) -> ConnectionRateLimiter {
+        let mut config = Config::default();
+        config.server.max_new_connections_per_sec = global;
+        config.server.connection_rate_policy = policy;
+        config.server.max_connection_queue_delay_ms = 1000;
+        config.outbounds = vec![OutboundConfig { max_connections_per_sec: outbound, ..OutboundConfig::direct("provider") }];
+        ConnectionRateLimiter::new(&config)
+    }
+
+    /// When each of `count` simultaneous connects is admitted (ms after start), None when refused
+    async fn burst(limiter: &'static ConnectionRateLimiter, count: usize) -> Vec<Option<u64>> {
+        let started = Instant::now();
+        let attempts: Vec<_> = (0..count)
+            .map(|_| {
+                tokio::spawn(async move {
+                    let admitted = limiter.acquire(&["provider"]).await.ok();
+                    admitted.map(|_| started.elapsed().as_millis() as u64)
+                })
+            })
+            .collect();
+        let mut admitted = Vec::with_capacity(count);
+        for attempt in attempts {
+            admitted.push(attempt.await.unwrap());
+        }
+        admitted
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delay_policy_paces_connections() {
+        let limiter: &'static _ = Box::leak(Box::new(limiter(ConnectionRatePolicy::Delay, None, Some(2))));
+        // 每秒 2 个：桶内 2 个立即放行，之后每 500ms 一个，超过 1s 排队上限的被拒绝
+        assert_eq!(burst(limiter, 6).await, [Some(0), Some(0), Some(500), Some(1000), None, None]);
+        let stats = limiter.stats().outbounds;
+        assert_eq!(stats[0].0, "provider");
+        assert_eq!((stats[0].1.throttled, stats[0].1.rejected), (2, 2));
+        assert_eq!(stats[0].1.rate, 4);
+
+        // 空闲后桶重新填满
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(burst(limiter, 2).await, [Some(0), Some(0)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reject_policy_refuses_without_waiting() {
+        let limiter: &'static _ = Box::leak(Box::new(limiter(ConnectionRatePolicy::Reject, None, Some(2))));
+        assert_eq!(burst(limiter, 6).await, [Some(0), Some(0), None, None, None, None]);
+        let err = limiter.acquire(&["provider"]).await.unwrap_err();
+        assert_eq!(err.socks5_reply_code(), 0x01);
+        assert!(err.to_string().contains("outbound provider of 2 connections/s"), "{}", err);
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(burst(limiter, 2).await, [Some(0), None]);
+        let stats = limiter.stats().outbounds[0].1;
+        assert_eq!((stats.throttled, stats.rejected), (0, 6));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_global_bucket_shared_by_outbounds() {
+        let limiter: &'static _ = Box::leak(Box::new(limiter(ConnectionRatePolicy::Reject, Some(3), Some(2))));
+        // 出站桶先拒绝时不消耗全局令牌
+        assert_eq!(burst(limiter, 3).await, [Some(0), Some(0), None]);
+        assert!(limiter.acquire(&["other"]).await.is_ok());
+        let err = limiter.acquire(&["other"]).await.unwrap_err();
+        assert!(err.to_string().contains("global limit of 3"), "{}", err);
+        // 组成员的限制同样生效
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(limiter.acquire(&["group", "provider"]).await.is_ok());
+
+        let report = limiter.stats();
+        assert_eq!(report.global.map(|g| (g.limit, g.rejected)), Some((3, 1)));
+        assert_eq!(report.outbounds[0].1.rejected, 1);
+    }
+}
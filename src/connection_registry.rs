@@ -0,0 +1,473 @@
+// 活动连接注册表：记录每个连接的阶段、流量与最后活动时间
+use crate::protocol::TargetAddr;
+use crate::zero_copy::RelayResult;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// Lifecycle phase of a proxied connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionPhase {
+    /// Waiting for the client's method selection
+    Greeting,
+    /// Username/password sub-negotiation
+    Auth,
+    /// Waiting for the client's request
+    Request,
+    Connecting,
+    Relaying,
+}
+
+impl ConnectionPhase {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => ConnectionPhase::Greeting,
+            1 => ConnectionPhase::Auth,
+            2 => ConnectionPhase::Request,
+            3 => ConnectionPhase::Connecting,
+            _ => ConnectionPhase::Relaying,
+        }
+    }
+
+    /// Whether the connection has not reached the relay yet
+    pub fn is_pre_relay(self) -> bool {
+        self != ConnectionPhase::Relaying
+    }
+}
+
+impl fmt::Display for ConnectionPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ConnectionPhase::Greeting => "greeting",
+            ConnectionPhase::Auth => "auth",
+            ConnectionPhase::Request => "request",
+            ConnectionPhase::Connecting => "connecting",
+            ConnectionPhase::Relaying => "relaying",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Live state of one tracked connection
+pub struct TrackedConnection {
+    id: u64,
+    client: SocketAddr,
+    target: Mutex<Option<String>>,
+    /// 规则改写后实际连接的目标
+    rewritten_to: Mutex<Option<String>>,
+    outbound: Mutex<Option<String>>,
+    /// SOCKS5 用户名（如有）
+    user: Mutex<Option<String>>,
+    /// 用户名密码已校验
+    authenticated: AtomicBool,
+    /// dry-run 模式下本应拨号的地址
+    dry_run: Mutex<Option<TargetAddr>>,
+    /// 客户端的能力探测请求，已直接应答
+    probe: AtomicBool,
+    /// 路由所用的路由配置，None 为默认路由
+    profile: Mutex<Option<String>>,
+    /// 转发结束的方式，未进入转发或转发出错时为 None
+    relay_result: Mutex<Option<RelayResult>>,
+    phase: AtomicU8,
+    /// Milliseconds since `started` when the current phase began
+    phase_since_ms: AtomicU64,
+    started: Instant,
+    /// Milliseconds since `started` of the last byte in either direction
+    last_activity_ms: AtomicU64,
+    /// Milliseconds since `started` until the target connected, plus one (0: not yet)
+    connected_ms: AtomicU64,
+    /// Milliseconds since `started` of the first byte from the target, plus one (0: none yet)
+    first_download_ms: AtomicU64,
+    upload: AtomicU64,
+    download: AtomicU64,
+    cancel: CancellationToken,
+}
+
+impl TrackedConnection {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn set_phase(&self, phase: ConnectionPhase) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.phase_since_ms.store(elapsed, Ordering::Relaxed);
+        self.phase.store(phase as u8, Ordering::Relaxed);
+    }
+
+    /// Time spent in the current phase
+    pub fn phase_age(&self) -> Duration {
+        let since = Duration::from_millis(self.phase_since_ms.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(since)
+    }
+
+    /// Resolves with the phase once the connection has spent longer than
+    /// `limit(phase)` in a pre-relay phase; never resolves once relaying
+    pub async fn stuck_before_relay(&self, limit: impl Fn(ConnectionPhase) -> Duration) -> ConnectionPhase {
+        loop {
+            let phase = self.phase();
+            if !phase.is_pre_relay() {
+                return std::future::pending().await;
+            }
+            // 阶段可能在等待期间推进，醒来后按新阶段重新计算
+            match limit(phase).checked_sub(self.phase_age()) {
+                Some(remaining) if !remaining.is_zero() => tokio::time::sleep(remaining).await,
+                _ => return phase,
+            }
+        }
+    }
+
+    pub fn phase(&self) -> ConnectionPhase {
+        ConnectionPhase::from_u8(self.phase.load(Ordering::Relaxed))
+    }
+
+    pub fn set_target(&self, target: impl Into<String>) {
+        *self.target.lock().unwrap() = Some(target.into());
+    }
+
+    /// Record the destination a rule rewrote the target to
+    pub fn set_rewritten_to(&self, target: impl Into<String>) {
+        *self.rewritten_to.lock().unwrap() = Some(target.into());
+    }
+
+    pub fn set_outbound(&self, outbound: impl Into<String>) {
+        *self.outbound.lock().unwrap() = Some(outbound.into());
+    }
+
+    pub fn set_user(&self, user: impl Into<String>) {
+        *self.user.lock().unwrap() = Some(user.into());
+    }
+
+    /// Record that the client proved its credentials as `user`
+    pub fn set_authenticated(&self, user: impl Into<String>) {
+        self.set_user(user);
+        self.authenticated.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated.load(Ordering::Relaxed)
+    }
+
+    /// Record that the connection was only routed, and where it would have been dialed
+    pub fn set_dry_run(&self, would_dial: TargetAddr) {
+        *self.dry_run.lock().unwrap() = Some(would_dial);
+    }
+
+    /// Record that the request was a client probe answered without dialing
+    pub fn set_probe(&self) {
+        self.probe.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_probe(&self) -> bool {
+        self.probe.load(Ordering::Relaxed)
+    }
+
+    /// Record the routing profile the connection was routed with
+    pub fn set_profile(&self, profile: impl Into<String>) {
+        *self.profile.lock().unwrap() = Some(profile.into());
+    }
+
+    /// Record how the relay ended
+    pub fn set_relay_result(&self, result: RelayResult) {
+        *self.relay_result.lock().unwrap() = Some(result);
+    }
+
+    /// Record bytes sent from the client towards the target
+    pub fn add_upload(&self, bytes: u64) {
+        self.upload.fetch_add(bytes, Ordering::Relaxed);
+        self.touch();
+    }
+
+    /// Record bytes sent from the target back to the client
+    pub fn add_download(&self, bytes: u64) {
+        if self.download.fetch_add(bytes, Ordering::Relaxed) == 0 && bytes > 0 {
+            let elapsed = self.started.elapsed().as_millis() as u64;
+            self.first_download_ms.store(elapsed + 1, Ordering::Relaxed);
+        }
+        self.touch();
+    }
+
+    /// When the first byte from the target arrived
+    pub fn first_download_at(&self) -> Option<Instant> {
+        match self.first_download_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(self.started + Duration::from_millis(ms - 1)),
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.last_activity_ms.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Record that the target connection was established
+    pub fn mark_connected(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.connected_ms.store(elapsed + 1, Ordering::Relaxed);
+    }
+
+    /// Time from accept until the target connected
+    pub fn connect_latency(&self) -> Option<Duration> {
+        match self.connected_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(Duration::from_millis(ms - 1)),
+        }
+    }
+
+    /// Time since the last byte in either direction (or since start if none)
+    pub fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last_activity_ms.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last)
+    }
+
+    pub fn age(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.upload.load(Ordering::Relaxed) + self.download.load(Ordering::Relaxed)
+    }
+
+    /// Ask the tasks serving this connection to stop
+    pub fn kill(&self) {
+        self.cancel.cancel();
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Resolves once the connection has been killed
+    pub async fn killed(&self) {
+        self.cancel.cancelled().await
+    }
+
+    pub fn snapshot(&self) -> ConnectionSnapshot {
+        ConnectionSnapshot {
+            id: self.id,
+            client: self.client,
+            target: self.target.lock().unwrap().clone(),
+            rewritten_to: self.rewritten_to.lock().unwrap().clone(),
+            outbound: self.outbound.lock().unwrap().clone(),
+            user: self.user.lock().unwrap().clone(),
+            authenticated: self.is_authenticated(),
+            dry_run: self.dry_run.lock().unwrap().clone(),
+            probe: self.is_probe(),
+            profile: self.profile.lock().unwrap().clone(),
+            relay_result: self.relay_result.lock().unwrap().clone(),
+            phase: self.phase(),
+            age: self.age(),
+            idle: self.idle_for(),
+            connect_latency: self.connect_latency(),
+            upload: self.upload.load(Ordering::Relaxed),
+            download: self.download.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time view of a tracked connection
+#[derive(Debug, Clone)]
+pub struct ConnectionSnapshot {
+    pub id: u64,
+    pub client: SocketAddr,
+    pub target: Option<String>,
+    /// Destination actually dialed when a rule rewrote `target`
+    pub rewritten_to: Option<String>,
+    pub outbound: Option<String>,
+    pub user: Option<String>,
+    /// Whether `user` was checked against the inbound's credentials
+    pub authenticated: bool,
+    /// Address that would have been dialed, for connections served in dry-run mode
+    pub dry_run: Option<TargetAddr>,
+    /// A client probe answered without dialing
+    pub probe: bool,
+    /// Routing profile of the inbound; None for `[router]`
+    pub profile: Option<String>,
+    /// How the relay ended; None before relaying or when it failed
+    pub relay_result: Option<RelayResult>,
+    pub phase: ConnectionPhase,
+    pub age: Duration,
+    pub idle: Duration,
+    pub connect_latency: Option<Duration>,
+    pub upload: u64,
+    pub download: u64,
+}
+
+/// Registry of all live proxied connections
+pub struct ConnectionRegistry {
+    next_id: AtomicU64,
+    connections: RwLock<HashMap<u64, Arc<TrackedConnection>>>,
+    /// 已关闭连接累计的上传/下载字节数
+    closed_upload: AtomicU64,
+    closed_download: AtomicU64,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            connections: RwLock::new(HashMap::new()),
+            closed_upload: AtomicU64::new(0),
+            closed_download: AtomicU64::new(0),
+        }
+    }
+
+    /// Register a new connection; it is removed when the guard is dropped
+    pub fn register(&self, client: SocketAddr) -> ConnectionGuard<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let connection = Arc::new(TrackedConnection {
+            id,
+            client,
+            target: Mutex::new(None),
+            rewritten_to: Mutex::new(None),
+            outbound: Mutex::new(None),
+            user: Mutex::new(None),
+            authenticated: AtomicBool::new(false),
+            dry_run: Mutex::new(None),
+            probe: AtomicBool::new(false),
+            profile: Mutex::new(None),
+            relay_result: Mutex::new(None),
+            phase: AtomicU8::new(ConnectionPhase::Greeting as u8),
+            phase_since_ms: AtomicU64::new(0),
+            started: Instant::now(),
+            last_activity_ms: AtomicU64::new(0),
+            connected_ms: AtomicU64::new(0),
+            first_download_ms: AtomicU64::new(0),
+            upload: AtomicU64::new(0),
+            download: AtomicU64::new(0),
+            cancel: CancellationToken::new(),
+        });
+        self.connections.write().unwrap().insert(id, connection.clone());
+
+        ConnectionGuard {
+            registry: self,
+            connection,
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<TrackedConnection>> {
+        self.connections.read().unwrap().get(&id).cloned()
+    }
+
+    /// All live connections
+    pub fn connections(&self) -> Vec<Arc<TrackedConnection>> {
+        self.connections.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes uploaded and downloaded by all connections so far, closed ones included
+    pub fn traffic_totals(&self) -> (u64, u64) {
+        let live = self.connections.read().unwrap();
+        live.values().fold(
+            (self.closed_upload.load(Ordering::Relaxed), self.closed_download.load(Ordering::Relaxed)),
+            |(upload, download), connection| {
+                (upload + connection.upload.load(Ordering::Relaxed), download + connection.download.load(Ordering::Relaxed))
+            },
+        )
+    }
+}
+
+impl Default for ConnectionRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Keeps a connection registered for as long as it is alive
+pub struct ConnectionGuard<'a> {
+    registry: &'a ConnectionRegistry,
+    connection: Arc<TrackedConnection>,
+}
+
+impl ConnectionGuard<'_> {
+    pub fn connection(&self) -> &Arc<TrackedConnection> {
+        &self.connection
+    }
+}
+
+impl std::ops::Deref for ConnectionGuard<'_> {
+    type Target = TrackedConnection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.connection
+    }
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        let mut connections = self.registry.connections.write().unwrap();
+        connections.remove(&self.connection.id);
+        // 在锁内计入，统计总量时不会漏掉或重复计算这条连接
+        self.registry.closed_upload.fetch_add(self.connection.upload.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.registry.closed_download.fetch_add(self.connection.download.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+static GLOBAL_CONNECTION_REGISTRY: OnceLock<ConnectionRegistry> = OnceLock::new();
+
+/// Get the global connection registry
+pub fn get_global_connection_registry() -> &'static ConnectionRegistry {
+    GLOBAL_CONNECTION_REGISTRY.get_or_init(ConnectionRegistry::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_register_and_track_activity() {
+        let registry = ConnectionRegistry::new();
+        let guard = registry.register("127.0.0.1:5000".parse().unwrap());
+        guard.set_target("example.com:443");
+        guard.set_phase(ConnectionPhase::Relaying);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(guard.idle_for(), Duration::from_secs(10));
+
+        guard.add_upload(100);
+        guard.add_download(200);
+        assert_eq!(guard.idle_for(), Duration::ZERO);
+        assert_eq!(guard.total_bytes(), 300);
+
+        let snapshot = registry.get(guard.id()).unwrap().snapshot();
+        assert_eq!(snapshot.target.as_deref(), Some("example.com:443"));
+        assert_eq!(snapshot.phase, ConnectionPhase::Relaying);
+
+        let other = registry.register("127.0.0.1:5002".parse().unwrap());
+        other.add_upload(1);
+        drop(guard);
+        assert_eq!(registry.len(), 1);
+        assert_eq!(registry.traffic_totals(), (101, 200));
+        drop(other);
+        assert!(registry.is_empty());
+        assert_eq!(registry.traffic_totals(), (101, 200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stuck_before_relay_restarts_per_phase() {
+        let registry = ConnectionRegistry::new();
+        let guard = registry.register("127.0.0.1:5001".parse().unwrap());
+        let limit = |_| Duration::from_secs(10);
+
+        tokio::time::advance(Duration::from_secs(6)).await;
+        guard.set_phase(ConnectionPhase::Request);
+        let started = Instant::now();
+        assert_eq!(guard.stuck_before_relay(limit).await, ConnectionPhase::Request);
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+
+        guard.set_phase(ConnectionPhase::Relaying);
+        let relaying = tokio::time::timeout(Duration::from_secs(60), guard.stuck_before_relay(limit)).await;
+        assert!(relaying.is_err());
+    }
+}
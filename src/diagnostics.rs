@@ -0,0 +1,151 @@
+// 连接诊断：记录路由、DNS与逐地址连接尝试，失败时输出为一行
+use crate::error::ProxyError;
+use crate::protocol::TargetAddr;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Where the addresses of a target came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsSource {
+    /// Answered by the named upstream server
+    Upstream(String),
+    /// Last known answer, served after every upstream failed
+    Cache,
+}
+
+impl fmt::Display for DnsSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsSource::Upstream(server) => write!(f, "upstream {}", server),
+            DnsSource::Cache => write!(f, "cache"),
+        }
+    }
+}
+
+/// Outcome of the resolution step
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsDiagnostics {
+    pub source: DnsSource,
+    pub duration: Duration,
+    pub addresses: Vec<IpAddr>,
+}
+
+/// One connection attempt to a resolved address, or to a domain handed to the outbound
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectAttempt {
+    pub addr: TargetAddr,
+    pub duration: Duration,
+    /// None when the attempt succeeded
+    pub error: Option<io::ErrorKind>,
+}
+
+/// Everything the connect pipeline learned about one connection
+///
+/// Built up step by step while routing, resolving and connecting. Nothing
+/// allocates on the common path where the first address connects; failed
+/// attempts are only collected once they happen.
+#[derive(Debug, Clone)]
+pub struct ConnectDiagnostics {
+    started: Instant,
+    /// Index of the routing rule that matched, None for the default outbound
+    pub rule: Option<usize>,
+    pub outbound: String,
+    pub dns: Option<DnsDiagnostics>,
+    /// Failed attempts in the order they were made
+    pub failed_attempts: Vec<ConnectAttempt>,
+    pub connected: Option<ConnectAttempt>,
+    pub elapsed: Duration,
+}
+
+impl ConnectDiagnostics {
+    pub fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            rule: None,
+            outbound: String::new(),
+            dns: None,
+            failed_attempts: Vec::new(),
+            connected: None,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    pub fn route(&mut self, rule: Option<usize>, outbound: String) -> &mut Self {
+        self.rule = rule;
+        self.outbound = outbound;
+        self
+    }
+
+    pub fn dns(&mut self, source: DnsSource, duration: Duration, addresses: Vec<IpAddr>) -> &mut Self {
+        self.dns = Some(DnsDiagnostics { source, duration, addresses });
+        self
+    }
+
+    pub fn attempt(&mut self, addr: TargetAddr, duration: Duration, error: Option<io::ErrorKind>) -> &mut Self {
+        let attempt = ConnectAttempt { addr, duration, error };
+        match error {
+            Some(_) => self.failed_attempts.push(attempt),
+            None => self.connected = Some(attempt),
+        }
+        self
+    }
+
+    /// Stop the clock
+    pub fn finish(&mut self) -> &mut Self {
+        self.elapsed = self.started.elapsed();
+        self
+    }
+
+    /// Number of addresses tried so far
+    pub fn attempts(&self) -> usize {
+        self.failed_attempts.len() + usize::from(self.connected.is_some())
+    }
+
+    /// Stop the clock and turn the diagnostics into the error returned to callers
+    pub fn fail(&mut self, cause: impl fmt::Display) -> ProxyError {
+        self.finish();
+        ProxyError::ConnectFailed {
+            cause: cause.to_string(),
+            diagnostics: Box::new(self.clone()),
+        }
+    }
+
+    /// Error of the most recent failed attempt
+    pub fn last_error(&self) -> Option<io::ErrorKind> {
+        self.failed_attempts.last().and_then(|a| a.error)
+    }
+}
+
+impl fmt::Display for ConnectDiagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.rule {
+            Some(index) => write!(f, "rule=#{}", index)?,
+            None => write!(f, "rule=default")?,
+        }
+        write!(f, " outbound={}", self.outbound)?;
+        if let Some(dns) = &self.dns {
+            write!(f, " dns={} in {:.1?} [", dns.source, dns.duration)?;
+            for (i, ip) in dns.addresses.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}", ip)?;
+            }
+            write!(f, "]")?;
+        }
+        write!(f, " attempts={} [", self.attempts())?;
+        let attempts = self.failed_attempts.iter().chain(self.connected.as_ref());
+        for (i, attempt) in attempts.enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            match attempt.error {
+                Some(kind) => write!(f, "{} {} in {:.1?}", attempt.addr, kind, attempt.duration)?,
+                None => write!(f, "{} ok in {:.1?}", attempt.addr, attempt.duration)?,
+            }
+        }
+        write!(f, "] elapsed={:.1?}", self.elapsed)
+    }
+}
@@ -1,91 +1,608 @@
+use crate::config::{DnsConfig, DnsFailurePolicy, DnsPrefetchConfig};
+use crate::diagnostics::DnsSource;
+use crate::dns_cache::{DnsAnswer, DnsCache, DnsCacheStats, CACHE_CLEANUP_INTERVAL, MAX_CACHED_ANSWERS};
+use crate::dns_ecs;
+use crate::dns_stats::{DnsOutcome, DomainDnsStats, DomainStatsTable, DEFAULT_MAX_DOMAINS};
 use crate::error::{ProxyError, Result};
-use log::{debug, warn};
+use crate::tasks::{get_global_task_tracker, TaskGroup};
+use ipnet::IpNet;
+use log::{debug, info, warn};
+use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, Instant};
 use trust_dns_resolver::{
-    config::{ResolverConfig, ResolverOpts},
+    config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
+    error::{ResolveError, ResolveErrorKind},
+    proto::op::ResponseCode,
+    proto::rr::RecordType,
     TokioAsyncResolver,
 };
 
+/// A single server in the resolver fallback chain
+struct Upstream {
+    label: String,
+    /// Server address for queries with a client subnet, which bypass `resolver`
+    server: Option<SocketAddr>,
+    resolver: TokioAsyncResolver,
+    timeout: Duration,
+    queries: AtomicU64,
+    failures: AtomicU64,
+    fallbacks: AtomicU64,
+}
+
+impl Upstream {
+    fn new(label: String, server: Option<SocketAddr>, resolver: TokioAsyncResolver, timeout: Duration) -> Self {
+        Self {
+            label,
+            server,
+            resolver,
+            timeout,
+            queries: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            fallbacks: AtomicU64::new(0),
+        }
+    }
+
+    /// Query this server, returning the addresses and how long they may be cached
+    ///
+    /// With a `subnet` the query carries it as EDNS Client Subnet.
+    async fn lookup(
+        &self,
+        domain: &str,
+        strategy: LookupStrategy,
+        subnet: Option<IpNet>,
+    ) -> std::result::Result<(Vec<IpAddr>, Duration), ResolveError> {
+        if let (Some(subnet), Some(server)) = (subnet, self.server) {
+            return Self::lookup_with_subnet(server, domain, strategy, subnet).await;
+        }
+        let (ips, valid_until) = match strategy {
+            LookupStrategy::All => {
+                let lookup = self.resolver.lookup_ip(domain).await?;
+                (lookup.iter().collect(), lookup.valid_until())
+            }
+            LookupStrategy::Ipv4Only => {
+                let lookup = self.resolver.ipv4_lookup(domain).await?;
+                (lookup.iter().map(|a| IpAddr::V4(**a)).collect(), lookup.valid_until())
+            }
+            LookupStrategy::Ipv6Only => {
+                let lookup = self.resolver.ipv6_lookup(domain).await?;
+                (lookup.iter().map(|aaaa| IpAddr::V6(**aaaa)).collect(), lookup.valid_until())
+            }
+        };
+        Ok((ips, valid_until.saturating_duration_since(Instant::now())))
+    }
+
+    async fn lookup_with_subnet(
+        server: SocketAddr,
+        domain: &str,
+        strategy: LookupStrategy,
+        subnet: IpNet,
+    ) -> std::result::Result<(Vec<IpAddr>, Duration), ResolveError> {
+        match strategy {
+            LookupStrategy::Ipv4Only => dns_ecs::query(server, domain, RecordType::A, subnet).await,
+            LookupStrategy::Ipv6Only => dns_ecs::query(server, domain, RecordType::AAAA, subnet).await,
+            // 与 trust-dns 默认的 Ipv4thenIpv6 一致：没有 A 记录时再查 AAAA
+            LookupStrategy::All => match dns_ecs::query(server, domain, RecordType::A, subnet).await {
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ResolveErrorKind::NoRecordsFound { response_code: ResponseCode::NoError, .. }
+                    ) =>
+                {
+                    dns_ecs::query(server, domain, RecordType::AAAA, subnet).await
+                }
+                result => result,
+            },
+        }
+    }
+}
+
+/// How a failed lookup should be treated by the fallback chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FailureClass {
+    /// NXDOMAIN / NODATA: the answer is authoritative, asking elsewhere won't help
+    Authoritative,
+    /// SERVFAIL, timeouts and transport errors: try the next server
+    Retryable,
+}
+
+/// Domains listed in the stats snapshot
+const SLOWEST_DOMAINS_IN_STATS: usize = 10;
+/// Domains whose last answer is kept for `serve_stale`; the oldest makes room
+const MAX_STALE_ANSWERS: usize = MAX_CACHED_ANSWERS;
+
+/// Address families a lookup asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum LookupStrategy {
+    All,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+impl LookupStrategy {
+    fn name(self) -> &'static str {
+        match self {
+            LookupStrategy::All => "all",
+            LookupStrategy::Ipv4Only => "ipv4_only",
+            LookupStrategy::Ipv6Only => "ipv6_only",
+        }
+    }
+
+    /// Whether a cached address may answer this lookup
+    fn accepts(self, ip: &IpAddr) -> bool {
+        match self {
+            LookupStrategy::All => true,
+            LookupStrategy::Ipv4Only => ip.is_ipv4(),
+            LookupStrategy::Ipv6Only => ip.is_ipv6(),
+        }
+    }
+}
+
+/// Where a query ended up, for the query log
+struct QueryTrace {
+    resolver: Option<String>,
+    outcome: DnsOutcome,
+}
+
+impl QueryTrace {
+    /// Trace of a lookup that was answered without running the chain itself
+    fn answered(answer: &DnsAnswer) -> Self {
+        let (resolver, outcome) = match answer {
+            DnsAnswer::Cached(_) => (Some("cache".to_string()), DnsOutcome::Cached),
+            DnsAnswer::Answer { server, .. } => (Some(server.clone()), DnsOutcome::Upstream),
+            DnsAnswer::Stale(_) => (None, DnsOutcome::Cached),
+            DnsAnswer::NxDomain(_) => (None, DnsOutcome::NxDomain),
+            DnsAnswer::Failed(_) => (None, DnsOutcome::Failed),
+        };
+        Self { resolver, outcome }
+    }
+}
+
+fn classify(error: &ResolveError) -> FailureClass {
+    match error.kind() {
+        ResolveErrorKind::NoRecordsFound { response_code, .. }
+            if matches!(*response_code, ResponseCode::NXDomain | ResponseCode::NoError) =>
+        {
+            FailureClass::Authoritative
+        }
+        _ => FailureClass::Retryable,
+    }
+}
+
 /// DNS resolver for SOCKS5 proxy
+///
+/// Servers are queried in order; a timeout or server failure moves on to the
+/// next one, while NXDOMAIN is returned to the caller immediately. Answers
+/// are cached for their TTL and concurrent lookups of a name share a query.
 pub struct DnsResolver {
-    resolver: TokioAsyncResolver,
+    upstreams: Vec<Upstream>,
+    on_failure: DnsFailurePolicy,
+    stale_max_age: Duration,
+    /// Last successful answer per domain, used for `serve_stale`; at most
+    /// `MAX_STALE_ANSWERS`
+    last_answers: RwLock<HashMap<String, (Vec<IpAddr>, Instant)>>,
+    stale_serves: AtomicU64,
+    /// Log every query and keep per-domain statistics
+    query_log: bool,
+    domain_stats: DomainStatsTable,
+    /// ECS subnet sent when the caller gives no egress hint
+    client_subnet: Option<IpNet>,
+    /// AAAA lookups are made only with `dns.enable_ipv6`
+    enable_ipv6: bool,
+    cache: DnsCache<(String, LookupStrategy, Option<IpNet>)>,
 }
 
 impl DnsResolver {
     /// Create a new DNS resolver with default configuration
     pub fn new() -> Result<Self> {
-        let resolver = TokioAsyncResolver::tokio(
-            ResolverConfig::default(),
-            ResolverOpts::default(),
-        );
-
-        Ok(Self { resolver })
+        Self::with_config(ResolverConfig::default(), ResolverOpts::default())
     }
 
     /// Create a new DNS resolver with custom configuration
     pub fn with_config(config: ResolverConfig, opts: ResolverOpts) -> Result<Self> {
+        let server = config.name_servers().first().map(|ns| ns.socket_addr);
+        let label = server.map(|addr| addr.to_string()).unwrap_or_else(|| "default".to_string());
+        let timeout = opts.timeout * opts.attempts.max(1) as u32;
         let resolver = TokioAsyncResolver::tokio(config, opts);
 
-        Ok(Self { resolver })
+        Ok(Self::from_upstreams(
+            vec![Upstream::new(label, server, resolver, timeout)],
+            DnsFailurePolicy::Fail,
+            Duration::ZERO,
+        ))
+    }
+
+    /// Resolver for the `[dns]` section that queries the system default servers
+    ///
+    /// Answers are cached here rather than by trust-dns, so the prefetcher
+    /// always reaches the network.
+    fn with_default_servers(config: &DnsConfig) -> Result<Self> {
+        let mut opts = ResolverOpts::default();
+        opts.timeout = Duration::from_secs(config.timeout_secs.max(1));
+        opts.attempts = 1;
+        if config.cache_ttl_secs > 0 {
+            opts.cache_size = 0;
+        }
+        let mut resolver = Self::with_config(ResolverConfig::default(), opts)?;
+        resolver.on_failure = config.on_failure;
+        resolver.stale_max_age = Duration::from_secs(config.serve_stale_max_secs);
+        resolver.client_subnet = parse_client_subnet(config)?;
+        resolver.enable_ipv6 = config.enable_ipv6;
+        Ok(resolver.with_cache(config))
+    }
+
+    /// Create a resolver chain from the `[dns]` section of the configuration
+    ///
+    /// Each entry in `servers` (`ip:port`, or a bare IP for port 53) becomes
+    /// one link of the chain and receives an equal slice of `timeout_secs`.
+    /// Without servers the system configuration is used.
+    pub fn from_config(config: &DnsConfig) -> Result<Self> {
+        let stale_max_age = Duration::from_secs(config.serve_stale_max_secs);
+        if config.servers.is_empty() {
+            let resolver = Self::with_default_servers(config)?;
+            return Ok(resolver.with_query_log(config.query_log, config.stats_max_domains));
+        }
+
+        let per_server_timeout =
+            Duration::from_secs(config.timeout_secs.max(1)) / config.servers.len() as u32;
+
+        let mut upstreams = Vec::with_capacity(config.servers.len());
+        for server in &config.servers {
+            let addr = parse_server(server)?;
+
+            let mut resolver_config = ResolverConfig::new();
+            resolver_config.add_name_server(NameServerConfig::new(addr, Protocol::Udp));
+
+            let mut opts = ResolverOpts::default();
+            opts.timeout = per_server_timeout;
+            opts.attempts = 1;
+            if config.cache_ttl_secs > 0 {
+                opts.cache_size = 0;
+            }
+
+            let resolver = TokioAsyncResolver::tokio(resolver_config, opts);
+            upstreams.push(Upstream::new(addr.to_string(), Some(addr), resolver, per_server_timeout));
+        }
+
+        let mut resolver = Self::from_upstreams(upstreams, config.on_failure, stale_max_age);
+        resolver.client_subnet = parse_client_subnet(config)?;
+        resolver.enable_ipv6 = config.enable_ipv6;
+        Ok(resolver.with_cache(config).with_query_log(config.query_log, config.stats_max_domains))
+    }
+
+    fn from_upstreams(
+        upstreams: Vec<Upstream>,
+        on_failure: DnsFailurePolicy,
+        stale_max_age: Duration,
+    ) -> Self {
+        Self {
+            upstreams,
+            on_failure,
+            stale_max_age,
+            last_answers: RwLock::new(HashMap::new()),
+            stale_serves: AtomicU64::new(0),
+            query_log: false,
+            domain_stats: DomainStatsTable::new(DEFAULT_MAX_DOMAINS),
+            client_subnet: None,
+            enable_ipv6: true,
+            cache: DnsCache::new(Duration::ZERO, DnsPrefetchConfig::default()),
+        }
+    }
+
+    /// Cache answers for up to `cache_ttl_secs` and set up prefetching
+    pub fn with_cache(mut self, config: &DnsConfig) -> Self {
+        self.cache = DnsCache::new(Duration::from_secs(config.cache_ttl_secs), config.prefetch.clone());
+        self
+    }
+
+    /// Log each query and track per-domain statistics for up to `max_domains` domains
+    pub fn with_query_log(mut self, enabled: bool, max_domains: usize) -> Self {
+        self.query_log = enabled;
+        self.domain_stats = DomainStatsTable::new(max_domains);
+        self
     }
 
     /// Resolve a domain name to an IP address
     pub async fn resolve_domain(&self, domain: &str, port: u16) -> Result<SocketAddr> {
         debug!("Resolving domain: {}:{}", domain, port);
 
-        // Try IPv4 first
-        match self.resolver.lookup_ip(domain).await {
-            Ok(lookup) => {
-                for ip in lookup.iter() {
-                    debug!("Resolved {} to IP: {}", domain, ip);
-                    return Ok(SocketAddr::new(ip, port));
-                }
-                Err(ProxyError::DnsResolution(format!("No IP addresses found for {}", domain)))
-            }
-            Err(e) => {
-                warn!("DNS resolution failed for {}: {}", domain, e);
-                Err(ProxyError::DnsResolution(e.to_string()))
-            }
-        }
+        let (ips, _) = self.resolve_all(domain).await?;
+
+        let ip = ips[0];
+        debug!("Resolved {} to IP: {}", domain, ip);
+        Ok(SocketAddr::new(ip, port))
+    }
+
+    /// Resolve every address of a domain name, reporting where the answer came from
+    pub async fn resolve_all(&self, domain: &str) -> Result<(Vec<IpAddr>, DnsSource)> {
+        self.resolve_all_with_subnet(domain, None).await
+    }
+
+    /// Resolve every address of a domain name on behalf of clients in `subnet`
+    ///
+    /// `subnet` is the egress hint of the outbound the domain was routed to
+    /// and replaces `dns.client_subnet`; answers are cached per subnet.
+    pub async fn resolve_all_with_subnet(
+        &self,
+        domain: &str,
+        subnet: Option<IpNet>,
+    ) -> Result<(Vec<IpAddr>, DnsSource)> {
+        debug!("Resolving all addresses of {}", domain);
+        self.lookup_chain(domain, LookupStrategy::All, subnet).await
     }
 
     /// Resolve a domain name to IPv4 address only
     pub async fn resolve_domain_v4(&self, domain: &str, port: u16) -> Result<SocketAddr> {
         debug!("Resolving domain to IPv4: {}:{}", domain, port);
 
-        match self.resolver.ipv4_lookup(domain).await {
-            Ok(lookup) => {
-                for ipv4 in lookup.iter() {
-                    debug!("Resolved {} to IPv4: {}", domain, ipv4);
-                    return Ok(SocketAddr::new(IpAddr::V4(**ipv4), port));
-                }
-                Err(ProxyError::DnsResolution(format!("No IPv4 addresses found for {}", domain)))
-            }
-            Err(e) => {
-                warn!("DNS resolution failed for {}: {}", domain, e);
-                Err(ProxyError::DnsResolution(e.to_string()))
-            }
-        }
+        let (ips, _) = self.lookup_chain(domain, LookupStrategy::Ipv4Only, None).await?;
+
+        let ip = ips[0];
+        debug!("Resolved {} to IPv4: {}", domain, ip);
+        Ok(SocketAddr::new(ip, port))
     }
 
     /// Resolve a domain name to IPv6 address only
     pub async fn resolve_domain_v6(&self, domain: &str, port: u16) -> Result<SocketAddr> {
         debug!("Resolving domain to IPv6: {}:{}", domain, port);
 
-        match self.resolver.ipv6_lookup(domain).await {
-            Ok(lookup) => {
-                for ipv6 in lookup.iter() {
-                    debug!("Resolved {} to IPv6: {}", domain, ipv6);
-                    return Ok(SocketAddr::new(IpAddr::V6(**ipv6), port));
+        let (ips, _) = self.lookup_chain(domain, LookupStrategy::Ipv6Only, None).await?;
+
+        let ip = ips[0];
+        debug!("Resolved {} to IPv6: {}", domain, ip);
+        Ok(SocketAddr::new(ip, port))
+    }
+
+    /// Run a lookup through the chain, logging and recording it when the query log is on
+    async fn lookup_chain(
+        &self,
+        domain: &str,
+        strategy: LookupStrategy,
+        subnet: Option<IpNet>,
+    ) -> Result<(Vec<IpAddr>, DnsSource)> {
+        let strategy = match strategy {
+            LookupStrategy::All if !self.enable_ipv6 => LookupStrategy::Ipv4Only,
+            LookupStrategy::Ipv6Only if !self.enable_ipv6 => {
+                return Err(ProxyError::DnsResolution(format!("{}: IPv6 resolution is disabled", domain)));
+            }
+            strategy => strategy,
+        };
+        let started = Instant::now();
+        let subnet = subnet.or(self.client_subnet);
+        let mut trace = None;
+        let answer = self
+            .cache
+            .resolve((domain.to_string(), strategy, subnet), || {
+                self.query_chain(domain, strategy, subnet, &mut trace)
+            })
+            .await;
+        // 命中缓存或等待了别人的查询时，本次调用没有自己的链路轨迹
+        let trace = trace.unwrap_or_else(|| QueryTrace::answered(&answer));
+        let result = match answer {
+            DnsAnswer::Cached(ips) | DnsAnswer::Stale(ips) => Ok((ips, DnsSource::Cache)),
+            DnsAnswer::Answer { ips, server, .. } => Ok((ips, DnsSource::Upstream(server))),
+            DnsAnswer::NxDomain(message) | DnsAnswer::Failed(message) => Err(ProxyError::DnsResolution(message)),
+        };
+        if self.query_log {
+            let duration = started.elapsed();
+            self.domain_stats.record(domain, duration, trace.outcome);
+            let addresses = match &result {
+                Ok((ips, _)) => ips.iter().map(IpAddr::to_string).collect::<Vec<_>>().join(","),
+                Err(_) => String::new(),
+            };
+            let client_subnet = subnet.map(|s| format!(" client_subnet={}", s)).unwrap_or_default();
+            info!(
+                target: "dns_query",
+                "domain={} strategy={} resolver={} duration_ms={:.1} outcome={} addresses=[{}]{}",
+                domain,
+                strategy.name(),
+                trace.resolver.as_deref().unwrap_or("-"),
+                duration.as_secs_f64() * 1000.0,
+                trace.outcome,
+                addresses,
+                client_subnet
+            );
+        }
+        result
+    }
+
+    /// Run a lookup against each server in order until one gives a usable answer
+    ///
+    /// `strategy` filters stale answers so that a v4-only lookup is never served
+    /// a cached v6 address.
+    async fn query_chain(
+        &self,
+        domain: &str,
+        strategy: LookupStrategy,
+        subnet: Option<IpNet>,
+        trace: &mut Option<QueryTrace>,
+    ) -> DnsAnswer {
+        let trace = trace.insert(QueryTrace { resolver: None, outcome: DnsOutcome::Failed });
+        let mut last_error = None;
+
+        for (index, upstream) in self.upstreams.iter().enumerate() {
+            upstream.queries.fetch_add(1, Ordering::Relaxed);
+            trace.resolver = Some(upstream.label.clone());
+
+            let outcome = match tokio::time::timeout(upstream.timeout, upstream.lookup(domain, strategy, subnet)).await {
+                Ok(result) => result,
+                Err(_) => Err(ResolveError::from(ResolveErrorKind::Timeout)),
+            };
+
+            match outcome {
+                Ok((ips, ttl)) if !ips.is_empty() => {
+                    trace.outcome = DnsOutcome::Upstream;
+                    self.remember(domain, &ips);
+                    return DnsAnswer::Answer { ips, ttl, server: upstream.label.clone() };
+                }
+                Ok(_) => {
+                    trace.outcome = DnsOutcome::NxDomain;
+                    return DnsAnswer::NxDomain(format!("No IP addresses found for {}", domain));
+                }
+                Err(e) if classify(&e) == FailureClass::Authoritative => {
+                    debug!("DNS server {} has no records for {}: {}", upstream.label, domain, e);
+                    trace.outcome = DnsOutcome::NxDomain;
+                    return DnsAnswer::NxDomain(e.to_string());
+                }
+                Err(e) => {
+                    upstream.failures.fetch_add(1, Ordering::Relaxed);
+                    trace.outcome = match e.kind() {
+                        ResolveErrorKind::Timeout => DnsOutcome::Timeout,
+                        _ => DnsOutcome::Failed,
+                    };
+                    if index + 1 < self.upstreams.len() {
+                        upstream.fallbacks.fetch_add(1, Ordering::Relaxed);
+                        warn!(
+                            "DNS server {} failed for {}: {}, falling back",
+                            upstream.label, domain, e
+                        );
+                    } else {
+                        warn!("DNS resolution failed for {}: {}", domain, e);
+                    }
+                    last_error = Some(e);
                 }
-                Err(ProxyError::DnsResolution(format!("No IPv6 addresses found for {}", domain)))
             }
-            Err(e) => {
-                warn!("DNS resolution failed for {}: {}", domain, e);
-                Err(ProxyError::DnsResolution(e.to_string()))
+        }
+
+        if self.on_failure == DnsFailurePolicy::ServeStale {
+            if let Some(ips) = self.stale_answer(domain, strategy) {
+                self.stale_serves.fetch_add(1, Ordering::Relaxed);
+                trace.outcome = DnsOutcome::Cached;
+                warn!("Serving stale DNS answer for {}", domain);
+                return DnsAnswer::Stale(ips);
+            }
+        }
+
+        DnsAnswer::Failed(match last_error {
+            Some(e) => e.to_string(),
+            None => format!("No DNS servers configured to resolve {}", domain),
+        })
+    }
+
+    /// Refresh popular cached answers before they expire, until the task is cancelled
+    pub async fn run_prefetch(&self) {
+        self.cache
+            .run_prefetch(|(domain, strategy, subnet): (String, LookupStrategy, Option<IpNet>)| async move {
+                let mut trace = None;
+                self.query_chain(&domain, strategy, subnet, &mut trace).await
+            })
+            .await
+    }
+
+    fn remember(&self, domain: &str, ips: &[IpAddr]) {
+        if self.on_failure != DnsFailurePolicy::ServeStale {
+            return;
+        }
+        let now = Instant::now();
+        let mut answers = self.last_answers.write().unwrap();
+        if answers.len() >= MAX_STALE_ANSWERS && !answers.contains_key(domain) {
+            answers.retain(|_, (_, stored_at)| now.duration_since(*stored_at) <= self.stale_max_age);
+            if answers.len() >= MAX_STALE_ANSWERS {
+                let oldest = answers.iter().min_by_key(|(_, (_, stored_at))| *stored_at).map(|(domain, _)| domain.clone());
+                if let Some(oldest) = oldest {
+                    answers.remove(&oldest);
+                }
             }
         }
+        answers.insert(domain.to_string(), (ips.to_vec(), now));
+    }
+
+    /// Drop last answers too old to be served stale, returning how many were removed
+    fn purge_stale_answers(&self) -> usize {
+        let mut answers = self.last_answers.write().unwrap();
+        let before = answers.len();
+        answers.retain(|_, (_, stored_at)| stored_at.elapsed() <= self.stale_max_age);
+        before - answers.len()
+    }
+
+    fn stale_answer(&self, domain: &str, strategy: LookupStrategy) -> Option<Vec<IpAddr>> {
+        let answers = self.last_answers.read().unwrap();
+        let (ips, stored_at) = answers.get(domain)?;
+        if stored_at.elapsed() > self.stale_max_age {
+            return None;
+        }
+        let ips: Vec<IpAddr> = ips.iter().copied().filter(|ip| strategy.accepts(ip)).collect();
+        (!ips.is_empty()).then_some(ips)
+    }
+
+    /// Get per-server query statistics
+    pub fn stats(&self) -> DnsStats {
+        DnsStats {
+            servers: self
+                .upstreams
+                .iter()
+                .map(|upstream| DnsServerStats {
+                    server: upstream.label.clone(),
+                    queries: upstream.queries.load(Ordering::Relaxed),
+                    failures: upstream.failures.load(Ordering::Relaxed),
+                    fallbacks: upstream.fallbacks.load(Ordering::Relaxed),
+                })
+                .collect(),
+            stale_serves: self.stale_serves.load(Ordering::Relaxed),
+            cache: self.cache.stats(),
+            slowest_domains: self.domain_stats.slowest(SLOWEST_DOMAINS_IN_STATS),
+        }
+    }
+
+    /// Answer cache counters: hits, misses, expired and cached entries
+    pub fn cache_stats(&self) -> DnsCacheStats {
+        self.cache.stats()
+    }
+
+    /// Drop expired answers from the cache and last answers older than
+    /// `serve_stale_max_secs`, returning how many were removed
+    pub fn purge_expired_answers(&self) -> usize {
+        self.cache.purge_expired() + self.purge_stale_answers()
+    }
+
+    /// Purge loop, until the task is cancelled
+    async fn run_cleanup(&self, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.purge_expired_answers();
+        }
     }
+
+    /// Addresses of the configured servers, in chain order (empty when the
+    /// system configuration is used)
+    pub fn name_servers(&self) -> Vec<SocketAddr> {
+        self.upstreams.iter().filter_map(|upstream| upstream.server).collect()
+    }
+
+    /// Resolution statistics of one domain (query log only)
+    pub fn domain_stats(&self, domain: &str) -> Option<DomainDnsStats> {
+        self.domain_stats.get(domain)
+    }
+
+    /// The `n` domains with the slowest p95 resolution time (query log only)
+    pub fn slowest_domains(&self, n: usize) -> Vec<DomainDnsStats> {
+        self.domain_stats.slowest(n)
+    }
+}
+
+/// A `dns.servers` entry: `ip:port`, `[v6]:port`, or a bare IP on port 53
+fn parse_server(server: &str) -> Result<SocketAddr> {
+    server
+        .parse::<SocketAddr>()
+        .or_else(|_| server.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|_| ProxyError::Protocol(format!("Invalid DNS server {:?}: expected ip:port", server)))
+}
+
+fn parse_client_subnet(config: &DnsConfig) -> Result<Option<IpNet>> {
+    config
+        .client_subnet
+        .as_deref()
+        .map(|subnet| {
+            subnet
+                .parse::<IpNet>()
+                .map(|net| net.trunc())
+                .map_err(|e| ProxyError::Protocol(format!("Invalid dns.client_subnet {}: {}", subnet, e)))
+        })
+        .transpose()
 }
 
 impl Default for DnsResolver {
@@ -94,22 +611,74 @@ impl Default for DnsResolver {
     }
 }
 
+/// Statistics for one server in the resolver chain
+#[derive(Debug, Clone)]
+pub struct DnsServerStats {
+    pub server: String,
+    pub queries: u64,
+    pub failures: u64,
+    pub fallbacks: u64,
+}
+
+/// DNS resolver statistics
+#[derive(Debug, Clone)]
+pub struct DnsStats {
+    pub servers: Vec<DnsServerStats>,
+    pub stale_serves: u64,
+    /// Answer cache and prefetch counters
+    pub cache: DnsCacheStats,
+    /// Domains with the slowest p95 resolution time, when the query log is on
+    pub slowest_domains: Vec<DomainDnsStats>,
+}
+
 /// Global DNS resolver instance
-static mut GLOBAL_DNS_RESOLVER: Option<DnsResolver> = None;
+static GLOBAL_DNS_RESOLVER: OnceLock<DnsResolver> = OnceLock::new();
 
-/// Initialize the global DNS resolver
-pub fn init_global_dns_resolver() -> Result<()> {
-    unsafe {
-        GLOBAL_DNS_RESOLVER = Some(DnsResolver::new()?);
-    }
-    Ok(())
+/// Initialize the global DNS resolver from the `[dns]` section
+///
+/// The query log is on with `dns.query_log` or `enable_metrics`
+/// (`logging.enable_metrics`). Invalid settings fail here, at startup,
+/// as does a second initialization.
+pub fn init_global_dns_resolver(config: &DnsConfig, enable_metrics: bool) -> Result<()> {
+    let query_log = config.query_log || enable_metrics;
+    let resolver = DnsResolver::from_config(config)?.with_query_log(query_log, config.stats_max_domains);
+    GLOBAL_DNS_RESOLVER.set(resolver).map_err(|_| ProxyError::AlreadyInitialized("Global DNS resolver"))
+}
+
+/// Get the global DNS resolver, or an error before `init_global_dns_resolver`
+pub fn try_get_global_dns_resolver() -> Result<&'static DnsResolver> {
+    GLOBAL_DNS_RESOLVER.get().ok_or(ProxyError::NotInitialized("Global DNS resolver"))
 }
 
 /// Get the global DNS resolver
+///
+/// Panics before `init_global_dns_resolver`; lookups go through
+/// `try_get_global_dns_resolver` instead.
 pub fn get_global_dns_resolver() -> &'static DnsResolver {
-    unsafe {
-        GLOBAL_DNS_RESOLVER.as_ref()
-            .expect("Global DNS resolver not initialized")
+    try_get_global_dns_resolver().unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Start the background prefetch of the global resolver when `dns.prefetch` is on
+pub fn start_dns_prefetch() {
+    let resolver = get_global_dns_resolver();
+    if !resolver.cache.prefetch_enabled() {
+        return;
+    }
+    if let Err(e) = get_global_task_tracker().spawn(TaskGroup::DnsPrefetch, resolver.run_prefetch()) {
+        warn!("Failed to start DNS prefetch: {}", e);
+    }
+}
+
+/// Start the periodic purge of expired answers when the cache or
+/// `serve_stale` is on
+pub fn start_dns_cache_cleanup() {
+    let resolver = get_global_dns_resolver();
+    if !resolver.cache.enabled() && resolver.on_failure != DnsFailurePolicy::ServeStale {
+        return;
+    }
+    let cleanup = resolver.run_cleanup(CACHE_CLEANUP_INTERVAL);
+    if let Err(e) = get_global_task_tracker().spawn(TaskGroup::DnsCacheCleanup, cleanup) {
+        warn!("DNS cache cleanup not started: {}", e);
     }
 }
 
@@ -117,6 +686,389 @@ pub fn get_global_dns_resolver() -> &'static DnsResolver {
 mod tests {
     use super::*;
     use std::net::Ipv4Addr;
+    use tokio::net::UdpSocket;
+    use crate::config::{OutboundConfig, OutboundType};
+    use crate::outbound::OutboundManager;
+    use std::sync::{Arc, Mutex};
+    use trust_dns_resolver::proto::op::{Message, MessageType};
+    use trust_dns_resolver::proto::rr::rdata::opt::{ClientSubnet, EdnsCode, EdnsOption};
+    use trust_dns_resolver::proto::rr::{RData, Record};
+
+    #[derive(Clone, Copy)]
+    enum MockBehavior {
+        Silent,
+        Answer(Ipv4Addr),
+        Reply(ResponseCode),
+        /// Answer after `delay` with a zero TTL (so nothing is cached); names
+        /// starting with "missing" get NXDOMAIN
+        Delayed(Duration, Ipv4Addr),
+    }
+
+    /// Spawn a UDP DNS server on localhost that responds according to `behavior`
+    async fn spawn_mock_server(behavior: MockBehavior) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let Ok((n, peer)) = socket.recv_from(&mut buf).await else {
+                    return;
+                };
+                let request = Message::from_vec(&buf[..n]).unwrap();
+
+                let mut response = Message::new();
+                response.set_id(request.id());
+                response.set_message_type(MessageType::Response);
+                response.set_recursion_desired(request.recursion_desired());
+                response.set_recursion_available(true);
+                response.add_queries(request.queries().to_vec());
+
+                match behavior {
+                    MockBehavior::Silent => continue,
+                    MockBehavior::Answer(ip) => {
+                        let query = &request.queries()[0];
+                        if query.query_type() == RecordType::A {
+                            response.add_answer(Record::from_rdata(
+                                query.name().clone(),
+                                60,
+                                RData::A(ip.into()),
+                            ));
+                        }
+                    }
+                    MockBehavior::Reply(code) => {
+                        response.set_response_code(code);
+                    }
+                    MockBehavior::Delayed(delay, ip) => {
+                        tokio::time::sleep(delay).await;
+                        let query = &request.queries()[0];
+                        if query.name().to_string().starts_with("missing") {
+                            response.set_response_code(ResponseCode::NXDomain);
+                        } else if query.query_type() == RecordType::A {
+                            response.add_answer(Record::from_rdata(query.name().clone(), 0, RData::A(ip.into())));
+                        }
+                    }
+                }
+
+                let bytes = response.to_vec().unwrap();
+                let _ = socket.send_to(&bytes, peer).await;
+            }
+        });
+
+        addr
+    }
+
+    /// Spawn a UDP DNS server answering A queries with `ip` that records the
+    /// client subnet of every query it receives
+    async fn spawn_ecs_recorder(ip: Ipv4Addr) -> (SocketAddr, Arc<Mutex<Vec<Option<ClientSubnet>>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = seen.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            loop {
+                let Ok((n, peer)) = socket.recv_from(&mut buf).await else {
+                    return;
+                };
+                let request = Message::from_vec(&buf[..n]).unwrap();
+                let subnet = request.extensions().as_ref().and_then(|edns| match edns.option(EdnsCode::Subnet) {
+                    Some(EdnsOption::Subnet(subnet)) => Some(*subnet),
+                    _ => None,
+                });
+                recorded.lock().unwrap().push(subnet);
+
+                let mut response = Message::new();
+                response.set_id(request.id());
+                response.set_message_type(MessageType::Response);
+                response.add_queries(request.queries().to_vec());
+                let query = &request.queries()[0];
+                if query.query_type() == RecordType::A {
+                    response.add_answer(Record::from_rdata(query.name().clone(), 60, RData::A(ip.into())));
+                }
+                let _ = socket.send_to(&response.to_vec().unwrap(), peer).await;
+            }
+        });
+
+        (addr, seen)
+    }
+
+    #[test]
+    fn test_global_resolver_initialized_once() {
+        let err = try_get_global_dns_resolver().err().unwrap();
+        assert_eq!(err.to_string(), "Global DNS resolver is not initialized");
+
+        let servers: [SocketAddr; 1] = ["127.0.0.1:5353".parse().unwrap()];
+        init_global_dns_resolver(&chain_config(&servers, DnsFailurePolicy::Fail), false).unwrap();
+        let other: [SocketAddr; 1] = ["127.0.0.1:5354".parse().unwrap()];
+        let err = init_global_dns_resolver(&chain_config(&other, DnsFailurePolicy::Fail), false).unwrap_err();
+        assert!(matches!(err, ProxyError::AlreadyInitialized(_)), "{}", err);
+        assert_eq!(get_global_dns_resolver().name_servers(), servers);
+    }
+
+    fn chain_config(servers: &[SocketAddr], on_failure: DnsFailurePolicy) -> DnsConfig {
+        DnsConfig {
+            servers: servers.iter().map(|s| s.to_string()).collect(),
+            timeout_secs: 2,
+            on_failure,
+            ..DnsConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fallback_to_second_server_on_timeout() {
+        let silent = spawn_mock_server(MockBehavior::Silent).await;
+        let answering = spawn_mock_server(MockBehavior::Answer(Ipv4Addr::new(10, 0, 0, 1))).await;
+        let resolver =
+            DnsResolver::from_config(&chain_config(&[silent, answering], DnsFailurePolicy::Fail))
+                .unwrap();
+
+        let started = Instant::now();
+        let addr = resolver.resolve_domain("example.test", 443).await.unwrap();
+
+        assert_eq!(addr, "10.0.0.1:443".parse().unwrap());
+        assert!(started.elapsed() < Duration::from_secs(2) + Duration::from_millis(500));
+
+        let stats = resolver.stats();
+        assert_eq!(stats.servers[0].failures, 1);
+        assert_eq!(stats.servers[0].fallbacks, 1);
+        assert_eq!(stats.servers[1].queries, 1);
+        assert_eq!(stats.servers[1].failures, 0);
+    }
+
+    #[tokio::test]
+    async fn test_fallback_on_servfail() {
+        let failing = spawn_mock_server(MockBehavior::Reply(ResponseCode::ServFail)).await;
+        let answering = spawn_mock_server(MockBehavior::Answer(Ipv4Addr::new(10, 0, 0, 2))).await;
+        let resolver =
+            DnsResolver::from_config(&chain_config(&[failing, answering], DnsFailurePolicy::Fail))
+                .unwrap();
+
+        let addr = resolver.resolve_domain("example.test", 80).await.unwrap();
+        assert_eq!(addr.ip(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(resolver.stats().servers[0].fallbacks, 1);
+    }
+
+    #[tokio::test]
+    async fn test_nxdomain_short_circuits_chain() {
+        let nxdomain = spawn_mock_server(MockBehavior::Reply(ResponseCode::NXDomain)).await;
+        let answering = spawn_mock_server(MockBehavior::Answer(Ipv4Addr::new(10, 0, 0, 3))).await;
+        let resolver = DnsResolver::from_config(&chain_config(
+            &[nxdomain, answering],
+            DnsFailurePolicy::ServeStale,
+        ))
+        .unwrap();
+        resolver.last_answers.write().unwrap().insert(
+            "missing.test".to_string(),
+            (vec![IpAddr::V4(Ipv4Addr::new(10, 9, 9, 9))], Instant::now()),
+        );
+
+        let result = resolver.resolve_domain("missing.test", 80).await;
+        assert!(matches!(result, Err(ProxyError::DnsResolution(_))));
+
+        let stats = resolver.stats();
+        assert_eq!(stats.servers[0].fallbacks, 0);
+        assert_eq!(stats.servers[1].queries, 0);
+        assert_eq!(stats.stale_serves, 0);
+    }
+
+    #[tokio::test]
+    async fn test_serve_stale_when_all_servers_fail() {
+        let first = spawn_mock_server(MockBehavior::Silent).await;
+        let second = spawn_mock_server(MockBehavior::Reply(ResponseCode::ServFail)).await;
+        let stale_ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 4));
+
+        let resolver =
+            DnsResolver::from_config(&chain_config(&[first, second], DnsFailurePolicy::ServeStale))
+                .unwrap();
+        resolver
+            .last_answers
+            .write()
+            .unwrap()
+            .insert("stale.test".to_string(), (vec![stale_ip], Instant::now()));
+
+        let addr = resolver.resolve_domain("stale.test", 80).await.unwrap();
+        assert_eq!(addr.ip(), stale_ip);
+        assert_eq!(resolver.stats().stale_serves, 1);
+
+        // IPv6-only lookups must not be served a stale IPv4 address
+        assert!(resolver.resolve_domain_v6("stale.test", 80).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stale_answer_respects_max_age_and_policy() {
+        let first = spawn_mock_server(MockBehavior::Reply(ResponseCode::ServFail)).await;
+        let stale_ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5));
+
+        let mut config = chain_config(&[first], DnsFailurePolicy::ServeStale);
+        config.serve_stale_max_secs = 0;
+        let resolver = DnsResolver::from_config(&config).unwrap();
+        resolver.last_answers.write().unwrap().insert(
+            "old.test".to_string(),
+            (vec![stale_ip], Instant::now() - Duration::from_secs(1)),
+        );
+        assert!(resolver.resolve_domain("old.test", 80).await.is_err());
+
+        let resolver =
+            DnsResolver::from_config(&chain_config(&[first], DnsFailurePolicy::Fail)).unwrap();
+        resolver
+            .last_answers
+            .write()
+            .unwrap()
+            .insert("old.test".to_string(), (vec![stale_ip], Instant::now()));
+        assert!(resolver.resolve_domain("old.test", 80).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stale_answers_purged_and_capped() {
+        let first = spawn_mock_server(MockBehavior::Reply(ResponseCode::ServFail)).await;
+        let mut config = chain_config(&[first], DnsFailurePolicy::ServeStale);
+        config.serve_stale_max_secs = 60;
+        let resolver = DnsResolver::from_config(&config).unwrap();
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7));
+        resolver.last_answers.write().unwrap().insert("old.test".to_string(), (vec![ip], Instant::now() - Duration::from_secs(61)));
+        resolver.remember("fresh.test", &[ip]);
+
+        // 超过 serve_stale_max_secs 的旧答案被清理，新的留下
+        assert_eq!(resolver.purge_expired_answers(), 1);
+        let answers = resolver.last_answers.read().unwrap();
+        assert_eq!(answers.keys().collect::<Vec<_>>(), ["fresh.test"]);
+        drop(answers);
+
+        // 大量不同域名也不会超过上限，最旧的先被挤出
+        for i in 0..MAX_STALE_ANSWERS {
+            resolver.remember(&format!("name{}.test", i), &[ip]);
+        }
+        let answers = resolver.last_answers.read().unwrap();
+        assert_eq!(answers.len(), MAX_STALE_ANSWERS);
+        assert!(!answers.contains_key("fresh.test"));
+    }
+
+    #[tokio::test]
+    async fn test_query_log_tracks_domains() {
+        let delay = Duration::from_millis(40);
+        let server = spawn_mock_server(MockBehavior::Delayed(delay, Ipv4Addr::new(10, 0, 0, 6))).await;
+        let mut config = chain_config(&[server], DnsFailurePolicy::Fail);
+        config.query_log = true;
+        config.stats_max_domains = 2;
+        let resolver = DnsResolver::from_config(&config).unwrap();
+
+        for _ in 0..3 {
+            resolver.resolve_domain_v4("slow.test", 80).await.unwrap();
+        }
+        assert!(resolver.resolve_domain_v4("missing.test", 80).await.is_err());
+
+        let slow = resolver.domain_stats("slow.test").unwrap();
+        assert_eq!((slow.queries, slow.failures), (3, 0));
+        assert!(slow.p50 >= delay && slow.p95 >= slow.p50, "{}", slow);
+        let missing = resolver.domain_stats("missing.test").unwrap();
+        assert_eq!((missing.queries, missing.failures), (1, 1));
+        assert_eq!(resolver.stats().slowest_domains.len(), 2);
+
+        // 容量为2：最久未查询的 slow.test 被淘汰
+        resolver.resolve_domain_v4("other.test", 80).await.unwrap();
+        assert!(resolver.domain_stats("slow.test").is_none());
+        assert!(resolver.domain_stats("missing.test").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_no_domain_stats_without_query_log() {
+        let server = spawn_mock_server(MockBehavior::Answer(Ipv4Addr::new(10, 0, 0, 7))).await;
+        let resolver = DnsResolver::from_config(&chain_config(&[server], DnsFailurePolicy::Fail)).unwrap();
+        resolver.resolve_domain_v4("quiet.test", 80).await.unwrap();
+        assert!(resolver.domain_stats("quiet.test").is_none());
+        assert!(resolver.stats().slowest_domains.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_answers_cached_for_their_ttl() {
+        let server = spawn_mock_server(MockBehavior::Answer(Ipv4Addr::new(10, 0, 0, 8))).await;
+        let resolver = DnsResolver::from_config(&chain_config(&[server], DnsFailurePolicy::Fail)).unwrap();
+        resolver.resolve_domain_v4("cached.test", 80).await.unwrap();
+        let addr = resolver.resolve_domain_v4("cached.test", 443).await.unwrap();
+        assert_eq!(addr, "10.0.0.8:443".parse().unwrap());
+
+        let stats = resolver.stats();
+        assert_eq!(stats.servers[0].queries, 1);
+        assert_eq!((stats.cache.hits, stats.cache.entries), (1, 1));
+        assert_eq!(resolver.cache_stats(), stats.cache);
+        assert_eq!(resolver.cache_stats().misses, 1);
+    }
+
+    #[tokio::test]
+    async fn test_client_subnet_follows_egress_hint() {
+        let (server, seen) = spawn_ecs_recorder(Ipv4Addr::new(10, 0, 0, 9)).await;
+        let subnet = |s: &str| ClientSubnet::from(s.parse::<IpNet>().unwrap());
+
+        let japan = OutboundConfig { egress_hint_subnet: Some("203.0.113.0/24".to_string()), ..OutboundConfig::direct("jp") };
+        let group = OutboundConfig {
+            kind: OutboundType::Selector { outbounds: vec!["jp".to_string()], default: None },
+            ..OutboundConfig::direct("asia")
+        };
+        let manager = OutboundManager::from_configs(&[japan, group]).unwrap();
+
+        let mut config = chain_config(&[server], DnsFailurePolicy::Fail);
+        config.client_subnet = Some("198.51.100.7/24".to_string());
+        let resolver = DnsResolver::from_config(&config).unwrap();
+
+        let (ips, _) = resolver.resolve_all("cdn.test").await.unwrap();
+        assert_eq!(ips, vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 9))]);
+        resolver.resolve_all_with_subnet("cdn.test", manager.egress_hint_subnet("asia")).await.unwrap();
+        resolver.resolve_all_with_subnet("cdn.test", manager.egress_hint_subnet("direct")).await.unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![Some(subnet("198.51.100.0/24")), Some(subnet("203.0.113.0/24"))],
+            "answers are cached per subnet"
+        );
+
+        // 未配置子网时不带 ECS
+        seen.lock().unwrap().clear();
+        let resolver = DnsResolver::from_config(&chain_config(&[server], DnsFailurePolicy::Fail)).unwrap();
+        resolver.resolve_domain_v4("cdn.test", 80).await.unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec![None]);
+    }
+
+    #[test]
+    fn test_invalid_server_rejected() {
+        let config = DnsConfig {
+            servers: vec!["not-an-address".to_string()],
+            ..DnsConfig::default()
+        };
+        let err = DnsResolver::from_config(&config).err().unwrap();
+        assert!(matches!(err, ProxyError::Protocol(_)));
+        assert!(err.to_string().contains("Invalid DNS server \"not-an-address\""), "{}", err);
+        assert!(init_global_dns_resolver(&config, false).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_configured_servers_used() {
+        let config = DnsConfig { servers: vec!["1.1.1.1:53".to_string()], ..DnsConfig::default() };
+        let resolver = DnsResolver::from_config(&config).unwrap();
+        assert_eq!(resolver.name_servers(), ["1.1.1.1:53".parse::<SocketAddr>().unwrap()]);
+
+        let config = DnsConfig {
+            servers: vec!["9.9.9.9".to_string(), "[2606:4700::1111]:5353".to_string()],
+            ..DnsConfig::default()
+        };
+        let resolver = DnsResolver::from_config(&config).unwrap();
+        let expected: Vec<SocketAddr> = vec!["9.9.9.9:53".parse().unwrap(), "[2606:4700::1111]:5353".parse().unwrap()];
+        assert_eq!(resolver.name_servers(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_aaaa_lookups_skipped_without_ipv6() {
+        let answering = spawn_mock_server(MockBehavior::Answer(Ipv4Addr::new(10, 0, 0, 9))).await;
+        let config = DnsConfig { enable_ipv6: false, ..chain_config(&[answering], DnsFailurePolicy::Fail) };
+        let resolver = DnsResolver::from_config(&config).unwrap();
+
+        let err = resolver.resolve_domain_v6("example.test", 443).await.unwrap_err();
+        assert!(err.to_string().contains("IPv6 resolution is disabled"), "{}", err);
+        assert_eq!(resolver.stats().servers[0].queries, 0);
+
+        let (ips, _) = resolver.resolve_all("example.test").await.unwrap();
+        assert_eq!(ips, [IpAddr::V4(Ipv4Addr::new(10, 0, 0, 9))]);
+    }
 
     #[tokio::test]
     async fn test_dns_resolution() {
@@ -0,0 +1,520 @@
+// DNS应答缓存：按记录TTL缓存结果，合并同一域名的并发查询（singleflight），
+// 并由后台任务在热门域名过期前预取，让连接几乎总能命中热缓存
+use crate::config::DnsPrefetchConfig;
+use futures::stream::{self, StreamExt};
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// Answers cached at most; expired ones are pruned first, then the soonest to expire
+pub const MAX_CACHED_ANSWERS: usize = 4096;
+/// How often the prefetcher looks for answers about to expire
+pub const PREFETCH_SCAN_INTERVAL: Duration = Duration::from_secs(1);
+/// How often expired answers nobody looked up again are dropped
+pub const CACHE_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+/// Answers with a shorter TTL are not worth prefetching
+const MIN_PREFETCH_TTL: Duration = Duration::from_secs(10);
+/// Delay before retrying a failed refresh; doubles with each consecutive failure
+const PREFETCH_BACKOFF_BASE: Duration = Duration::from_secs(5);
+const PREFETCH_BACKOFF_MAX: Duration = Duration::from_secs(600);
+
+/// Outcome of a lookup; upstream outcomes are shared with every caller joined on them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsAnswer {
+    /// Served from the cache without a query
+    Cached(Vec<IpAddr>),
+    /// Addresses from `server`, cacheable for `ttl`
+    Answer { ips: Vec<IpAddr>, ttl: Duration, server: String },
+    /// Every upstream failed and the last known answer was served instead
+    Stale(Vec<IpAddr>),
+    /// The domain has no records; never cached nor prefetched
+    NxDomain(String),
+    /// Every upstream failed
+    Failed(String),
+}
+
+/// Answer cache counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DnsCacheStats {
+    /// Answers currently cached
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Answers dropped after their TTL ran out
+    pub expired: u64,
+    /// Lookups that waited for an identical query already in flight
+    pub joined: u64,
+    /// Background refreshes started
+    pub prefetches: u64,
+    pub prefetch_failures: u64,
+    /// Lookups served by a prefetched answer that would otherwise have missed
+    pub prefetch_hits: u64,
+}
+
+struct Entry {
+    ips: Vec<IpAddr>,
+    ttl: Duration,
+    expires: Instant,
+    /// 由预取写入且尚未被查询命中
+    prefetched: bool,
+}
+
+impl Entry {
+    /// Whether the prefetcher should refresh this answer at `now`
+    fn refresh_due(&self, now: Instant) -> bool {
+        if self.ttl < MIN_PREFETCH_TTL {
+            return false;
+        }
+        let lead = (self.ttl / 10).max(PREFETCH_SCAN_INTERVAL * 2);
+        now < self.expires && now + lead >= self.expires
+    }
+}
+
+/// Popularity of one key in the top-K table
+struct Hot {
+    hits: u64,
+    /// `hits` when the last refresh started; only keys looked up since are refreshed again
+    hits_at_refresh: u64,
+    /// Consecutive failed refreshes
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+/// Bounded table of the most looked-up keys (space-saving counting)
+///
+/// When full, a new key replaces the least popular one and inherits its
+/// count, so a burst of one-off names cannot push out steadily hot ones.
+struct TopK<K> {
+    capacity: usize,
+    keys: HashMap<K, Hot>,
+}
+
+impl<K: Clone + Eq + Hash> TopK<K> {
+    fn hit(&mut self, key: &K) {
+        if let Some(hot) = self.keys.get_mut(key) {
+            hot.hits += 1;
+            return;
+        }
+        if self.capacity == 0 {
+            return;
+        }
+        let mut hits = 1;
+        if self.keys.len() >= self.capacity {
+            let coldest = self.keys.iter().min_by_key(|(_, hot)| hot.hits).map(|(key, hot)| (key.clone(), hot.hits));
+            if let Some((coldest, coldest_hits)) = coldest {
+                self.keys.remove(&coldest);
+                hits += coldest_hits;
+            }
+        }
+        self.keys.insert(key.clone(), Hot { hits, hits_at_refresh: 0, failures: 0, retry_at: None });
+    }
+}
+
+type Inflight<K> = Mutex<HashMap<K, watch::Receiver<Option<DnsAnswer>>>>;
+
+/// Forgets the in-flight query once its leader finishes or is cancelled
+struct Flight<'a, K: Eq + Hash> {
+    inflight: &'a Inflight<K>,
+    key: &'a K,
+}
+
+impl<K: Eq + Hash> Drop for Flight<'_, K> {
+    fn drop(&mut self) {
+        self.inflight.lock().unwrap().remove(self.key);
+    }
+}
+
+/// Answer cache with singleflight lookups and popularity-driven prefetch
+///
+/// Generic over the key so the resolver can cache per address family.
+pub struct DnsCache<K> {
+    /// Upper bound on cached TTLs; zero disables caching
+    max_ttl: Duration,
+    prefetch: DnsPrefetchConfig,
+    entries: Mutex<HashMap<K, Entry>>,
+    inflight: Inflight<K>,
+    popular: Mutex<TopK<K>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    expired: AtomicU64,
+    joined: AtomicU64,
+    prefetches: AtomicU64,
+    prefetch_failures: AtomicU64,
+    prefetch_hits: AtomicU64,
+}
+
+impl<K: Clone + Eq + Hash> DnsCache<K> {
+    pub fn new(max_ttl: Duration, prefetch: DnsPrefetchConfig) -> Self {
+        let capacity = if prefetch.enabled { prefetch.top_k } else { 0 };
+        Self {
+            max_ttl,
+            prefetch,
+            entries: Mutex::new(HashMap::new()),
+            inflight: Mutex::new(HashMap::new()),
+            popular: Mutex::new(TopK { capacity, keys: HashMap::new() }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            joined: AtomicU64::new(0),
+            prefetches: AtomicU64::new(0),
+            prefetch_failures: AtomicU64::new(0),
+            prefetch_hits: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether answers are cached at all
+    pub fn enabled(&self) -> bool {
+        !self.max_ttl.is_zero()
+    }
+
+    /// Whether a background prefetch task has anything to do
+    pub fn prefetch_enabled(&self) -> bool {
+        self.prefetch.enabled && self.enabled()
+    }
+
+    /// The cached addresses of `key` if they have not expired
+    fn get(&self, key: &K) -> Option<Vec<IpAddr>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(key)?;
+        if Instant::now() >= entry.expires {
+            entries.remove(key);
+            self.expired.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        if entry.prefetched {
+            entry.prefetched = false;
+            self.prefetch_hits.fetch_add(1, Ordering::Relaxed);
+        }
+        Some(entry.ips.clone())
+    }
+
+    /// Cached answer for `key`, or the result of `fetch`
+    ///
+    /// Concurrent misses for the same key share a single `fetch`.
+    pub async fn resolve<F, Fut>(&self, key: K, fetch: F) -> DnsAnswer
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = DnsAnswer>,
+    {
+        self.popular.lock().unwrap().hit(&key);
+        if let Some(ips) = self.get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return DnsAnswer::Cached(ips);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        self.fetch_shared(&key, false, fetch).await
+    }
+
+    /// Run `fetch` unless the same key is already being fetched, then wait for that instead
+    async fn fetch_shared<F, Fut>(&self, key: &K, prefetch: bool, fetch: F) -> DnsAnswer
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = DnsAnswer>,
+    {
+        let sender = loop {
+            let mut receiver = {
+                let mut inflight = self.inflight.lock().unwrap();
+                match inflight.get(key) {
+                    Some(receiver) => receiver.clone(),
+                    None => {
+                        let (sender, receiver) = watch::channel(None);
+                        inflight.insert(key.clone(), receiver);
+                        break sender;
+                    }
+                }
+            };
+            self.joined.fetch_add(1, Ordering::Relaxed);
+            // 领头的查询被取消时发送端随之丢弃，由本调用者重新发起
+            let answer = receiver.wait_for(Option::is_some).await.map(|answer| answer.clone());
+            if let Ok(Some(answer)) = answer {
+                return answer;
+            }
+        };
+
+        let _flight = Flight { inflight: &self.inflight, key };
+        let fetched = fetch().await;
+        self.store(key, &fetched, prefetch);
+        let _ = sender.send(Some(fetched.clone()));
+        fetched
+    }
+
+    fn store(&self, key: &K, fetched: &DnsAnswer, prefetched: bool) {
+        match fetched {
+            DnsAnswer::Answer { ips, ttl, .. } => {
+                let ttl = (*ttl).min(self.max_ttl);
+                if ttl.is_zero() {
+                    return;
+                }
+                let now = Instant::now();
+                let mut entries = self.entries.lock().unwrap();
+                if entries.len() >= MAX_CACHED_ANSWERS && !entries.contains_key(key) {
+                    self.retain_live(&mut entries, now);
+                    if entries.len() >= MAX_CACHED_ANSWERS {
+                        let soonest = entries.iter().min_by_key(|(_, entry)| entry.expires).map(|(key, _)| key.clone());
+                        if let Some(soonest) = soonest {
+                            entries.remove(&soonest);
+                        }
+                    }
+                }
+                entries.insert(key.clone(), Entry { ips: ips.clone(), ttl, expires: now + ttl, prefetched });
+            }
+            DnsAnswer::NxDomain(_) => {
+                self.entries.lock().unwrap().remove(key);
+                self.popular.lock().unwrap().keys.remove(key);
+            }
+            DnsAnswer::Cached(_) | DnsAnswer::Stale(_) | DnsAnswer::Failed(_) => {}
+        }
+    }
+
+    fn retain_live(&self, entries: &mut HashMap<K, Entry>, now: Instant) -> usize {
+        let before = entries.len();
+        entries.retain(|_, entry| entry.expires > now);
+        let removed = before - entries.len();
+        self.expired.fetch_add(removed as u64, Ordering::Relaxed);
+        removed
+    }
+
+    /// Drop every expired answer, returning how many were removed
+    ///
+    /// Lookups already skip expired answers; this only frees the memory of
+    /// names that are not looked up again.
+    pub fn purge_expired(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        self.retain_live(&mut entries, Instant::now())
+    }
+
+    /// Purge loop, until the task is cancelled
+    pub async fn run_cleanup(&self, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.purge_expired();
+        }
+    }
+
+    /// Popular keys whose cached answer is about to expire
+    fn due_for_refresh(&self, now: Instant) -> Vec<K> {
+        let mut popular = self.popular.lock().unwrap();
+        let entries = self.entries.lock().unwrap();
+        let min_hits = self.prefetch.min_hits;
+        let mut due = Vec::new();
+        for (key, hot) in popular.keys.iter_mut() {
+            let ready = hot.hits >= min_hits
+                && hot.hits > hot.hits_at_refresh
+                && hot.retry_at.is_none_or(|at| now >= at)
+                && entries.get(key).is_some_and(|entry| entry.refresh_due(now));
+            if ready {
+                hot.hits_at_refresh = hot.hits;
+                due.push(key.clone());
+            }
+        }
+        due
+    }
+
+    async fn refresh<F, Fut>(&self, key: K, refresh: &F)
+    where
+        F: Fn(K) -> Fut,
+        Fut: Future<Output = DnsAnswer>,
+    {
+        self.prefetches.fetch_add(1, Ordering::Relaxed);
+        let fetched = self.fetch_shared(&key, true, || refresh(key.clone())).await;
+        let mut popular = self.popular.lock().unwrap();
+        let Some(hot) = popular.keys.get_mut(&key) else {
+            return;
+        };
+        match fetched {
+            DnsAnswer::Cached(_) | DnsAnswer::Answer { .. } => {
+                hot.failures = 0;
+                hot.retry_at = None;
+            }
+            DnsAnswer::NxDomain(_) => {}
+            DnsAnswer::Stale(_) | DnsAnswer::Failed(_) => {
+                self.prefetch_failures.fetch_add(1, Ordering::Relaxed);
+                let backoff = PREFETCH_BACKOFF_BASE.saturating_mul(1 << hot.failures.min(16)).min(PREFETCH_BACKOFF_MAX);
+                hot.failures += 1;
+                hot.retry_at = Some(Instant::now() + backoff);
+                // 失败的刷新不算数：退避结束且应答未过期时再试
+                hot.hits_at_refresh = 0;
+            }
+        }
+    }
+
+    /// Refresh every popular answer that is about to expire, `concurrency` at a time
+    pub async fn prefetch_once<F, Fut>(&self, refresh: &F)
+    where
+        F: Fn(K) -> Fut,
+        Fut: Future<Output = DnsAnswer>,
+    {
+        let due = self.due_for_refresh(Instant::now());
+        stream::iter(due)
+            .for_each_concurrent(self.prefetch.concurrency.max(1), |key| self.refresh(key, refresh))
+            .await;
+    }
+
+    /// Prefetch loop; `refresh` queries the upstreams for a key without the cache
+    pub async fn run_prefetch<F, Fut>(&self, refresh: F)
+    where
+        F: Fn(K) -> Fut,
+        Fut: Future<Output = DnsAnswer>,
+    {
+        let mut interval = tokio::time::interval(PREFETCH_SCAN_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.prefetch_once(&refresh).await;
+        }
+    }
+
+    pub fn stats(&self) -> DnsCacheStats {
+        DnsCacheStats {
+            entries: self.entries.lock().unwrap().len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+            joined: self.joined.load(Ordering::Relaxed),
+            prefetches: self.prefetches.load(Ordering::Relaxed),
+            prefetch_failures: self.prefetch_failures.load(Ordering::Relaxed),
+            prefetch_hits: self.prefetch_hits.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::pin::Pin;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    fn prefetching(min_hits: u64) -> DnsPrefetchConfig {
+        DnsPrefetchConfig { enabled: true, top_k: 8, min_hits, concurrency: 2 }
+    }
+
+    /// Mock upstream counting its queries; names starting with "missing" are NXDOMAIN
+    fn upstream(calls: &Arc<AtomicUsize>, ttl: Duration) -> impl Fn(String) -> Pin<Box<dyn Future<Output = DnsAnswer> + Send>> {
+        let calls = calls.clone();
+        move |domain: String| {
+            let calls = calls.clone();
+            Box::pin(async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                if domain.starts_with("missing") {
+                    return DnsAnswer::NxDomain(format!("{} does not exist", domain));
+                }
+                DnsAnswer::Answer { ips: vec![IP], ttl, server: "mock".to_string() }
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_hot_domain_refreshed_before_expiry() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let query = upstream(&calls, Duration::from_secs(60));
+        let cache = Arc::new(DnsCache::new(Duration::from_secs(300), prefetching(3)));
+
+        for _ in 0..3 {
+            cache.resolve("hot.test".to_string(), || query("hot.test".to_string())).await;
+        }
+        cache.resolve("cold.test".to_string(), || query("cold.test".to_string())).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let prefetcher = cache.clone();
+        let refresh = upstream(&calls, Duration::from_secs(60));
+        let task = tokio::spawn(async move { prefetcher.run_prefetch(refresh).await });
+
+        // 60s TTL 的应答在剩余 6s 内被刷新；冷门域名不预取
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        let answer = cache.resolve("hot.test".to_string(), || query("hot.test".to_string())).await;
+        assert_eq!(answer, DnsAnswer::Cached(vec![IP]));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        cache.resolve("cold.test".to_string(), || query("cold.test".to_string())).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        let stats = cache.stats();
+        assert_eq!((stats.prefetches, stats.prefetch_hits, stats.prefetch_failures), (1, 1, 0));
+        assert_eq!((stats.hits, stats.misses), (3, 3));
+        task.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ttl_capped_and_expired_answers_purged() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = DnsCache::new(Duration::from_secs(30), DnsPrefetchConfig::default());
+        let short = upstream(&calls, Duration::from_secs(10));
+        let long = upstream(&calls, Duration::from_secs(3600));
+        cache.resolve("short.test".to_string(), || short("short.test".to_string())).await;
+        cache.resolve("long.test".to_string(), || long("long.test".to_string())).await;
+
+        // 记录 TTL 10s 生效；3600s 被上限截为 30s
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(cache.purge_expired(), 1);
+        let answer = cache.resolve("long.test".to_string(), || long("long.test".to_string())).await;
+        assert_eq!(answer, DnsAnswer::Cached(vec![IP]));
+        tokio::time::sleep(Duration::from_secs(21)).await;
+        assert_eq!(cache.purge_expired(), 1);
+
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.expired, stats.hits, stats.misses), (0, 2, 1, 2));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_concurrent_lookups_share_one_query() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let query = upstream(&calls, Duration::from_secs(60));
+        let cache = DnsCache::new(Duration::from_secs(300), DnsPrefetchConfig::default());
+
+        let key = || "shared.test".to_string();
+        let (a, b) = tokio::join!(cache.resolve(key(), || query(key())), cache.resolve(key(), || query(key())));
+        assert_eq!(a, b);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.stats().joined, 1);
+
+        // 前导查询被取消后，等待者自己重新发起
+        let cache = DnsCache::new(Duration::ZERO, DnsPrefetchConfig::default());
+        let leader = tokio::time::timeout(Duration::from_millis(5), cache.resolve(key(), || query(key())));
+        let (cancelled, follower) = tokio::join!(leader, cache.resolve(key(), || query(key())));
+        assert!(cancelled.is_err());
+        assert!(matches!(follower, DnsAnswer::Answer { .. }));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_nxdomain_never_prefetched_and_failures_back_off() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let query = upstream(&calls, Duration::from_secs(300));
+        let cache = DnsCache::new(Duration::from_secs(300), prefetching(1));
+
+        for _ in 0..3 {
+            let answer = cache.resolve("missing.test".to_string(), || query("missing.test".to_string())).await;
+            assert!(matches!(answer, DnsAnswer::NxDomain(_)));
+        }
+        cache.resolve("flaky.test".to_string(), || query("flaky.test".to_string())).await;
+        let stored = Instant::now();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        let failing = |_: String| async { DnsAnswer::Failed("SERVFAIL".to_string()) };
+        // 300s TTL：剩余 30s 起可刷新；失败后依次退避 5s、10s
+        let mut prefetches = Vec::new();
+        for at in [270, 271, 275, 280, 285] {
+            tokio::time::sleep_until(stored + Duration::from_secs(at)).await;
+            cache.prefetch_once(&failing).await;
+            prefetches.push(cache.stats().prefetches);
+        }
+        assert_eq!(prefetches, [1, 1, 2, 2, 3]);
+        assert_eq!(cache.stats().prefetch_failures, 3);
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+}
@@ -0,0 +1,162 @@
+//! DNS queries carrying an EDNS Client Subnet option (RFC 7871)
+//!
+//! trust-dns' resolver cannot attach EDNS options to individual lookups, so
+//! lookups with a client subnet are sent as hand-built UDP queries straight to
+//! the upstream server. Failures are reported as [`ResolveError`]s shaped like
+//! the resolver's own, so the fallback chain treats both paths alike.
+
+use ipnet::IpNet;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_resolver::proto::op::{Edns, Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_resolver::proto::rr::rdata::opt::{ClientSubnet, EdnsOption};
+use trust_dns_resolver::proto::rr::{Name, RData, RecordType};
+
+/// Advertised UDP payload size, the DNS flag day 2020 recommendation
+const MAX_PAYLOAD: u16 = 1232;
+
+/// Query `server` for the `record_type` addresses of `domain`, sending `subnet` as ECS
+///
+/// Returns the addresses and the smallest TTL among the answers. NXDOMAIN and
+/// empty answers become `NoRecordsFound`; the caller bounds the wait.
+pub async fn query(
+    server: SocketAddr,
+    domain: &str,
+    record_type: RecordType,
+    subnet: IpNet,
+) -> Result<(Vec<IpAddr>, Duration), ResolveError> {
+    let mut name = Name::from_utf8(domain)?;
+    name.set_fqdn(true);
+    let query = Query::query(name, record_type);
+    let request = build_request(query_id()?, query.clone(), subnet);
+
+    let bind: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(server).await?;
+    socket.send(&request.to_vec()?).await?;
+
+    let mut buf = vec![0u8; 4096];
+    let response = loop {
+        let n = socket.recv(&mut buf).await?;
+        // 丢弃 ID 或问题不匹配的报文（迟到的旧响应或伪造包）
+        match Message::from_vec(&buf[..n]) {
+            Ok(response) if response.id() == request.id() && response.queries() == request.queries() => break response,
+            _ => continue,
+        }
+    };
+    parse_response(query, response)
+}
+
+fn build_request(id: u16, query: Query, subnet: IpNet) -> Message {
+    let mut request = Message::new();
+    request
+        .set_id(id)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(query);
+    let mut edns = Edns::new();
+    edns.set_max_payload(MAX_PAYLOAD);
+    // 地址须截断到前缀长度，否则服务器按 RFC 7871 应回 FORMERR
+    edns.options_mut().insert(EdnsOption::Subnet(ClientSubnet::from(subnet.trunc())));
+    request.set_edns(edns);
+    request
+}
+
+fn parse_response(query: Query, response: Message) -> Result<(Vec<IpAddr>, Duration), ResolveError> {
+    if response.truncated() {
+        return Err(ResolveErrorKind::Message("truncated response to ECS query").into());
+    }
+    let response_code = response.response_code();
+    if !matches!(response_code, ResponseCode::NoError | ResponseCode::NXDomain) {
+        return Err(ResolveErrorKind::Msg(format!("server responded with {}", response_code)).into());
+    }
+
+    let mut ips = Vec::new();
+    let mut ttl = u32::MAX;
+    for record in response.answers() {
+        let ip = match record.data() {
+            Some(RData::A(a)) if query.query_type() == RecordType::A => IpAddr::V4(a.0),
+            Some(RData::AAAA(aaaa)) if query.query_type() == RecordType::AAAA => IpAddr::V6(aaaa.0),
+            _ => continue,
+        };
+        ips.push(ip);
+        ttl = ttl.min(record.ttl());
+    }
+    if ips.is_empty() {
+        return Err(ResolveErrorKind::NoRecordsFound {
+            query: Box::new(query),
+            soa: None,
+            negative_ttl: None,
+            response_code,
+            trusted: true,
+        }
+        .into());
+    }
+    Ok((ips, Duration::from_secs(ttl as u64)))
+}
+
+/// Unpredictable query ID, so off-path answers are hard to forge
+fn query_id() -> Result<u16, ResolveError> {
+    let mut bytes = [0u8; 2];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| ResolveErrorKind::Message("no randomness for DNS query id"))?;
+    Ok(u16::from_ne_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trust_dns_resolver::proto::rr::rdata::opt::EdnsCode;
+    use trust_dns_resolver::proto::rr::Record;
+
+    fn query_for(record_type: RecordType) -> Query {
+        Query::query(Name::from_ascii("example.test.").unwrap(), record_type)
+    }
+
+    #[test]
+    fn test_request_carries_truncated_subnet() {
+        let subnet: IpNet = "203.0.113.77/24".parse().unwrap();
+        let request = build_request(7, query_for(RecordType::A), subnet);
+        let request = Message::from_vec(&request.to_vec().unwrap()).unwrap();
+
+        let edns = request.extensions().as_ref().unwrap();
+        assert_eq!(edns.max_payload(), MAX_PAYLOAD);
+        let expected = ClientSubnet::from("203.0.113.0/24".parse::<IpNet>().unwrap());
+        assert_eq!(edns.option(EdnsCode::Subnet), Some(&EdnsOption::Subnet(expected)));
+        assert!(request.recursion_desired());
+    }
+
+    #[test]
+    fn test_response_ttl_and_empty_answers() {
+        let query = query_for(RecordType::A);
+        let mut response = Message::new();
+        response.add_query(query.clone());
+        for (ttl, last) in [(300, 1), (60, 2)] {
+            response.add_answer(Record::from_rdata(
+                query.name().clone(),
+                ttl,
+                RData::A(Ipv4Addr::new(192, 0, 2, last).into()),
+            ));
+        }
+        let (ips, ttl) = parse_response(query.clone(), response).unwrap();
+        assert_eq!(ips.len(), 2);
+        assert_eq!(ttl, Duration::from_secs(60));
+
+        let mut nxdomain = Message::new();
+        nxdomain.set_response_code(ResponseCode::NXDomain);
+        let error = parse_response(query.clone(), nxdomain).unwrap_err();
+        assert!(matches!(error.kind(), ResolveErrorKind::NoRecordsFound { response_code: ResponseCode::NXDomain, .. }));
+
+        let mut servfail = Message::new();
+        servfail.set_response_code(ResponseCode::ServFail);
+        assert!(matches!(parse_response(query, servfail).unwrap_err().kind(), ResolveErrorKind::Msg(_)));
+    }
+}
@@ -0,0 +1,270 @@
+// DNS查询统计：按域名滚动统计耗时分位数与失败率，LRU淘汰防止高基数流量撑爆内存
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Domains tracked before the least recently queried one is evicted
+pub const DEFAULT_MAX_DOMAINS: usize = 1024;
+/// Recent query durations kept per domain for the percentiles
+pub const DURATION_SAMPLES: usize = 64;
+
+/// How a DNS query ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsOutcome {
+    /// Answered by an upstream server
+    Upstream,
+    /// Served from the last known answer after the upstreams failed
+    Cached,
+    /// The domain has no records
+    NxDomain,
+    Timeout,
+    /// Any other failure (SERVFAIL, transport errors)
+    Failed,
+}
+
+impl DnsOutcome {
+    pub fn is_failure(self) -> bool {
+        matches!(self, DnsOutcome::NxDomain | DnsOutcome::Timeout | DnsOutcome::Failed)
+    }
+}
+
+impl fmt::Display for DnsOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DnsOutcome::Upstream => "upstream",
+            DnsOutcome::Cached => "cached",
+            DnsOutcome::NxDomain => "nxdomain",
+            DnsOutcome::Timeout => "timeout",
+            DnsOutcome::Failed => "failed",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Aggregates for one domain
+#[derive(Debug, Clone, PartialEq)]
+pub struct DomainDnsStats {
+    pub domain: String,
+    pub queries: u64,
+    pub failures: u64,
+    /// Median over the last `DURATION_SAMPLES` queries
+    pub p50: Duration,
+    pub p95: Duration,
+}
+
+impl DomainDnsStats {
+    pub fn failure_rate(&self) -> f64 {
+        if self.queries == 0 {
+            return 0.0;
+        }
+        self.failures as f64 / self.queries as f64
+    }
+}
+
+impl fmt::Display for DomainDnsStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} queries={} p50={:.1?} p95={:.1?} failure_rate={:.1}%",
+            self.domain,
+            self.queries,
+            self.p50,
+            self.p95,
+            self.failure_rate() * 100.0
+        )
+    }
+}
+
+struct DomainEntry {
+    queries: u64,
+    failures: u64,
+    samples: VecDeque<Duration>,
+    /// 最近一次访问的序号，对应 `order` 中的键
+    last_used: u64,
+}
+
+impl DomainEntry {
+    fn stats(&self, domain: &str) -> DomainDnsStats {
+        let mut sorted: Vec<Duration> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        DomainDnsStats {
+            domain: domain.to_string(),
+            queries: self.queries,
+            failures: self.failures,
+            p50: percentile(&sorted, 50),
+            p95: percentile(&sorted, 95),
+        }
+    }
+}
+
+/// Nearest-rank percentile of sorted samples
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (sorted.len() * p).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<String, DomainEntry>,
+    /// 访问序号 -> 域名，最小的即最久未访问
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+/// Rolling per-domain query statistics, bounded by an LRU
+pub struct DomainStatsTable {
+    capacity: usize,
+    inner: Mutex<Lru>,
+}
+
+impl DomainStatsTable {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            inner: Mutex::new(Lru::default()),
+        }
+    }
+
+    /// Record one query for `domain`
+    pub fn record(&self, domain: &str, duration: Duration, outcome: DnsOutcome) {
+        let mut lru = self.inner.lock().unwrap();
+        let Lru { entries, order, tick } = &mut *lru;
+        *tick += 1;
+
+        match entries.get_mut(domain) {
+            Some(entry) => {
+                order.remove(&entry.last_used);
+                entry.last_used = *tick;
+            }
+            None => {
+                if entries.len() >= self.capacity {
+                    if let Some((_, evicted)) = order.pop_first() {
+                        entries.remove(&evicted);
+                    }
+                }
+                entries.insert(
+                    domain.to_string(),
+                    DomainEntry { queries: 0, failures: 0, samples: VecDeque::new(), last_used: *tick },
+                );
+            }
+        }
+        order.insert(*tick, domain.to_string());
+
+        let entry = entries.get_mut(domain).expect("entry inserted above");
+        entry.queries += 1;
+        if outcome.is_failure() {
+            entry.failures += 1;
+        }
+        if entry.samples.len() == DURATION_SAMPLES {
+            entry.samples.pop_front();
+        }
+        entry.samples.push_back(duration);
+    }
+
+    pub fn get(&self, domain: &str) -> Option<DomainDnsStats> {
+        let lru = self.inner.lock().unwrap();
+        lru.entries.get(domain).map(|entry| entry.stats(domain))
+    }
+
+    /// The `n` domains with the highest p95 resolution time
+    pub fn slowest(&self, n: usize) -> Vec<DomainDnsStats> {
+        let lru = self.inner.lock().unwrap();
+        let mut all: Vec<DomainDnsStats> = lru.entries.iter().map(|(domain, entry)| entry.stats(domain)).collect();
+        all.sort_by(|a, b| b.p95.cmp(&a.p95).then_with(|| a.domain.cmp(&b.domain)));
+        all.truncate(n);
+        all
+    }
+
+    /// Number of domains tracked
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for DomainStatsTable {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_DOMAINS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn test_percentiles_and_failure_rate() {
+        let table = DomainStatsTable::new(8);
+        for i in 1..=20 {
+            table.record("slow.test", ms(i * 10), DnsOutcome::Upstream);
+        }
+        table.record("slow.test", ms(5), DnsOutcome::Timeout);
+        table.record("slow.test", ms(5), DnsOutcome::NxDomain);
+        table.record("slow.test", ms(5), DnsOutcome::Cached);
+
+        let stats = table.get("slow.test").unwrap();
+        assert_eq!((stats.queries, stats.failures), (23, 2));
+        assert!((stats.failure_rate() - 2.0 / 23.0).abs() < 1e-9);
+        // 23个样本：第12个为中位数，第22个为p95
+        assert_eq!(stats.p50, ms(90));
+        assert_eq!(stats.p95, ms(190));
+    }
+
+    #[test]
+    fn test_samples_are_rolling() {
+        let table = DomainStatsTable::new(8);
+        for _ in 0..DURATION_SAMPLES {
+            table.record("a.test", ms(500), DnsOutcome::Upstream);
+        }
+        for _ in 0..DURATION_SAMPLES {
+            table.record("a.test", ms(1), DnsOutcome::Upstream);
+        }
+        let stats = table.get("a.test").unwrap();
+        assert_eq!(stats.queries, 2 * DURATION_SAMPLES as u64);
+        assert_eq!(stats.p95, ms(1));
+    }
+
+    #[test]
+    fn test_least_recently_queried_domain_evicted() {
+        let table = DomainStatsTable::new(3);
+        table.record("a.test", ms(1), DnsOutcome::Upstream);
+        table.record("b.test", ms(1), DnsOutcome::Upstream);
+        table.record("c.test", ms(1), DnsOutcome::Upstream);
+        // 访问 a 后，b 成为最久未访问的
+        table.record("a.test", ms(1), DnsOutcome::Upstream);
+        table.record("d.test", ms(1), DnsOutcome::Upstream);
+
+        assert_eq!(table.len(), 3);
+        assert!(table.get("b.test").is_none());
+        assert_eq!(table.get("a.test").unwrap().queries, 2);
+        assert!(table.get("c.test").is_some() && table.get("d.test").is_some());
+
+        for i in 0..1000 {
+            table.record(&format!("{}.random.test", i), ms(1), DnsOutcome::Upstream);
+        }
+        assert_eq!(table.len(), 3);
+        assert!(table.get("999.random.test").is_some());
+    }
+
+    #[test]
+    fn test_slowest_orders_by_p95() {
+        let table = DomainStatsTable::new(8);
+        table.record("fast.test", ms(2), DnsOutcome::Upstream);
+        table.record("slow.test", ms(300), DnsOutcome::Upstream);
+        table.record("mid.test", ms(40), DnsOutcome::Upstream);
+
+        let slowest: Vec<String> = table.slowest(2).into_iter().map(|s| s.domain).collect();
+        assert_eq!(slowest, ["slow.test", "mid.test"]);
+    }
+}
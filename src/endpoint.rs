@@ -0,0 +1,319 @@
+// 上游服务器端点：支持端口范围/端口列表，按连接选端口并在失败时换端口重试
+use crate::error::{ProxyError, Result};
+use crate::scope::ScopedIp;
+use crate::traffic_mark::{dial_tcp, DialOptions};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+/// Ports tried per connection before giving up
+pub const DEFAULT_PORT_ATTEMPTS: usize = 3;
+/// How long a port that failed to connect is tried last
+pub const FAILED_PORT_TTL: Duration = Duration::from_secs(60);
+/// Bound on a single port's connect, so a blackholed port leaves time to try others
+pub const PORT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How the port for a new connection is picked
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PortStrategy {
+    #[default]
+    Random,
+    RoundRobin,
+}
+
+/// Connect outcomes for one port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortStats {
+    pub port: u16,
+    pub successes: u64,
+    pub failures: u64,
+}
+
+#[derive(Default)]
+struct PortCounters {
+    successes: AtomicU64,
+    failures: AtomicU64,
+}
+
+/// Parse an outbound server address
+///
+/// Accepts `ip:port`, `ip:first-last` and `ip:p1,p2,...` (IPv6 in brackets,
+/// link-local ones with a zone: `[fe80::1%eth0]:443`). `extra_ports` is the
+/// outbound's `ports` list; when it is set the address must not carry ports
+/// of its own.
+pub fn parse_server_address(address: &str, extra_ports: &[u16]) -> Result<(ScopedIp, Vec<u16>)> {
+    let invalid = |reason: &str| ProxyError::Protocol(format!("Invalid server address {}: {}", address, reason));
+
+    if let Ok(host) = address.parse::<ScopedIp>() {
+        if extra_ports.is_empty() {
+            return Err(invalid("missing port"));
+        }
+        return Ok((host, dedup_ports(extra_ports)));
+    }
+
+    let (host, spec) = address.rsplit_once(':').ok_or_else(|| invalid("missing port"))?;
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    let host: ScopedIp = host.parse().map_err(|_| invalid("host must be an IP address"))?;
+    if !extra_ports.is_empty() {
+        return Err(invalid("set ports either in the address or in `ports`, not both"));
+    }
+
+    let mut ports = Vec::new();
+    for part in spec.split(',') {
+        let parse = |p: &str| match p.trim().parse::<u16>() {
+            Ok(0) | Err(_) => Err(invalid(&format!("bad port {:?}", p))),
+            Ok(port) => Ok(port),
+        };
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse(first)?, parse(last)?);
+                if first > last {
+                    return Err(invalid(&format!("empty port range {}", part)));
+                }
+                ports.extend(first..=last);
+            }
+            None => ports.push(parse(part)?),
+        }
+    }
+    Ok((host, dedup_ports(&ports)))
+}
+
+/// Drop repeated ports, keeping the configured order
+fn dedup_ports(ports: &[u16]) -> Vec<u16> {
+    let mut seen = std::collections::HashSet::new();
+    ports.iter().copied().filter(|p| seen.insert(*p)).collect()
+}
+
+/// An upstream server reachable on one or more ports
+pub struct ServerEndpoint {
+    host: ScopedIp,
+    ports: Vec<u16>,
+    strategy: PortStrategy,
+    max_attempts: usize,
+    next: AtomicUsize,
+    /// 最近连接失败的端口 -> 失败时间
+    failed: Mutex<HashMap<u16, Instant>>,
+    counters: Vec<PortCounters>,
+}
+
+impl ServerEndpoint {
+    pub fn new(host: ScopedIp, ports: Vec<u16>, strategy: PortStrategy) -> Self {
+        let counters = ports.iter().map(|_| PortCounters::default()).collect();
+        Self {
+            host,
+            ports,
+            strategy,
+            max_attempts: DEFAULT_PORT_ATTEMPTS,
+            next: AtomicUsize::new(0),
+            failed: Mutex::new(HashMap::new()),
+            counters,
+        }
+    }
+
+    /// Endpoint with a single fixed port
+    pub fn single(addr: SocketAddr) -> Self {
+        Self::new(ScopedIp::from(addr), vec![addr.port()], PortStrategy::default())
+    }
+
+    /// Build from an outbound's `address` and `ports` settings
+    pub fn parse(address: &str, extra_ports: &[u16], strategy: PortStrategy) -> Result<Self> {
+        let (host, ports) = parse_server_address(address, extra_ports)?;
+        Ok(Self::new(host, ports, strategy))
+    }
+
+    pub fn with_max_attempts(mut self, attempts: usize) -> Self {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    /// First configured address, used where a single address is needed
+    pub fn primary(&self) -> SocketAddr {
+        self.host.socket_addr(self.ports[0])
+    }
+
+    pub fn ports(&self) -> &[u16] {
+        &self.ports
+    }
+
+    /// Addresses to try for the next connection, in order
+    ///
+    /// Starts at the strategy's pick and walks the port list; ports that failed
+    /// within `FAILED_PORT_TTL` are moved behind the healthy ones.
+    pub fn candidates(&self) -> Vec<SocketAddr> {
+        let len = self.ports.len();
+        let budget = self.max_attempts.min(len);
+        let start = match self.strategy {
+            PortStrategy::Random => random_index(len),
+            PortStrategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % len,
+        };
+
+        let now = Instant::now();
+        let mut failed = self.failed.lock().unwrap();
+        failed.retain(|_, at| now.duration_since(*at) < FAILED_PORT_TTL);
+
+        let mut healthy = Vec::with_capacity(budget);
+        let mut deprioritized = Vec::new();
+        for offset in 0..len {
+            let port = self.ports[(start + offset) % len];
+            if failed.contains_key(&port) {
+                if deprioritized.len() < budget {
+                    deprioritized.push(port);
+                }
+            } else {
+                healthy.push(port);
+                if healthy.len() == budget {
+                    break;
+                }
+            }
+        }
+        healthy
+            .into_iter()
+            .chain(deprioritized)
+            .take(budget)
+            .map(|port| self.host.socket_addr(port))
+            .collect()
+    }
+
+    /// Record the outcome of a connect to `port`
+    pub fn record(&self, port: u16, success: bool) {
+        let Some(index) = self.ports.iter().position(|p| *p == port) else {
+            return;
+        };
+        let counters = &self.counters[index];
+        let mut failed = self.failed.lock().unwrap();
+        if success {
+            counters.successes.fetch_add(1, Ordering::Relaxed);
+            failed.remove(&port);
+        } else {
+            counters.failures.fetch_add(1, Ordering::Relaxed);
+            failed.insert(port, Instant::now());
+        }
+    }
+
+    /// Per-port counters for every port that has been tried
+    pub fn stats(&self) -> Vec<PortStats> {
+        self.ports
+            .iter()
+            .zip(&self.counters)
+            .map(|(port, c)| PortStats {
+                port: *port,
+                successes: c.successes.load(Ordering::Relaxed),
+                failures: c.failures.load(Ordering::Relaxed),
+            })
+            .filter(|s| s.successes + s.failures > 0)
+            .collect()
+    }
+
+    /// Connect to the server, moving on to another port when one fails
+    pub async fn dial(&self, options: &DialOptions) -> Result<TcpStream> {
+        let mut last_error = None;
+        for addr in self.candidates() {
+            let error = match tokio::time::timeout(PORT_CONNECT_TIMEOUT, dial_tcp(addr, options)).await {
+                Ok(Ok(stream)) => {
+                    self.record(addr.port(), true);
+                    return Ok(stream);
+                }
+                Ok(Err(e)) => e,
+                Err(_) => ProxyError::Io(std::io::ErrorKind::TimedOut.into()),
+            };
+            debug!("Upstream {} failed: {}", addr, error);
+            self.record(addr.port(), false);
+            last_error = Some(error);
+        }
+        Err(last_error.unwrap_or_else(|| ProxyError::Protocol("No upstream ports to connect to".to_string())))
+    }
+}
+
+fn random_index(len: usize) -> usize {
+    // RandomState 每次都有新的随机密钥，不需要密码学强度
+    (RandomState::new().build_hasher().finish() % len as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_parse_ranges_and_lists() {
+        let (host, ports) = parse_server_address("203.0.113.1:20000-20002", &[]).unwrap();
+        assert_eq!(host, "203.0.113.1".parse::<ScopedIp>().unwrap());
+        assert_eq!(ports, vec![20000, 20001, 20002]);
+
+        let (_, ports) = parse_server_address("[2001:db8::1]:443,8443,2053-2054", &[]).unwrap();
+        assert_eq!(ports, vec![443, 8443, 2053, 2054]);
+
+        let (_, ports) = parse_server_address("203.0.113.1", &[443, 8443, 443]).unwrap();
+        assert_eq!(ports, vec![443, 8443]);
+
+        for bad in ["203.0.113.1", "203.0.113.1:0", "203.0.113.1:9-1", "203.0.113.1:x", "example.com:443"] {
+            assert!(parse_server_address(bad, &[]).is_err(), "{}", bad);
+        }
+        assert!(parse_server_address("203.0.113.1:443", &[8443]).is_err());
+    }
+
+    #[test]
+    fn test_parse_zoned_link_local() {
+        let (host, ports) = parse_server_address("[fe80::1%3]:443,8443", &[]).unwrap();
+        assert_eq!(host.to_string(), "fe80::1%3");
+        assert_eq!(ports, vec![443, 8443]);
+        let (host, _) = parse_server_address("fe80::1%3", &[443]).unwrap();
+        assert_eq!(host.scope_id, 3);
+
+        let endpoint = ServerEndpoint::new(host, vec![443], PortStrategy::default());
+        assert_eq!(endpoint.primary().to_string(), "[fe80::1%3]:443");
+        assert!(parse_server_address("[fe80::1%no-such-interface0]:443", &[]).is_err());
+    }
+
+    /// A port nothing listens on
+    async fn closed_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn test_failed_ports_deprioritized() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap().port();
+        let closed = [closed_port().await, closed_port().await];
+        let host = "127.0.0.1".parse().unwrap();
+        let endpoint = ServerEndpoint::new(host, vec![closed[0], live, closed[1]], PortStrategy::RoundRobin);
+
+        // 轮询从每个端口各开始一次，都应在三次尝试内连上
+        for _ in 0..3 {
+            let stream = endpoint.dial(&DialOptions::default()).await.unwrap();
+            assert_eq!(stream.peer_addr().unwrap().port(), live);
+        }
+
+        // 失败过的端口排到后面，之后的连接第一次就成功
+        for _ in 0..3 {
+            assert_eq!(endpoint.candidates()[0].port(), live);
+            endpoint.dial(&DialOptions::default()).await.unwrap();
+        }
+
+        let stats = endpoint.stats();
+        let live_stats = stats.iter().find(|s| s.port == live).unwrap();
+        assert_eq!((live_stats.successes, live_stats.failures), (6, 0));
+        let failures: u64 = stats.iter().filter(|s| s.port != live).map(|s| s.failures).sum();
+        assert_eq!(failures, 2, "{:?}", stats);
+    }
+
+    #[tokio::test]
+    async fn test_attempts_are_bounded() {
+        let ports = vec![closed_port().await, closed_port().await, closed_port().await];
+        let host = "127.0.0.1".parse().unwrap();
+        let endpoint = ServerEndpoint::new(host, ports, PortStrategy::Random).with_max_attempts(2);
+
+        let err = endpoint.dial(&DialOptions::default()).await.unwrap_err();
+        assert_eq!(err.io_kind(), std::io::ErrorKind::ConnectionRefused);
+        let failures: u64 = endpoint.stats().iter().map(|s| s.failures).sum();
+        assert_eq!(failures, 2);
+    }
+}
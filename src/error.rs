@@ -1,3 +1,8 @@
+use crate::connection_registry::ConnectionPhase;
+use crate::diagnostics::ConnectDiagnostics;
+use crate::routing::MatcherError;
+use std::io;
+use std::net::IpAddr;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -17,11 +22,110 @@ pub enum ProxyError {
     #[error("Invalid address type: {0}")]
     InvalidAddressType(u8),
 
+    #[error("Invalid domain name: {0}")]
+    InvalidDomain(String),
+
     #[error("Connection failed: {0}")]
     ConnectionFailed(String),
 
     #[error("DNS resolution failed: {0}")]
     DnsResolution(String),
+
+    #[error("Write stalled: {0}")]
+    WriteStalled(String),
+
+    #[error("Forwarding loop: {0}")]
+    LoopDetected(String),
+
+    #[error("DNS rebinding blocked: {domain} resolved to internal address {addr}")]
+    RebindingBlocked { domain: String, addr: IpAddr },
+
+    #[error("Connection pool exhausted: {0}")]
+    PoolExhausted(String),
+
+    #[error("Rule set {tag}: {reason}")]
+    RuleSet { tag: String, reason: String },
+
+    #[error("Rule set {tag}: download from {url} rejected: {reason}")]
+    DownloadRejected { tag: String, url: String, reason: String },
+
+    #[error("{} rule sets failed: {}", .0.len(), join_errors(.0))]
+    RuleSetsFailed(Vec<ProxyError>),
+
+    #[error("Integrity check failed: {0}")]
+    Integrity(String),
+
+    #[error("Task group {group} is full ({limit} tasks)")]
+    TaskLimit { group: &'static str, limit: usize },
+
+    #[error("Task group {0} is shut down")]
+    TaskGroupClosed(&'static str),
+
+    #[error("Handshake timed out in {0} phase")]
+    HandshakeTimeout(ConnectionPhase),
+
+    #[error("Blocked by routing: {0}")]
+    Blocked(String),
+
+    #[error("Connection rate limit exceeded: {0}")]
+    RateLimited(String),
+
+    #[error("{0} is not initialized")]
+    NotInitialized(&'static str),
+
+    #[error("{0} is already initialized")]
+    AlreadyInitialized(&'static str),
+
+    #[error("Outbound {name} is disabled: {reason}")]
+    OutboundDisabled { name: String, reason: String },
+
+    #[error("{cause} ({diagnostics})")]
+    ConnectFailed {
+        cause: String,
+        diagnostics: Box<ConnectDiagnostics>,
+    },
+}
+
+impl ProxyError {
+    /// SOCKS5 REP code reported to the client for this error
+    pub fn socks5_reply_code(&self) -> u8 {
+        match self {
+            ProxyError::LoopDetected(_) | ProxyError::RebindingBlocked { .. } | ProxyError::Blocked(_) => 0x02,
+            ProxyError::ConnectFailed { diagnostics, .. } => match diagnostics.last_error() {
+                Some(io::ErrorKind::ConnectionRefused) => 0x05,
+                Some(io::ErrorKind::NetworkUnreachable) => 0x03,
+                _ => 0x04,
+            },
+            ProxyError::UnsupportedCommand(_) => 0x07,
+            ProxyError::InvalidAddressType(_) | ProxyError::InvalidDomain(_) => 0x08,
+            _ => 0x01,
+        }
+    }
+}
+
+impl ProxyError {
+    /// Closest I/O error kind, used to classify connect attempts
+    pub fn io_kind(&self) -> io::ErrorKind {
+        match self {
+            ProxyError::Io(e) => e.kind(),
+            ProxyError::HandshakeTimeout(_) => io::ErrorKind::TimedOut,
+            ProxyError::ConnectFailed { diagnostics, .. } => {
+                diagnostics.last_error().unwrap_or(io::ErrorKind::Other)
+            }
+            _ => io::ErrorKind::Other,
+        }
+    }
+}
+
+// 匹配器错误保持原有的 Protocol 错误文本
+impl From<MatcherError> for ProxyError {
+    fn from(e: MatcherError) -> Self {
+        ProxyError::Protocol(e.to_string())
+    }
+}
+
+fn join_errors(errors: &[ProxyError]) -> String {
+    errors.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ")
 }
 
 pub type Result<T> = std::result::Result<T, ProxyError>;
@@ -0,0 +1,389 @@
+// Fake-IP：DNS 入站给每个域名分配保留网段里的地址；连接到达假地址时查回域名，再按域名路由和解析
+use crate::config::FakeIpConfig;
+use crate::error::{ProxyError, Result};
+use crate::tasks::{get_global_task_tracker, TaskGroup};
+use ipnet::Ipv4Net;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+/// TTL of fake answers, so clients ask again instead of outliving the mapping
+pub const FAKE_ANSWER_TTL: u32 = 1;
+/// How often expired mappings are purged and the table is saved
+pub const FAKE_IP_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Mapping {
+    domain: String,
+    expires: Instant,
+}
+
+struct Table {
+    by_ip: HashMap<Ipv4Addr, Mapping>,
+    by_domain: HashMap<String, Ipv4Addr>,
+    /// 下一个分配的主机号（网段内偏移）
+    next: u32,
+}
+
+/// A mapping as saved to `store_path`
+#[derive(Serialize, Deserialize)]
+struct StoredMapping {
+    ip: Ipv4Addr,
+    domain: String,
+    /// Unix time the mapping expires at
+    expires_at: u64,
+}
+
+/// Fake address ↔ domain table shared by the `dns` inbound and the inbounds
+/// whose connections arrive for fake addresses
+///
+/// A mapping lives for `ttl_secs` after its last query or connection.
+/// Addresses are handed out in turn across the pool; once it wraps around,
+/// the mapping previously at the next address is replaced.
+pub struct FakeIpPool {
+    range: Ipv4Net,
+    ttl: Duration,
+    exclude: Vec<String>,
+    store_path: Option<PathBuf>,
+    table: Mutex<Table>,
+}
+
+impl FakeIpPool {
+    pub fn new(config: &FakeIpConfig) -> Self {
+        Self {
+            range: config.inet4_range.trunc(),
+            ttl: Duration::from_secs(config.ttl_secs),
+            exclude: config.exclude_domains.iter().map(|domain| normalize(domain)).collect(),
+            store_path: config.store_path.as_ref().map(PathBuf::from),
+            table: Mutex::new(Table { by_ip: HashMap::new(), by_domain: HashMap::new(), next: 1 }),
+        }
+    }
+
+    pub fn range(&self) -> Ipv4Net {
+        self.range
+    }
+
+    /// Whether `ip` belongs to the pool
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        matches!(ip.to_canonical(), IpAddr::V4(ip) if self.range.contains(&ip))
+    }
+
+    /// Whether `domain` is answered with its real addresses
+    pub fn is_excluded(&self, domain: &str) -> bool {
+        let domain = normalize(domain);
+        self.exclude.iter().any(|suffix| {
+            domain == *suffix || domain.strip_suffix(suffix.as_str()).is_some_and(|rest| rest.ends_with('.'))
+        })
+    }
+
+    /// Addresses that can be handed out; the network and broadcast addresses never are
+    fn hosts(&self) -> u32 {
+        let size = 1u64 << (32 - self.range.prefix_len());
+        size.saturating_sub(2).max(1) as u32
+    }
+
+    /// Fake address of `domain`, allocating one on its first query
+    pub fn allocate(&self, domain: &str) -> Ipv4Addr {
+        let domain = normalize(domain);
+        let now = Instant::now();
+        let mut table = self.table.lock().unwrap();
+        if let Some(ip) = table.by_domain.get(&domain).copied() {
+            if let Some(mapping) = table.by_ip.get_mut(&ip) {
+                mapping.expires = now + self.ttl;
+                return ip;
+            }
+        }
+
+        let offset = table.next;
+        table.next = offset % self.hosts() + 1;
+        let ip = Ipv4Addr::from(u32::from(self.range.network()) + offset);
+        let mapping = Mapping { domain: domain.clone(), expires: now + self.ttl };
+        if let Some(old) = table.by_ip.insert(ip, mapping) {
+            if old.expires > now {
+                debug!("Fake IP pool wrapped around, {} now stands for {} instead of {}", ip, domain, old.domain);
+            }
+            table.by_domain.remove(&old.domain);
+        }
+        table.by_domain.insert(domain, ip);
+        ip
+    }
+
+    /// Domain a fake address stands for, refreshing its mapping
+    ///
+    /// Ok(None) for addresses outside the pool. An address of the pool
+    /// without a live mapping is an error: it expired, or was handed out
+    /// before a restart and not saved, so the destination is unknown.
+    pub fn lookup(&self, ip: IpAddr) -> Result<Option<String>> {
+        let IpAddr::V4(ip) = ip.to_canonical() else {
+            return Ok(None);
+        };
+        if !self.range.contains(&ip) {
+            return Ok(None);
+        }
+        let now = Instant::now();
+        let mut table = self.table.lock().unwrap();
+        match table.by_ip.get_mut(&ip) {
+            Some(mapping) if mapping.expires > now => {
+                mapping.expires = now + self.ttl;
+                Ok(Some(mapping.domain.clone()))
+            }
+            _ => Err(ProxyError::Protocol(format!("Fake IP {} does not stand for any domain (expired or unknown)", ip))),
+        }
+    }
+
+    /// Mappings currently held, expired ones not yet purged included
+    pub fn len(&self) -> usize {
+        self.table.lock().unwrap().by_ip.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drop expired mappings, returning how many were dropped
+    pub fn purge_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut table = self.table.lock().unwrap();
+        let expired: Vec<Ipv4Addr> =
+            table.by_ip.iter().filter(|(_, mapping)| mapping.expires <= now).map(|(ip, _)| *ip).collect();
+        for ip in &expired {
+            if let Some(mapping) = table.by_ip.remove(ip) {
+                table.by_domain.remove(&mapping.domain);
+            }
+        }
+        expired.len()
+    }
+
+    /// Write the live mappings to `store_path`, if set; returns how many were written
+    pub fn save(&self) -> Result<usize> {
+        let Some(path) = &self.store_path else {
+            return Ok(0);
+        };
+        let now = Instant::now();
+        let unix_now = unix_now();
+        let stored: Vec<StoredMapping> = self
+            .table
+            .lock()
+            .unwrap()
+            .by_ip
+            .iter()
+            .filter(|(_, mapping)| mapping.expires > now)
+            .map(|(ip, mapping)| StoredMapping {
+                ip: *ip,
+                domain: mapping.domain.clone(),
+                expires_at: unix_now + (mapping.expires - now).as_secs(),
+            })
+            .collect();
+        let content = serde_json::to_vec(&stored)
+            .map_err(|e| ProxyError::Protocol(format!("Failed to encode fake IP mappings: {}", e)))?;
+        // 先写临时文件再改名，写到一半退出也不会留下残缺的文件
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, content)?;
+        std::fs::rename(&temp, path)?;
+        Ok(stored.len())
+    }
+
+    /// Restore the mappings saved to `store_path`, if set; returns how many were restored
+    ///
+    /// Expired mappings and addresses outside the current range are skipped.
+    /// A missing file restores nothing.
+    pub fn load(&self) -> Result<usize> {
+        let Some(path) = &self.store_path else {
+            return Ok(0);
+        };
+        let content = match std::fs::read(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let stored: Vec<StoredMapping> = serde_json::from_slice(&content)
+            .map_err(|e| ProxyError::Protocol(format!("Invalid fake IP mappings in {}: {}", path.display(), e)))?;
+
+        let now = Instant::now();
+        let unix_now = unix_now();
+        let network = u32::from(self.range.network());
+        let hosts = self.hosts();
+        let mut table = self.table.lock().unwrap();
+        let mut last = 0;
+        let mut restored = 0;
+        for mapping in stored {
+            let offset = u32::from(mapping.ip).wrapping_sub(network);
+            let usable = self.range.contains(&mapping.ip) && (1..=hosts).contains(&offset);
+            let domain = normalize(&mapping.domain);
+            if !usable
+                || mapping.expires_at <= unix_now
+                || table.by_ip.contains_key(&mapping.ip)
+                || table.by_domain.contains_key(&domain)
+            {
+                continue;
+            }
+            let expires = now + Duration::from_secs(mapping.expires_at - unix_now);
+            table.by_ip.insert(mapping.ip, Mapping { domain: domain.clone(), expires });
+            table.by_domain.insert(domain, mapping.ip);
+            last = last.max(offset);
+            restored += 1;
+        }
+        // 从恢复的最后一个地址之后继续分配，不立即覆盖刚恢复的映射
+        table.next = last % hosts + 1;
+        Ok(restored)
+    }
+
+    /// Purge expired mappings and save the table every `period`
+    pub async fn run_cleanup(&self, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let purged = self.purge_expired();
+            if purged > 0 {
+                debug!("Purged {} expired fake IP mappings", purged);
+            }
+            if let Err(e) = self.save() {
+                warn!("Failed to save fake IP mappings: {}", e);
+            }
+        }
+    }
+}
+
+/// 域名不区分大小写，末尾的点可有可无
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+static GLOBAL_FAKE_IP_POOL: OnceLock<FakeIpPool> = OnceLock::new();
+
+/// Initialize the global pool when `dns.fake_ip` is enabled
+///
+/// Mappings saved to `store_path` are restored; a file that cannot be
+/// read is logged and the pool starts empty.
+pub fn init_global_fake_ip_pool(config: &FakeIpConfig) -> Result<()> {
+    if !config.enabled {
+        return Ok(());
+    }
+    let pool = FakeIpPool::new(config);
+    match pool.load() {
+        Ok(0) => {}
+        Ok(restored) => info!("Restored {} fake IP mappings", restored),
+        Err(e) => warn!("Fake IP mappings not restored: {}", e),
+    }
+    GLOBAL_FAKE_IP_POOL.set(pool).map_err(|_| ProxyError::AlreadyInitialized("Global fake IP pool"))
+}
+
+/// The global pool, None unless `dns.fake_ip` is enabled
+pub fn try_get_global_fake_ip_pool() -> Option<&'static FakeIpPool> {
+    GLOBAL_FAKE_IP_POOL.get()
+}
+
+/// Start the periodic purge (and save, with `store_path`) of the global pool
+pub fn start_fake_ip_cleanup() {
+    let Some(pool) = try_get_global_fake_ip_pool() else {
+        return;
+    };
+    if let Err(e) = get_global_task_tracker().spawn(TaskGroup::DnsCacheCleanup, pool.run_cleanup(FAKE_IP_CLEANUP_INTERVAL)) {
+        warn!("Fake IP cleanup not started: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(range: &str, ttl_secs: u64) -> FakeIpPool {
+        FakeIpPool::new(&FakeIpConfig {
+            enabled: true,
+            inet4_range: range.parse().unwrap(),
+            ttl_secs,
+            exclude_domains: vec!["lan".to_string()],
+            store_path: None,
+        })
+    }
+
+    #[test]
+    fn test_allocation_is_stable_per_domain() {
+        let pool = pool("198.18.0.0/15", 600);
+        let first = pool.allocate("example.com");
+        assert_eq!(first, Ipv4Addr::new(198, 18, 0, 1));
+        assert_eq!(pool.allocate("Example.COM."), first);
+        let second = pool.allocate("example.org");
+        assert_ne!(second, first);
+        assert_eq!(pool.lookup(IpAddr::V4(second)).unwrap().as_deref(), Some("example.org"));
+        assert!(pool.contains("::ffff:198.19.255.254".parse().unwrap()));
+        assert!(!pool.contains("198.20.0.1".parse().unwrap()));
+        assert_eq!(pool.lookup("1.1.1.1".parse().unwrap()).unwrap(), None);
+        assert!(pool.lookup("198.18.9.9".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_wrap_around_replaces_oldest_mapping() {
+        // /30 只有两个可分配地址
+        let pool = pool("10.0.0.0/30", 600);
+        let a = pool.allocate("a.test");
+        let b = pool.allocate("b.test");
+        let c = pool.allocate("c.test");
+        assert_eq!((a, b), (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(c, a);
+        assert_eq!(pool.lookup(IpAddr::V4(a)).unwrap().as_deref(), Some("c.test"));
+        assert_eq!(pool.len(), 2);
+        // 被覆盖的域名再查询时重新分配
+        assert_eq!(pool.allocate("a.test"), b);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mappings_expire_unless_used() {
+        let pool = pool("198.18.0.0/15", 10);
+        let idle = IpAddr::V4(pool.allocate("idle.test"));
+        let busy = IpAddr::V4(pool.allocate("busy.test"));
+        tokio::time::advance(Duration::from_secs(6)).await;
+        assert!(pool.lookup(busy).unwrap().is_some());
+        tokio::time::advance(Duration::from_secs(6)).await;
+        assert!(pool.lookup(idle).is_err());
+        assert_eq!(pool.lookup(busy).unwrap().as_deref(), Some("busy.test"));
+        assert_eq!(pool.purge_expired(), 1);
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn test_excluded_domains() {
+        let pool = pool("198.18.0.0/15", 600);
+        assert!(pool.is_excluded("lan"));
+        assert!(pool.is_excluded("nas.LAN."));
+        assert!(!pool.is_excluded("plan"));
+        assert!(!pool.is_excluded("example.com"));
+    }
+
+    #[test]
+    fn test_save_and_restore() {
+        let path = std::env::temp_dir().join(format!("anybls-fakeip-{}.json", std::process::id()));
+        let config = FakeIpConfig {
+            enabled: true,
+            store_path: Some(path.to_string_lossy().into_owned()),
+            ..FakeIpConfig::default()
+        };
+        let saved = FakeIpPool::new(&config);
+        let a = saved.allocate("a.test");
+        let b = saved.allocate("b.test");
+        assert_eq!(saved.save().unwrap(), 2);
+
+        let restored = FakeIpPool::new(&config);
+        assert_eq!(restored.load().unwrap(), 2);
+        assert_eq!(restored.lookup(IpAddr::V4(b)).unwrap().as_deref(), Some("b.test"));
+        assert_eq!(restored.allocate("a.test"), a);
+        // 新域名接在恢复的地址之后分配
+        assert_eq!(restored.allocate("c.test"), Ipv4Addr::new(198, 18, 0, 3));
+
+        // 换了网段，旧映射不再恢复
+        let moved = FakeIpPool::new(&FakeIpConfig { inet4_range: "100.64.0.0/10".parse().unwrap(), ..config.clone() });
+        assert_eq!(moved.load().unwrap(), 0);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(moved.load().unwrap(), 0);
+    }
+}
@@ -0,0 +1,416 @@
+// 健康检查端点：/healthz 检查入站、DNS 与关键出站，/readyz 另外要求规则集合已加载；
+// 每项检查都有截止时间，卡住的解析器或出站不会拖住探针
+use crate::config::{Config, HealthConfig};
+use crate::dns::{get_global_dns_resolver, DnsResolver};
+use crate::error::{ProxyError, Result};
+use crate::outbound::{get_global_outbound_manager, OutboundSource};
+use crate::pac::{read_request, write_response, RouterSource};
+use crate::routing::get_global_router;
+use crate::tasks::{get_global_task_tracker, TaskGroup};
+use futures::future::{join_all, OptionFuture};
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Outcome of one sub-check
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckResult {
+    /// What was checked: `inbound:<addr>`, `dns:<domain>`, `outbound:<name>` or `rule_sets`
+    pub component: String,
+    pub ok: bool,
+    pub duration_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Body of a `/healthz` or `/readyz` response
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    pub ok: bool,
+    /// Components whose check failed
+    pub failed: Vec<String>,
+    pub checks: Vec<CheckResult>,
+}
+
+impl HealthReport {
+    fn new(checks: Vec<CheckResult>) -> Self {
+        let failed: Vec<String> = checks.iter().filter(|check| !check.ok).map(|check| check.component.clone()).collect();
+        Self { ok: failed.is_empty(), failed, checks }
+    }
+}
+
+/// Runs the liveness and readiness checks
+pub struct HealthService {
+    config: HealthConfig,
+    /// Addresses the inbounds listen on
+    inbounds: Vec<SocketAddr>,
+    resolver: &'static DnsResolver,
+    outbounds: OutboundSource,
+    router: RouterSource,
+}
+
+impl HealthService {
+    /// Service checking the global resolver, outbounds and router
+    pub fn new(config: &HealthConfig, inbounds: Vec<SocketAddr>) -> Self {
+        Self::with_sources(
+            config,
+            inbounds,
+            get_global_dns_resolver(),
+            Box::new(get_global_outbound_manager),
+            Box::new(get_global_router),
+        )
+    }
+
+    pub fn with_sources(
+        config: &HealthConfig,
+        inbounds: Vec<SocketAddr>,
+        resolver: &'static DnsResolver,
+        outbounds: OutboundSource,
+        router: RouterSource,
+    ) -> Self {
+        Self { config: config.clone(), inbounds, resolver, outbounds, router }
+    }
+
+    /// Liveness: inbounds accept, DNS resolves and the critical outbounds are reachable
+    pub async fn healthz(&self) -> HealthReport {
+        HealthReport::new(self.liveness_checks().await)
+    }
+
+    /// Readiness: liveness plus every rule set referenced by the routing is loaded
+    pub async fn readyz(&self) -> HealthReport {
+        let mut checks = self.liveness_checks().await;
+        checks.push(self.run("rule_sets".to_string(), async { self.check_rule_sets() }).await);
+        HealthReport::new(checks)
+    }
+
+    /// 各项检查并发执行，总耗时不超过一个截止时间
+    async fn liveness_checks(&self) -> Vec<CheckResult> {
+        let inbounds = join_all(self.inbounds.iter().map(|addr| self.run(format!("inbound:{}", addr), check_inbound(*addr))));
+        let dns: OptionFuture<_> = self
+            .config
+            .dns_check
+            .then(|| self.run(format!("dns:{}", self.config.dns_probe_domain), self.check_dns()))
+            .into();
+        let outbounds = join_all(
+            self.config.critical_outbounds.iter().map(|name| self.run(format!("outbound:{}", name), self.check_outbound(name))),
+        );
+        let (mut checks, dns, outbounds) = tokio::join!(inbounds, dns, outbounds);
+        checks.extend(dns);
+        checks.extend(outbounds);
+        checks
+    }
+
+    /// Run one check, failing it when it does not finish within `timeout_ms`
+    async fn run(&self, component: String, check: impl Future<Output = Result<()>>) -> CheckResult {
+        let deadline = Duration::from_millis(self.config.timeout_ms);
+        let started = Instant::now();
+        let result = tokio::time::timeout(deadline, check)
+            .await
+            .unwrap_or_else(|_| Err(ProxyError::Protocol(format!("no result within {:?}", deadline))));
+        CheckResult {
+            component,
+            ok: result.is_ok(),
+            duration_ms: started.elapsed().as_secs_f64() * 1000.0,
+            error: result.err().map(|e| e.to_string()),
+        }
+    }
+
+    async fn check_dns(&self) -> Result<()> {
+        let (addrs, _) = self.resolver.resolve_all(&self.config.dns_probe_domain).await?;
+        if addrs.is_empty() {
+            return Err(ProxyError::DnsResolution(format!("no addresses for {}", self.config.dns_probe_domain)));
+        }
+        Ok(())
+    }
+
+    /// Connect to the outbound's server, or through it to `outbound_probe_target`
+    async fn check_outbound(&self, name: &str) -> Result<()> {
+        let outbounds = (self.outbounds)();
+        if let Some(disabled) = outbounds.disabled(name) {
+            return Err(ProxyError::OutboundDisabled { name: name.to_string(), reason: disabled.reason().to_string() });
+        }
+        let outbound = outbounds
+            .get(name)
+            .ok_or_else(|| ProxyError::Protocol(format!("Outbound not found: {}", name)))?;
+        if let Some(server) = outbound.server_addr() {
+            TcpStream::connect(server)
+                .await
+                .map_err(|e| ProxyError::ConnectionFailed(format!("server {}: {}", server, e)))?;
+        } else if let Some(target) = self.config.outbound_probe_target {
+            outbound.connect_outbound(&target.into()).await?;
+        }
+        Ok(())
+    }
+
+    fn check_rule_sets(&self) -> Result<()> {
+        let router = (self.router)();
+        let mut routers = vec![router.clone()];
+        routers.extend(router.profile_names().into_iter().filter_map(|name| router.profile(name)));
+        let mut missing = BTreeSet::new();
+        for routing in &routers {
+            let manager = routing.rule_manager();
+            for id in routing.rules().iter().flat_map(|rule| &rule.rule_sets) {
+                if manager.get_domain_set(id).is_none() && manager.get_ip_set(id).is_none() {
+                    missing.insert(id.as_str());
+                }
+            }
+        }
+        if missing.is_empty() {
+            return Ok(());
+        }
+        Err(ProxyError::Protocol(format!(
+            "rule sets not loaded: {}",
+            missing.into_iter().collect::<Vec<_>>().join(", ")
+        )))
+    }
+
+    async fn handle(&self, mut stream: TcpStream) -> Result<()> {
+        let Some((method, path)) = read_request(&mut stream).await? else {
+            return Ok(());
+        };
+        let report = match (method.as_str(), path.as_str()) {
+            ("GET" | "HEAD", "/healthz") => self.healthz().await,
+            ("GET" | "HEAD", "/readyz") => self.readyz().await,
+            (_, "/healthz" | "/readyz") => {
+                return write_response(&mut stream, &method, "405 Method Not Allowed", "text/plain", "method not allowed\n")
+                    .await
+            }
+            _ => return write_response(&mut stream, &method, "404 Not Found", "text/plain", "not found\n").await,
+        };
+        let status = if report.ok {
+            "200 OK"
+        } else {
+            warn!("Health check {} failed: {}", path, report.failed.join(", "));
+            "503 Service Unavailable"
+        };
+        let body = serde_json::to_string(&report).expect("reports always serialize") + "\n";
+        write_response(&mut stream, &method, status, "application/json", &body).await
+    }
+}
+
+/// An inbound accepts when a TCP connection to it succeeds
+async fn check_inbound(addr: SocketAddr) -> Result<()> {
+    TcpStream::connect(reachable(addr)).await?;
+    Ok(())
+}
+
+/// Loopback in place of an unspecified listen address
+fn reachable(mut addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => addr.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        IpAddr::V6(ip) if ip.is_unspecified() => addr.set_ip(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+        _ => {}
+    }
+    addr
+}
+
+/// Accept loop of the health listener
+pub async fn serve_health(listener: TcpListener, service: Arc<HealthService>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Health listener accept failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let service = service.clone();
+        tokio::spawn(async move {
+            if let Err(e) = service.handle(stream).await {
+                debug!("Health request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Bind `health.listen` and serve the probes when `health.enabled`
+pub async fn start_health_server(config: &Config, inbounds: Vec<SocketAddr>) -> Result<()> {
+    if !config.health.enabled {
+        return Ok(());
+    }
+    let listener = TcpListener::bind(config.health.listen).await?;
+    info!("Serving health checks at http://{}/healthz and /readyz", listener.local_addr()?);
+    let service = Arc::new(HealthService::new(&config.health, inbounds));
+    get_global_task_tracker().spawn(TaskGroup::Listeners, serve_health(listener, service))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DnsConfig, OutboundConfig, OutboundType};
+    use crate::outbound::OutboundManager;
+    use crate::routing::rule_sets::{DomainRuleSet, RuleSetManager};
+    use crate::routing::{HighPerformanceRouter, RouteRule};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UdpSocket;
+    use trust_dns_resolver::proto::op::{Message, MessageType};
+    use trust_dns_resolver::proto::rr::{RData, Record, RecordType};
+
+    /// UDP DNS server answering every A query with 192.0.2.1, or never answering when `silent`
+    async fn mock_dns(silent: bool) -> &'static DnsResolver {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((n, peer)) = socket.recv_from(&mut buf).await {
+                let request = Message::from_vec(&buf[..n]).unwrap();
+                if silent {
+                    continue;
+                }
+                let mut response = Message::new();
+                response.set_id(request.id());
+                response.set_message_type(MessageType::Response);
+                response.add_queries(request.queries().to_vec());
+                let query = &request.queries()[0];
+                if query.query_type() == RecordType::A {
+                    response.add_answer(Record::from_rdata(query.name().clone(), 60, RData::A(Ipv4Addr::new(192, 0, 2, 1).into())));
+                }
+                let _ = socket.send_to(&response.to_vec().unwrap(), peer).await;
+            }
+        });
+        let config = DnsConfig { servers: vec![addr.to_string()], timeout_secs: 5, ..DnsConfig::default() };
+        Box::leak(Box::new(DnsResolver::from_config(&config).unwrap()))
+    }
+
+    /// A listener that accepts in the background, standing in for an inbound or proxy server
+    async fn listening() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+        addr
+    }
+
+    async fn closed_port() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap()
+    }
+
+    fn outbounds(proxy_server: SocketAddr) -> OutboundSource {
+        let configs = [OutboundConfig {
+            kind: OutboundType::Socks5 { address: proxy_server.to_string() },
+            ..OutboundConfig::direct("proxy")
+        }];
+        let manager = Arc::new(OutboundManager::from_configs(&configs).unwrap());
+        Box::new(move || manager.clone())
+    }
+
+    fn health_config() -> HealthConfig {
+        HealthConfig {
+            enabled: true,
+            critical_outbounds: vec!["proxy".to_string(), "direct".to_string()],
+            timeout_ms: 500,
+            ..HealthConfig::default()
+        }
+    }
+
+    fn router(rule_set: &str, loaded: bool) -> RouterSource {
+        let mut router = HighPerformanceRouter::new("direct".to_string());
+        if loaded {
+            let mut manager = RuleSetManager::new();
+            manager.add_domain_set(DomainRuleSet::builder(rule_set).suffix("example.com").build());
+            router.set_rule_manager(manager);
+        }
+        router.add_rule(RouteRule::builder("proxy").rule_set(rule_set).build());
+        let router = Arc::new(router);
+        Box::new(move || router.clone())
+    }
+
+    async fn http_get(addr: SocketAddr, path: &str) -> (String, serde_json::Value) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(format!("GET {} HTTP/1.1\r\nHost: health\r\n\r\n", path).as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), serde_json::from_str(body).unwrap())
+    }
+
+    async fn serve(service: HealthService) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_health(listener, Arc::new(service)));
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_healthy_setup_passes() {
+        let inbound = listening().await;
+        let service = HealthService::with_sources(
+            &health_config(),
+            vec![inbound],
+            mock_dns(false).await,
+            outbounds(listening().await),
+            router("ads", true),
+        );
+        let addr = serve(service).await;
+
+        for path in ["/healthz", "/readyz"] {
+            let (status, body) = http_get(addr, path).await;
+            assert_eq!(status, "HTTP/1.1 200 OK", "{}", body);
+            assert_eq!(body["ok"], true);
+            let checks = body["checks"].as_array().unwrap();
+            let components: Vec<&str> = checks.iter().map(|check| check["component"].as_str().unwrap()).collect();
+            let mut expected = vec![format!("inbound:{}", inbound), "dns:example.com".to_string()];
+            expected.extend(["outbound:proxy".to_string(), "outbound:direct".to_string()]);
+            if path == "/readyz" {
+                expected.push("rule_sets".to_string());
+            }
+            assert_eq!(components, expected);
+            assert!(checks.iter().all(|check| check["ok"] == true && check["duration_ms"].is_f64()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_critical_outbound_fails_healthz() {
+        let service = HealthService::with_sources(
+            &health_config(),
+            vec![listening().await],
+            mock_dns(false).await,
+            outbounds(closed_port().await),
+            router("ads", true),
+        );
+        let addr = serve(service).await;
+
+        let (status, body) = http_get(addr, "/healthz").await;
+        assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+        assert_eq!(body["ok"], false);
+        assert_eq!(body["failed"], serde_json::json!(["outbound:proxy"]));
+        let failed = body["checks"].as_array().unwrap().iter().find(|check| check["ok"] == false).unwrap();
+        assert!(failed["error"].as_str().unwrap().contains("server 127.0.0.1"), "{}", failed);
+    }
+
+    #[tokio::test]
+    async fn test_deadlines_and_readiness() {
+        // 不应答的 DNS 服务器在截止时间内判为失败，不会拖住探针
+        let config = HealthConfig { critical_outbounds: Vec::new(), timeout_ms: 200, ..health_config() };
+        let service = HealthService::with_sources(
+            &config,
+            vec![listening().await],
+            mock_dns(true).await,
+            outbounds(listening().await),
+            router("ads", false),
+        );
+        let started = Instant::now();
+        let report = service.readyz().await;
+        assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+        assert_eq!(report.failed, ["dns:example.com", "rule_sets"]);
+        let rule_sets = report.checks.last().unwrap();
+        assert_eq!(rule_sets.error.as_deref(), Some("Protocol error: rule sets not loaded: ads"));
+
+        let config = HealthConfig { dns_check: false, ..config };
+        let service = HealthService::with_sources(
+            &config,
+            vec![listening().await],
+            mock_dns(true).await,
+            outbounds(listening().await),
+            router("ads", false),
+        );
+        assert!(service.healthz().await.ok);
+        assert!(!service.readyz().await.ok);
+    }
+}
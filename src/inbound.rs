@@ -1,10 +1,442 @@
-use crate::error::Result;
+use crate::accept::{shard_accept_counter, AcceptLoop, BoundListener};
+use crate::access_log::{get_global_access_log, AccessLogger};
+use crate::config::{get_global_config, Config, InboundConfig, LoopProtectionConfig, SocksAuthConfig, SocksAuthMode};
+use crate::connection_pool::{try_get_global_connection_pool, ConnectionPool};
+use crate::connection_registry::{get_global_connection_registry, ConnectionRegistry};
+use crate::error::{ProxyError, Result};
+use crate::fake_ip::{try_get_global_fake_ip_pool, FakeIpPool};
+use crate::outbound::{get_global_outbound_manager, OutboundManager};
+use crate::protocol::MethodPolicy;
 use crate::protocols::Protocol;
-use std::net::SocketAddr;
+use crate::routing::{get_global_router, HighPerformanceRouter, IpMatcher, MatcherResult};
+use crate::tasks::{get_global_task_tracker, TaskGroup, TaskTracker};
+use crate::traffic_mark::LingerPolicy;
+use futures::future::BoxFuture;
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Minimum interval between forwarding loop warnings
+const LOOP_WARN_INTERVAL: Duration = Duration::from_secs(10);
+/// How long local interface addresses are cached for wildcard binds
+const LOCAL_ADDRS_TTL: Duration = Duration::from_secs(30);
 
 #[async_trait::async_trait]
 pub trait Inbound: Send + Sync {
-    async fn start(&self) -> Result<()>;
+    async fn start(&self, ctx: InboundContext) -> Result<RunningInbound>;
+}
+
+/// Answers a client turned away at `server.max_connections` when
+/// `server.reject_when_full` is set, in the inbound's protocol
+pub type Refusal = fn(TcpStream) -> BoxFuture<'static, ()>;
+
+/// Handles an inbound serves its connections with
+///
+/// Passed in by whoever starts the inbound instead of being looked up from
+/// globals, so tests and reloads can run inbounds side by side with their
+/// own config, outbounds and router.
+#[derive(Clone)]
+pub struct InboundContext {
+    pub config: &'static Config,
+    /// Fixed outbounds; None follows the global outbounds across config reloads
+    pub outbounds: Option<Arc<OutboundManager>>,
+    /// Fixed router; None follows the global router across rule set reloads
+    pub router: Option<Arc<HighPerformanceRouter>>,
+    pub listeners: &'static ListenerRegistry,
+    pub connections: &'static ConnectionRegistry,
+    pub access_log: &'static AccessLogger,
+    /// SOCKS5 authentication of the inbound
+    pub auth: Arc<InboundAuth>,
+    /// Routing profile of the inbound; None routes with `[router]`
+    pub profile: Option<String>,
+    /// Tag of the inbound, matched by rules with `inbound`; None for the untagged `[server]` inbound
+    pub tag: Option<String>,
+    /// SO_LINGER of accepted client connections
+    pub linger: LingerPolicy,
+    /// Pool for `connection_pool.reuse_outbound_connections`; None uses the
+    /// global pool once it is initialized
+    pub connection_pool: Option<&'static ConnectionPool>,
+    /// Fake-IP table of `dns.fake_ip`; None uses the global pool, if enabled
+    pub fake_ip: Option<&'static FakeIpPool>,
+    /// Tracker connection tasks are spawned on; its `InboundConns` limit is
+    /// `server.max_connections`
+    pub tasks: &'static TaskTracker,
+    /// How clients over the limit are refused; without one they are closed
+    pub refusal: Option<Refusal>,
+}
+
+impl InboundContext {
+    /// Context with the given config and outbounds and the global router and stats
+    pub fn new(config: &'static Config, outbounds: Arc<OutboundManager>) -> Self {
+        Self::with_outbounds(config, Some(outbounds))
+    }
+
+    fn with_outbounds(config: &'static Config, outbounds: Option<Arc<OutboundManager>>) -> Self {
+        Self {
+            config,
+            outbounds,
+            router: None,
+            listeners: get_global_listener_registry(),
+            connections: get_global_connection_registry(),
+            access_log: get_global_access_log(),
+            auth: Arc::new(InboundAuth::new(&config.server.auth).unwrap_or_else(|e| {
+                error!("Invalid server.auth, no source is exempt from authentication: {}", e);
+                InboundAuth::without_exemptions(&config.server.auth)
+            })),
+            profile: config.server.profile.clone(),
+            tag: None,
+            linger: config.server.linger,
+            connection_pool: None,
+            fake_ip: None,
+            tasks: get_global_task_tracker(),
+            refusal: None,
+        }
+    }
+
+    /// The same context with its own authentication settings
+    pub fn with_auth(mut self, auth: InboundAuth) -> Self {
+        self.auth = Arc::new(auth);
+        self
+    }
+
+    /// The same context routing with the named profile
+    pub fn with_profile(mut self, profile: impl Into<String>) -> Self {
+        self.profile = Some(profile.into());
+        self
+    }
+
+    /// The same context for the inbound tagged `tag`
+    pub fn with_tag(mut self, tag: impl Into<String>) -> Self {
+        self.tag = Some(tag.into());
+        self
+    }
+
+    /// The same context reusing outbound connections through `pool`
+    pub fn with_connection_pool(mut self, pool: &'static ConnectionPool) -> Self {
+        self.connection_pool = Some(pool);
+        self
+    }
+
+    /// The same context answering and resolving fake addresses from `pool`
+    pub fn with_fake_ip(mut self, pool: &'static FakeIpPool) -> Self {
+        self.fake_ip = Some(pool);
+        self
+    }
+
+    /// The same context spawning connection tasks on `tasks`
+    pub fn with_task_tracker(mut self, tasks: &'static TaskTracker) -> Self {
+        self.tasks = tasks;
+        self
+    }
+
+    /// The same context refusing clients over the limit with `refusal`
+    pub fn with_refusal(mut self, refusal: Refusal) -> Self {
+        self.refusal = Some(refusal);
+        self
+    }
+
+    /// Context built from the global config that follows the global outbounds and router
+    pub fn global() -> Self {
+        Self::with_outbounds(get_global_config(), None)
+    }
+
+    /// Outbounds to connect a new connection with
+    pub fn outbounds(&self) -> Arc<OutboundManager> {
+        self.outbounds.clone().unwrap_or_else(get_global_outbound_manager)
+    }
+
+    /// Pool to take and return reusable outbound connections, if any
+    pub fn connection_pool(&self) -> Option<&'static ConnectionPool> {
+        self.connection_pool.or_else(|| try_get_global_connection_pool().ok())
+    }
+
+    /// Fake-IP table, if fake-IP mode is on
+    pub fn fake_ip(&self) -> Option<&'static FakeIpPool> {
+        self.fake_ip.or_else(try_get_global_fake_ip_pool)
+    }
+
+    /// Router to route a new connection with, the inbound's profile if it has one
+    pub fn router(&self) -> Arc<HighPerformanceRouter> {
+        let router = self.router.clone().unwrap_or_else(get_global_router);
+        let Some(name) = &self.profile else {
+            return router;
+        };
+        router.profile(name).unwrap_or_else(|| {
+            // 配置校验保证路由配置存在；热重载前后不一致时退回默认路由
+            warn!("Routing profile {} not found, using the default router", name);
+            router
+        })
+    }
+}
+
+/// Compiled SOCKS5 authentication settings of an inbound
+pub struct InboundAuth {
+    config: SocksAuthConfig,
+    exempt: IpMatcher,
+}
+
+impl InboundAuth {
+    pub fn new(config: &SocksAuthConfig) -> Result<Self> {
+        config.validate()?;
+        Ok(Self {
+            config: config.clone(),
+            exempt: IpMatcher::new(config.no_auth_source_cidrs.clone())?,
+        })
+    }
+
+    /// Settings with no source exempt from authentication
+    pub fn without_exemptions(config: &SocksAuthConfig) -> Self {
+        Self {
+            config: SocksAuthConfig { no_auth_source_cidrs: Vec::new(), ..config.clone() },
+            exempt: IpMatcher::new(Vec::new()).expect("empty CIDR list"),
+        }
+    }
+
+    /// Whether a client from `source` may skip authentication
+    pub fn is_exempt(&self, source: IpAddr) -> bool {
+        self.exempt.matches(source.to_canonical()) == MatcherResult::Match
+    }
+
+    /// Method negotiation policy for a client from `source`
+    ///
+    /// Decided before the negotiation so exempt clients are offered "no
+    /// authentication" and the others only username/password. `hint` asks
+    /// for usernames as routing hints where credentials are not required.
+    pub fn policy(&self, source: IpAddr, hint: bool) -> MethodPolicy<'_> {
+        match self.config.mode {
+            SocksAuthMode::Password if !self.is_exempt(source) => MethodPolicy::Required(&self.config.users),
+            _ if hint => MethodPolicy::UsernameHint,
+            _ => MethodPolicy::NoAuth,
+        }
+    }
+}
+
+/// A started inbound
+///
+/// `shutdown` closes the listener; connections already accepted keep running
+/// and `join` waits until the last of them has finished.
+pub struct RunningInbound {
+    local_addr: SocketAddr,
+    shutdown: CancellationToken,
+    closed: CancellationToken,
+    handle: JoinHandle<Option<Result<()>>>,
+    shard_accepts: Vec<Arc<AtomicU64>>,
+}
+
+impl RunningInbound {
+    /// The address actually bound (port 0 resolved)
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Connections accepted by each listener shard
+    pub fn shard_accepts(&self) -> Vec<u64> {
+        self.shard_accepts.iter().map(|accepts| accepts.load(Ordering::Relaxed)).collect()
+    }
+
+    /// Stop accepting; returns once the listener is closed
+    pub async fn shutdown(&self) {
+        self.shutdown.cancel();
+        self.closed.cancelled().await;
+    }
+
+    /// Token cancelled when the inbound is shut down, for tasks serving the
+    /// same inbound outside the accept loop
+    pub(crate) fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Whether the inbound has stopped and drained
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Wait for the inbound to stop and its connections to drain
+    pub async fn join(self) -> Result<()> {
+        match self.handle.await {
+            Ok(result) => result.unwrap_or(Ok(())),
+            Err(e) => Err(ProxyError::Protocol(format!("Inbound {} task failed: {}", self.local_addr, e))),
+        }
+    }
+
+    /// Shut down and wait for the drain
+    pub async fn stop(self) -> Result<()> {
+        self.shutdown().await;
+        self.join().await
+    }
+}
+
+/// How long a `reject-drop` connection is held when `server.handshake_timeout_secs` is 0
+const DROP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Hold a connection of a `reject-drop` rule without ever answering
+///
+/// What the client sends is read and discarded until it closes, or until
+/// `server.handshake_timeout_secs` passes.
+pub async fn drop_silently(stream: &mut TcpStream, config: &Config) {
+    let mut sink = [0u8; 4096];
+    let discard = async { while matches!(stream.read(&mut sink).await, Ok(n) if n > 0) {} };
+    let _ = tokio::time::timeout(config.handshake_timeout().unwrap_or(DROP_TIMEOUT), discard).await;
+}
+
+/// Serve `listener` until shut down, handing each connection to `handler`
+pub fn serve_inbound<H, F>(name: &'static str, listener: TcpListener, ctx: InboundContext, handler: H) -> Result<RunningInbound>
+where
+    H: Fn(TcpStream, SocketAddr, InboundContext) -> F + Send + Sync + 'static,
+    F: Future<Output = Result<()>> + Send + 'static,
+{
+    serve_inbound_shards(name, vec![listener], ctx, handler)
+}
+
+/// Serve listeners sharing one address, each with its own accept loop task
+///
+/// The bound address is registered for loop protection while the listeners
+/// are open. Connection tasks hold a drain token; after shutdown the
+/// inbound task finishes once all of them have dropped it. A shard whose
+/// listener cannot be re-bound stops the whole inbound.
+pub fn serve_inbound_shards<H, F>(
+    name: &'static str,
+    listeners: Vec<TcpListener>,
+    ctx: InboundContext,
+    handler: H,
+) -> Result<RunningInbound>
+where
+    H: Fn(TcpStream, SocketAddr, InboundContext) -> F + Send + Sync + 'static,
+    F: Future<Output = Result<()>> + Send + 'static,
+{
+    let Some(first) = listeners.first() else {
+        return Err(ProxyError::Protocol(format!("{} inbound has no listener", name)));
+    };
+    let local_addr = first.local_addr()?;
+    let shards = listeners.len();
+    let incoming = listeners
+        .into_iter()
+        .map(|listener| Ok(AcceptLoop::new(BoundListener::new(listener)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let shard_accepts: Vec<_> = (0..shards).map(|shard| shard_accept_counter(local_addr, shard)).collect();
+    let shutdown = CancellationToken::new();
+    let closed = CancellationToken::new();
+    let (drain_tx, mut drain_rx) = mpsc::channel::<()>(1);
+    let handler = Arc::new(handler);
+    ctx.listeners.register(local_addr);
+
+    let mut shard_tasks = Vec::with_capacity(shards);
+    for (shard, incoming) in incoming.into_iter().enumerate() {
+        let accept = accept_shard(
+            incoming,
+            shutdown.clone(),
+            ctx.clone(),
+            handler.clone(),
+            drain_tx.clone(),
+            shard_accepts[shard].clone(),
+        );
+        match get_global_task_tracker().spawn(TaskGroup::Listeners, accept) {
+            Ok(task) => shard_tasks.push(task),
+            Err(e) => {
+                shutdown.cancel();
+                ctx.listeners.unregister(local_addr);
+                return Err(e);
+            }
+        }
+    }
+    drop(drain_tx);
+    if shards > 1 {
+        info!("{} inbound listening on {} ({} shards)", name, local_addr, shards);
+    } else {
+        info!("{} inbound listening on {}", name, local_addr);
+    }
+
+    let closed_guard = closed.clone().drop_guard();
+    let handle = get_global_task_tracker().spawn(TaskGroup::Listeners, async move {
+        let mut result = Ok(());
+        for task in shard_tasks {
+            match task.await {
+                Ok(Some(Err(e))) if result.is_ok() => result = Err(e),
+                Err(e) if result.is_ok() => {
+                    result = Err(ProxyError::Protocol(format!("Inbound {} shard failed: {}", local_addr, e)))
+                }
+                _ => {}
+            }
+        }
+
+        ctx.listeners.unregister(local_addr);
+        drop(closed_guard);
+        info!("{} inbound on {} stopped accepting, draining connections", name, local_addr);
+        let _ = drain_rx.recv().await;
+        debug!("{} inbound on {} drained", name, local_addr);
+        result
+    })?;
+
+    Ok(RunningInbound { local_addr, shutdown, closed, handle, shard_accepts })
+}
+
+/// Accept loop of one listener shard
+async fn accept_shard<H, F>(
+    mut incoming: AcceptLoop<BoundListener>,
+    stop: CancellationToken,
+    ctx: InboundContext,
+    handler: Arc<H>,
+    drain_tx: mpsc::Sender<()>,
+    accepts: Arc<AtomicU64>,
+) -> Result<()>
+where
+    H: Fn(TcpStream, SocketAddr, InboundContext) -> F + Send + Sync + 'static,
+    F: Future<Output = Result<()>> + Send + 'static,
+{
+    loop {
+        let accepted = tokio::select! {
+            _ = stop.cancelled() => return Ok(()),
+            accepted = incoming.next() => accepted,
+        };
+        // 接受错误在 AcceptLoop 内退避或重新绑定，只有重新绑定失败才返回错误，并停止其他分片
+        let (stream, client_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                stop.cancel();
+                return Err(e);
+            }
+        };
+        accepts.fetch_add(1, Ordering::Relaxed);
+        info!("New connection from {}", client_addr);
+
+        let slot = if ctx.config.server.reject_when_full {
+            ctx.tasks.reserve(TaskGroup::InboundConns)
+        } else {
+            // 满员时拿着这条连接等空位，期间不再接受，后来的连接留在内核的积压队列里
+            tokio::select! {
+                _ = stop.cancelled() => return Ok(()),
+                slot = ctx.tasks.reserve_waiting(TaskGroup::InboundConns) => slot,
+            }
+        };
+        let slot = match slot {
+            Ok(slot) => slot,
+            Err(e) => {
+                warn!("Rejecting connection from {}: {}", client_addr, e);
+                // 没有拒绝应答（或应答任务也满了）时流随之丢弃，连接被关闭
+                if let Some(refusal) = ctx.refusal.filter(|_| matches!(e, ProxyError::TaskLimit { .. })) {
+                    let _ = ctx.tasks.spawn(TaskGroup::Refusals, refusal(stream));
+                }
+                continue;
+            }
+        };
+        let drain = drain_tx.clone();
+        let connection = handler(stream, client_addr, ctx.clone());
+        ctx.tasks.spawn_reserved(slot, async move {
+            let _drain = drain;
+            if let Err(e) = connection.await {
+                error!("Error handling connection from {}: {}", client_addr, e);
+            }
+        });
+    }
 }
 
 /// 基于协议的inbound实现
@@ -21,91 +453,523 @@ impl ProtocolInbound {
 
 #[async_trait::async_trait]
 impl Inbound for ProtocolInbound {
-    async fn start(&self) -> Result<()> {
-        self.protocol.start_inbound(self.bind_addr).await
+    async fn start(&self, ctx: InboundContext) -> Result<RunningInbound> {
+        self.protocol.start_inbound(self.bind_addr, ctx).await
     }
 }
 
-#[cfg(target_os = "linux")]
-pub mod tproxy {
-    use super::*;
-    use log::{error, info};
-    use nix::sys::socket::{setsockopt, sockopt::IpTransparent};
-    use socket2::{Domain, Protocol, Socket, Type};
-    use std::os::fd::FromRawFd;
-    use tokio::net::{TcpListener, UdpSocket};
+/// Inbound protocols that can be started from a spec
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InboundKind {
+    #[serde(rename = "socks", alias = "socks5")]
+    Socks5,
+    Tproxy,
+    /// iptables/nftables REDIRECT (NAT), Linux only
+    Redirect,
+    /// DNS server (UDP and TCP) answering from `dns.fake_ip` or the configured resolvers
+    Dns,
+}
 
-    pub struct TProxyInbound {
-        pub bind_addr: SocketAddr,
+/// What an inbound is started from; a changed spec means a restart
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InboundSpec {
+    pub tag: String,
+    pub kind: InboundKind,
+    pub bind_addr: SocketAddr,
+    /// Authentication of this inbound; None uses `server.auth`
+    pub auth: Option<SocksAuthConfig>,
+    /// Routing profile of this inbound; None uses `server.profile`
+    pub profile: Option<String>,
+    /// SO_LINGER of this inbound's connections; None uses `server.linger`
+    pub linger: Option<LingerPolicy>,
+}
+
+impl InboundSpec {
+    /// Spec of an `[[inbounds]]` entry; `server.auth` and `server.linger` apply to it
+    pub fn from_config(config: &InboundConfig) -> Self {
+        Self {
+            tag: config.tag.clone(),
+            kind: config.kind,
+            bind_addr: config.bind_addr(),
+            auth: None,
+            profile: config.profile.clone(),
+            linger: None,
+        }
     }
 
-    impl TProxyInbound {
-        pub fn new(bind_addr: SocketAddr) -> Self { Self { bind_addr } }
+    fn inbound(&self) -> ProtocolInbound {
+        let protocol: Box<dyn Protocol> = match self.kind {
+            InboundKind::Socks5 => Box::new(crate::protocols::Socks5Protocol::new()),
+            InboundKind::Tproxy => Box::new(crate::protocols::TproxyProtocol::new()),
+            InboundKind::Redirect => Box::new(crate::protocols::RedirectProtocol::new()),
+            InboundKind::Dns => Box::new(crate::protocols::DnsProtocol::new()),
+        };
+        ProtocolInbound::new(protocol, self.bind_addr)
     }
+}
 
-    #[async_trait::async_trait]
-    impl Inbound for TProxyInbound {
-        async fn start(&self) -> Result<()> {
-            // TCP transparent listener
-            let tcp_listener = create_transparent_tcp_listener(self.bind_addr)?;
-            let listener = unsafe { TcpListener::from_std(std::net::TcpListener::from_raw_fd(tcp_listener.as_raw_fd())) };
-            info!("TProxy TCP listening on {}", self.bind_addr);
-            tokio::spawn(async move {
-                loop {
-                    match listener.accept().await {
-                        Ok((_stream, peer)) => {
-                            info!("TProxy TCP accepted from {}", peer);
-                        }
-                        Err(e) => {
-                            error!("TProxy TCP accept error: {}", e);
-                            break;
-                        }
-                    }
+/// Running inbounds by tag
+///
+/// `apply` brings the set in line with a new list of specs: inbounds whose
+/// spec is unchanged keep running untouched, removed ones are shut down and
+/// changed ones are shut down and started again. Shut down inbounds drain
+/// their connections in the background.
+#[derive(Default)]
+pub struct InboundManager {
+    running: HashMap<String, (InboundSpec, RunningInbound)>,
+}
+
+impl InboundManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn apply(&mut self, specs: &[InboundSpec], ctx: &InboundContext) -> Result<()> {
+        let stale: Vec<String> = self
+            .running
+            .iter()
+            .filter(|(_, (spec, _))| !specs.contains(spec))
+            .map(|(tag, _)| tag.clone())
+            .collect();
+        for tag in stale {
+            if let Some((spec, running)) = self.running.remove(&tag) {
+                info!("Stopping inbound {} on {}", tag, spec.bind_addr);
+                // 先关闭监听再启动新配置，避免端口冲突
+                running.shutdown().await;
+            }
+        }
+
+        for spec in specs {
+            if self.running.contains_key(&spec.tag) {
+                continue;
+            }
+            let running = Self::start_spec(spec, ctx).await?;
+            self.running.insert(spec.tag.clone(), (spec.clone(), running));
+        }
+        Ok(())
+    }
+
+    /// Start every inbound of `specs`
+    ///
+    /// An inbound that fails to start is logged and skipped so the others
+    /// still serve; only when none of them starts is the error returned.
+    pub async fn start(&mut self, specs: &[InboundSpec], ctx: &InboundContext) -> Result<()> {
+        let mut failures = Vec::new();
+        for spec in specs {
+            match Self::start_spec(spec, ctx).await {
+                Ok(running) => {
+                    info!("Inbound {} ({:?}) listening on {}", spec.tag, spec.kind, running.local_addr());
+                    self.running.insert(spec.tag.clone(), (spec.clone(), running));
                 }
-            });
+                Err(e) => {
+                    error!("Inbound {} on {} failed to start: {}", spec.tag, spec.bind_addr, e);
+                    failures.push(format!("{}: {}", spec.tag, e));
+                }
+            }
+        }
+        if self.running.is_empty() && !failures.is_empty() {
+            return Err(ProxyError::Protocol(format!("No inbound could be started ({})", failures.join("; "))));
+        }
+        Ok(())
+    }
 
-            // UDP transparent socket
-            let udp = create_transparent_udp_socket(self.bind_addr)?;
-            let _udp = UdpSocket::from_std(udp)?;
-            info!("TProxy UDP bound on {}", self.bind_addr);
-            Ok(())
+    async fn start_spec(spec: &InboundSpec, ctx: &InboundContext) -> Result<RunningInbound> {
+        let mut ctx = match &spec.auth {
+            Some(auth) => ctx.clone().with_auth(InboundAuth::new(auth)?),
+            None => ctx.clone(),
         }
+        .with_tag(spec.tag.as_str());
+        if let Some(profile) = &spec.profile {
+            if ctx.router().profile(profile).is_none() {
+                return Err(ProxyError::Protocol(format!("Inbound {} references unknown profile: {}", spec.tag, profile)));
+            }
+            ctx = ctx.with_profile(profile.as_str());
+        }
+        if let Some(linger) = spec.linger {
+            linger.validate()?;
+            ctx.linger = linger;
+        }
+        spec.inbound().start(ctx).await
     }
 
-    use std::os::fd::{AsRawFd, IntoRawFd};
-    fn create_transparent_tcp_listener(addr: SocketAddr) -> Result<std::net::TcpListener> {
-        let domain = match addr { SocketAddr::V4(_) => Domain::IPV4, SocketAddr::V6(_) => Domain::IPV6 };
-        let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
-        socket.set_reuse_address(true)?;
-        // IP_TRANSPARENT
-        setsockopt(socket.as_raw_fd(), IpTransparent, &true).map_err(|e| std::io::Error::other(e))?;
-        socket.bind(&addr.into())?;
-        socket.listen(1024)?;
-        Ok(socket.into())
+    /// Bound addresses of the running inbounds
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.running.values().map(|(_, running)| running.local_addr()).collect()
     }
 
-    fn create_transparent_udp_socket(addr: SocketAddr) -> Result<std::net::UdpSocket> {
-        let domain = match addr { SocketAddr::V4(_) => Domain::IPV4, SocketAddr::V6(_) => Domain::IPV6 };
-        let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
-        socket.set_reuse_address(true)?;
-        setsockopt(socket.as_raw_fd(), IpTransparent, &true).map_err(|e| std::io::Error::other(e))?;
-        socket.bind(&addr.into())?;
-        Ok(socket.into())
+    /// Wait until every inbound has stopped
+    ///
+    /// An inbound whose accept loop fails is logged while the others keep
+    /// serving; the error is returned once all of them have stopped and
+    /// none ended cleanly.
+    pub async fn join(&mut self) -> Result<()> {
+        use futures::stream::{FuturesUnordered, StreamExt};
+
+        let mut inbounds: FuturesUnordered<_> =
+            self.running.drain().map(|(tag, (_, running))| async move { (tag, running.join().await) }).collect();
+        let mut last_error = None;
+        let mut stopped_cleanly = false;
+        // 按结束顺序逐个报告，一个入站出错不影响其余入站继续服务
+        while let Some((tag, result)) = inbounds.next().await {
+            match result {
+                Ok(()) => stopped_cleanly = true,
+                Err(e) => {
+                    error!("Inbound {} stopped: {}", tag, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        match last_error {
+            Some(e) if !stopped_cleanly => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Bound address of a running inbound
+    pub fn local_addr(&self, tag: &str) -> Option<SocketAddr> {
+        self.running.get(tag).map(|(_, running)| running.local_addr())
+    }
+
+    /// Shut down every inbound and wait for their connections to drain
+    pub async fn shutdown(&mut self) -> Result<()> {
+        for (_, (_, running)) in self.running.drain() {
+            running.stop().await?;
+        }
+        Ok(())
     }
 }
 
-#[cfg(not(target_os = "linux"))]
+/// Addresses our inbound listeners are bound to
+///
+/// Used to refuse connections that would be forwarded back into the proxy
+/// itself and loop until file descriptors run out.
+pub struct ListenerRegistry {
+    config: LoopProtectionConfig,
+    bound: RwLock<Vec<SocketAddr>>,
+    local_addrs: Mutex<Option<(Instant, Vec<IpAddr>)>>,
+    last_warn: Mutex<Option<Instant>>,
+    suppressed_warnings: AtomicU64,
+}
+
+impl ListenerRegistry {
+    pub fn new(config: LoopProtectionConfig) -> Self {
+        Self {
+            config,
+            bound: RwLock::new(Vec::new()),
+            local_addrs: Mutex::new(None),
+            last_warn: Mutex::new(None),
+            suppressed_warnings: AtomicU64::new(0),
+        }
+    }
+
+    /// Record a bound listener address (the actual one, after port 0 is resolved)
+    pub fn register(&self, addr: SocketAddr) {
+        let mut bound = self.bound.write().unwrap();
+        if !bound.contains(&addr) {
+            debug!("Registered listener {}", addr);
+            bound.push(addr);
+        }
+    }
+
+    pub fn unregister(&self, addr: SocketAddr) {
+        self.bound.write().unwrap().retain(|a| *a != addr);
+    }
+
+    pub fn bound(&self) -> Vec<SocketAddr> {
+        self.bound.read().unwrap().clone()
+    }
+
+    /// The listener a connection to `target` would reach, if any
+    pub fn listener_for(&self, target: SocketAddr) -> Option<SocketAddr> {
+        let target_ip = target.ip().to_canonical();
+        let bound = self.bound.read().unwrap();
+        bound
+            .iter()
+            .copied()
+            .find(|listener| listener.port() == target.port() && self.reaches(listener.ip(), target_ip))
+    }
+
+    /// Whether connecting to `target` lands on a listener bound to `listener`
+    fn reaches(&self, listener: IpAddr, target: IpAddr) -> bool {
+        // 连接 0.0.0.0 / :: 实际到达本机
+        if target.is_unspecified() {
+            return true;
+        }
+        match listener {
+            // IPv4通配只接收IPv4；IPv6通配为双栈，两种都接收
+            IpAddr::V4(ip) if ip.is_unspecified() => target.is_ipv4() && self.is_local(target),
+            IpAddr::V6(ip) if ip.is_unspecified() => self.is_local(target),
+            ip => ip.to_canonical() == target,
+        }
+    }
+
+    /// Whether `ip` belongs to this host
+    fn is_local(&self, ip: IpAddr) -> bool {
+        if ip.is_loopback() {
+            return true;
+        }
+        let mut cache = self.local_addrs.lock().unwrap();
+        let fresh = matches!(&*cache, Some((at, _)) if at.elapsed() < LOCAL_ADDRS_TTL);
+        if !fresh {
+            *cache = Some((Instant::now(), local_interface_addrs()));
+        }
+        cache.as_ref().is_some_and(|(_, addrs)| addrs.contains(&ip))
+    }
+
+    /// Refuse connections whose target, or the selected outbound's server,
+    /// is one of our own listeners
+    pub fn check_loop(&self, target: SocketAddr, outbound_server: Option<SocketAddr>) -> Result<()> {
+        self.check_loop_for(Some(target), outbound_server)
+    }
+
+    /// Same as [`check_loop`](Self::check_loop) for a domain target the
+    /// outbound's server resolves, where only the server can be checked
+    pub fn check_outbound_server(&self, outbound_server: Option<SocketAddr>) -> Result<()> {
+        self.check_loop_for(None, outbound_server)
+    }
+
+    fn check_loop_for(&self, target: Option<SocketAddr>, outbound_server: Option<SocketAddr>) -> Result<()> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+        let hit = target
+            .and_then(|target| self.listener_for(target).map(|listener| ("target", target, listener)))
+            .or_else(|| {
+                let server = outbound_server?;
+                self.listener_for(server).map(|listener| ("outbound server", server, listener))
+            });
+        let Some((what, addr, listener)) = hit else {
+            return Ok(());
+        };
+        if self.config.allow.contains(&addr) || self.config.allow.contains(&listener) {
+            debug!("Allowing hairpin connection to {} (listener {})", addr, listener);
+            return Ok(());
+        }
+
+        self.warn_loop(what, addr, listener);
+        Err(ProxyError::LoopDetected(format!("{} {} is our own listener {}", what, addr, listener)))
+    }
+
+    fn warn_loop(&self, what: &str, addr: SocketAddr, listener: SocketAddr) {
+        let mut last = self.last_warn.lock().unwrap();
+        if last.is_some_and(|at| at.elapsed() < LOOP_WARN_INTERVAL) {
+            self.suppressed_warnings.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        *last = Some(Instant::now());
+        let suppressed = self.suppressed_warnings.swap(0, Ordering::Relaxed);
+        warn!(
+            "Refused connection: {} {} is our own listener {}, forwarding it would loop back into the proxy. \
+             Check the client's proxy settings and routing rules, or add it to loop_protection.allow \
+             for a deliberate hairpin ({} similar warnings suppressed)",
+            what, addr, listener, suppressed
+        );
+    }
+}
+
+/// Addresses assigned to local interfaces
+fn local_interface_addrs() -> Vec<IpAddr> {
+    let interfaces = match nix::ifaddrs::getifaddrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            warn!("Failed to list interface addresses: {}", e);
+            return Vec::new();
+        }
+    };
+    interfaces
+        .filter_map(|interface| {
+            let address = interface.address?;
+            if let Some(v4) = address.as_sockaddr_in() {
+                Some(IpAddr::V4(*std::net::SocketAddrV4::from(*v4).ip()))
+            } else {
+                address.as_sockaddr_in6().map(|v6| IpAddr::V6(v6.ip()))
+            }
+        })
+        .collect()
+}
+
+static GLOBAL_LISTENER_REGISTRY: OnceLock<ListenerRegistry> = OnceLock::new();
+
+/// Initialize the global listener registry with the loop protection settings
+pub fn init_global_listener_registry(config: LoopProtectionConfig) {
+    let _ = GLOBAL_LISTENER_REGISTRY.set(ListenerRegistry::new(config));
+}
+
+/// Get the global listener registry (default loop protection when not initialized)
+pub fn get_global_listener_registry() -> &'static ListenerRegistry {
+    GLOBAL_LISTENER_REGISTRY.get_or_init(|| ListenerRegistry::new(LoopProtectionConfig::default()))
+}
+
 pub mod tproxy {
     use super::*;
-    use crate::error::ProxyError;
 
-    pub struct TProxyInbound { pub bind_addr: SocketAddr }
-    impl TProxyInbound { pub fn new(bind_addr: SocketAddr) -> Self { Self { bind_addr } } }
+    /// Transparent proxy inbound; see [`TproxyProtocol`](crate::protocols::TproxyProtocol)
+    pub struct TProxyInbound {
+        pub bind_addr: SocketAddr,
+    }
+
+    impl TProxyInbound {
+        pub fn new(bind_addr: SocketAddr) -> Self { Self { bind_addr } }
+    }
 
     #[async_trait::async_trait]
     impl Inbound for TProxyInbound {
-        async fn start(&self) -> Result<()> { Err(ProxyError::Protocol("TProxy inbound is only supported on Linux".into())) }
+        async fn start(&self, ctx: InboundContext) -> Result<RunningInbound> {
+            // 仅 Linux 支持，其他平台由协议返回错误
+            crate::protocols::TproxyProtocol::new().start_inbound(self.bind_addr, ctx).await
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_connect_to_own_listener_refused() {
+        let registry = ListenerRegistry::new(LoopProtectionConfig::default());
+        registry.register(addr("127.0.0.1:1080"));
+        registry.register(addr("0.0.0.0:8080"));
+
+        let err = registry.check_loop(addr("127.0.0.1:1080"), None).unwrap_err();
+        assert!(matches!(err, ProxyError::LoopDetected(_)));
+        assert_eq!(err.socks5_reply_code(), 0x02);
+
+        // 通配绑定展开为本机地址
+        assert!(registry.check_loop(addr("127.0.0.1:8080"), None).is_err());
+        assert!(registry.check_loop(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 8080), None).is_err());
+        assert!(registry.check_loop(addr("[::ffff:127.0.0.1]:1080"), None).is_err());
+        assert!(registry.check_loop(addr("192.0.2.1:8080"), None).is_ok());
+
+        // 出站服务器指向自身
+        assert!(registry.check_loop(addr("192.0.2.1:443"), Some(addr("127.0.0.1:1080"))).is_err());
+        assert!(registry.check_loop(addr("127.0.0.1:1081"), Some(addr("192.0.2.1:1080"))).is_ok());
+        // 域名目标由上游解析，只检查出站服务器
+        assert!(registry.check_outbound_server(Some(addr("127.0.0.1:1080"))).is_err());
+        assert!(registry.check_outbound_server(None).is_ok());
+
+        registry.unregister(addr("127.0.0.1:1080"));
+        assert!(registry.check_loop(addr("127.0.0.1:1080"), None).is_ok());
+    }
+
+    #[test]
+    fn test_allowed_hairpin_passes() {
+        let registry = ListenerRegistry::new(LoopProtectionConfig {
+            enabled: true,
+            allow: vec![addr("127.0.0.1:1080")],
+        });
+        registry.register(addr("127.0.0.1:1080"));
+        registry.register(addr("127.0.0.1:1081"));
+
+        assert!(registry.check_loop(addr("127.0.0.1:1080"), None).is_ok());
+        assert!(registry.check_loop(addr("127.0.0.1:1081"), None).is_err());
+
+        let disabled = ListenerRegistry::new(LoopProtectionConfig {
+            enabled: false,
+            allow: Vec::new(),
+        });
+        disabled.register(addr("127.0.0.1:1081"));
+        assert!(disabled.check_loop(addr("127.0.0.1:1081"), None).is_ok());
+    }
+
+    fn socks(tag: &str, bind: &str) -> InboundSpec {
+        InboundSpec { tag: tag.to_string(), kind: InboundKind::Socks5, bind_addr: addr(bind), auth: None, profile: None, linger: None }
+    }
+
+    #[tokio::test]
+    async fn test_apply_restarts_only_changed_inbounds() {
+        let config = Config::default();
+        let outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        let ctx = InboundContext::new(Box::leak(Box::new(config)), Arc::new(outbounds));
+        let mut manager = InboundManager::new();
+
+        manager.apply(&[socks("a", "127.0.0.1:0"), socks("b", "127.0.0.1:0")], &ctx).await.unwrap();
+        let a = manager.local_addr("a").unwrap();
+        let b = manager.local_addr("b").unwrap();
+
+        manager.apply(&[socks("a", "127.0.0.1:0"), socks("b", "[::1]:0")], &ctx).await.unwrap();
+        assert_eq!(manager.local_addr("a"), Some(a));
+        assert!(manager.local_addr("b").unwrap().is_ipv6());
+        assert!(TcpStream::connect(b).await.is_err());
+
+        manager.apply(&[socks("b", "[::1]:0")], &ctx).await.unwrap();
+        assert_eq!(manager.local_addr("a"), None);
+        assert!(TcpStream::connect(a).await.is_err());
+
+        manager.shutdown().await.unwrap();
+        assert_eq!(manager.local_addr("b"), None);
+    }
+
+    #[tokio::test]
+    async fn test_start_skips_inbounds_that_fail() {
+        let config = Config::default();
+        let outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        let ctx = InboundContext::new(Box::leak(Box::new(config)), Arc::new(outbounds));
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let busy = taken.local_addr().unwrap().to_string();
 
+        let mut manager = InboundManager::new();
+        manager.start(&[socks("busy", &busy), socks("free", "127.0.0.1:0")], &ctx).await.unwrap();
+        assert_eq!(manager.local_addr("busy"), None);
+        let free = manager.local_addr("free").unwrap();
+        assert_eq!(manager.local_addrs(), vec![free]);
+        assert!(TcpStream::connect(free).await.is_ok());
+        manager.shutdown().await.unwrap();
+
+        let mut manager = InboundManager::new();
+        let err = manager.start(&[socks("busy", &busy)], &ctx).await.unwrap_err();
+        assert!(err.to_string().contains("No inbound could be started"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_join_returns_once_every_inbound_stopped() {
+        let config = Config::default();
+        let outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        let ctx = InboundContext::new(Box::leak(Box::new(config)), Arc::new(outbounds));
+        let mut manager = InboundManager::new();
+        manager.start(&[socks("a", "127.0.0.1:0"), socks("b", "127.0.0.1:0")], &ctx).await.unwrap();
+        let tokens: Vec<CancellationToken> = manager.running.values().map(|(_, running)| running.shutdown_token()).collect();
+
+        let join = tokio::spawn(async move { manager.join().await });
+        tokens[0].cancel();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!join.is_finished());
+        tokens[1].cancel();
+        tokio::time::timeout(Duration::from_secs(5), join).await.unwrap().unwrap().unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_reuseport_shards_share_accepts() {
+        use crate::listener::{bind_tcp_listeners_with, ListenerOptions};
+
+        let config = Config::default();
+        let outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        let ctx = InboundContext::new(Box::leak(Box::new(config)), Arc::new(outbounds));
+        let options = ListenerOptions { shards: 2, ..ListenerOptions::default() };
+        let listeners = bind_tcp_listeners_with(addr("127.0.0.1:0"), &options, |_| Ok(())).await.unwrap();
+        let running = serve_inbound_shards("test", listeners, ctx, |_stream, _peer, _ctx| async { Ok(()) }).unwrap();
+
+        let mut clients = Vec::new();
+        for _ in 0..100 {
+            clients.push(TcpStream::connect(running.local_addr()).await.unwrap());
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while running.shard_accepts().iter().sum::<u64>() < 100 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let accepts = running.shard_accepts();
+        assert_eq!(accepts.iter().sum::<u64>(), 100, "{:?}", accepts);
+        assert!(accepts.iter().all(|&accepted| accepted > 0), "{:?}", accepts);
+        let stats = crate::accept::shard_accept_stats();
+        assert_eq!(stats.iter().filter(|s| s.addr == running.local_addr()).count(), 2);
+        running.stop().await.unwrap();
+    }
+}
@@ -0,0 +1,134 @@
+// 下载内容的完整性校验：SHA-256 摘要与 ed25519 分离签名
+use crate::error::{ProxyError, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ring::digest::{digest, SHA256};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use std::fmt;
+
+const PUBLIC_KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+
+/// Hex SHA-256 of `data`
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex(digest(&SHA256, data).as_ref())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decode hex or standard base64, whichever yields `len` bytes
+fn decode_key_material(text: &str, len: usize, what: &str) -> Result<Vec<u8>> {
+    let text = text.trim();
+    let from_hex = (text.len() == len * 2)
+        .then(|| (0..len).map(|i| u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok()).collect::<Option<Vec<u8>>>())
+        .flatten();
+    let bytes = match from_hex {
+        Some(bytes) => bytes,
+        None => BASE64
+            .decode(text)
+            .map_err(|e| ProxyError::Integrity(format!("{} is neither hex nor base64: {}", what, e)))?,
+    };
+    if bytes.len() != len {
+        return Err(ProxyError::Integrity(format!("{} must be {} bytes, got {}", what, len, bytes.len())));
+    }
+    Ok(bytes)
+}
+
+/// ed25519 public key a rule set must be signed with
+#[derive(Clone, PartialEq, Eq)]
+pub struct PublicKey([u8; PUBLIC_KEY_LEN]);
+
+impl PublicKey {
+    /// Parse a 32-byte key written as hex or base64
+    pub fn parse(text: &str) -> Result<Self> {
+        let bytes = decode_key_material(text, PUBLIC_KEY_LEN, "public key")?;
+        Ok(Self(bytes.try_into().expect("length checked")))
+    }
+
+    /// Check a detached signature over `message`
+    ///
+    /// The signature file may hold the 64 raw bytes or their hex/base64 text.
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> Result<()> {
+        let signature = match signature.len() {
+            SIGNATURE_LEN => signature.to_vec(),
+            _ => {
+                let text = std::str::from_utf8(signature)
+                    .map_err(|_| ProxyError::Integrity(format!("signature is not {} raw bytes or text", SIGNATURE_LEN)))?;
+                decode_key_material(text, SIGNATURE_LEN, "signature")?
+            }
+        };
+        UnparsedPublicKey::new(&ED25519, &self.0)
+            .verify(message, &signature)
+            .map_err(|_| ProxyError::Integrity(format!("bad ed25519 signature for key {}", self)))
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex(&self.0))
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({})", self)
+    }
+}
+
+/// Where a rule set's detached signature is fetched and the key it is checked with
+#[derive(Debug, Clone)]
+pub struct SignatureSource {
+    pub url: String,
+    pub key: PublicKey,
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    /// Fixed test key pair, so no RNG is needed
+    pub(crate) fn keypair(seed: u8) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap()
+    }
+
+    pub(crate) fn public_key(pair: &Ed25519KeyPair) -> PublicKey {
+        PublicKey::parse(&hex(pair.public_key().as_ref())).unwrap()
+    }
+
+    #[test]
+    fn test_parse_key_hex_and_base64() {
+        let pair = keypair(7);
+        let raw = pair.public_key().as_ref();
+        let from_hex = PublicKey::parse(&hex(raw)).unwrap();
+        let from_base64 = PublicKey::parse(&BASE64.encode(raw)).unwrap();
+        assert_eq!(from_hex, from_base64);
+        assert!(PublicKey::parse("abcd").is_err());
+        assert!(PublicKey::parse(&hex(&raw[..31])).is_err());
+    }
+
+    #[test]
+    fn test_valid_and_invalid_signatures() {
+        let pair = keypair(7);
+        let key = public_key(&pair);
+        let content = b"example.com\nexample.org\n";
+        let signature = pair.sign(content);
+
+        key.verify(content, signature.as_ref()).unwrap();
+        key.verify(content, hex(signature.as_ref()).as_bytes()).unwrap();
+        key.verify(content, format!("{}\n", BASE64.encode(signature.as_ref())).as_bytes()).unwrap();
+
+        let err = key.verify(b"example.com\nevil.example\n", signature.as_ref()).unwrap_err();
+        assert!(err.to_string().contains("bad ed25519 signature"), "{}", err);
+        let other = public_key(&keypair(8));
+        assert!(other.verify(content, signature.as_ref()).is_err());
+        assert!(key.verify(content, b"not a signature").is_err());
+    }
+
+    #[test]
+    fn test_sha256_hex() {
+        assert_eq!(sha256_hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+}
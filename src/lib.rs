@@ -1,27 +1,119 @@
+#[cfg(feature = "runtime")]
+pub mod accept;
+#[cfg(feature = "runtime")]
+pub mod access_log;
+#[cfg(feature = "runtime")]
+pub mod blocked;
+#[cfg(feature = "runtime")]
+pub mod buffer_pool;
+#[cfg(feature = "runtime")]
+pub mod capture;
+#[cfg(feature = "runtime")]
+pub mod clash_api;
+#[cfg(feature = "runtime")]
 pub mod config;
+#[cfg(feature = "runtime")]
+pub mod config_builder;
+#[cfg(feature = "runtime")]
 pub mod connection_pool;
+#[cfg(feature = "runtime")]
+pub mod connection_rate;
+#[cfg(feature = "runtime")]
+pub mod connection_registry;
+#[cfg(feature = "runtime")]
+pub mod diagnostics;
+#[cfg(feature = "runtime")]
 pub mod dns;
+#[cfg(feature = "runtime")]
+pub mod dns_cache;
+#[cfg(feature = "runtime")]
+pub mod dns_ecs;
+#[cfg(feature = "runtime")]
+pub mod dns_stats;
+#[cfg(feature = "runtime")]
+pub mod endpoint;
+#[cfg(feature = "runtime")]
 pub mod error;
+#[cfg(feature = "runtime")]
+pub mod fake_ip;
+#[cfg(feature = "runtime")]
+pub mod health;
+#[cfg(feature = "runtime")]
 pub mod inbound;
+#[cfg(feature = "runtime")]
+pub mod integrity;
+#[cfg(feature = "runtime")]
+pub mod listener;
+#[cfg(feature = "runtime")]
+pub mod loadgen;
+#[cfg(feature = "runtime")]
+pub mod log_file;
+#[cfg(feature = "runtime")]
+pub mod negative_cache;
+#[cfg(feature = "runtime")]
 pub mod outbound;
+#[cfg(feature = "runtime")]
+pub mod pac;
+#[cfg(feature = "runtime")]
 pub mod protocol;
+#[cfg(feature = "runtime")]
+pub mod protocol_util;
+#[cfg(feature = "runtime")]
 pub mod protocols;
+#[cfg(feature = "runtime")]
 pub mod proxy;
+#[cfg(feature = "runtime")]
+pub mod rebinding;
+#[cfg(feature = "runtime")]
+pub mod reload;
+#[cfg(feature = "runtime")]
 pub mod ron_config;
 pub mod routing;
+#[cfg(feature = "runtime")]
 pub mod rule_set_downloader;
+#[cfg(feature = "runtime")]
+pub mod scope;
+#[cfg(all(target_os = "linux", feature = "runtime"))]
+pub mod splice_relay;
+#[cfg(feature = "runtime")]
+pub mod stream;
+#[cfg(feature = "runtime")]
+pub mod tasks;
+#[cfg(feature = "runtime")]
+pub mod tls;
+#[cfg(feature = "runtime")]
+pub mod tls_fragment;
+#[cfg(feature = "runtime")]
 pub mod traffic_mark;
+#[cfg(feature = "runtime")]
+pub mod uot;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring_relay;
+#[cfg(feature = "runtime")]
+pub mod watchdog;
+#[cfg(feature = "runtime")]
 pub mod zero_copy;
 
+#[cfg(feature = "runtime")]
 pub use error::{ProxyError, Result};
-pub use inbound::{Inbound, ProtocolInbound};
+#[cfg(feature = "runtime")]
+pub use inbound::{Inbound, InboundContext, InboundManager, ProtocolInbound, RunningInbound};
+#[cfg(feature = "runtime")]
 pub use outbound::{OutboundConnector, OutboundManager};
-pub use protocol::{Address, Socks5Request, Socks5Response};
+#[cfg(feature = "runtime")]
+pub use protocol::{Address, AddressFormat, Socks5Request, Socks5Response, TargetAddr};
+#[cfg(feature = "runtime")]
 pub use protocols::{
-    BlackholeProtocol, DirectProtocol, Protocol, Socks5Protocol, TproxyProtocol, VlessProtocol,
+    BlackholeProtocol, DirectProtocol, HttpProtocol, Protocol, Socks5Protocol, TproxyProtocol, VlessProtocol,
 };
+#[cfg(feature = "runtime")]
 pub use proxy::Socks5Proxy;
 pub use routing::rule_sets::{DomainRuleSet, IpRuleSet, RuleSetManager};
+#[cfg(feature = "runtime")]
 pub use routing::{HighPerformanceRouter, RouteRule};
+#[cfg(feature = "runtime")]
 pub use rule_set_downloader::{RuleSetDownloader, RuleSetCacheInfo, CacheStats};
+#[cfg(feature = "runtime")]
+pub use stream::{OutboundStream, ProxyStream};
+#[cfg(feature = "runtime")]
 pub use zero_copy::{OptimizedCopier, ZeroCopyBuffer, ZeroCopyRelay};
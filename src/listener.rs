@@ -0,0 +1,308 @@
+// 监听套接字创建：端口复用、绑定重试与端口占用诊断
+use crate::config::Config;
+use crate::error::Result;
+use log::{info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+
+/// Initial delay between bind attempts while the port is busy
+const BIND_RETRY_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+/// Upper bound for the bind retry backoff
+const BIND_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Options applied to every inbound listener socket
+#[derive(Debug, Clone)]
+pub struct ListenerOptions {
+    /// Set SO_REUSEADDR before binding
+    pub reuse_addr: bool,
+    /// Set SO_REUSEPORT before binding (Linux only)
+    pub reuse_port: bool,
+    /// Listening sockets per inbound; more than one implies SO_REUSEPORT
+    pub shards: usize,
+    /// How long to keep retrying when the address is in use
+    pub bind_retry: Duration,
+    /// Listen backlog
+    pub backlog: i32,
+}
+
+impl ListenerOptions {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            reuse_addr: config.performance.reuse_addr,
+            reuse_port: config.performance.reuse_port,
+            shards: config.performance.listener_shards,
+            bind_retry: Duration::from_secs(config.server.bind_retry_secs),
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for ListenerOptions {
+    fn default() -> Self {
+        Self {
+            reuse_addr: true,
+            reuse_port: false,
+            shards: 1,
+            bind_retry: Duration::ZERO,
+            backlog: 1024,
+        }
+    }
+}
+
+/// Bind a TCP listener using the global listener options
+pub async fn bind_tcp_listener(addr: SocketAddr) -> Result<TcpListener> {
+    bind_tcp_listener_with(addr, get_global_listener_options(), |_| Ok(())).await
+}
+
+/// Bind one TCP listener per shard using the global listener options
+pub async fn bind_tcp_listeners(addr: SocketAddr) -> Result<Vec<TcpListener>> {
+    bind_tcp_listeners_with(addr, get_global_listener_options(), |_| Ok(())).await
+}
+
+/// Bind `options.shards` listeners to the same address
+///
+/// The first listener resolves port 0; the others join it through
+/// SO_REUSEPORT and the kernel spreads incoming connections across them.
+pub async fn bind_tcp_listeners_with<F>(
+    addr: SocketAddr,
+    options: &ListenerOptions,
+    configure: F,
+) -> Result<Vec<TcpListener>>
+where
+    F: Fn(&Socket) -> io::Result<()>,
+{
+    let first = bind_tcp_listener_with(addr, options, &configure).await?;
+    let addr = first.local_addr()?;
+    let mut listeners = vec![first];
+    for _ in 1..options.shards {
+        listeners.push(bind_tcp_listener_with(addr, options, &configure).await?);
+    }
+    Ok(listeners)
+}
+
+/// Bind a TCP listener, retrying while the address is in use
+///
+/// `configure` runs on the fresh socket before bind, for options such as
+/// IP_TRANSPARENT that only some inbounds need.
+pub async fn bind_tcp_listener_with<F>(
+    addr: SocketAddr,
+    options: &ListenerOptions,
+    configure: F,
+) -> Result<TcpListener>
+where
+    F: Fn(&Socket) -> io::Result<()>,
+{
+    let deadline = Instant::now() + options.bind_retry;
+    let mut backoff = BIND_RETRY_INITIAL_BACKOFF;
+    let mut attempts = 0u32;
+
+    loop {
+        attempts += 1;
+        match try_bind(addr, options, &configure) {
+            Ok(listener) => {
+                if attempts > 1 {
+                    info!("Bound {} after {} attempts", addr, attempts);
+                }
+                return Ok(TcpListener::from_std(listener)?);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(address_in_use_error(addr, attempts).into());
+                }
+                warn!(
+                    "Address {} in use, retrying bind in {:?} (attempt {})",
+                    addr, backoff, attempts
+                );
+                tokio::time::sleep(backoff.min(deadline - now)).await;
+                backoff = (backoff * 2).min(BIND_RETRY_MAX_BACKOFF);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn try_bind<F>(addr: SocketAddr, options: &ListenerOptions, configure: &F) -> io::Result<std::net::TcpListener>
+where
+    F: Fn(&Socket) -> io::Result<()>,
+{
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if options.reuse_addr {
+        socket.set_reuse_address(true)?;
+    }
+    if options.reuse_port || options.shards > 1 {
+        #[cfg(target_os = "linux")]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(target_os = "linux"))]
+        warn!("SO_REUSEPORT not supported on this platform");
+    }
+    configure(&socket)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(options.backlog)?;
+    Ok(socket.into())
+}
+
+fn address_in_use_error(addr: SocketAddr, attempts: u32) -> io::Error {
+    let owner = match find_port_owner(addr.port()) {
+        Some(owner) => format!(", held by {}", owner),
+        None => String::new(),
+    };
+    io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!(
+            "Address {} already in use after {} bind attempts{}",
+            addr, attempts, owner
+        ),
+    )
+}
+
+/// Best-effort lookup of the process listening on a TCP port
+///
+/// Returns a description such as `pid 1234 (nginx)`.
+#[cfg(target_os = "linux")]
+pub fn find_port_owner(port: u16) -> Option<String> {
+    let inodes: Vec<u64> = ["/proc/net/tcp", "/proc/net/tcp6"]
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .flat_map(|table| listening_inodes(&table, port))
+        .collect();
+    if inodes.is_empty() {
+        return None;
+    }
+
+    for entry in std::fs::read_dir("/proc").ok()?.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|s| s.parse::<u32>().ok()) else {
+            continue;
+        };
+        let Ok(fds) = std::fs::read_dir(entry.path().join("fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            let Ok(target) = std::fs::read_link(fd.path()) else {
+                continue;
+            };
+            let target = target.to_string_lossy();
+            let owns = inodes
+                .iter()
+                .any(|inode| target == format!("socket:[{}]", inode).as_str());
+            if owns {
+                let comm = std::fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+                return Some(format!("pid {} ({})", pid, comm.trim()));
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn find_port_owner(_port: u16) -> Option<String> {
+    None
+}
+
+/// Extract inodes of sockets in LISTEN state on `port` from a /proc/net/tcp table
+#[cfg(target_os = "linux")]
+fn listening_inodes(table: &str, port: u16) -> Vec<u64> {
+    const TCP_LISTEN: &str = "0A";
+
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let local = fields.get(1)?;
+            let state = fields.get(3)?;
+            let inode = fields.get(9)?.parse::<u64>().ok()?;
+            let local_port = u16::from_str_radix(local.rsplit(':').next()?, 16).ok()?;
+            (local_port == port && *state == TCP_LISTEN && inode != 0).then_some(inode)
+        })
+        .collect()
+}
+
+static GLOBAL_LISTENER_OPTIONS: OnceLock<ListenerOptions> = OnceLock::new();
+
+/// Initialize global listener options
+pub fn init_global_listener_options(options: ListenerOptions) {
+    if GLOBAL_LISTENER_OPTIONS.set(options).is_err() {
+        warn!("Listener options already initialized");
+    }
+}
+
+/// Get global listener options, falling back to defaults when not initialized
+pub fn get_global_listener_options() -> &'static ListenerOptions {
+    GLOBAL_LISTENER_OPTIONS.get_or_init(ListenerOptions::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn occupy_port() -> std::net::TcpListener {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_bind_succeeds_after_port_released() {
+        let blocker = occupy_port();
+        let addr = blocker.local_addr().unwrap();
+        let options = ListenerOptions {
+            bind_retry: Duration::from_secs(5),
+            ..ListenerOptions::default()
+        };
+
+        let bind = tokio::spawn(async move { bind_tcp_listener_with(addr, &options, |_| Ok(())).await });
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!bind.is_finished());
+        drop(blocker);
+
+        let listener = bind.await.unwrap().unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+    }
+
+    #[tokio::test]
+    async fn test_bind_fails_when_retry_budget_exhausted() {
+        let blocker = occupy_port();
+        let addr = blocker.local_addr().unwrap();
+        let options = ListenerOptions {
+            bind_retry: Duration::from_millis(200),
+            ..ListenerOptions::default()
+        };
+
+        let err = bind_tcp_listener_with(addr, &options, |_| Ok(())).await.unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("already in use"), "{}", message);
+
+        #[cfg(target_os = "linux")]
+        assert!(
+            message.contains(&format!("pid {}", std::process::id())),
+            "{}",
+            message
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_listening_inodes_parses_proc_table() {
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n\
+                     0: 0100007F:0438 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 4242 1\n\
+                     1: 0100007F:0438 0100007F:9C40 01 00000000:00000000 00:00000000 00000000     0        0 4343 1\n";
+        assert_eq!(listening_inodes(table, 1080), vec![4242]);
+        assert!(listening_inodes(table, 1081).is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_shards_share_resolved_port() {
+        let options = ListenerOptions { shards: 3, ..ListenerOptions::default() };
+        let listeners = bind_tcp_listeners_with("127.0.0.1:0".parse().unwrap(), &options, |_| Ok(())).await.unwrap();
+        let addrs: Vec<SocketAddr> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+        assert_eq!(addrs.len(), 3);
+        assert_ne!(addrs[0].port(), 0);
+        assert!(addrs.iter().all(|addr| *addr == addrs[0]));
+    }
+}
@@ -0,0 +1,274 @@
+// 压测工具：通过运行中的SOCKS5实例产生真实负载并汇总吞吐与延迟
+use crate::error::{ProxyError, Result};
+use crate::protocols::socks5_client_connect;
+use log::{debug, warn};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+
+/// Load generator settings
+#[derive(Debug, Clone)]
+pub struct LoadgenOptions {
+    /// SOCKS5 address of the instance under test
+    pub proxy: SocketAddr,
+    /// Total connections to open
+    pub connections: usize,
+    /// Connections in flight at once
+    pub concurrency: usize,
+    /// Bytes sent (and echoed back) per connection
+    pub payload_bytes: usize,
+    /// Echo server to tunnel to; a local one is spawned when unset
+    pub echo: Option<SocketAddr>,
+}
+
+/// Measurements for one successful connection
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionSample {
+    /// Time until the SOCKS5 CONNECT succeeded
+    pub connect: Duration,
+    /// Time until the whole payload came back
+    pub total: Duration,
+    /// Bytes transferred in both directions
+    pub bytes: u64,
+}
+
+/// Latency distribution
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LatencySummary {
+    pub min: Duration,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl LatencySummary {
+    pub fn from_durations(mut durations: Vec<Duration>) -> Self {
+        if durations.is_empty() {
+            return Self::default();
+        }
+        durations.sort_unstable();
+        let total: Duration = durations.iter().sum();
+        Self {
+            min: durations[0],
+            mean: total / durations.len() as u32,
+            p50: percentile(&durations, 50.0),
+            p90: percentile(&durations, 90.0),
+            p99: percentile(&durations, 99.0),
+            max: durations[durations.len() - 1],
+        }
+    }
+}
+
+/// Nearest-rank percentile of an ascending, non-empty slice
+fn percentile(sorted: &[Duration], pct: f64) -> Duration {
+    let rank = ((pct / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Aggregated result of a load run
+#[derive(Debug, Clone)]
+pub struct LoadReport {
+    pub succeeded: usize,
+    pub failed: usize,
+    pub elapsed: Duration,
+    pub bytes: u64,
+    pub connect_latency: LatencySummary,
+    pub total_latency: LatencySummary,
+}
+
+impl LoadReport {
+    pub fn from_samples(samples: &[ConnectionSample], failed: usize, elapsed: Duration) -> Self {
+        Self {
+            succeeded: samples.len(),
+            failed,
+            elapsed,
+            bytes: samples.iter().map(|s| s.bytes).sum(),
+            connect_latency: LatencySummary::from_durations(samples.iter().map(|s| s.connect).collect()),
+            total_latency: LatencySummary::from_durations(samples.iter().map(|s| s.total).collect()),
+        }
+    }
+
+    /// Successful connections per second
+    pub fn connections_per_sec(&self) -> f64 {
+        rate(self.succeeded as f64, self.elapsed)
+    }
+
+    /// Bytes per second in both directions
+    pub fn throughput(&self) -> f64 {
+        rate(self.bytes as f64, self.elapsed)
+    }
+}
+
+fn rate(amount: f64, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        0.0
+    } else {
+        amount / elapsed.as_secs_f64()
+    }
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        writeln!(f, "connections  {} ok, {} failed in {:.2?}", self.succeeded, self.failed, self.elapsed)?;
+        writeln!(f, "rate         {:.1} conn/s", self.connections_per_sec())?;
+        writeln!(f, "throughput   {:.2} MiB/s", self.throughput() / (1024.0 * 1024.0))?;
+        writeln!(f)?;
+        writeln!(f, "latency (ms)     min     mean      p50      p90      p99      max")?;
+        for (label, l) in [("connect", &self.connect_latency), ("round trip", &self.total_latency)] {
+            writeln!(
+                f,
+                "{:<12} {:>7.2} {:>8.2} {:>8.2} {:>8.2} {:>8.2} {:>8.2}",
+                label,
+                ms(l.min),
+                ms(l.mean),
+                ms(l.p50),
+                ms(l.p90),
+                ms(l.p99),
+                ms(l.max)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Spawn a TCP echo server on an ephemeral loopback port
+pub async fn spawn_echo_server() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut stream, _)) = listener.accept().await else {
+                continue;
+            };
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// Run the load and aggregate the results
+pub async fn run(options: LoadgenOptions) -> Result<LoadReport> {
+    let echo = match options.echo {
+        Some(addr) => addr,
+        None => spawn_echo_server().await?,
+    };
+    let payload = Arc::new(vec![0x5Au8; options.payload_bytes]);
+    let permits = Arc::new(Semaphore::new(options.concurrency.max(1)));
+
+    let started = Instant::now();
+    let mut tasks = Vec::with_capacity(options.connections);
+    for _ in 0..options.connections {
+        let permit = permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| ProxyError::Protocol(e.to_string()))?;
+        let payload = payload.clone();
+        tasks.push(tokio::spawn(async move {
+            let result = run_connection(options.proxy, echo, &payload).await;
+            drop(permit);
+            result
+        }));
+    }
+
+    let mut samples = Vec::with_capacity(tasks.len());
+    let mut failed = 0;
+    for task in tasks {
+        match task.await {
+            Ok(Ok(sample)) => samples.push(sample),
+            Ok(Err(e)) => {
+                debug!("Load connection failed: {}", e);
+                failed += 1;
+            }
+            Err(e) => {
+                warn!("Load task panicked: {}", e);
+                failed += 1;
+            }
+        }
+    }
+
+    Ok(LoadReport::from_samples(&samples, failed, started.elapsed()))
+}
+
+async fn run_connection(proxy: SocketAddr, echo: SocketAddr, payload: &[u8]) -> Result<ConnectionSample> {
+    let started = Instant::now();
+    let mut stream = TcpStream::connect(proxy).await?;
+    stream.set_nodelay(true)?;
+    socks5_client_connect(&mut stream, echo).await?;
+    let connect = started.elapsed();
+
+    // 同时发送与接收，避免大负载时双方缓冲区写满互相等待
+    let (mut reader, mut writer) = stream.split();
+    let send = async {
+        writer.write_all(payload).await?;
+        writer.shutdown().await
+    };
+    let receive = async {
+        let mut echoed = vec![0u8; payload.len()];
+        reader.read_exact(&mut echoed).await.map(|_| ())
+    };
+    tokio::try_join!(send, receive)?;
+
+    Ok(ConnectionSample {
+        connect,
+        total: started.elapsed(),
+        bytes: payload.len() as u64 * 2,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn test_latency_percentiles() {
+        // 乱序输入，1..=100ms
+        let durations: Vec<Duration> = (1..=100).rev().map(ms).collect();
+        let summary = LatencySummary::from_durations(durations);
+
+        assert_eq!(summary.min, ms(1));
+        assert_eq!(summary.max, ms(100));
+        assert_eq!(summary.p50, ms(50));
+        assert_eq!(summary.p90, ms(90));
+        assert_eq!(summary.p99, ms(99));
+        assert_eq!(summary.mean, Duration::from_micros(50_500));
+
+        let single = LatencySummary::from_durations(vec![ms(7)]);
+        assert_eq!((single.p50, single.p99), (ms(7), ms(7)));
+        assert_eq!(LatencySummary::from_durations(Vec::new()), LatencySummary::default());
+    }
+
+    #[test]
+    fn test_report_rates() {
+        let samples: Vec<ConnectionSample> = (0..10)
+            .map(|i| ConnectionSample {
+                connect: ms(i + 1),
+                total: ms(10 * (i + 1)),
+                bytes: 2048,
+            })
+            .collect();
+        let report = LoadReport::from_samples(&samples, 2, Duration::from_secs(2));
+
+        assert_eq!(report.succeeded, 10);
+        assert_eq!(report.failed, 2);
+        assert_eq!(report.bytes, 20480);
+        assert_eq!(report.connections_per_sec(), 5.0);
+        assert_eq!(report.throughput(), 10240.0);
+        assert_eq!(report.total_latency.p90, ms(90));
+        assert_eq!(LoadReport::from_samples(&[], 0, Duration::ZERO).throughput(), 0.0);
+    }
+}
@@ -0,0 +1,502 @@
+//! Log file writer with built-in rotation
+//!
+//! `logging.file` (and with it the access log) goes through a
+//! [`RotatingFile`]: when the live file reaches `rotate_size_mb`, or at the
+//! configured UTC hour each day, it is renamed to `<file>.<unix_secs>-<seq>`
+//! and a fresh file is opened under the original name. The rename happens
+//! between two records on the single writer, so no record is lost or split.
+//! Rotated files are gzip-compressed on a background thread when `compress`
+//! is set, and only the newest `keep_files` of them are kept.
+//!
+//! When the live file is moved or deleted from outside (logrotate without
+//! `copytruncate`), the change of inode is noticed within a second and the
+//! file is reopened under its configured path.
+//!
+//! [`init_logging`] installs the process logger: the file sits behind a
+//! [`BackgroundWriter`], so tasks that log never wait on the disk or on a
+//! rotation, and `structured` turns every record into a JSON line.
+
+use crate::config::LoggingConfig;
+use crate::error::{ProxyError, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::warn;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often the live file's inode is compared with the open one
+const REOPEN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Records queued for the writer thread before new ones are dropped
+const WRITER_QUEUE_RECORDS: usize = 8192;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Device and inode number
+type FileId = (u64, u64);
+
+/// When to rotate and what to keep
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RotationPolicy {
+    /// Rotate once the live file reaches this many bytes
+    pub max_bytes: Option<u64>,
+    /// Rotate every day at this UTC hour
+    pub daily_at_hour: Option<u8>,
+    /// Rotated files kept; older ones are deleted
+    pub keep_files: usize,
+    /// Gzip rotated files in the background
+    pub compress: bool,
+}
+
+impl RotationPolicy {
+    pub fn from_config(config: &LoggingConfig) -> Self {
+        Self {
+            max_bytes: (config.rotate_size_mb > 0).then(|| config.rotate_size_mb * 1024 * 1024),
+            daily_at_hour: config.rotate_daily,
+            keep_files: config.keep_files,
+            compress: config.compress,
+        }
+    }
+}
+
+/// Append-only log file rotated by size and/or time
+pub struct RotatingFile {
+    path: PathBuf,
+    policy: RotationPolicy,
+    file: File,
+    /// Bytes in the live file
+    size: u64,
+    id: Option<FileId>,
+    next_daily: Option<SystemTime>,
+    last_reopen_check: Instant,
+    reopen_check_interval: Duration,
+    /// Sequence number keeping names of rotations within one second apart
+    sequence: u64,
+    /// Background compression of the latest rotation; each one waits for the one before
+    compression: Option<JoinHandle<()>>,
+}
+
+impl RotatingFile {
+    pub fn open(path: impl Into<PathBuf>, policy: RotationPolicy) -> Result<Self> {
+        let path = path.into();
+        let (file, size, id) = open_append(&path)?;
+        let next_daily = policy.daily_at_hour.map(|hour| next_daily_rotation(SystemTime::now(), hour));
+        Ok(Self {
+            path,
+            policy,
+            file,
+            size,
+            id,
+            next_daily,
+            last_reopen_check: Instant::now(),
+            reopen_check_interval: REOPEN_CHECK_INTERVAL,
+            sequence: 0,
+            compression: None,
+        })
+    }
+
+    /// Path of the live file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Rotated files, oldest first (compressed ones end in `.gz`)
+    pub fn rotated_files(&self) -> Vec<PathBuf> {
+        let mut files = rotated_generations(&self.path);
+        files.sort();
+        files.into_iter().map(|(_, path)| path).collect()
+    }
+
+    /// Move the live file aside and continue in a new one
+    pub fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let rotated = loop {
+            self.sequence += 1;
+            let candidate = rotated_path(&self.path, secs, self.sequence);
+            if !candidate.exists() && !gz_path(&candidate).exists() {
+                break candidate;
+            }
+        };
+        // rename 是原子的：旧文件完整地换到新名字下，之后的记录写进新文件
+        fs::rename(&self.path, &rotated)?;
+        let (file, size, id) = open_append(&self.path)?;
+        self.file = file;
+        self.size = size;
+        self.id = id;
+
+        if self.policy.compress {
+            // 压缩按轮转顺序串行进行，清理放在压缩之后，避免删掉正在压缩的文件
+            let previous = self.compression.take();
+            let (path, keep_files) = (self.path.clone(), self.policy.keep_files);
+            self.compression = Some(std::thread::spawn(move || {
+                if let Some(previous) = previous {
+                    let _ = previous.join();
+                }
+                if let Err(e) = compress(&rotated) {
+                    warn!("Failed to compress rotated log {}: {}", rotated.display(), e);
+                }
+                prune(&path, keep_files);
+            }));
+        } else {
+            prune(&self.path, self.policy.keep_files);
+        }
+        Ok(())
+    }
+
+    /// Wait for background compressions started so far
+    pub fn wait_for_compression(&mut self) {
+        if let Some(handle) = self.compression.take() {
+            let _ = handle.join();
+        }
+    }
+
+    fn due(&mut self, incoming: usize) -> bool {
+        let by_size = self
+            .policy
+            .max_bytes
+            .is_some_and(|max_bytes| self.size > 0 && self.size + incoming as u64 > max_bytes);
+        let by_time = match self.next_daily {
+            Some(at) if SystemTime::now() >= at => {
+                self.next_daily = self.policy.daily_at_hour.map(|hour| next_daily_rotation(SystemTime::now(), hour));
+                self.size > 0
+            }
+            _ => false,
+        };
+        by_size || by_time
+    }
+
+    /// Reopen the configured path when the open file was moved or deleted
+    fn reopen_if_moved(&mut self) -> io::Result<()> {
+        if self.last_reopen_check.elapsed() < self.reopen_check_interval {
+            return Ok(());
+        }
+        self.last_reopen_check = Instant::now();
+        let moved = match fs::metadata(&self.path) {
+            Ok(metadata) => file_id(&metadata) != self.id,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+            Err(e) => return Err(e),
+        };
+        if moved {
+            let _ = self.file.flush();
+            let (file, size, id) = open_append(&self.path)?;
+            self.file = file;
+            self.size = size;
+            self.id = id;
+        }
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    /// Write one record; rotation only happens between records
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.reopen_if_moved()?;
+        if self.due(buf.len()) {
+            if let Err(e) = self.rotate() {
+                // 轮转失败时继续写当前文件，不丢记录
+                eprintln!("Failed to rotate {}: {}", self.path.display(), e);
+            }
+        }
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// `Write` handing every record to a thread that owns the real writer
+///
+/// Writes only queue the record, so logging from async tasks never blocks on
+/// the file, a rotation or a compression. When the thread falls
+/// `WRITER_QUEUE_RECORDS` behind, further records are dropped and counted.
+pub struct BackgroundWriter {
+    queue: SyncSender<Vec<u8>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl BackgroundWriter {
+    pub fn spawn<W: Write + Send + 'static>(mut writer: W) -> io::Result<Self> {
+        let (queue, records) = sync_channel::<Vec<u8>>(WRITER_QUEUE_RECORDS);
+        std::thread::Builder::new().name("log-writer".to_string()).spawn(move || {
+            for record in records {
+                if let Err(e) = writer.write_all(&record) {
+                    // 写日志本身失败时不能再走 log
+                    eprintln!("Failed to write log record: {}", e);
+                }
+            }
+            let _ = writer.flush();
+        })?;
+        Ok(Self { queue, dropped: Arc::new(AtomicU64::new(0)) })
+    }
+
+    /// Records dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Write for BackgroundWriter {
+    /// Queue one record; never blocks
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.queue.try_send(buf.to_vec()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "log writer thread exited"));
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// One log record as a JSON line: timestamp, level, target and message
+fn json_record(timestamp: &str, record: &log::Record) -> String {
+    serde_json::json!({
+        "timestamp": timestamp,
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    })
+    .to_string()
+}
+
+/// Install the process logger from `[logging]`
+///
+/// `RUST_LOG` takes precedence over `level`. With `file` set, records go to
+/// a [`RotatingFile`] through a [`BackgroundWriter`]; otherwise to stderr.
+pub fn init_logging(config: &LoggingConfig) -> Result<()> {
+    let mut logger = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(&config.level));
+    if config.structured {
+        logger.format(|buf, record| {
+            let timestamp = buf.timestamp_millis().to_string();
+            writeln!(buf, "{}", json_record(&timestamp, record))
+        });
+    }
+    if let Some(path) = config.file.as_deref().filter(|path| !path.is_empty()) {
+        let file = RotatingFile::open(path, RotationPolicy::from_config(config))?;
+        let writer = BackgroundWriter::spawn(file)?;
+        logger.target(env_logger::Target::Pipe(Box::new(writer))).write_style(env_logger::WriteStyle::Never);
+    }
+    logger.try_init().map_err(|_| ProxyError::AlreadyInitialized("logger"))
+}
+
+/// Delete rotated files of `path` beyond the newest `keep_files`
+fn prune(path: &Path, keep_files: usize) {
+    let mut generations = rotated_generations(path);
+    generations.sort();
+    let excess = generations.len().saturating_sub(keep_files);
+    for (_, path) in &generations[..excess] {
+        if let Err(e) = fs::remove_file(path) {
+            // 可能在日志写入端持锁时调用，不能再走 log
+            eprintln!("Failed to delete old log {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<(File, u64, Option<FileId>)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let metadata = file.metadata()?;
+    Ok((file, metadata.len(), file_id(&metadata)))
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &fs::Metadata) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &fs::Metadata) -> Option<FileId> {
+    None
+}
+
+fn rotated_path(path: &Path, secs: u64, sequence: u64) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}-{}", secs, sequence));
+    PathBuf::from(name)
+}
+
+fn gz_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".gz");
+    PathBuf::from(name)
+}
+
+/// Rotated files of `path` with their (unix_secs, sequence) generation
+fn rotated_generations(path: &Path) -> Vec<((u64, u64), PathBuf)> {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name().and_then(|name| name.to_str())) else {
+        return Vec::new();
+    };
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let file_name = entry.file_name();
+            let suffix = file_name.to_str()?.strip_prefix(name)?.strip_prefix('.')?;
+            let suffix = suffix.strip_suffix(".gz").unwrap_or(suffix);
+            let (secs, sequence) = suffix.split_once('-')?;
+            Some(((secs.parse().ok()?, sequence.parse().ok()?), path.with_file_name(file_name)))
+        })
+        .collect()
+}
+
+/// Gzip `path` into `path.gz` and delete the original
+fn compress(path: &Path) -> io::Result<()> {
+    let target = gz_path(path);
+    let partial = target.with_extension("gz.tmp");
+    let mut encoder = GzEncoder::new(BufWriter::new(File::create(&partial)?), Compression::default());
+    io::copy(&mut BufReader::new(File::open(path)?), &mut encoder)?;
+    encoder.finish()?.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    // 写完后再改名，.gz 出现时内容一定完整
+    fs::rename(&partial, &target)?;
+    fs::remove_file(path)
+}
+
+/// First time after `now` at `hour` o'clock UTC
+fn next_daily_rotation(now: SystemTime, hour: u8) -> SystemTime {
+    let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let today = secs - secs % SECS_PER_DAY + hour as u64 * 3600;
+    let next = if today > secs { today } else { today + SECS_PER_DAY };
+    UNIX_EPOCH + Duration::from_secs(next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("anybls-log-file-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn record(id: usize) -> String {
+        format!("record {:04} {}\n", id, "x".repeat(80))
+    }
+
+    #[test]
+    fn test_background_writer_keeps_record_order() {
+        let dir = temp_dir("background");
+        let path = dir.join("proxy.log");
+        let policy = RotationPolicy { max_bytes: Some(1000), keep_files: 100, ..RotationPolicy::default() };
+        let mut writer = BackgroundWriter::spawn(RotatingFile::open(&path, policy).unwrap()).unwrap();
+        for id in 0..100 {
+            writer.write_all(record(id).as_bytes()).unwrap();
+        }
+
+        // 写线程异步落盘，轮转也在那边发生：等到最后一条出现
+        let expected: String = (0..100).map(record).collect();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let contents = loop {
+            let mut generations = rotated_generations(&path);
+            generations.sort();
+            let mut contents: String = generations.iter().map(|(_, path)| fs::read_to_string(path).unwrap()).collect();
+            contents.push_str(&fs::read_to_string(&path).unwrap());
+            if contents.len() >= expected.len() || Instant::now() > deadline {
+                break contents;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(writer.dropped(), 0);
+        assert_eq!(contents, expected, "records lost or reordered");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_json_record_fields() {
+        let line = json_record(
+            "2026-01-02T03:04:05.678Z",
+            &log::Record::builder()
+                .args(format_args!("Relay completed \"ok\""))
+                .level(log::Level::Warn)
+                .target("anybls::zero_copy")
+                .build(),
+        );
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["timestamp"], "2026-01-02T03:04:05.678Z");
+        assert_eq!(value["level"], "WARN");
+        assert_eq!(value["target"], "anybls::zero_copy");
+        assert_eq!(value["message"], "Relay completed \"ok\"");
+    }
+
+    #[test]
+    fn test_size_rotation_keeps_and_compresses() {
+        let dir = temp_dir("size");
+        let path = dir.join("access.log");
+        let policy = RotationPolicy { max_bytes: Some(1000), keep_files: 2, compress: true, ..RotationPolicy::default() };
+        let mut file = RotatingFile::open(&path, policy).unwrap();
+        for id in 0..40 {
+            file.write_all(record(id).as_bytes()).unwrap();
+        }
+        file.wait_for_compression();
+
+        // 每个文件 10 条记录：三次轮转后只保留最新两个压缩文件，活动文件继续写
+        let rotated = file.rotated_files();
+        assert_eq!(rotated.len(), 2, "{:?}", rotated);
+        let mut contents = String::new();
+        for path in &rotated {
+            assert_eq!(path.extension().unwrap(), "gz");
+            GzDecoder::new(File::open(path).unwrap()).read_to_string(&mut contents).unwrap();
+        }
+        contents.push_str(&fs::read_to_string(&path).unwrap());
+        let expected: String = (0..40).map(record).collect();
+        assert!(expected.ends_with(&contents), "records lost or reordered");
+        assert_eq!(contents.lines().count(), 30, "the oldest generation must be deleted");
+        assert!(fs::metadata(&path).unwrap().len() <= 1000);
+        assert!(!fs::read_dir(&dir).unwrap().any(|e| e.unwrap().path().extension().unwrap() == "tmp"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_moved_file_is_reopened() {
+        let dir = temp_dir("moved");
+        let path = dir.join("proxy.log");
+        let mut file = RotatingFile::open(&path, RotationPolicy::default()).unwrap();
+        file.reopen_check_interval = Duration::ZERO;
+        file.write_all(b"before\n").unwrap();
+
+        // 外部 logrotate 把文件移走（不使用 copytruncate）
+        let moved = dir.join("proxy.log.1");
+        fs::rename(&path, &moved).unwrap();
+        file.write_all(b"after\n").unwrap();
+        file.flush().unwrap();
+        assert_eq!(fs::read_to_string(&moved).unwrap(), "before\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "after\n");
+
+        fs::remove_file(&path).unwrap();
+        file.write_all(b"recreated\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "recreated\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_next_daily_rotation() {
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        // 1970-01-02 01:00 UTC
+        let now = at(SECS_PER_DAY + 3600);
+        assert_eq!(next_daily_rotation(now, 3), at(SECS_PER_DAY + 3 * 3600));
+        assert_eq!(next_daily_rotation(now, 1), at(2 * SECS_PER_DAY + 3600));
+        assert_eq!(next_daily_rotation(now, 0), at(2 * SECS_PER_DAY));
+    }
+}
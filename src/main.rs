@@ -1,13 +1,36 @@
+use anybls::access_log::init_global_access_log;
+use anybls::blocked::{init_global_blocked_traffic, start_blocked_summary};
+use anybls::buffer_pool::init_global_buffer_pool;
+use anybls::capture::{init_global_capture, replay};
+use anybls::clash_api::start_clash_api;
 use anybls::config::{init_global_config, Config};
+use anybls::scope::ScopedIp;
 use anybls::connection_pool::{init_global_connection_pool, start_connection_pool_cleanup};
-use anybls::dns::init_global_dns_resolver;
-use anybls::error::Result;
-use anybls::outbound::init_global_outbound_manager;
-use anybls::proxy::Socks5Proxy;
+use anybls::connection_rate::init_global_connection_rate_limiter;
+use anybls::dns::{init_global_dns_resolver, start_dns_cache_cleanup, start_dns_prefetch};
+use anybls::fake_ip::{init_global_fake_ip_pool, start_fake_ip_cleanup};
+use anybls::health::start_health_server;
+use anybls::inbound::{init_global_listener_registry, InboundContext, InboundManager, InboundSpec};
+use anybls::error::{ProxyError, Result};
+use anybls::listener::{init_global_listener_options, ListenerOptions};
+use anybls::loadgen::{self, LoadgenOptions};
+use anybls::log_file::init_logging;
+use anybls::negative_cache::init_global_negative_cache;
+use anybls::outbound::{init_global_outbound_manager, start_url_tests};
+use anybls::pac::{generate_pac, start_pac_server};
+use anybls::rebinding::init_global_rebinding_guard;
+use anybls::reload::start_reload_on_sighup;
+use anybls::rule_set_downloader::{CacheCheck, RuleSetDownloader};
+use anybls::routing::diff::{diff_configs, parse_samples};
+use anybls::routing::{build_router, set_global_router, start_rule_set_updates};
+use anybls::tasks::{get_global_task_tracker, TaskGroup};
 use anybls::traffic_mark::{init_global_traffic_mark_config, TrafficMarkConfig};
-use clap::Parser;
+use anybls::watchdog::start_watchdog;
+use anybls::zero_copy::init_relay_backend;
+use clap::{Parser, Subcommand};
 use log::{error, info};
-use std::net::{IpAddr, SocketAddr};
+use std::net::SocketAddr;
+use std::sync::Arc;
 
 #[derive(Parser)]
 #[command(name = "anybls")]
@@ -17,23 +40,205 @@ struct Args {
     #[arg(short, long, default_value = "1080")]
     port: u16,
 
-    /// IP address to bind to
+    /// IP address to bind to (IPv6 link-local addresses take a zone, e.g. fe80::1%eth0)
     #[arg(long, default_value = "127.0.0.1")]
-    host: IpAddr,
+    host: ScopedIp,
 
     /// Enable debug logging
     #[arg(short, long)]
     debug: bool,
 
-    /// Configuration file path
+    /// Configuration file path (TOML, or sing-box style RON or JSON)
     #[arg(short, long)]
     config: Option<String>,
+
+    /// Route and resolve connections but never dial (server.dry_run)
+    #[arg(long)]
+    dry_run: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Drive load through a running instance and report throughput and latency
+    Loadgen {
+        /// SOCKS5 address of the instance under test
+        #[arg(long)]
+        target: SocketAddr,
+        /// Total connections to open
+        #[arg(long, default_value = "1000")]
+        connections: usize,
+        /// Connections in flight at once
+        #[arg(long, default_value = "64")]
+        concurrency: usize,
+        /// Bytes echoed through each connection
+        #[arg(long, default_value = "16384")]
+        payload_bytes: usize,
+        /// Echo server to tunnel to (a local one is started by default)
+        #[arg(long)]
+        echo: Option<SocketAddr>,
+    },
+    /// Compare the routing of two configs, optionally on sample domains/IPs
+    Diff {
+        /// Current configuration
+        #[arg(short = 'c', long)]
+        old: String,
+        /// Proposed configuration
+        #[arg(short = 'C', long)]
+        new: String,
+        /// File of domains and IPs, one per line, to route with both configs
+        #[arg(long)]
+        sample: Option<String>,
+    },
+    /// Print the routing table of a config as JSON
+    Dump {
+        /// Configuration file path
+        #[arg(short, long)]
+        config: String,
+    },
+    /// Write the PAC (proxy auto-config) script of a config
+    Pac {
+        /// Configuration file path
+        #[arg(short, long)]
+        config: String,
+        /// Output file (stdout when omitted)
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Inspect downloaded rule sets
+    Rules {
+        #[command(subcommand)]
+        command: RulesCommand,
+    },
+    /// Work with traffic captures (capture.dir)
+    Capture {
+        #[command(subcommand)]
+        command: CaptureCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum CaptureCommand {
+    /// Send the client-side bytes of a capture to its target again
+    Replay {
+        /// Capture directory, e.g. captures/1700000000000-1
+        dir: String,
+        /// Target to replay against instead of the captured one (host:port)
+        #[arg(long)]
+        target: Option<String>,
+        /// Stop reading once the target is silent this long
+        #[arg(long, default_value = "5")]
+        idle_timeout_secs: u64,
+        /// Write the bytes the target sent back to this file
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum RulesCommand {
+    /// Re-check every cached rule set against the SHA-256 recorded at download
+    Verify {
+        /// Configuration file whose rule set cache to check (defaults otherwise)
+        #[arg(short, long)]
+        config: Option<String>,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
+    if let Some(Command::Loadgen { target, connections, concurrency, payload_bytes, echo }) = args.command {
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+        let report = loadgen::run(LoadgenOptions {
+            proxy: target,
+            connections,
+            concurrency,
+            payload_bytes,
+            echo,
+        })
+        .await?;
+        println!("{}", report);
+        return Ok(());
+    }
+    if let Some(Command::Diff { old, new, sample }) = &args.command {
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+        let samples = match sample {
+            Some(path) => parse_samples(&std::fs::read_to_string(path)?),
+            None => Vec::new(),
+        };
+        let diff = diff_configs(&Config::from_file(old)?, &Config::from_file(new)?, &samples).await?;
+        println!("{}", diff);
+        return Ok(());
+    }
+    if let Some(Command::Dump { config }) = &args.command {
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+        let config = Config::from_file(config)?;
+        config.validate()?;
+        let dump = build_router(&config).await?.dump();
+        let json = serde_json::to_string_pretty(&dump)
+            .map_err(|e| anybls::error::ProxyError::Protocol(format!("Failed to serialize routing table: {}", e)))?;
+        println!("{}", json);
+        return Ok(());
+    }
+
+    if let Some(Command::Pac { config, output }) = &args.command {
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+        let pac = generate_pac(&Config::from_file(config)?).await?;
+        match output {
+            Some(path) => {
+                std::fs::write(path, &pac.script)?;
+                eprintln!(
+                    "Wrote {} ({} bytes, {} domain entries, {} omitted, {} regex/IP entries left to the proxy)",
+                    path,
+                    pac.script.len(),
+                    pac.entries,
+                    pac.omitted,
+                    pac.unsupported
+                );
+            }
+            None => print!("{}", pac.script),
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Rules { command: RulesCommand::Verify { config } }) = &args.command {
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+        let config = match config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        let downloader = RuleSetDownloader::new(&config.router.rule_set_cache_dir)?;
+        let checks = downloader.verify_cache();
+        for (tag, check) in &checks {
+            println!("{}: {}", tag, check);
+        }
+        let failed = checks.iter().filter(|(_, check)| *check != CacheCheck::Ok).count();
+        if failed > 0 {
+            return Err(ProxyError::Integrity(format!("{} of {} cached rule sets failed verification", failed, checks.len())));
+        }
+        println!("{} cached rule sets verified", checks.len());
+        return Ok(());
+    }
+
+    if let Some(Command::Capture { command: CaptureCommand::Replay { dir, target, idle_timeout_secs, output } }) = &args.command {
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+        let report = replay(
+            std::path::Path::new(dir),
+            target.as_deref(),
+            std::time::Duration::from_secs(*idle_timeout_secs),
+        )
+        .await?;
+        if let Some(path) = output {
+            std::fs::write(path, &report.received)?;
+        }
+        println!("{}", report);
+        return Ok(());
+    }
+
     // Load configuration
     let mut config = if let Some(config_path) = &args.config {
         Config::from_file(config_path)?
@@ -47,22 +252,53 @@ async fn main() -> Result<()> {
     }
     config.server.host = args.host;
     config.server.port = args.port;
+    if args.dry_run {
+        config.server.dry_run = true;
+    }
 
     // Initialize global configuration
     init_global_config(config.clone())?;
 
     // Initialize logging
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(&config.logging.level))
-        .init();
+    init_logging(&config.logging)?;
+
+    init_global_access_log(&config.logging)?;
+    init_global_listener_options(ListenerOptions::from_config(&config));
+    init_global_buffer_pool(&config.performance);
+    init_relay_backend(&config.performance);
+    init_global_listener_registry(config.loop_protection.clone());
+    init_global_rebinding_guard(&config.rebinding_protection)?;
+    init_global_negative_cache(&config.negative_cache);
+    init_global_capture(&config.capture)?;
+    init_global_blocked_traffic(&config.blocked);
+    start_blocked_summary(&config.blocked);
 
     // Initialize DNS resolver
-    init_global_dns_resolver()?;
+    init_global_dns_resolver(&config.dns, config.logging.enable_metrics)?;
+    start_dns_prefetch();
+    start_dns_cache_cleanup();
+    init_global_fake_ip_pool(&config.dns.fake_ip)?;
+    start_fake_ip_cleanup();
     info!("DNS resolver initialized");
 
     // Initialize outbounds and router
-    init_global_outbound_manager(&config.outbounds)?;
-    // 路由器初始化已移除，现在使用高性能路由器
-    info!("Outbounds and router initialized");
+    init_global_outbound_manager(&config.outbounds, config.on_outbound_error)?;
+    start_url_tests();
+    init_global_connection_rate_limiter(&config);
+    let router = build_router(&config).await?;
+    info!(
+        "Outbounds and router initialized ({} rules, {} rule sets)",
+        router.rule_count(),
+        router.rule_set_count()
+    );
+    set_global_router(router);
+    start_rule_set_updates(Arc::new(config.clone()));
+    if let Some(config_path) = &args.config {
+        start_reload_on_sighup(config_path.into())?;
+        info!("Send SIGHUP to reload outbounds and routing from {}", config_path);
+    }
+    start_pac_server(&config).await?;
+    start_clash_api(&config).await?;
 
     // Initialize connection pool
     init_global_connection_pool(
@@ -70,6 +306,9 @@ async fn main() -> Result<()> {
         config.connection_pool.max_total_connections,
         config.pool_connection_timeout(),
         config.pool_idle_timeout(),
+        config.connection_pool.on_exhausted,
+        config.pool_wait_timeout(),
+        config.connection_pool.auto_tune.clone(),
     )?;
     info!("Connection pool initialized");
 
@@ -81,23 +320,42 @@ async fn main() -> Result<()> {
         if config.traffic_mark.so_mark > 0 { Some(config.traffic_mark.so_mark) } else { None },
         if config.traffic_mark.net_service_type > 0 { Some(config.traffic_mark.net_service_type) } else { None },
     );
-    init_global_traffic_mark_config(traffic_mark_config);
+    init_global_traffic_mark_config(traffic_mark_config)?;
     info!("Traffic marking initialized");
 
-    info!("Starting SOCKS5 proxy server...");
+    // 连接任务数上限即 max_connections，满员时新连接排队或被拒绝；拒绝应答同样限量
+    get_global_task_tracker().set_limit(TaskGroup::InboundConns, Some(config.server.max_connections));
+    get_global_task_tracker().set_limit(TaskGroup::Refusals, Some(config.server.max_connections));
+
+    // Start slow-connection watchdog
+    start_watchdog(config.watchdog.clone());
+
+    let specs: Vec<InboundSpec> = config.inbound_configs().iter().map(InboundSpec::from_config).collect();
+    info!("Starting proxy server...");
     info!("Configuration:");
-    info!("  Host: {}", config.server.host);
-    info!("  Port: {}", config.server.port);
-    info!("  Max connections: {}", config.server.max_connections);
+    for spec in &specs {
+        info!("  Inbound {}: {:?} on {}", spec.tag, spec.kind, spec.bind_addr);
+    }
+    info!(
+        "  Max connections: {} ({} when full)",
+        config.server.max_connections,
+        if config.server.reject_when_full { "reject" } else { "queue" }
+    );
     info!("  SO_MARK: {}", config.traffic_mark.so_mark);
     info!("  SO_NET_SERVICE_TYPE: {}", config.traffic_mark.net_service_type);
     info!("  Debug: {}", args.debug);
+    if config.server.dry_run {
+        info!("  Dry run: connections are routed but never dialed (reply {:#04x})", config.server.dry_run_reply);
+    }
 
-    let bind_addr = SocketAddr::new(config.server.host, config.server.port);
-    let proxy = Socks5Proxy::new(bind_addr);
-
-    // Start the proxy server
-    if let Err(e) = proxy.start().await {
+    // 每个入站单独启动，个别失败只记录日志，全部失败才退出
+    let mut inbounds = InboundManager::new();
+    if let Err(e) = inbounds.start(&specs, &InboundContext::global()).await {
+        error!("Proxy server error: {}", e);
+        return Err(e);
+    }
+    start_health_server(&config, inbounds.local_addrs()).await?;
+    if let Err(e) = inbounds.join().await {
         error!("Proxy server error: {}", e);
         return Err(e);
     }
@@ -108,7 +366,8 @@ async fn main() -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::net::Ipv4Addr;
+    use anybls::proxy::Socks5Proxy;
+    use std::net::{IpAddr, Ipv4Addr};
     use tokio::net::TcpStream;
 
     #[tokio::test]
@@ -0,0 +1,147 @@
+// 直连负缓存：最近连接超时的地址在 TTL 内直接失败，不再为丢弃 SYN 的网络空等整个连接超时
+use crate::config::NegativeCacheConfig;
+use crate::error::ProxyError;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Negative cache counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NegativeCacheStats {
+    /// Addresses currently remembered as failing
+    pub entries: usize,
+    /// Connects failed from the cache without dialing
+    pub suppressed: u64,
+}
+
+struct Failure {
+    kind: io::ErrorKind,
+    at: Instant,
+}
+
+/// Bounded map of recently unreachable addresses
+pub struct NegativeCache {
+    ttl: Duration,
+    max_entries: usize,
+    failures: Mutex<HashMap<SocketAddr, Failure>>,
+    suppressed: AtomicU64,
+}
+
+impl NegativeCache {
+    pub fn new(config: &NegativeCacheConfig) -> Self {
+        Self {
+            ttl: Duration::from_secs(config.ttl_secs),
+            max_entries: config.max_entries,
+            failures: Mutex::new(HashMap::new()),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        !self.ttl.is_zero() && self.max_entries > 0
+    }
+
+    /// The cached error for `addr` if it timed out less than `ttl` ago
+    pub fn check(&self, addr: SocketAddr) -> Option<ProxyError> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let mut failures = self.failures.lock().unwrap();
+        let failure = failures.get(&addr)?;
+        let age = failure.at.elapsed();
+        if age >= self.ttl {
+            failures.remove(&addr);
+            return None;
+        }
+        self.suppressed.fetch_add(1, Ordering::Relaxed);
+        Some(ProxyError::Io(io::Error::new(
+            failure.kind,
+            format!(
+                "{} connecting to {} {}ms ago (recent failure, suppressed retry)",
+                failure.kind,
+                addr,
+                age.as_millis()
+            ),
+        )))
+    }
+
+    /// Remember a failed connect; only timeouts are cached, refusals never are
+    pub fn record_failure(&self, addr: SocketAddr, kind: io::ErrorKind) {
+        if !self.is_enabled() || kind != io::ErrorKind::TimedOut {
+            return;
+        }
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= self.max_entries && !failures.contains_key(&addr) {
+            failures.retain(|_, failure| now.duration_since(failure.at) < self.ttl);
+            if failures.len() >= self.max_entries {
+                let oldest = failures.iter().min_by_key(|(_, failure)| failure.at).map(|(addr, _)| *addr);
+                if let Some(oldest) = oldest {
+                    failures.remove(&oldest);
+                }
+            }
+        }
+        failures.insert(addr, Failure { kind, at: now });
+    }
+
+    /// Forget `addr` after a successful connect
+    pub fn record_success(&self, addr: SocketAddr) {
+        if self.is_enabled() {
+            self.failures.lock().unwrap().remove(&addr);
+        }
+    }
+
+    pub fn stats(&self) -> NegativeCacheStats {
+        NegativeCacheStats {
+            entries: self.failures.lock().unwrap().len(),
+            suppressed: self.suppressed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+static GLOBAL_NEGATIVE_CACHE: OnceLock<NegativeCache> = OnceLock::new();
+
+/// Initialize the global negative cache
+pub fn init_global_negative_cache(config: &NegativeCacheConfig) {
+    let _ = GLOBAL_NEGATIVE_CACHE.set(NegativeCache::new(config));
+}
+
+/// Get the global negative cache (default settings when not initialized)
+pub fn get_global_negative_cache() -> &'static NegativeCache {
+    GLOBAL_NEGATIVE_CACHE.get_or_init(|| NegativeCache::new(&NegativeCacheConfig::default()))
+}
+
+/// Counters of the global negative cache
+pub fn negative_cache_stats() -> NegativeCacheStats {
+    get_global_negative_cache().stats()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_only_timeouts_cached_and_bounded() {
+        let cache = NegativeCache::new(&NegativeCacheConfig { ttl_secs: 10, max_entries: 2 });
+        let addr = |port: u16| SocketAddr::from(([192, 0, 2, 1], port));
+
+        cache.record_failure(addr(1), io::ErrorKind::ConnectionRefused);
+        assert!(cache.check(addr(1)).is_none());
+
+        for port in 1..=3 {
+            cache.record_failure(addr(port), io::ErrorKind::TimedOut);
+            tokio::time::advance(Duration::from_millis(1)).await;
+        }
+        assert_eq!(cache.stats().entries, 2);
+        assert!(cache.check(addr(1)).is_none());
+        assert!(cache.check(addr(3)).is_some());
+
+        cache.record_success(addr(3));
+        assert!(cache.check(addr(3)).is_none());
+        assert_eq!(cache.stats(), NegativeCacheStats { entries: 1, suppressed: 1 });
+    }
+}
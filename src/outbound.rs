@@ -1,63 +1,1362 @@
-use crate::config::{OutboundConfig, OutboundType};
+use crate::config::{validate_outbound_graph, OutboundConfig, OutboundErrorPolicy, OutboundType, BUILTIN_OUTBOUNDS};
+use crate::diagnostics::{ConnectDiagnostics, DnsSource};
+use crate::dns::try_get_global_dns_resolver;
+use crate::endpoint::ServerEndpoint;
 use crate::error::{ProxyError, Result};
+use crate::negative_cache::{get_global_negative_cache, NegativeCache};
+use crate::protocol::{Address, TargetAddr};
+use crate::rebinding::{get_global_rebinding_guard, RebindingGuard};
 use crate::protocols::{
-    BlackholeProtocol, DirectProtocol, Protocol, Socks5Protocol, VlessProtocol,
+    BlackholeProtocol, ChainProtocol, DirectProtocol, DisabledProtocol, HttpProtocol, OutboundCapabilities, Protocol,
+    ShadowsocksProtocol, Socks5Protocol, VlessProtocol,
 };
+use crate::stream::ProxyStream;
+use crate::tasks::{get_global_task_tracker, TaskGroup};
+use crate::tls::{TlsClient, TlsClientOptions};
+use crate::tls_fragment::TlsFragmentConfig;
+use crate::traffic_mark::{DialOptions, LingerPolicy};
 use async_trait::async_trait;
-use std::net::SocketAddr;
+use ipnet::IpNet;
+use log::{debug, error, info, warn};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
 
 #[async_trait]
 pub trait OutboundConnector: Send + Sync {
-    async fn connect(&self, target: SocketAddr) -> Result<TcpStream>;
+    async fn connect(&self, target: &TargetAddr) -> Result<ProxyStream>;
 }
 
 // 旧的outbound实现已移动到protocols模块中
 
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, OnceLock, RwLock};
 
 pub struct OutboundManager {
     connectors: HashMap<String, Arc<dyn Protocol>>,
+    /// 出站组名 -> 组（成员与当前选中的成员）
+    groups: HashMap<String, OutboundGroup>,
+    tcp_user_timeouts: HashMap<String, Duration>,
+    dscp: HashMap<String, u8>,
+    /// 出站上的 SO_MARK
+    routing_marks: HashMap<String, u32>,
+    /// 出站连接的 TCP_MAXSEG
+    tcp_mss: HashMap<String, u16>,
+    /// 启用了ClientHello分片的出站
+    tls_fragments: HashMap<String, TlsFragmentConfig>,
+    /// 出站连接的 SO_LINGER
+    lingers: HashMap<String, LingerPolicy>,
+    /// 开启低延迟模式的出站
+    latency_modes: HashSet<String>,
+    /// 经该出站的域名解析时携带的 ECS 子网
+    egress_hints: HashMap<String, IpNet>,
+    /// 构建失败被禁用的出站
+    disabled: HashMap<String, Arc<DisabledProtocol>>,
 }
 
 impl OutboundManager {
+    /// Build all outbounds
+    ///
+    /// The built-in `direct` and `block` outbounds are always available; a user
+    /// outbound with the same name replaces the built-in one.
     pub fn from_configs(configs: &[OutboundConfig]) -> Result<Self> {
+        Self::with_error_policy(configs, OutboundErrorPolicy::Fail)
+    }
+
+    /// Build all outbounds, disabling the ones that fail to build when
+    /// `policy` is `Disable` instead of failing as a whole
+    ///
+    /// Rebuilding from a corrected config brings a disabled outbound back.
+    pub fn with_error_policy(configs: &[OutboundConfig], policy: OutboundErrorPolicy) -> Result<Self> {
+        validate_outbound_graph(configs)?;
+
         let mut map: HashMap<String, Arc<dyn Protocol>> = HashMap::new();
+        let mut groups = HashMap::new();
+        let mut tcp_user_timeouts = HashMap::new();
+        let mut dscp = HashMap::new();
+        let mut routing_marks = HashMap::new();
+        let mut tcp_mss = HashMap::new();
+        let mut tls_fragments = HashMap::new();
+        let mut lingers = HashMap::new();
+        let mut latency_modes = HashSet::new();
+        let mut egress_hints = HashMap::new();
+        let mut disabled = HashMap::new();
+        for (name, kind) in BUILTIN_OUTBOUNDS {
+            let protocol: Arc<dyn Protocol> = match kind {
+                OutboundType::Blackhole => Arc::new(BlackholeProtocol::new()),
+                _ => Arc::new(DirectProtocol::new()),
+            };
+            map.insert(name.to_string(), protocol);
+        }
         for cfg in configs {
             let name = cfg.name.clone();
-            let protocol: Arc<dyn Protocol> = match &cfg.kind {
-                OutboundType::Direct => Arc::new(DirectProtocol::new()),
-                OutboundType::Blackhole => Arc::new(BlackholeProtocol::new()),
-                OutboundType::Socks5 { address } => {
-                    let addr: SocketAddr = address.parse().map_err(|e| ProxyError::Protocol(format!("Invalid socks5 address: {}", e)))?;
-                    Arc::new(Socks5Protocol::with_server(addr))
+            if let Some(secs) = cfg.tcp_user_timeout_secs {
+                tcp_user_timeouts.insert(name.clone(), Duration::from_secs(secs));
+            }
+            if let Some(value) = cfg.dscp {
+                dscp.insert(name.clone(), value);
+            }
+            if let Some(mark) = cfg.routing_mark {
+                routing_marks.insert(name.clone(), mark);
+            }
+            if let Some(mss) = cfg.tcp_mss {
+                tcp_mss.insert(name.clone(), mss);
+            }
+            if cfg.tls_fragment.enabled {
+                tls_fragments.insert(name.clone(), cfg.tls_fragment);
+            }
+            if cfg.linger != LingerPolicy::Off {
+                lingers.insert(name.clone(), cfg.linger);
+            }
+            if cfg.latency_mode {
+                latency_modes.insert(name.clone());
+            }
+            if let Some(subnet) = cfg.egress_hint_subnet.as_deref().and_then(|s| s.parse::<IpNet>().ok()) {
+                egress_hints.insert(name.clone(), subnet.trunc());
+            }
+            let group = match &cfg.kind {
+                OutboundType::Selector { outbounds, default } => Some(OutboundGroup::Selector(Arc::new(
+                    SelectorOutbound::new(name.clone(), outbounds.clone(), default.as_deref()),
+                ))),
+                OutboundType::UrlTest { outbounds, url, interval_secs, tolerance_ms } => {
+                    Some(OutboundGroup::UrlTest(Arc::new(UrlTestOutbound::new(
+                        name.clone(),
+                        outbounds.clone(),
+                        url,
+                        Duration::from_secs(*interval_secs),
+                        Duration::from_millis(*tolerance_ms),
+                    )?)))
                 }
-                OutboundType::Vless { address, uuid, tls } => {
-                    let addr: SocketAddr = address.parse().map_err(|e| ProxyError::Protocol(format!("Invalid vless address: {}", e)))?;
-                    Arc::new(VlessProtocol::with_config(addr, uuid.clone(), *tls))
+                _ => None,
+            };
+            if let Some(group) = group {
+                map.remove(&name);
+                groups.insert(name, group);
+                continue;
+            }
+            let protocol = match build_outbound(cfg) {
+                Ok(protocol) => protocol,
+                Err(e) if policy == OutboundErrorPolicy::Disable => {
+                    error!("Outbound {} disabled, connections routed to it will be refused: {}", name, e);
+                    let stub = Arc::new(DisabledProtocol::new(name.clone(), e.to_string()));
+                    disabled.insert(name.clone(), stub.clone());
+                    stub
                 }
+                Err(e) => return Err(e),
             };
             map.insert(name, protocol);
         }
-        Ok(Self { connectors: map })
+        if !disabled.is_empty() {
+            let mut names: Vec<&String> = disabled.keys().collect();
+            names.sort();
+            warn!("Started in degraded mode with {} disabled outbound(s): {:?}", names.len(), names);
+        }
+        Ok(Self {
+            connectors: map,
+            groups,
+            tcp_user_timeouts,
+            dscp,
+            routing_marks,
+            tcp_mss,
+            tls_fragments,
+            lingers,
+            latency_modes,
+            egress_hints,
+            disabled,
+        })
     }
 
+    /// Follow groups to the outbound that actually carries connections
+    fn resolve<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        let mut name = name;
+        // 组之间无环（已校验），最多跟随 groups.len() 次
+        for _ in 0..=self.groups.len() {
+            match self.groups.get(name) {
+                Some(group) => name = group.selected(),
+                None => return Some(name),
+            }
+        }
+        None
+    }
+
+    /// The outbound that carries connections routed to `name`, following groups
+    pub fn selected<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        self.resolve(name)
+    }
+
+    /// Look up an outbound by name, following groups to their selected member
     pub fn get(&self, name: &str) -> Option<Arc<dyn Protocol>> {
-        self.connectors.get(name).cloned()
+        self.connectors.get(self.resolve(name)?).cloned()
+    }
+
+    /// What `name` can do; a group promises only what all of its members can do
+    pub fn capabilities(&self, name: &str) -> Option<OutboundCapabilities> {
+        match self.groups.get(name) {
+            // 组之间无环（已校验），递归必然结束
+            Some(group) => {
+                OutboundCapabilities::of_group(group.members().iter().filter_map(|member| self.capabilities(member)))
+            }
+            None => self.connectors.get(name).map(|connector| connector.capabilities()),
+        }
+    }
+
+    /// Compose a rule's `outbound_chain` from its hops, following groups per hop
+    ///
+    /// Fails like the hop would when one is unknown or disabled.
+    pub fn chain(&self, names: &[String]) -> Result<Arc<dyn Protocol>> {
+        let mut hops = Vec::with_capacity(names.len());
+        for name in names {
+            if let Some(disabled) = self.disabled(name) {
+                return Err(disabled.reject());
+            }
+            let hop = self.get(name).ok_or_else(|| ProxyError::Protocol(format!("Outbound not found: {}", name)))?;
+            hops.push((name.clone(), hop));
+        }
+        Ok(Arc::new(ChainProtocol::new(hops)?))
+    }
+
+    /// The disabled stub `name` routes to, if its outbound failed to build
+    pub fn disabled(&self, name: &str) -> Option<&DisabledProtocol> {
+        self.disabled.get(self.resolve(name)?).map(Arc::as_ref)
+    }
+
+    /// Outbounds that failed to build, with the reason, sorted by name
+    pub fn outbound_errors(&self) -> Vec<(String, String)> {
+        let mut errors: Vec<_> = self
+            .disabled
+            .iter()
+            .map(|(name, stub)| (name.clone(), stub.reason().to_string()))
+            .collect();
+        errors.sort();
+        errors
+    }
+
+    /// Register a connector built outside the config, replacing any outbound of the same name
+    pub fn insert(&mut self, name: impl Into<String>, connector: Arc<dyn Protocol>) {
+        let name = name.into();
+        self.groups.remove(&name);
+        self.disabled.remove(&name);
+        self.connectors.insert(name, connector);
+    }
+
+    /// Names of all outbounds and groups, sorted
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.connectors.keys().chain(self.groups.keys()).map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Members of the group `name`, None when it is not a group
+    pub fn group_members(&self, name: &str) -> Option<&[String]> {
+        self.groups.get(name).map(OutboundGroup::members)
+    }
+
+    /// The group `name`, None when it is not a group
+    pub fn group(&self, name: &str) -> Option<&OutboundGroup> {
+        self.groups.get(name)
+    }
+
+    /// Switch the selector group `group` to its member `member` for new connections
+    pub fn select(&self, group: &str, member: &str) -> Result<()> {
+        match self.groups.get(group) {
+            Some(OutboundGroup::Selector(selector)) => selector.select(member),
+            Some(OutboundGroup::UrlTest(_)) => Err(ProxyError::Protocol(format!(
+                "Outbound group {} is a url-test group, its member is picked by latency",
+                group
+            ))),
+            None => Err(ProxyError::Protocol(format!("Outbound group not found: {}", group))),
+        }
+    }
+
+    /// All url-test groups
+    pub fn url_tests(&self) -> impl Iterator<Item = &Arc<UrlTestOutbound>> {
+        self.groups.values().filter_map(|group| match group {
+            OutboundGroup::UrlTest(url_test) => Some(url_test),
+            OutboundGroup::Selector(_) => None,
+        })
+    }
+
+    /// Whether `name` is a known outbound or group
+    pub fn contains(&self, name: &str) -> bool {
+        self.connectors.contains_key(name) || self.groups.contains_key(name)
+    }
+
+    /// Per-outbound TCP_USER_TIMEOUT override
+    pub fn tcp_user_timeout(&self, name: &str) -> Option<Duration> {
+        self.tcp_user_timeouts.get(name).copied()
+    }
+
+    /// Per-outbound DSCP mark
+    pub fn dscp(&self, name: &str) -> Option<u8> {
+        self.dscp.get(name).copied()
+    }
+
+    /// SO_MARK for `name`, or for the group member it selects
+    pub fn routing_mark(&self, name: &str) -> Option<u32> {
+        self.routing_marks
+            .get(name)
+            .or_else(|| self.routing_marks.get(self.resolve(name)?))
+            .copied()
+    }
+
+    /// TCP_MAXSEG clamp for `name`, or for the group member it selects
+    pub fn tcp_mss(&self, name: &str) -> Option<u16> {
+        self.tcp_mss
+            .get(name)
+            .or_else(|| self.tcp_mss.get(self.resolve(name)?))
+            .copied()
+    }
+
+    /// ClientHello fragmentation for `name`, or for the group member it selects
+    pub fn tls_fragment(&self, name: &str) -> Option<TlsFragmentConfig> {
+        self.tls_fragments
+            .get(name)
+            .or_else(|| self.tls_fragments.get(self.resolve(name)?))
+            .copied()
+    }
+
+    /// Whether `name`, or the group member it selects, runs in latency mode
+    pub fn latency_mode(&self, name: &str) -> bool {
+        self.latency_modes.contains(name) || self.resolve(name).is_some_and(|member| self.latency_modes.contains(member))
+    }
+
+    /// Client subnet for DNS queries of domains routed to `name`, or to the group member it selects
+    pub fn egress_hint_subnet(&self, name: &str) -> Option<IpNet> {
+        self.egress_hints
+            .get(name)
+            .or_else(|| self.egress_hints.get(self.resolve(name)?))
+            .copied()
+    }
+
+    /// SO_LINGER policy for `name`, or for the group member it selects
+    pub fn linger(&self, name: &str) -> LingerPolicy {
+        self.lingers
+            .get(name)
+            .or_else(|| self.lingers.get(self.resolve(name)?))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// A group of outbounds and the member currently carrying its connections
+#[derive(Clone)]
+pub enum OutboundGroup {
+    Selector(Arc<SelectorOutbound>),
+    UrlTest(Arc<UrlTestOutbound>),
+}
+
+impl OutboundGroup {
+    pub fn members(&self) -> &[String] {
+        self.selection().members()
+    }
+
+    /// The member new connections go through; may itself be a group
+    pub fn selected(&self) -> &str {
+        self.selection().selected()
+    }
+
+    fn selection(&self) -> &GroupSelection {
+        match self {
+            OutboundGroup::Selector(selector) => &selector.selection,
+            OutboundGroup::UrlTest(url_test) => &url_test.selection,
+        }
+    }
+}
+
+/// Members of a group and the index of the selected one
+struct GroupSelection {
+    name: String,
+    members: Vec<String>,
+    selected: AtomicUsize,
+}
+
+impl GroupSelection {
+    fn new(name: String, members: Vec<String>, default: Option<&str>) -> Self {
+        let selected = default.and_then(|d| members.iter().position(|m| m == d)).unwrap_or(0);
+        Self { name, members, selected: AtomicUsize::new(selected) }
+    }
+
+    fn members(&self) -> &[String] {
+        &self.members
+    }
+
+    fn selected(&self) -> &str {
+        &self.members[self.selected.load(Ordering::Relaxed)]
+    }
+
+    fn switch_to(&self, index: usize, reason: &str) {
+        let previous = self.selected.swap(index, Ordering::Relaxed);
+        if previous != index {
+            info!("Outbound group {} switched from {} to {} ({})", self.name, self.members[previous], self.members[index], reason);
+        }
+    }
+}
+
+/// Connect to `target` through `member`, looked up in the current global outbounds
+///
+/// Looking the member up per connection lets a group follow config reloads.
+async fn connect_member(member: &str, target: &TargetAddr) -> Result<ProxyStream> {
+    let outbounds = try_get_global_outbound_manager()?;
+    if let Some(disabled) = outbounds.disabled(member) {
+        return Err(disabled.reject());
+    }
+    let connector = outbounds
+        .get(member)
+        .ok_or_else(|| ProxyError::Protocol(format!("Outbound not found: {}", member)))?;
+    connector.connect_outbound(target).await
+}
+
+/// Group whose member is picked by hand, through [`OutboundManager::select`]
+pub struct SelectorOutbound {
+    selection: GroupSelection,
+}
+
+impl SelectorOutbound {
+    /// `default` picks the initial member, the first one when unset or not a member
+    pub fn new(name: String, members: Vec<String>, default: Option<&str>) -> Self {
+        Self { selection: GroupSelection::new(name, members, default) }
+    }
+
+    pub fn selected(&self) -> &str {
+        self.selection.selected()
+    }
+
+    /// Route new connections through `member`; existing ones stay where they are
+    pub fn select(&self, member: &str) -> Result<()> {
+        let index = self.selection.members.iter().position(|m| m == member).ok_or_else(|| {
+            ProxyError::Protocol(format!("Outbound group {} has no member {}", self.selection.name, member))
+        })?;
+        self.selection.switch_to(index, "selected");
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl OutboundConnector for SelectorOutbound {
+    async fn connect(&self, target: &TargetAddr) -> Result<ProxyStream> {
+        connect_member(self.selected(), target).await
+    }
+}
+
+/// How long one url-test probe may take before the member counts as failed
+const URL_TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where a url-test group sends its probe request
+pub struct UrlTestTarget {
+    target: TargetAddr,
+    host: String,
+    /// 请求行中的路径和查询串
+    path: String,
+    https: bool,
+}
+
+impl UrlTestTarget {
+    /// Parse an `http://` or `https://` URL
+    pub fn parse(url: &str) -> Result<Self> {
+        let invalid = |reason: &str| ProxyError::Protocol(format!("Invalid url-test URL {}: {}", url, reason));
+        let parsed = reqwest::Url::parse(url).map_err(|e| invalid(&e.to_string()))?;
+        let https = match parsed.scheme() {
+            "http" => false,
+            "https" => true,
+            _ => return Err(invalid("only http and https are supported")),
+        };
+        let host_str = parsed.host_str().ok_or_else(|| invalid("no host"))?;
+        let address = match host_str.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => Address::V4(ip),
+            Ok(IpAddr::V6(ip)) => Address::V6(ip, 0),
+            Err(_) => Address::Domain(host_str.to_string()),
+        };
+        let port = parsed.port_or_known_default().ok_or_else(|| invalid("no port"))?;
+        let host = match parsed.port() {
+            Some(port) => format!("{}:{}", host_str, port),
+            None => host_str.to_string(),
+        };
+        let path = match parsed.query() {
+            Some(query) => format!("{}?{}", parsed.path(), query),
+            None => parsed.path().to_string(),
+        };
+        Ok(Self { target: TargetAddr::new(address, port), host, path, https })
+    }
+}
+
+/// Group that follows whichever member answers `url` fastest
+///
+/// Another member only takes over when it is faster by more than `tolerance`,
+/// or when the current one fails its probe, so close latencies do not flap.
+pub struct UrlTestOutbound {
+    selection: GroupSelection,
+    target: UrlTestTarget,
+    tls: Option<TlsClient>,
+    interval: Duration,
+    tolerance: Duration,
+    /// 各成员最近一次测得的延迟，失败或未测为 None
+    latencies: Mutex<Vec<Option<Duration>>>,
+}
+
+impl UrlTestOutbound {
+    pub fn new(name: String, members: Vec<String>, url: &str, interval: Duration, tolerance: Duration) -> Result<Self> {
+        let target = UrlTestTarget::parse(url)?;
+        let tls = match target.https {
+            true => Some(TlsClient::new(TlsClientOptions::default())?),
+            false => None,
+        };
+        let latencies = Mutex::new(vec![None; members.len()]);
+        Ok(Self { selection: GroupSelection::new(name, members, None), target, tls, interval, tolerance, latencies })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.selection.name
+    }
+
+    pub fn selected(&self) -> &str {
+        self.selection.selected()
+    }
+
+    /// How often the members are tested
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Latency `member` measured in the last test, None when it failed or was not tested yet
+    pub fn latency(&self, member: &str) -> Option<Duration> {
+        let index = self.selection.members.iter().position(|m| m == member)?;
+        self.latencies.lock().unwrap()[index]
+    }
+
+    /// Probe every member through `outbounds` and switch to the fastest one if warranted
+    pub async fn test(&self, outbounds: &OutboundManager) {
+        let probes = self.selection.members.iter().map(|member| async move {
+            match self.probe(outbounds, member).await {
+                Ok(latency) => {
+                    debug!("Outbound group {}: {} answered in {:?}", self.name(), member, latency);
+                    Some(latency)
+                }
+                Err(e) => {
+                    debug!("Outbound group {}: {} failed the url test: {}", self.name(), member, e);
+                    None
+                }
+            }
+        });
+        let latencies = futures::future::join_all(probes).await;
+        let current = self.selection.selected.load(Ordering::Relaxed);
+        match self.pick(current, &latencies) {
+            Some(best) if best != current => {
+                let reason = match latencies[current] {
+                    Some(latency) => format!("{:?} vs {:?}", latencies[best].unwrap_or_default(), latency),
+                    None => format!("{} failed its url test", self.selection.members[current]),
+                };
+                self.selection.switch_to(best, &reason);
+            }
+            Some(_) => {}
+            None => warn!("Outbound group {}: every member failed the url test, keeping {}", self.name(), self.selected()),
+        }
+        *self.latencies.lock().unwrap() = latencies;
+    }
+
+    /// The member to use given fresh `latencies`, None when all failed
+    fn pick(&self, current: usize, latencies: &[Option<Duration>]) -> Option<usize> {
+        let (best, best_latency) = latencies
+            .iter()
+            .enumerate()
+            .filter_map(|(index, latency)| Some((index, (*latency)?)))
+            .min_by_key(|(_, latency)| *latency)?;
+        match latencies[current] {
+            // 容差之内不切换，避免在延迟相近的成员间来回跳
+            Some(latency) if best_latency + self.tolerance >= latency => Some(current),
+            _ => Some(best),
+        }
+    }
+
+    /// Time an HTTP request to the test URL through `member`
+    async fn probe(&self, outbounds: &OutboundManager, member: &str) -> Result<Duration> {
+        if let Some(disabled) = outbounds.disabled(member) {
+            return Err(ProxyError::OutboundDisabled { name: member.to_string(), reason: disabled.reason().to_string() });
+        }
+        let connector = outbounds
+            .get(member)
+            .ok_or_else(|| ProxyError::Protocol(format!("Outbound not found: {}", member)))?;
+        let started = Instant::now();
+        let request = async {
+            let stream = connector.connect_outbound(&self.target.target).await?;
+            match &self.tls {
+                Some(tls) => http_probe(tls.connect(stream, &self.target.host).await?, &self.target).await,
+                None => http_probe(stream, &self.target).await,
+            }
+        };
+        tokio::time::timeout(URL_TEST_TIMEOUT, request)
+            .await
+            .map_err(|_| ProxyError::ConnectionFailed(format!("url test through {} timed out", member)))??;
+        Ok(started.elapsed())
+    }
+}
+
+#[async_trait]
+impl OutboundConnector for UrlTestOutbound {
+    async fn connect(&self, target: &TargetAddr) -> Result<ProxyStream> {
+        connect_member(self.selected(), target).await
+    }
+}
+
+/// Send a GET for the test URL and wait for the start of any HTTP response
+async fn http_probe<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, target: &UrlTestTarget) -> Result<()> {
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: anybls\r\nConnection: close\r\n\r\n",
+        target.path, target.host
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut head = [0u8; 5];
+    stream.read_exact(&mut head).await?;
+    if &head != b"HTTP/" {
+        return Err(ProxyError::Protocol("url test got a non-HTTP response".to_string()));
+    }
+    Ok(())
+}
+
+/// Test the url-test groups of the global outbounds, each every `interval`
+///
+/// Calling it again, e.g. after a config reload, replaces the previous tests.
+pub fn start_url_tests() {
+    // 重载后旧的组已不再使用，先停掉它们的测速任务
+    static TESTS: Mutex<Vec<JoinHandle<Option<()>>>> = Mutex::new(Vec::new());
+    let mut tests = TESTS.lock().unwrap();
+    for previous in tests.drain(..) {
+        previous.abort();
+    }
+    let Ok(outbounds) = try_get_global_outbound_manager() else {
+        return;
+    };
+    for group in outbounds.url_tests() {
+        let (group, name) = (group.clone(), group.name().to_string());
+        let spawned = get_global_task_tracker().spawn(TaskGroup::HealthChecks, async move {
+            let mut interval = tokio::time::interval(group.interval());
+            loop {
+                interval.tick().await;
+                // 成员每轮从当前的全局出站中查找
+                match try_get_global_outbound_manager() {
+                    Ok(outbounds) => group.test(&outbounds).await,
+                    Err(_) => return,
+                }
+            }
+        });
+        match spawned {
+            Ok(handle) => tests.push(handle),
+            Err(e) => warn!("Url tests of outbound group {} not started: {}", name, e),
+        }
+    }
+}
+
+/// Build the connector for a non-group outbound
+fn build_outbound(cfg: &OutboundConfig) -> Result<Arc<dyn Protocol>> {
+    let protocol: Arc<dyn Protocol> = match &cfg.kind {
+        OutboundType::Direct => Arc::new(DirectProtocol::new()),
+        OutboundType::Blackhole => Arc::new(BlackholeProtocol::new()),
+        OutboundType::Selector { .. } | OutboundType::UrlTest { .. } => unreachable!("groups have no connector"),
+        OutboundType::Socks5 { address } => {
+            let server = ServerEndpoint::parse(address, &cfg.ports, cfg.port_strategy)?;
+            Arc::new(Socks5Protocol::with_endpoint(server).with_udp_over_tcp(cfg.udp_over_tcp))
+        }
+        OutboundType::Http { address, override_host_header } => {
+            let server = ServerEndpoint::parse(address, &cfg.ports, cfg.port_strategy)?;
+            Arc::new(HttpProtocol::with_endpoint(server, override_host_header.clone()))
+        }
+        OutboundType::Shadowsocks { address, method, password } => {
+            let server = ServerEndpoint::parse(address, &cfg.ports, cfg.port_strategy)?;
+            Arc::new(ShadowsocksProtocol::new(server, method, password)?)
+        }
+        OutboundType::Vless {
+            address,
+            uuid,
+            tls,
+            server_name,
+            override_sni,
+            override_host_header,
+            insecure,
+            session_cache_size,
+            early_data,
+        } => {
+            // VLESS 尚未实现拨号，端口范围只取第一个端口
+            let addr = ServerEndpoint::parse(address, &cfg.ports, cfg.port_strategy)?.primary();
+            let tls_options = TlsClientOptions {
+                server_name: server_name.clone(),
+                override_sni: override_sni.clone(),
+                insecure: *insecure,
+                session_cache_size: *session_cache_size,
+                early_data: *early_data,
+            };
+            Arc::new(
+                VlessProtocol::with_config(addr, uuid.clone(), *tls)
+                    .with_udp_over_tcp(cfg.udp_over_tcp)
+                    .with_tls_options(tls_options, override_host_header.clone())?,
+            )
+        }
+    };
+    Ok(protocol)
+}
+
+/// Set TCP_USER_TIMEOUT on an outbound stream
+///
+/// Bounds how long written data may stay unacknowledged before the kernel
+/// fails the connection. Other platforms rely on the relay's write-stall
+/// detection instead.
+pub fn set_tcp_user_timeout(stream: &TcpStream, timeout: Duration) -> Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    socket2::SockRef::from(stream).set_tcp_user_timeout(Some(timeout))?;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = (stream, timeout);
+    Ok(())
+}
+
+/// Resolve a request target to the addresses to try, recording the DNS step
+///
+/// Answers pointing into internal networks are checked by the rebinding guard.
+/// `client_subnet` is the egress hint of the outbound the target was routed
+/// to; it replaces `dns.client_subnet` for this lookup.
+pub async fn resolve_target(
+    address: &Address,
+    port: u16,
+    client_subnet: Option<IpNet>,
+    diagnostics: &mut ConnectDiagnostics,
+) -> Result<Vec<SocketAddr>> {
+    resolve_target_with(address, port, diagnostics, get_global_rebinding_guard(), |domain| async move {
+        try_get_global_dns_resolver()?.resolve_all_with_subnet(domain, client_subnet).await
+    })
+    .await
+}
+
+async fn resolve_target_with<'a, F, Fut>(
+    address: &'a Address,
+    port: u16,
+    diagnostics: &mut ConnectDiagnostics,
+    guard: &RebindingGuard,
+    resolve: F,
+) -> Result<Vec<SocketAddr>>
+where
+    F: FnOnce(&'a str) -> Fut,
+    Fut: std::future::Future<Output = Result<(Vec<IpAddr>, DnsSource)>>,
+{
+    let domain = match address {
+        Address::Domain(domain) => domain,
+        // IPv6 带上区域，链路本地地址才能连通
+        ip => return Ok(vec![ip.to_socket_addr(port)?]),
+    };
+
+    let started = Instant::now();
+    match resolve(domain).await {
+        Ok((ips, source)) => {
+            let addrs = ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+            diagnostics.dns(source, started.elapsed(), ips);
+            guard.check(domain, addrs)
+        }
+        Err(e) => Err(diagnostics.fail(e)),
     }
 }
 
-static mut GLOBAL_OUTBOUND_MANAGER: Option<OutboundManager> = None;
+/// Connect through `connector`, trying each address in order until one succeeds
+///
+/// Addresses are resolved ones, or the single domain target of an outbound
+/// that accepts domains. Every attempt is bounded by `attempt_timeout` and
+/// recorded in `diagnostics`; on failure the diagnostics are attached to the
+/// error. Direct connects skip addresses that recently timed out (see
+/// [`NegativeCache`]) unless `options.probe` is set.
+pub async fn connect_addresses(
+    connector: &dyn Protocol,
+    addrs: &[TargetAddr],
+    options: &DialOptions,
+    attempt_timeout: Duration,
+    diagnostics: &mut ConnectDiagnostics,
+) -> Result<ProxyStream> {
+    connect_addresses_with(connector, addrs, options, attempt_timeout, diagnostics, get_global_negative_cache()).await
+}
+
+async fn connect_addresses_with(
+    connector: &dyn Protocol,
+    addrs: &[TargetAddr],
+    options: &DialOptions,
+    attempt_timeout: Duration,
+    diagnostics: &mut ConnectDiagnostics,
+    negative_cache: &NegativeCache,
+) -> Result<ProxyStream> {
+    // 只有自己拨号目标的出站（直连）地址才是真正拨号的对象
+    let negative_cache = connector.capabilities().needs_resolved_target.then_some(negative_cache);
+    let mut last_error = None;
+    for target in addrs {
+        let started = Instant::now();
+        // 域名目标交给上游解析，没有可缓存的地址
+        let cached = negative_cache.zip(target.socket_addr());
+        let suppressed = cached.filter(|_| !options.probe).and_then(|(cache, addr)| cache.check(addr));
+        let error = match suppressed {
+            Some(error) => error,
+            None => {
+                let error = match tokio::time::timeout(attempt_timeout, connector.connect_outbound_with(target, options)).await {
+                    Ok(Ok(stream)) => {
+                        if let Some((cache, addr)) = cached {
+                            cache.record_success(addr);
+                        }
+                        diagnostics.attempt(target.clone(), started.elapsed(), None).finish();
+                        return Ok(stream);
+                    }
+                    Ok(Err(e)) => e,
+                    Err(_) => ProxyError::Io(std::io::ErrorKind::TimedOut.into()),
+                };
+                if let Some((cache, addr)) = cached {
+                    cache.record_failure(addr, error.io_kind());
+                }
+                error
+            }
+        };
+        diagnostics.attempt(target.clone(), started.elapsed(), Some(error.io_kind()));
+        last_error = Some(error);
+    }
+
+    Err(match last_error {
+        Some(e) => diagnostics.fail(format!("All {} addresses failed, last error: {}", addrs.len(), e)),
+        None => diagnostics.fail("No addresses to connect to"),
+    })
+}
+
+/// Where a service reads the current outbounds from, so it sees reloads
+pub type OutboundSource = Box<dyn Fn() -> Arc<OutboundManager> + Send + Sync>;
 
-pub fn init_global_outbound_manager(cfgs: &[OutboundConfig]) -> Result<()> {
-    let m = OutboundManager::from_configs(cfgs)?;
-    unsafe { GLOBAL_OUTBOUND_MANAGER = Some(m); }
+static GLOBAL_OUTBOUND_MANAGER: OnceLock<RwLock<Arc<OutboundManager>>> = OnceLock::new();
+
+pub fn init_global_outbound_manager(cfgs: &[OutboundConfig], policy: OutboundErrorPolicy) -> Result<()> {
+    let m = OutboundManager::with_error_policy(cfgs, policy)?;
+    GLOBAL_OUTBOUND_MANAGER
+        .set(RwLock::new(Arc::new(m)))
+        .map_err(|_| ProxyError::AlreadyInitialized("OutboundManager"))
+}
+
+/// Replace the outbounds used for new connections
+///
+/// Connections already holding the previous manager keep using it until they close.
+pub fn set_global_outbound_manager(manager: OutboundManager) -> Result<()> {
+    let slot = GLOBAL_OUTBOUND_MANAGER.get().ok_or(ProxyError::NotInitialized("OutboundManager"))?;
+    *slot.write().unwrap() = Arc::new(manager);
     Ok(())
 }
 
-pub fn get_global_outbound_manager() -> &'static OutboundManager {
-    unsafe { GLOBAL_OUTBOUND_MANAGER.as_ref().expect("OutboundManager not initialized") }
+pub fn try_get_global_outbound_manager() -> Result<Arc<OutboundManager>> {
+    let slot = GLOBAL_OUTBOUND_MANAGER.get().ok_or(ProxyError::NotInitialized("OutboundManager"))?;
+    Ok(slot.read().unwrap().clone())
+}
+
+/// Panics before `init_global_outbound_manager`
+pub fn get_global_outbound_manager() -> Arc<OutboundManager> {
+    try_get_global_outbound_manager().unwrap_or_else(|e| panic!("{}", e))
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inbound::{InboundContext, RunningInbound};
+    use crate::endpoint::PortStrategy;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_global_manager_initialized_once() {
+        assert!(matches!(try_get_global_outbound_manager(), Err(ProxyError::NotInitialized(_))));
+        let policy = OutboundErrorPolicy::default();
+        init_global_outbound_manager(&[OutboundConfig::direct("first")], policy).unwrap();
+        let err = init_global_outbound_manager(&[OutboundConfig::direct("second")], policy).unwrap_err();
+        assert!(matches!(err, ProxyError::AlreadyInitialized(_)), "{}", err);
+        let manager = get_global_outbound_manager();
+        assert!(manager.get("first").is_some());
+        assert!(manager.get("second").is_none());
+
+        set_global_outbound_manager(OutboundManager::from_configs(&[OutboundConfig::direct("second")]).unwrap()).unwrap();
+        assert!(get_global_outbound_manager().get("second").is_some());
+        // 替换前取得的实例不受影响
+        assert!(manager.get("first").is_some());
+    }
+
+    #[test]
+    fn test_per_outbound_user_timeout_override() {
+        let mut proxy = OutboundConfig::direct("slow-path");
+        proxy.tcp_user_timeout_secs = Some(15);
+        let manager = OutboundManager::from_configs(&[OutboundConfig::direct("direct"), proxy]).unwrap();
+
+        assert_eq!(manager.tcp_user_timeout("slow-path"), Some(Duration::from_secs(15)));
+        assert_eq!(manager.tcp_user_timeout("direct"), None);
+    }
+
+    #[test]
+    fn test_routing_mark_follows_group_selection() {
+        let marked = OutboundConfig { routing_mark: Some(255), ..OutboundConfig::direct("marked") };
+        let group = OutboundConfig {
+            kind: OutboundType::Selector { outbounds: vec!["marked".to_string()], default: None },
+            ..OutboundConfig::direct("group")
+        };
+        let manager = OutboundManager::from_configs(&[marked, group]).unwrap();
+
+        assert_eq!(manager.routing_mark("marked"), Some(255));
+        assert_eq!(manager.routing_mark("group"), Some(255));
+        assert_eq!(manager.routing_mark("direct"), None);
+    }
+
+    #[test]
+    fn test_latency_mode_follows_group_selection() {
+        let ssh = OutboundConfig { latency_mode: true, ..OutboundConfig::direct("ssh") };
+        let group = OutboundConfig {
+            kind: OutboundType::Selector { outbounds: vec!["ssh".to_string()], default: None },
+            ..OutboundConfig::direct("interactive")
+        };
+        let manager = OutboundManager::from_configs(&[ssh, group]).unwrap();
+
+        assert!(manager.latency_mode("ssh"));
+        assert!(manager.latency_mode("interactive"));
+        assert!(!manager.latency_mode("direct"));
+    }
+
+    #[test]
+    fn test_builtins_and_groups_resolve() {
+        let group = OutboundConfig {
+            name: "proxy".to_string(),
+            kind: OutboundType::Selector {
+                outbounds: vec!["direct".to_string(), "block".to_string()],
+                default: Some("block".to_string()),
+            },
+            tcp_user_timeout_secs: None,
+            udp_over_tcp: false,
+            dscp: None,
+            latency_mode: false,
+            egress_hint_subnet: None,
+            routing_mark: None,
+            tcp_mss: None,
+            ports: Vec::new(),
+            port_strategy: PortStrategy::default(),
+            tls_fragment: TlsFragmentConfig::default(),
+            linger: LingerPolicy::default(),
+            max_connections_per_sec: None,
+        };
+        let manager = OutboundManager::from_configs(&[group]).unwrap();
+
+        assert_eq!(manager.get("direct").unwrap().name(), "direct");
+        assert_eq!(manager.get("block").unwrap().name(), "blackhole");
+        assert_eq!(manager.get("proxy").unwrap().name(), "blackhole");
+        assert!(manager.contains("proxy"));
+        assert!(manager.get("missing").is_none());
+    }
+
+    #[test]
+    fn test_user_outbound_replaces_builtin() {
+        let user_block = OutboundConfig {
+            name: "block".to_string(),
+            kind: OutboundType::Socks5 { address: "127.0.0.1:1081".to_string() },
+            tcp_user_timeout_secs: None,
+            udp_over_tcp: false,
+            dscp: None,
+            latency_mode: false,
+            egress_hint_subnet: None,
+            routing_mark: None,
+            tcp_mss: None,
+            ports: Vec::new(),
+            port_strategy: PortStrategy::default(),
+            tls_fragment: TlsFragmentConfig::default(),
+            linger: LingerPolicy::default(),
+            max_connections_per_sec: None,
+        };
+        let manager = OutboundManager::from_configs(&[user_block]).unwrap();
+        assert_eq!(manager.get("block").unwrap().name(), "socks5");
+    }
+
+    fn socks5(name: &str, address: &str) -> OutboundConfig {
+        OutboundConfig {
+            kind: OutboundType::Socks5 { address: address.to_string() },
+            ..OutboundConfig::direct(name)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_broken_outbound_disabled_in_degraded_mode() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let configs = vec![
+            OutboundConfig::direct("good"),
+            socks5("bad", "127.0.0.1:10800x"),
+            OutboundConfig {
+                kind: OutboundType::Selector { outbounds: vec!["bad".to_string()], default: None },
+                ..OutboundConfig::direct("group")
+            },
+        ];
+
+        let err = OutboundManager::from_configs(&configs).err().unwrap();
+        assert!(err.to_string().contains("Invalid server address 127.0.0.1:10800x"), "{}", err);
+
+        let manager = OutboundManager::with_error_policy(&configs, OutboundErrorPolicy::Disable).unwrap();
+        let stream = manager.get("good").unwrap().connect_outbound(&target.into()).await.unwrap();
+        assert_eq!(stream.tcp_stream().unwrap().peer_addr().unwrap(), target);
+        assert!(manager.disabled("good").is_none());
+
+        let err = manager.get("bad").unwrap().connect_outbound(&target.into()).await.unwrap_err();
+        let ProxyError::OutboundDisabled { name, reason } = &err else {
+            panic!("unexpected error: {}", err);
+        };
+        assert_eq!(name, "bad");
+        assert!(reason.contains("Invalid server address"), "{}", reason);
+        assert_eq!(manager.disabled("group").map(|d| d.reason()), Some(reason.as_str()));
+        assert_eq!(manager.outbound_errors(), vec![("bad".to_string(), reason.clone())]);
+
+        // 修正配置后重建即恢复
+        let fixed = [OutboundConfig::direct("good"), socks5("bad", "127.0.0.1:10800")];
+        let manager = OutboundManager::with_error_policy(&fixed, OutboundErrorPolicy::Disable).unwrap();
+        assert!(manager.outbound_errors().is_empty());
+        assert_eq!(manager.get("bad").unwrap().name(), "socks5");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_tcp_user_timeout_applied_to_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+
+        set_tcp_user_timeout(&stream, Duration::from_secs(20)).unwrap();
+        let applied = socket2::SockRef::from(&stream).tcp_user_timeout().unwrap();
+        assert_eq!(applied, Some(Duration::from_secs(20)));
+    }
+
+    /// Direct connector that never completes connects to `hang`
+    struct HangingProtocol {
+        hang: SocketAddr,
+    }
+
+    #[async_trait]
+    impl Protocol for HangingProtocol {
+        fn name(&self) -> &str {
+            "hanging"
+        }
+
+        async fn connect_outbound(&self, target: &TargetAddr) -> Result<ProxyStream> {
+            if target.socket_addr() == Some(self.hang) {
+                std::future::pending::<()>().await;
+            }
+            DirectProtocol::new().connect_outbound(target).await
+        }
+
+        async fn start_inbound(&self, _bind_addr: SocketAddr, _ctx: InboundContext) -> Result<RunningInbound> {
+            unimplemented!()
+        }
+    }
+
+    /// Connector on a network that silently drops every SYN, advertising the given capabilities
+    struct DropAll {
+        attempts: std::sync::atomic::AtomicUsize,
+        capabilities: OutboundCapabilities,
+    }
+
+    impl DropAll {
+        fn new(capabilities: OutboundCapabilities) -> Self {
+            Self { attempts: Default::default(), capabilities }
+        }
+    }
+
+    #[async_trait]
+    impl Protocol for DropAll {
+        fn name(&self) -> &str {
+            "drop-all"
+        }
+
+        fn capabilities(&self) -> OutboundCapabilities {
+            self.capabilities
+        }
+
+        async fn connect_outbound(&self, _target: &TargetAddr) -> Result<ProxyStream> {
+            self.attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            std::future::pending().await
+        }
+
+        async fn start_inbound(&self, _bind_addr: SocketAddr, _ctx: InboundContext) -> Result<RunningInbound> {
+            unimplemented!()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_negative_cache_suppresses_recent_timeouts() {
+        let cache = NegativeCache::new(&crate::config::NegativeCacheConfig::default());
+        let connector = DropAll::new(DirectProtocol::CAPABILITIES);
+        let addrs = [TargetAddr::from(SocketAddr::from(([192, 0, 2, 1], 443)))];
+        let connect = |options: DialOptions| {
+            let (connector, cache, addrs) = (&connector, &cache, &addrs);
+            async move {
+                let started = tokio::time::Instant::now();
+                let mut diagnostics = ConnectDiagnostics::start();
+                let result = connect_addresses_with(connector, addrs, &options, Duration::from_secs(5), &mut diagnostics, cache).await;
+                (result.unwrap_err().to_string(), started.elapsed())
+            }
+        };
+        let attempts = || connector.attempts.load(std::sync::atomic::Ordering::SeqCst);
+
+        let (error, elapsed) = connect(DialOptions::default()).await;
+        assert_eq!((attempts(), elapsed), (1, Duration::from_secs(5)));
+        assert!(!error.contains("suppressed retry"), "{}", error);
+
+        let (error, elapsed) = connect(DialOptions::default()).await;
+        assert_eq!((attempts(), elapsed), (1, Duration::ZERO));
+        assert!(error.contains("recent failure, suppressed retry"), "{}", error);
+
+        // 探测绕过缓存
+        connect(DialOptions { probe: true, ..DialOptions::default() }).await;
+        assert_eq!(attempts(), 2);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let (_, elapsed) = connect(DialOptions::default()).await;
+        assert_eq!((attempts(), elapsed), (3, Duration::from_secs(5)));
+        assert_eq!(cache.stats().suppressed, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_negative_cache_only_for_connectors_dialing_targets() {
+        let cache = NegativeCache::new(&crate::config::NegativeCacheConfig::default());
+        let addrs = [TargetAddr::from(SocketAddr::from(([192, 0, 2, 1], 443)))];
+        // 代理出站拨号的是服务器，目标地址超时不代表目标不可达
+        let proxy = DropAll::new(Socks5Protocol::capabilities_with(false));
+        for _ in 0..2 {
+            let mut diagnostics = ConnectDiagnostics::start();
+            let options = DialOptions::default();
+            connect_addresses_with(&proxy, &addrs, &options, Duration::from_secs(5), &mut diagnostics, &cache).await.unwrap_err();
+        }
+        assert_eq!(proxy.attempts.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert_eq!(cache.stats().suppressed, 0);
+    }
+
+    #[test]
+    fn test_group_capabilities_follow_members() {
+        let proxy = OutboundConfig {
+            kind: OutboundType::Socks5 { address: "127.0.0.1:1080".to_string() },
+            udp_over_tcp: true,
+            ..OutboundConfig::direct("proxy")
+        };
+        let group = |name: &str, members: &[&str]| OutboundConfig {
+            kind: OutboundType::Selector { outbounds: members.iter().map(|m| m.to_string()).collect(), default: None },
+            ..OutboundConfig::direct(name)
+        };
+        let mut manager =
+            OutboundManager::from_configs(&[proxy, group("udp", &["proxy", "direct"]), group("any", &["udp", "block"])]).unwrap();
+
+        assert_eq!(manager.capabilities("direct"), Some(DirectProtocol::CAPABILITIES));
+        let udp = manager.capabilities("udp").unwrap();
+        assert!(udp.is_group && udp.supports_udp && udp.needs_resolved_target && !udp.accepts_domain_targets);
+        let any = manager.capabilities("any").unwrap();
+        assert!(!any.supports_udp && !any.blocks);
+        // 被替换的连接器以自己声明的能力参与汇总
+        manager.insert("block", Arc::new(DropAll::new(OutboundCapabilities { supports_udp: true, ..Default::default() })));
+        assert!(manager.capabilities("any").unwrap().supports_udp);
+        assert_eq!(manager.capabilities("missing"), None);
+    }
+
+    /// An address nothing listens on
+    async fn closed_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_connect_diagnostics_multi_address_failure() {
+        let refused = [closed_addr().await, closed_addr().await];
+        let hanging = SocketAddr::from(([192, 0, 2, 1], 443));
+        let connector = HangingProtocol { hang: hanging };
+
+        let mut diagnostics = ConnectDiagnostics::start();
+        diagnostics.route(Some(3), "proxy".to_string());
+        let addrs = [refused[0], hanging, refused[1]].map(TargetAddr::from);
+        let err = connect_addresses(&connector, &addrs, &DialOptions::default(), Duration::from_millis(200), &mut diagnostics)
+            .await
+            .unwrap_err();
+
+        let ProxyError::ConnectFailed { cause, diagnostics } = &err else {
+            panic!("unexpected error: {}", err);
+        };
+        assert!(cause.starts_with("All 3 addresses failed"));
+        assert_eq!(diagnostics.rule, Some(3));
+        assert_eq!(diagnostics.outbound, "proxy");
+        assert!(diagnostics.connected.is_none());
+
+        let attempts: Vec<_> = diagnostics.failed_attempts.iter().map(|a| (a.addr.socket_addr().unwrap(), a.error)).collect();
+        assert_eq!(
+            attempts,
+            vec![
+                (refused[0], Some(std::io::ErrorKind::ConnectionRefused)),
+                (hanging, Some(std::io::ErrorKind::TimedOut)),
+                (refused[1], Some(std::io::ErrorKind::ConnectionRefused)),
+            ]
+        );
+        assert!(diagnostics.failed_attempts[1].duration >= Duration::from_millis(200));
+        assert!(diagnostics.elapsed >= diagnostics.failed_attempts[1].duration);
+        assert_eq!(err.socks5_reply_code(), 0x05);
+
+        let line = err.to_string();
+        assert!(line.contains("rule=#3 outbound=proxy attempts=3"), "{}", line);
+        assert!(line.contains(&format!("{} timed out", hanging)), "{}", line);
+    }
+
+    #[tokio::test]
+    async fn test_connect_falls_through_to_next_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+        let refused = closed_addr().await;
+
+        let mut diagnostics = ConnectDiagnostics::start();
+        let stream = connect_addresses(&DirectProtocol::new(), &[refused.into(), live.into()], &DialOptions::default(), Duration::from_secs(5), &mut diagnostics)
+            .await
+            .unwrap();
+
+        assert_eq!(stream.tcp_stream().unwrap().peer_addr().unwrap(), live);
+        assert_eq!(diagnostics.attempts(), 2);
+        assert_eq!(diagnostics.failed_attempts[0].addr, refused.into());
+        assert_eq!(diagnostics.connected.as_ref().map(|a| a.addr.clone()), Some(live.into()));
+        assert!(diagnostics.to_string().contains(&format!("{} ok in", live)));
+    }
+
+    /// A link-local address assigned to a local interface, loopback first
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn link_local_interface() -> Option<(std::net::Ipv6Addr, String)> {
+        let mut found: Vec<_> = nix::ifaddrs::getifaddrs()
+            .ok()?
+            .filter_map(|interface| {
+                let ip = interface.address?.as_sockaddr_in6()?.ip();
+                ((ip.segments()[0] & 0xffc0) == 0xfe80).then_some((ip, interface.interface_name))
+            })
+            .collect();
+        found.sort_by_key(|(_, name)| !name.starts_with("lo"));
+        found.into_iter().next()
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[tokio::test]
+    async fn test_connect_link_local_with_zone() {
+        let Some((ip, interface)) = link_local_interface() else {
+            eprintln!("no IPv6 link-local address, skipping");
+            return;
+        };
+        let scope_id = crate::scope::interface_index(&interface).unwrap();
+        let bind = SocketAddr::V6(std::net::SocketAddrV6::new(ip, 0, 0, scope_id));
+        // 地址可能仍在DAD等状态而不可绑定，这种环境下跳过
+        let Ok(listener) = TcpListener::bind(bind).await else {
+            eprintln!("cannot bind {}, skipping", bind);
+            return;
+        };
+        let port = listener.local_addr().unwrap().port();
+
+        // 客户端以域名类型发来 "fe80::...%eth0"
+        let target = Address::from_domain_bytes(format!("{}%{}", ip, interface).as_bytes()).unwrap();
+        assert_eq!(target, Address::V6(ip, scope_id));
+        let mut diagnostics = ConnectDiagnostics::start();
+        let addrs: Vec<_> = resolve_target(&target, port, None, &mut diagnostics).await.unwrap().into_iter().map(TargetAddr::from).collect();
+        let stream = connect_addresses(&DirectProtocol::new(), &addrs, &DialOptions::default(), Duration::from_secs(5), &mut diagnostics)
+            .await
+            .unwrap();
+        assert_eq!(stream.tcp_stream().unwrap().peer_addr().unwrap(), SocketAddr::V6(std::net::SocketAddrV6::new(ip, port, 0, scope_id)));
+    }
+
+    #[tokio::test]
+    async fn test_rebinding_guard_on_resolved_targets() {
+        use crate::config::{DomainLists, RebindingProtectionConfig};
+
+        let mut config = RebindingProtectionConfig {
+            block_private_resolution_for_public_domains: true,
+            ..Default::default()
+        };
+        // 模拟解析器：任何域名都解析到内网地址
+        let resolver = |_: &str| async { Ok((vec!["192.168.1.10".parse().unwrap()], DnsSource::Cache)) };
+        let target = Address::Domain("totally-public.example".to_string());
+
+        let guard = RebindingGuard::new(&config).unwrap();
+        let mut diagnostics = ConnectDiagnostics::start();
+        let err = resolve_target_with(&target, 80, &mut diagnostics, &guard, resolver).await.unwrap_err();
+        assert!(matches!(err, ProxyError::RebindingBlocked { .. }));
+        assert_eq!(err.socks5_reply_code(), 0x02);
+
+        config.internal_domains = DomainLists { domain: vec!["totally-public.example".to_string()], ..Default::default() };
+        let guard = RebindingGuard::new(&config).unwrap();
+        let addrs = resolve_target_with(&target, 80, &mut diagnostics, &guard, resolver).await.unwrap();
+        assert_eq!(addrs, vec!["192.168.1.10:80".parse().unwrap()]);
+    }
+
+    fn url_test(url: &str, members: &[&str]) -> OutboundConfig {
+        OutboundConfig {
+            kind: OutboundType::UrlTest {
+                outbounds: members.iter().map(|m| m.to_string()).collect(),
+                url: url.to_string(),
+                interval_secs: 60,
+                tolerance_ms: 50,
+            },
+            ..OutboundConfig::direct("auto")
+        }
+    }
+
+    #[test]
+    fn test_selector_switched_at_runtime() {
+        let selector = OutboundConfig {
+            kind: OutboundType::Selector { outbounds: vec!["direct".to_string(), "block".to_string()], default: None },
+            ..OutboundConfig::direct("proxy")
+        };
+        let auto = url_test("http://127.0.0.1/generate_204", &["direct"]);
+        let manager = OutboundManager::from_configs(&[selector, auto]).unwrap();
+        assert_eq!(manager.group("proxy").unwrap().selected(), "direct");
+
+        manager.select("proxy", "block").unwrap();
+        assert_eq!(manager.get("proxy").unwrap().name(), "blackhole");
+        assert_eq!(manager.selected("proxy"), Some("block"));
+
+        // 非成员、测速组和不存在的组都不能手动选择，选择保持不变
+        assert!(manager.select("proxy", "missing").is_err());
+        assert!(manager.select("auto", "direct").is_err());
+        assert!(manager.select("missing", "direct").is_err());
+        assert_eq!(manager.selected("proxy"), Some("block"));
+    }
+
+    #[test]
+    fn test_url_test_keeps_member_within_tolerance() {
+        let group = UrlTestOutbound::new(
+            "auto".to_string(),
+            vec!["a".to_string(), "b".to_string(), "c".to_string()],
+            "http://example.com/",
+            Duration::from_secs(60),
+            Duration::from_millis(50),
+        )
+        .unwrap();
+        let ms = |ms| Some(Duration::from_millis(ms));
+
+        assert_eq!(group.pick(0, &[ms(100), ms(60), None]), Some(0));
+        assert_eq!(group.pick(0, &[ms(100), ms(40), None]), Some(1));
+        assert_eq!(group.pick(0, &[None, ms(300), ms(200)]), Some(2));
+        assert_eq!(group.pick(2, &[None, None, None]), None);
+    }
+
+    #[tokio::test]
+    async fn test_url_test_moves_off_failing_member() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/generate_204", server.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = server.accept().await {
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request).await;
+                let _ = stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await;
+            }
+        });
+        let manager = OutboundManager::from_configs(&[url_test(&url, &["block", "direct"])]).unwrap();
+        let Some(OutboundGroup::UrlTest(group)) = manager.group("auto") else { panic!("not a url-test group") };
+        assert_eq!(group.selected(), "block");
+
+        group.test(&manager).await;
+        assert_eq!(group.selected(), "direct");
+        assert!(group.latency("direct").is_some());
+        assert!(group.latency("block").is_none());
+        assert_eq!(manager.get("auto").unwrap().name(), "direct");
+    }
+
+    #[test]
+    fn test_url_test_url_validated() {
+        for url in ["ftp://example.com/", "not a url"] {
+            let err = OutboundManager::from_configs(&[url_test(url, &["direct"])]).err().unwrap();
+            assert!(err.to_string().contains("Invalid url-test URL"), "{}", err);
+        }
+        let target = UrlTestTarget::parse("https://[::1]:8443/check?x=1").unwrap();
+        assert_eq!((target.host.as_str(), target.path.as_str(), target.https), ("[::1]:8443", "/check?x=1", true));
+        assert_eq!(target.target.to_string(), TargetAddr::new(Address::V6("::1".parse().unwrap(), 0), 8443).to_string());
+    }
+}
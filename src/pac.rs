@@ -0,0 +1,644 @@
+// PAC（代理自动配置）：把路由表的域名规则渲染成 FindProxyForURL 脚本，
+// 供不能按域名分流的客户端使用；经独立的小型 HTTP 监听器提供并随路由重载重新生成
+use crate::config::{Config, OutboundType, PacConfig, BUILTIN_OUTBOUNDS};
+use crate::error::{ProxyError, Result};
+use crate::routing::{build_router, get_global_router, HighPerformanceRouter};
+use crate::tasks::{get_global_task_tracker, TaskGroup};
+use log::{debug, info, warn};
+use std::collections::{BTreeSet, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Largest request head accepted by the PAC, health and API listeners
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+/// Time a client gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+pub const PAC_CONTENT_TYPE: &str = "application/x-ns-proxy-autoconfig";
+
+/// Matching half of the script; `RULES` entries are `[proxied, exact, suffix, keyword]`
+const PAC_FUNCTIONS: &str = r#"function hasName(names, name) {
+  return Object.prototype.hasOwnProperty.call(names, name);
+}
+
+function hasSuffix(suffixes, host) {
+  var name = host;
+  while (true) {
+    if (hasName(suffixes, name)) return true;
+    var dot = name.indexOf(".");
+    if (dot < 0) return false;
+    name = name.substring(dot + 1);
+  }
+}
+
+function hasKeyword(keywords, host) {
+  for (var i = 0; i < keywords.length; i++) {
+    if (host.indexOf(keywords[i]) >= 0) return true;
+  }
+  return false;
+}
+
+function FindProxyForURL(url, host) {
+  host = host.toLowerCase();
+  if (host.charAt(host.length - 1) == ".") host = host.substring(0, host.length - 1);
+  for (var i = 0; i < RULES.length; i++) {
+    var rule = RULES[i];
+    if (hasName(rule[1], host) || hasSuffix(rule[2], host) || hasKeyword(rule[3], host)) {
+      return rule[0] ? PROXY : "DIRECT";
+    }
+  }
+  return FALLBACK ? PROXY : "DIRECT";
+}
+"#;
+
+/// What a PAC script is generated for
+#[derive(Debug, Clone)]
+pub struct PacOptions {
+    /// SOCKS5 `host:port` proxied domains are sent to
+    pub proxy: String,
+    /// Outbounds that connect directly; every other outbound goes through the proxy
+    pub direct: HashSet<String>,
+    pub max_bytes: usize,
+}
+
+impl PacOptions {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            proxy: proxy_address(config),
+            direct: direct_outbounds(config),
+            max_bytes: config.pac.max_bytes,
+        }
+    }
+}
+
+/// SOCKS5 address clients should use: `pac.proxy`, or the listener with
+/// loopback in place of an unspecified host
+fn proxy_address(config: &Config) -> String {
+    if let Some(proxy) = &config.pac.proxy {
+        return proxy.clone();
+    }
+    let mut host = config.server.host;
+    if host.ip.is_unspecified() {
+        host.ip = match host.ip {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        };
+        host.scope_id = 0;
+    }
+    host.socket_addr(config.server.port).to_string()
+}
+
+/// Names of the direct outbounds, builtin `direct` included unless redefined
+fn direct_outbounds(config: &Config) -> HashSet<String> {
+    let mut direct: HashSet<String> = BUILTIN_OUTBOUNDS
+        .iter()
+        .filter(|(_, kind)| matches!(kind, OutboundType::Direct))
+        .map(|(name, _)| name.to_string())
+        .collect();
+    for outbound in &config.outbounds {
+        if matches!(outbound.kind, OutboundType::Direct) {
+            direct.insert(outbound.name.clone());
+        } else {
+            direct.remove(&outbound.name);
+        }
+    }
+    direct
+}
+
+/// A generated PAC script
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacScript {
+    pub script: String,
+    /// Domain entries written into the script
+    pub entries: usize,
+    /// Domain entries dropped by the size cap
+    pub omitted: usize,
+    /// Regex and IP entries PAC cannot express, left to the proxy
+    pub unsupported: usize,
+}
+
+/// Consecutive rules with the same action; merging them keeps first-match order
+struct PacGroup {
+    proxied: bool,
+    exact: BTreeSet<String>,
+    suffix: BTreeSet<String>,
+    keyword: BTreeSet<String>,
+}
+
+fn json_string(s: &str) -> String {
+    serde_json::to_string(s).expect("strings always serialize")
+}
+
+/// Render the router's domain rules as a PAC script
+///
+/// Exact, suffix and keyword entries are matched in rule order; regex and
+/// IP rules are only listed in a comment. Once the script would exceed
+/// `max_bytes` the remaining entries are dropped and unmatched hosts go to
+/// the proxy, which still routes them correctly.
+pub fn render_pac(router: &HighPerformanceRouter, options: &PacOptions) -> PacScript {
+    let manager = router.rule_manager();
+    let mut groups: Vec<PacGroup> = Vec::new();
+    let mut notes = Vec::new();
+    let mut unsupported = 0;
+    for (index, rule) in router.rules().iter().enumerate() {
+        let proxied = !options.direct.contains(&rule.outbound);
+        if groups.last().is_none_or(|group| group.proxied != proxied) {
+            groups.push(PacGroup { proxied, exact: BTreeSet::new(), suffix: BTreeSet::new(), keyword: BTreeSet::new() });
+        }
+        let group = groups.last_mut().expect("pushed above");
+        let (mut regex, mut cidr) = (0, 0);
+        for id in &rule.rule_sets {
+            if let Some(set) = manager.get_domain_set(id) {
+                group.exact.extend(set.domain.iter().cloned());
+                group.suffix.extend(set.domain_suffix.iter().cloned());
+                group.keyword.extend(set.domain_keyword.iter().cloned());
+                regex += set.domain_regex.len();
+            }
+            if let Some(set) = manager.get_ip_set(id) {
+                cidr += set.ip_cidr.len();
+            }
+        }
+        if regex + cidr > 0 {
+            notes.push(format!("//   rule #{} -> {}: {} domain_regex, {} ip_cidr", index, rule.outbound, regex, cidr));
+            unsupported += regex + cidr;
+        }
+    }
+
+    let proxy = json_string(&format!("SOCKS5 {0}; SOCKS {0}", options.proxy));
+    let mut head = String::from("// Generated by anybls from the routing table; do not edit\n");
+    if !notes.is_empty() {
+        head.push_str("// Rules PAC cannot express, left to the proxy:\n");
+        for note in &notes {
+            head.push_str(note);
+            head.push('\n');
+        }
+    }
+    head.push_str(&format!("var PROXY = {};\n", proxy));
+
+    // 预留尾部（FALLBACK、省略说明与函数）的空间，剩余的给规则
+    let reserved = head.len() + PAC_FUNCTIONS.len() + 128;
+    let mut budget = options.max_bytes.saturating_sub(reserved);
+    let (mut entries, mut omitted) = (0, 0);
+    let mut lines = Vec::new();
+    for group in &groups {
+        // 每行的 `  [true,{},{},[]],` 框架
+        budget = budget.saturating_sub(24);
+        let mut fields: [Vec<String>; 3] = Default::default();
+        for (field, kind) in fields.iter_mut().zip([&group.exact, &group.suffix, &group.keyword]) {
+            for name in kind {
+                // 超出上限后其余条目全部省略，不再挑选能放下的短条目
+                if omitted > 0 {
+                    omitted += 1;
+                    continue;
+                }
+                let name = json_string(name);
+                // 对象成员 `"name":1,` 比字符串本身多 3 字节
+                let cost = name.len() + 3;
+                if cost > budget {
+                    omitted += 1;
+                    continue;
+                }
+                budget -= cost;
+                field.push(name);
+            }
+        }
+        if omitted > 0 && fields.iter().all(Vec::is_empty) {
+            continue;
+        }
+        entries += fields.iter().map(Vec::len).sum::<usize>();
+        let [exact, suffix, keyword] = fields;
+        let object = |names: Vec<String>| names.into_iter().map(|name| format!("{}:1", name)).collect::<Vec<_>>().join(",");
+        lines.push(format!(
+            "  [{},{{{}}},{{{}}},[{}]]",
+            group.proxied,
+            object(exact),
+            object(suffix),
+            keyword.join(",")
+        ));
+    }
+
+    let fallback = omitted > 0 || !options.direct.contains(router.default_outbound());
+    let mut script = head;
+    script.push_str("var RULES = [\n");
+    script.push_str(&lines.join(",\n"));
+    if !lines.is_empty() {
+        script.push('\n');
+    }
+    script.push_str("];\n");
+    if omitted > 0 {
+        script.push_str(&format!("// {} entries omitted by the size cap; unmatched hosts use the proxy\n", omitted));
+    }
+    script.push_str(&format!("var FALLBACK = {};\n\n", fallback));
+    script.push_str(PAC_FUNCTIONS);
+    PacScript { script, entries, omitted, unsupported }
+}
+
+fn log_script(pac: &PacScript) {
+    if pac.omitted > 0 {
+        warn!("PAC script capped: {} of {} domain entries omitted", pac.omitted, pac.omitted + pac.entries);
+    }
+    if pac.unsupported > 0 {
+        debug!("PAC script leaves {} regex/IP entries to the proxy", pac.unsupported);
+    }
+}
+
+/// Build the config's router and render its PAC script (the listener's profile if it has one)
+pub async fn generate_pac(config: &Config) -> Result<PacScript> {
+    config.validate()?;
+    let router = Arc::new(build_router(config).await?);
+    let pac = render_pac(&routing_for(&router, config.server.profile.as_deref()), &PacOptions::from_config(config));
+    log_script(&pac);
+    Ok(pac)
+}
+
+fn routing_for(router: &Arc<HighPerformanceRouter>, profile: Option<&str>) -> Arc<HighPerformanceRouter> {
+    profile.and_then(|name| router.profile(name)).unwrap_or_else(|| router.clone())
+}
+
+/// Source of the router the script is generated from
+pub type RouterSource = Box<dyn Fn() -> Arc<HighPerformanceRouter> + Send + Sync>;
+
+/// Serves the PAC script, regenerating it whenever the router is replaced
+pub struct PacService {
+    path: String,
+    profile: Option<String>,
+    options: PacOptions,
+    router: RouterSource,
+    /// 上次生成所用的路由器与结果；路由器被替换（规则重载）后重新生成
+    cached: Mutex<Option<(Arc<HighPerformanceRouter>, Arc<PacScript>)>>,
+}
+
+impl PacService {
+    /// Service following the global router
+    pub fn new(config: &Config) -> Self {
+        Self::with_router_source(config, Box::new(get_global_router))
+    }
+
+    pub fn with_router_source(config: &Config, router: RouterSource) -> Self {
+        Self {
+            path: config.pac.path.clone(),
+            profile: config.server.profile.clone(),
+            options: PacOptions::from_config(config),
+            router,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Current script, rendered again if the router changed since the last call
+    pub fn script(&self) -> Arc<PacScript> {
+        let router = (self.router)();
+        let mut cached = self.cached.lock().unwrap();
+        if let Some((rendered_for, pac)) = &*cached {
+            if Arc::ptr_eq(rendered_for, &router) {
+                return pac.clone();
+            }
+        }
+        let pac = Arc::new(render_pac(&routing_for(&router, self.profile.as_deref()), &self.options));
+        log_script(&pac);
+        *cached = Some((router, pac.clone()));
+        pac
+    }
+
+    async fn handle(&self, mut stream: TcpStream) -> Result<()> {
+        let Some((method, path)) = read_request(&mut stream).await? else {
+            return Ok(());
+        };
+        let pac;
+        let (status, content_type, body) = match (method.as_str(), path == self.path) {
+            ("GET" | "HEAD", true) => {
+                pac = self.script();
+                ("200 OK", PAC_CONTENT_TYPE, pac.script.as_str())
+            }
+            (_, true) => ("405 Method Not Allowed", "text/plain", "method not allowed\n"),
+            _ => ("404 Not Found", "text/plain", "not found\n"),
+        };
+        write_response(&mut stream, &method, status, content_type, body).await
+    }
+}
+
+/// Request line and headers of an HTTP request
+pub(crate) struct RequestHead {
+    pub method: String,
+    /// Path without the query
+    pub path: String,
+    /// Query without the leading '?', empty when there is none
+    pub query: String,
+    headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    /// Value of the first query parameter named `name`
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query.split('&').filter_map(|pair| pair.split_once('=')).find(|(key, _)| *key == name).map(|(_, value)| value)
+    }
+
+    /// Value of the first header named `name`, compared case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+/// Read a request head and return its method and path without the query;
+/// None when the client closed the connection first
+pub(crate) async fn read_request(stream: &mut TcpStream) -> Result<Option<(String, String)>> {
+    Ok(read_request_head(stream).await?.map(|head| (head.method, head.path)))
+}
+
+/// Same as [`read_request`], keeping the headers
+pub(crate) async fn read_request_head(stream: &mut TcpStream) -> Result<Option<RequestHead>> {
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD {
+            return Err(ProxyError::Protocol("HTTP request head too large".to_string()));
+        }
+        let n = tokio::time::timeout(REQUEST_TIMEOUT, stream.read(&mut buf))
+            .await
+            .map_err(|_| ProxyError::Protocol("HTTP request timed out".to_string()))??;
+        if n == 0 {
+            return Ok(None);
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or("").split_whitespace();
+    let method = request_line.next().unwrap_or("").to_string();
+    let target = request_line.next().unwrap_or("");
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let headers = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    Ok(Some(RequestHead { method, path: path.to_string(), query: query.to_string(), headers }))
+}
+
+/// Send a complete response and close the connection; HEAD gets the headers only
+pub(crate) async fn write_response(
+    stream: &mut TcpStream,
+    method: &str,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    write_response_with_headers(stream, method, status, content_type, &[], body).await
+}
+
+/// Same as [`write_response`] with additional headers
+pub(crate) async fn write_response_with_headers(
+    stream: &mut TcpStream,
+    method: &str,
+    status: &str,
+    content_type: &str,
+    headers: &[(&str, String)],
+    body: &str,
+) -> Result<()> {
+    let mut response = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n", status, content_type, body.len());
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str("Cache-Control: no-cache\r\nConnection: close\r\n\r\n");
+    if method != "HEAD" {
+        response.push_str(body);
+    }
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Accept loop of the PAC listener
+pub async fn serve_pac(listener: TcpListener, service: Arc<PacService>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("PAC listener accept failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let service = service.clone();
+        tokio::spawn(async move {
+            if let Err(e) = service.handle(stream).await {
+                debug!("PAC request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Bind `pac.listen` and serve the script when `pac.enabled`
+pub async fn start_pac_server(config: &Config) -> Result<()> {
+    let pac: &PacConfig = &config.pac;
+    if !pac.enabled {
+        return Ok(());
+    }
+    let listener = TcpListener::bind(pac.listen).await?;
+    info!("Serving PAC file at http://{}{}", listener.local_addr()?, pac.path);
+    let service = Arc::new(PacService::new(config));
+    get_global_task_tracker().spawn(TaskGroup::Listeners, serve_pac(listener, service))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DomainLists, OutboundConfig, RouteAction, RouterRuleConfig};
+    use std::collections::HashMap;
+
+    /// One `RULES` entry: proxied, exact names, suffixes, keywords
+    type ModelRule = (bool, HashMap<String, u8>, HashMap<String, u8>, Vec<String>);
+
+    /// JS-free model of the generated `FindProxyForURL`
+    struct PacModel {
+        rules: Vec<ModelRule>,
+        fallback: bool,
+    }
+
+    impl PacModel {
+        fn parse(script: &str) -> Self {
+            let body = script.split("var RULES = [\n").nth(1).unwrap().split("];\n").next().unwrap();
+            let rules = body
+                .lines()
+                .map(|line| serde_json::from_str(line.trim().trim_end_matches(',')).unwrap())
+                .collect();
+            Self { rules, fallback: script.contains("var FALLBACK = true;") }
+        }
+
+        fn find_proxy(&self, host: &str) -> &'static str {
+            let host = host.to_ascii_lowercase();
+            let host = host.strip_suffix('.').unwrap_or(&host);
+            let has_suffix = |suffixes: &HashMap<String, u8>| {
+                let mut name = host;
+                loop {
+                    if suffixes.contains_key(name) {
+                        return true;
+                    }
+                    match name.find('.') {
+                        Some(dot) => name = &name[dot + 1..],
+                        None => return false,
+                    }
+                }
+            };
+            let action = |proxied: bool| if proxied { "PROXY" } else { "DIRECT" };
+            for (proxied, exact, suffix, keyword) in &self.rules {
+                if exact.contains_key(host) || has_suffix(suffix) || keyword.iter().any(|k| host.contains(k.as_str())) {
+                    return action(*proxied);
+                }
+            }
+            action(self.fallback)
+        }
+    }
+
+    fn rule(outbound: &str, domains: DomainLists, ip_cidr: &[&str]) -> RouterRuleConfig {
+        RouterRuleConfig {
+            outbound: outbound.to_string(),
+            action: RouteAction::Forward,
+            outbound_chain: Vec::new(),
+            rule_sets: Vec::new(),
+            domains,
+            ip_cidr: ip_cidr.iter().map(|s| s.to_string()).collect(),
+            dscp: None,
+            latency_mode: false,
+            rewrite_to: None,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
+        }
+    }
+
+    fn list(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn config() -> Config {
+        let mut config = Config {
+            outbounds: vec![OutboundConfig {
+                kind: OutboundType::Socks5 { address: "127.0.0.1:1081".to_string() },
+                ..OutboundConfig::direct("proxy")
+            }],
+            ..Config::default()
+        };
+        config.server.host = "0.0.0.0".parse().unwrap();
+        config.server.port = 1080;
+        config.router.rules = vec![
+            rule("block", DomainLists { domain_suffix: list(&["ads.example"]), ..Default::default() }, &[]),
+            rule(
+                "direct",
+                DomainLists { domain: list(&["cdn.netflix.com"]), domain_suffix: list(&["lan.example"]), ..Default::default() },
+                &[],
+            ),
+            rule(
+                "proxy",
+                DomainLists {
+                    domain_suffix: list(&["netflix.com"]),
+                    domain_keyword: list(&["google"]),
+                    domain_regex: list(&[r"^foo\d+\.test$"]),
+                    ..Default::default()
+                },
+                &["10.0.0.0/8"],
+            ),
+        ];
+        config
+    }
+
+    #[tokio::test]
+    async fn test_pac_matches_router_decisions() {
+        let config = config();
+        let pac = generate_pac(&config).await.unwrap();
+        assert!(pac.script.contains(r#"var PROXY = "SOCKS5 127.0.0.1:1080; SOCKS 127.0.0.1:1080";"#), "{}", pac.script);
+        assert!(pac.script.contains("//   rule #2 -> proxy: 1 domain_regex, 1 ip_cidr"), "{}", pac.script);
+        assert_eq!((pac.entries, pac.omitted, pac.unsupported), (5, 0, 2));
+
+        let model = PacModel::parse(&pac.script);
+        let expected = [
+            ("www.ads.example", "PROXY"),
+            ("cdn.netflix.com", "DIRECT"),
+            ("WWW.Netflix.com.", "PROXY"),
+            ("netflix.com", "PROXY"),
+            ("mynetflix.com", "DIRECT"),
+            ("www.google.co.jp", "PROXY"),
+            ("printer.lan.example", "DIRECT"),
+            ("example.org", "DIRECT"),
+            // 正则规则无法表达，落到默认的 DIRECT
+            ("foo1.test", "DIRECT"),
+        ];
+        for (host, want) in expected {
+            assert_eq!(model.find_proxy(host), want, "{}", host);
+        }
+
+        // 可表达的规则与路由器决定一致
+        let router = build_router(&config).await.unwrap();
+        let direct = direct_outbounds(&config);
+        for (host, want) in &expected[..8] {
+            let outbound = router.explain_domain(host.to_ascii_lowercase().trim_end_matches('.')).decision.outbound;
+            assert_eq!(direct.contains(&outbound), *want == "DIRECT", "{} -> {}", host, outbound);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_rule_sets_capped() {
+        let mut config = config();
+        let sites: Vec<String> = (0..10_000).map(|i| format!("site{:05}.example", i)).collect();
+        config.router.rules =
+            vec![rule("proxy", DomainLists { domain_suffix: sites.clone(), ..Default::default() }, &[])];
+        config.pac.max_bytes = 16 * 1024;
+
+        let pac = generate_pac(&config).await.unwrap();
+        assert!(pac.script.len() <= config.pac.max_bytes, "{} bytes", pac.script.len());
+        assert!(pac.omitted > 0 && pac.entries > 0);
+        assert_eq!(pac.entries + pac.omitted, sites.len());
+        assert!(pac.script.contains(&format!("// {} entries omitted by the size cap", pac.omitted)));
+
+        // 被省略的域名和未列出的域名都交给代理，由它按完整规则路由
+        let model = PacModel::parse(&pac.script);
+        assert_eq!(model.find_proxy("www.site00000.example"), "PROXY");
+        assert_eq!(model.find_proxy("site09999.example"), "PROXY");
+        assert_eq!(model.find_proxy("example.org"), "PROXY");
+
+        config.pac.max_bytes = 4 * 1024 * 1024;
+        let pac = generate_pac(&config).await.unwrap();
+        assert_eq!((pac.entries, pac.omitted), (sites.len(), 0));
+        assert_eq!(PacModel::parse(&pac.script).find_proxy("example.org"), "DIRECT");
+    }
+
+    async fn http_get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(format!("GET {} HTTP/1.1\r\nHost: pac\r\n\r\n", path).as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_served_script_follows_router_reloads() {
+        let config = config();
+        let current = Arc::new(Mutex::new(Arc::new(build_router(&config).await.unwrap())));
+        let source = current.clone();
+        let service = Arc::new(PacService::with_router_source(&config, Box::new(move || source.lock().unwrap().clone())));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve_pac(listener, service.clone()));
+
+        let response = http_get(addr, "/proxy.pac?v=1").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains(&format!("Content-Type: {}\r\n", PAC_CONTENT_TYPE)));
+        assert!(response.ends_with(&service.script().script));
+        assert!(response.contains("\"netflix.com\":1"));
+        assert!(http_get(addr, "/other").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let mut reloaded = config.clone();
+        reloaded.router.rules.truncate(2);
+        *current.lock().unwrap() = Arc::new(build_router(&reloaded).await.unwrap());
+        let response = http_get(addr, "/proxy.pac").await;
+        assert!(!response.contains("\"netflix.com\":1"), "{}", response);
+        assert!(response.contains("\"cdn.netflix.com\":1"));
+        server.abort();
+    }
+}
@@ -1,51 +1,271 @@
 use crate::error::{ProxyError, Result};
+use crate::protocol_util::FramedRead;
+use crate::scope::parse_scoped_ipv6;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use tokio::io::{AsyncRead, AsyncWriteExt};
 
-#[derive(Debug, Clone)]
+/// Longest hostname accepted (RFC 1123, without the trailing dot)
+const MAX_HOSTNAME_LEN: usize = 253;
+/// Longest single label
+const MAX_LABEL_LEN: usize = 63;
+
+/// Display wrapper that escapes non-printable characters in client-supplied
+/// strings before they reach logs or terminals
+pub struct LogSafe<'a>(pub &'a str);
+
+impl std::fmt::Display for LogSafe<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for c in self.0.chars() {
+            if c.is_control() {
+                write!(f, "{}", c.escape_default())?;
+            } else {
+                write!(f, "{}", c)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Address {
     V4(Ipv4Addr),
-    V6(Ipv6Addr),
+    /// IPv6 address and scope ID (0 when unscoped, as in `SocketAddrV6`)
+    V6(Ipv6Addr, u32),
     Domain(String),
 }
 
+/// Wire layout of a target address: type codes and where the port goes
+///
+/// SOCKS5 and shadowsocks share one layout; VLESS puts the port first and
+/// numbers the types differently, sing-box UoT numbers them from zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressFormat {
+    pub ipv4: u8,
+    pub domain: u8,
+    pub ipv6: u8,
+    /// Port precedes the type byte instead of following the address
+    pub port_first: bool,
+}
+
+impl AddressFormat {
+    pub const SOCKS5: AddressFormat = AddressFormat { ipv4: 0x01, domain: 0x03, ipv6: 0x04, port_first: false };
+    pub const SHADOWSOCKS: AddressFormat = AddressFormat::SOCKS5;
+    pub const VLESS: AddressFormat = AddressFormat { ipv4: 0x01, domain: 0x02, ipv6: 0x03, port_first: true };
+    pub const UOT: AddressFormat = AddressFormat { ipv4: 0x00, domain: 0x02, ipv6: 0x01, port_first: false };
+}
+
+fn truncated() -> ProxyError {
+    ProxyError::Protocol("Truncated address".to_string())
+}
+
 impl Address {
+    /// Parse a SOCKS5 address (ATYP + address + port)
     pub fn from_bytes(buf: &mut Bytes) -> Result<(Address, u16)> {
+        Self::read_socks5(buf)
+    }
+
+    pub fn read_socks5(buf: &mut Bytes) -> Result<(Address, u16)> {
+        Self::read_with(buf, AddressFormat::SOCKS5)
+    }
+
+    pub fn write_socks5(&self, buf: &mut BytesMut, port: u16) -> Result<()> {
+        self.write_with(buf, port, AddressFormat::SOCKS5)
+    }
+
+    /// Decode an address in `format`, failing instead of panicking on short input
+    pub fn read_with(buf: &mut Bytes, format: AddressFormat) -> Result<(Address, u16)> {
+        let mut port = None;
+        if format.port_first {
+            if buf.remaining() < 2 {
+                return Err(truncated());
+            }
+            port = Some(buf.get_u16());
+        }
+        if !buf.has_remaining() {
+            return Err(truncated());
+        }
         let addr_type = buf.get_u8();
-        match addr_type {
-            0x01 => {
-                // IPv4
-                let ip = Ipv4Addr::new(buf.get_u8(), buf.get_u8(), buf.get_u8(), buf.get_u8());
-                let port = buf.get_u16();
-                Ok((Address::V4(ip), port))
-            }
-            0x03 => {
-                // Domain name
-                let len = buf.get_u8() as usize;
-                let mut domain = vec![0u8; len];
-                buf.copy_to_slice(&mut domain);
-                let domain = String::from_utf8(domain)
-                    .map_err(|_| ProxyError::Protocol("Invalid domain name".to_string()))?;
-                let port = buf.get_u16();
-                Ok((Address::Domain(domain), port))
-            }
-            0x04 => {
-                // IPv6
-                let mut ip_bytes = [0u8; 16];
-                buf.copy_to_slice(&mut ip_bytes);
-                let ip = Ipv6Addr::from(ip_bytes);
-                let port = buf.get_u16();
-                Ok((Address::V6(ip), port))
-            }
-            _ => Err(ProxyError::InvalidAddressType(addr_type)),
+        let len = if addr_type == format.ipv4 {
+            4
+        } else if addr_type == format.ipv6 {
+            16
+        } else if addr_type == format.domain {
+            if !buf.has_remaining() {
+                return Err(truncated());
+            }
+            buf.get_u8() as usize
+        } else {
+            return Err(ProxyError::InvalidAddressType(addr_type));
+        };
+        let trailer = if format.port_first { 0 } else { 2 };
+        if buf.remaining() < len + trailer {
+            return Err(truncated());
+        }
+        let raw = buf.split_to(len);
+        let address = Self::decode(addr_type, &raw, format)?;
+        let port = match port {
+            Some(port) => port,
+            None => buf.get_u16(),
+        };
+        Ok((address, port))
+    }
+
+    /// Read an address in `format` from a stream, consuming exactly its bytes
+    pub async fn read_from<R>(reader: &mut R, format: AddressFormat) -> Result<(Address, u16)>
+    where
+        R: AsyncRead + Unpin,
+    {
+        Self::read_framed(&mut FramedRead::new(reader), format).await
+    }
+
+    /// Read an address and its port from a [`FramedRead`]
+    pub async fn read_framed<R>(framed: &mut FramedRead<R>, format: AddressFormat) -> Result<(Address, u16)>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let port = if format.port_first { Some(framed.read_u16().await?) } else { None };
+        let addr_type = framed.read_u8().await?;
+        let address = Self::read_body(addr_type, framed, format).await?;
+        let port = match port {
+            Some(port) => port,
+            None => framed.read_u16().await?,
+        };
+        Ok((address, port))
+    }
+
+    /// Read the address body once the caller has consumed the type byte
+    ///
+    /// The port is left in the stream.
+    pub async fn read_after_type<R>(addr_type: u8, reader: &mut R, format: AddressFormat) -> Result<Address>
+    where
+        R: AsyncRead + Unpin,
+    {
+        Self::read_body(addr_type, &mut FramedRead::new(reader), format).await
+    }
+
+    /// Same as [`Address::read_after_type`], reading from a [`FramedRead`]
+    pub async fn read_body<R>(addr_type: u8, framed: &mut FramedRead<R>, format: AddressFormat) -> Result<Address>
+    where
+        R: AsyncRead + Unpin,
+    {
+        // 握手路径不做堆分配：地址在 FramedRead 的内联缓冲区里解析
+        let raw = if addr_type == format.ipv4 {
+            framed.read_exact(4).await?
+        } else if addr_type == format.ipv6 {
+            framed.read_exact(16).await?
+        } else if addr_type == format.domain {
+            framed.read_u8_prefixed().await?
+        } else {
+            return Err(ProxyError::InvalidAddressType(addr_type));
+        };
+        Self::decode(addr_type, raw, format)
+    }
+
+    fn decode(addr_type: u8, raw: &[u8], format: AddressFormat) -> Result<Address> {
+        if addr_type == format.domain {
+            return Self::from_domain_bytes(raw);
         }
+        Ok(match raw.len() {
+            4 => Address::V4(Ipv4Addr::new(raw[0], raw[1], raw[2], raw[3])),
+            _ => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(raw);
+                Address::V6(Ipv6Addr::from(octets), 0)
+            }
+        })
+    }
+
+    /// Encode the address and `port` in `format`
+    ///
+    /// Fails only for domains longer than the 255 bytes a length byte can hold.
+    pub fn write_with(&self, buf: &mut BytesMut, port: u16, format: AddressFormat) -> Result<()> {
+        if let Address::Domain(domain) = self {
+            if domain.len() > u8::MAX as usize {
+                return Err(ProxyError::InvalidDomain(format!("{} bytes", domain.len())));
+            }
+        }
+        if format.port_first {
+            buf.put_u16(port);
+        }
+        match self {
+            Address::V4(ip) => {
+                buf.put_u8(format.ipv4);
+                buf.put_slice(&ip.octets());
+            }
+            // 区域只在本机有意义，线上格式不携带
+            Address::V6(ip, _) => {
+                buf.put_u8(format.ipv6);
+                buf.put_slice(&ip.octets());
+            }
+            Address::Domain(domain) => {
+                buf.put_u8(format.domain);
+                buf.put_u8(domain.len() as u8);
+                buf.put_slice(domain.as_bytes());
+            }
+        }
+        if !format.port_first {
+            buf.put_u16(port);
+        }
+        Ok(())
+    }
+
+    /// Validate a client-supplied hostname (ATYP=domain)
+    ///
+    /// Enforces RFC 952/1123 label rules, strips one trailing dot and turns IP
+    /// literals, which some clients send as domains, into IP addresses.
+    /// Underscores are accepted since they are common in real-world names.
+    pub fn from_domain_bytes(raw: &[u8]) -> Result<Address> {
+        let invalid = |reason: &str| {
+            ProxyError::InvalidDomain(format!("{} ({})", reason, LogSafe(&String::from_utf8_lossy(raw))))
+        };
+
+        if raw.iter().any(|b| b.is_ascii_control()) {
+            return Err(invalid("control character"));
+        }
+        let name = std::str::from_utf8(raw).map_err(|_| invalid("not UTF-8"))?;
+
+        // IP字面量走IP地址路径
+        let literal = name.strip_prefix('[').and_then(|n| n.strip_suffix(']')).unwrap_or(name);
+        if let Ok(ip) = literal.parse::<IpAddr>() {
+            return Ok(Address::from(ip));
+        }
+        // 非标准但常见：域名类型里带区域的链路本地地址，如 "fe80::1%eth0"
+        if literal.contains('%') {
+            let (ip, scope_id) = parse_scoped_ipv6(literal).map_err(|e| invalid(&e.to_string()))?;
+            return Ok(Address::V6(ip, scope_id));
+        }
+
+        let name = name.strip_suffix('.').unwrap_or(name);
+        if name.is_empty() {
+            return Err(invalid("empty name"));
+        }
+        if name.len() > MAX_HOSTNAME_LEN {
+            return Err(invalid("name longer than 253 bytes"));
+        }
+        for label in name.split('.') {
+            if label.is_empty() {
+                return Err(invalid("empty label"));
+            }
+            if label.len() > MAX_LABEL_LEN {
+                return Err(invalid("label longer than 63 bytes"));
+            }
+            if !label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+                return Err(invalid("invalid character"));
+            }
+            if label.starts_with('-') || label.ends_with('-') {
+                return Err(invalid("label starts or ends with a hyphen"));
+            }
+        }
+
+        Ok(Address::Domain(name.to_string()))
     }
 
     pub fn to_socket_addr(&self, port: u16) -> Result<SocketAddr> {
         match self {
             Address::V4(ip) => Ok(SocketAddr::new(IpAddr::V4(*ip), port)),
-            Address::V6(ip) => Ok(SocketAddr::new(IpAddr::V6(*ip), port)),
+            Address::V6(ip, scope_id) => Ok(SocketAddr::V6(SocketAddrV6::new(*ip, port, 0, *scope_id))),
             Address::Domain(_) => Err(ProxyError::Protocol("Domain resolution requires async context".to_string())),
         }
     }
@@ -53,16 +273,140 @@ impl Address {
     pub async fn to_socket_addr_async(&self, port: u16) -> Result<SocketAddr> {
         match self {
             Address::V4(ip) => Ok(SocketAddr::new(IpAddr::V4(*ip), port)),
-            Address::V6(ip) => Ok(SocketAddr::new(IpAddr::V6(*ip), port)),
+            Address::V6(ip, scope_id) => Ok(SocketAddr::V6(SocketAddrV6::new(*ip, port, 0, *scope_id))),
+            Address::Domain(domain) => {
+                use crate::dns::try_get_global_dns_resolver;
+                use crate::rebinding::get_global_rebinding_guard;
+                let addr = try_get_global_dns_resolver()?.resolve_domain(domain, port).await?;
+                let addrs = get_global_rebinding_guard().check(domain, vec![addr])?;
+                Ok(addrs[0])
+            }
+        }
+    }
+
+    /// Loopback addresses, including IPv4-mapped ones and `localhost` names
+    pub fn is_loopback(&self) -> bool {
+        match self {
+            Address::V4(ip) => ip.is_loopback(),
+            Address::V6(ip, _) => ip.is_loopback() || ip.to_ipv4_mapped().is_some_and(|v4| v4.is_loopback()),
             Address::Domain(domain) => {
-                use crate::dns::get_global_dns_resolver;
-                get_global_dns_resolver().resolve_domain(domain, port).await
+                let domain = domain.to_ascii_lowercase();
+                domain == "localhost" || domain.ends_with(".localhost")
             }
         }
     }
+
+    /// Addresses of private networks: RFC 1918, shared address space
+    /// (100.64.0.0/10), link-local and IPv6 unique local
+    ///
+    /// Loopback is reported by [`Address::is_loopback`] instead; domains are
+    /// never private since that depends on what they resolve to.
+    pub fn is_private(&self) -> bool {
+        fn v4_private(ip: &Ipv4Addr) -> bool {
+            let [a, b, ..] = ip.octets();
+            ip.is_private() || ip.is_link_local() || (a == 100 && (64..128).contains(&b))
+        }
+        match self {
+            Address::V4(ip) => v4_private(ip),
+            Address::V6(ip, _) => match ip.to_ipv4_mapped() {
+                Some(v4) => v4_private(&v4),
+                // fc00::/7 唯一本地地址，fe80::/10 链路本地地址
+                None => (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80,
+            },
+            Address::Domain(_) => false,
+        }
+    }
+
+    /// Domain names with at least two labels, as opposed to IPs and bare
+    /// host names such as `localhost`
+    pub fn is_fqdn(&self) -> bool {
+        matches!(self, Address::Domain(domain) if domain.contains('.'))
+    }
+}
+
+impl From<IpAddr> for Address {
+    fn from(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => Address::V4(ip),
+            IpAddr::V6(ip) => Address::V6(ip, 0),
+        }
+    }
 }
 
-#[derive(Debug)]
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(v4) => Address::V4(*v4.ip()),
+            SocketAddr::V6(v6) => Address::V6(*v6.ip(), v6.scope_id()),
+        }
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::V4(ip) => write!(f, "{}", ip),
+            Address::V6(ip, 0) => write!(f, "[{}]", ip),
+            Address::V6(ip, scope_id) => write!(f, "[{}%{}]", ip, scope_id),
+            Address::Domain(domain) => write!(f, "{}", LogSafe(domain)),
+        }
+    }
+}
+
+/// Address plus port, written as "host:port" ("[v6]:port" or "[v6%zone]:port"
+/// for IPv6)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TargetAddr {
+    pub address: Address,
+    pub port: u16,
+}
+
+impl TargetAddr {
+    pub fn new(address: Address, port: u16) -> Self {
+        Self { address, port }
+    }
+
+    /// The socket address for IP targets, None for domains
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        self.address.to_socket_addr(self.port).ok()
+    }
+}
+
+impl From<SocketAddr> for TargetAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self::new(Address::from(addr), addr.port())
+    }
+}
+
+impl std::str::FromStr for TargetAddr {
+    type Err = ProxyError;
+
+    /// Parse "host:port"; IPv6 literals must be bracketed so the port is unambiguous
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |reason: &str| ProxyError::Protocol(format!("Invalid address {}: {}", LogSafe(s), reason));
+
+        let (host, port) = s.rsplit_once(':').ok_or_else(|| invalid("missing port"))?;
+        let port = port.parse::<u16>().map_err(|_| invalid("invalid port"))?;
+        let address = if let Some(inner) = host.strip_prefix('[') {
+            let inner = inner.strip_suffix(']').ok_or_else(|| invalid("unclosed bracket"))?;
+            let (ip, scope_id) = parse_scoped_ipv6(inner).map_err(|e| invalid(&e.to_string()))?;
+            Address::V6(ip, scope_id)
+        } else if host.contains(':') {
+            return Err(invalid("IPv6 address must be bracketed"));
+        } else {
+            Address::from_domain_bytes(host.as_bytes())?
+        };
+        Ok(Self { address, port })
+    }
+}
+
+impl std::fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.address, self.port)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Socks5Request {
     pub command: u8,
     pub address: Address,
@@ -95,8 +439,39 @@ impl Socks5Request {
             port,
         })
     }
+
+    /// Encode the request as a client sends it
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        let mut buf = BytesMut::with_capacity(4 + 1 + 255 + 2);
+        buf.put_slice(&[0x05, self.command, 0x00]);
+        self.address.write_socks5(&mut buf, self.port)?;
+        Ok(buf.freeze())
+    }
+
+    /// Read one request from the client, however it is split across writes
+    ///
+    /// Reads exactly the request's bytes, so data the client pipelines after
+    /// it stays in the stream.
+    pub async fn read_from<R>(reader: &mut R) -> Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut framed: FramedRead<_> = FramedRead::new(reader);
+        let head = framed.read_array::<4>().await?;
+        if head[0] != 0x05 {
+            return Err(ProxyError::Protocol(format!("Unsupported SOCKS version: {}", head[0])));
+        }
+        if head[1] != 0x01 {
+            return Err(ProxyError::UnsupportedCommand(head[1]));
+        }
+
+        let address = Address::read_body(head[3], &mut framed, AddressFormat::SOCKS5).await?;
+        let port = framed.read_u16().await?;
+        Ok(Socks5Request { command: head[1], address, port })
+    }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Socks5Response {
     pub status: u8,
     pub address: Address,
@@ -108,37 +483,50 @@ impl Socks5Response {
         Self { status, address, port }
     }
 
-    pub fn to_bytes(&self) -> Bytes {
-        let mut buf = BytesMut::with_capacity(256);
-
-        // Version
-        buf.put_u8(0x05);
-        // Status
-        buf.put_u8(self.status);
-        // Reserved
-        buf.put_u8(0x00);
+    pub fn from_bytes(buf: &mut Bytes) -> Result<Self> {
+        if buf.len() < 4 {
+            return Err(ProxyError::Protocol("Incomplete SOCKS5 reply".to_string()));
+        }
 
-        // Address
-        match &self.address {
-            Address::V4(ip) => {
-                buf.put_u8(0x01); // IPv4
-                buf.put_slice(&ip.octets());
-            }
-            Address::V6(ip) => {
-                buf.put_u8(0x04); // IPv6
-                buf.put_slice(&ip.octets());
-            }
-            Address::Domain(domain) => {
-                buf.put_u8(0x03); // Domain
-                buf.put_u8(domain.len() as u8);
-                buf.put_slice(domain.as_bytes());
-            }
+        let version = buf.get_u8();
+        if version != 0x05 {
+            return Err(ProxyError::Protocol(format!("Unsupported SOCKS version: {}", version)));
         }
 
-        // Port
-        buf.put_u16(self.port);
+        let status = buf.get_u8();
+        buf.get_u8(); // Reserved byte
 
-        buf.freeze()
+        let (address, port) = Address::from_bytes(buf)?;
+
+        Ok(Socks5Response { status, address, port })
+    }
+
+    /// Encode the reply; fails for a domain longer than 255 bytes, which
+    /// the one-byte length prefix cannot carry
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        let mut buf = BytesMut::with_capacity(4 + 1 + 255 + 2);
+        buf.put_slice(&[0x05, self.status, 0x00]);
+        self.address.write_socks5(&mut buf, self.port)?;
+        Ok(buf.freeze())
+    }
+
+    /// Read one reply from the server, however it is split across writes
+    ///
+    /// Reads exactly the reply's bytes, so tunneled data the server sends
+    /// right after it stays in the stream.
+    pub async fn read_from<R>(reader: &mut R) -> Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut framed: FramedRead<_> = FramedRead::new(reader);
+        let head = framed.read_array::<4>().await?;
+        if head[0] != 0x05 {
+            return Err(ProxyError::Protocol(format!("Unsupported SOCKS version: {}", head[0])));
+        }
+
+        let address = Address::read_body(head[3], &mut framed, AddressFormat::SOCKS5).await?;
+        let port = framed.read_u16().await?;
+        Ok(Socks5Response { status: head[1], address, port })
     }
 }
 
@@ -146,36 +534,441 @@ pub async fn handle_socks5_handshake<T>(stream: &mut T) -> Result<()>
 where
     T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
 {
-    let mut buf = [0u8; 256];
-    let n = stream.read(&mut buf).await?;
+    handle_socks5_handshake_with_auth(stream, false).await.map(|_| ())
+}
 
-    if n < 3 {
-        return Err(ProxyError::Protocol("Incomplete handshake".to_string()));
-    }
+/// SOCKS5 method negotiation, optionally accepting username/password (RFC 1929)
+///
+/// With `userpass` set, clients offering method 0x02 go through the
+/// username/password sub-negotiation and the username is returned; the
+/// password is not checked, the username only serves as a routing hint.
+/// Clients that only offer "no authentication" are still accepted.
+pub async fn handle_socks5_handshake_with_auth<T>(stream: &mut T, userpass: bool) -> Result<Option<String>>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let policy = if userpass { MethodPolicy::UsernameHint } else { MethodPolicy::NoAuth };
+    negotiate_socks5_auth(stream, policy).await.map(|user| user.map(|user| user.name))
+}
+
+/// Which methods the server accepts from a client
+#[derive(Debug, Clone, Copy)]
+pub enum MethodPolicy<'a> {
+    /// Only "no authentication"
+    NoAuth,
+    /// "No authentication", or username/password with the username used
+    /// as a routing hint and the password ignored
+    UsernameHint,
+    /// Username/password checked against `users` (username -> password)
+    Required(&'a HashMap<String, String>),
+}
+
+/// Username a client sent in the RFC 1929 sub-negotiation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocksUser {
+    pub name: String,
+    /// Whether the password was checked
+    pub authenticated: bool,
+}
+
+/// SOCKS5 method negotiation under `policy`
+///
+/// Under `Required` only method 0x02 is offered; clients that do not offer
+/// it get 0xFF, and a wrong username or password gets a failure status.
+pub async fn negotiate_socks5_auth<T>(stream: &mut T, policy: MethodPolicy<'_>) -> Result<Option<SocksUser>>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    negotiate_socks5_auth_with(stream, policy, || {}).await
+}
 
-    let version = buf[0];
+/// Same as [`negotiate_socks5_auth`], calling `on_auth` when the
+/// username/password sub-negotiation starts
+pub async fn negotiate_socks5_auth_with<T>(
+    stream: &mut T,
+    policy: MethodPolicy<'_>,
+    on_auth: impl FnOnce(),
+) -> Result<Option<SocksUser>>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    // 按字节数精确读取，客户端可能分多次写入，也可能紧接着发送请求
+    let mut framed: FramedRead<_> = FramedRead::new(stream);
+    let head = framed.read_array::<2>().await?;
+
+    let version = head[0];
     if version != 0x05 {
         return Err(ProxyError::Protocol(format!("Unsupported SOCKS version: {}", version)));
     }
 
-    let nmethods = buf[1] as usize;
-    if n < 2 + nmethods {
-        return Err(ProxyError::Protocol("Incomplete handshake".to_string()));
+    let methods = framed.read_exact(head[1] as usize).await?;
+    let offers_userpass = methods.contains(&0x02);
+    let offers_no_auth = methods.contains(&0x00);
+    match policy {
+        MethodPolicy::Required(users) if offers_userpass => {
+            framed.get_mut().write_all(&[0x05, 0x02]).await?;
+            on_auth();
+            let (name, password) = read_userpass(&mut framed).await?;
+            if users.get(&name).is_none_or(|expected| *expected != password) {
+                framed.get_mut().write_all(&[0x01, 0x01]).await?;
+                return Err(ProxyError::AuthFailed);
+            }
+            framed.get_mut().write_all(&[0x01, 0x00]).await?;
+            return Ok(Some(SocksUser { name, authenticated: true }));
+        }
+        MethodPolicy::UsernameHint if offers_userpass => {
+            framed.get_mut().write_all(&[0x05, 0x02]).await?;
+            on_auth();
+            // 密码不校验
+            let (name, _) = read_userpass(&mut framed).await?;
+            framed.get_mut().write_all(&[0x01, 0x00]).await?;
+            return Ok(Some(SocksUser { name, authenticated: false }));
+        }
+        _ => {}
     }
 
     // Check if no authentication is supported
-    let no_auth_supported = buf[2..2 + nmethods].contains(&0x00);
+    let no_auth_supported = offers_no_auth && !matches!(policy, MethodPolicy::Required(_));
 
     if !no_auth_supported {
         // Send "no acceptable methods" response
         let response = [0x05, 0xFF];
-        stream.write_all(&response).await?;
+        framed.get_mut().write_all(&response).await?;
         return Err(ProxyError::AuthFailed);
     }
 
     // Send "no authentication required" response
     let response = [0x05, 0x00];
-    stream.write_all(&response).await?;
+    framed.get_mut().write_all(&response).await?;
 
-    Ok(())
+    Ok(None)
+}
+
+/// Username/password sub-negotiation; returns the username and password
+/// without sending the status
+async fn read_userpass<T>(framed: &mut FramedRead<T>) -> Result<(String, String)>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let version = framed.read_u8().await?;
+    if version != 0x01 {
+        framed.get_mut().write_all(&[0x01, 0x01]).await?;
+        return Err(ProxyError::Protocol(format!("Unsupported auth version: {}", version)));
+    }
+    let username = String::from_utf8_lossy(framed.read_u8_prefixed().await?).into_owned();
+    let password = String::from_utf8_lossy(framed.read_u8_prefixed().await?).into_owned();
+    Ok((username, password))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn parse(name: &[u8]) -> Result<Address> {
+        Address::from_domain_bytes(name)
+    }
+
+    fn assert_rejected(name: &[u8], reason: &str) {
+        let err = parse(name).unwrap_err();
+        assert!(matches!(err, ProxyError::InvalidDomain(_)), "{}", err);
+        assert!(err.to_string().contains(reason), "{}", err);
+        assert_eq!(err.socks5_reply_code(), 0x08);
+    }
+
+    #[tokio::test]
+    async fn test_userpass_handshake_returns_username() {
+        let (mut client, mut server) = tokio::io::duplex(256);
+        let server = tokio::spawn(async move { handle_socks5_handshake_with_auth(&mut server, true).await });
+
+        client.write_all(&[0x05, 0x02, 0x00, 0x02]).await.unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x02]);
+
+        client.write_all(&[0x01, 0x07]).await.unwrap();
+        client.write_all(b"jp-node").await.unwrap();
+        client.write_all(&[0x01, b'x']).await.unwrap();
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x01, 0x00]);
+        assert_eq!(server.await.unwrap().unwrap().as_deref(), Some("jp-node"));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_and_request_arrive_one_byte_at_a_time() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let greeting = [0x05, 0x02, 0x01, 0x00];
+        let request = Socks5Request { command: 0x01, address: Address::Domain("example.com".to_string()), port: 443 };
+        let mut sent = greeting.to_vec();
+        sent.extend_from_slice(&request.to_bytes().unwrap());
+        sent.extend_from_slice(b"GET");
+        let writer = tokio::spawn(async move {
+            for byte in sent {
+                client.write_all(&[byte]).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+            client
+        });
+
+        handle_socks5_handshake(&mut server).await.unwrap();
+        assert_eq!(Socks5Request::read_from(&mut server).await.unwrap(), request);
+        // 请求之后流水线发送的数据留在流里
+        let mut pipelined = [0u8; 3];
+        server.read_exact(&mut pipelined).await.unwrap();
+        assert_eq!(&pipelined, b"GET");
+
+        let mut client = writer.await.unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x00]);
+
+        // 每种地址类型都按 ATYP 决定的长度读取
+        for address in sample_addresses() {
+            let request = Socks5Request { command: 0x01, address, port: 8080 };
+            let (mut client, mut server) = tokio::io::duplex(64);
+            let bytes = request.to_bytes().unwrap();
+            let writer = tokio::spawn(async move {
+                for byte in bytes {
+                    client.write_all(&[byte]).await.unwrap();
+                    tokio::task::yield_now().await;
+                }
+            });
+            assert_eq!(Socks5Request::read_from(&mut server).await.unwrap(), request);
+            writer.await.unwrap();
+        }
+    }
+
+    #[test]
+    fn test_valid_hostnames() {
+        assert!(matches!(parse(b"www.example.com"), Ok(Address::Domain(d)) if d == "www.example.com"));
+        assert!(matches!(parse(b"example.com."), Ok(Address::Domain(d)) if d == "example.com"));
+        assert!(matches!(parse(b"_dmarc.xn--bcher-kva.de"), Ok(Address::Domain(_))));
+        assert!(matches!(parse(b"localhost"), Ok(Address::Domain(_))));
+    }
+
+    #[test]
+    fn test_rejects_malformed_hostnames() {
+        assert_rejected(b"evil\x1b[31m.com", "control character");
+        assert_rejected(b"bad\n.com", "control character");
+        assert_rejected(b"", "empty name");
+        assert_rejected(b".", "empty name");
+        assert_rejected(b".example.com", "empty label");
+        assert_rejected(b"example..com", "empty label");
+        assert_rejected(b"example.com..", "empty label");
+        assert_rejected(format!("{}.com", "a".repeat(64)).as_bytes(), "label longer than 63");
+        assert_rejected(vec!["a".repeat(50); 5].join(".").as_bytes(), "longer than 253");
+        assert_rejected(b"exa mple.com", "invalid character");
+        assert_rejected("b\u{fc}cher.de".as_bytes(), "invalid character");
+        assert_rejected(b"-example.com", "hyphen");
+        assert_rejected(b"\xff\xfe.com", "not UTF-8");
+    }
+
+    #[test]
+    fn test_ip_literal_converted_to_ip_address() {
+        assert!(matches!(parse(b"192.0.2.10"), Ok(Address::V4(ip)) if ip == Ipv4Addr::new(192, 0, 2, 10)));
+        assert!(matches!(parse(b"2001:db8::1"), Ok(Address::V6(..))));
+        assert!(matches!(parse(b"[2001:db8::1]"), Ok(Address::V6(..))));
+
+        let mut request = Bytes::from_static(b"\x05\x01\x00\x03\x0810.0.0.1\x00\x50");
+        let request = Socks5Request::from_bytes(&mut request).unwrap();
+        assert!(matches!(request.address, Address::V4(ip) if ip == Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(request.port, 80);
+    }
+
+    fn sample_addresses() -> Vec<Address> {
+        vec![
+            Address::V4(Ipv4Addr::new(192, 0, 2, 1)),
+            Address::V6("2001:db8::1".parse().unwrap(), 0),
+            Address::V6(Ipv6Addr::UNSPECIFIED, 0),
+            Address::Domain("example.com".to_string()),
+            Address::Domain("a".repeat(63)),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_address_round_trips_in_every_format() {
+        for format in [AddressFormat::SOCKS5, AddressFormat::VLESS, AddressFormat::UOT] {
+            for address in sample_addresses() {
+                let mut buf = BytesMut::new();
+                address.write_with(&mut buf, 8443, format).unwrap();
+                let encoded = buf.freeze();
+
+                let mut bytes = encoded.clone();
+                assert_eq!(Address::read_with(&mut bytes, format).unwrap(), (address.clone(), 8443));
+                assert!(bytes.is_empty());
+
+                let mut reader = &encoded[..];
+                assert_eq!(Address::read_from(&mut reader, format).await.unwrap(), (address.clone(), 8443));
+                assert!(reader.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn test_address_layouts() {
+        let address = Address::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let encode = |format| {
+            let mut buf = BytesMut::new();
+            address.write_with(&mut buf, 80, format).unwrap();
+            buf.to_vec()
+        };
+        assert_eq!(encode(AddressFormat::SOCKS5), [0x01, 10, 0, 0, 1, 0, 80]);
+        assert_eq!(encode(AddressFormat::SHADOWSOCKS), [0x01, 10, 0, 0, 1, 0, 80]);
+        assert_eq!(encode(AddressFormat::VLESS), [0, 80, 0x01, 10, 0, 0, 1]);
+        assert_eq!(encode(AddressFormat::UOT), [0x00, 10, 0, 0, 1, 0, 80]);
+
+        let mut buf = BytesMut::new();
+        Address::Domain("a.io".to_string()).write_socks5(&mut buf, 443).unwrap();
+        assert_eq!(&buf[..], b"\x03\x04a.io\x01\xbb");
+    }
+
+    #[test]
+    fn test_malformed_addresses_rejected() {
+        let read = |raw: &'static [u8]| Address::read_socks5(&mut Bytes::from_static(raw));
+        for truncated in [&b""[..], b"\x01\x7f\x00", b"\x01\x7f\x00\x00\x01\x00", b"\x03", b"\x03\x05abc", b"\x04\x00"] {
+            assert!(matches!(read(truncated), Err(ProxyError::Protocol(_))), "{:?}", truncated);
+        }
+        assert!(matches!(read(b"\x02\x00\x00"), Err(ProxyError::InvalidAddressType(0x02))));
+        assert!(matches!(read(b"\x03\x03a b\x00\x50"), Err(ProxyError::InvalidDomain(_))));
+        let mut vless = Bytes::from_static(b"\x00\x50\x04");
+        assert!(matches!(Address::read_with(&mut vless, AddressFormat::VLESS), Err(ProxyError::InvalidAddressType(0x04))));
+
+        let mut buf = BytesMut::new();
+        let long = Address::Domain("a".repeat(256));
+        assert!(matches!(long.write_socks5(&mut buf, 80), Err(ProxyError::InvalidDomain(_))));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_target_addr_parse_and_display() {
+        let parse = |s: &str| s.parse::<TargetAddr>();
+        assert_eq!(parse("example.com:443").unwrap(), TargetAddr::new(Address::Domain("example.com".to_string()), 443));
+        assert_eq!(parse("192.0.2.1:80").unwrap(), TargetAddr::from("192.0.2.1:80".parse::<SocketAddr>().unwrap()));
+        assert_eq!(parse("[::1]:53").unwrap().address, Address::V6(Ipv6Addr::LOCALHOST, 0));
+        assert_eq!(parse("[::ffff:10.0.0.1]:1").unwrap().address, Address::V6("::ffff:10.0.0.1".parse().unwrap(), 0));
+
+        for bad in ["example.com", "example.com:", "example.com:65536", "::1:53", "[::1:53", "[example.com]:80", "[::1]", ":80", "exa mple.com:80"] {
+            assert!(parse(bad).is_err(), "{}", bad);
+        }
+
+        for text in ["example.com:443", "192.0.2.1:80", "[2001:db8::1]:8080", "[::]:0"] {
+            assert_eq!(parse(text).unwrap().to_string(), text);
+        }
+        let v6: SocketAddr = "[fe80::1]:22".parse().unwrap();
+        assert_eq!(TargetAddr::from(v6).to_string(), v6.to_string());
+        assert_eq!(Address::from(v6), Address::V6("fe80::1".parse().unwrap(), 0));
+    }
+
+    #[test]
+    fn test_scoped_ipv6_parse_and_display() {
+        let scoped = Address::V6("fe80::1".parse().unwrap(), 3);
+        let target: TargetAddr = "[fe80::1%3]:8080".parse().unwrap();
+        assert_eq!(target, TargetAddr::new(scoped.clone(), 8080));
+        assert_eq!(target.to_string(), "[fe80::1%3]:8080");
+        let socket_addr = scoped.to_socket_addr(8080).unwrap();
+        assert_eq!(socket_addr.to_string(), "[fe80::1%3]:8080");
+        assert_eq!(TargetAddr::from(socket_addr), target);
+
+        // 域名类型里带区域的写法，带或不带方括号
+        assert_eq!(parse(b"fe80::1%3").unwrap(), scoped);
+        assert_eq!(parse(b"[fe80::1%3]").unwrap(), scoped);
+        let err = parse(b"fe80::1%no-such-interface0").unwrap_err();
+        assert!(matches!(err, ProxyError::InvalidDomain(_)), "{}", err);
+        assert!(parse(b"10.0.0.1%3").is_err());
+        assert!("[fe80::1%]:80".parse::<TargetAddr>().is_err());
+
+        // 线上格式不携带区域
+        let mut buf = BytesMut::new();
+        scoped.write_socks5(&mut buf, 80).unwrap();
+        assert_eq!(Address::read_socks5(&mut buf.freeze()).unwrap(), (Address::V6("fe80::1".parse().unwrap(), 0), 80));
+
+        if let Some(index) = crate::scope::interface_index("lo") {
+            let mut request = BytesMut::from(&b"\x05\x01\x00\x03"[..]);
+            request.put_u8(b"fe80::1%lo".len() as u8);
+            request.put_slice(b"fe80::1%lo");
+            request.put_u16(22);
+            let request = Socks5Request::from_bytes(&mut request.freeze()).unwrap();
+            assert_eq!(request.address, Address::V6("fe80::1".parse().unwrap(), index));
+        }
+    }
+
+    #[test]
+    fn test_address_classification() {
+        let addr = |s: &str| s.parse::<TargetAddr>().unwrap().address;
+        assert!(addr("127.0.0.1:1").is_loopback());
+        assert!(addr("[::1]:1").is_loopback());
+        assert!(addr("[::ffff:127.0.0.2]:1").is_loopback());
+        assert!(addr("LocalHost:1").is_loopback() && addr("app.localhost:1").is_loopback());
+        assert!(!addr("192.168.1.1:1").is_loopback());
+
+        for private in ["10.1.2.3", "172.16.0.1", "192.168.1.1", "100.64.0.1", "169.254.1.1", "[fd00::1]", "[fe80::1]", "[::ffff:10.0.0.1]"] {
+            assert!(addr(&format!("{}:1", private)).is_private(), "{}", private);
+        }
+        for public in ["8.8.8.8", "100.128.0.1", "127.0.0.1", "[2001:db8::1]", "[::1]", "example.com"] {
+            assert!(!addr(&format!("{}:1", public)).is_private(), "{}", public);
+        }
+
+        assert!(addr("example.com:1").is_fqdn());
+        assert!(!addr("localhost:1").is_fqdn());
+        assert!(!addr("10.0.0.1:1").is_fqdn());
+    }
+
+    #[test]
+    fn test_request_to_bytes_round_trip() {
+        for address in sample_addresses() {
+            let request = Socks5Request { command: 0x01, address, port: 1080 };
+            let mut bytes = request.to_bytes().unwrap();
+            assert_eq!(&bytes[..3], [0x05, 0x01, 0x00]);
+            assert_eq!(Socks5Request::from_bytes(&mut bytes).unwrap(), request);
+        }
+
+        let response = Socks5Response::new(0x00, Address::V6(Ipv6Addr::LOCALHOST, 0), 53).to_bytes().unwrap();
+        let mut expected = vec![0x05, 0x00, 0x00, 0x04];
+        expected.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        expected.extend_from_slice(&[0, 53]);
+        assert_eq!(&response[..], expected);
+    }
+
+    #[tokio::test]
+    async fn test_response_round_trips_every_address_type() {
+        for address in sample_addresses() {
+            let response = Socks5Response::new(0x05, address, 8443);
+            let bytes = response.to_bytes().unwrap();
+            assert_eq!(Socks5Response::from_bytes(&mut bytes.clone()).unwrap(), response);
+
+            // 逐字节到达的应答，以及紧跟其后的隧道数据
+            let (mut client, mut server) = tokio::io::duplex(64);
+            let written = [&bytes[..], b"tunnel"].concat();
+            tokio::spawn(async move {
+                for byte in written {
+                    server.write_all(&[byte]).await.unwrap();
+                }
+            });
+            assert_eq!(Socks5Response::read_from(&mut client).await.unwrap(), response);
+            let mut rest = [0u8; 6];
+            client.read_exact(&mut rest).await.unwrap();
+            assert_eq!(&rest, b"tunnel");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_rejects_oversized_and_truncated_replies() {
+        let oversized = Socks5Response::new(0x00, Address::Domain("a".repeat(256)), 80);
+        assert!(oversized.to_bytes().is_err());
+
+        let bytes = Socks5Response::new(0x00, Address::Domain("example.com".to_string()), 443).to_bytes().unwrap();
+        for len in [2, 4, bytes.len() - 1] {
+            let mut truncated = bytes.slice(..len);
+            assert!(Socks5Response::from_bytes(&mut truncated).is_err(), "{} bytes", len);
+            let mut reader = &bytes[..len];
+            assert!(Socks5Response::read_from(&mut reader).await.is_err(), "{} bytes", len);
+        }
+        assert!(Socks5Response::from_bytes(&mut Bytes::from_static(&[0x04, 0x5a, 0x00, 0x01, 0, 0, 0, 0, 0, 0])).is_err());
+    }
+
+    #[test]
+    fn test_log_safe_escapes_control_characters() {
+        assert_eq!(LogSafe("a\x1b[0mb\n").to_string(), "a\\u{1b}[0mb\\n");
+        assert_eq!(Address::Domain("x\ty".to_string()).to_string(), "x\\ty");
+    }
 }
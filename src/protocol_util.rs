@@ -0,0 +1,166 @@
+// 入站协议解析共用的分帧读取：按字段精确读取、长度有上限、被取消后可以继续
+use crate::error::{ProxyError, Result};
+use std::io::ErrorKind;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Largest field a [`FramedRead`] holds by default, enough for a SOCKS5
+/// request carrying a 255-byte domain
+pub const DEFAULT_FIELD_LIMIT: usize = 512;
+
+/// Reads length-delimited fields from a stream without reading past them
+///
+/// A field is handed out only once all of its bytes arrived. A read that is
+/// cancelled half-way (dropped by `select!`, or timed out) keeps what it
+/// already got, and the next read on the same `FramedRead` continues the
+/// field. Fields are collected in an inline buffer of `N` bytes, so parsing
+/// does not allocate and no length prefix can make it read more than `N`
+/// bytes; every read returns at least one byte, so a field of `len` bytes
+/// takes at most `len` reads.
+pub struct FramedRead<R, const N: usize = DEFAULT_FIELD_LIMIT> {
+    reader: R,
+    buf: [u8; N],
+    /// Bytes of the current field received so far
+    filled: usize,
+    /// Longest a single field may take to arrive
+    timeout: Option<Duration>,
+}
+
+impl<R: AsyncRead + Unpin, const N: usize> FramedRead<R, N> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, buf: [0; N], filled: 0, timeout: None }
+    }
+
+    /// Fail reads whose field does not arrive within `timeout` with `ErrorKind::TimedOut`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// The underlying stream, e.g. to write replies between fields
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Read exactly `len` bytes
+    pub async fn read_exact(&mut self, len: usize) -> Result<&[u8]> {
+        self.fill(len).await?;
+        Ok(self.take(len))
+    }
+
+    /// Read exactly `L` bytes into an array
+    pub async fn read_array<const L: usize>(&mut self) -> Result<[u8; L]> {
+        let mut array = [0u8; L];
+        array.copy_from_slice(self.read_exact(L).await?);
+        Ok(array)
+    }
+
+    pub async fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_array::<1>().await?[0])
+    }
+
+    /// Read a big-endian u16
+    pub async fn read_u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.read_array().await?))
+    }
+
+    /// Read a field prefixed by its one-byte length
+    pub async fn read_u8_prefixed(&mut self) -> Result<&[u8]> {
+        self.fill(1).await?;
+        let len = self.buf[0] as usize;
+        self.fill(1 + len).await?;
+        Ok(&self.take(1 + len)[1..])
+    }
+
+    /// Read a field prefixed by its big-endian u16 length, refusing lengths over `max_len`
+    pub async fn read_len_prefixed(&mut self, max_len: usize) -> Result<&[u8]> {
+        self.fill(2).await?;
+        let len = u16::from_be_bytes([self.buf[0], self.buf[1]]) as usize;
+        if len > max_len {
+            self.filled = 0;
+            return Err(ProxyError::Protocol(format!("Field of {} bytes exceeds the limit of {} bytes", len, max_len)));
+        }
+        self.fill(2 + len).await?;
+        Ok(&self.take(2 + len)[2..])
+    }
+
+    /// Wait until the first `len` bytes of the current field arrived
+    async fn fill(&mut self, len: usize) -> Result<()> {
+        if len > N {
+            self.filled = 0;
+            return Err(ProxyError::Protocol(format!("Field of {} bytes exceeds the limit of {} bytes", len, N)));
+        }
+        let timeout = self.timeout;
+        // read 可安全取消：未完成的读取不会丢数据，已到的字节记在 filled 里
+        let fill = async {
+            while self.filled < len {
+                let read = self.reader.read(&mut self.buf[self.filled..len]).await?;
+                if read == 0 {
+                    return Err(ProxyError::Io(ErrorKind::UnexpectedEof.into()));
+                }
+                self.filled += read;
+            }
+            Ok(())
+        };
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, fill)
+                .await
+                .map_err(|_| ProxyError::Io(ErrorKind::TimedOut.into()))?,
+            None => fill.await,
+        }
+    }
+
+    /// Hand out the current field's first `len` bytes; the next read starts a new field
+    fn take(&mut self, len: usize) -> &[u8] {
+        self.filled = 0;
+        &self.buf[..len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_fields_split_across_writes() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut framed: FramedRead<_> = FramedRead::new(server);
+        tokio::spawn(async move {
+            for byte in [0x07, 0x01, 0x02, 3, b'a', b'b', b'c', 0x00, 0x02, b'h', b'i', 0xff] {
+                client.write_all(&[byte]).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        assert_eq!(framed.read_u8().await.unwrap(), 0x07);
+        assert_eq!(framed.read_u16().await.unwrap(), 0x0102);
+        assert_eq!(framed.read_u8_prefixed().await.unwrap(), b"abc");
+        assert_eq!(framed.read_len_prefixed(16).await.unwrap(), b"hi");
+        // 只读到字段末尾，后面的字节留在流里
+        assert_eq!(framed.read_exact(1).await.unwrap(), [0xff]);
+        assert!(matches!(framed.read_u8().await, Err(ProxyError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timed_out_read_resumes() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut framed: FramedRead<_> = FramedRead::new(server).with_timeout(Duration::from_secs(1));
+        client.write_all(&[5, b'h', b'e']).await.unwrap();
+        let err = framed.read_u8_prefixed().await.unwrap_err();
+        assert_eq!(err.io_kind(), ErrorKind::TimedOut);
+
+        // 已收到的长度和前两个字节没有丢
+        client.write_all(b"llo").await.unwrap();
+        assert_eq!(framed.read_u8_prefixed().await.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_lengths_are_bounded() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut framed: FramedRead<_, 8> = FramedRead::new(server);
+        client.write_all(&[0x00, 0x20, 9, 0, 0]).await.unwrap();
+        assert!(framed.read_len_prefixed(16).await.unwrap_err().to_string().contains("limit of 16 bytes"));
+        // 长度前缀超出内联缓冲区时不会去读
+        assert!(framed.read_u8_prefixed().await.unwrap_err().to_string().contains("limit of 8 bytes"));
+    }
+}
@@ -1,12 +1,25 @@
-use super::Protocol;
+use super::{OutboundCapabilities, Protocol};
 use crate::error::{ProxyError, Result};
+use crate::protocol::TargetAddr;
+use crate::inbound::{InboundContext, RunningInbound};
+use crate::stream::ProxyStream;
 use async_trait::async_trait;
 use std::net::SocketAddr;
-use tokio::net::TcpStream;
 
 pub struct BlackholeProtocol;
 
 impl BlackholeProtocol {
+    /// 不解析也不拨号，域名目标同样直接拒绝
+    pub const CAPABILITIES: OutboundCapabilities = OutboundCapabilities {
+        accepts_domain_targets: true,
+        supports_udp: false,
+        poolable: false,
+        is_group: false,
+        needs_resolved_target: false,
+        tunnelable: false,
+        blocks: true,
+    };
+
     pub fn new() -> Self {
         Self
     }
@@ -18,11 +31,15 @@ impl Protocol for BlackholeProtocol {
         "blackhole"
     }
 
-    async fn connect_outbound(&self, _target: SocketAddr) -> Result<TcpStream> {
+    async fn connect_outbound(&self, _target: &TargetAddr) -> Result<ProxyStream> {
         Err(ProxyError::ConnectionFailed("Blackhole outbound - connection dropped".to_string()))
     }
 
-    async fn start_inbound(&self, _bind_addr: SocketAddr) -> Result<()> {
+    fn capabilities(&self) -> OutboundCapabilities {
+        Self::CAPABILITIES
+    }
+
+    async fn start_inbound(&self, _bind_addr: SocketAddr, _ctx: InboundContext) -> Result<RunningInbound> {
         // Blackhole作为inbound没有意义
         Err(ProxyError::Protocol("Blackhole protocol cannot be used as inbound".to_string()))
     }
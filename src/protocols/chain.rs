@@ -0,0 +1,161 @@
+use super::{OutboundCapabilities, Protocol};
+use crate::error::{ProxyError, Result};
+use crate::inbound::{InboundContext, RunningInbound};
+use crate::protocol::TargetAddr;
+use crate::traffic_mark::DialOptions;
+use crate::stream::ProxyStream;
+use async_trait::async_trait;
+use log::debug;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// 规则级代理链：拨号第一跳，经其隧道依次连接后续各跳，最后一跳连接目标
+pub struct ChainProtocol {
+    /// (出站名称, 连接器)，按拨号顺序
+    hops: Vec<(String, Arc<dyn Protocol>)>,
+}
+
+impl ChainProtocol {
+    /// Chain the given hops; every hop after the first must be a proxy with a server address
+    pub fn new(hops: Vec<(String, Arc<dyn Protocol>)>) -> Result<Self> {
+        if hops.is_empty() {
+            return Err(ProxyError::Protocol("Outbound chain has no hops".to_string()));
+        }
+        if let Some((name, _)) = hops[1..].iter().find(|(_, hop)| hop.server_addr().is_none()) {
+            return Err(ProxyError::Protocol(format!("Outbound chain hop {} has no server to tunnel to", name)));
+        }
+        Ok(Self { hops })
+    }
+
+    /// 第 index 跳之后要连接的地址：下一跳的服务器，最后一跳为目标
+    fn next_addr(&self, index: usize, target: &TargetAddr) -> TargetAddr {
+        match self.hops.get(index + 1).and_then(|(_, hop)| hop.server_addr()) {
+            Some(server) => server.into(),
+            None => target.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Protocol for ChainProtocol {
+    fn name(&self) -> &str {
+        "chain"
+    }
+
+    /// 实际拨号的是第一跳
+    fn server_addr(&self) -> Option<SocketAddr> {
+        self.hops[0].1.server_addr()
+    }
+
+    async fn connect_outbound(&self, target: &TargetAddr) -> Result<ProxyStream> {
+        self.connect_outbound_with(target, &DialOptions::default()).await
+    }
+
+    async fn connect_outbound_with(&self, target: &TargetAddr, options: &DialOptions) -> Result<ProxyStream> {
+        let (first, connector) = &self.hops[0];
+        let mut stream = connector.connect_outbound_with(&self.next_addr(0, target), options).await.inspect_err(|e| {
+            debug!("Outbound chain hop {} failed: {}", first, e);
+        })?;
+        for (index, (name, hop)) in self.hops.iter().enumerate().skip(1) {
+            stream = hop.connect_over(stream, &self.next_addr(index, target)).await.inspect_err(|e| {
+                debug!("Outbound chain hop {} failed: {}", name, e);
+            })?;
+        }
+        Ok(stream)
+    }
+
+    /// 目标由最后一跳连接；只有一跳时等同该跳本身
+    fn capabilities(&self) -> OutboundCapabilities {
+        let (_, last) = &self.hops[self.hops.len() - 1];
+        let last = last.capabilities();
+        if self.hops.len() == 1 {
+            return last;
+        }
+        OutboundCapabilities {
+            accepts_domain_targets: last.accepts_domain_targets,
+            blocks: self.hops.iter().any(|(_, hop)| hop.capabilities().blocks),
+            ..OutboundCapabilities::default()
+        }
+    }
+
+    async fn start_inbound(&self, _bind_addr: SocketAddr, _ctx: InboundContext) -> Result<RunningInbound> {
+        Err(ProxyError::Protocol("Outbound chain cannot be used as inbound".to_string()))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::protocol::{Address, Socks5Request};
+    use crate::protocols::{DirectProtocol, Socks5Protocol};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+
+    /// No-auth SOCKS5 server that reports each CONNECT target and relays to it
+    pub(crate) async fn recording_socks_server() -> (SocketAddr, mpsc::UnboundedReceiver<SocketAddr>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut client, _)) = listener.accept().await {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let mut greeting = [0u8; 3];
+                    client.read_exact(&mut greeting).await.unwrap();
+                    client.write_all(&[0x05, 0x00]).await.unwrap();
+                    let request = Socks5Request::read_from(&mut client).await.unwrap();
+                    let target = match request.address {
+                        Address::V4(ip) => SocketAddr::from((ip, request.port)),
+                        other => panic!("unexpected address {:?}", other),
+                    };
+                    tx.send(target).unwrap();
+                    let mut upstream = TcpStream::connect(target).await.unwrap();
+                    client.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await.unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+                });
+            }
+        });
+        (addr, rx)
+    }
+
+    #[tokio::test]
+    async fn test_two_hop_chain_reaches_target() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+        let (first_addr, mut first_seen) = recording_socks_server().await;
+        let (second_addr, mut second_seen) = recording_socks_server().await;
+
+        let chain = ChainProtocol::new(vec![
+            ("proxy-a".to_string(), Arc::new(Socks5Protocol::with_server(first_addr)) as Arc<dyn Protocol>),
+            ("proxy-b".to_string(), Arc::new(Socks5Protocol::with_server(second_addr))),
+        ])
+        .unwrap();
+        assert_eq!(chain.server_addr(), Some(first_addr));
+
+        let mut stream = chain.connect_outbound(&target.into()).await.unwrap();
+        // 第一跳只看到第二跳的地址，第二跳才看到目标
+        assert_eq!(first_seen.recv().await, Some(second_addr));
+        assert_eq!(second_seen.recv().await, Some(target));
+        stream.write_all(b"hello").await.unwrap();
+        let mut reply = [0u8; 5];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"hello");
+    }
+
+    #[test]
+    fn test_later_hops_need_a_server() {
+        let hops: Vec<(String, Arc<dyn Protocol>)> = vec![
+            ("proxy".to_string(), Arc::new(Socks5Protocol::with_server("127.0.0.1:1080".parse().unwrap()))),
+            ("direct".to_string(), Arc::new(DirectProtocol::new())),
+        ];
+        let err = ChainProtocol::new(hops).err().unwrap().to_string();
+        assert!(err.contains("direct"), "{}", err);
+    }
+}
@@ -1,30 +1,113 @@
-use super::Protocol;
+use super::{DatagramTransport, OutboundCapabilities, Protocol};
 use crate::error::{ProxyError, Result};
+use crate::protocol::TargetAddr;
+use crate::inbound::{InboundContext, RunningInbound};
+use crate::traffic_mark::{dial_tcp, DialOptions};
+use crate::uot::MAX_DATAGRAM_SIZE;
+use crate::stream::ProxyStream;
 use async_trait::async_trait;
-use std::net::SocketAddr;
-use tokio::net::TcpStream;
+use bytes::Bytes;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
 
 pub struct DirectProtocol;
 
 impl DirectProtocol {
+    pub const CAPABILITIES: OutboundCapabilities = OutboundCapabilities {
+        accepts_domain_targets: false,
+        supports_udp: true,
+        poolable: true,
+        is_group: false,
+        needs_resolved_target: true,
+        tunnelable: false,
+        blocks: false,
+    };
+
     pub fn new() -> Self {
         Self
     }
 }
 
+/// 直连的数据报会话：已 connect 到目标的本地UDP套接字
+struct DirectDatagram {
+    socket: UdpSocket,
+    buf: Vec<u8>,
+}
+
+#[async_trait]
+impl DatagramTransport for DirectDatagram {
+    async fn send(&mut self, payload: &[u8]) -> Result<()> {
+        self.socket.send(payload).await?;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<Option<Bytes>> {
+        let len = self.socket.recv(&mut self.buf).await?;
+        Ok(Some(Bytes::copy_from_slice(&self.buf[..len])))
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
 #[async_trait]
 impl Protocol for DirectProtocol {
     fn name(&self) -> &str {
         "direct"
     }
 
-    async fn connect_outbound(&self, target: SocketAddr) -> Result<TcpStream> {
-        TcpStream::connect(target).await
-            .map_err(|e| ProxyError::ConnectionFailed(e.to_string()))
+    async fn connect_outbound(&self, target: &TargetAddr) -> Result<ProxyStream> {
+        self.connect_outbound_with(target, &DialOptions::default()).await
     }
 
-    async fn start_inbound(&self, _bind_addr: SocketAddr) -> Result<()> {
+    async fn connect_outbound_with(&self, target: &TargetAddr, options: &DialOptions) -> Result<ProxyStream> {
+        // 直连自己拨号，域名须先在本地解析
+        let addr = target
+            .socket_addr()
+            .ok_or_else(|| ProxyError::Protocol(format!("Direct outbound needs a resolved target, got {}", target)))?;
+        // 保留io错误类型，供连接诊断区分拒绝/超时等
+        dial_tcp(addr, options).await.map(ProxyStream::from)
+    }
+
+    async fn open_datagram(&self, target: SocketAddr) -> Result<Box<dyn DatagramTransport>> {
+        let bind: SocketAddr = match target {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(target).await?;
+        Ok(Box::new(DirectDatagram { socket, buf: vec![0; MAX_DATAGRAM_SIZE] }))
+    }
+
+    fn capabilities(&self) -> OutboundCapabilities {
+        Self::CAPABILITIES
+    }
+
+    async fn start_inbound(&self, _bind_addr: SocketAddr, _ctx: InboundContext) -> Result<RunningInbound> {
         // Direct协议作为inbound没有意义，直接返回错误
         Err(ProxyError::Protocol("Direct protocol cannot be used as inbound".to_string()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_direct_datagram_round_trip() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (len, peer) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..len], peer).await.unwrap();
+        });
+
+        assert!(DirectProtocol::new().capabilities().supports_udp);
+        let mut session = DirectProtocol::new().open_datagram(target).await.unwrap();
+        session.send(b"ping").await.unwrap();
+        assert_eq!(session.recv().await.unwrap().as_deref(), Some(&b"ping"[..]));
+        session.close().await.unwrap();
+    }
+}
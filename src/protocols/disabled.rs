@@ -0,0 +1,80 @@
+use super::{DatagramTransport, Protocol};
+use crate::error::{ProxyError, Result};
+use crate::protocol::TargetAddr;
+use crate::inbound::{InboundContext, RunningInbound};
+use crate::stream::ProxyStream;
+use async_trait::async_trait;
+use log::warn;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// 同一出站的拒绝告警最小间隔
+const REJECT_WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Stand-in for an outbound that failed to build at startup
+///
+/// Registered under the outbound's name when `on_outbound_error = "disable"`,
+/// so routes to it fail with the construction error instead of taking the
+/// whole proxy down.
+pub struct DisabledProtocol {
+    name: String,
+    reason: String,
+    last_warn: Mutex<Option<Instant>>,
+    suppressed_warnings: AtomicU64,
+}
+
+impl DisabledProtocol {
+    pub fn new(name: String, reason: String) -> Self {
+        Self {
+            name,
+            reason,
+            last_warn: Mutex::new(None),
+            suppressed_warnings: AtomicU64::new(0),
+        }
+    }
+
+    /// Why the outbound could not be built
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    /// Refuse a connection routed here, warning at most once per interval
+    pub fn reject(&self) -> ProxyError {
+        let mut last = self.last_warn.lock().unwrap();
+        if last.is_some_and(|at| at.elapsed() < REJECT_WARN_INTERVAL) {
+            self.suppressed_warnings.fetch_add(1, Ordering::Relaxed);
+        } else {
+            *last = Some(Instant::now());
+            let suppressed = self.suppressed_warnings.swap(0, Ordering::Relaxed);
+            warn!(
+                "Connection routed to disabled outbound {}: {} ({} similar warnings suppressed)",
+                self.name, self.reason, suppressed
+            );
+        }
+        ProxyError::OutboundDisabled {
+            name: self.name.clone(),
+            reason: self.reason.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Protocol for DisabledProtocol {
+    fn name(&self) -> &str {
+        "disabled"
+    }
+
+    async fn connect_outbound(&self, _target: &TargetAddr) -> Result<ProxyStream> {
+        Err(self.reject())
+    }
+
+    async fn open_datagram(&self, _target: SocketAddr) -> Result<Box<dyn DatagramTransport>> {
+        Err(self.reject())
+    }
+
+    async fn start_inbound(&self, _bind_addr: SocketAddr, _ctx: InboundContext) -> Result<RunningInbound> {
+        Err(ProxyError::Protocol("Disabled outbound cannot be used as inbound".to_string()))
+    }
+}
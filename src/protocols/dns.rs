@@ -0,0 +1,266 @@
+// DNS 入站：在 UDP 与 TCP 上回答查询。开启 dns.fake_ip 时 A 查询以假地址应答并记下地址对应的域名，
+// 透明代理收到发往假地址的连接时据此查回域名；否则用配置的 DNS 服务器解析出真实地址应答
+use super::Protocol;
+use crate::dns::try_get_global_dns_resolver;
+use crate::error::{ProxyError, Result};
+use crate::fake_ip::FAKE_ANSWER_TTL;
+use crate::inbound::{serve_inbound, InboundContext, RunningInbound};
+use crate::listener::bind_tcp_listener;
+use crate::protocol::TargetAddr;
+use crate::stream::ProxyStream;
+use crate::tasks::TaskGroup;
+use async_trait::async_trait;
+use log::{debug, info};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio_util::sync::CancellationToken;
+use trust_dns_resolver::proto::op::{Message, MessageType, OpCode, ResponseCode};
+use trust_dns_resolver::proto::rr::rdata::{A, AAAA};
+use trust_dns_resolver::proto::rr::{DNSClass, Name, RData, Record, RecordType};
+
+/// TTL of answers with real addresses
+const ANSWER_TTL: u32 = 60;
+/// Largest UDP query accepted
+const MAX_UDP_QUERY: usize = 4096;
+/// How long an idle TCP client is kept when `server.handshake_timeout_secs` is 0
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+pub struct DnsProtocol;
+
+impl DnsProtocol {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+#[async_trait]
+impl Protocol for DnsProtocol {
+    fn name(&self) -> &str {
+        "dns"
+    }
+
+    async fn connect_outbound(&self, _target: &TargetAddr) -> Result<ProxyStream> {
+        Err(ProxyError::Protocol("DNS protocol cannot be used as outbound".to_string()))
+    }
+
+    async fn start_inbound(&self, bind_addr: SocketAddr, ctx: InboundContext) -> Result<RunningInbound> {
+        let listener = bind_tcp_listener(bind_addr).await?;
+        // UDP 与 TCP 同一端口；绑定 0 端口时跟随 TCP 实际分到的端口
+        let udp = Arc::new(UdpSocket::bind(listener.local_addr()?).await?);
+        let tasks = ctx.tasks;
+        let udp_ctx = ctx.clone();
+        let running = serve_inbound("DNS", listener, ctx, serve_tcp)?;
+
+        // UDP 随入站一起停止
+        tasks.spawn(TaskGroup::UdpSessions, serve_udp(udp, udp_ctx, running.shutdown_token()))?;
+        info!("DNS UDP bound on {}", running.local_addr());
+        Ok(running)
+    }
+}
+
+/// Queries over TCP, each prefixed by its two-byte length, until the client closes
+async fn serve_tcp(mut stream: TcpStream, client: SocketAddr, ctx: InboundContext) -> Result<()> {
+    let idle = ctx.config.handshake_timeout().unwrap_or(TCP_IDLE_TIMEOUT);
+    loop {
+        let read_query = async {
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).await?;
+            let mut query = vec![0u8; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut query).await?;
+            Ok::<_, std::io::Error>(query)
+        };
+        // 客户端关闭、读错误或空闲超时都结束连接
+        let Ok(Ok(query)) = tokio::time::timeout(idle, read_query).await else {
+            return Ok(());
+        };
+        let Some(response) = answer(&query, client, &ctx).await else {
+            return Ok(());
+        };
+        let len = u16::try_from(response.len())
+            .map_err(|_| ProxyError::Protocol(format!("DNS response to {} is too large", client)))?;
+        stream.write_all(&len.to_be_bytes()).await?;
+        stream.write_all(&response).await?;
+    }
+}
+
+/// Answer UDP queries until `shutdown`
+async fn serve_udp(socket: Arc<UdpSocket>, ctx: InboundContext, shutdown: CancellationToken) {
+    let mut buf = vec![0u8; MAX_UDP_QUERY];
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buf) => received,
+            _ = shutdown.cancelled() => return,
+        };
+        let (n, client) = match received {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("DNS UDP receive failed: {}", e);
+                continue;
+            }
+        };
+        let query = buf[..n].to_vec();
+        let (reply_socket, reply_ctx) = (socket.clone(), ctx.clone());
+        // 真实地址要等上游解析，每个查询单独起任务，不耽误其他客户端
+        let reply = async move {
+            let Some(response) = answer(&query, client, &reply_ctx).await else {
+                return;
+            };
+            if let Err(e) = reply_socket.send_to(&response, client).await {
+                debug!("DNS UDP reply to {} failed: {}", client, e);
+            }
+        };
+        if let Err(e) = ctx.tasks.spawn(TaskGroup::UdpSessions, reply) {
+            debug!("DNS query from {} dropped: {}", client, e);
+        }
+    }
+}
+
+/// Encoded response to one query; None for anything that is not a DNS query
+async fn answer(query: &[u8], client: SocketAddr, ctx: &InboundContext) -> Option<Vec<u8>> {
+    let request = match Message::from_vec(query) {
+        Ok(request) if request.message_type() == MessageType::Query => request,
+        _ => {
+            debug!("Ignoring malformed DNS message from {}", client);
+            return None;
+        }
+    };
+    let mut response = Message::new();
+    response
+        .set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request.op_code())
+        .set_recursion_desired(request.recursion_desired())
+        .set_recursion_available(true)
+        .add_queries(request.queries().to_vec());
+
+    let code = match (request.op_code(), request.queries()) {
+        (OpCode::Query, [question]) if question.query_class() == DNSClass::IN => {
+            match records(question.name(), question.query_type(), client, ctx).await {
+                Ok(records) => {
+                    response.add_answers(records);
+                    ResponseCode::NoError
+                }
+                Err(code) => code,
+            }
+        }
+        (OpCode::Query, _) => ResponseCode::FormErr,
+        _ => ResponseCode::NotImp,
+    };
+    response.set_response_code(code);
+    response.to_vec().ok()
+}
+
+/// Answer records for `name`, or the response code to fail with
+async fn records(
+    name: &Name,
+    record_type: RecordType,
+    client: SocketAddr,
+    ctx: &InboundContext,
+) -> std::result::Result<Vec<Record>, ResponseCode> {
+    let domain = name.to_utf8();
+    let domain = domain.trim_end_matches('.');
+    if domain.is_empty() {
+        return Err(ResponseCode::Refused);
+    }
+
+    if let Some(pool) = ctx.fake_ip().filter(|pool| !pool.is_excluded(domain)) {
+        // 假地址只有 IPv4：AAAA 等其他类型回答空结果，客户端改用 A 记录
+        if record_type != RecordType::A {
+            return Ok(Vec::new());
+        }
+        let ip = pool.allocate(domain);
+        debug!("DNS query for {} from {} answered with fake IP {}", domain, client, ip);
+        return Ok(vec![Record::from_rdata(name.clone(), FAKE_ANSWER_TTL, RData::A(A(ip)))]);
+    }
+
+    if !matches!(record_type, RecordType::A | RecordType::AAAA) {
+        return Err(ResponseCode::NotImp);
+    }
+    let resolver = try_get_global_dns_resolver().map_err(|_| ResponseCode::ServFail)?;
+    let (ips, _) = resolver.resolve_all(domain).await.map_err(|e| {
+        debug!("DNS query for {} from {} failed: {}", domain, client, e);
+        ResponseCode::ServFail
+    })?;
+    Ok(ips
+        .into_iter()
+        .filter_map(|ip| match (ip, record_type) {
+            (IpAddr::V4(ip), RecordType::A) => Some(RData::A(A(ip))),
+            (IpAddr::V6(ip), RecordType::AAAA) => Some(RData::AAAA(AAAA(ip))),
+            _ => None,
+        })
+        .map(|rdata| Record::from_rdata(name.clone(), ANSWER_TTL, rdata))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, FakeIpConfig};
+    use crate::fake_ip::FakeIpPool;
+    use crate::outbound::OutboundManager;
+    use trust_dns_resolver::proto::op::Query;
+
+    fn query(id: u16, domain: &str, record_type: RecordType) -> Vec<u8> {
+        let mut message = Message::new();
+        message
+            .set_id(id)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .add_query(Query::query(Name::from_utf8(domain).unwrap(), record_type));
+        message.to_vec().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_fake_ip_answers_over_udp_and_tcp() {
+        let config: &'static Config = Box::leak(Box::new(Config::default()));
+        let outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        let pool: &'static FakeIpPool = Box::leak(Box::new(FakeIpPool::new(&FakeIpConfig {
+            enabled: true,
+            ..FakeIpConfig::default()
+        })));
+        let ctx = InboundContext::new(config, Arc::new(outbounds)).with_fake_ip(pool);
+        let running = DnsProtocol::new().start_inbound("127.0.0.1:0".parse().unwrap(), ctx).await.unwrap();
+        let server = running.local_addr();
+
+        // UDP 的 A 查询拿到假地址，表里记下对应域名
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.send_to(&query(7, "www.Example.com.", RecordType::A), server).await.unwrap();
+        let mut buf = [0u8; 512];
+        let n = socket.recv(&mut buf).await.unwrap();
+        let response = Message::from_vec(&buf[..n]).unwrap();
+        assert_eq!((response.id(), response.response_code()), (7, ResponseCode::NoError));
+        let ip = match response.answers()[0].data() {
+            Some(RData::A(A(ip))) => *ip,
+            other => panic!("unexpected answer {:?}", other),
+        };
+        assert!(pool.contains(IpAddr::V4(ip)));
+        assert_eq!(response.answers()[0].ttl(), FAKE_ANSWER_TTL);
+        assert_eq!(pool.lookup(IpAddr::V4(ip)).unwrap().as_deref(), Some("www.example.com"));
+
+        // TCP 上同一连接连续两个查询；AAAA 回答空结果
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        for (id, record_type) in [(8, RecordType::AAAA), (9, RecordType::A)] {
+            let request = query(id, "www.example.com.", record_type);
+            stream.write_all(&(request.len() as u16).to_be_bytes()).await.unwrap();
+            stream.write_all(&request).await.unwrap();
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).await.unwrap();
+            let mut body = vec![0u8; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut body).await.unwrap();
+            let response = Message::from_vec(&body).unwrap();
+            assert_eq!((response.id(), response.response_code()), (id, ResponseCode::NoError));
+            let answers: Vec<_> = response.answers().iter().filter_map(|record| record.data().cloned()).collect();
+            match record_type {
+                RecordType::A => assert_eq!(answers, vec![RData::A(A(ip))]),
+                _ => assert!(answers.is_empty()),
+            }
+        }
+        drop(stream);
+        running.stop().await.unwrap();
+    }
+}
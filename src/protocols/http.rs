@@ -0,0 +1,170 @@
+use super::{OutboundCapabilities, Protocol};
+use crate::error::{ProxyError, Result};
+use crate::protocol::TargetAddr;
+use crate::inbound::{InboundContext, RunningInbound};
+use crate::endpoint::ServerEndpoint;
+use crate::traffic_mark::DialOptions;
+use crate::stream::ProxyStream;
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// 响应头最大长度
+const MAX_RESPONSE_HEAD: usize = 8192;
+
+pub struct HttpProtocol {
+    // 作为outbound时的代理服务器端点
+    server: Option<ServerEndpoint>,
+    // CONNECT请求中的Host头覆盖
+    override_host_header: Option<String>,
+}
+
+impl HttpProtocol {
+    pub const CAPABILITIES: OutboundCapabilities = OutboundCapabilities {
+        accepts_domain_targets: true,
+        supports_udp: false,
+        poolable: false,
+        is_group: false,
+        needs_resolved_target: false,
+        tunnelable: true,
+        blocks: false,
+    };
+
+    pub fn with_server(server_addr: SocketAddr, override_host_header: Option<String>) -> Self {
+        Self::with_endpoint(ServerEndpoint::single(server_addr), override_host_header)
+    }
+
+    /// Outbound to a proxy that may listen on several ports
+    pub fn with_endpoint(server: ServerEndpoint, override_host_header: Option<String>) -> Self {
+        Self {
+            server: Some(server),
+            override_host_header,
+        }
+    }
+
+    /// 在已建立的连接上完成HTTP CONNECT握手
+    pub async fn handshake<S>(&self, stream: &mut S, target: &str) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let host = self.override_host_header.as_deref().unwrap_or(target);
+        let request = format!(
+            "CONNECT {} HTTP/1.1\r\nHost: {}\r\nProxy-Connection: Keep-Alive\r\n\r\n",
+            target, host
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        // 逐字节读取响应头，避免吞掉隧道中的后续数据
+        let mut head = Vec::with_capacity(128);
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_RESPONSE_HEAD {
+                return Err(ProxyError::Protocol("HTTP CONNECT response too large".to_string()));
+            }
+            head.push(stream.read_u8().await?);
+        }
+
+        let status_line = String::from_utf8_lossy(&head);
+        let status_line = status_line.lines().next().unwrap_or_default();
+        let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+        if status != "200" {
+            return Err(ProxyError::ConnectionFailed(format!(
+                "HTTP CONNECT failed: {}",
+                status_line
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Protocol for HttpProtocol {
+    fn name(&self) -> &str {
+        "http"
+    }
+
+    fn server_addr(&self) -> Option<SocketAddr> {
+        self.server.as_ref().map(ServerEndpoint::primary)
+    }
+
+    async fn connect_outbound(&self, target: &TargetAddr) -> Result<ProxyStream> {
+        self.connect_outbound_with(target, &DialOptions::default()).await
+    }
+
+    async fn connect_outbound_with(&self, target: &TargetAddr, options: &DialOptions) -> Result<ProxyStream> {
+        let server = self.server.as_ref()
+            .ok_or_else(|| ProxyError::Protocol("HTTP proxy server address not configured".to_string()))?;
+
+        let mut stream = server.dial(options).await
+            .map_err(|e| ProxyError::ConnectionFailed(e.to_string()))?;
+        self.handshake(&mut stream, &target.to_string()).await?;
+
+        Ok(stream.into())
+    }
+
+    async fn connect_over(&self, mut stream: ProxyStream, target: &TargetAddr) -> Result<ProxyStream> {
+        self.handshake(&mut stream, &target.to_string()).await?;
+        Ok(stream)
+    }
+
+    fn capabilities(&self) -> OutboundCapabilities {
+        Self::CAPABILITIES
+    }
+
+    async fn start_inbound(&self, _bind_addr: SocketAddr, _ctx: InboundContext) -> Result<RunningInbound> {
+        Err(ProxyError::Protocol("HTTP inbound is not supported".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Accept one CONNECT request, reply 200 and return the Host header
+    async fn mock_connect_proxy() -> (SocketAddr, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(head)
+                .unwrap()
+                .lines()
+                .find_map(|line| line.strip_prefix("Host: ").map(str::to_string))
+                .unwrap()
+        });
+        (addr, handle)
+    }
+
+    #[tokio::test]
+    async fn test_connect_sends_override_host_header() {
+        let (addr, server) = mock_connect_proxy().await;
+        let protocol = HttpProtocol::with_server(addr, Some("cdn.example.net".to_string()));
+
+        protocol
+            .connect_outbound(&"198.51.100.7:443".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(server.await.unwrap(), "cdn.example.net");
+    }
+
+    #[tokio::test]
+    async fn test_connect_defaults_host_to_target() {
+        let (addr, server) = mock_connect_proxy().await;
+        let protocol = HttpProtocol::with_server(addr, None);
+
+        protocol
+            .connect_outbound(&"198.51.100.7:443".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(server.await.unwrap(), "198.51.100.7:443");
+    }
+}
@@ -1,32 +1,131 @@
 // 协议模块 - 统一的协议trait，支持inbound和outbound
-use crate::error::Result;
+use crate::error::{ProxyError, Result};
+use crate::inbound::{InboundContext, RunningInbound};
+use crate::protocol::TargetAddr;
+use crate::stream::ProxyStream;
+use crate::traffic_mark::DialOptions;
 use async_trait::async_trait;
+use bytes::Bytes;
 use std::net::SocketAddr;
-use tokio::net::TcpStream;
 
 /// 统一的协议trait
-/// 所有协议（direct、socks5、vless、blackhole等）都实现这个trait
+/// 所有协议（direct、socks5、shadowsocks、vless、blackhole等）都实现这个trait
 /// 既可以作为inbound也可以作为outbound使用
 #[async_trait]
 pub trait Protocol: Send + Sync {
     /// 协议名称
     fn name(&self) -> &str;
 
-    /// 作为outbound连接时使用
-    async fn connect_outbound(&self, target: SocketAddr) -> Result<TcpStream>;
+    /// 作为outbound连接时使用；返回的流可能是明文TCP，也可能带有协议自己的封装
+    /// 目标可以是域名，只有声明了 accepts_domain_targets 的出站才会收到域名
+    async fn connect_outbound(&self, target: &TargetAddr) -> Result<ProxyStream>;
 
-    /// 作为inbound启动时使用
-    async fn start_inbound(&self, bind_addr: SocketAddr) -> Result<()>;
+    /// 带路由选定的套接字选项（DSCP等）连接；不支持的协议忽略选项
+    async fn connect_outbound_with(&self, target: &TargetAddr, _options: &DialOptions) -> Result<ProxyStream> {
+        self.connect_outbound(target).await
+    }
+
+    /// 在已建立的隧道上与本出站的服务器握手并连接target，用于代理链中第一跳之后的各跳
+    async fn connect_over(&self, _stream: ProxyStream, _target: &TargetAddr) -> Result<ProxyStream> {
+        Err(ProxyError::Protocol(format!("{} outbound cannot be reached through a proxy chain", self.name())))
+    }
+
+    /// 作为inbound启动：绑定后立即返回，通过RunningInbound获取实际地址并停止
+    async fn start_inbound(&self, bind_addr: SocketAddr, ctx: InboundContext) -> Result<RunningInbound>;
+
+    /// 上游服务器地址（代理类outbound），用于环路检测
+    fn server_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// 打开到target的数据报会话，不支持UDP的outbound返回错误
+    async fn open_datagram(&self, _target: SocketAddr) -> Result<Box<dyn DatagramTransport>> {
+        Err(ProxyError::Protocol(format!("{} outbound does not support UDP", self.name())))
+    }
+
+    /// 作为outbound的能力，校验和连接流程据此分支而不是按协议名判断；默认什么都不支持
+    fn capabilities(&self) -> OutboundCapabilities {
+        OutboundCapabilities::default()
+    }
+}
+
+/// What an outbound can do
+///
+/// Config validation and the connect pipeline ask these instead of matching
+/// on the outbound type. The default claims nothing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutboundCapabilities {
+    /// Can hand a domain name to its server instead of a resolved address
+    pub accepts_domain_targets: bool,
+    /// `open_datagram` works
+    pub supports_udp: bool,
+    /// Idle connections to a target may be kept and reused by the next
+    /// connection to the same target through the same outbound
+    pub poolable: bool,
+    /// A group that carries connections through one of its members
+    pub is_group: bool,
+    /// Dials the target itself, so the target must be resolved locally first
+    pub needs_resolved_target: bool,
+    /// Can be reached through another outbound's tunnel (proxy chain hops after the first)
+    pub tunnelable: bool,
+    /// Refuses every connection
+    pub blocks: bool,
+}
+
+impl OutboundCapabilities {
+    /// What a group can promise whichever member it selects: only what all of them can do
+    pub fn common(self, other: Self) -> Self {
+        Self {
+            accepts_domain_targets: self.accepts_domain_targets && other.accepts_domain_targets,
+            supports_udp: self.supports_udp && other.supports_udp,
+            poolable: self.poolable && other.poolable,
+            is_group: true,
+            // 任一成员需要本地解析，整个组就需要
+            needs_resolved_target: self.needs_resolved_target || other.needs_resolved_target,
+            tunnelable: self.tunnelable && other.tunnelable,
+            blocks: self.blocks && other.blocks,
+        }
+    }
+
+    /// Capabilities shared by all of `members`, `None` when there are none
+    pub fn of_group(members: impl IntoIterator<Item = Self>) -> Option<Self> {
+        members.into_iter().reduce(Self::common).map(|common| Self { is_group: true, ..common })
+    }
+}
+
+/// 面向单一目标的数据报会话（UDP或UDP-over-TCP）
+#[async_trait]
+pub trait DatagramTransport: Send {
+    /// 发送一个数据报
+    async fn send(&mut self, payload: &[u8]) -> Result<()>;
+
+    /// 接收一个数据报，会话结束时返回None
+    async fn recv(&mut self) -> Result<Option<Bytes>>;
+
+    /// 结束会话并关闭底层连接
+    async fn close(&mut self) -> Result<()>;
 }
 
 pub mod blackhole;
+pub mod chain;
 pub mod direct;
+pub mod disabled;
+pub mod dns;
+pub mod http;
+pub mod redirect;
+pub mod shadowsocks;
 pub mod socks5;
 pub mod tproxy;
 pub mod vless;
 
 pub use blackhole::BlackholeProtocol;
+pub use chain::ChainProtocol;
 pub use direct::DirectProtocol;
-pub use socks5::Socks5Protocol;
+pub use disabled::DisabledProtocol;
+pub use dns::DnsProtocol;
+pub use http::HttpProtocol;
+pub use redirect::RedirectProtocol;
+pub use shadowsocks::{ShadowsocksCipher, ShadowsocksProtocol, ShadowsocksStream};
+pub use socks5::{socks5_client_connect, Socks5Protocol};
 pub use tproxy::TproxyProtocol;
 pub use vless::VlessProtocol;
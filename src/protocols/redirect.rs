@@ -0,0 +1,101 @@
+// REDIRECT 入站（仅 Linux）：iptables/nftables 的 REDIRECT（NAT）目标把连接改写到本机监听端口，
+// 原目标记录在 conntrack 里，通过 SO_ORIGINAL_DST（IPv6 为 IP6T_SO_ORIGINAL_DST）取回
+use super::Protocol;
+use crate::error::{ProxyError, Result};
+use crate::inbound::{InboundContext, RunningInbound};
+use crate::protocol::TargetAddr;
+use crate::stream::ProxyStream;
+use async_trait::async_trait;
+use std::net::SocketAddr;
+
+#[derive(Default)]
+pub struct RedirectProtocol;
+
+impl RedirectProtocol {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+#[async_trait]
+impl Protocol for RedirectProtocol {
+    fn name(&self) -> &str {
+        "redirect"
+    }
+
+    async fn connect_outbound(&self, _target: &TargetAddr) -> Result<ProxyStream> {
+        Err(ProxyError::Protocol("Redirect protocol cannot be used as outbound".to_string()))
+    }
+
+    async fn start_inbound(&self, _bind_addr: SocketAddr, _ctx: InboundContext) -> Result<RunningInbound> {
+        #[cfg(target_os = "linux")]
+        {
+            self.start_redirect_linux(_bind_addr, _ctx).await
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            Err(ProxyError::Protocol("Redirect is only supported on Linux".to_string()))
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl RedirectProtocol {
+    async fn start_redirect_linux(&self, bind_addr: SocketAddr, ctx: InboundContext) -> Result<RunningInbound> {
+        use crate::inbound::serve_inbound;
+        use crate::listener::bind_tcp_listener;
+        use crate::protocols::TproxyProtocol;
+
+        let listener = bind_tcp_listener(bind_addr).await?;
+        serve_inbound("Redirect", listener, ctx, |stream, peer, ctx| async move {
+            let target = original_dst(&stream).map_err(|e| {
+                ProxyError::Protocol(format!(
+                    "No original destination for connection from {} (is it redirected by iptables/nftables?): {}",
+                    peer, e
+                ))
+            })?;
+            // 之后的路由与转发和透明代理相同
+            TproxyProtocol::handle_tcp(stream, peer, target, ctx).await
+        })
+    }
+}
+
+/// Destination the client connected to before REDIRECT rewrote it to our listener
+#[cfg(target_os = "linux")]
+fn original_dst(stream: &tokio::net::TcpStream) -> std::io::Result<SocketAddr> {
+    let socket = socket2::SockRef::from(stream);
+    // 双栈监听上的 IPv4 客户端由 iptables 而不是 ip6tables 改写
+    let original = match stream.local_addr()?.ip().to_canonical() {
+        std::net::IpAddr::V4(_) => socket.original_dst()?,
+        std::net::IpAddr::V6(_) => socket.original_dst_ipv6()?,
+    };
+    original
+        .as_socket()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "original destination is not an IP address"))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::access_log::AccessLogger;
+    use crate::config::{AccessLogConfig, Config};
+    use crate::outbound::OutboundManager;
+    use std::sync::Arc;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn test_unredirected_connection_is_not_relayed() {
+        let config: &'static Config = Box::leak(Box::new(Config::default()));
+        let outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        let (access_log, _records) = AccessLogger::channel(&AccessLogConfig { enabled: true, ..Default::default() });
+        let ctx = InboundContext { access_log: Box::leak(Box::new(access_log)), ..InboundContext::new(config, Arc::new(outbounds)) };
+        let running = RedirectProtocol::new().start_inbound("127.0.0.1:0".parse().unwrap(), ctx).await.unwrap();
+
+        // 没有 REDIRECT 规则：要么 conntrack 里没有原目标，要么原目标就是监听地址本身（回环），都不转发
+        let mut client = TcpStream::connect(running.local_addr()).await.unwrap();
+        let mut buf = [0u8; 1];
+        assert!(matches!(client.read(&mut buf).await, Ok(0) | Err(_)));
+        running.stop().await.unwrap();
+    }
+}
@@ -0,0 +1,529 @@
+use super::{OutboundCapabilities, Protocol};
+use crate::endpoint::ServerEndpoint;
+use crate::error::{ProxyError, Result};
+use crate::inbound::{InboundContext, RunningInbound};
+use crate::protocol::{Address, AddressFormat, TargetAddr};
+use crate::stream::{OutboundStream, ProxyStream};
+use crate::traffic_mark::DialOptions;
+use async_trait::async_trait;
+use bytes::{Buf, BytesMut};
+use md5::{Digest, Md5};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN};
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_util::io::poll_read_buf;
+
+/// AEAD标签长度，三种加密方法相同
+const TAG_LEN: usize = 16;
+/// 单个数据块的最大负载（SIP004）
+const MAX_PAYLOAD: usize = 0x3FFF;
+/// 每次从底层读取的预留空间
+const READ_RESERVE: usize = 2 + TAG_LEN + MAX_PAYLOAD + TAG_LEN;
+
+/// Shadowsocks AEAD cipher
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShadowsocksCipher {
+    Aes128Gcm,
+    Aes256Gcm,
+    Chacha20IetfPoly1305,
+}
+
+impl ShadowsocksCipher {
+    /// Method names as written in configs
+    pub const METHODS: &'static [&'static str] = &["aes-128-gcm", "aes-256-gcm", "chacha20-ietf-poly1305"];
+
+    pub fn from_method(method: &str) -> Result<Self> {
+        match method {
+            "aes-128-gcm" => Ok(Self::Aes128Gcm),
+            "aes-256-gcm" => Ok(Self::Aes256Gcm),
+            "chacha20-ietf-poly1305" => Ok(Self::Chacha20IetfPoly1305),
+            other => Err(ProxyError::Protocol(format!(
+                "unsupported shadowsocks method {} (expected one of {})",
+                other,
+                Self::METHODS.join(", ")
+            ))),
+        }
+    }
+
+    pub fn method(self) -> &'static str {
+        match self {
+            Self::Aes128Gcm => "aes-128-gcm",
+            Self::Aes256Gcm => "aes-256-gcm",
+            Self::Chacha20IetfPoly1305 => "chacha20-ietf-poly1305",
+        }
+    }
+
+    /// 主密钥与子密钥长度，salt与其等长
+    pub fn key_len(self) -> usize {
+        match self {
+            Self::Aes128Gcm => 16,
+            Self::Aes256Gcm | Self::Chacha20IetfPoly1305 => 32,
+        }
+    }
+
+    fn algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            Self::Aes128Gcm => &aead::AES_128_GCM,
+            Self::Aes256Gcm => &aead::AES_256_GCM,
+            Self::Chacha20IetfPoly1305 => &aead::CHACHA20_POLY1305,
+        }
+    }
+
+    /// Master key derived from the password the way OpenSSL's EVP_BytesToKey does (MD5, one round)
+    pub fn derive_key(self, password: &str) -> Vec<u8> {
+        let mut key = Vec::with_capacity(self.key_len() + 16);
+        let mut block = Vec::new();
+        while key.len() < self.key_len() {
+            block.extend_from_slice(password.as_bytes());
+            let digest = md5(&block);
+            key.extend_from_slice(&digest);
+            block.clear();
+            block.extend_from_slice(&digest);
+        }
+        key.truncate(self.key_len());
+        key
+    }
+}
+
+/// HKDF输出长度
+struct SubkeyLen(usize);
+
+impl hkdf::KeyType for SubkeyLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// 单向的AEAD状态：由salt派生的子密钥和递增的nonce
+struct AeadState {
+    key: LessSafeKey,
+    nonce: [u8; NONCE_LEN],
+}
+
+impl AeadState {
+    fn new(cipher: ShadowsocksCipher, master_key: &[u8], salt: &[u8]) -> Self {
+        let mut subkey = vec![0u8; cipher.key_len()];
+        hkdf::Salt::new(hkdf::HKDF_SHA1_FOR_LEGACY_USE_ONLY, salt)
+            .extract(master_key)
+            .expand(&[b"ss-subkey"], SubkeyLen(subkey.len()))
+            .and_then(|okm| okm.fill(&mut subkey))
+            .expect("subkey length is valid for HKDF-SHA1");
+        let key = UnboundKey::new(cipher.algorithm(), &subkey).expect("subkey length matches the cipher");
+        Self { key: LessSafeKey::new(key), nonce: [0; NONCE_LEN] }
+    }
+
+    /// 取出当前nonce并按小端递增
+    fn next_nonce(&mut self) -> Nonce {
+        let nonce = Nonce::assume_unique_for_key(self.nonce);
+        for byte in self.nonce.iter_mut() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                break;
+            }
+        }
+        nonce
+    }
+
+    /// 加密`data`并连同标签追加到`out`
+    fn seal_into(&mut self, data: &[u8], out: &mut BytesMut) {
+        let start = out.len();
+        out.extend_from_slice(data);
+        let nonce = self.next_nonce();
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(nonce, Aad::empty(), &mut out[start..])
+            .expect("chunk is within the AEAD size limit");
+        out.extend_from_slice(tag.as_ref());
+    }
+
+    /// 原地解密`data`（密文加标签），返回明文长度
+    fn open(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        let nonce = self.next_nonce();
+        self.key
+            .open_in_place(nonce, Aad::empty(), data)
+            .map(|plain| plain.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "shadowsocks chunk failed authentication"))
+    }
+}
+
+/// Stream encrypted with the Shadowsocks AEAD protocol
+///
+/// Each direction starts with a random salt followed by chunks of an
+/// encrypted length and an encrypted payload. Writes are encrypted into an
+/// internal buffer; `flush` pushes it to the inner stream.
+pub struct ShadowsocksStream<S> {
+    inner: S,
+    cipher: ShadowsocksCipher,
+    master_key: Arc<[u8]>,
+    encoder: AeadState,
+    decoder: Option<AeadState>,
+    /// 待写出的密文
+    write_buf: BytesMut,
+    /// 已收到但未解密的密文
+    read_buf: BytesMut,
+    /// 已解密但未交给调用方的明文
+    plain: BytesMut,
+    /// 正在接收的数据块的负载长度
+    pending_len: Option<usize>,
+}
+
+impl<S> ShadowsocksStream<S> {
+    /// Wrap `inner`; the salt of the sending direction goes out with the first flush
+    pub fn new(inner: S, cipher: ShadowsocksCipher, master_key: Arc<[u8]>) -> Result<Self> {
+        let mut salt = vec![0u8; cipher.key_len()];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| ProxyError::Protocol("failed to generate shadowsocks salt".to_string()))?;
+        let encoder = AeadState::new(cipher, &master_key, &salt);
+        Ok(Self {
+            inner,
+            cipher,
+            master_key,
+            encoder,
+            decoder: None,
+            write_buf: BytesMut::from(&salt[..]),
+            read_buf: BytesMut::new(),
+            plain: BytesMut::new(),
+            pending_len: None,
+        })
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// 加密一块不超过 MAX_PAYLOAD 的明文
+    fn seal_chunk(&mut self, data: &[u8]) {
+        debug_assert!(!data.is_empty() && data.len() <= MAX_PAYLOAD);
+        self.encoder.seal_into(&(data.len() as u16).to_be_bytes(), &mut self.write_buf);
+        self.encoder.seal_into(data, &mut self.write_buf);
+    }
+
+    /// 从已收到的密文中解出一个数据块到`plain`，密文不足时返回false
+    fn decode_chunk(&mut self) -> io::Result<bool> {
+        let decoder = match &mut self.decoder {
+            Some(decoder) => decoder,
+            None => {
+                let salt_len = self.cipher.key_len();
+                if self.read_buf.len() < salt_len {
+                    return Ok(false);
+                }
+                let salt = self.read_buf.split_to(salt_len);
+                self.decoder.insert(AeadState::new(self.cipher, &self.master_key, &salt))
+            }
+        };
+
+        let len = match self.pending_len {
+            Some(len) => len,
+            None => {
+                if self.read_buf.len() < 2 + TAG_LEN {
+                    return Ok(false);
+                }
+                let mut head = self.read_buf.split_to(2 + TAG_LEN);
+                decoder.open(&mut head)?;
+                let len = u16::from_be_bytes([head[0], head[1]]) as usize;
+                if len == 0 || len > MAX_PAYLOAD {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid shadowsocks chunk length {}", len),
+                    ));
+                }
+                *self.pending_len.insert(len)
+            }
+        };
+        if self.read_buf.len() < len + TAG_LEN {
+            return Ok(false);
+        }
+
+        let mut chunk = self.read_buf.split_to(len + TAG_LEN);
+        let plain_len = decoder.open(&mut chunk)?;
+        chunk.truncate(plain_len);
+        self.pending_len = None;
+        self.plain = chunk;
+        Ok(true)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> ShadowsocksStream<S> {
+    /// 把缓冲的密文全部写入底层流
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ShadowsocksStream<S> {
+    /// Client side: send the target address as the start of the stream
+    pub async fn connect(
+        inner: S,
+        cipher: ShadowsocksCipher,
+        master_key: Arc<[u8]>,
+        target: &Address,
+        port: u16,
+    ) -> Result<Self> {
+        let mut stream = Self::new(inner, cipher, master_key)?;
+        let mut header = BytesMut::new();
+        target.write_with(&mut header, port, AddressFormat::SHADOWSOCKS)?;
+        stream.seal_chunk(&header);
+        // 服务端先说话的协议不会等到客户端写入，地址头必须立即发出
+        stream.flush().await?;
+        Ok(stream)
+    }
+}
+
+impl<S: OutboundStream> OutboundStream for ShadowsocksStream<S> {
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        self.inner.tcp_stream()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ShadowsocksStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if !this.plain.is_empty() {
+                let n = this.plain.len().min(buf.remaining());
+                buf.put_slice(&this.plain.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+            if this.decode_chunk()? {
+                continue;
+            }
+
+            this.read_buf.reserve(READ_RESERVE);
+            if ready!(poll_read_buf(Pin::new(&mut this.inner), cx, &mut this.read_buf))? == 0 {
+                if this.read_buf.is_empty() && this.pending_len.is_none() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "shadowsocks stream ended inside a chunk",
+                )));
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ShadowsocksStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        // 上一块写完才接受新数据，缓冲最多一个数据块
+        ready!(self.poll_drain(cx))?;
+        let n = buf.len().min(MAX_PAYLOAD);
+        self.seal_chunk(&buf[..n]);
+        if let Poll::Ready(Err(e)) = self.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+pub struct ShadowsocksProtocol {
+    server: ServerEndpoint,
+    cipher: ShadowsocksCipher,
+    master_key: Arc<[u8]>,
+}
+
+impl ShadowsocksProtocol {
+    pub const CAPABILITIES: OutboundCapabilities = OutboundCapabilities {
+        accepts_domain_targets: true,
+        supports_udp: false,
+        poolable: false,
+        is_group: false,
+        needs_resolved_target: false,
+        tunnelable: true,
+        blocks: false,
+    };
+
+    pub fn new(server: ServerEndpoint, method: &str, password: &str) -> Result<Self> {
+        if password.is_empty() {
+            return Err(ProxyError::Protocol("shadowsocks password must not be empty".to_string()));
+        }
+        let cipher = ShadowsocksCipher::from_method(method)?;
+        Ok(Self {
+            server,
+            cipher,
+            master_key: cipher.derive_key(password).into(),
+        })
+    }
+
+    pub fn cipher(&self) -> ShadowsocksCipher {
+        self.cipher
+    }
+
+    /// Dial the server and open an encrypted stream to `target`
+    pub async fn connect_stream(
+        &self,
+        target: &Address,
+        port: u16,
+        options: &DialOptions,
+    ) -> Result<ShadowsocksStream<TcpStream>> {
+        let stream = self.server.dial(options).await
+            .map_err(|e| ProxyError::ConnectionFailed(e.to_string()))?;
+        ShadowsocksStream::connect(stream, self.cipher, self.master_key.clone(), target, port).await
+    }
+}
+
+#[async_trait]
+impl Protocol for ShadowsocksProtocol {
+    fn name(&self) -> &str {
+        "shadowsocks"
+    }
+
+    fn server_addr(&self) -> Option<SocketAddr> {
+        Some(self.server.primary())
+    }
+
+    async fn connect_outbound(&self, target: &TargetAddr) -> Result<ProxyStream> {
+        self.connect_outbound_with(target, &DialOptions::default()).await
+    }
+
+    async fn connect_outbound_with(&self, target: &TargetAddr, options: &DialOptions) -> Result<ProxyStream> {
+        let stream = self.connect_stream(&target.address, target.port, options).await?;
+        Ok(ProxyStream::boxed(stream))
+    }
+
+    async fn connect_over(&self, stream: ProxyStream, target: &TargetAddr) -> Result<ProxyStream> {
+        let stream =
+            ShadowsocksStream::connect(stream, self.cipher, self.master_key.clone(), &target.address, target.port).await?;
+        Ok(ProxyStream::boxed(stream))
+    }
+
+    fn capabilities(&self) -> OutboundCapabilities {
+        Self::CAPABILITIES
+    }
+
+    async fn start_inbound(&self, _bind_addr: SocketAddr, _ctx: InboundContext) -> Result<RunningInbound> {
+        Err(ProxyError::Protocol("shadowsocks inbound is not supported".to_string()))
+    }
+}
+
+/// MD5 (RFC 1321)，仅用于 EVP_BytesToKey 的密码派生
+fn md5(data: &[u8]) -> [u8; 16] {
+    Md5::digest(data).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt};
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_md5_and_password_key_derivation() {
+        assert_eq!(hex(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(&md5(b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(
+            hex(&md5(b"12345678901234567890123456789012345678901234567890123456789012345678901234567890")),
+            "57edf4a22be3c955ac49da2e2107b67a"
+        );
+
+        // EVP_BytesToKey: MD5(password) || MD5(MD5(password) || password)
+        let key = ShadowsocksCipher::Aes256Gcm.derive_key("abc");
+        let mut second = md5(b"abc").to_vec();
+        second.extend_from_slice(b"abc");
+        assert_eq!(&key[..16], &md5(b"abc"));
+        assert_eq!(&key[16..], &md5(&second));
+        assert_eq!(ShadowsocksCipher::Aes128Gcm.derive_key("abc"), md5(b"abc"));
+
+        assert!(ShadowsocksCipher::from_method("rc4-md5").is_err());
+        for method in ShadowsocksCipher::METHODS {
+            assert_eq!(ShadowsocksCipher::from_method(method).unwrap().method(), *method);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_round_trip_with_address_header() {
+        for method in ShadowsocksCipher::METHODS {
+            let cipher = ShadowsocksCipher::from_method(method).unwrap();
+            let key: Arc<[u8]> = cipher.derive_key("secret").into();
+            let (client, server) = duplex(1024);
+
+            let server_key = key.clone();
+            let server = tokio::spawn(async move {
+                let mut stream = ShadowsocksStream::new(server, cipher, server_key).unwrap();
+                let (address, port) = Address::read_from(&mut stream, AddressFormat::SHADOWSOCKS).await.unwrap();
+                let mut payload = Vec::new();
+                stream.read_to_end(&mut payload).await.unwrap();
+                stream.write_all(&payload).await.unwrap();
+                stream.shutdown().await.unwrap();
+                (address, port)
+            });
+
+            let target = Address::Domain("example.com".to_string());
+            let mut stream = ShadowsocksStream::connect(client, cipher, key, &target, 443).await.unwrap();
+            // 跨越多个数据块
+            let payload: Vec<u8> = (0..MAX_PAYLOAD * 3 + 17).map(|i| i as u8).collect();
+            stream.write_all(&payload).await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut echoed = Vec::new();
+            stream.read_to_end(&mut echoed).await.unwrap();
+
+            assert_eq!(echoed, payload, "{}", method);
+            assert_eq!(server.await.unwrap(), (target, 443));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wrong_password_fails_authentication() {
+        let cipher = ShadowsocksCipher::Chacha20IetfPoly1305;
+        let (client, server) = duplex(1024);
+        let mut stream = ShadowsocksStream::connect(
+            client,
+            cipher,
+            cipher.derive_key("right").into(),
+            &Address::V4("198.51.100.7".parse().unwrap()),
+            80,
+        )
+        .await
+        .unwrap();
+        stream.shutdown().await.unwrap();
+
+        let mut server = ShadowsocksStream::new(server, cipher, cipher.derive_key("wrong").into()).unwrap();
+        let error = server.read_u8().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_chunk_is_an_error() {
+        let cipher = ShadowsocksCipher::Aes256Gcm;
+        let key: Arc<[u8]> = cipher.derive_key("secret").into();
+        let mut stream = ShadowsocksStream::new(Vec::new(), cipher, key.clone()).unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        stream.flush().await.unwrap();
+
+        // 去掉最后一个字节再交给接收方
+        let wire = stream.get_ref();
+        let mut reader = ShadowsocksStream::new(&wire[..wire.len() - 1], cipher, key).unwrap();
+        let error = reader.read_u8().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -1,23 +1,94 @@
-use super::Protocol;
+use super::{DatagramTransport, OutboundCapabilities, Protocol};
 use crate::error::{ProxyError, Result};
+use crate::inbound::{serve_inbound_shards, InboundContext, RunningInbound};
+use crate::protocol::{Address, Socks5Request, Socks5Response, TargetAddr};
+use crate::uot::{self, UotTransport};
+use crate::listener::bind_tcp_listeners;
+use crate::endpoint::ServerEndpoint;
+use crate::traffic_mark::DialOptions;
+use crate::stream::ProxyStream;
 use async_trait::async_trait;
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub struct Socks5Protocol {
-    // 作为outbound时的服务器地址
-    server_addr: Option<SocketAddr>,
+    // 作为outbound时的服务器端点
+    server: Option<ServerEndpoint>,
+    // UDP经由上游的TCP承载（UoT v2）
+    udp_over_tcp: bool,
 }
 
 impl Socks5Protocol {
     pub fn new() -> Self {
-        Self { server_addr: None }
+        Self { server: None, udp_over_tcp: false }
     }
     
     pub fn with_server(server_addr: SocketAddr) -> Self {
-        Self { server_addr: Some(server_addr) }
+        Self::with_endpoint(ServerEndpoint::single(server_addr))
     }
+
+    /// Outbound to a server that may listen on several ports
+    pub fn with_endpoint(server: ServerEndpoint) -> Self {
+        Self { server: Some(server), udp_over_tcp: false }
+    }
+
+    pub fn endpoint(&self) -> Option<&ServerEndpoint> {
+        self.server.as_ref()
+    }
+
+    pub fn with_udp_over_tcp(mut self, enabled: bool) -> Self {
+        self.udp_over_tcp = enabled;
+        self
+    }
+
+    /// UDP只能经由UoT承载；CONNECT 后的隧道绑定目标，按出站和目标入池复用
+    pub const fn capabilities_with(udp_over_tcp: bool) -> OutboundCapabilities {
+        OutboundCapabilities {
+            accepts_domain_targets: true,
+            supports_udp: udp_over_tcp,
+            poolable: true,
+            is_group: false,
+            needs_resolved_target: false,
+            tunnelable: true,
+            blocks: false,
+        }
+    }
+}
+
+/// SOCKS5客户端握手：无认证协商并发送CONNECT请求
+///
+/// 成功返回后 `stream` 即为到 `target` 的隧道。
+pub async fn socks5_client_connect<S>(stream: &mut S, target: SocketAddr) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    socks5_client_connect_to(stream, &Address::from(target), target.port()).await
+}
+
+/// 同 [`socks5_client_connect`]，目标可以是域名
+pub async fn socks5_client_connect_to<S>(stream: &mut S, address: &Address, port: u16) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // SOCKS5握手
+    stream.write_all(&[0x05u8, 0x01, 0x00]).await?; // 版本5，1个方法，无认证
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf).await?;
+    if buf != [0x05, 0x00] {
+        return Err(ProxyError::Protocol("SOCKS5 authentication failed".to_string()));
+    }
+
+    // 发送连接请求
+    let request = Socks5Request { command: 0x01, address: address.clone(), port };
+    stream.write_all(&request.to_bytes()?).await?;
+
+    // 读取响应，绑定的地址不需要
+    let response = Socks5Response::read_from(stream).await?;
+    if response.status != 0x00 {
+        return Err(ProxyError::ConnectionFailed(format!("SOCKS5 connect failed: {:x}", response.status)));
+    }
+
+    Ok(())
 }
 
 #[async_trait]
@@ -26,85 +97,55 @@ impl Protocol for Socks5Protocol {
         "socks5"
     }
 
-    async fn connect_outbound(&self, target: SocketAddr) -> Result<TcpStream> {
-        let server_addr = self.server_addr
+    fn server_addr(&self) -> Option<SocketAddr> {
+        self.server.as_ref().map(ServerEndpoint::primary)
+    }
+
+    async fn connect_outbound(&self, target: &TargetAddr) -> Result<ProxyStream> {
+        self.connect_outbound_with(target, &DialOptions::default()).await
+    }
+
+    async fn connect_outbound_with(&self, target: &TargetAddr, options: &DialOptions) -> Result<ProxyStream> {
+        let server = self.server.as_ref()
             .ok_or_else(|| ProxyError::Protocol("SOCKS5 server address not configured".to_string()))?;
             
         // 连接到SOCKS5服务器
-        let mut stream = TcpStream::connect(server_addr).await
+        let mut stream = server.dial(options).await
             .map_err(|e| ProxyError::ConnectionFailed(e.to_string()))?;
 
-        // SOCKS5握手
-        stream.write_all(&[0x05u8, 0x01, 0x00]).await?; // 版本5，1个方法，无认证
-        let mut buf = [0u8; 2];
-        stream.read_exact(&mut buf).await?;
-        if buf != [0x05, 0x00] { 
-            return Err(ProxyError::Protocol("SOCKS5 authentication failed".to_string())); 
-        }
-
-        // 发送连接请求
-        let mut req = Vec::with_capacity(32);
-        req.push(0x05); // 版本
-        req.push(0x01); // 连接命令
-        req.push(0x00); // 保留字段
-
-        // 地址类型和地址
-        match target.ip() {
-            std::net::IpAddr::V4(ipv4) => {
-                req.push(0x01); // IPv4
-                req.extend_from_slice(&ipv4.octets());
-            }
-            std::net::IpAddr::V6(ipv6) => {
-                req.push(0x04); // IPv6
-                req.extend_from_slice(&ipv6.octets());
-            }
-        }
-        req.extend_from_slice(&target.port().to_be_bytes());
-        stream.write_all(&req).await?;
-
-        // 读取响应
-        let mut head = [0u8; 4];
-        stream.read_exact(&mut head).await?;
-        if head[1] != 0x00 { 
-            return Err(ProxyError::ConnectionFailed(format!("SOCKS5 connect failed: {:x}", head[1]))); 
-        }
+        // 域名原样交给服务器（ATYP 0x03），由服务器解析
+        socks5_client_connect_to(&mut stream, &target.address, target.port).await?;
 
-        // 跳过绑定的地址信息
-        let to_read = match head[3] {
-            0x01 => 4,  // IPv4
-            0x04 => 16, // IPv6
-            0x03 => {   // 域名
-                let mut l = [0u8; 1];
-                stream.read_exact(&mut l).await?;
-                l[0] as usize
-            }
-            _ => 0,
-        };
-        if to_read > 0 {
-            let mut addr = vec![0u8; to_read];
-            stream.read_exact(&mut addr).await?;
-        }
-        let mut port = [0u8; 2];
-        stream.read_exact(&mut port).await?;
+        Ok(stream.into())
+    }
 
+    async fn connect_over(&self, mut stream: ProxyStream, target: &TargetAddr) -> Result<ProxyStream> {
+        socks5_client_connect_to(&mut stream, &target.address, target.port).await?;
         Ok(stream)
     }
 
-    async fn start_inbound(&self, bind_addr: SocketAddr) -> Result<()> {
-        let listener = TcpListener::bind(bind_addr).await?;
-        log::info!("SOCKS5 inbound listening on {}", bind_addr);
-
-        loop {
-            match listener.accept().await {
-                Ok((_stream, client_addr)) => {
-                    log::info!("SOCKS5 connection from {}", client_addr);
-                    // 这里应该处理SOCKS5连接，但为了简化，先只记录
-                    // 实际实现需要处理SOCKS5协议握手和转发
-                }
-                Err(e) => {
-                    log::error!("SOCKS5 accept error: {}", e);
-                }
-            }
+    fn capabilities(&self) -> OutboundCapabilities {
+        Self::capabilities_with(self.udp_over_tcp)
+    }
+
+    async fn open_datagram(&self, target: SocketAddr) -> Result<Box<dyn DatagramTransport>> {
+        if !self.udp_over_tcp {
+            return Err(ProxyError::Protocol(
+                "SOCKS5 outbound UDP requires udp_over_tcp".to_string(),
+            ));
         }
+        let server = self.server.as_ref()
+            .ok_or_else(|| ProxyError::Protocol("SOCKS5 server address not configured".to_string()))?;
+
+        // 通过魔术域名向上游请求UoT会话
+        let mut stream = server.dial(&DialOptions::default()).await?;
+        socks5_client_connect_to(&mut stream, &Address::Domain(uot::MAGIC_ADDRESS.to_string()), 0).await?;
+        Ok(Box::new(UotTransport::connect(stream, target).await?))
+    }
+
+    async fn start_inbound(&self, bind_addr: SocketAddr, ctx: InboundContext) -> Result<RunningInbound> {
+        let listeners = bind_tcp_listeners(bind_addr).await?;
+        let ctx = ctx.with_refusal(crate::proxy::Socks5Proxy::refuse_connection);
+        serve_inbound_shards("SOCKS5", listeners, ctx, crate::proxy::Socks5Proxy::handle_connection)
     }
 }
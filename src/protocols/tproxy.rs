@@ -1,8 +1,12 @@
+// 透明代理入站（仅 Linux）：iptables/nftables 的 TPROXY 目标把流量交给 IP_TRANSPARENT 套接字，
+// TCP 连接的本地地址就是原目标；UDP 数据报的原目标由 IP_RECVORIGDSTADDR 随包带回
 use super::Protocol;
 use crate::error::{ProxyError, Result};
+use crate::protocol::TargetAddr;
+use crate::inbound::{InboundContext, RunningInbound};
+use crate::stream::ProxyStream;
 use async_trait::async_trait;
 use std::net::SocketAddr;
-use tokio::net::TcpStream;
 
 pub struct TproxyProtocol;
 
@@ -18,15 +22,15 @@ impl Protocol for TproxyProtocol {
         "tproxy"
     }
 
-    async fn connect_outbound(&self, _target: SocketAddr) -> Result<TcpStream> {
+    async fn connect_outbound(&self, _target: &TargetAddr) -> Result<ProxyStream> {
         // TProxy作为outbound没有意义
         Err(ProxyError::Protocol("TProxy protocol cannot be used as outbound".to_string()))
     }
 
-    async fn start_inbound(&self, _bind_addr: SocketAddr) -> Result<()> {
+    async fn start_inbound(&self, _bind_addr: SocketAddr, _ctx: InboundContext) -> Result<RunningInbound> {
         #[cfg(target_os = "linux")]
         {
-            self.start_tproxy_linux(_bind_addr).await
+            self.start_tproxy_linux(_bind_addr, _ctx).await
         }
         #[cfg(not(target_os = "linux"))]
         {
@@ -35,78 +39,570 @@ impl Protocol for TproxyProtocol {
     }
 }
 
+#[cfg(target_os = "linux")]
 impl TproxyProtocol {
-    #[cfg(target_os = "linux")]
-    async fn start_tproxy_linux(&self, bind_addr: SocketAddr) -> Result<()> {
-        use nix::sys::socket::{setsockopt, sockopt::IpTransparent};
-        use socket2::{Domain, Protocol, Socket, Type};
-        use std::os::fd::FromRawFd;
-        use tokio::net::{TcpListener, UdpSocket};
+    async fn start_tproxy_linux(&self, bind_addr: SocketAddr, ctx: InboundContext) -> Result<RunningInbound> {
+        use crate::inbound::serve_inbound;
+        use crate::listener::{bind_tcp_listener_with, get_global_listener_options};
+        use crate::tasks::TaskGroup;
 
         // TCP透明代理
-        let tcp_listener = self.create_transparent_tcp_listener(bind_addr)?;
-        let listener = unsafe { 
-            TcpListener::from_std(std::net::TcpListener::from_raw_fd(tcp_listener.as_raw_fd())) 
-        };
-        log::info!("TProxy TCP listening on {}", bind_addr);
+        let listener = bind_tcp_listener_with(bind_addr, get_global_listener_options(), |socket| {
+            set_transparent(socket, bind_addr)
+        })
+        .await?;
+        let tasks = ctx.tasks;
+        let running = serve_inbound("TProxy TCP", listener, ctx, |stream, peer, ctx| async move {
+            // 透明套接字上接受的连接，本地地址就是客户端原本要连的目标
+            let target = stream.local_addr()?;
+            Self::handle_tcp(stream, peer, target, ctx).await
+        })?;
 
-        tokio::spawn(async move {
-            loop {
-                match listener.accept().await {
-                    Ok((_stream, peer)) => {
-                        log::info!("TProxy TCP accepted from {}", peer);
-                        // 这里需要处理TProxy连接和路由
-                    }
-                    Err(e) => {
-                        log::error!("TProxy TCP accept error: {}", e);
-                        break;
-                    }
-                }
+        // UDP透明代理，随入站一起停止
+        let udp = tokio::net::UdpSocket::from_std(udp::bind_transparent(bind_addr, true)?)?;
+        tasks.spawn(TaskGroup::UdpSessions, udp::serve(udp, running.shutdown_token()))?;
+        log::info!("TProxy UDP bound on {}", bind_addr);
+
+        Ok(running)
+    }
+
+    /// Relay one transparently redirected TCP connection to `target`
+    ///
+    /// Shared with the REDIRECT inbound, which finds `target` through conntrack.
+    pub async fn handle_tcp(stream: tokio::net::TcpStream, client: SocketAddr, target: SocketAddr, ctx: InboundContext) -> Result<()> {
+        let tracked = ctx.connections.register(client);
+        let result = Self::serve_tcp(stream, client, target, &ctx, tracked.connection()).await;
+        ctx.access_log.record_connection(&tracked, result.as_ref().err());
+        match result {
+            Err(ProxyError::Blocked(_) | ProxyError::RateLimited(_)) => Ok(()),
+            result => result,
+        }
+    }
+
+    async fn serve_tcp(
+        mut stream: tokio::net::TcpStream,
+        client: SocketAddr,
+        target: SocketAddr,
+        ctx: &InboundContext,
+        tracked: &std::sync::Arc<crate::connection_registry::TrackedConnection>,
+    ) -> Result<()> {
+        use crate::blocked::get_global_blocked_traffic;
+        use crate::config::{Network, RouteAction};
+        use crate::connection_rate::get_global_connection_rate_limiter;
+        use crate::connection_registry::ConnectionPhase;
+        use crate::diagnostics::ConnectDiagnostics;
+        use crate::inbound::drop_silently;
+        use crate::outbound::{connect_addresses, resolve_target};
+        use crate::protocol::Address;
+        use crate::routing::{RouteContext, RouteHost};
+        use crate::traffic_mark::{apply_linger, DialOptions, LingerPolicy};
+        use crate::zero_copy::{RelayOptions, RelayResult, ZeroCopyRelay};
+
+        let target = SocketAddr::new(target.ip().to_canonical(), target.port());
+        tracked.set_phase(ConnectionPhase::Connecting);
+        tracked.set_target(target.to_string());
+        if let Some(profile) = &ctx.profile {
+            tracked.set_profile(profile.as_str());
+        }
+        // 发往假地址的连接换回 DNS 入站记下的域名，按域名路由，再由出站解析或交给上游
+        let domain = match ctx.fake_ip() {
+            Some(pool) => pool.lookup(target.ip())?,
+            None => None,
+        };
+        let host = domain.clone().unwrap_or_else(|| target.ip().to_string());
+        let destination = match &domain {
+            Some(domain) => {
+                let destination = format!("{}:{}", domain, target.port());
+                tracked.set_target(destination.clone());
+                destination
             }
+            None => target.to_string(),
+        };
+
+        let decision = ctx.router().route(&RouteContext {
+            host: match &domain {
+                Some(domain) => RouteHost::Domain(domain),
+                None => RouteHost::Ip(target.ip()),
+            },
+            port: Some(target.port()),
+            network: Some(Network::Tcp),
+            source: Some(client),
+            inbound: ctx.tag.as_deref(),
         });
+        tracked.set_outbound(decision.outbound.clone());
+        // 透明代理没有应答码：reject 以 RST 关闭，reject-drop 不作应答
+        if !decision.action.is_forward() {
+            get_global_blocked_traffic().record(&host, decision.rule);
+            match decision.action {
+                RouteAction::Drop => drop_silently(&mut stream, ctx.config).await,
+                _ => apply_linger(&stream, LingerPolicy::Reset)?,
+            }
+            return Err(ProxyError::Blocked(destination));
+        }
+        let ob_manager = ctx.outbounds();
+        let dial_outbound = decision.outbound_chain.first().unwrap_or(&decision.outbound).clone();
+        let connector = if decision.outbound_chain.is_empty() {
+            let connector = ob_manager
+                .get(&decision.outbound)
+                .ok_or_else(|| ProxyError::Protocol(format!("Outbound not found: {}", decision.outbound)))?;
+            if let Some(disabled) = ob_manager.disabled(&decision.outbound) {
+                return Err(disabled.reject());
+            }
+            connector
+        } else {
+            ob_manager.chain(&decision.outbound_chain)?
+        };
+        if connector.capabilities().blocks {
+            get_global_blocked_traffic().record(&host, decision.rule);
+            return Err(ProxyError::Blocked(destination));
+        }
 
-        // UDP透明代理
-        let udp = self.create_transparent_udp_socket(bind_addr)?;
-        let _udp = UdpSocket::from_std(udp)?;
-        log::info!("TProxy UDP bound on {}", bind_addr);
+        let rate_outbounds: Vec<&str> =
+            [Some(dial_outbound.as_str()), ob_manager.selected(&dial_outbound)].into_iter().flatten().collect();
+        get_global_connection_rate_limiter().acquire(&rate_outbounds).await?;
+
+        let dial_options = DialOptions {
+            dscp: decision.dscp.or_else(|| ob_manager.dscp(&dial_outbound)),
+            so_mark: ob_manager.routing_mark(&dial_outbound),
+            tcp_mss: ob_manager.tcp_mss(&dial_outbound),
+            ..DialOptions::default()
+        };
+        let mut diagnostics = ConnectDiagnostics::start();
+        diagnostics.route(decision.rule, decision.outbound);
+        let target_addrs = match domain {
+            // 目标是本机入站（没有 TPROXY 规则时直接连到了监听端口）会形成回环
+            None => {
+                ctx.listeners.check_loop(target, connector.server_addr())?;
+                vec![TargetAddr::from(target)]
+            }
+            Some(domain) if connector.capabilities().accepts_domain_targets => {
+                ctx.listeners.check_outbound_server(connector.server_addr())?;
+                vec![TargetAddr::new(Address::Domain(domain), target.port())]
+            }
+            Some(domain) => {
+                let client_subnet = ob_manager.egress_hint_subnet(&dial_outbound);
+                let resolved = resolve_target(&Address::Domain(domain), target.port(), client_subnet, &mut diagnostics).await?;
+                for addr in &resolved {
+                    ctx.listeners.check_loop(*addr, connector.server_addr())?;
+                }
+                resolved.into_iter().map(TargetAddr::from).collect()
+            }
+        };
+        let attempt_timeout = ctx.config.connection_timeout();
+        let target_stream =
+            connect_addresses(connector.as_ref(), &target_addrs, &dial_options, attempt_timeout, &mut diagnostics).await?;
+        tracked.mark_connected();
+        log::info!("Connected {} to original destination {} via {}", client, destination, diagnostics.outbound);
 
+        if let Err(e) = apply_linger(&stream, ctx.linger) {
+            log::warn!("Failed to set SO_LINGER {:?} for {}: {}", ctx.linger, client, e);
+        }
+        let relay_options = RelayOptions {
+            tls_fragment: ob_manager.tls_fragment(&dial_outbound),
+            latency_mode: decision.latency_mode || ob_manager.latency_mode(&dial_outbound),
+            ..RelayOptions::from_config(&ctx.config.performance)
+        };
+        tracked.set_phase(ConnectionPhase::Relaying);
+        let result = ZeroCopyRelay::with_options(stream, target_stream, relay_options)
+            .with_tracker(tracked.clone())
+            .start()
+            .await?;
+        if let RelayResult::PeerAborted(reason) | RelayResult::Aborted(reason) = &result {
+            log::info!("Connection from {} to {} aborted: {}", client, destination, reason);
+        }
+        tracked.set_relay_result(result);
         Ok(())
     }
+}
 
-    #[cfg(target_os = "linux")]
-    fn create_transparent_tcp_listener(&self, addr: SocketAddr) -> Result<std::net::TcpListener> {
-        use nix::sys::socket::{setsockopt, sockopt::IpTransparent};
-        use socket2::{Domain, Protocol, Socket, Type};
-        use std::os::fd::AsRawFd;
+/// IP_TRANSPARENT, or IPV6_TRANSPARENT for an IPv6 socket
+#[cfg(target_os = "linux")]
+fn set_transparent(socket: &socket2::Socket, addr: SocketAddr) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
 
-        let domain = match addr { 
-            SocketAddr::V4(_) => Domain::IPV4, 
-            SocketAddr::V6(_) => Domain::IPV6 
-        };
-        let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    match addr {
+        SocketAddr::V4(_) => socket.set_ip_transparent(true),
+        SocketAddr::V6(_) => {
+            let on: libc::c_int = 1;
+            let rc = unsafe {
+                libc::setsockopt(
+                    socket.as_raw_fd(),
+                    libc::SOL_IPV6,
+                    libc::IPV6_TRANSPARENT,
+                    &on as *const _ as *const libc::c_void,
+                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                )
+            };
+            if rc < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Transparent UDP: datagrams go to their original destination through a
+/// direct socket carrying `traffic_mark.so_mark`, and replies are sent back
+/// from that destination's address
+#[cfg(target_os = "linux")]
+mod udp {
+    use super::set_transparent;
+    use crate::dns::try_get_global_dns_resolver;
+    use crate::error::{ProxyError, Result};
+    use crate::fake_ip::try_get_global_fake_ip_pool;
+    use crate::tasks::{get_global_task_tracker, TaskGroup};
+    use crate::traffic_mark::{apply_traffic_mark, get_global_traffic_mark_config, TrafficMarkConfig};
+    use crate::uot::MAX_DATAGRAM_SIZE;
+    use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+    use std::collections::HashMap;
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::os::fd::{AsRawFd, RawFd};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::Interest;
+    use tokio::net::UdpSocket;
+    use tokio_util::sync::CancellationToken;
+
+    /// A session with no datagram in either direction for this long is closed
+    const SESSION_IDLE: Duration = Duration::from_secs(60);
+
+    /// (client, original destination) → socket towards the destination
+    type Sessions = Arc<Mutex<HashMap<(SocketAddr, SocketAddr), Arc<UdpSocket>>>>;
+
+    /// Nonblocking transparent UDP socket bound to `addr`; `listen` also asks
+    /// for the original destination of each datagram
+    pub(super) fn bind_transparent(addr: SocketAddr, listen: bool) -> io::Result<std::net::UdpSocket> {
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
         socket.set_reuse_address(true)?;
-        setsockopt(socket.as_raw_fd(), IpTransparent, &true)
-            .map_err(|e| std::io::Error::other(e))?;
+        set_transparent(&socket, addr)?;
+        if listen {
+            let (level, name) = match addr {
+                SocketAddr::V4(_) => (libc::SOL_IP, libc::IP_RECVORIGDSTADDR),
+                SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR),
+            };
+            set_flag(socket.as_raw_fd(), level, name)?;
+        }
+        socket.set_nonblocking(true)?;
         socket.bind(&addr.into())?;
-        socket.listen(1024)?;
         Ok(socket.into())
     }
 
-    #[cfg(target_os = "linux")]
-    fn create_transparent_udp_socket(&self, addr: SocketAddr) -> Result<std::net::UdpSocket> {
-        use nix::sys::socket::{setsockopt, sockopt::IpTransparent};
-        use socket2::{Domain, Protocol, Socket, Type};
-        use std::os::fd::AsRawFd;
+    fn set_flag(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+        let on: libc::c_int = 1;
+        let rc = unsafe {
+            libc::setsockopt(fd, level, name, &on as *const _ as *const libc::c_void, mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// One datagram with its source and, when the socket asked for it, the
+    /// destination it was originally sent to
+    pub(super) fn recv_with_orig_dst(fd: RawFd, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+        let mut source: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut iov = libc::iovec { iov_base: buf.as_mut_ptr().cast(), iov_len: buf.len() };
+        // 控制消息缓冲按 cmsghdr 对齐
+        let mut control = [0u64; 16];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = (&mut source as *mut libc::sockaddr_storage).cast();
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        let n = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+        let n = usize::try_from(n).map_err(|_| io::Error::last_os_error())?;
+        let source = unsafe { SockAddr::new(source, msg.msg_namelen) }
+            .as_socket()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "datagram from a non-IP address"))?;
+
+        let mut orig_dst = None;
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cmsg.is_null() {
+            let header = unsafe { &*cmsg };
+            let is_orig_dst = matches!(
+                (header.cmsg_level, header.cmsg_type),
+                (libc::SOL_IP, libc::IP_ORIGDSTADDR) | (libc::SOL_IPV6, libc::IPV6_ORIGDSTADDR)
+            );
+            if is_orig_dst {
+                let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+                let data_len = header.cmsg_len as usize - unsafe { libc::CMSG_LEN(0) } as usize;
+                let len = data_len.min(mem::size_of::<libc::sockaddr_storage>());
+                // 内核写入的 sockaddr_in / sockaddr_in6，按长度拷出
+                unsafe {
+                    std::ptr::copy_nonoverlapping(libc::CMSG_DATA(cmsg), (&mut addr as *mut libc::sockaddr_storage).cast(), len);
+                }
+                orig_dst = unsafe { SockAddr::new(addr, len as libc::socklen_t) }.as_socket();
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+        Ok((n, source, orig_dst))
+    }
+
+    /// Receive redirected datagrams until `shutdown`
+    pub(super) async fn serve(socket: UdpSocket, shutdown: CancellationToken) {
+        let sessions: Sessions = Arc::default();
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            let received = tokio::select! {
+                received = socket.async_io(Interest::READABLE, || recv_with_orig_dst(socket.as_raw_fd(), &mut buf)) => received,
+                _ = shutdown.cancelled() => return,
+            };
+            let (n, client, orig_dst) = match received {
+                Ok(datagram) => datagram,
+                Err(e) => {
+                    log::debug!("TProxy UDP receive failed: {}", e);
+                    continue;
+                }
+            };
+            let Some(orig_dst) = orig_dst else {
+                log::debug!("TProxy UDP datagram from {} without original destination", client);
+                continue;
+            };
+            let upstream = match session(&sessions, client, orig_dst, &shutdown).await {
+                Ok(upstream) => upstream,
+                Err(e) => {
+                    log::warn!("TProxy UDP session {} -> {} failed: {}", client, orig_dst, e);
+                    continue;
+                }
+            };
+            if let Err(e) = upstream.send(&buf[..n]).await {
+                log::debug!("TProxy UDP send to {} failed: {}", orig_dst, e);
+            }
+        }
+    }
+
+    /// Socket towards `orig_dst` for `client`, opening the session on first use
+    async fn session(sessions: &Sessions, client: SocketAddr, orig_dst: SocketAddr, shutdown: &CancellationToken) -> Result<Arc<UdpSocket>> {
+        let key = (client, SocketAddr::new(orig_dst.ip().to_canonical(), orig_dst.port()));
+        if let Some(upstream) = sessions.lock().unwrap().get(&key) {
+            return Ok(upstream.clone());
+        }
+        let orig_dst = key.1;
+        let upstream = Arc::new(connect_marked(real_destination(orig_dst).await?).await?);
+        // 回包要以原目标的地址发给客户端
+        let reply = UdpSocket::from_std(bind_transparent(orig_dst, false)?)?;
+        sessions.lock().unwrap().insert(key, upstream.clone());
+        log::debug!("TProxy UDP session {} -> {} opened", client, orig_dst);
+
+        let sessions = sessions.clone();
+        let shutdown = shutdown.clone();
+        let relay_replies = {
+            let upstream = upstream.clone();
+            async move {
+                let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+                loop {
+                    let n = tokio::select! {
+                        received = tokio::time::timeout(SESSION_IDLE, upstream.recv(&mut buf)) => match received {
+                            Ok(Ok(n)) => n,
+                            Ok(Err(e)) => {
+                                log::debug!("TProxy UDP session {} -> {} ended: {}", client, orig_dst, e);
+                                break;
+                            }
+                            Err(_) => break,
+                        },
+                        _ = shutdown.cancelled() => break,
+                    };
+                    if let Err(e) = reply.send_to(&buf[..n], client).await {
+                        log::debug!("TProxy UDP reply to {} failed: {}", client, e);
+                    }
+                }
+                sessions.lock().unwrap().remove(&key);
+                log::debug!("TProxy UDP session {} -> {} closed", client, orig_dst);
+            }
+        };
+        get_global_task_tracker().spawn(TaskGroup::UdpSessions, relay_replies)?;
+        Ok(upstream)
+    }
+
+    /// Where datagrams for `orig_dst` really go: a fake address is replaced by
+    /// an address of the domain it stands for
+    async fn real_destination(orig_dst: SocketAddr) -> Result<SocketAddr> {
+        let domain = match try_get_global_fake_ip_pool() {
+            Some(pool) => pool.lookup(orig_dst.ip())?,
+            None => None,
+        };
+        let Some(domain) = domain else {
+            return Ok(orig_dst);
+        };
+        let (ips, _) = try_get_global_dns_resolver()?.resolve_all(&domain).await?;
+        let ip = ips.first().ok_or_else(|| ProxyError::DnsResolution(format!("No address for {}", domain)))?;
+        Ok(SocketAddr::new(*ip, orig_dst.port()))
+    }
 
-        let domain = match addr { 
-            SocketAddr::V4(_) => Domain::IPV4, 
-            SocketAddr::V6(_) => Domain::IPV6 
+    /// Direct UDP socket connected to `target`, marked like outbound TCP
+    async fn connect_marked(target: SocketAddr) -> Result<UdpSocket> {
+        let local: SocketAddr = match target {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
         };
-        let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
-        socket.set_reuse_address(true)?;
-        setsockopt(socket.as_raw_fd(), IpTransparent, &true)
-            .map_err(|e| std::io::Error::other(e))?;
-        socket.bind(&addr.into())?;
-        Ok(socket.into())
+        let socket = Socket::new(Domain::for_address(target), Type::DGRAM, Some(Protocol::UDP))?;
+        if let Some(mark) = get_global_traffic_mark_config().and_then(|config| config.so_mark) {
+            apply_traffic_mark(&socket, &TrafficMarkConfig::with_so_mark(mark))?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&local.into())?;
+        let socket = UdpSocket::from_std(socket.into())?;
+        socket.connect(target).await?;
+        Ok(socket)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::net::IpAddr;
+
+        #[tokio::test]
+        async fn test_recv_reports_original_destination() {
+            // 没有 TPROXY 规则时，原目标就是套接字自己的地址；IP_TRANSPARENT 需要 CAP_NET_ADMIN
+            let std_socket = match bind_transparent((Ipv4Addr::LOCALHOST, 0).into(), true) {
+                Ok(socket) => socket,
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return,
+                Err(e) => panic!("{}", e),
+            };
+            let socket = UdpSocket::from_std(std_socket).unwrap();
+            let local = socket.local_addr().unwrap();
+            let sender = UdpSocket::bind((IpAddr::from(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+            sender.send_to(b"datagram", local).await.unwrap();
+
+            let mut buf = [0u8; 64];
+            let (n, source, orig_dst) =
+                socket.async_io(Interest::READABLE, || recv_with_orig_dst(socket.as_raw_fd(), &mut buf)).await.unwrap();
+            assert_eq!(&buf[..n], b"datagram");
+            assert_eq!(source, sender.local_addr().unwrap());
+            assert_eq!(orig_dst, Some(local));
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::access_log::{AccessLogger, AccessRecord, Termination};
+    use crate::config::{AccessLogConfig, Config};
+    use crate::inbound::serve_inbound;
+    use crate::outbound::OutboundManager;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+
+    fn context() -> (InboundContext, mpsc::Receiver<AccessRecord>) {
+        let config: &'static Config = Box::leak(Box::new(Config::default()));
+        let outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        let (access_log, records) = AccessLogger::channel(&AccessLogConfig { enabled: true, ..Default::default() });
+        let ctx = InboundContext { access_log: Box::leak(Box::new(access_log)), ..InboundContext::new(config, Arc::new(outbounds)) };
+        (ctx, records)
+    }
+
+    #[tokio::test]
+    async fn test_tcp_relayed_to_original_destination() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let (mut read, mut write) = stream.split();
+            let _ = tokio::io::copy(&mut read, &mut write).await;
+        });
+
+        // 真实部署中原目标来自透明套接字的本地地址；这里直接指定
+        let (ctx, mut records) = context();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let running = serve_inbound("TProxy TCP", listener, ctx, move |stream, peer, ctx| {
+            TproxyProtocol::handle_tcp(stream, peer, echo_addr, ctx)
+        })
+        .unwrap();
+
+        let mut client = TcpStream::connect(running.local_addr()).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+        client.shutdown().await.unwrap();
+        assert_eq!(client.read(&mut echoed).await.unwrap(), 0);
+
+        let record = records.recv().await.unwrap();
+        assert_eq!(record.target, Some(echo_addr.to_string()));
+        assert_eq!(record.outbound.as_deref(), Some("direct"));
+        assert_eq!((record.upload, record.download), (4, 4));
+        assert_eq!(record.termination, Termination::Ok);
+        running.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fake_ip_routed_by_domain() {
+        use crate::config::{FakeIpConfig, RouteAction, RouterRuleConfig};
+        use crate::fake_ip::FakeIpPool;
+
+        let mut config = Config::default();
+        config.router.rules.push(RouterRuleConfig {
+            outbound: String::new(),
+            action: RouteAction::Reject,
+            outbound_chain: Vec::new(),
+            rule_sets: Vec::new(),
+            domains: crate::config::DomainLists { domain_suffix: vec!["ads.test".to_string()], ..Default::default() },
+            ip_cidr: Vec::new(),
+            dscp: None,
+            latency_mode: false,
+            rewrite_to: None,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
+        });
+        let router = Arc::new(crate::routing::build_router(&config).await.unwrap());
+        let pool: &'static FakeIpPool =
+            Box::leak(Box::new(FakeIpPool::new(&FakeIpConfig { enabled: true, ..FakeIpConfig::default() })));
+        let fake = pool.allocate("tracker.ads.test");
+        let (ctx, mut records) = context();
+        let ctx = InboundContext { router: Some(router), ..ctx.with_fake_ip(pool) };
+
+        // 发往假地址的连接按域名命中 reject 规则；没有映射的假地址无法得知目标，直接关闭
+        for (ip, port) in [(fake, 443), (std::net::Ipv4Addr::new(198, 18, 200, 1), 80)] {
+            let target = SocketAddr::from((ip, port));
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let running = serve_inbound("TProxy TCP", listener, ctx.clone(), move |stream, peer, ctx| {
+                TproxyProtocol::handle_tcp(stream, peer, target, ctx)
+            })
+            .unwrap();
+            let mut client = TcpStream::connect(running.local_addr()).await.unwrap();
+            let mut buf = [0u8; 1];
+            assert!(matches!(client.read(&mut buf).await, Ok(0) | Err(_)));
+            running.stop().await.unwrap();
+        }
+
+        let blocked = records.recv().await.unwrap();
+        assert_eq!(blocked.target.as_deref(), Some("tracker.ads.test:443"));
+        assert_eq!(blocked.outbound.as_deref(), Some("reject"));
+        let unknown = records.recv().await.unwrap();
+        assert_eq!(unknown.target.as_deref(), Some("198.18.200.1:80"));
+        assert!(unknown.error.unwrap().contains("Fake IP 198.18.200.1"));
+    }
+
+    #[tokio::test]
+    async fn test_connection_to_the_listener_itself_is_a_loop() {
+        let (ctx, mut records) = context();
+        let running = match TproxyProtocol::new().start_inbound("127.0.0.1:0".parse().unwrap(), ctx).await {
+            Ok(running) => running,
+            // IP_TRANSPARENT 需要 CAP_NET_ADMIN
+            Err(ProxyError::Io(e)) if e.kind() == std::io::ErrorKind::PermissionDenied => return,
+            Err(e) => panic!("{}", e),
+        };
+
+        // 没有 TPROXY 规则直接连到监听端口：原目标就是监听地址本身
+        let mut client = TcpStream::connect(running.local_addr()).await.unwrap();
+        let mut buf = [0u8; 1];
+        assert!(matches!(client.read(&mut buf).await, Ok(0) | Err(_)));
+        let record = records.recv().await.unwrap();
+        assert_eq!(record.termination, Termination::ConnectFailed);
+        assert!(record.error.unwrap().contains("own listener"));
+        running.stop().await.unwrap();
     }
 }
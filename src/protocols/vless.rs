@@ -1,13 +1,21 @@
-use super::Protocol;
+use super::{DatagramTransport, OutboundCapabilities, Protocol};
 use crate::error::{ProxyError, Result};
+use crate::protocol::TargetAddr;
+use crate::inbound::{InboundContext, RunningInbound};
+use crate::tls::{TlsClient, TlsClientOptions};
+use crate::stream::ProxyStream;
 use async_trait::async_trait;
 use std::net::SocketAddr;
-use tokio::net::TcpStream;
+use std::sync::Arc;
 
 pub struct VlessProtocol {
     server_addr: Option<SocketAddr>,
     uuid: Option<String>,
     tls: bool,
+    /// 每个出站一个TLS客户端，连接间共享会话缓存
+    tls_client: Option<Arc<TlsClient>>,
+    override_host_header: Option<String>,
+    udp_over_tcp: bool,
 }
 
 impl VlessProtocol {
@@ -15,7 +23,10 @@ impl VlessProtocol {
         Self { 
             server_addr: None, 
             uuid: None, 
-            tls: false 
+            tls: false,
+            tls_client: None,
+            override_host_header: None,
+            udp_over_tcp: false,
         }
     }
     
@@ -23,9 +34,45 @@ impl VlessProtocol {
         Self { 
             server_addr: Some(server_addr), 
             uuid: Some(uuid), 
-            tls 
+            tls,
+            tls_client: None,
+            override_host_header: None,
+            udp_over_tcp: false,
         }
     }
+
+    /// 设置TLS选项（SNI覆盖、会话恢复等）与传输层Host头覆盖
+    pub fn with_tls_options(mut self, tls_options: TlsClientOptions, override_host_header: Option<String>) -> Result<Self> {
+        self.tls_client = if self.tls {
+            Some(Arc::new(TlsClient::new(tls_options)?))
+        } else {
+            None
+        };
+        self.override_host_header = override_host_header;
+        Ok(self)
+    }
+
+    pub fn with_udp_over_tcp(mut self, enabled: bool) -> Self {
+        self.udp_over_tcp = enabled;
+        self
+    }
+
+    /// UDP只能经由UoT承载；不能作为代理链的后续跳
+    pub const fn capabilities_with(udp_over_tcp: bool) -> OutboundCapabilities {
+        OutboundCapabilities {
+            accepts_domain_targets: true,
+            supports_udp: udp_over_tcp,
+            poolable: false,
+            is_group: false,
+            needs_resolved_target: false,
+            tunnelable: false,
+            blocks: false,
+        }
+    }
+
+    pub fn tls_client(&self) -> Option<&Arc<TlsClient>> {
+        self.tls_client.as_ref()
+    }
 }
 
 #[async_trait]
@@ -34,11 +81,27 @@ impl Protocol for VlessProtocol {
         "vless"
     }
 
-    async fn connect_outbound(&self, _target: SocketAddr) -> Result<TcpStream> {
+    fn server_addr(&self) -> Option<SocketAddr> {
+        self.server_addr
+    }
+
+    async fn connect_outbound(&self, _target: &TargetAddr) -> Result<ProxyStream> {
+        Err(ProxyError::Protocol("VLESS protocol not implemented yet".to_string()))
+    }
+
+    async fn open_datagram(&self, _target: SocketAddr) -> Result<Box<dyn DatagramTransport>> {
+        if !self.udp_over_tcp {
+            return Err(ProxyError::Protocol("VLESS outbound UDP requires udp_over_tcp".to_string()));
+        }
+        // UoT会话承载在到 uot::MAGIC_ADDRESS 的VLESS流上，依赖VLESS连接实现
         Err(ProxyError::Protocol("VLESS protocol not implemented yet".to_string()))
     }
 
-    async fn start_inbound(&self, _bind_addr: SocketAddr) -> Result<()> {
+    fn capabilities(&self) -> OutboundCapabilities {
+        Self::capabilities_with(self.udp_over_tcp)
+    }
+
+    async fn start_inbound(&self, _bind_addr: SocketAddr, _ctx: InboundContext) -> Result<RunningInbound> {
         Err(ProxyError::Protocol("VLESS protocol not implemented yet".to_string()))
     }
 }
@@ -1,13 +1,28 @@
+use crate::blocked::get_global_blocked_traffic;
+use crate::capture::{get_global_capture, CaptureMeta};
+use crate::config::{try_get_global_config, Config, Network, RouteAction};
+use crate::connection_pool::{PoolKey, PooledConnection};
+use crate::connection_rate::get_global_connection_rate_limiter;
 use crate::error::{ProxyError, Result};
-use crate::outbound::get_global_outbound_manager;
-use crate::protocol::{handle_socks5_handshake, Socks5Request, Socks5Response};
-use crate::routing::HighPerformanceRouter;
-use crate::traffic_mark::{create_marked_tcp_stream, get_global_traffic_mark_config};
-use crate::zero_copy::ZeroCopyRelay;
-use log::{debug, error, info, warn};
+use crate::inbound::{drop_silently, get_global_listener_registry, serve_inbound_shards, InboundContext, RunningInbound};
+use crate::listener::bind_tcp_listeners;
+use crate::diagnostics::ConnectDiagnostics;
+use crate::outbound::{connect_addresses, resolve_target, set_tcp_user_timeout, OutboundManager};
+use crate::protocol::{handle_socks5_handshake, negotiate_socks5_auth_with, Address, LogSafe, Socks5Request, Socks5Response, TargetAddr};
+use crate::routing::{RouteContext, RouteDecision, RouteHost};
+use crate::traffic_mark::{apply_linger, dial_tcp, DialOptions};
+use crate::connection_registry::{ConnectionPhase, TrackedConnection};
+use crate::uot;
+use crate::zero_copy::{RelayOptions, RelayResult, ZeroCopyRelay};
+use futures::future::BoxFuture;
+use log::{debug, info, warn};
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpStream;
 
 pub struct Socks5Proxy {
     bind_addr: SocketAddr,
@@ -19,100 +34,553 @@ impl Socks5Proxy {
     }
 
     pub async fn start(&self) -> Result<()> {
-        let listener = TcpListener::bind(self.bind_addr).await?;
-        info!("SOCKS5 proxy listening on {}", self.bind_addr);
-
-        loop {
-            match listener.accept().await {
-                Ok((stream, client_addr)) => {
-                    info!("New connection from {}", client_addr);
-
-                    // Spawn a new task for each connection
-                    tokio::spawn(async move {
-                        if let Err(e) = Self::handle_connection(stream, client_addr).await {
-                            error!("Error handling connection from {}: {}", client_addr, e);
-                        }
-                    });
-                }
-                Err(e) => {
-                    error!("Failed to accept connection: {}", e);
-                }
+        self.bind(InboundContext::global()).await?.join().await
+    }
+
+    /// Bind the listener and serve it in the background
+    pub async fn bind(&self, context: InboundContext) -> Result<RunningInbound> {
+        let listeners = bind_tcp_listeners(self.bind_addr).await?;
+        serve_inbound_shards("SOCKS5", listeners, context.with_refusal(Self::refuse_connection), Self::handle_connection)
+    }
+
+    /// Turn away a client over `server.max_connections`: take its greeting
+    /// without authentication and refuse the request with general failure (0x01)
+    pub fn refuse_connection(mut client_stream: TcpStream) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            let refuse = async {
+                handle_socks5_handshake(&mut client_stream).await?;
+                Socks5Request::read_from(&mut client_stream).await?;
+                send_failure_reply(&mut client_stream, 0x01).await;
+                Ok::<_, ProxyError>(())
+            };
+            match tokio::time::timeout(REFUSAL_TIMEOUT, refuse).await {
+                Ok(Err(e)) => debug!("Refusing client over the connection limit: {}", e),
+                Err(_) => debug!("Refused client sent no request within {:?}", REFUSAL_TIMEOUT),
+                Ok(Ok(())) => {}
             }
-        }
+        })
     }
 
-    async fn handle_connection(mut client_stream: TcpStream, client_addr: SocketAddr) -> Result<()> {
+    /// Serve one accepted SOCKS5 client until its session ends
+    pub async fn handle_connection(client_stream: TcpStream, client_addr: SocketAddr, context: InboundContext) -> Result<()> {
         debug!("Handling connection from {}", client_addr);
+        let tracked = context.connections.register(client_addr);
+        let result = match context.config.handshake_timeout() {
+            Some(timeout) => {
+                // 连接阶段至少留出拨号超时，其余握手阶段受 handshake_timeout 限制
+                let connect_timeout = timeout.max(context.config.connection_timeout());
+                let limit = |phase| if phase == ConnectionPhase::Connecting { connect_timeout } else { timeout };
+                tokio::select! {
+                    result = Self::serve(client_stream, client_addr, &context, tracked.connection()) => result,
+                    phase = tracked.stuck_before_relay(limit) => {
+                        debug!("Closing connection from {}: handshake timed out in {} phase", client_addr, phase);
+                        Err(ProxyError::HandshakeTimeout(phase))
+                    }
+                }
+            }
+            None => Self::serve(client_stream, client_addr, &context, tracked.connection()).await,
+        };
+        context.access_log.record_connection(&tracked, result.as_ref().err());
+        match result {
+            // 拦截、限速与握手超时是预期结果，已记入统计或访问日志，不作为连接错误上报
+            Err(ProxyError::Blocked(_) | ProxyError::RateLimited(_) | ProxyError::HandshakeTimeout(_)) => Ok(()),
+            // 健康探针和端口扫描连上即断开，不算连接错误
+            Err(ProxyError::Io(e))
+                if e.kind() == std::io::ErrorKind::UnexpectedEof
+                    && tracked.connection().phase() == ConnectionPhase::Greeting =>
+            {
+                debug!("Connection from {} closed before the greeting", client_addr);
+                Ok(())
+            }
+            result => result,
+        }
+    }
 
+    async fn serve(
+        mut client_stream: TcpStream,
+        client_addr: SocketAddr,
+        context: &InboundContext,
+        tracked: &Arc<TrackedConnection>,
+    ) -> Result<()> {
         // Perform SOCKS5 handshake
-        handle_socks5_handshake(&mut client_stream).await?;
+        let server_config = &context.config.server;
+        let offer_userpass = !server_config.user_routing.is_empty() || server_config.allow_client_outbound_selection;
+        let policy = context.auth.policy(client_addr.ip(), offer_userpass);
+        let user = negotiate_socks5_auth_with(&mut client_stream, policy, || tracked.set_phase(ConnectionPhase::Auth)).await?;
+        let user = user.map(|user| {
+            if user.authenticated {
+                tracked.set_authenticated(user.name.as_str());
+            } else {
+                tracked.set_user(user.name.as_str());
+            }
+            user.name
+        });
         debug!("SOCKS5 handshake completed for {}", client_addr);
+        tracked.set_phase(ConnectionPhase::Request);
 
         // Read the SOCKS5 request
-        let mut request_buf = [0u8; 256];
-        let n = client_stream.read(&mut request_buf).await?;
-        let mut request_bytes = bytes::Bytes::from(request_buf[..n].to_vec());
-
-        let request = Socks5Request::from_bytes(&mut request_bytes)?;
+        let mut request = match Socks5Request::read_from(&mut client_stream).await {
+            Ok(request) => request,
+            Err(e) => {
+                reply_request_error(&mut client_stream, &e).await;
+                return Err(e);
+            }
+        };
         debug!("SOCKS5 request: {:?}", request);
+        tracked.set_phase(ConnectionPhase::Connecting);
+        tracked.set_target(format!("{}:{}", request.address, request.port));
 
-        // Connect to the target
-        let target_addr = request.address.to_socket_addr_async(request.port).await?;
+        if uot::is_uot_request(&request.address) {
+            // UoT会话：作为服务端解封装并直接转发数据报
+            let response = Socks5Response::new(0x00, request.address, request.port);
+            client_stream.write_all(&response.to_bytes()?).await?;
+            tracked.set_phase(ConnectionPhase::Relaying);
+            return uot::serve(client_stream).await;
+        }
+        if server_config.answer_probe_requests && is_probe_target(&request.address) {
+            return answer_probe(client_stream, client_addr, tracked).await;
+        }
+        if request.port == 0 {
+            send_failure_reply(&mut client_stream, 0x01).await;
+            return Err(ProxyError::Protocol(format!("Request for port 0 of {}", request.address)));
+        }
+
+        // 应答客户端时用它请求的原目标（假地址，或改写前的目标）
+        let requested = (request.address.clone(), request.port);
+        // 发往假地址的请求换回 DNS 入站记下的域名，按域名路由与解析
+        let requested_ip = match &request.address {
+            Address::Domain(_) => None,
+            Address::V4(ip) => Some(std::net::IpAddr::V4(*ip)),
+            Address::V6(ip, _) => Some(std::net::IpAddr::V6(*ip)),
+        };
+        if let (Some(pool), Some(ip)) = (context.fake_ip(), requested_ip) {
+            match pool.lookup(ip) {
+                Ok(Some(domain)) => {
+                    debug!("Fake IP {} from {} stands for {}", ip, client_addr, domain);
+                    request.address = Address::Domain(domain);
+                    tracked.set_target(format!("{}:{}", request.address, request.port));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    send_failure_reply(&mut client_stream, 0x04).await;
+                    return Err(e);
+                }
+            }
+        }
 
-        debug!("Connecting to target: {}", target_addr);
         // Decide outbound based on domain/ip
-        // 创建一个简单的路由器用于测试
-        let router = HighPerformanceRouter::new("direct".to_string());
-        let outbound_name = match &request.address {
-            crate::protocol::Address::Domain(d) => router.select_outbound_for_domain(d),
-            crate::protocol::Address::V4(ip) => router.select_outbound_for_ip(std::net::IpAddr::V4(*ip)),
-            crate::protocol::Address::V6(ip) => router.select_outbound_for_ip(std::net::IpAddr::V6(*ip)),
-        };
-        let ob_manager = get_global_outbound_manager();
-        let connector = ob_manager.get(&outbound_name).ok_or_else(|| crate::error::ProxyError::Protocol(format!("Outbound not found: {}", outbound_name)))?;
-
-        let target_stream = match connector.connect_outbound(target_addr).await {
-            Ok(stream) => stream,
-            Err(e) => {
-                warn!("Failed to connect to {}: {}", target_addr, e);
-                let response = Socks5Response::new(0x04, request.address.clone(), request.port);
-                let response_bytes = response.to_bytes();
-                let _ = client_stream.write_all(&response_bytes).await;
-                return Err(ProxyError::ConnectionFailed(e.to_string()));
+        let router = context.router();
+        if let Some(profile) = &context.profile {
+            tracked.set_profile(profile.as_str());
+            record_profile_route(profile);
+        }
+        let host = match &request.address {
+            Address::Domain(d) => RouteHost::Domain(d),
+            Address::V4(ip) => RouteHost::Ip(std::net::IpAddr::V4(*ip)),
+            Address::V6(ip, _) => RouteHost::Ip(std::net::IpAddr::V6(*ip)),
+        };
+        let route_context = RouteContext {
+            host,
+            port: Some(request.port),
+            network: Some(Network::Tcp),
+            source: Some(client_addr),
+            inbound: context.tag.as_deref(),
+        };
+        let decision = router.route(&route_context);
+        let ob_manager = context.outbounds();
+        let decision = apply_user_routing(decision, user.as_deref(), &server_config.user_routing, &ob_manager);
+        let selected = client_selected_outbound(user.as_deref(), server_config.allow_client_outbound_selection, &ob_manager);
+        let decision = match selected {
+            Some(outbound) => {
+                info!(
+                    "Client {} selected outbound {}, routing bypassed for {}:{}",
+                    client_addr, outbound, request.address, request.port
+                );
+                RouteDecision {
+                    outbound: outbound.to_string(),
+                    action: RouteAction::Forward,
+                    outbound_chain: Vec::new(),
+                    rule: None,
+                    ..decision
+                }
+            }
+            None => decision,
+        };
+        tracked.set_outbound(decision.outbound.clone());
+        // reject 立即以 0x02 拒绝；reject-drop 不作任何应答，读掉客户端数据直到它放弃
+        if !decision.action.is_forward() {
+            let target = request.address.to_string();
+            get_global_blocked_traffic().record(&target, decision.rule);
+            match decision.action {
+                RouteAction::Drop => drop_silently(&mut client_stream, context.config).await,
+                _ => send_failure_reply(&mut client_stream, 0x02).await,
             }
+            return Err(ProxyError::Blocked(target));
+        }
+        // 改写目标：出站仍按原目标的路由结果，改写后不再重新路由
+        if let Some(rewrite) = &decision.rewrite_to {
+            let (address, port) = match rewrite.apply(&request.address, request.port) {
+                Ok(target) => target,
+                Err(e) => {
+                    send_failure_reply(&mut client_stream, e.socks5_reply_code()).await;
+                    return Err(e);
+                }
+            };
+            debug!("Rewrote {}:{} to {}:{} for client {}", request.address, request.port, address, port, client_addr);
+            tracked.set_rewritten_to(format!("{}:{}", address, port));
+            request.address = address;
+            request.port = port;
+        }
+        // 代理链在连接时由各跳组合而成；套接字选项取实际拨号的第一跳
+        let dial_outbound = decision.outbound_chain.first().unwrap_or(&decision.outbound).clone();
+        let connector = if decision.outbound_chain.is_empty() {
+            let connector = ob_manager.get(&decision.outbound).ok_or_else(|| crate::error::ProxyError::Protocol(format!("Outbound not found: {}", decision.outbound)))?;
+            if let Some(disabled) = ob_manager.disabled(&decision.outbound) {
+                let e = disabled.reject();
+                send_failure_reply(&mut client_stream, e.socks5_reply_code()).await;
+                return Err(e);
+            }
+            connector
+        } else {
+            match ob_manager.chain(&decision.outbound_chain) {
+                Ok(connector) => connector,
+                Err(e) => {
+                    send_failure_reply(&mut client_stream, e.socks5_reply_code()).await;
+                    return Err(e);
+                }
+            }
+        };
+        // 黑洞出站：不解析也不拨号，直接拒绝并计入拦截统计（dry-run 同样拒绝）
+        if connector.capabilities().blocks {
+            let target = request.address.to_string();
+            get_global_blocked_traffic().record(&target, decision.rule);
+            send_failure_reply(&mut client_stream, 0x02).await;
+            return Err(ProxyError::Blocked(target));
+        }
+        // 规则上的 DSCP 优先于出站配置
+        let dial_options = DialOptions {
+            dscp: decision.dscp.or_else(|| ob_manager.dscp(&dial_outbound)),
+            so_mark: ob_manager.routing_mark(&dial_outbound),
+            tcp_mss: ob_manager.tcp_mss(&dial_outbound),
+            ..DialOptions::default()
         };
+        // 先路由后解析：按选中出站的出口子网做 ECS 解析
+        let client_subnet = ob_manager.egress_hint_subnet(&dial_outbound);
+        let mut diagnostics = ConnectDiagnostics::start();
+        diagnostics.route(decision.rule, decision.outbound);
 
-        info!("Connected to target {} for client {}", target_addr, client_addr);
+        // 能把域名交给服务器的出站不在本地解析，由上游解析；直连等自己拨号的出站才解析
+        let target_addrs: Vec<TargetAddr> = if matches!(request.address, Address::Domain(_)) && connector.capabilities().accepts_domain_targets {
+            if let Err(e) = context.listeners.check_outbound_server(connector.server_addr()) {
+                send_failure_reply(&mut client_stream, e.socks5_reply_code()).await;
+                return Err(e);
+            }
+            vec![TargetAddr::new(request.address.clone(), request.port)]
+        } else {
+            let resolved = match resolve_target(&request.address, request.port, client_subnet, &mut diagnostics).await {
+                Ok(addrs) => addrs,
+                Err(e) => {
+                    warn!("Failed to resolve {}: {}", request.address, e);
+                    send_failure_reply(&mut client_stream, e.socks5_reply_code()).await;
+                    return Err(e);
+                }
+            };
+            for target_addr in &resolved {
+                if let Err(e) = context.listeners.check_loop(*target_addr, connector.server_addr()) {
+                    send_failure_reply(&mut client_stream, e.socks5_reply_code()).await;
+                    return Err(e);
+                }
+            }
+            resolved.into_iter().map(TargetAddr::from).collect()
+        };
+
+        // dry-run：路由与解析照常进行，但不拨号
+        if server_config.dry_run {
+            let would_dial = &target_addrs[0];
+            tracked.set_dry_run(would_dial.clone());
+            record_dry_run(&diagnostics.outbound);
+            info!(
+                "Dry run: {}:{} for client {} would be dialed to {} via outbound {}",
+                request.address, request.port, client_addr, would_dial, diagnostics.outbound
+            );
+            send_failure_reply(&mut client_stream, server_config.dry_run_reply).await;
+            return Ok(());
+        }
+
+        // 开启复用时先取池中到同一出站和目标的空闲连接；代理链和不可复用的出站每次新建
+        let pool = context.connection_pool().filter(|_| {
+            context.config.connection_pool.reuse_outbound_connections
+                && decision.outbound_chain.is_empty()
+                && connector.capabilities().poolable
+        });
+        let pool_outbound = ob_manager.selected(&dial_outbound).unwrap_or(&dial_outbound).to_string();
+        let requested_at = std::time::Instant::now();
+        let pooled = match pool {
+            Some(pool) => pool.checkout_any(&pool_outbound, &target_addrs).await,
+            None => None,
+        };
+        let reused = pooled.is_some();
+        let (target_stream, target_addr) = match pooled {
+            Some(connection) => {
+                let target_addr = connection.key().target.clone();
+                debug!("Reusing pooled connection to {} via {}", target_addr, pool_outbound);
+                (connection.into_stream(), target_addr)
+            }
+            None => {
+                // 新建连接限速：全局一个桶，出站按实际拨号的出站或其选中的组成员计
+                let rate_outbounds: Vec<&str> = [Some(dial_outbound.as_str()), ob_manager.selected(&dial_outbound)].into_iter().flatten().collect();
+                if let Err(e) = get_global_connection_rate_limiter().acquire(&rate_outbounds).await {
+                    debug!("Refused {}:{} for client {}: {}", request.address, request.port, client_addr, e);
+                    send_failure_reply(&mut client_stream, e.socks5_reply_code()).await;
+                    return Err(e);
+                }
+
+                debug!("Connecting to target: {}:{}", request.address, request.port);
+                let attempt_timeout = context.config.connection_timeout();
+                let target_stream =
+                    match connect_addresses(connector.as_ref(), &target_addrs, &dial_options, attempt_timeout, &mut diagnostics).await {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!("Failed to connect to {}:{}: {}", request.address, request.port, e);
+                            send_failure_reply(&mut client_stream, e.socks5_reply_code()).await;
+                            return Err(e);
+                        }
+                    };
+                debug!("Connected to {}:{}: {}", request.address, request.port, diagnostics);
+                let target_addr = diagnostics.connected.map_or_else(|| target_addrs[0].clone(), |attempt| attempt.addr);
+                (target_stream, target_addr)
+            }
+        };
+        tracked.mark_connected();
+        let outbound_name = diagnostics.outbound;
+
+        match &user {
+            Some(user) => info!(
+                "Connected to target {} for client {} (user {}, outbound {})",
+                target_addr, client_addr, LogSafe(user), outbound_name
+            ),
+            None => info!("Connected to target {} for client {}", target_addr, client_addr),
+        }
+
+        let performance = &context.config.performance;
+        let user_timeout = ob_manager
+            .tcp_user_timeout(&dial_outbound)
+            .or(performance.tcp_user_timeout_secs.map(std::time::Duration::from_secs));
+        if let Some(timeout) = user_timeout {
+            // 出站协议的封装之下仍是同一个套接字
+            if let Some(Err(e)) = target_stream.tcp_stream().map(|socket| set_tcp_user_timeout(socket, timeout)) {
+                warn!("Failed to set TCP_USER_TIMEOUT for {}: {}", target_addr, e);
+            }
+        }
+
+        let capture = get_global_capture().and_then(|manager| {
+            manager.begin(CaptureMeta {
+                client: client_addr,
+                target: format!("{}:{}", request.address, request.port),
+                domain: match &request.address {
+                    Address::Domain(domain) => Some(domain.clone()),
+                    _ => None,
+                },
+                outbound: outbound_name.clone(),
+                rule: diagnostics.rule,
+            })
+        });
 
         // Send success response
-        let response = Socks5Response::new(0x00, request.address, request.port);
-        let response_bytes = response.to_bytes();
+        let response = Socks5Response::new(0x00, requested.0, requested.1);
+        let response_bytes = response.to_bytes()?;
         client_stream.write_all(&response_bytes).await?;
 
         // Start zero-copy relay
-        let relay = ZeroCopyRelay::new(client_stream, target_stream);
-        relay.start().await?;
-
-        info!("Connection from {} completed", client_addr);
+        // 规则或出站任一开启即使用低延迟模式
+        let relay_options = RelayOptions {
+            tls_fragment: ob_manager.tls_fragment(&dial_outbound),
+            latency_mode: decision.latency_mode || ob_manager.latency_mode(&dial_outbound),
+            ..RelayOptions::from_config(performance)
+        };
+        for (stream, linger) in [(Some(&client_stream), context.linger), (target_stream.tcp_stream(), ob_manager.linger(&dial_outbound))] {
+            let Some(stream) = stream else { continue };
+            if let Err(e) = apply_linger(stream, linger) {
+                warn!("Failed to set SO_LINGER {:?} for {}: {}", linger, client_addr, e);
+            }
+        }
+        tracked.set_phase(ConnectionPhase::Relaying);
+        let relay = ZeroCopyRelay::with_options(client_stream, target_stream, relay_options)
+            .with_tracker(tracked.clone())
+            .with_capture(capture);
+        let result = match pool {
+            Some(pool) => {
+                let (result, target_stream) = relay.start_reusable().await?;
+                let key = PoolKey::new(pool_outbound, target_addr);
+                // 转发中的首个来回即首次交互：目标有回应为成功；两端都没发过数据则不计
+                if tracked.total_bytes() > 0 {
+                    let answered_at = tracked.first_download_at().map(|at| at.into_std());
+                    pool.record_relayed_exchange(&key, reused, requested_at, answered_at);
+                }
+                if let Some(target_stream) = target_stream {
+                    pool.return_connection(PooledConnection::new(target_stream, key)).await;
+                }
+                result
+            }
+            None => relay.start().await?,
+        };
+        match &result {
+            RelayResult::Completed => info!("Connection from {} completed", client_addr),
+            RelayResult::PeerAborted(reason) => info!("Connection from {} aborted by peer: {}", client_addr, reason),
+            RelayResult::Aborted(reason) => info!("Connection from {} aborted: {}", client_addr, reason),
+            RelayResult::IdleTimeout(limit) => info!("Connection from {} closed: idle for {:?}", client_addr, limit),
+        }
+        tracked.set_relay_result(result);
         Ok(())
     }
 }
 
-/// Create a TCP connection with traffic marking applied
-async fn create_marked_connection(target_addr: SocketAddr) -> Result<TcpStream> {
-    // Check if traffic marking is configured
-    if let Some(traffic_config) = get_global_traffic_mark_config() {
-        // Check if any marking is enabled
-        if traffic_config.so_mark.is_some() || traffic_config.net_service_type.is_some() {
-            debug!("Creating marked connection to {}", target_addr);
-            return create_marked_tcp_stream(target_addr, traffic_config).await;
-        }
+/// How long an answered probe connection waits for the client to close it
+const PROBE_HOLD: Duration = Duration::from_secs(5);
+/// How long a client refused at `server.max_connections` has to send its
+/// greeting and request
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Probe requests answered without dialing
+static PROBE_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+/// Whether a request targets the unspecified address, as GUI clients' startup probes do
+fn is_probe_target(address: &Address) -> bool {
+    match address {
+        Address::V4(ip) => ip.is_unspecified(),
+        Address::V6(ip, _) => ip.is_unspecified(),
+        Address::Domain(_) => false,
     }
+}
+
+/// Answer a client probe with success and BND 0.0.0.0:0, then wait for the client to close
+async fn answer_probe(mut stream: TcpStream, client_addr: SocketAddr, tracked: &TrackedConnection) -> Result<()> {
+    tracked.set_probe();
+    PROBE_REQUESTS.fetch_add(1, Ordering::Relaxed);
+    let response = Socks5Response::new(0x00, Address::V4(std::net::Ipv4Addr::UNSPECIFIED), 0);
+    stream.write_all(&response.to_bytes()?).await?;
+    // 不再受握手超时约束，最多保持 PROBE_HOLD
+    tracked.set_phase(ConnectionPhase::Relaying);
+    debug!("Answered probe request from {}", client_addr);
+    let mut buf = [0u8; 512];
+    let drain = async {
+        while let Ok(1..) = stream.read(&mut buf).await {}
+    };
+    let _ = tokio::time::timeout(PROBE_HOLD, drain).await;
+    Ok(())
+}
+
+/// Probe requests answered without dialing since start
+pub fn probe_requests() -> u64 {
+    PROBE_REQUESTS.load(Ordering::Relaxed)
+}
+
+/// Connection counters by name
+type NamedCounters = OnceLock<Mutex<HashMap<String, u64>>>;
+
+/// Dry-run decisions per outbound
+static DRY_RUN_DECISIONS: NamedCounters = OnceLock::new();
+/// Connections routed per profile
+static PROFILE_ROUTES: NamedCounters = OnceLock::new();
+
+fn bump(counters: &NamedCounters, name: &str) {
+    let mut counters = counters.get_or_init(Default::default).lock().unwrap();
+    *counters.entry(name.to_string()).or_default() += 1;
+}
+
+fn sorted_counts(counters: &NamedCounters) -> Vec<(String, u64)> {
+    let Some(counters) = counters.get() else {
+        return Vec::new();
+    };
+    let mut stats: Vec<_> = counters.lock().unwrap().iter().map(|(name, count)| (name.clone(), *count)).collect();
+    stats.sort();
+    stats
+}
+
+fn record_dry_run(outbound: &str) {
+    bump(&DRY_RUN_DECISIONS, outbound);
+}
+
+/// Connections answered in dry-run mode, per outbound they would have used, sorted by outbound
+pub fn dry_run_stats() -> Vec<(String, u64)> {
+    sorted_counts(&DRY_RUN_DECISIONS)
+}
 
-    // Fall back to regular connection if no marking is configured
-    debug!("Creating regular connection to {}", target_addr);
-    TcpStream::connect(target_addr).await
-        .map_err(|e| ProxyError::ConnectionFailed(e.to_string()))
+fn record_profile_route(profile: &str) {
+    bump(&PROFILE_ROUTES, profile);
+}
+
+/// Connections routed with a named profile, per profile, sorted by profile
+pub fn profile_route_stats() -> Vec<(String, u64)> {
+    sorted_counts(&PROFILE_ROUTES)
+}
+
+/// Let the SOCKS5 username pick the outbound on top of the router's decision
+///
+/// Destinations the router blocks stay blocked; users without a mapping keep
+/// the router's choice.
+fn apply_user_routing(
+    decision: RouteDecision,
+    user: Option<&str>,
+    user_routing: &HashMap<String, String>,
+    outbounds: &OutboundManager,
+) -> RouteDecision {
+    let Some(outbound) = user.and_then(|user| user_routing.get(user)) else {
+        return decision;
+    };
+    let blocked = !decision.action.is_forward()
+        || outbounds.get(&decision.outbound).is_some_and(|c| c.capabilities().blocks);
+    if blocked {
+        return decision;
+    }
+    RouteDecision {
+        outbound: outbound.clone(),
+        action: RouteAction::Forward,
+        outbound_chain: Vec::new(),
+        rule: None,
+        dscp: decision.dscp,
+        latency_mode: decision.latency_mode,
+        rewrite_to: decision.rewrite_to,
+    }
+}
+
+/// `outbound=<name>` token in a SOCKS5 username, e.g. `outbound=proxy-b` or `alice;outbound=proxy-b`
+fn requested_outbound(user: &str) -> Option<&str> {
+    user.split([',', ';'])
+        .find_map(|token| token.trim().strip_prefix("outbound="))
+        .filter(|name| !name.is_empty())
+}
+
+/// Outbound the client forces through its username, bypassing routing
+///
+/// Only honoured when `allow_client_outbound_selection` is on and the outbound
+/// exists; otherwise the routed decision stands.
+fn client_selected_outbound<'a>(user: Option<&'a str>, allowed: bool, outbounds: &OutboundManager) -> Option<&'a str> {
+    let requested = user.and_then(requested_outbound)?;
+    if !allowed {
+        debug!("Ignoring outbound selection {}: allow_client_outbound_selection is off", LogSafe(requested));
+        return None;
+    }
+    if !outbounds.contains(requested) {
+        debug!("Ignoring outbound selection {}: no such outbound", LogSafe(requested));
+        return None;
+    }
+    Some(requested)
+}
+
+/// Report a request that could not be read, unless the client already went away
+async fn reply_request_error(stream: &mut TcpStream, error: &ProxyError) {
+    if !matches!(error, ProxyError::Io(_)) {
+        send_failure_reply(stream, error.socks5_reply_code()).await;
+    }
+}
+
+/// Best-effort SOCKS5 failure reply for a request that could not be parsed
+async fn send_failure_reply(stream: &mut TcpStream, code: u8) {
+    let response = Socks5Response::new(code, Address::V4(std::net::Ipv4Addr::UNSPECIFIED), 0);
+    if let Ok(bytes) = response.to_bytes() {
+        let _ = stream.write_all(&bytes).await;
+    }
 }
 
 /// Connection handler for individual client connections
@@ -140,6 +608,11 @@ impl ConnectionHandler {
         let request = self.read_socks5_request().await?;
         debug!("SOCKS5 request: {:?}", request);
 
+        if uot::is_uot_request(&request.address) {
+            self.send_success_response(&request).await?;
+            return uot::serve(self.client_stream).await;
+        }
+
         // Connect to target
         let target_stream = self.connect_to_target(&request).await?;
 
@@ -153,29 +626,1166 @@ impl ConnectionHandler {
     }
 
     async fn read_socks5_request(&mut self) -> Result<Socks5Request> {
-        let mut request_buf = [0u8; 256];
-        let n = self.client_stream.read(&mut request_buf).await?;
-        let mut request_bytes = bytes::Bytes::from(request_buf[..n].to_vec());
-
-        Socks5Request::from_bytes(&mut request_bytes)
+        let request = Socks5Request::read_from(&mut self.client_stream).await;
+        if let Err(e) = &request {
+            reply_request_error(&mut self.client_stream, e).await;
+        }
+        request
     }
 
-    async fn connect_to_target(&self, request: &Socks5Request) -> Result<TcpStream> {
+    async fn connect_to_target(&mut self, request: &Socks5Request) -> Result<TcpStream> {
         let target_addr = request.address.to_socket_addr_async(request.port).await?;
+        if let Err(e) = get_global_listener_registry().check_loop(target_addr, None) {
+            send_failure_reply(&mut self.client_stream, e.socks5_reply_code()).await;
+            return Err(e);
+        }
 
         debug!("Connecting to target: {}", target_addr);
-        create_marked_connection(target_addr).await
+        // 全局 traffic_mark 在 dial_tcp 里应用；没有全局配置时按默认的连接超时
+        let connect_timeout = try_get_global_config().map_or_else(|_| Config::default().connection_timeout(), Config::connection_timeout);
+        dial_tcp(target_addr, &DialOptions { connect_timeout: Some(connect_timeout), ..DialOptions::default() })
+            .await
+            .map_err(|e| ProxyError::ConnectionFailed(e.to_string()))
     }
 
     async fn send_success_response(&mut self, request: &Socks5Request) -> Result<()> {
         let response = Socks5Response::new(0x00, request.address.clone(), request.port);
-        let response_bytes = response.to_bytes();
+        let response_bytes = response.to_bytes()?;
         self.client_stream.write_all(&response_bytes).await?;
         Ok(())
     }
 
     async fn start_relay(self, target_stream: TcpStream) -> Result<()> {
         let relay = ZeroCopyRelay::new(self.client_stream, target_stream);
-        relay.start().await
+        relay.start().await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RewriteTarget;
+    use crate::connection_pool::ConnectionPool;
+    use crate::protocols::chain::tests::recording_socks_server;
+    use std::sync::Arc;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_connect_to_own_port_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        get_global_listener_registry().register(proxy_addr);
+        tokio::spawn(async move {
+            let (stream, client_addr) = listener.accept().await.unwrap();
+            let _ = ConnectionHandler::new(stream, client_addr).handle().await;
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [0x05, 0x00]);
+
+        let mut request = vec![0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+        request.extend_from_slice(&proxy_addr.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x02]);
+    }
+
+    /// Outbound that ignores the target and connects to a fixed upstream
+    struct MockOutbound {
+        upstream: SocketAddr,
+    }
+
+    #[async_trait::async_trait]
+    impl crate::protocols::Protocol for MockOutbound {
+        fn name(&self) -> &str {
+            "mock"
+        }
+
+        async fn connect_outbound(&self, _target: &TargetAddr) -> Result<crate::stream::ProxyStream> {
+            Ok(TcpStream::connect(self.upstream).await?.into())
+        }
+
+        async fn start_inbound(&self, _bind_addr: SocketAddr, _ctx: InboundContext) -> Result<RunningInbound> {
+            unimplemented!()
+        }
+    }
+
+    /// Outbound that counts connect attempts and fails them
+    struct CountingOutbound {
+        connects: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl crate::protocols::Protocol for CountingOutbound {
+        fn name(&self) -> &str {
+            "counting"
+        }
+
+        async fn connect_outbound(&self, _target: &TargetAddr) -> Result<crate::stream::ProxyStream> {
+            self.connects.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Err(ProxyError::ConnectionFailed("counting outbound".to_string()))
+        }
+
+        async fn start_inbound(&self, _bind_addr: SocketAddr, _ctx: InboundContext) -> Result<RunningInbound> {
+            unimplemented!()
+        }
+    }
+
+    /// Filtering outbound that advertises refusing every connection, counting any connect attempt
+    struct BlockingOutbound {
+        connects: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl crate::protocols::Protocol for BlockingOutbound {
+        fn name(&self) -> &str {
+            "filter"
+        }
+
+        fn capabilities(&self) -> crate::protocols::OutboundCapabilities {
+            crate::protocols::OutboundCapabilities { blocks: true, ..Default::default() }
+        }
+
+        async fn connect_outbound(&self, _target: &TargetAddr) -> Result<crate::stream::ProxyStream> {
+            self.connects.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Err(ProxyError::ConnectionFailed("filter outbound".to_string()))
+        }
+
+        async fn start_inbound(&self, _bind_addr: SocketAddr, _ctx: InboundContext) -> Result<RunningInbound> {
+            unimplemented!()
+        }
+    }
+
+    /// Upstream that greets every connection with `tag`
+    async fn tagged_upstream(tag: &'static [u8]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = stream.write_all(tag).await;
+            }
+        });
+        addr
+    }
+
+    /// SOCKS5 CONNECT through `proxy` as `user`, returning the upstream's greeting
+    async fn connect_as(proxy: SocketAddr, user: &str) -> [u8; 2] {
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(&[0x05, 0x02, 0x00, 0x02]).await.unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x02]);
+
+        let mut auth = vec![0x01, user.len() as u8];
+        auth.extend_from_slice(user.as_bytes());
+        auth.extend_from_slice(&[0x01, b'-']);
+        client.write_all(&auth).await.unwrap();
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x01, 0x00]);
+
+        client.write_all(&[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0, 80]).await.unwrap();
+        let mut response = [0u8; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], 0x00);
+        let mut tag = [0u8; 2];
+        client.read_exact(&mut tag).await.unwrap();
+        tag
+    }
+
+    /// Serve SOCKS5 on an ephemeral port with the given config and outbounds
+    async fn spawn_proxy(config: Config, outbounds: OutboundManager) -> SocketAddr {
+        let context = InboundContext::new(Box::leak(Box::new(config)), Arc::new(outbounds));
+        let running = Socks5Proxy::new("127.0.0.1:0".parse().unwrap()).bind(context).await.unwrap();
+        running.local_addr()
+    }
+
+    #[tokio::test]
+    async fn test_username_selects_outbound() {
+        let mut config = Config::default();
+        config.server.user_routing.insert("us-node".to_string(), "us".to_string());
+        config.server.user_routing.insert("jp-node".to_string(), "jp".to_string());
+        let mut outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        outbounds.insert("us", Arc::new(MockOutbound { upstream: tagged_upstream(b"us").await }));
+        outbounds.insert("jp", Arc::new(MockOutbound { upstream: tagged_upstream(b"jp").await }));
+        // 默认路由走 direct，这里让它也指向一个上游以区分
+        outbounds.insert("direct", Arc::new(MockOutbound { upstream: tagged_upstream(b"dr").await }));
+        let proxy_addr = spawn_proxy(config, outbounds).await;
+
+        assert_eq!(&connect_as(proxy_addr, "jp-node").await, b"jp");
+        assert_eq!(&connect_as(proxy_addr, "us-node").await, b"us");
+        assert_eq!(&connect_as(proxy_addr, "jp-node").await, b"jp");
+        assert_eq!(&connect_as(proxy_addr, "someone").await, b"dr");
+    }
+
+    #[test]
+    fn test_user_routing_keeps_blocked_destinations() {
+        let outbounds = OutboundManager::from_configs(&[]).unwrap();
+        let user_routing = HashMap::from([("us-node".to_string(), "direct".to_string())]);
+        let decision = |outbound: &str| RouteDecision { outbound: outbound.to_string(), action: RouteAction::Forward, outbound_chain: Vec::new(), rule: Some(0), dscp: None, latency_mode: false, rewrite_to: None };
+
+        let blocked = apply_user_routing(decision("block"), Some("us-node"), &user_routing, &outbounds);
+        assert_eq!(blocked.outbound, "block");
+        let routed = apply_user_routing(decision("proxy"), Some("us-node"), &user_routing, &outbounds);
+        assert_eq!((routed.outbound.as_str(), routed.rule), ("direct", None));
+
+        // 按能力而不是协议名判断拦截
+        let mut outbounds = outbounds;
+        outbounds.insert("filter", Arc::new(BlockingOutbound { connects: Default::default() }));
+        let filtered = apply_user_routing(decision("filter"), Some("us-node"), &user_routing, &outbounds);
+        assert_eq!(filtered.outbound, "filter");
+    }
+
+    #[tokio::test]
+    async fn test_blocking_capability_refuses_without_dialing() {
+        let config = Config::default();
+        let connects = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        // 默认路由走 direct
+        outbounds.insert("direct", Arc::new(BlockingOutbound { connects: connects.clone() }));
+        let proxy_addr = spawn_proxy(config, outbounds).await;
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0, 80]).await.unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x02]);
+        assert_eq!(connects.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_relays_through_encrypted_outbound_stream() {
+        use crate::protocol::AddressFormat;
+        use crate::protocols::{ShadowsocksCipher, ShadowsocksProtocol, ShadowsocksStream};
+
+        let echo = crate::loadgen::spawn_echo_server().await.unwrap();
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = server.accept().await.unwrap();
+            let cipher = ShadowsocksCipher::Aes256Gcm;
+            let mut stream = ShadowsocksStream::new(stream, cipher, cipher.derive_key("secret").into()).unwrap();
+            let (address, port) = Address::read_from(&mut stream, AddressFormat::SHADOWSOCKS).await.unwrap();
+            let mut upstream = TcpStream::connect(address.to_socket_addr(port).unwrap()).await.unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+        });
+
+        let config = Config::default();
+        let mut outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        let server = crate::endpoint::ServerEndpoint::single(server_addr);
+        outbounds.insert("direct", Arc::new(ShadowsocksProtocol::new(server, "aes-256-gcm", "secret").unwrap()));
+        let proxy_addr = spawn_proxy(config, outbounds).await;
+
+        let (mut client, method) = negotiate(proxy_addr, &[0x00]).await;
+        assert_eq!(method, 0x00);
+        connect_echo(&mut client, echo).await;
+    }
+
+    #[tokio::test]
+    async fn test_domain_target_passed_to_proxy_outbound() {
+        use crate::protocols::Socks5Protocol;
+
+        // 上游SOCKS5服务器：记录收到的目标，应答成功后回显
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let (seen_tx, mut seen) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[0x05, 0x00]).await.unwrap();
+            let request = Socks5Request::read_from(&mut stream).await.unwrap();
+            seen_tx.send((request.address, request.port)).unwrap();
+            stream.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await.unwrap();
+            let mut buf = [0u8; 2];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        let config = Config::default();
+        let mut outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        outbounds.insert("direct", Arc::new(Socks5Protocol::with_server(upstream_addr)));
+        let proxy_addr = spawn_proxy(config, outbounds).await;
+
+        // 本地没有DNS解析器，域名若在本地解析连接就会失败
+        let (mut client, _) = negotiate(proxy_addr, &[0x00]).await;
+        let domain = b"example.invalid";
+        let mut request = vec![0x05, 0x01, 0x00, 0x03, domain.len() as u8];
+        request.extend_from_slice(domain);
+        request.extend_from_slice(&443u16.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        // 应答带回客户端请求的域名
+        let reply = Socks5Response::read_from(&mut client).await.unwrap();
+        assert_eq!(reply.status, 0x00);
+        assert_eq!(seen.recv().await, Some((Address::Domain("example.invalid".to_string()), 443)));
+
+        let mut echoed = [0u8; 2];
+        client.write_all(b"hi").await.unwrap();
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"hi");
+    }
+
+    /// Outbounds "a" and "b" plus a "direct" that all answer with their own tag
+    async fn tagged_outbounds(config: &Config) -> OutboundManager {
+        let mut outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        outbounds.insert("a", Arc::new(MockOutbound { upstream: tagged_upstream(b"aa").await }));
+        outbounds.insert("b", Arc::new(MockOutbound { upstream: tagged_upstream(b"bb").await }));
+        outbounds.insert("direct", Arc::new(MockOutbound { upstream: tagged_upstream(b"dr").await }));
+        outbounds
+    }
+
+    #[tokio::test]
+    async fn test_client_selects_outbound() {
+        let mut config = Config::default();
+        config.server.allow_client_outbound_selection = true;
+        config.server.user_routing.insert("alice".to_string(), "a".to_string());
+        let outbounds = tagged_outbounds(&config).await;
+        let proxy_addr = spawn_proxy(config, outbounds).await;
+
+        assert_eq!(&connect_as(proxy_addr, "outbound=b").await, b"bb");
+        assert_eq!(&connect_as(proxy_addr, "outbound=a").await, b"aa");
+        // 显式选择优先于按用户名路由
+        assert_eq!(&connect_as(proxy_addr, "alice").await, b"aa");
+        assert_eq!(&connect_as(proxy_addr, "alice;outbound=b").await, b"bb");
+        // 不存在的出站回落到正常路由
+        assert_eq!(&connect_as(proxy_addr, "outbound=missing").await, b"dr");
+    }
+
+    #[tokio::test]
+    async fn test_client_selection_ignored_when_disabled() {
+        let mut config = Config::default();
+        config.server.user_routing.insert("alice".to_string(), "a".to_string());
+        let outbounds = tagged_outbounds(&config).await;
+        let proxy_addr = spawn_proxy(config, outbounds).await;
+
+        assert_eq!(&connect_as(proxy_addr, "outbound=b").await, b"dr");
+        assert_eq!(&connect_as(proxy_addr, "alice;outbound=b").await, b"dr");
+    }
+
+    #[test]
+    fn test_requested_outbound_token() {
+        assert_eq!(requested_outbound("outbound=proxy-b"), Some("proxy-b"));
+        assert_eq!(requested_outbound("alice, outbound=jp"), Some("jp"));
+        assert_eq!(requested_outbound("alice"), None);
+        assert_eq!(requested_outbound("outbound="), None);
+    }
+
+    #[tokio::test]
+    async fn test_inbound_shutdown_drains_open_connections() {
+        use crate::inbound::{Inbound, ProtocolInbound};
+        use crate::protocols::Socks5Protocol;
+
+        let config = Config::default();
+        let outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        let context = InboundContext::new(Box::leak(Box::new(config)), Arc::new(outbounds));
+        let inbound = ProtocolInbound::new(Box::new(Socks5Protocol::new()), "127.0.0.1:0".parse().unwrap());
+        let running = inbound.start(context).await.unwrap();
+        let proxy_addr = running.local_addr();
+        assert_ne!(proxy_addr.port(), 0);
+
+        let SocketAddr::V4(echo) = crate::loadgen::spawn_echo_server().await.unwrap() else { unreachable!() };
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [0x05, 0x00]);
+        let mut request = vec![0x05, 0x01, 0x00, 0x01];
+        request.extend_from_slice(&echo.ip().octets());
+        request.extend_from_slice(&echo.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0x00);
+
+        let mut echoed = [0u8; 4];
+        client.write_all(b"ping").await.unwrap();
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+
+        running.shutdown().await;
+        assert!(TcpStream::connect(proxy_addr).await.is_err());
+        assert!(!get_global_listener_registry().bound().contains(&proxy_addr));
+
+        // 已建立的连接继续转发，关闭后 inbound 才结束
+        client.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"pong");
+        assert!(!running.is_finished());
+
+        drop(client);
+        tokio::time::timeout(std::time::Duration::from_secs(5), running.join())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    /// SOCKS5 inbound on an ephemeral port requiring alice's password except
+    /// from `exempt`
+    async fn spawn_auth_proxy(exempt: &str) -> (SocketAddr, InboundContext) {
+        let mut config = Config::default();
+        config.server.auth = crate::config::SocksAuthConfig {
+            mode: crate::config::SocksAuthMode::Password,
+            users: HashMap::from([("alice".to_string(), "secret".to_string())]),
+            no_auth_source_cidrs: vec![exempt.to_string()],
+        };
+        let outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        let context = InboundContext::new(Box::leak(Box::new(config)), Arc::new(outbounds));
+        let running = Socks5Proxy::new("127.0.0.1:0".parse().unwrap()).bind(context.clone()).await.unwrap();
+        (running.local_addr(), context)
+    }
+
+    /// CONNECT to `target` on an already negotiated client, expecting success
+    async fn connect_echo(client: &mut TcpStream, target: SocketAddr) {
+        let SocketAddr::V4(target) = target else { unreachable!() };
+        let mut request = vec![0x05, 0x01, 0x00, 0x01];
+        request.extend_from_slice(&target.ip().octets());
+        request.extend_from_slice(&target.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0x00);
+        let mut echoed = [0u8; 2];
+        client.write_all(b"hi").await.unwrap();
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"hi");
+    }
+
+    /// Offer `methods` and return the method the server picked
+    async fn negotiate(proxy: SocketAddr, methods: &[u8]) -> (TcpStream, u8) {
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        let mut greeting = vec![0x05, methods.len() as u8];
+        greeting.extend_from_slice(methods);
+        client.write_all(&greeting).await.unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        (client, reply[1])
+    }
+
+    async fn send_userpass(client: &mut TcpStream, user: &str, password: &str) -> u8 {
+        let mut auth = vec![0x01, user.len() as u8];
+        auth.extend_from_slice(user.as_bytes());
+        auth.push(password.len() as u8);
+        auth.extend_from_slice(password.as_bytes());
+        client.write_all(&auth).await.unwrap();
+        let mut status = [0u8; 2];
+        client.read_exact(&mut status).await.unwrap();
+        status[1]
+    }
+
+    #[tokio::test]
+    async fn test_exempt_source_skips_auth() {
+        let echo = crate::loadgen::spawn_echo_server().await.unwrap();
+        let (proxy_addr, _) = spawn_auth_proxy("127.0.0.0/8").await;
+
+        let (mut client, method) = negotiate(proxy_addr, &[0x00, 0x02]).await;
+        assert_eq!(method, 0x00);
+        connect_echo(&mut client, echo).await;
+    }
+
+    #[tokio::test]
+    async fn test_non_exempt_source_must_authenticate() {
+        let echo = crate::loadgen::spawn_echo_server().await.unwrap();
+        let (proxy_addr, context) = spawn_auth_proxy("192.168.0.0/16").await;
+
+        // 只提供无认证方法的客户端被拒绝
+        assert_eq!(negotiate(proxy_addr, &[0x00]).await.1, 0xFF);
+
+        let (mut client, method) = negotiate(proxy_addr, &[0x00, 0x02]).await;
+        assert_eq!(method, 0x02);
+        assert_eq!(send_userpass(&mut client, "alice", "wrong").await, 0x01);
+        let mut rest = Vec::new();
+        assert_eq!(client.read_to_end(&mut rest).await.unwrap(), 0);
+
+        let (mut client, _) = negotiate(proxy_addr, &[0x02]).await;
+        assert_eq!(send_userpass(&mut client, "alice", "secret").await, 0x00);
+        connect_echo(&mut client, echo).await;
+        let local = client.local_addr().unwrap();
+        let tracked = context.connections.connections().into_iter().find(|c| c.snapshot().client == local).unwrap();
+        let snapshot = tracked.snapshot();
+        assert!(snapshot.authenticated);
+        assert_eq!(snapshot.user.as_deref(), Some("alice"));
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_never_dials() {
+        let mut config = Config::default();
+        config.server.dry_run = true;
+        config.server.dry_run_reply = 0x05;
+        let connects = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        // 默认路由走 direct
+        outbounds.insert("direct", Arc::new(CountingOutbound { connects: connects.clone() }));
+        let access_config = crate::config::AccessLogConfig { enabled: true, ..Default::default() };
+        let (access_log, mut records) = crate::access_log::AccessLogger::channel(&access_config);
+
+        let config: &'static Config = Box::leak(Box::new(config));
+        let context = InboundContext {
+            access_log: Box::leak(Box::new(access_log)),
+            ..InboundContext::new(config, Arc::new(outbounds))
+        };
+        let running = Socks5Proxy::new("127.0.0.1:0".parse().unwrap()).bind(context).await.unwrap();
+
+        let mut client = TcpStream::connect(running.local_addr()).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0, 80]).await.unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x05]);
+
+        let record = records.recv().await.unwrap();
+        assert_eq!(connects.load(std::sync::atomic::Ordering::SeqCst), 0);
+        assert_eq!(record.outbound.as_deref(), Some("direct"));
+        assert_eq!(record.target.as_deref(), Some("192.0.2.1:80"));
+        assert_eq!(record.dry_run, Some("192.0.2.1:80".parse().unwrap()));
+        assert!(record.error.is_none());
+        assert!(dry_run_stats().iter().any(|(outbound, count)| outbound == "direct" && *count >= 1));
+    }
+
+    /// Proxy whose default outbound counts dials, with an access log to inspect
+    async fn probe_proxy(answer_probe_requests: bool) -> (SocketAddr, Arc<std::sync::atomic::AtomicUsize>, tokio::sync::mpsc::Receiver<crate::access_log::AccessRecord>) {
+        let mut config = Config::default();
+        config.server.answer_probe_requests = answer_probe_requests;
+        let connects = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        outbounds.insert("direct", Arc::new(CountingOutbound { connects: connects.clone() }));
+        let access_config = crate::config::AccessLogConfig { enabled: true, ..Default::default() };
+        let (access_log, records) = crate::access_log::AccessLogger::channel(&access_config);
+        let context = InboundContext {
+            access_log: Box::leak(Box::new(access_log)),
+            ..InboundContext::new(Box::leak(Box::new(config)), Arc::new(outbounds))
+        };
+        let running = Socks5Proxy::new("127.0.0.1:0".parse().unwrap()).bind(context).await.unwrap();
+        (running.local_addr(), connects, records)
+    }
+
+    /// Send a no-auth CONNECT request and return the client with the reply read so far
+    async fn send_connect(proxy: SocketAddr, request: &[u8], reply_len: usize) -> (TcpStream, Vec<u8>) {
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        client.write_all(request).await.unwrap();
+        let mut reply = vec![0u8; reply_len];
+        client.read_exact(&mut reply).await.unwrap();
+        (client, reply)
+    }
+
+    #[tokio::test]
+    async fn test_probe_requests_answered_without_dialing() {
+        let (proxy, connects, mut records) = probe_proxy(true).await;
+        let probes_before = probe_requests();
+
+        let (mut client, reply) = send_connect(proxy, &[0x05, 0x01, 0x00, 0x01, 0, 0, 0, 0, 0, 0], 10).await;
+        assert_eq!(reply, [0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+        // 客户端关闭后服务端随即干净地关闭
+        client.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        tokio::time::timeout(Duration::from_secs(2), client.read_to_end(&mut rest)).await.unwrap().unwrap();
+        assert!(rest.is_empty());
+
+        let mut ipv6 = vec![0x05, 0x01, 0x00, 0x04];
+        ipv6.extend_from_slice(&[0; 16]);
+        ipv6.extend_from_slice(&443u16.to_be_bytes());
+        let (_client, reply) = send_connect(proxy, &ipv6, 10).await;
+        assert_eq!(reply[..2], [0x05, 0x00]);
+
+        let record = records.recv().await.unwrap();
+        assert!(record.probe && record.error.is_none() && record.outbound.is_none());
+        assert!(crate::access_log::AccessLogWriter::new(&Default::default()).format(&record).contains(" probe=true result=ok"));
+        assert_eq!(connects.load(std::sync::atomic::Ordering::SeqCst), 0);
+        assert!(probe_requests() >= probes_before + 2);
+    }
+
+    #[tokio::test]
+    async fn test_port_zero_on_real_address_fails() {
+        let (proxy, connects, mut records) = probe_proxy(true).await;
+        let (_client, reply) = send_connect(proxy, &[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0, 0], 2).await;
+        assert_eq!(reply, [0x05, 0x01]);
+        let record = records.recv().await.unwrap();
+        assert!(!record.probe && record.error.unwrap().contains("port 0"));
+
+        // 关闭探测应答后，0.0.0.0:0 同样按端口 0 失败
+        let (proxy, _, _records) = probe_proxy(false).await;
+        let (_client, reply) = send_connect(proxy, &[0x05, 0x01, 0x00, 0x01, 0, 0, 0, 0, 0, 0], 2).await;
+        assert_eq!(reply, [0x05, 0x01]);
+        assert_eq!(connects.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_blackhole_refused_before_resolve_and_counted() {
+        let mut config = Config::default();
+        config.router.rules.push(crate::config::RouterRuleConfig {
+            outbound: "block".to_string(),
+            action: RouteAction::Forward,
+            outbound_chain: Vec::new(),
+            rule_sets: Vec::new(),
+            domains: Default::default(),
+            ip_cidr: vec!["198.51.100.0/24".to_string()],
+            dscp: None,
+            latency_mode: false,
+            rewrite_to: None,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
+        });
+        let router = Arc::new(crate::routing::build_router(&config).await.unwrap());
+        let connects = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        outbounds.insert("direct", Arc::new(CountingOutbound { connects: connects.clone() }));
+        let access_config = crate::config::AccessLogConfig { enabled: true, ..Default::default() };
+        let (access_log, mut records) = crate::access_log::AccessLogger::channel(&access_config);
+
+        let config: &'static Config = Box::leak(Box::new(config));
+        let context = InboundContext {
+            router: Some(router),
+            access_log: Box::leak(Box::new(access_log)),
+            ..InboundContext::new(config, Arc::new(outbounds))
+        };
+        let running = Socks5Proxy::new("127.0.0.1:0".parse().unwrap()).bind(context).await.unwrap();
+        for _ in 0..2 {
+            let mut client = TcpStream::connect(running.local_addr()).await.unwrap();
+            client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+            let mut method = [0u8; 2];
+            client.read_exact(&mut method).await.unwrap();
+            client.write_all(&[0x05, 0x01, 0x00, 0x01, 198, 51, 100, 7, 0, 80]).await.unwrap();
+            let mut reply = [0u8; 2];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [0x05, 0x02]);
+
+            let record = records.recv().await.unwrap();
+            assert_eq!(record.outbound.as_deref(), Some("block"));
+            assert_eq!(record.termination, crate::access_log::Termination::ConnectFailed);
+            assert_eq!((record.upload, record.download), (0, 0));
+            assert!(record.error.unwrap().contains("Blocked"));
+        }
+
+        assert_eq!(connects.load(std::sync::atomic::Ordering::SeqCst), 0);
+        let blocked = crate::blocked::blocked_stats(usize::MAX);
+        assert!(blocked.top.iter().any(|(target, count)| target == "198.51.100.7" && *count >= 2));
+        assert!(blocked.rules.iter().any(|(rule, count)| *rule == Some(0) && *count >= 2));
+    }
+
+    #[tokio::test]
+    async fn test_reject_and_drop_rules_seen_by_client() {
+        let mut config = Config::default();
+        let rule = |outbound: &str, action, cidr: &str| crate::config::RouterRuleConfig {
+            outbound: outbound.to_string(),
+            action,
+            outbound_chain: Vec::new(),
+            rule_sets: Vec::new(),
+            domains: Default::default(),
+            ip_cidr: vec![cidr.to_string()],
+            dscp: None,
+            latency_mode: false,
+            rewrite_to: None,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
+        };
+        config.router.rules.push(rule("reject", RouteAction::Forward, "198.51.100.0/24"));
+        config.router.rules.push(rule("", RouteAction::Drop, "203.0.113.0/24"));
+        config.validate().unwrap();
+        let router = Arc::new(crate::routing::build_router(&config).await.unwrap());
+        let connects = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        outbounds.insert("direct", Arc::new(CountingOutbound { connects: connects.clone() }));
+        let access_config = crate::config::AccessLogConfig { enabled: true, ..Default::default() };
+        let (access_log, mut records) = crate::access_log::AccessLogger::channel(&access_config);
+
+        let config: &'static Config = Box::leak(Box::new(config));
+        let context = InboundContext {
+            router: Some(router),
+            access_log: Box::leak(Box::new(access_log)),
+            ..InboundContext::new(config, Arc::new(outbounds))
+        };
+        let running = Socks5Proxy::new("127.0.0.1:0".parse().unwrap()).bind(context).await.unwrap();
+        let proxy = running.local_addr();
+        let connect = |ip: [u8; 4]| async move {
+            let mut client = TcpStream::connect(proxy).await.unwrap();
+            client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+            let mut method = [0u8; 2];
+            client.read_exact(&mut method).await.unwrap();
+            client.write_all(&[0x05, 0x01, 0x00, 0x01, ip[0], ip[1], ip[2], ip[3], 0, 80]).await.unwrap();
+            client
+        };
+
+        // reject：立即以 0x02 应答并关闭
+        let mut client = connect([198, 51, 100, 7]).await;
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0x05, 0x02]);
+        assert_eq!(records.recv().await.unwrap().outbound.as_deref(), Some("reject"));
+
+        // reject-drop：没有任何应答，数据被读掉，客户端关闭后连接才结束
+        let mut client = connect([203, 0, 113, 9]).await;
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut buf = [0u8; 16];
+        assert!(tokio::time::timeout(Duration::from_millis(300), client.read(&mut buf)).await.is_err());
+        client.shutdown().await.unwrap();
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        let record = records.recv().await.unwrap();
+        assert_eq!(record.outbound.as_deref(), Some("reject-drop"));
+        assert!(record.error.unwrap().contains("Blocked"));
+
+        assert_eq!(connects.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_routes_by_client_address_and_inbound_tag() {
+        let mut config = Config::default();
+        let rule = |outbound: &str| crate::config::RouterRuleConfig {
+            outbound: outbound.to_string(),
+            action: RouteAction::Forward,
+            outbound_chain: Vec::new(),
+            rule_sets: Vec::new(),
+            domains: Default::default(),
+            ip_cidr: vec!["198.51.100.0/24".to_string()],
+            dscp: None,
+            latency_mode: false,
+            rewrite_to: None,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
+        };
+        config.router.rules = vec![
+            crate::config::RouterRuleConfig { source_ip_cidr: vec!["127.0.0.2/32".to_string()], ..rule("block") },
+            crate::config::RouterRuleConfig { inbound: vec!["guest".to_string()], ..rule("block") },
+        ];
+        let router = Arc::new(crate::routing::build_router(&config).await.unwrap());
+        let connects = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        outbounds.insert("direct", Arc::new(CountingOutbound { connects: connects.clone() }));
+        let access_config = crate::config::AccessLogConfig { enabled: true, ..Default::default() };
+        let (access_log, mut records) = crate::access_log::AccessLogger::channel(&access_config);
+
+        let config: &'static Config = Box::leak(Box::new(config));
+        let context = InboundContext {
+            router: Some(router),
+            access_log: Box::leak(Box::new(access_log)),
+            ..InboundContext::new(config, Arc::new(outbounds))
+        };
+        let lan = Socks5Proxy::new("127.0.0.1:0".parse().unwrap()).bind(context.clone().with_tag("lan")).await.unwrap();
+        let guest = Socks5Proxy::new("127.0.0.1:0".parse().unwrap()).bind(context.with_tag("guest")).await.unwrap();
+
+        let request = [0x05, 0x01, 0x00, 0x01, 198, 51, 100, 7, 0, 80];
+        for (client, proxy, outbound) in [
+            ("127.0.0.1:0", lan.local_addr(), "direct"),
+            ("127.0.0.2:0", lan.local_addr(), "block"),
+            ("127.0.0.1:0", guest.local_addr(), "block"),
+        ] {
+            let socket = tokio::net::TcpSocket::new_v4().unwrap();
+            socket.bind(client.parse().unwrap()).unwrap();
+            let mut client = socket.connect(proxy).await.unwrap();
+            client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+            let mut method = [0u8; 2];
+            client.read_exact(&mut method).await.unwrap();
+            client.write_all(&request).await.unwrap();
+            let mut reply = [0u8; 2];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_ne!(reply[1], 0x00);
+
+            let record = records.recv().await.unwrap();
+            assert_eq!(record.outbound.as_deref(), Some(outbound));
+        }
+        // 只有 127.0.0.1 经 lan 入站的连接走到 direct
+        assert_eq!(connects.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_queues_or_refuses() {
+        use crate::tasks::{TaskGroup, TaskTracker};
+
+        let echo = crate::loadgen::spawn_echo_server().await.unwrap();
+        for reject_when_full in [false, true] {
+            let mut config = Config::default();
+            config.server.reject_when_full = reject_when_full;
+            let config: &'static Config = Box::leak(Box::new(config));
+            let tasks: &'static TaskTracker = Box::leak(Box::new(TaskTracker::new()));
+            tasks.set_limit(TaskGroup::InboundConns, Some(2));
+            let outbounds = Arc::new(OutboundManager::from_configs(&config.outbounds).unwrap());
+            let context = InboundContext::new(config, outbounds).with_task_tracker(tasks);
+            let proxy = Socks5Proxy::new("127.0.0.1:0".parse().unwrap()).bind(context).await.unwrap().local_addr();
+
+            let mut held = Vec::new();
+            for _ in 0..2 {
+                let (mut client, method) = negotiate(proxy, &[0x00]).await;
+                assert_eq!(method, 0x00);
+                connect_echo(&mut client, echo).await;
+                held.push(client);
+            }
+
+            let mut third = TcpStream::connect(proxy).await.unwrap();
+            third.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+            let mut method = [0u8; 2];
+            if reject_when_full {
+                third.read_exact(&mut method).await.unwrap();
+                assert_eq!(method, [0x05, 0x00]);
+                let SocketAddr::V4(target) = echo else { unreachable!() };
+                let mut request = vec![0x05, 0x01, 0x00, 0x01];
+                request.extend_from_slice(&target.ip().octets());
+                request.extend_from_slice(&target.port().to_be_bytes());
+                third.write_all(&request).await.unwrap();
+                let mut reply = [0u8; 10];
+                third.read_exact(&mut reply).await.unwrap();
+                assert_eq!(reply[1], 0x01);
+                assert_eq!(tasks.live(TaskGroup::InboundConns), 2);
+            } else {
+                // 满员时排队：一条连接结束之前得不到应答
+                let queued = tokio::time::timeout(Duration::from_millis(200), third.read_exact(&mut method)).await;
+                assert!(queued.is_err());
+                drop(held.pop());
+                tokio::time::timeout(Duration::from_secs(5), third.read_exact(&mut method)).await.unwrap().unwrap();
+                assert_eq!(method, [0x05, 0x00]);
+                connect_echo(&mut third, echo).await;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outbound_connection_reused_after_client_closes() {
+        // 回显服务器，记录接受的连接数
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo = listener.local_addr().unwrap();
+        let accepts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = accepts.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        let mut config = Config::default();
+        config.connection_pool.reuse_outbound_connections = true;
+        let config: &'static Config = Box::leak(Box::new(config));
+        let outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        let pool: &'static ConnectionPool =
+            Box::leak(Box::new(ConnectionPool::new(4, 16, Duration::from_secs(5), Duration::from_secs(30))));
+        let context = InboundContext::new(config, Arc::new(outbounds)).with_connection_pool(pool);
+        let proxy = Socks5Proxy::new("127.0.0.1:0".parse().unwrap()).bind(context).await.unwrap();
+
+        for _ in 0..2 {
+            let (mut client, method) = negotiate(proxy.local_addr(), &[0x00]).await;
+            assert_eq!(method, 0x00);
+            connect_echo(&mut client, echo).await;
+            drop(client);
+
+            // 客户端先关闭后，到回显服务器的连接回到池中
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while pool.stats().await.total_connections != 1 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap();
+        }
+        assert_eq!(accepts.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // 两次的首次交互都记入该目标的复用统计：一次新建，一次取自池中
+        let per_target = pool.stats().await.per_target;
+        assert_eq!(per_target.len(), 1);
+        assert_eq!(per_target[0].target, PoolKey::new("direct", echo.into()));
+        assert_eq!((per_target[0].fresh, per_target[0].pooled, per_target[0].dead_on_arrival), (1, 1, 0));
+        assert!(per_target[0].pooled_latency.is_some() && per_target[0].fresh_latency.is_some());
+    }
+
+    /// Proxy routing `api.example.com` and `127.0.0.1:1` through rewrite rules to `upstream`
+    async fn rewrite_proxy(upstream: SocketAddr) -> (SocketAddr, tokio::sync::mpsc::Receiver<crate::access_log::AccessRecord>) {
+        let rule = |domains: Vec<String>, ip_cidr: Vec<String>, rewrite_to, outbound: &str| crate::config::RouterRuleConfig {
+            outbound: outbound.to_string(),
+            action: RouteAction::Forward,
+            outbound_chain: Vec::new(),
+            rule_sets: Vec::new(),
+            domains: crate::config::DomainLists { domain: domains, ..Default::default() },
+            ip_cidr,
+            dscp: None,
+            latency_mode: false,
+            rewrite_to,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
+        };
+        let mut config = Config::default();
+        config.router.rules = vec![
+            rule(
+                vec!["api.example.com".to_string()],
+                Vec::new(),
+                Some(RewriteTarget { host: Some(upstream.ip().to_string()), port: Some(upstream.port()) }),
+                "direct",
+            ),
+            rule(Vec::new(), vec!["127.0.0.1/32".to_string()], Some(RewriteTarget { host: None, port: Some(upstream.port()) }), "direct"),
+            // 改写后的目标不再参与路由，否则会被这条规则拦截
+            rule(Vec::new(), vec!["127.0.0.0/8".to_string()], None, "block"),
+        ];
+        let router = Arc::new(crate::routing::build_router(&config).await.unwrap());
+        let outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        let access_config = crate::config::AccessLogConfig { enabled: true, ..Default::default() };
+        let (access_log, records) = crate::access_log::AccessLogger::channel(&access_config);
+
+        let config: &'static Config = Box::leak(Box::new(config));
+        let context = InboundContext {
+            router: Some(router),
+            access_log: Box::leak(Box::new(access_log)),
+            ..InboundContext::new(config, Arc::new(outbounds))
+        };
+        let running = Socks5Proxy::new("127.0.0.1:0".parse().unwrap()).bind(context).await.unwrap();
+        (running.local_addr(), records)
+    }
+
+    /// SOCKS5 CONNECT for the raw address `target` (ATYP onwards), returning the reply and the upstream's greeting
+    async fn connect_raw(proxy: SocketAddr, target: &[u8]) -> (Vec<u8>, [u8; 2]) {
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        client.write_all(&[&[0x05, 0x01, 0x00][..], target].concat()).await.unwrap();
+        let mut reply = vec![0u8; 4 + target.len() - 1];
+        client.read_exact(&mut reply).await.unwrap();
+        let mut greeting = [0u8; 2];
+        client.read_exact(&mut greeting).await.unwrap();
+        (reply, greeting)
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_rules_redirect_the_destination() {
+        let upstream = tagged_upstream(b"ok").await;
+        let (proxy, mut records) = rewrite_proxy(upstream).await;
+        let access_config = crate::config::AccessLogConfig { enabled: true, ..Default::default() };
+        let writer = crate::access_log::AccessLogWriter::new(&access_config);
+
+        // 域名改写为 IP:端口，应答里仍是客户端请求的目标
+        let domain = b"api.example.com";
+        let target = [&[0x03, domain.len() as u8][..], domain, &443u16.to_be_bytes()].concat();
+        let (reply, greeting) = connect_raw(proxy, &target).await;
+        assert_eq!((reply[1], &reply[3..]), (0x00, &target[..]));
+        assert_eq!(&greeting, b"ok");
+        let record = records.recv().await.unwrap();
+        let rewritten = format!("127.0.0.1:{}", upstream.port());
+        assert_eq!(record.target.as_deref(), Some("api.example.com:443"));
+        assert_eq!(record.rewritten_to.as_deref(), Some(rewritten.as_str()));
+        assert_eq!(record.outbound.as_deref(), Some("direct"));
+        let line = writer.format(&record);
+        assert!(line.contains("target=api.example.com:443 outbound=direct"), "{}", line);
+        assert!(line.contains(&format!(" rewritten_to={}", rewritten)), "{}", line);
+
+        // 只改写端口的 IP 目标
+        let target = [0x01, 127, 0, 0, 1, 0, 1];
+        let (reply, greeting) = connect_raw(proxy, &target).await;
+        assert_eq!((reply[1], &reply[3..]), (0x00, &target[..]));
+        assert_eq!(&greeting, b"ok");
+        let record = records.recv().await.unwrap();
+        assert_eq!(record.target.as_deref(), Some("127.0.0.1:1"));
+        assert_eq!(record.rewritten_to.as_deref(), Some(rewritten.as_str()));
+        assert!(record.error.is_none(), "{:?}", record.error);
+    }
+
+    #[tokio::test]
+    async fn test_rule_chain_tunnels_through_each_hop() {
+        let target = tagged_upstream(b"ok").await;
+        let mut config = Config::default();
+        config.router.rules.push(crate::config::RouterRuleConfig {
+            outbound: String::new(),
+            action: RouteAction::Forward,
+            outbound_chain: vec!["hop-a".to_string(), "hop-b".to_string()],
+            rule_sets: Vec::new(),
+            domains: Default::default(),
+            ip_cidr: vec![format!("{}/32", target.ip())],
+            dscp: None,
+            latency_mode: false,
+            rewrite_to: None,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
+        });
+        let router = Arc::new(crate::routing::build_router(&config).await.unwrap());
+        let mut outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        let (first_addr, mut first_seen) = recording_socks_server().await;
+        let (second_addr, mut second_seen) = recording_socks_server().await;
+        outbounds.insert("hop-a", Arc::new(crate::protocols::Socks5Protocol::with_server(first_addr)));
+        outbounds.insert("hop-b", Arc::new(crate::protocols::Socks5Protocol::with_server(second_addr)));
+        let access_config = crate::config::AccessLogConfig { enabled: true, ..Default::default() };
+        let (access_log, mut records) = crate::access_log::AccessLogger::channel(&access_config);
+
+        let config: &'static Config = Box::leak(Box::new(config));
+        let context = InboundContext {
+            router: Some(router),
+            access_log: Box::leak(Box::new(access_log)),
+            ..InboundContext::new(config, Arc::new(outbounds))
+        };
+        let running = Socks5Proxy::new("127.0.0.1:0".parse().unwrap()).bind(context).await.unwrap();
+        let mut client = TcpStream::connect(running.local_addr()).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        let mut request = vec![0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+        request.extend_from_slice(&target.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut response = [0u8; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], 0x00);
+        let mut tag = [0u8; 2];
+        client.read_exact(&mut tag).await.unwrap();
+        assert_eq!(&tag, b"ok");
+        drop(client);
+
+        assert_eq!(first_seen.recv().await, Some(second_addr));
+        assert_eq!(second_seen.recv().await, Some(target));
+        let record = records.recv().await.unwrap();
+        assert_eq!(record.outbound.as_deref(), Some("hop-a>hop-b"));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout_closes_stalled_clients() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+        let mut config = Config::default();
+        config.server.handshake_timeout_secs = 1;
+        let mut outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        outbounds.insert("direct", Arc::new(MockOutbound { upstream: echo_addr }));
+        let access_config = crate::config::AccessLogConfig { enabled: true, ..Default::default() };
+        let (access_log, mut records) = crate::access_log::AccessLogger::channel(&access_config);
+
+        let config: &'static Config = Box::leak(Box::new(config));
+        let context = InboundContext {
+            access_log: Box::leak(Box::new(access_log)),
+            ..InboundContext::new(config, Arc::new(outbounds))
+        };
+        let running = Socks5Proxy::new("127.0.0.1:0".parse().unwrap()).bind(context).await.unwrap();
+
+        // 只发送问候、不发请求的客户端在超时后被关闭
+        let mut stalled = TcpStream::connect(running.local_addr()).await.unwrap();
+        stalled.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        stalled.read_exact(&mut method).await.unwrap();
+        let started = std::time::Instant::now();
+        let mut rest = Vec::new();
+        assert_eq!(stalled.read_to_end(&mut rest).await.unwrap(), 0);
+        assert!(started.elapsed() >= std::time::Duration::from_millis(900));
+        let record = records.recv().await.unwrap();
+        assert_eq!(record.handshake_timeout, Some(ConnectionPhase::Request));
+        let line = crate::access_log::AccessLogWriter::new(&access_config).format(&record);
+        assert!(line.ends_with("result=handshake_timeout phase=request"), "{}", line);
+
+        // 正常客户端进入转发后不受握手超时影响
+        let mut client = TcpStream::connect(running.local_addr()).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.read_exact(&mut method).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0, 80]).await.unwrap();
+        let mut response = [0u8; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], 0x00);
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        client.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+        drop(client);
+        let record = records.recv().await.unwrap();
+        assert!(record.error.is_none() && record.handshake_timeout.is_none());
+        assert_eq!(record.termination, crate::access_log::Termination::Ok);
+    }
+
+    #[tokio::test]
+    async fn test_inbound_profiles_route_independently() {
+        let mut config = Config::default();
+        config.server.dry_run = true;
+        for (name, outbound) in [("home", "home-upstream"), ("work", "work-upstream")] {
+            let rule = crate::config::RouterRuleConfig {
+                outbound: outbound.to_string(),
+                action: RouteAction::Forward,
+                outbound_chain: Vec::new(),
+                rule_sets: Vec::new(),
+                domains: Default::default(),
+                ip_cidr: vec!["192.0.2.0/24".to_string()],
+                dscp: None,
+                latency_mode: false,
+                rewrite_to: None,
+                port: Vec::new(),
+                port_range: Vec::new(),
+                network: None,
+                source_ip_cidr: Vec::new(),
+                inbound: Vec::new(),
+                ip_geoip: Vec::new(),
+            };
+            let profile = crate::config::RoutingProfileConfig { default_outbound: "direct".to_string(), rules: vec![rule] };
+            config.profiles.insert(name.to_string(), profile);
+        }
+        let router = Arc::new(crate::routing::build_router(&config).await.unwrap());
+        let connects = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+        for name in ["direct", "home-upstream", "work-upstream"] {
+            outbounds.insert(name, Arc::new(CountingOutbound { connects: connects.clone() }));
+        }
+        let access_config = crate::config::AccessLogConfig { enabled: true, ..Default::default() };
+        let (access_log, mut records) = crate::access_log::AccessLogger::channel(&access_config);
+
+        let config: &'static Config = Box::leak(Box::new(config));
+        let context = InboundContext {
+            router: Some(router),
+            access_log: Box::leak(Box::new(access_log)),
+            ..InboundContext::new(config, Arc::new(outbounds))
+        };
+        let mut routed = Vec::new();
+        for profile in [None, Some("home"), Some("work")] {
+            let context = match profile {
+                Some(profile) => context.clone().with_profile(profile),
+                None => context.clone(),
+            };
+            let running = Socks5Proxy::new("127.0.0.1:0".parse().unwrap()).bind(context).await.unwrap();
+            let mut client = TcpStream::connect(running.local_addr()).await.unwrap();
+            client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+            let mut method = [0u8; 2];
+            client.read_exact(&mut method).await.unwrap();
+            client.write_all(&[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0, 80]).await.unwrap();
+            let mut reply = [0u8; 2];
+            client.read_exact(&mut reply).await.unwrap();
+
+            let record = records.recv().await.unwrap();
+            routed.push((record.profile, record.outbound.unwrap()));
+        }
+
+        assert_eq!(
+            routed,
+            [
+                (None, "direct".to_string()),
+                (Some("home".to_string()), "home-upstream".to_string()),
+                (Some("work".to_string()), "work-upstream".to_string()),
+            ]
+        );
+        assert_eq!(connects.load(std::sync::atomic::Ordering::SeqCst), 0);
+        assert!(profile_route_stats().iter().any(|(profile, count)| profile == "work" && *count >= 1));
     }
 }
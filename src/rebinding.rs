@@ -0,0 +1,153 @@
+// DNS重绑定防护：非内部域名解析到内网地址时拒绝连接，防止代理成为进入本地网络的跳板
+use crate::config::{RebindingProtectionConfig, RebindingStrictness};
+use crate::error::{ProxyError, Result};
+use crate::protocol::Address;
+use crate::routing::{DomainMatcher, MatcherResult};
+use log::warn;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+/// Connections refused because their domain resolved to an internal address
+static BLOCKED: AtomicU64 = AtomicU64::new(0);
+
+/// Loopback, private, link-local, shared (CGNAT) and unspecified addresses
+pub fn is_internal_ip(ip: IpAddr) -> bool {
+    let address = Address::from(ip.to_canonical());
+    ip.to_canonical().is_unspecified() || address.is_loopback() || address.is_private()
+}
+
+/// Checks resolved addresses against the rebinding protection settings
+pub struct RebindingGuard {
+    enabled: bool,
+    internal_domains: Option<DomainMatcher>,
+    strictness: RebindingStrictness,
+}
+
+impl RebindingGuard {
+    pub fn new(config: &RebindingProtectionConfig) -> Result<Self> {
+        let lists = &config.internal_domains;
+        let internal_domains = DomainMatcher::new(
+            lists.domain.iter().map(|d| d.to_ascii_lowercase()).collect(),
+            lists.domain_suffix.iter().map(|d| d.to_ascii_lowercase()).collect(),
+            lists.domain_keyword.clone(),
+            lists.domain_regex.clone(),
+        )?;
+        Ok(Self {
+            enabled: config.block_private_resolution_for_public_domains,
+            internal_domains: Some(internal_domains),
+            strictness: config.strictness,
+        })
+    }
+
+    /// Guard that lets every answer through
+    pub fn disabled() -> Self {
+        Self { enabled: false, internal_domains: None, strictness: RebindingStrictness::default() }
+    }
+
+    /// Addresses `domain` may be connected to, out of those it resolved to
+    ///
+    /// Internal addresses of domains outside the allowlist either refuse the
+    /// connection (`reject_any`) or are dropped (`drop_internal`), in which
+    /// case the connection is refused only when no public address is left.
+    pub fn check(&self, domain: &str, addrs: Vec<SocketAddr>) -> Result<Vec<SocketAddr>> {
+        if !self.enabled {
+            return Ok(addrs);
+        }
+        let Some(internal) = addrs.iter().find(|addr| is_internal_ip(addr.ip())) else {
+            return Ok(addrs);
+        };
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        if self.is_internal_domain(&domain) {
+            return Ok(addrs);
+        }
+
+        let blocked = internal.ip();
+        let addrs = match self.strictness {
+            RebindingStrictness::RejectAny => Vec::new(),
+            RebindingStrictness::DropInternal => addrs.into_iter().filter(|addr| !is_internal_ip(addr.ip())).collect(),
+        };
+        if !addrs.is_empty() {
+            warn!("Dropped internal addresses of {} (first {}), possible DNS rebinding", domain, blocked);
+            return Ok(addrs);
+        }
+
+        BLOCKED.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "Refused connection: {} resolved to internal address {}, possible DNS rebinding. \
+             Add the domain to rebinding_protection.internal_domains if it is meant to be internal",
+            domain, blocked
+        );
+        Err(ProxyError::RebindingBlocked { domain, addr: blocked })
+    }
+
+    fn is_internal_domain(&self, domain: &str) -> bool {
+        self.internal_domains
+            .as_ref()
+            .is_some_and(|matcher| matches!(matcher.matches(domain), MatcherResult::Match))
+    }
+}
+
+/// Number of connections refused by the global guard
+pub fn rebinding_blocked() -> u64 {
+    BLOCKED.load(Ordering::Relaxed)
+}
+
+static GLOBAL_REBINDING_GUARD: OnceLock<RebindingGuard> = OnceLock::new();
+
+/// Initialize the global rebinding guard
+pub fn init_global_rebinding_guard(config: &RebindingProtectionConfig) -> Result<()> {
+    let _ = GLOBAL_REBINDING_GUARD.set(RebindingGuard::new(config)?);
+    Ok(())
+}
+
+/// Get the global rebinding guard (disabled when not initialized)
+pub fn get_global_rebinding_guard() -> &'static RebindingGuard {
+    GLOBAL_REBINDING_GUARD.get_or_init(RebindingGuard::disabled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DomainLists;
+
+    fn guard(strictness: RebindingStrictness) -> RebindingGuard {
+        RebindingGuard::new(&RebindingProtectionConfig {
+            block_private_resolution_for_public_domains: true,
+            internal_domains: DomainLists { domain_suffix: vec!["corp.example".to_string()], ..Default::default() },
+            strictness,
+        })
+        .unwrap()
+    }
+
+    fn addrs(ips: &[&str]) -> Vec<SocketAddr> {
+        ips.iter().map(|ip| SocketAddr::new(ip.parse().unwrap(), 443)).collect()
+    }
+
+    #[test]
+    fn test_internal_ips() {
+        for ip in ["127.0.0.1", "10.0.0.1", "192.168.1.10", "169.254.1.1", "100.64.0.1", "0.0.0.0", "::1", "fd00::1", "fe80::1", "::ffff:192.168.1.1"] {
+            assert!(is_internal_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "100.128.0.1", "2001:db8::1", "::ffff:1.1.1.1"] {
+            assert!(!is_internal_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_strictness() {
+        let mixed = addrs(&["93.184.216.34", "10.0.0.5"]);
+        let err = guard(RebindingStrictness::RejectAny).check("evil.example", mixed.clone()).unwrap_err();
+        assert!(matches!(err, ProxyError::RebindingBlocked { ref domain, .. } if domain == "evil.example"));
+        assert_eq!(err.socks5_reply_code(), 0x02);
+
+        let drop = guard(RebindingStrictness::DropInternal);
+        assert_eq!(drop.check("evil.example", mixed).unwrap(), addrs(&["93.184.216.34"]));
+        assert!(drop.check("evil.example", addrs(&["10.0.0.5"])).is_err());
+
+        // 白名单域名和公网地址不受影响
+        assert!(drop.check("Git.Corp.Example.", addrs(&["10.0.0.5"])).is_ok());
+        assert!(guard(RebindingStrictness::RejectAny).check("example.com", addrs(&["1.1.1.1"])).is_ok());
+        assert!(RebindingGuard::disabled().check("evil.example", addrs(&["10.0.0.5"])).is_ok());
+    }
+}
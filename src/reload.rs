@@ -0,0 +1,164 @@
+// 配置热重载：收到 SIGHUP 时重新读取 --config 指定的文件，校验后重建出站与路由器并替换全局实例。
+// 进行中的连接继续使用原来的出站；新配置无效时记录错误，旧配置保持生效。
+// 只有出站、路由规则和规则集会重载，其余设置（监听地址、DNS、连接池等）仍需重启。
+use crate::config::Config;
+use crate::error::Result;
+use crate::outbound::{set_global_outbound_manager, start_url_tests, OutboundManager};
+use crate::routing::{build_router, set_global_router, start_rule_set_updates, HighPerformanceRouter};
+use log::info;
+use std::path::Path;
+use std::sync::Arc;
+
+/// A config read from disk with the outbounds and router built from it
+pub struct ReloadedConfig {
+    pub config: Config,
+    pub outbounds: OutboundManager,
+    pub router: HighPerformanceRouter,
+}
+
+impl ReloadedConfig {
+    /// Read, validate and build `path` without touching the running state
+    pub async fn load(path: &Path) -> Result<Self> {
+        let config = Config::from_file(path)?;
+        config.validate()?;
+        let outbounds = OutboundManager::with_error_policy(&config.outbounds, config.on_outbound_error)?;
+        let router = build_router(&config).await?;
+        Ok(Self { config, outbounds, router })
+    }
+
+    /// Make the outbounds and router the ones new connections use
+    pub fn install(self) -> Result<()> {
+        let (rules, rule_sets) = (self.router.rule_count(), self.router.rule_set_count());
+        // 先换出站再换路由：新规则引用的出站在路由生效时已经存在
+        set_global_outbound_manager(self.outbounds)?;
+        start_url_tests();
+        set_global_router(self.router);
+        start_rule_set_updates(Arc::new(self.config));
+        info!("Configuration reloaded ({} rules, {} rule sets)", rules, rule_sets);
+        Ok(())
+    }
+}
+
+/// Reload the outbounds and routing of `path`; on error nothing changes
+pub async fn reload_config(path: &Path) -> Result<()> {
+    ReloadedConfig::load(path).await?.install()
+}
+
+/// Reload `path` whenever the process receives SIGHUP
+#[cfg(unix)]
+pub fn start_reload_on_sighup(path: std::path::PathBuf) -> Result<()> {
+    use crate::tasks::{get_global_task_tracker, TaskGroup};
+    use log::error;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    get_global_task_tracker().spawn(TaskGroup::ConfigReload, async move {
+        while hangups.recv().await.is_some() {
+            info!("SIGHUP received, reloading {}", path.display());
+            if let Err(e) = reload_config(&path).await {
+                error!("Config reload failed, keeping the current config: {}", e);
+            }
+        }
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{RouteAction, RouterRuleConfig};
+    use crate::inbound::InboundContext;
+    use crate::proxy::Socks5Proxy;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn block_rule(outbound: &str, cidr: &str) -> RouterRuleConfig {
+        RouterRuleConfig {
+            outbound: outbound.to_string(),
+            action: RouteAction::Forward,
+            outbound_chain: Vec::new(),
+            rule_sets: Vec::new(),
+            domains: Default::default(),
+            ip_cidr: vec![cidr.to_string()],
+            dscp: None,
+            latency_mode: false,
+            rewrite_to: None,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
+        }
+    }
+
+    /// SOCKS5 CONNECT to `target`, returning the reply code
+    async fn connect(proxy: SocketAddr, target: SocketAddr) -> (TcpStream, u8) {
+        let SocketAddr::V4(target) = target else { unreachable!() };
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        let mut request = vec![0x05, 0x01, 0x00, 0x01];
+        request.extend_from_slice(&target.ip().octets());
+        request.extend_from_slice(&target.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        (client, reply[1])
+    }
+
+    async fn echo(client: &mut TcpStream) {
+        let mut echoed = [0u8; 2];
+        client.write_all(b"hi").await.unwrap();
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"hi");
+    }
+
+    #[tokio::test]
+    async fn test_swapped_rules_apply_to_new_connections() {
+        // 其他测试也经全局路由器路由，这里只为单独的回环地址加规则
+        let target_listener = TcpListener::bind("127.0.0.2:0").await.unwrap();
+        let target = target_listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = target_listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        let path = std::env::temp_dir().join(format!("anybls-reload-{}.toml", std::process::id()));
+        Config::default().to_file(&path).unwrap();
+
+        let initial = ReloadedConfig::load(&path).await.unwrap();
+        set_global_router(initial.router);
+        let config: &'static Config = Box::leak(Box::new(initial.config));
+        // 全局出站只能初始化一次，监听器用固定的出站，路由跟随全局路由器
+        let context = InboundContext::new(config, Arc::new(initial.outbounds));
+        let running = Socks5Proxy::new("127.0.0.1:0".parse().unwrap()).bind(context).await.unwrap();
+        let proxy = running.local_addr();
+
+        let (mut in_flight, reply) = connect(proxy, target).await;
+        assert_eq!(reply, 0x00);
+        echo(&mut in_flight).await;
+
+        // 引用不存在的出站：拒绝新配置，旧规则继续生效
+        let mut invalid = Config::default();
+        invalid.router.rules = vec![block_rule("missing", "127.0.0.2/32")];
+        invalid.to_file(&path).unwrap();
+        assert!(ReloadedConfig::load(&path).await.is_err());
+        assert_eq!(connect(proxy, target).await.1, 0x00);
+
+        let mut blocking = Config::default();
+        blocking.router.rules = vec![block_rule("block", "127.0.0.2/32")];
+        blocking.to_file(&path).unwrap();
+        let reloaded = ReloadedConfig::load(&path).await.unwrap();
+        set_global_router(reloaded.router);
+
+        assert_ne!(connect(proxy, target).await.1, 0x00);
+        echo(&mut in_flight).await;
+        std::fs::remove_file(&path).unwrap();
+    }
+}
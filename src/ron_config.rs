@@ -1,28 +1,60 @@
 // RON配置文件支持
 use serde::{Deserialize, Serialize};
+use crate::config::{is_builtin_outbound, DomainLists, RouteAction, RouterRuleConfig};
+use crate::endpoint::PortStrategy;
+use crate::tls_fragment::TlsFragmentConfig;
+use crate::traffic_mark::LingerPolicy;
 use crate::error::Result;
-use crate::rule_set_downloader::RuleSetDownloader;
+use crate::inbound::InboundKind;
+use crate::rule_set_downloader::{RuleSetDownloader, DEFAULT_MAX_CACHE_AGE};
+use crate::scope::ScopedIp;
+use log::warn;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::Path;
+use std::time::Duration;
 
-/// RON配置根结构
+/// 可以转换为内部配置的sing-box出站类型
+const CONVERTIBLE_OUTBOUND_TYPES: &[&str] = &[
+    "direct", "block", "socks", "http", "shadowsocks", "vless", "selector", "urltest", "balancer",
+];
+
+fn is_group_type(outbound_type: &str) -> bool {
+    matches!(outbound_type, "selector" | "urltest" | "balancer")
+}
+
+/// RON配置根结构，与 sing-box 的 JSON 配置同构
+///
+/// 缺省的字段取 sing-box 的默认值，我们不认识的字段直接忽略，
+/// 转换时再把有意义但无法映射的字段记为警告。
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RonConfig {
     pub log: Option<LogConfig>,
     pub experimental: Option<ExperimentalConfig>,
     pub dns: Option<DnsConfig>,
+    #[serde(default)]
     pub inbounds: Vec<InboundConfig>,
+    #[serde(default)]
     pub outbounds: Vec<OutboundConfig>,
+    #[serde(default)]
     pub route: RouteConfig,
 }
 
 /// 日志配置
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogConfig {
+    #[serde(default)]
     pub disabled: bool,
+    #[serde(default)]
     pub timestamp: bool,
+    #[serde(default = "default_log_level")]
     pub level: String,
 }
 
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
 /// 实验性功能配置
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExperimentalConfig {
@@ -31,7 +63,8 @@ pub struct ExperimentalConfig {
 }
 
 /// Clash API配置
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ClashApiConfig {
     pub external_controller: String,
     pub external_ui: String,
@@ -44,7 +77,8 @@ pub struct ClashApiConfig {
 }
 
 /// 缓存文件配置
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct CacheFileConfig {
     pub enabled: bool,
     pub path: String,
@@ -55,7 +89,8 @@ pub struct CacheFileConfig {
 }
 
 /// DNS配置
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct DnsConfig {
     pub servers: Vec<DnsServer>,
     pub strategy: String,
@@ -68,17 +103,26 @@ pub struct DnsServer {
     pub tag: String,
     #[serde(rename = "type")]
     pub server_type: String,
+    #[serde(default)]
     pub server: String,
     pub domain_resolver: Option<String>,
     pub detour: Option<String>,
+    /// fakeip 服务器的地址池
+    pub inet4_range: Option<String>,
+    pub inet6_range: Option<String>,
 }
 
 /// 入站配置
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InboundConfig {
+    #[serde(default)]
+    pub tag: Option<String>,
     #[serde(rename = "type")]
     pub inbound_type: String,
+    /// tun 等入站没有监听地址
+    #[serde(default)]
     pub listen: String,
+    #[serde(default)]
     pub listen_port: u16,
     pub tcp_fast_open: Option<bool>,
     pub tcp_multi_path: Option<bool>,
@@ -96,6 +140,8 @@ pub struct OutboundConfig {
     pub server: Option<String>,
     pub server_port: Option<u16>,
     pub password: Option<String>,
+    /// shadowsocks加密方法
+    pub method: Option<String>,
     pub uuid: Option<String>,
     pub flow: Option<String>,
     pub packet_encoding: Option<String>,
@@ -105,17 +151,26 @@ pub struct OutboundConfig {
     pub tolerance: Option<u32>,
     pub interrupt_exist_connections: Option<bool>,
     pub outbounds: Option<Vec<String>>,
+    /// selector 初始选中的成员
+    pub default: Option<String>,
     pub tls: Option<TlsConfig>,
     pub transport: Option<TransportConfig>,
+    pub override_host_header: Option<String>,
+    pub udp_over_tcp: Option<bool>,
 }
 
 /// TLS配置
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TlsConfig {
+    #[serde(default)]
     pub enabled: bool,
     pub disable_sni: Option<bool>,
     pub server_name: Option<String>,
+    pub override_sni: Option<String>,
     pub insecure: Option<bool>,
+    /// 会话恢复缓存大小，0表示关闭
+    pub session_cache_size: Option<usize>,
+    pub early_data: Option<bool>,
     pub alpn: Option<Vec<String>>,
     pub utls: Option<UtlsConfig>,
     pub reality: Option<RealityConfig>,
@@ -124,15 +179,20 @@ pub struct TlsConfig {
 /// uTLS配置
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UtlsConfig {
+    #[serde(default)]
     pub enabled: bool,
+    #[serde(default)]
     pub fingerprint: String,
 }
 
 /// Reality配置
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RealityConfig {
+    #[serde(default)]
     pub enabled: bool,
+    #[serde(default)]
     pub public_key: String,
+    #[serde(default)]
     pub short_id: String,
 }
 
@@ -148,7 +208,8 @@ pub struct TransportConfig {
 }
 
 /// 路由配置
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct RouteConfig {
     pub rules: Vec<RouteRule>,
     pub rule_set: Vec<RuleSetConfig>,
@@ -160,34 +221,58 @@ pub struct RouteConfig {
 /// 路由规则
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RouteRule {
+    /// sing-box 1.11 之前的规则没有 action，都是 route
+    #[serde(default = "default_rule_action")]
     pub action: String,
+    /// reject 的方式：default 立即拒绝，drop 静默丢弃
+    pub method: Option<String>,
     pub protocol: Option<String>,
     pub rule_set: Option<Vec<String>>,
     pub domain_suffix: Option<Vec<String>>,
     pub outbound: Option<String>,
 }
 
+fn default_rule_action() -> String {
+    "route".to_string()
+}
+
 /// 规则集合配置
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RuleSetConfig {
     pub tag: String,
     #[serde(rename = "type")]
     pub rule_set_type: String,
+    #[serde(default)]
     pub url: String,
+    #[serde(default)]
     pub format: String,
     pub download_detour: Option<String>,
 }
 
 impl RonConfig {
-    /// 从RON文件加载配置
+    /// 从RON文件加载配置；可选字段直接写值即可，不必包在 `Some(...)` 里
     pub fn from_ron_file<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
         let content = std::fs::read_to_string(path)
             .map_err(|e| crate::error::ProxyError::Io(e))?;
-        
-        let config: RonConfig = ron::from_str(&content)
-            .map_err(|e| crate::error::ProxyError::Protocol(format!("Invalid RON config: {}", e)))?;
-        
-        Ok(config)
+        Self::from_ron_str(&content)
+    }
+
+    pub fn from_ron_str(content: &str) -> Result<Self> {
+        ron::Options::default()
+            .with_default_extension(ron::extensions::Extensions::IMPLICIT_SOME)
+            .from_str(content)
+            .map_err(|e| crate::error::ProxyError::Protocol(format!("Invalid RON config: {}", e)))
+    }
+
+    /// 从 sing-box 的 JSON 配置文件加载
+    pub fn from_json_file<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        Self::from_json_str(&content)
+    }
+
+    pub fn from_json_str(content: &str) -> Result<Self> {
+        serde_json::from_str(content)
+            .map_err(|e| crate::error::ProxyError::Protocol(format!("Invalid JSON config: {}", e)))
     }
 
     /// 获取入站配置
@@ -217,7 +302,15 @@ impl RonConfig {
         for rule_set in &self.route.rule_set {
             if rule_set.rule_set_type == "remote" {
                 println!("准备下载规则集: {} -> {}", rule_set.tag, rule_set.url);
-                downloader.download_rule_set(&rule_set.tag, &rule_set.url).await?;
+                // 按声明的格式检查下载内容
+                let format = match rule_set.format.as_str() {
+                    "binary" => Some(crate::config::RuleSetFormat::Srs),
+                    "source" => Some(crate::config::RuleSetFormat::Source),
+                    _ => None,
+                };
+                downloader
+                    .download_rule_set_signed(&rule_set.tag, &rule_set.url, format, DEFAULT_MAX_CACHE_AGE, None)
+                    .await?;
             }
         }
         
@@ -229,116 +322,1351 @@ impl RonConfig {
         &self.route.rule_set
     }
 
-    /// 转换为我们的内部配置格式
+    /// 可转换的出站标签；成员全部无法转换的组会被剔除
+    fn convertible_outbound_tags(&self) -> HashSet<&str> {
+        let mut tags: HashSet<&str> = self
+            .outbounds
+            .iter()
+            .filter(|o| CONVERTIBLE_OUTBOUND_TYPES.contains(&o.outbound_type.as_str()))
+            .map(|o| o.tag.as_str())
+            .collect();
+        loop {
+            let empty_group = self.outbounds.iter().find(|o| {
+                is_group_type(&o.outbound_type)
+                    && tags.contains(o.tag.as_str())
+                    && !o.outbounds.iter().flatten().any(|m| {
+                        tags.contains(m.as_str()) || is_builtin_outbound(m)
+                    })
+            });
+            match empty_group {
+                Some(group) => {
+                    tags.remove(group.tag.as_str());
+                }
+                None => return tags,
+            }
+        }
+    }
+
+    /// 转换为我们的内部配置格式，无法映射的字段记录为警告日志
     pub fn to_internal_config(&self) -> Result<crate::config::Config> {
-        // 转换出站配置
+        let (config, warnings) = self.to_internal_config_with_warnings()?;
+        for warning in &warnings {
+            warn!("RON config: {}", warning);
+        }
+        Ok(config)
+    }
+
+    /// 转换为内部配置，并按出现顺序返回所有无法映射的字段
+    ///
+    /// 内置的 `direct` 与 `block` 出站无需声明；引用不支持出站（如 `dns-out`）
+    /// 的规则会被跳过，而不是在运行时报 "Outbound not found"。
+    pub fn to_internal_config_with_warnings(&self) -> Result<(crate::config::Config, Vec<String>)> {
+        let mut warnings = Vec::new();
+        let level = self.convert_log(&mut warnings);
+        let clash_api = self.convert_experimental(&mut warnings);
+        let fake_ip = self.convert_fake_ip(&mut warnings);
+        let (dns_servers, enable_ipv6) = self.convert_dns(&mut warnings);
+        let (host, port, inbounds) = self.convert_inbounds(&mut warnings)?;
+
+        let tags = self.convertible_outbound_tags();
+        let known = |name: &str| tags.contains(name) || is_builtin_outbound(name);
         let mut outbounds = Vec::new();
         for outbound in &self.outbounds {
-            let internal_outbound = match outbound.outbound_type.as_str() {
-                "direct" => crate::config::OutboundConfig {
-                    name: outbound.tag.clone(),
-                    kind: crate::config::OutboundType::Direct,
-                },
-                "socks" => {
-                    let server_addr = format!("{}:{}", 
-                        outbound.server.as_ref().unwrap_or(&"127.0.0.1".to_string()),
-                        outbound.server_port.unwrap_or(1080)
-                    );
-                    crate::config::OutboundConfig {
-                        name: outbound.tag.clone(),
-                        kind: crate::config::OutboundType::Socks5 { address: server_addr },
-                    }
-                },
-                "vless" => {
-                    let server_addr = format!("{}:{}", 
-                        outbound.server.as_ref().unwrap_or(&"127.0.0.1".to_string()),
-                        outbound.server_port.unwrap_or(443)
-                    );
-                    crate::config::OutboundConfig {
-                        name: outbound.tag.clone(),
-                        kind: crate::config::OutboundType::Vless {
-                            address: server_addr,
-                            uuid: outbound.uuid.clone().unwrap_or_default(),
-                            tls: outbound.tls.as_ref().map_or(false, |t| t.enabled),
-                        },
-                    }
-                },
-                _ => continue,
-            };
-            outbounds.push(internal_outbound);
+            if !CONVERTIBLE_OUTBOUND_TYPES.contains(&outbound.outbound_type.as_str()) {
+                warnings.push(format!("outbound {} of type {} is not supported", outbound.tag, outbound.outbound_type));
+            } else if !tags.contains(outbound.tag.as_str()) {
+                warnings.push(format!("outbound group {} has no supported members", outbound.tag));
+            } else {
+                outbounds.push(convert_outbound(outbound, &known, &mut warnings));
+            }
         }
 
-        // 转换路由规则
-        let mut rules = Vec::new();
-        for rule in &self.route.rules {
-            if let Some(outbound) = &rule.outbound {
-                let mut rule_sets = Vec::new();
-                if let Some(sets) = &rule.rule_set {
-                    rule_sets = sets.clone();
-                }
+        let rule_sets = self.convert_rule_sets(&mut warnings);
+        let rule_set_tags: HashSet<&str> = rule_sets.iter().map(|r| r.tag.as_str()).collect();
+        let rules = self.convert_rules(&known, &rule_set_tags, &mut warnings);
 
-                let internal_rule = crate::config::HighPerformanceRouteRule {
-                    rule_sets,
-                    outbound: outbound.clone(),
-                };
-                rules.push(internal_rule);
-            }
+        let route = &self.route;
+        if route.default_domain_resolver.is_some() {
+            warnings.push(unsupported("default_domain_resolver", "route"));
+        }
+        if route.auto_detect_interface == Some(true) {
+            warnings.push(unsupported("auto_detect_interface", "route"));
         }
+        // 没有 final 时 sing-box 使用第一个出站
+        let r#final = match route.r#final.as_str() {
+            "" => self.outbounds.first().map_or("direct", |outbound| outbound.tag.as_str()),
+            r#final => r#final,
+        };
+        let default_outbound = if known(r#final) {
+            r#final.to_string()
+        } else {
+            warnings.push(format!("final outbound {} is not supported, falling back to direct", r#final));
+            "direct".to_string()
+        };
 
         let internal_config = crate::config::Config {
             server: crate::config::ServerConfig {
-                host: std::net::IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0)),
-                port: 1080,
+                host,
+                port,
                 max_connections: 1000,
+                reject_when_full: false,
                 connection_timeout_secs: 30,
                 keep_alive_timeout_secs: 60,
+                handshake_timeout_secs: 10,
+                bind_retry_secs: 0,
+                user_routing: std::collections::HashMap::new(),
+                allow_client_outbound_selection: false,
+                auth: crate::config::SocksAuthConfig::default(),
+                dry_run: false,
+                dry_run_reply: 0x02,
+                answer_probe_requests: true,
+                profile: None,
+                linger: LingerPolicy::default(),
+                max_new_connections_per_sec: None,
+                connection_rate_policy: crate::config::ConnectionRatePolicy::Delay,
+                max_connection_queue_delay_ms: 1000,
             },
+            inbounds,
             connection_pool: crate::config::ConnectionPoolConfig {
                 max_connections_per_target: 10,
                 max_total_connections: 1000,
                 connection_timeout_secs: 30,
                 idle_timeout_secs: 300,
                 cleanup_interval_secs: 60,
+                on_exhausted: crate::config::OnExhausted::default(),
+                wait_timeout_ms: 1000,
+                auto_tune: crate::config::PoolAutoTuneConfig::default(),
+                reuse_outbound_connections: false,
             },
             dns: crate::config::DnsConfig {
-                servers: Vec::new(),
+                servers: dns_servers,
                 timeout_secs: 5,
-                enable_ipv6: true,
+                enable_ipv6,
                 cache_ttl_secs: 300,
+                on_failure: crate::config::DnsFailurePolicy::Fail,
+                serve_stale_max_secs: 3600,
+                query_log: false,
+                stats_max_domains: crate::dns_stats::DEFAULT_MAX_DOMAINS,
+                prefetch: crate::config::DnsPrefetchConfig::default(),
+                client_subnet: None,
+                fake_ip,
             },
             logging: crate::config::LoggingConfig {
-                level: "info".to_string(),
+                level,
                 structured: false,
                 file: None,
+                rotate_size_mb: 0,
+                rotate_daily: None,
+                keep_files: 7,
+                compress: false,
                 enable_metrics: true,
+                access_log: crate::config::AccessLogConfig::default(),
             },
             performance: crate::config::PerformanceConfig {
                 buffer_size: 65536,
+                adaptive_buffers: true,
+                buffer_pool: true,
+                max_pooled_buffers: crate::buffer_pool::DEFAULT_MAX_POOLED_BUFFERS,
                 tcp_nodelay: true,
                 reuse_addr: true,
+                reuse_port: false,
+                listener_shards: 1,
                 keep_alive: true,
+                tcp_user_timeout_secs: None,
+                write_stall_secs: None,
+                relay_idle_timeout_secs: None,
                 worker_threads: 0,
+                relay_backend: crate::config::RelayBackendMode::Auto,
+                relay_backend_order: vec![
+                    crate::config::RelayBackend::Splice,
+                    crate::config::RelayBackend::Uring,
+                    crate::config::RelayBackend::Buffered,
+                ],
+                uring_threads: 1,
             },
             traffic_mark: crate::config::TrafficMarkConfig::default(),
             outbounds,
+            on_outbound_error: crate::config::OutboundErrorPolicy::default(),
+            rule_sets,
             router: crate::config::RouterConfig {
-                default_outbound: self.route.r#final.clone(),
-                rules: Vec::new(), // 旧格式规则，我们使用新的高性能路由器
+                default_outbound: default_outbound.clone(),
+                rules,
+                ..crate::config::RouterConfig::default()
             },
+            profiles: std::collections::HashMap::new(),
             high_performance_router: crate::config::HighPerformanceRouterConfig {
-                default_outbound: self.route.r#final.clone(),
-                rules,
-                cache: crate::config::CacheConfig {
-                    max_size: 10000,
-                    enabled: true,
-                },
+                default_outbound: default_outbound.clone(),
+                rules: Vec::new(),
+                cache: crate::config::CacheConfig::default(),
                 rule_set_files: crate::config::RuleSetFilesConfig {
                     domain_files: Vec::new(),
                     ip_files: Vec::new(),
                 },
             },
+            watchdog: crate::config::WatchdogConfig::default(),
+            loop_protection: crate::config::LoopProtectionConfig::default(),
+            rebinding_protection: crate::config::RebindingProtectionConfig::default(),
+            negative_cache: crate::config::NegativeCacheConfig::default(),
+            pac: crate::config::PacConfig::default(),
+            capture: crate::config::CaptureConfig::default(),
+            blocked: crate::config::BlockedConfig::default(),
+            health: crate::config::HealthConfig::default(),
+            clash_api,
+        };
+
+        Ok((internal_config, warnings))
+    }
+
+    /// sing-box 日志级别映射到 env_logger 过滤级别
+    fn convert_log(&self, warnings: &mut Vec<String>) -> String {
+        let Some(log) = &self.log else {
+            return "info".to_string();
+        };
+        // 我们的日志总是带时间戳
+        if !log.timestamp {
+            warnings.push(unsupported("timestamp", "log"));
+        }
+        if log.disabled {
+            return "off".to_string();
+        }
+        match log.level.as_str() {
+            level @ ("trace" | "debug" | "info" | "warn" | "error") => level.to_string(),
+            "fatal" | "panic" => "error".to_string(),
+            other => {
+                warnings.push(format!("log level {} is not supported, using info", other));
+                "info".to_string()
+            }
+        }
+    }
+
+    /// 只转换 clash_api 的监听地址、密钥与 CORS 来源；面板文件不由我们提供
+    fn convert_experimental(&self, warnings: &mut Vec<String>) -> crate::config::ClashApiConfig {
+        let mut converted = crate::config::ClashApiConfig::default();
+        let Some(experimental) = &self.experimental else {
+            return converted;
+        };
+        if let Some(clash_api) = &experimental.clash_api {
+            match clash_api.external_controller.parse::<SocketAddr>() {
+                Ok(listen) => {
+                    converted.enabled = true;
+                    converted.listen = listen;
+                }
+                Err(_) if clash_api.external_controller.is_empty() => {}
+                Err(_) => warnings.push(format!(
+                    "clash_api external_controller {} is not supported",
+                    clash_api.external_controller
+                )),
+            }
+            converted.secret = Some(clash_api.secret.clone()).filter(|secret| !secret.is_empty());
+            converted.access_control_allow_origin = clash_api.access_control_allow_origin.clone();
+            if !clash_api.external_ui.is_empty() {
+                warnings.push(unsupported("external_ui", "clash_api"));
+            }
+            if !matches!(clash_api.default_mode.as_str(), "" | "rule") {
+                warnings.push(unsupported("default_mode", "clash_api"));
+            }
+            if clash_api.access_control_allow_private_network {
+                warnings.push(unsupported("access_control_allow_private_network", "clash_api"));
+            }
+        }
+        converted
+    }
+
+    /// fakeip 服务器转为 dns.fake_ip；cache_file 开启 store_fakeip 时映射保存到它的 path，其余缓存内容不支持
+    fn convert_fake_ip(&self, warnings: &mut Vec<String>) -> crate::config::FakeIpConfig {
+        let mut converted = crate::config::FakeIpConfig::default();
+        let fake_server = self.dns.iter().flat_map(|dns| &dns.servers).find(|server| server.server_type == "fakeip");
+        if let Some(server) = fake_server {
+            let owner = format!("dns server {}", server.tag);
+            converted.enabled = true;
+            if let Some(range) = &server.inet4_range {
+                match range.parse() {
+                    Ok(range) => converted.inet4_range = range,
+                    Err(_) => warnings.push(format!(
+                        "{} inet4_range {} is not supported, using {}",
+                        owner, range, converted.inet4_range
+                    )),
+                }
+            }
+            if server.inet6_range.is_some() {
+                warnings.push(unsupported("inet6_range", &owner));
+            }
+        }
+
+        let cache_file = self.experimental.as_ref().and_then(|e| e.cache_file.as_ref()).filter(|c| c.enabled);
+        match cache_file {
+            Some(cache_file) if cache_file.store_fakeip && converted.enabled => {
+                let path = if cache_file.path.is_empty() { "cache.db" } else { cache_file.path.as_str() };
+                converted.store_path = Some(path.to_string());
+                if cache_file.store_rdrc {
+                    warnings.push(unsupported("store_rdrc", "cache_file"));
+                }
+            }
+            Some(_) => warnings.push(unsupported("cache_file", "experimental")),
+            None => {}
+        }
+        converted
+    }
+
+    /// 只转换 UDP 服务器；`final` 指定的服务器排在解析链最前面
+    fn convert_dns(&self, warnings: &mut Vec<String>) -> (Vec<String>, bool) {
+        let Some(dns) = &self.dns else {
+            return (Vec::new(), true);
+        };
+        let mut servers: Vec<(&str, String)> = Vec::new();
+        for server in &dns.servers {
+            let owner = format!("dns server {}", server.tag);
+            // fakeip 服务器由 convert_fake_ip 处理
+            if server.server_type == "fakeip" {
+                continue;
+            }
+            if server.server_type != "udp" {
+                warnings.push(format!("{} of type {} is not supported", owner, server.server_type));
+                continue;
+            }
+            let addr = match server.server.parse::<SocketAddr>() {
+                Ok(addr) => addr,
+                Err(_) => match server.server.parse::<IpAddr>() {
+                    Ok(ip) => SocketAddr::new(ip, 53),
+                    Err(_) => {
+                        warnings.push(format!("{} address {} is not supported", owner, server.server));
+                        continue;
+                    }
+                },
+            };
+            if server.domain_resolver.is_some() {
+                warnings.push(unsupported("domain_resolver", &owner));
+            }
+            if server.detour.as_deref().is_some_and(|d| d != "direct") {
+                warnings.push(unsupported("detour", &owner));
+            }
+            servers.push((server.tag.as_str(), addr.to_string()));
+        }
+
+        match servers.iter().position(|(tag, _)| *tag == dns.r#final) {
+            Some(index) => {
+                let server = servers.remove(index);
+                servers.insert(0, server);
+            }
+            None if !dns.r#final.is_empty() => {
+                warnings.push(format!("dns final server {} is not supported", dns.r#final));
+            }
+            None => {}
+        }
+
+        let enable_ipv6 = match dns.strategy.as_str() {
+            "ipv4_only" => false,
+            "" => true,
+            other => {
+                warnings.push(format!("dns strategy {} is not supported", other));
+                true
+            }
+        };
+        (servers.into_iter().map(|(_, addr)| addr).collect(), enable_ipv6)
+    }
+
+    /// socks、mixed（只保留 SOCKS5）、tproxy、redirect 与 dns 入站转为 [[inbounds]]，第一个 socks 入站同时作为 server 的监听地址
+    fn convert_inbounds(
+        &self,
+        warnings: &mut Vec<String>,
+    ) -> Result<(ScopedIp, u16, Vec<crate::config::InboundConfig>)> {
+        let mut listen = None;
+        let mut inbounds = Vec::new();
+        for inbound in &self.inbounds {
+            let owner = format!("inbound {} {}:{}", inbound.inbound_type, inbound.listen, inbound.listen_port);
+            let kind = match inbound.inbound_type.as_str() {
+                "socks" => InboundKind::Socks5,
+                "mixed" => {
+                    warnings.push(format!("{} only accepts SOCKS5, not HTTP", owner));
+                    InboundKind::Socks5
+                }
+                "tproxy" => InboundKind::Tproxy,
+                "redirect" => InboundKind::Redirect,
+                "dns" => InboundKind::Dns,
+                _ => {
+                    warnings.push(format!("{} is not supported", owner));
+                    continue;
+                }
+            };
+            let flags = [
+                ("tcp_fast_open", inbound.tcp_fast_open),
+                ("tcp_multi_path", inbound.tcp_multi_path),
+                ("udp_fragment", inbound.udp_fragment),
+                ("sniff", inbound.sniff),
+            ];
+            for (field, value) in flags {
+                if value == Some(true) {
+                    warnings.push(unsupported(field, &owner));
+                }
+            }
+            if inbound.udp_timeout.is_some() {
+                warnings.push(unsupported("udp_timeout", &owner));
+            }
+            if inbound.listen.is_empty() {
+                warnings.push(format!("{} has no listen address", owner));
+                continue;
+            }
+            let host: ScopedIp = inbound.listen.parse()?;
+            if kind == InboundKind::Socks5 && listen.is_none() {
+                listen = Some((host, inbound.listen_port));
+            }
+            inbounds.push(crate::config::InboundConfig {
+                tag: inbound.tag.clone().unwrap_or_else(|| format!("{}-{}", inbound.inbound_type, inbound.listen_port)),
+                kind,
+                listen: host,
+                port: inbound.listen_port,
+                profile: None,
+            });
+        }
+        let (host, port) = listen.unwrap_or((IpAddr::V4(Ipv4Addr::UNSPECIFIED).into(), 1080));
+        Ok((host, port, inbounds))
+    }
+
+    /// 远程 source 与 binary (srs) 格式规则集转为 [[rule_sets]]，路由规则按 tag 引用
+    fn convert_rule_sets(&self, warnings: &mut Vec<String>) -> Vec<crate::config::RuleSetConfig> {
+        let mut rule_sets = Vec::new();
+        for rule_set in &self.route.rule_set {
+            let owner = format!("rule set {}", rule_set.tag);
+            if rule_set.rule_set_type != "remote" {
+                warnings.push(format!("{} of type {} is not supported", owner, rule_set.rule_set_type));
+                continue;
+            }
+            let format = match rule_set.format.as_str() {
+                "source" => crate::config::RuleSetFormat::Source,
+                "binary" => crate::config::RuleSetFormat::Srs,
+                other => {
+                    warnings.push(format!("{} format {} is not supported", owner, other));
+                    continue;
+                }
+            };
+            if rule_set.download_detour.as_deref().is_some_and(|d| d != "direct") {
+                warnings.push(unsupported("download_detour", &owner));
+            }
+            rule_sets.push(crate::config::RuleSetConfig {
+                tag: rule_set.tag.clone(),
+                kind: crate::config::RuleSetType::Remote,
+                path: None,
+                url: Some(rule_set.url.clone()),
+                format,
+                update_interval_secs: None,
+                signature_url: None,
+                public_key: None,
+            });
+        }
+        rule_sets
+    }
+
+    /// `route`、`reject` 与 `reject-drop` 规则转为 [[router.rules]]，`domain_suffix` 成为规则的内联列表
+    ///
+    /// 带有无法表示的条件（如 `protocol`）的规则会被跳过，而不是放宽为匹配更多流量。
+    fn convert_rules(
+        &self,
+        known: &dyn Fn(&str) -> bool,
+        rule_sets: &HashSet<&str>,
+        warnings: &mut Vec<String>,
+    ) -> Vec<RouterRuleConfig> {
+        let mut rules = Vec::new();
+        for (index, rule) in self.route.rules.iter().enumerate() {
+            let owner = format!("route rule {}", index);
+            let (outbound, action) = match (rule.action.as_str(), &rule.outbound) {
+                ("route", Some(outbound)) => (outbound.clone(), RouteAction::Forward),
+                ("route", None) => {
+                    warnings.push(format!("{} has no outbound", owner));
+                    continue;
+                }
+                ("reject", _) if rule.method.as_deref() == Some("drop") => (String::new(), RouteAction::Drop),
+                ("reject", _) => (String::new(), RouteAction::Reject),
+                ("reject-drop", _) => (String::new(), RouteAction::Drop),
+                (other, _) => {
+                    warnings.push(format!("action {} on {} is not supported", other, owner));
+                    continue;
+                }
+            };
+            if rule.method.as_deref().is_some_and(|method| rule.action != "reject" || !matches!(method, "default" | "drop")) {
+                warnings.push(unsupported("method", &owner));
+            }
+            if rule.protocol.is_some() {
+                warnings.push(unsupported("protocol", &owner));
+                continue;
+            }
+            if action.is_forward() && !known(&outbound) {
+                warnings.push(format!("{} outbound {} is not supported", owner, outbound));
+                continue;
+            }
+
+            let mut tags = Vec::new();
+            for tag in rule.rule_set.iter().flatten() {
+                if rule_sets.contains(tag.as_str()) {
+                    tags.push(tag.clone());
+                } else {
+                    warnings.push(format!("rule set {} on {} is not supported", tag, owner));
+                }
+            }
+            let domain_suffix = rule.domain_suffix.clone().unwrap_or_default();
+            if tags.is_empty() && domain_suffix.is_empty() {
+                warnings.push(format!("{} has no supported conditions", owner));
+                continue;
+            }
+            rules.push(RouterRuleConfig {
+                outbound,
+                action,
+                outbound_chain: Vec::new(),
+                rule_sets: tags,
+                domains: DomainLists { domain_suffix, ..DomainLists::default() },
+                ip_cidr: Vec::new(),
+                dscp: None,
+                latency_mode: false,
+                rewrite_to: None,
+                port: Vec::new(),
+                port_range: Vec::new(),
+                network: None,
+                source_ip_cidr: Vec::new(),
+                inbound: Vec::new(),
+                ip_geoip: Vec::new(),
+            });
+        }
+        rules
+    }
+}
+
+/// Parse a sing-box (Go) duration such as `300ms`, `15s` or `1m30s`
+fn parse_duration(text: &str) -> Option<Duration> {
+    let mut total = Duration::ZERO;
+    let mut rest = text.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let value: u64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let unit = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        total += match &rest[..unit] {
+            "ms" => Duration::from_millis(value),
+            "s" => Duration::from_secs(value),
+            "m" => Duration::from_secs(value * 60),
+            "h" => Duration::from_secs(value * 3600),
+            _ => return None,
+        };
+        rest = &rest[unit..];
+    }
+    Some(total)
+}
+
+fn unsupported(field: &str, owner: &str) -> String {
+    format!("field {} on {} is not supported", field, owner)
+}
+
+/// 转换一个可转换类型的出站，记录其上不被支持的字段
+fn convert_outbound(
+    outbound: &OutboundConfig,
+    known: &dyn Fn(&str) -> bool,
+    warnings: &mut Vec<String>,
+) -> crate::config::OutboundConfig {
+    let owner = format!("outbound {}", outbound.tag);
+    let outbound_type = outbound.outbound_type.as_str();
+    let dials_server = matches!(outbound_type, "socks" | "http" | "shadowsocks" | "vless");
+    let is_set = |value: &Option<String>| value.as_deref().is_some_and(|v| !v.is_empty());
+
+    let mut ignored = Vec::new();
+    if !dials_server && outbound.server.is_some() {
+        ignored.push("server");
+    }
+    if !dials_server && outbound.server_port.is_some() {
+        ignored.push("server_port");
+    }
+    if outbound_type != "shadowsocks" && outbound.password.is_some() {
+        ignored.push("password");
+    }
+    if outbound_type != "shadowsocks" && outbound.method.is_some() {
+        ignored.push("method");
+    }
+    if outbound_type != "vless" && outbound.uuid.is_some() {
+        ignored.push("uuid");
+    }
+    if is_set(&outbound.flow) {
+        ignored.push("flow");
+    }
+    if is_set(&outbound.packet_encoding) {
+        ignored.push("packet_encoding");
+    }
+    // 只有 urltest 组测速
+    let url_test = outbound_type == "urltest";
+    if !url_test && outbound.url.is_some() {
+        ignored.push("url");
+    }
+    if !url_test && outbound.interval.is_some() {
+        ignored.push("interval");
+    }
+    if !url_test && outbound.tolerance.is_some() {
+        ignored.push("tolerance");
+    }
+    if outbound_type != "selector" && outbound.default.is_some() {
+        ignored.push("default");
+    }
+    if outbound.interrupt_exist_connections == Some(true) {
+        ignored.push("interrupt_exist_connections");
+    }
+    if !is_group_type(outbound_type) && outbound.outbounds.is_some() {
+        ignored.push("outbounds");
+    }
+    if let Some(tls) = outbound.tls.as_ref().filter(|t| t.enabled) {
+        if outbound_type != "vless" {
+            ignored.push("tls");
+        } else {
+            if tls.disable_sni == Some(true) {
+                ignored.push("tls.disable_sni");
+            }
+            if tls.alpn.as_ref().is_some_and(|a| !a.is_empty()) {
+                ignored.push("tls.alpn");
+            }
+            if tls.utls.as_ref().is_some_and(|u| u.enabled) {
+                ignored.push("tls.utls");
+            }
+            if tls.reality.as_ref().is_some_and(|r| r.enabled) {
+                ignored.push("tls.reality");
+            }
+        }
+    }
+    if outbound.transport.is_some() {
+        ignored.push("transport");
+    }
+    if !matches!(outbound_type, "http" | "vless") && outbound.override_host_header.is_some() {
+        ignored.push("override_host_header");
+    }
+    let udp_over_tcp = outbound.udp_over_tcp == Some(true);
+    if udp_over_tcp && !matches!(outbound_type, "socks" | "vless") {
+        ignored.push("udp_over_tcp");
+    }
+    warnings.extend(ignored.into_iter().map(|field| unsupported(field, &owner)));
+
+    let server_addr = |default_port: u16| {
+        format!(
+            "{}:{}",
+            outbound.server.as_deref().unwrap_or("127.0.0.1"),
+            outbound.server_port.unwrap_or(default_port)
+        )
+    };
+    let kind = match outbound_type {
+        "direct" => crate::config::OutboundType::Direct,
+        "block" => crate::config::OutboundType::Blackhole,
+        "socks" => crate::config::OutboundType::Socks5 { address: server_addr(1080) },
+        "http" => crate::config::OutboundType::Http {
+            address: server_addr(8080),
+            override_host_header: outbound.override_host_header.clone(),
+        },
+        "shadowsocks" => crate::config::OutboundType::Shadowsocks {
+            address: server_addr(8388),
+            method: outbound.method.clone().unwrap_or_default(),
+            password: outbound.password.clone().unwrap_or_default(),
+        },
+        "vless" => {
+            let tls = outbound.tls.as_ref();
+            crate::config::OutboundType::Vless {
+                address: server_addr(443),
+                uuid: outbound.uuid.clone().unwrap_or_default(),
+                tls: tls.is_some_and(|t| t.enabled),
+                server_name: tls.and_then(|t| t.server_name.clone()),
+                override_sni: tls.and_then(|t| t.override_sni.clone()),
+                override_host_header: outbound.override_host_header.clone(),
+                insecure: tls.and_then(|t| t.insecure).unwrap_or(false),
+                session_cache_size: tls
+                    .and_then(|t| t.session_cache_size)
+                    .unwrap_or(crate::tls::DEFAULT_SESSION_CACHE_SIZE),
+                early_data: tls.and_then(|t| t.early_data).unwrap_or(false),
+            }
+        }
+        _ => {
+            let mut members = Vec::new();
+            for member in outbound.outbounds.iter().flatten() {
+                if known(member) {
+                    members.push(member.clone());
+                } else {
+                    warnings.push(format!("outbound group {} member {} is not supported", outbound.tag, member));
+                }
+            }
+            if url_test {
+                let interval_secs = match outbound.interval.as_deref().map(|i| (i, parse_duration(i))) {
+                    Some((_, Some(interval))) if interval.as_secs() > 0 => interval.as_secs(),
+                    Some((interval, _)) => {
+                        warnings.push(format!("{} has an invalid interval {}, using the default", owner, interval));
+                        crate::config::default_url_test_interval_secs()
+                    }
+                    None => crate::config::default_url_test_interval_secs(),
+                };
+                crate::config::OutboundType::UrlTest {
+                    outbounds: members,
+                    url: outbound.url.clone().unwrap_or_else(crate::config::default_url_test_url),
+                    interval_secs,
+                    tolerance_ms: outbound
+                        .tolerance
+                        .map_or_else(crate::config::default_url_test_tolerance_ms, u64::from),
+                }
+            } else {
+                // 默认成员不可用时退回第一个成员
+                let default = outbound.default.clone().filter(|d| members.contains(d));
+                crate::config::OutboundType::Selector { outbounds: members, default }
+            }
+        }
+    };
+
+    crate::config::OutboundConfig {
+        name: outbound.tag.clone(),
+        kind,
+        tcp_user_timeout_secs: None,
+        udp_over_tcp: udp_over_tcp && matches!(outbound_type, "socks" | "vless"),
+        dscp: None,
+        latency_mode: false,
+        egress_hint_subnet: None,
+        routing_mark: outbound.routing_mark,
+        tcp_mss: None,
+        ports: Vec::new(),
+        port_strategy: PortStrategy::default(),
+        tls_fragment: TlsFragmentConfig::default(),
+        linger: LingerPolicy::default(),
+        max_connections_per_sec: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outbound(tag: &str, outbound_type: &str, members: Option<&[&str]>) -> OutboundConfig {
+        OutboundConfig {
+            tag: tag.to_string(),
+            outbound_type: outbound_type.to_string(),
+            server: Some("127.0.0.1".to_string()),
+            server_port: Some(1081),
+            password: None,
+            method: None,
+            uuid: None,
+            flow: None,
+            packet_encoding: None,
+            routing_mark: None,
+            url: None,
+            interval: None,
+            tolerance: None,
+            interrupt_exist_connections: None,
+            outbounds: members.map(|m| m.iter().map(|s| s.to_string()).collect()),
+            default: None,
+            tls: None,
+            transport: None,
+            override_host_header: None,
+            udp_over_tcp: None,
+        }
+    }
+
+    fn rule(outbound: &str) -> RouteRule {
+        RouteRule {
+            action: "route".to_string(),
+            method: None,
+            protocol: None,
+            rule_set: Some(vec![format!("{}-set", outbound)]),
+            domain_suffix: None,
+            outbound: Some(outbound.to_string()),
+        }
+    }
+
+    fn rule_set(tag: &str) -> RuleSetConfig {
+        RuleSetConfig {
+            tag: tag.to_string(),
+            rule_set_type: "remote".to_string(),
+            url: format!("https://rules.example/{}.json", tag),
+            format: "source".to_string(),
+            download_detour: None,
+        }
+    }
+
+    #[test]
+    fn test_implicit_outbounds_mapped() {
+        let config = RonConfig {
+            log: None,
+            experimental: None,
+            dns: None,
+            inbounds: Vec::new(),
+            outbounds: vec![
+                outbound("anytls-out", "anytls", None),
+                outbound("socks-out", "socks", None),
+                outbound("dns-out", "dns", None),
+                outbound("auto", "urltest", Some(&["anytls-out", "socks-out"])),
+            ],
+            route: RouteConfig {
+                rules: vec![rule("block"), rule("dns-out"), rule("auto")],
+                rule_set: ["block", "dns-out", "auto"].iter().map(|o| rule_set(&format!("{}-set", o))).collect(),
+                default_domain_resolver: None,
+                auto_detect_interface: None,
+                r#final: "direct".to_string(),
+            },
         };
 
-        Ok(internal_config)
+        let internal = config.to_internal_config().unwrap();
+        assert!(internal.validate().is_ok());
+
+        let names: Vec<&str> = internal.outbounds.iter().map(|o| o.name.as_str()).collect();
+        assert_eq!(names, vec!["socks-out", "auto"]);
+        assert_eq!(internal.outbounds[1].members(), ["socks-out".to_string()]);
+
+        let routed: Vec<&str> = internal.router.rules.iter().map(|r| r.outbound.as_str()).collect();
+        assert_eq!(routed, vec!["block", "auto"]);
+    }
+
+    /// Every key in `expected` must be present in `actual` with a matching value;
+    /// arrays must match element by element
+    fn assert_subset(path: &str, expected: &serde_json::Value, actual: &serde_json::Value) {
+        use serde_json::Value;
+        match (expected, actual) {
+            (Value::Object(expected), Value::Object(actual)) => {
+                for (key, value) in expected {
+                    let child = format!("{}.{}", path, key);
+                    let actual = actual.get(key).unwrap_or_else(|| panic!("{}: missing", child));
+                    assert_subset(&child, value, actual);
+                }
+            }
+            (Value::Array(expected), Value::Array(actual)) => {
+                assert_eq!(expected.len(), actual.len(), "{}: {:?}", path, actual);
+                for (index, (expected, actual)) in expected.iter().zip(actual).enumerate() {
+                    assert_subset(&format!("{}[{}]", path, index), expected, actual);
+                }
+            }
+            _ => assert_eq!(expected, actual, "{}", path),
+        }
+    }
+
+    struct Case {
+        name: &'static str,
+        ron: &'static str,
+        expected: serde_json::Value,
+        warnings: &'static [&'static str],
+    }
+
+    fn cases() -> Vec<Case> {
+        use serde_json::json;
+        vec![
+            Case {
+                name: "minimal",
+                ron: r#"(inbounds: [], outbounds: [(tag: "direct", type: "direct")], route: (rules: [], rule_set: [], final: "direct"))"#,
+                expected: json!({
+                    "server": {"port": 1080},
+                    "logging": {"level": "info"},
+                    "dns": {"servers": [], "enable_ipv6": true},
+                    "outbounds": [{"name": "direct", "type": "direct", "routing_mark": null}],
+                    "rule_sets": [],
+                    "router": {"default_outbound": "direct", "rules": []},
+                }),
+                warnings: &[],
+            },
+            Case {
+                name: "log and experimental",
+                ron: r#"(
+                    log: (disabled: false, timestamp: false, level: "fatal"),
+                    experimental: (
+                        clash_api: (
+                            external_controller: "0.0.0.0:9090",
+                            external_ui: "ui",
+                            external_ui_download_url: "",
+                            external_ui_download_detour: "direct",
+                            secret: "",
+                            default_mode: "rule",
+                            access_control_allow_origin: ["*"],
+                            access_control_allow_private_network: false,
+                        ),
+                        cache_file: (
+                            enabled: true,
+                            path: "cache.db",
+                            cache_id: "",
+                            store_fakeip: true,
+                            store_rdrc: true,
+                            rdrc_timeout: "7d",
+                        ),
+                    ),
+                    inbounds: [], outbounds: [(tag: "direct", type: "direct")], route: (rules: [], rule_set: [], final: "direct"),
+                )"#,
+                expected: json!({
+                    "logging": {"level": "error"},
+                    "clash_api": {"enabled": true, "listen": "0.0.0.0:9090", "secret": null, "access_control_allow_origin": ["*"]},
+                }),
+                warnings: &[
+                    "field timestamp on log is not supported",
+                    "field external_ui on clash_api is not supported",
+                    "field cache_file on experimental is not supported",
+                ],
+            },
+            Case {
+                name: "log disabled",
+                ron: r#"(
+                    log: (disabled: true, timestamp: true, level: "debug"),
+                    inbounds: [], outbounds: [(tag: "direct", type: "direct")], route: (rules: [], rule_set: [], final: "direct"),
+                )"#,
+                expected: json!({"logging": {"level": "off"}}),
+                warnings: &[],
+            },
+            Case {
+                name: "dns servers",
+                ron: r#"(
+                    dns: (
+                        servers: [
+                            (tag: "google", type: "https", server: "dns.google", domain_resolver: "cloudflare", detour: "proxy"),
+                            (tag: "cloudflare", type: "udp", server: "1.1.1.1"),
+                            (tag: "quad9", type: "udp", server: "[2620:fe::fe]:5353", detour: "proxy"),
+                            (tag: "named", type: "udp", server: "dns.example", domain_resolver: "cloudflare"),
+                        ],
+                        strategy: "ipv4_only",
+                        final: "quad9",
+                    ),
+                    inbounds: [], outbounds: [(tag: "direct", type: "direct")], route: (rules: [], rule_set: [], final: "direct"),
+                )"#,
+                expected: json!({"dns": {"servers": ["[2620:fe::fe]:5353", "1.1.1.1:53"], "enable_ipv6": false}}),
+                warnings: &[
+                    "dns server google of type https is not supported",
+                    "field detour on dns server quad9 is not supported",
+                    "dns server named address dns.example is not supported",
+                ],
+            },
+            Case {
+                name: "dns strategy and final",
+                ron: r#"(
+                    dns: (servers: [(tag: "cloudflare", type: "udp", server: "1.1.1.1")], strategy: "prefer_ipv6", final: "local"),
+                    inbounds: [], outbounds: [(tag: "direct", type: "direct")], route: (rules: [], rule_set: [], final: "direct"),
+                )"#,
+                expected: json!({"dns": {"servers": ["1.1.1.1:53"], "enable_ipv6": true}}),
+                warnings: &[
+                    "dns final server local is not supported",
+                    "dns strategy prefer_ipv6 is not supported",
+                ],
+            },
+            Case {
+                name: "fake-ip",
+                ron: r#"(
+                    experimental: (
+                        cache_file: (enabled: true, path: "fakeip.db", cache_id: "", store_fakeip: true, store_rdrc: true, rdrc_timeout: ""),
+                    ),
+                    dns: (
+                        servers: [
+                            (tag: "fake", type: "fakeip", inet4_range: "198.18.0.0/16", inet6_range: "fc00::/18"),
+                            (tag: "cloudflare", type: "udp", server: "1.1.1.1"),
+                        ],
+                        strategy: "ipv4_only",
+                        final: "cloudflare",
+                    ),
+                    inbounds: [(tag: "dns-in", type: "dns", listen: "0.0.0.0", listen_port: 5353)],
+                    outbounds: [(tag: "direct", type: "direct")],
+                    route: (rules: [], rule_set: [], final: "direct"),
+                )"#,
+                expected: json!({
+                    "dns": {
+                        "servers": ["1.1.1.1:53"],
+                        "fake_ip": {"enabled": true, "inet4_range": "198.18.0.0/16", "store_path": "fakeip.db"},
+                    },
+                    "inbounds": [{"tag": "dns-in", "type": "dns", "listen": "0.0.0.0", "port": 5353}],
+                }),
+                warnings: &[
+                    "field inet6_range on dns server fake is not supported",
+                    "field store_rdrc on cache_file is not supported",
+                ],
+            },
+            Case {
+                name: "inbounds",
+                ron: r#"(
+                    inbounds: [
+                        (type: "tproxy", listen: "0.0.0.0", listen_port: 12345, tcp_fast_open: true),
+                        (tag: "nat", type: "redirect", listen: "::", listen_port: 12346),
+                        (type: "socks", listen: "127.0.0.1", listen_port: 1081, tcp_fast_open: false, udp_timeout: "300s", sniff: true),
+                        (type: "socks", listen: "::", listen_port: 1082),
+                    ],
+                    outbounds: [(tag: "direct", type: "direct")],
+                    route: (rules: [], rule_set: [], final: "direct"),
+                )"#,
+                expected: json!({
+                    "server": {"port": 1081},
+                    "inbounds": [
+                        {"tag": "tproxy-12345", "type": "tproxy", "listen": "0.0.0.0", "port": 12345},
+                        {"tag": "nat", "type": "redirect", "listen": "::", "port": 12346},
+                        {"tag": "socks-1081", "type": "socks", "listen": "127.0.0.1", "port": 1081},
+                        {"tag": "socks-1082", "type": "socks", "listen": "::", "port": 1082},
+                    ],
+                }),
+                warnings: &[
+                    "field tcp_fast_open on inbound tproxy 0.0.0.0:12345 is not supported",
+                    "field sniff on inbound socks 127.0.0.1:1081 is not supported",
+                    "field udp_timeout on inbound socks 127.0.0.1:1081 is not supported",
+                ],
+            },
+            Case {
+                name: "direct and block",
+                ron: r#"(
+                    inbounds: [],
+                    outbounds: [
+                        (tag: "direct", type: "direct", routing_mark: 255),
+                        (tag: "deny", type: "block", server: "127.0.0.1", udp_over_tcp: true),
+                    ],
+                    route: (rules: [], rule_set: [], final: "direct"),
+                )"#,
+                expected: json!({"outbounds": [
+                    {"name": "direct", "type": "direct", "routing_mark": 255},
+                    {"name": "deny", "type": "blackhole", "routing_mark": null, "udp_over_tcp": false},
+                ]}),
+                warnings: &[
+                    "field server on outbound deny is not supported",
+                    "field udp_over_tcp on outbound deny is not supported",
+                ],
+            },
+            Case {
+                name: "socks and http",
+                ron: r#"(
+                    inbounds: [],
+                    outbounds: [
+                        (tag: "union", type: "socks", server: "127.0.0.1", server_port: 1183, routing_mark: 255, udp_over_tcp: true, password: "secret"),
+                        (tag: "corp", type: "http", server: "10.0.0.2", override_host_header: "cdn.example", tls: (enabled: true)),
+                        (tag: "fallback", type: "socks"),
+                    ],
+                    route: (rules: [], rule_set: [], final: "direct"),
+                )"#,
+                expected: json!({"outbounds": [
+                    {"name": "union", "type": "socks5", "address": "127.0.0.1:1183", "udp_over_tcp": true, "routing_mark": 255},
+                    {"name": "corp", "type": "http", "address": "10.0.0.2:8080", "override_host_header": "cdn.example"},
+                    {"name": "fallback", "type": "socks5", "address": "127.0.0.1:1080"},
+                ]}),
+                warnings: &[
+                    "field password on outbound union is not supported",
+                    "field tls on outbound corp is not supported",
+                ],
+            },
+            Case {
+                name: "shadowsocks",
+                ron: r#"(
+                    inbounds: [],
+                    outbounds: [
+                        (tag: "ss", type: "shadowsocks", server: "192.0.2.9", server_port: 8389, method: "chacha20-ietf-poly1305", password: "secret"),
+                        (tag: "ss-default-port", type: "shadowsocks", server: "192.0.2.9", method: "aes-256-gcm", password: "secret"),
+                        (tag: "plain", type: "socks", method: "aes-256-gcm"),
+                    ],
+                    route: (rules: [], rule_set: [], final: "ss"),
+                )"#,
+                expected: json!({
+                    "outbounds": [
+                        {
+                            "name": "ss", "type": "shadowsocks", "address": "192.0.2.9:8389",
+                            "method": "chacha20-ietf-poly1305", "password": "secret",
+                        },
+                        {
+                            "name": "ss-default-port", "type": "shadowsocks", "address": "192.0.2.9:8388",
+                            "method": "aes-256-gcm", "password": "secret",
+                        },
+                        {"name": "plain", "type": "socks5", "address": "127.0.0.1:1080"},
+                    ],
+                    "router": {"default_outbound": "ss"},
+                }),
+                warnings: &["field method on outbound plain is not supported"],
+            },
+            Case {
+                name: "vless",
+                ron: r#"(
+                    inbounds: [],
+                    outbounds: [
+                        (
+                            tag: "grpc", type: "vless", server: "192.0.2.1", server_port: 8443,
+                            uuid: "the-uuid", flow: "", packet_encoding: "xudp", routing_mark: 7,
+                            tls: (
+                                enabled: true, server_name: "sni.example", insecure: true, early_data: true,
+                                alpn: ["h2"], utls: (enabled: false, fingerprint: "chrome"),
+                                reality: (enabled: true, public_key: "key", short_id: "id"),
+                            ),
+                            transport: (type: "grpc", service_name: "svc"),
+                        ),
+                        (tag: "plain", type: "vless", uuid: "other", tls: (enabled: false, alpn: ["h2"])),
+                    ],
+                    route: (rules: [], rule_set: [], final: "grpc"),
+                )"#,
+                expected: json!({
+                    "outbounds": [
+                        {
+                            "name": "grpc", "type": "vless", "address": "192.0.2.1:8443", "uuid": "the-uuid",
+                            "tls": true, "server_name": "sni.example", "insecure": true, "early_data": true,
+                            "routing_mark": 7,
+                        },
+                        {"name": "plain", "type": "vless", "address": "127.0.0.1:443", "tls": false, "server_name": null},
+                    ],
+                    "router": {"default_outbound": "grpc"},
+                }),
+                warnings: &[
+                    "field packet_encoding on outbound grpc is not supported",
+                    "field tls.alpn on outbound grpc is not supported",
+                    "field tls.reality on outbound grpc is not supported",
+                    "field transport on outbound grpc is not supported",
+                ],
+            },
+            Case {
+                name: "groups",
+                ron: r#"(
+                    inbounds: [],
+                    outbounds: [
+                        (
+                            tag: "auto", type: "balancer", url: "https://www.google.com/generate_204", interval: "15s",
+                            tolerance: 50, interrupt_exist_connections: true, outbounds: ["anytls-out", "socks-out", "direct"],
+                        ),
+                        (tag: "pick", type: "selector", outbounds: ["anytls-out"]),
+                        (tag: "anytls-out", type: "anytls", server: "server.example", password: "secret"),
+                        (tag: "socks-out", type: "socks", outbounds: ["direct"]),
+                    ],
+                    route: (rules: [], rule_set: [], final: "pick"),
+                )"#,
+                expected: json!({
+                    "outbounds": [
+                        {"name": "auto", "type": "selector", "outbounds": ["socks-out", "direct"], "default": null},
+                        {"name": "socks-out", "type": "socks5"},
+                    ],
+                    "router": {"default_outbound": "direct"},
+                }),
+                warnings: &[
+                    "field url on outbound auto is not supported",
+                    "field interval on outbound auto is not supported",
+                    "field tolerance on outbound auto is not supported",
+                    "field interrupt_exist_connections on outbound auto is not supported",
+                    "outbound group auto member anytls-out is not supported",
+                    "outbound group pick has no supported members",
+                    "outbound anytls-out of type anytls is not supported",
+                    "field outbounds on outbound socks-out is not supported",
+                    "final outbound pick is not supported, falling back to direct",
+                ],
+            },
+            Case {
+                name: "url-test groups",
+                ron: r#"(
+                    inbounds: [],
+                    outbounds: [
+                        (
+                            tag: "auto", type: "urltest", url: "http://cp.example/generate_204", interval: "1m30s",
+                            tolerance: 80, outbounds: ["socks-out", "direct"],
+                        ),
+                        (tag: "slow", type: "urltest", interval: "soon", outbounds: ["direct"]),
+                        (tag: "pick", type: "selector", default: "auto", outbounds: ["auto", "socks-out"]),
+                        (tag: "socks-out", type: "socks", default: "direct"),
+                    ],
+                    route: (rules: [], rule_set: [], final: "pick"),
+                )"#,
+                expected: json!({
+                    "outbounds": [
+                        {
+                            "name": "auto", "type": "urltest", "outbounds": ["socks-out", "direct"],
+                            "url": "http://cp.example/generate_204", "interval_secs": 90, "tolerance_ms": 80,
+                        },
+                        {
+                            "name": "slow", "type": "urltest", "outbounds": ["direct"],
+                            "url": "https://www.gstatic.com/generate_204", "interval_secs": 180, "tolerance_ms": 50,
+                        },
+                        {"name": "pick", "type": "selector", "outbounds": ["auto", "socks-out"], "default": "auto"},
+                        {"name": "socks-out", "type": "socks5"},
+                    ],
+                    "router": {"default_outbound": "pick"},
+                }),
+                warnings: &[
+                    "outbound slow has an invalid interval soon, using the default",
+                    "field default on outbound socks-out is not supported",
+                ],
+            },
+            Case {
+                name: "rules",
+                ron: r#"(
+                    inbounds: [],
+                    outbounds: [(tag: "proxy", type: "socks", server: "10.0.0.1")],
+                    route: (
+                        rules: [
+                            (action: "sniff"),
+                            (action: "hijack-dns", protocol: "dns"),
+                            (action: "reject", rule_set: ["ads"]),
+                            (action: "route", rule_set: ["cn", "geoip-cn"], domain_suffix: ["quay.io"], outbound: "direct"),
+                            (action: "route", rule_set: ["geoip-cn"], outbound: "proxy"),
+                            (action: "route", protocol: "quic", outbound: "block"),
+                            (action: "route", domain_suffix: ["example.com"], outbound: "anytls-out"),
+                            (action: "route", rule_set: ["gfw"]),
+                            (action: "route", rule_set: ["gfw"], outbound: "proxy"),
+                            (action: "reject", method: "drop", domain_suffix: ["scan.example"]),
+                        ],
+                        rule_set: [
+                            (tag: "ads", type: "remote", url: "https://rules.example/ads.json", format: "source", download_detour: "direct"),
+                            (tag: "cn", type: "remote", url: "https://rules.example/cn.json", format: "source", download_detour: "proxy"),
+                            (tag: "geoip-cn", type: "remote", url: "https://rules.example/geoip-cn.srs", format: "binary"),
+                            (tag: "gfw", type: "local", url: "", format: "source"),
+                        ],
+                        default_domain_resolver: "local",
+                        auto_detect_interface: true,
+                        final: "proxy",
+                    ),
+                )"#,
+                expected: json!({
+                    "rule_sets": [
+                        {"tag": "ads", "type": "remote", "url": "https://rules.example/ads.json", "format": "source"},
+                        {"tag": "cn", "type": "remote", "url": "https://rules.example/cn.json", "format": "source"},
+                        {"tag": "geoip-cn", "type": "remote", "url": "https://rules.example/geoip-cn.srs", "format": "srs"},
+                    ],
+                    "router": {
+                        "default_outbound": "proxy",
+                        "rules": [
+                            {"outbound": "", "action": "reject", "rule_sets": ["ads"], "domains": {"domain_suffix": []}},
+                            {"outbound": "direct", "rule_sets": ["cn", "geoip-cn"], "domains": {"domain_suffix": ["quay.io"]}},
+                            {"outbound": "proxy", "rule_sets": ["geoip-cn"]},
+                            {"outbound": "", "action": "reject-drop", "rule_sets": [], "domains": {"domain_suffix": ["scan.example"]}},
+                        ],
+                    },
+                    "high_performance_router": {"default_outbound": "proxy", "rules": []},
+                }),
+                warnings: &[
+                    "field download_detour on rule set cn is not supported",
+                    "rule set gfw of type local is not supported",
+                    "action sniff on route rule 0 is not supported",
+                    "action hijack-dns on route rule 1 is not supported",
+                    "field protocol on route rule 5 is not supported",
+                    "route rule 6 outbound anytls-out is not supported",
+                    "route rule 7 has no outbound",
+                    "rule set gfw on route rule 8 is not supported",
+                    "route rule 8 has no supported conditions",
+                    "field default_domain_resolver on route is not supported",
+                    "field auto_detect_interface on route is not supported",
+                ],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_ron_fixtures() {
+        for case in cases() {
+            let ron = format!("#![enable(implicit_some)]\n{}", case.ron);
+            let config: RonConfig = ron::from_str(&ron).unwrap_or_else(|e| panic!("{}: {}", case.name, e));
+            let (internal, warnings) = config.to_internal_config_with_warnings().unwrap();
+            assert!(internal.validate().is_ok(), "{}: {:?}", case.name, internal.validate());
+            assert_eq!(warnings, case.warnings, "{}", case.name);
+            assert_subset(case.name, &case.expected, &serde_json::to_value(&internal).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_example_config() {
+        let content = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/examples/config.json")).unwrap();
+        let config: RonConfig = serde_json::from_str(&content).unwrap();
+        // 示例中的服务器地址是占位域名，不做 validate
+        let (internal, warnings) = config.to_internal_config_with_warnings().unwrap();
+
+        let outbounds: Vec<(&str, Option<u32>)> =
+            internal.outbounds.iter().map(|o| (o.name.as_str(), o.routing_mark)).collect();
+        assert_eq!(outbounds, vec![("auto-gateway", None), ("outbound-grpc", Some(255)), ("union-traffic", Some(255)), ("direct", Some(255))]);
+        assert_eq!(internal.logging.level, "warn");
+        assert!(internal.clash_api.enabled && internal.clash_api.secret.is_none());
+        assert_eq!(internal.dns.servers, vec!["1.1.1.1:53".to_string()]);
+        // binary 规则集按 srs 格式下载，规则按 tag 引用
+        assert_eq!(internal.rule_sets.len(), 8);
+        assert!(internal.rule_sets.iter().all(|r| r.format == crate::config::RuleSetFormat::Srs));
+        let rules: Vec<(&str, &[String])> =
+            internal.router.rules.iter().map(|r| (r.outbound.as_str(), r.rule_sets.as_slice())).collect();
+        assert_eq!(rules.len(), 4);
+        assert_eq!(internal.router.rules[0].route_action(), RouteAction::Reject);
+        assert_eq!(rules[0], ("", &["Category-Ads".to_string()][..]));
+        assert_eq!(rules[1].1.len(), 4);
+        assert_eq!(rules[2].0, "union-traffic");
+        assert_eq!(rules[3], ("auto-gateway", &["GeoSite-Gfw".to_string()][..]));
+        assert_eq!(internal.router.rules[2].domains.domain_suffix, config.route.rules[4].domain_suffix.clone().unwrap());
+        assert_eq!(
+            warnings,
+            [
+                "field external_ui on clash_api is not supported",
+                "field cache_file on experimental is not supported",
+                "dns server google of type https is not supported",
+                "dns final server local is not supported",
+                "field tcp_fast_open on inbound tproxy 0.0.0.0:12345 is not supported",
+                "field udp_fragment on inbound tproxy 0.0.0.0:12345 is not supported",
+                "field udp_timeout on inbound tproxy 0.0.0.0:12345 is not supported",
+                "field sniff on inbound socks 0.0.0.0:1080 is not supported",
+                "field url on outbound auto-gateway is not supported",
+                "field interval on outbound auto-gateway is not supported",
+                "field tolerance on outbound auto-gateway is not supported",
+                "field interrupt_exist_connections on outbound auto-gateway is not supported",
+                "outbound group auto-gateway member outbound-anytls is not supported",
+                "outbound outbound-anytls of type anytls is not supported",
+                "field tls.alpn on outbound outbound-grpc is not supported",
+                "field transport on outbound outbound-grpc is not supported",
+                "action sniff on route rule 0 is not supported",
+                "action hijack-dns on route rule 1 is not supported",
+                "field default_domain_resolver on route is not supported",
+                "field auto_detect_interface on route is not supported",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ron_file_loaded_as_config() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/simple_config.ron");
+        let config = crate::config::Config::from_file(path).unwrap();
+        config.validate().unwrap();
+        let inbounds: Vec<(&str, SocketAddr)> = config.inbounds.iter().map(|i| (i.tag.as_str(), i.bind_addr())).collect();
+        assert_eq!(inbounds, vec![("socks-in", "127.0.0.1:1080".parse().unwrap())]);
+
+        let router = crate::routing::build_router(&config).await.unwrap();
+        assert_eq!(router.route_domain("www.youtube.com").outbound, "proxy");
+        assert_eq!(router.route_domain("ad.doubleclick.net").action, RouteAction::Reject);
+        assert_eq!(router.route_domain("example.com").outbound, "direct");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_sing_box_json_excerpt() {
+        // 未知字段（ntp、tun 入站的 stack、multiplex 等）忽略，缺省字段取 sing-box 默认值
+        let json = r#"{
+            "log": {"level": "warn"},
+            "ntp": {"enabled": true, "server": "time.apple.com"},
+            "dns": {"servers": [{"tag": "local", "type": "udp", "server": "223.5.5.5"}]},
+            "inbounds": [
+                {"type": "mixed", "tag": "mixed-in", "listen": "127.0.0.1", "listen_port": 2080, "set_system_proxy": false},
+                {"type": "tun", "tag": "tun-in", "address": ["172.19.0.1/30"], "auto_route": true, "stack": "mixed"}
+            ],
+            "outbounds": [
+                {
+                    "type": "vless", "tag": "proxy", "server": "203.0.113.10", "server_port": 443,
+                    "uuid": "bf000d23-0752-40b4-affe-68f7707a9661", "flow": "xtls-rprx-vision",
+                    "domain_strategy": "prefer_ipv4",
+                    "multiplex": {"enabled": false},
+                    "tls": {
+                        "enabled": true, "server_name": "www.example.com",
+                        "utls": {"enabled": true, "fingerprint": "chrome"},
+                        "reality": {"enabled": true, "public_key": "jNXHt1yRo0vDuchQlIP6Z0ZvjT3KtzVI-T4E7RoLJS0", "short_id": "0123456789abcdef"}
+                    }
+                },
+                {"type": "socks", "tag": "upstream", "server": "127.0.0.1", "server_port": 1081, "version": "5"},
+                {"type": "direct", "tag": "direct"}
+            ],
+            "route": {
+                "rules": [
+                    {"rule_set": ["geosite-cn"], "outbound": "direct"},
+                    {"domain_suffix": [".corp.example"], "outbound": "upstream"}
+                ],
+                "rule_set": [
+                    {"tag": "geosite-cn", "type": "remote", "format": "binary", "url": "https://example.com/geosite-cn.srs", "download_detour": "direct"}
+                ]
+            }
+        }"#;
+        let config = RonConfig::from_json_str(json).unwrap();
+        let (internal, warnings) = config.to_internal_config_with_warnings().unwrap();
+        internal.validate().unwrap();
+
+        assert_eq!(internal.logging.level, "warn");
+        assert_eq!(internal.router.default_outbound, "proxy");
+        let inbounds: Vec<(&str, InboundKind, u16)> =
+            internal.inbounds.iter().map(|i| (i.tag.as_str(), i.kind, i.port)).collect();
+        assert_eq!(inbounds, vec![("mixed-in", InboundKind::Socks5, 2080)]);
+        let outbounds: Vec<&str> = internal.outbounds.iter().map(|o| o.name.as_str()).collect();
+        assert_eq!(outbounds, vec!["proxy", "upstream", "direct"]);
+        let rules: Vec<(&str, &[String])> =
+            internal.router.rules.iter().map(|r| (r.outbound.as_str(), r.rule_sets.as_slice())).collect();
+        assert_eq!(rules, vec![("direct", &["geosite-cn".to_string()][..]), ("upstream", &[][..])]);
+        assert_eq!(internal.rule_sets[0].format, crate::config::RuleSetFormat::Srs);
+        assert_eq!(
+            warnings,
+            [
+                "field timestamp on log is not supported",
+                "inbound mixed 127.0.0.1:2080 only accepts SOCKS5, not HTTP",
+                "inbound tun :0 is not supported",
+                "field flow on outbound proxy is not supported",
+                "field tls.utls on outbound proxy is not supported",
+                "field tls.reality on outbound proxy is not supported",
+            ]
+        );
+    }
+}
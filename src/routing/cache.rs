@@ -1,8 +1,12 @@
 // 匹配结果缓存
+use crate::config::CacheConfig;
 use crate::routing::matchers::MatcherResult;
-use std::collections::HashMap;
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
 use std::hash::Hash;
 use std::net::IpAddr;
+use std::time::Duration;
+use tokio::time::Instant;
 
 /// 缓存键
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -11,56 +15,225 @@ pub enum CacheKey {
     Ip(IpAddr),
 }
 
+/// 缓存条目的默认有效期
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(300);
+
+struct Entry {
+    result: MatcherResult,
+    inserted: Instant,
+    generation: u64,
+    /// 插入序号（by_insert 的键），过期条目总是插入顺序的前缀
+    insert_seq: u64,
+    /// 最近使用序号（by_use 的键）
+    use_seq: u64,
+}
+
+/// 条目有效的条件：未过期且属于当前代
+#[derive(Clone, Copy)]
+struct Validity {
+    now: Instant,
+    ttl: Duration,
+    generation: u64,
+}
+
+impl Validity {
+    fn is_valid(&self, entry: &Entry) -> bool {
+        entry.generation == self.generation && self.now.duration_since(entry.inserted) < self.ttl
+    }
+}
+
+/// 带 TTL 和 LRU 淘汰的表
+struct LruTable<K> {
+    entries: HashMap<K, Entry>,
+    by_insert: BTreeMap<u64, K>,
+    by_use: BTreeMap<u64, K>,
+    seq: u64,
+    hits: u64,
+    misses: u64,
+    expired: u64,
+    evicted: u64,
+}
+
+impl<K: Hash + Eq + Clone> LruTable<K> {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            by_insert: BTreeMap::new(),
+            by_use: BTreeMap::new(),
+            seq: 0,
+            hits: 0,
+            misses: 0,
+            expired: 0,
+            evicted: 0,
+        }
+    }
+
+    fn remove<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let Some(entry) = self.entries.remove(key) else {
+            return false;
+        };
+        self.by_insert.remove(&entry.insert_seq);
+        self.by_use.remove(&entry.use_seq);
+        true
+    }
+
+    fn get<Q>(&mut self, key: &Q, validity: Validity) -> Option<MatcherResult>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let Some(entry) = self.entries.get_mut(key) else {
+            self.misses += 1;
+            return None;
+        };
+        if !validity.is_valid(entry) {
+            self.remove(key);
+            self.expired += 1;
+            self.misses += 1;
+            return None;
+        }
+        self.seq += 1;
+        if let Some(key) = self.by_use.remove(&entry.use_seq) {
+            self.by_use.insert(self.seq, key);
+        }
+        entry.use_seq = self.seq;
+        self.hits += 1;
+        Some(entry.result.clone())
+    }
+
+    fn insert(&mut self, key: K, result: MatcherResult, max_size: usize, validity: Validity) {
+        if max_size == 0 {
+            return;
+        }
+        self.remove(&key);
+        // 先淘汰过期条目，再按最近最少使用淘汰，每次只移除需要的数量
+        while self.entries.len() >= max_size {
+            let Some(oldest) = self.by_insert.values().next() else {
+                break;
+            };
+            if validity.is_valid(&self.entries[oldest]) {
+                break;
+            }
+            let oldest = oldest.clone();
+            self.remove(&oldest);
+            self.expired += 1;
+        }
+        while self.entries.len() >= max_size {
+            let Some(least_used) = self.by_use.values().next().cloned() else {
+                break;
+            };
+            self.remove(&least_used);
+            self.evicted += 1;
+        }
+
+        self.seq += 1;
+        self.by_insert.insert(self.seq, key.clone());
+        self.by_use.insert(self.seq, key.clone());
+        let entry = Entry {
+            result,
+            inserted: validity.now,
+            generation: validity.generation,
+            insert_seq: self.seq,
+            use_seq: self.seq,
+        };
+        self.entries.insert(key, entry);
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.by_insert.clear();
+        self.by_use.clear();
+    }
+}
+
 /// 匹配结果缓存
+///
+/// 条目超过 TTL 或属于旧的代（规则重载后）即视为过期，查找时惰性移除；
+/// 满时先淘汰过期条目，再按最近最少使用淘汰。
 pub struct MatchCache {
-    domain_cache: HashMap<String, MatcherResult>,
-    ip_cache: HashMap<IpAddr, MatcherResult>,
-    max_size: usize,
+    domain_cache: LruTable<String>,
+    ip_cache: LruTable<IpAddr>,
+    domain_max_size: usize,
+    ip_max_size: usize,
+    ttl: Duration,
+    generation: u64,
 }
 
 impl MatchCache {
     pub fn new(max_size: usize) -> Self {
+        Self::with_ttl(max_size, DEFAULT_CACHE_TTL)
+    }
+
+    pub fn with_ttl(max_size: usize, ttl: Duration) -> Self {
+        Self::with_capacities(max_size, max_size, ttl)
+    }
+
+    /// 域名和 IP 表使用各自的容量
+    pub fn with_capacities(domain_max_size: usize, ip_max_size: usize, ttl: Duration) -> Self {
         Self {
-            domain_cache: HashMap::new(),
-            ip_cache: HashMap::new(),
-            max_size,
+            domain_cache: LruTable::new(),
+            ip_cache: LruTable::new(),
+            domain_max_size,
+            ip_max_size,
+            ttl,
+            generation: 0,
+        }
+    }
+
+    /// 按配置创建；禁用时不保存任何条目
+    pub fn from_config(config: &CacheConfig) -> Self {
+        let capacity = |size: Option<usize>| if config.enabled { size.unwrap_or(config.max_size) } else { 0 };
+        Self::with_capacities(
+            capacity(config.domain_max_size),
+            capacity(config.ip_max_size),
+            Duration::from_secs(config.ttl_secs),
+        )
+    }
+
+    /// 容量和 TTL 相同的空缓存
+    pub fn empty_copy(&self) -> Self {
+        Self::with_capacities(self.domain_max_size, self.ip_max_size, self.ttl)
+    }
+
+    fn validity(&self) -> Validity {
+        Validity {
+            now: Instant::now(),
+            ttl: self.ttl,
+            generation: self.generation,
         }
     }
 
     /// 获取域名匹配结果
-    pub fn get_domain(&self, domain: &str) -> Option<&MatcherResult> {
-        self.domain_cache.get(domain)
+    pub fn get_domain(&mut self, domain: &str) -> Option<MatcherResult> {
+        let validity = self.validity();
+        self.domain_cache.get(domain, validity)
     }
 
     /// 设置域名匹配结果
     pub fn set_domain(&mut self, domain: String, result: MatcherResult) {
-        if self.domain_cache.len() >= self.max_size {
-            // 简单的LRU策略：清除一半缓存
-            let to_remove = self.domain_cache.len() / 2;
-            let keys: Vec<String> = self.domain_cache.keys().take(to_remove).cloned().collect();
-            for key in keys {
-                self.domain_cache.remove(&key);
-            }
-        }
-        self.domain_cache.insert(domain, result);
+        let validity = self.validity();
+        self.domain_cache.insert(domain, result, self.domain_max_size, validity);
     }
 
     /// 获取IP匹配结果
-    pub fn get_ip(&self, ip: &IpAddr) -> Option<&MatcherResult> {
-        self.ip_cache.get(ip)
+    pub fn get_ip(&mut self, ip: &IpAddr) -> Option<MatcherResult> {
+        let validity = self.validity();
+        self.ip_cache.get(ip, validity)
     }
 
     /// 设置IP匹配结果
     pub fn set_ip(&mut self, ip: IpAddr, result: MatcherResult) {
-        if self.ip_cache.len() >= self.max_size {
-            // 简单的LRU策略：清除一半缓存
-            let to_remove = self.ip_cache.len() / 2;
-            let keys: Vec<IpAddr> = self.ip_cache.keys().take(to_remove).cloned().collect();
-            for key in keys {
-                self.ip_cache.remove(&key);
-            }
-        }
-        self.ip_cache.insert(ip, result);
+        let validity = self.validity();
+        self.ip_cache.insert(ip, result, self.ip_max_size, validity);
+    }
+
+    /// 使所有现有条目失效：只增加代数，旧条目在查找或淘汰时移除
+    pub fn invalidate(&mut self) {
+        self.generation += 1;
     }
 
     /// 清除所有缓存
@@ -71,20 +244,39 @@ impl MatchCache {
 
     /// 获取缓存统计
     pub fn stats(&self) -> CacheStats {
+        let tables = [
+            (self.domain_cache.hits, self.domain_cache.misses, self.domain_cache.expired, self.domain_cache.evicted),
+            (self.ip_cache.hits, self.ip_cache.misses, self.ip_cache.expired, self.ip_cache.evicted),
+        ];
+        let sum = |field: fn(&(u64, u64, u64, u64)) -> u64| tables.iter().map(field).sum();
         CacheStats {
-            domain_cache_size: self.domain_cache.len(),
-            ip_cache_size: self.ip_cache.len(),
-            total_size: self.domain_cache.len() + self.ip_cache.len(),
+            domain_cache_size: self.domain_cache.entries.len(),
+            ip_cache_size: self.ip_cache.entries.len(),
+            total_size: self.domain_cache.entries.len() + self.ip_cache.entries.len(),
+            hits: sum(|t| t.0),
+            misses: sum(|t| t.1),
+            expired: sum(|t| t.2),
+            evicted: sum(|t| t.3),
+            generation: self.generation,
         }
     }
 }
 
 /// 缓存统计信息
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct CacheStats {
     pub domain_cache_size: usize,
     pub ip_cache_size: usize,
     pub total_size: usize,
+    pub hits: u64,
+    /// 未命中的查找，包括命中过期条目的查找
+    pub misses: u64,
+    /// 因过期或属于旧代被移除的条目
+    pub expired: u64,
+    /// 缓存满时按 LRU 淘汰的条目
+    pub evicted: u64,
+    /// 当前代数，每次失效加一
+    pub generation: u64,
 }
 
 impl Default for MatchCache {
@@ -103,7 +295,7 @@ mod tests {
         let mut cache = MatchCache::new(100);
 
         cache.set_domain("example.com".to_string(), MatcherResult::Match);
-        assert_eq!(cache.get_domain("example.com"), Some(&MatcherResult::Match));
+        assert_eq!(cache.get_domain("example.com"), Some(MatcherResult::Match));
         assert_eq!(cache.get_domain("other.com"), None);
     }
 
@@ -113,6 +305,94 @@ mod tests {
         let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1));
 
         cache.set_ip(ip, MatcherResult::Match);
-        assert_eq!(cache.get_ip(&ip), Some(&MatcherResult::Match));
+        assert_eq!(cache.get_ip(&ip), Some(MatcherResult::Match));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_entries_expire_after_ttl() {
+        let mut cache = MatchCache::with_ttl(100, Duration::from_secs(10));
+        cache.set_domain("example.com".to_string(), MatcherResult::Match);
+
+        tokio::time::advance(Duration::from_secs(9)).await;
+        assert_eq!(cache.get_domain("example.com"), Some(MatcherResult::Match));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(cache.get_domain("example.com"), None);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.expired, stats.total_size), (1, 1, 1, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_generation_bump_invalidates() {
+        let mut cache = MatchCache::new(100);
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        cache.set_ip(ip, MatcherResult::NoMatch);
+        cache.set_domain("old.example".to_string(), MatcherResult::Match);
+
+        cache.invalidate();
+        // 旧条目仍占位，直到被查找或淘汰
+        assert_eq!(cache.stats().total_size, 2);
+        assert_eq!(cache.get_ip(&ip), None);
+        cache.set_ip(ip, MatcherResult::Match);
+        assert_eq!(cache.get_ip(&ip), Some(MatcherResult::Match));
+
+        let stats = cache.stats();
+        assert_eq!(stats.generation, 1);
+        assert_eq!((stats.hits, stats.misses, stats.expired, stats.total_size), (1, 1, 1, 2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_full_cache_evicts_expired_then_least_recently_used() {
+        let mut cache = MatchCache::with_ttl(3, Duration::from_secs(10));
+        let domain = |name: &str| name.to_string();
+        cache.set_domain(domain("a"), MatcherResult::Match);
+        tokio::time::advance(Duration::from_secs(6)).await;
+        cache.set_domain(domain("b"), MatcherResult::Match);
+        cache.set_domain(domain("c"), MatcherResult::Match);
+        tokio::time::advance(Duration::from_secs(5)).await;
+
+        // a 已过期，先被移除；b、c 仍有效
+        cache.set_domain(domain("d"), MatcherResult::Match);
+        assert_eq!(cache.stats(), CacheStats {
+            domain_cache_size: 3,
+            total_size: 3,
+            expired: 1,
+            ..CacheStats::default()
+        });
+
+        // 读一次 b，最近最少使用的就是 c
+        assert!(cache.get_domain("b").is_some());
+        cache.set_domain(domain("e"), MatcherResult::Match);
+        assert!(cache.get_domain("c").is_none());
+        assert!(cache.get_domain("b").is_some());
+        assert!(cache.get_domain("d").is_some());
+        assert!(cache.get_domain("e").is_some());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.expired, stats.evicted), (4, 1, 1, 1));
+    }
+
+    #[test]
+    fn test_disabled_cache_stores_nothing() {
+        let config = CacheConfig { enabled: false, ..CacheConfig::default() };
+        let mut cache = MatchCache::from_config(&config);
+        cache.set_domain("example.com".to_string(), MatcherResult::Match);
+        assert_eq!(cache.get_domain("example.com"), None);
+        assert_eq!(cache.stats().total_size, 0);
+    }
+
+    #[test]
+    fn test_separate_domain_and_ip_capacities() {
+        let config = CacheConfig { domain_max_size: Some(1), ..CacheConfig::default() };
+        let mut cache = MatchCache::from_config(&config);
+        cache.set_domain("a.example".to_string(), MatcherResult::NoMatch);
+        cache.set_domain("b.example".to_string(), MatcherResult::NoMatch);
+        for last in 1..=3u8 {
+            cache.set_ip(IpAddr::V4(Ipv4Addr::new(192, 0, 2, last)), MatcherResult::NoMatch);
+        }
+
+        let stats = cache.stats();
+        assert_eq!((stats.domain_cache_size, stats.ip_cache_size, stats.evicted), (1, 3, 1));
+        assert!(cache.get_domain("b.example").is_some());
     }
 }
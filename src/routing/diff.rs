@@ -0,0 +1,270 @@
+// 路由差异：比较两份配置构建出的路由表，并找出样本中出站发生变化的目标
+use crate::config::{Config, INLINE_RULE_SET_PREFIX};
+use crate::error::Result;
+use crate::routing::loader::build_router;
+use crate::routing::router::{HighPerformanceRouter, RouteExplanation, RouterDump, RuleDump, RuleSetDump};
+use std::fmt;
+use std::net::IpAddr;
+
+/// One structural difference between two routing tables
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteChange {
+    DefaultOutbound { old: String, new: String },
+    RuleRemoved(RuleDump),
+    RuleAdded(RuleDump),
+    /// A rule kept by both configs whose rule set content differs
+    RuleSetChanged {
+        old_rule: usize,
+        new_rule: usize,
+        outbound: String,
+        old: RuleSetDump,
+        new: RuleSetDump,
+    },
+    /// A profile differs, or exists in one config only
+    ProfileChanged(String),
+}
+
+/// A sample the new config routes to a different outbound
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MovedSample {
+    pub sample: String,
+    pub old: RouteExplanation,
+    pub new: RouteExplanation,
+}
+
+/// Differences between the routing of two configs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoutingDiff {
+    pub changes: Vec<RouteChange>,
+    /// Samples evaluated against both routers
+    pub samples: usize,
+    pub moved: Vec<MovedSample>,
+}
+
+impl RoutingDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty() && self.moved.is_empty()
+    }
+}
+
+/// Rules are matched across configs by outbound and rule set tags; inline
+/// sets are named after their rule's index, so only their kind counts
+fn rule_key(rule: &RuleDump) -> (&str, Vec<&str>) {
+    let tags = rule
+        .rule_sets
+        .iter()
+        .map(|set| if set.tag.starts_with(INLINE_RULE_SET_PREFIX) { INLINE_RULE_SET_PREFIX } else { set.tag.as_str() })
+        .collect();
+    (rule.outbound.as_str(), tags)
+}
+
+/// Structural differences between two router dumps
+pub fn diff_dumps(old: &RouterDump, new: &RouterDump) -> Vec<RouteChange> {
+    let mut changes = Vec::new();
+    if old.default_outbound != new.default_outbound {
+        changes.push(RouteChange::DefaultOutbound {
+            old: old.default_outbound.clone(),
+            new: new.default_outbound.clone(),
+        });
+    }
+
+    let mut taken = vec![false; old.rules.len()];
+    let mut added = Vec::new();
+    for new_rule in &new.rules {
+        let key = rule_key(new_rule);
+        let Some(old_index) = (0..old.rules.len()).find(|&i| !taken[i] && rule_key(&old.rules[i]) == key) else {
+            added.push(RouteChange::RuleAdded(new_rule.clone()));
+            continue;
+        };
+        taken[old_index] = true;
+        let old_rule = &old.rules[old_index];
+        for (old_set, new_set) in old_rule.rule_sets.iter().zip(&new_rule.rule_sets) {
+            if (old_set.loaded, old_set.entries, &old_set.hash) != (new_set.loaded, new_set.entries, &new_set.hash) {
+                changes.push(RouteChange::RuleSetChanged {
+                    old_rule: old_rule.index,
+                    new_rule: new_rule.index,
+                    outbound: new_rule.outbound.clone(),
+                    old: old_set.clone(),
+                    new: new_set.clone(),
+                });
+            }
+        }
+    }
+    let removed = old.rules.iter().zip(&taken).filter(|(_, taken)| !**taken);
+    changes.extend(removed.map(|(rule, _)| RouteChange::RuleRemoved(rule.clone())));
+    changes.extend(added);
+
+    let names = old.profiles.keys().chain(new.profiles.keys().filter(|name| !old.profiles.contains_key(*name)));
+    for name in names {
+        if old.profiles.get(name) != new.profiles.get(name) {
+            changes.push(RouteChange::ProfileChanged(name.clone()));
+        }
+    }
+    changes
+}
+
+fn explain(router: &HighPerformanceRouter, sample: &str) -> RouteExplanation {
+    match sample.parse::<IpAddr>() {
+        Ok(ip) => router.explain_ip(ip),
+        Err(_) => router.explain_domain(sample),
+    }
+}
+
+/// Samples whose outbound differs between the two routers
+///
+/// Uses the explain API, so match caches and hit counters are left alone.
+pub fn diff_samples(old: &HighPerformanceRouter, new: &HighPerformanceRouter, samples: &[String]) -> Vec<MovedSample> {
+    samples
+        .iter()
+        .filter_map(|sample| {
+            let (old, new) = (explain(old, sample), explain(new, sample));
+            (old.decision.outbound != new.decision.outbound).then(|| MovedSample { sample: sample.clone(), old, new })
+        })
+        .collect()
+}
+
+/// Domains and IPs from a sample file, one per line; `#` starts a comment
+pub fn parse_samples(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Build both configs' routers and compare them, evaluating `samples` on each
+pub async fn diff_configs(old: &Config, new: &Config, samples: &[String]) -> Result<RoutingDiff> {
+    old.validate()?;
+    new.validate()?;
+    let old_router = build_router(old).await?;
+    let new_router = build_router(new).await?;
+    Ok(RoutingDiff {
+        changes: diff_dumps(&old_router.dump(), &new_router.dump()),
+        samples: samples.len(),
+        moved: diff_samples(&old_router, &new_router, samples),
+    })
+}
+
+impl fmt::Display for RouteChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sets = |rule: &RuleDump| rule.rule_sets.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ");
+        match self {
+            RouteChange::DefaultOutbound { old, new } => write!(f, "default outbound: {} -> {}", old, new),
+            RouteChange::RuleRemoved(rule) => write!(f, "- rule #{} -> {}: {}", rule.index, rule.outbound, sets(rule)),
+            RouteChange::RuleAdded(rule) => write!(f, "+ rule #{} -> {}: {}", rule.index, rule.outbound, sets(rule)),
+            RouteChange::RuleSetChanged { old_rule, new_rule, outbound, old, new } => {
+                write!(f, "~ rule #{} -> {}", new_rule, outbound)?;
+                if old_rule != new_rule {
+                    write!(f, " (was #{})", old_rule)?;
+                }
+                write!(f, ": {} -> {}", old, new)
+            }
+            RouteChange::ProfileChanged(name) => write!(f, "~ profile {}", name),
+        }
+    }
+}
+
+impl fmt::Display for RoutingDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.changes.is_empty() {
+            writeln!(f, "no routing table changes")?;
+        }
+        for change in &self.changes {
+            writeln!(f, "{}", change)?;
+        }
+        for moved in &self.moved {
+            writeln!(f, "moved {}: {} => {}", moved.sample, moved.old, moved.new)?;
+        }
+        write!(f, "{} samples, {} moved", self.samples, self.moved.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DomainLists, OutboundConfig, RouteAction, RouterRuleConfig};
+    use crate::routing::RouteDecision;
+
+    fn config(streaming: &[&str]) -> Config {
+        let rule = |outbound: &str, suffixes: &[&str]| RouterRuleConfig {
+            outbound: outbound.to_string(),
+            action: RouteAction::Forward,
+            outbound_chain: Vec::new(),
+            rule_sets: Vec::new(),
+            domains: DomainLists {
+                domain_suffix: suffixes.iter().map(|s| s.to_string()).collect(),
+                ..Default::default()
+            },
+            ip_cidr: Vec::new(),
+            dscp: None,
+            latency_mode: false,
+            rewrite_to: None,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
+        };
+        let mut config = Config {
+            outbounds: vec![OutboundConfig::direct("direct"), OutboundConfig::direct("proxy")],
+            ..Config::default()
+        };
+        config.router.rules = vec![rule("block", &["ads.example"]), rule("proxy", streaming)];
+        config
+    }
+
+    #[tokio::test]
+    async fn test_diff_reports_moved_samples_with_rules() {
+        let old = config(&["netflix.com"]);
+        let new = config(&["netflix.com", "nflxvideo.net"]);
+        let samples = parse_samples("# streaming\nwww.netflix.com\nassets.nflxvideo.net\n\nads.example\n8.8.8.8 # dns\nexample.com\n");
+        assert_eq!(samples.len(), 5);
+
+        let diff = diff_configs(&old, &new, &samples).await.unwrap();
+        let [RouteChange::RuleSetChanged { old_rule: 1, new_rule: 1, outbound, old: old_set, new: new_set }] =
+            diff.changes.as_slice()
+        else {
+            panic!("unexpected changes: {:?}", diff.changes);
+        };
+        assert_eq!(outbound, "proxy");
+        assert_eq!((old_set.entries, new_set.entries), (1, 2));
+        assert_ne!(old_set.hash, new_set.hash);
+
+        assert_eq!(
+            diff.moved,
+            [MovedSample {
+                sample: "assets.nflxvideo.net".to_string(),
+                old: RouteExplanation {
+                    decision: RouteDecision { outbound: "direct".to_string(), action: crate::config::RouteAction::Forward, outbound_chain: Vec::new(), rule: None, dscp: None, latency_mode: false, rewrite_to: None },
+                    rule_set: None,
+                },
+                new: RouteExplanation {
+                    decision: RouteDecision { outbound: "proxy".to_string(), action: crate::config::RouteAction::Forward, outbound_chain: Vec::new(), rule: Some(1), dscp: None, latency_mode: false, rewrite_to: None },
+                    rule_set: Some("inline#1".to_string()),
+                },
+            }]
+        );
+        let report = diff.to_string();
+        assert!(report.contains("moved assets.nflxvideo.net: default -> direct => rule #1 (inline#1) -> proxy"), "{}", report);
+        assert!(report.ends_with("5 samples, 1 moved"), "{}", report);
+    }
+
+    #[tokio::test]
+    async fn test_dump_is_deterministic_and_explain_skips_stats() {
+        let config = config(&["nflxvideo.net", "netflix.com"]);
+        let router = build_router(&config).await.unwrap();
+        let reordered = build_router(&self::config(&["netflix.com", "nflxvideo.net"])).await.unwrap();
+        assert_eq!(router.dump(), reordered.dump());
+        assert!(diff_dumps(&router.dump(), &reordered.dump()).is_empty());
+
+        let json = serde_json::to_value(router.dump()).unwrap();
+        assert_eq!(json["default_outbound"], "direct");
+        assert_eq!(json["rules"][1]["rule_sets"][0]["entries"], 2);
+
+        diff_samples(&router, &reordered, &["www.netflix.com".to_string()]);
+        assert!(router.rule_stats().iter().all(|stats| stats.hits == 0));
+        assert_eq!(router.get_cache_stats().total_size, 0);
+    }
+}
@@ -0,0 +1,788 @@
+// 规则集加载：读取 [[rule_sets]] 中的本地文件或下载远程文件，解析后与规则的内联列表一起构建路由器
+use crate::config::{
+    Config, RouteAction, RouterRuleConfig, RuleSetConfig, RuleSetErrorPolicy, RuleSetFormat, RuleSetType, INLINE_RULE_SET_PREFIX,
+};
+use crate::error::{ProxyError, Result};
+use crate::routing::cache::MatchCache;
+use crate::routing::matchers::{GeoIpMatcher, MatcherBuildReport};
+use crate::routing::router::{get_global_router, set_global_router, HighPerformanceRouter, RouteRule};
+use crate::routing::rule_sets::{parse_sing_box_source, DomainRuleSet, IpRuleSet, RuleSetManager};
+use crate::routing::srs::parse_srs;
+use crate::rule_set_downloader::{DownloadLimits, RuleSetDownloader};
+use crate::tasks::{get_global_task_tracker, TaskGroup};
+use ipnet::IpNet;
+use log::{debug, info, log, warn};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Matcher builds taking longer than this in total are summarized at info level
+const BUILD_SUMMARY_THRESHOLD: Duration = Duration::from_millis(500);
+
+fn rule_set_error(tag: &str, e: impl std::fmt::Display) -> ProxyError {
+    ProxyError::RuleSet { tag: tag.to_string(), reason: e.to_string() }
+}
+
+/// Apply the error policy to the rule sets that failed: `fail` reports all
+/// of them together, `skip` logs them and carries on
+fn handle_failures(failures: Vec<ProxyError>, policy: RuleSetErrorPolicy) -> Result<()> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+    match policy {
+        RuleSetErrorPolicy::Fail => Err(ProxyError::RuleSetsFailed(failures)),
+        RuleSetErrorPolicy::Skip => {
+            for failure in &failures {
+                warn!("{}, skipping it", failure);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn empty_sets(tag: &str) -> (DomainRuleSet, IpRuleSet) {
+    let domain = DomainRuleSet {
+        id: tag.to_string(),
+        domain: Vec::new(),
+        domain_suffix: Vec::new(),
+        domain_keyword: Vec::new(),
+        domain_regex: Vec::new(),
+    };
+    let ip = IpRuleSet {
+        id: tag.to_string(),
+        ip_cidr: Vec::new(),
+    };
+    (domain, ip)
+}
+
+/// Parse a rule set file into its domain and IP parts, both with id `tag`
+pub fn parse_rule_set(tag: &str, content: &str, format: RuleSetFormat) -> Result<(DomainRuleSet, IpRuleSet)> {
+    match format {
+        RuleSetFormat::Source => parse_source(tag, content),
+        RuleSetFormat::Clash => Ok(parse_clash(tag, content)),
+        RuleSetFormat::Domains => Ok(parse_domains(tag, content)),
+        RuleSetFormat::Srs => parse_srs(tag, content.as_bytes()).map_err(|e| rule_set_error(tag, e)),
+    }
+}
+
+/// sing-box source format; the items of all rules are merged into one set
+fn parse_source(tag: &str, content: &str) -> Result<(DomainRuleSet, IpRuleSet)> {
+    parse_sing_box_source(tag, content).map_err(|e| rule_set_error(tag, e))
+}
+
+/// Clash rule provider payload, YAML or plain list
+///
+/// Understands the domain behavior ("+.example.com", "example.com"), the
+/// ipcidr behavior and the DOMAIN*/IP-CIDR* entries of the classical
+/// behavior; other classical rule types are skipped.
+fn parse_clash(tag: &str, content: &str) -> (DomainRuleSet, IpRuleSet) {
+    let (mut domain, mut ip) = empty_sets(tag);
+    let mut skipped = 0;
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line == "payload:" {
+            continue;
+        }
+        let entry = line.strip_prefix('-').unwrap_or(line).trim().trim_matches(|c| c == '\'' || c == '"');
+        if let Some((kind, rest)) = entry.split_once(',') {
+            let value = rest.split(',').next().unwrap_or_default().trim().to_string();
+            match kind.trim() {
+                "DOMAIN" => domain.domain.push(value),
+                "DOMAIN-SUFFIX" => domain.domain_suffix.push(value),
+                "DOMAIN-KEYWORD" => domain.domain_keyword.push(value),
+                "DOMAIN-REGEX" => domain.domain_regex.push(value),
+                "IP-CIDR" | "IP-CIDR6" => ip.ip_cidr.push(value),
+                _ => skipped += 1,
+            }
+        } else if let Some(suffix) = entry.strip_prefix("+.").or_else(|| entry.strip_prefix('.')) {
+            domain.domain_suffix.push(suffix.to_string());
+        } else if entry.parse::<IpNet>().is_ok() {
+            ip.ip_cidr.push(entry.to_string());
+        } else {
+            domain.domain.push(entry.to_string());
+        }
+    }
+    if skipped > 0 {
+        warn!("Rule set {}: skipped {} unsupported clash rules", tag, skipped);
+    }
+    (domain, ip)
+}
+
+/// One domain per line; each matches itself and its subdomains
+fn parse_domains(tag: &str, content: &str) -> (DomainRuleSet, IpRuleSet) {
+    let (mut domain, ip) = empty_sets(tag);
+    domain.domain_suffix = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.trim_start_matches("+.").trim_start_matches('.').to_string())
+        .collect();
+    (domain, ip)
+}
+
+fn add_sets(manager: &mut RuleSetManager, (domain, ip): (DomainRuleSet, IpRuleSet)) {
+    debug!("Rule set {}: {} domain entries, {} CIDRs", domain.id, domain.len(), ip.ip_cidr.len());
+    manager.add_sets(domain, ip);
+}
+
+/// Read or download one rule set and parse it
+async fn load_rule_set(
+    rule_set: &RuleSetConfig,
+    cache_dir: &str,
+    limits: DownloadLimits,
+    downloader: &mut Option<RuleSetDownloader>,
+) -> Result<(DomainRuleSet, IpRuleSet)> {
+    let tag = rule_set.tag.as_str();
+    let path = match rule_set.kind {
+        RuleSetType::Local => rule_set.path.clone().map(Into::into),
+        RuleSetType::Remote => match &rule_set.url {
+            Some(url) => {
+                let downloader = match downloader {
+                    Some(downloader) => downloader,
+                    None => downloader.insert(RuleSetDownloader::with_limits(cache_dir, limits)?),
+                };
+                let signature = rule_set.signature()?;
+                let path = downloader
+                    .download_rule_set_signed(
+                        tag,
+                        url,
+                        Some(rule_set.format),
+                        rule_set.update_interval(),
+                        signature.as_ref(),
+                    )
+                    .await
+                    .map_err(|e| match e {
+                        e @ ProxyError::DownloadRejected { .. } => e,
+                        e => rule_set_error(tag, e),
+                    })?;
+                Some(path)
+            }
+            None => None,
+        },
+    };
+    let path: std::path::PathBuf = path.ok_or_else(|| rule_set_error(tag, "no path or url"))?;
+    let content = tokio::fs::read(&path)
+        .await
+        .map_err(|e| rule_set_error(tag, format!("{}: {}", path.display(), e)))?;
+    // srs 是二进制格式，其余格式都是文本
+    if rule_set.format == RuleSetFormat::Srs {
+        return parse_srs(tag, &content).map_err(|e| rule_set_error(tag, e));
+    }
+    let content =
+        String::from_utf8(content).map_err(|_| rule_set_error(tag, format!("{}: not UTF-8 text", path.display())))?;
+    parse_rule_set(tag, &content, rule_set.format)
+}
+
+/// Read or download every rule set and parse it
+///
+/// A failing rule set does not stop the others from loading; what happens
+/// to the failures is up to `policy`.
+pub async fn load_rule_sets(
+    rule_sets: &[RuleSetConfig],
+    cache_dir: &str,
+    limits: DownloadLimits,
+    policy: RuleSetErrorPolicy,
+) -> Result<RuleSetManager> {
+    let mut manager = RuleSetManager::new();
+    let mut downloader = None;
+    let mut failures = Vec::new();
+    for rule_set in rule_sets {
+        match load_rule_set(rule_set, cache_dir, limits, &mut downloader).await {
+            Ok(sets) => add_sets(&mut manager, sets),
+            Err(e @ (ProxyError::RuleSet { .. } | ProxyError::DownloadRejected { .. })) => failures.push(e),
+            Err(e) => failures.push(rule_set_error(&rule_set.tag, e)),
+        }
+    }
+    handle_failures(failures, policy)?;
+    Ok(manager)
+}
+
+/// Log how long each matcher took to build, as a table when the total is slow
+fn log_build_report(report: &MatcherBuildReport) {
+    let slow = report.elapsed >= BUILD_SUMMARY_THRESHOLD;
+    let level = if slow { log::Level::Info } else { log::Level::Debug };
+    log!(
+        level,
+        "Built {} rule set matchers in {} ms",
+        report.timings.len(),
+        report.elapsed.as_millis()
+    );
+    if !slow {
+        return;
+    }
+    let mut timings: Vec<_> = report.timings.iter().collect();
+    timings.sort_by_key(|timing| std::cmp::Reverse(timing.duration));
+    info!("{:<32} {:<6} {:>8} {:>9}", "tag", "kind", "entries", "build ms");
+    for timing in timings {
+        info!("{:<32} {:<6} {:>8} {:>9}", timing.tag, timing.kind, timing.entries, timing.duration.as_millis());
+    }
+}
+
+/// Matcher build concurrency: `performance.worker_threads`, or the CPU count
+fn build_parallelism(config: &Config) -> usize {
+    match config.performance.worker_threads {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+}
+
+/// Anonymous rule set holding a rule's inline domain and IP lists
+fn inline_sets(id: &str, rule: &RouterRuleConfig) -> Option<(DomainRuleSet, IpRuleSet)> {
+    let domains = &rule.domains;
+    let empty = domains.domain.is_empty()
+        && domains.domain_suffix.is_empty()
+        && domains.domain_keyword.is_empty()
+        && domains.domain_regex.is_empty()
+        && rule.ip_cidr.is_empty();
+    if empty {
+        return None;
+    }
+    let (mut domain, mut ip) = empty_sets(id);
+    domain.domain = domains.domain.clone();
+    domain.domain_suffix = domains.domain_suffix.clone();
+    domain.domain_keyword = domains.domain_keyword.clone();
+    domain.domain_regex = domains.domain_regex.clone();
+    ip.ip_cidr = rule.ip_cidr.clone();
+    Some((domain, ip))
+}
+
+/// Route rule for a `[[router.rules]]` entry, registering its inline lists as rule set `id`
+fn route_rule(manager: &mut RuleSetManager, id: &str, rule: &RouterRuleConfig) -> Result<RouteRule> {
+    let mut rule_sets = rule.rule_sets.clone();
+    if let Some(sets) = inline_sets(id, rule) {
+        rule_sets.push(sets.0.id.clone());
+        add_sets(manager, sets);
+    }
+    Ok(RouteRule {
+        rule_sets,
+        outbound: rule.outbound_label(),
+        action: rule.route_action(),
+        outbound_chain: rule.outbound_chain.clone(),
+        dscp: rule.dscp,
+        latency_mode: rule.latency_mode,
+        rewrite_to: rule.rewrite_to.clone(),
+        port: rule.port.clone(),
+        port_range: rule.port_ranges()?,
+        network: rule.network,
+        source_ip_cidr: rule.source_networks()?,
+        inbound: rule.inbound.clone(),
+        ip_geoip: rule.ip_geoip.iter().map(|code| code.to_ascii_lowercase()).collect(),
+    })
+}
+
+/// Build the router from `[[rule_sets]]`, `[router]`, `[high_performance_router]` and `[profiles]`
+///
+/// `router.rules` come first, each matching its referenced rule sets or its
+/// inline lists; `high_performance_router.rules` follow. Unmatched traffic
+/// goes to `router.default_outbound`. Every profile becomes a router of its
+/// own sharing the rule sets and matchers.
+pub async fn build_router(config: &Config) -> Result<HighPerformanceRouter> {
+    let policy = config.router.on_rule_set_error;
+    let cache_dir = &config.router.rule_set_cache_dir;
+    let mut manager = load_rule_sets(&config.rule_sets, cache_dir, config.router.download_limits(), policy).await?;
+    let files = &config.high_performance_router.rule_set_files;
+    for path in &files.domain_files {
+        manager.load_domain_from_json(&tokio::fs::read_to_string(path).await?)?;
+    }
+    for path in &files.ip_files {
+        manager.load_ip_from_json(&tokio::fs::read_to_string(path).await?)?;
+    }
+
+    let mut router = HighPerformanceRouter::new(config.router.default_outbound.clone());
+    router.set_match_cache(MatchCache::from_config(&config.high_performance_router.cache));
+    if let Some(path) = &config.router.geoip_path {
+        let geoip = GeoIpMatcher::open(path)?;
+        info!("Loaded GeoIP database {}", path);
+        router.set_geoip(geoip);
+    }
+    for (index, rule) in config.router.rules.iter().enumerate() {
+        router.add_rule(route_rule(&mut manager, &format!("{}{}", INLINE_RULE_SET_PREFIX, index), rule)?);
+    }
+    for rule in &config.high_performance_router.rules {
+        router.add_rule(RouteRule {
+            rule_sets: rule.rule_sets.clone(),
+            outbound: rule.outbound.clone(),
+            action: RouteAction::Forward,
+            outbound_chain: Vec::new(),
+            dscp: rule.dscp,
+            latency_mode: rule.latency_mode,
+            rewrite_to: None,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
+        });
+    }
+    let profiles = config
+        .profiles
+        .iter()
+        .map(|(name, profile)| {
+            let rules = profile
+                .rules
+                .iter()
+                .enumerate()
+                .map(|(index, rule)| {
+                    let id = format!("{}{}#{}", INLINE_RULE_SET_PREFIX, name, index);
+                    route_rule(&mut manager, &id, rule)
+                })
+                .collect::<Result<_>>()?;
+            Ok((name.clone(), profile.default_outbound.clone(), rules))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    router.set_rule_manager(manager);
+
+    let report = router.prebuild_matchers(build_parallelism(config)).await;
+    log_build_report(&report);
+    for tag in report.failed_tags() {
+        router.remove_rule_set(tag);
+    }
+    handle_failures(report.failures, policy)?;
+    for (name, default_outbound, rules) in profiles {
+        router.add_profile(name, default_outbound, rules);
+    }
+    Ok(router)
+}
+
+/// Rebuild the global router whenever the most frequently updated remote
+/// rule set is due; a failed refresh keeps the current router
+///
+/// Calling it again, e.g. after a config reload, replaces the previous refresh.
+pub fn start_rule_set_updates(config: Arc<Config>) {
+    // 配置重载后按新配置重新开始刷新，先停掉旧的刷新任务
+    static UPDATES: Mutex<Option<JoinHandle<Option<()>>>> = Mutex::new(None);
+    let mut updates = UPDATES.lock().unwrap();
+    if let Some(previous) = updates.take() {
+        previous.abort();
+    }
+    let Some(period) = config
+        .rule_sets
+        .iter()
+        .filter(|rule_set| rule_set.kind == RuleSetType::Remote)
+        .map(RuleSetConfig::update_interval)
+        .min()
+    else {
+        return;
+    };
+
+    let spawned = get_global_task_tracker().spawn(TaskGroup::RulesetRefresh, async move {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            match build_router(&config).await {
+                Ok(router) => {
+                    set_global_router(router);
+                    info!("Rule sets refreshed ({} rule sets)", get_global_router().rule_set_count());
+                }
+                Err(e) => warn!("Rule set refresh failed, keeping the current rules: {}", e),
+            }
+        }
+    });
+    match spawned {
+        Ok(handle) => *updates = Some(handle),
+        Err(e) => warn!("Rule set updates not started: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Network;
+    use crate::routing::RouteContext;
+
+    const SOURCE_JSON: &str = r#"{
+        "version": 1,
+        "rules": [
+            {"domain_suffix": ["netflix.com", "nflxvideo.net"]},
+            {"domain": "fast.com", "ip_cidr": ["198.38.96.0/19"]}
+        ]
+    }"#;
+
+    #[test]
+    fn test_parse_formats() {
+        let (domain, ip) = parse_rule_set("streaming", SOURCE_JSON, RuleSetFormat::Source).unwrap();
+        assert_eq!(domain.domain_suffix, ["netflix.com", "nflxvideo.net"]);
+        assert_eq!((domain.domain, ip.ip_cidr), (vec!["fast.com".to_string()], vec!["198.38.96.0/19".to_string()]));
+
+        let clash = "payload:\n  - '+.google.com'\n  - 'google.cn'\n  - DOMAIN-KEYWORD,youtube\n  - IP-CIDR,8.8.8.0/24,no-resolve\n  - PROCESS-NAME,curl\n  - '2001:db8::/32'\n";
+        let (domain, ip) = parse_rule_set("google", clash, RuleSetFormat::Clash).unwrap();
+        assert_eq!(domain.domain_suffix, ["google.com"]);
+        assert_eq!(domain.domain, ["google.cn"]);
+        assert_eq!(domain.domain_keyword, ["youtube"]);
+        assert_eq!(ip.ip_cidr, ["8.8.8.0/24", "2001:db8::/32"]);
+
+        let (domain, _) = parse_rule_set("ads", "# ads\nads.example\n\n+.tracker.example\n", RuleSetFormat::Domains).unwrap();
+        assert_eq!(domain.domain_suffix, ["ads.example", "tracker.example"]);
+
+        assert!(parse_rule_set("bad", "{", RuleSetFormat::Source).is_err());
+        assert!(parse_rule_set("bin", "", RuleSetFormat::Srs).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_toml_rule_sets_route() {
+        let dir = std::env::temp_dir().join(format!("anybls-rule-sets-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("streaming.json");
+        std::fs::write(&path, SOURCE_JSON).unwrap();
+
+        let fixture = format!(
+            r#"
+            [[rule_sets]]
+            tag = "streaming"
+            type = "local"
+            path = {path:?}
+            format = "source"
+
+            [[outbounds]]
+            name = "direct"
+            type = "direct"
+
+            [[outbounds]]
+            name = "proxy"
+            type = "socks5"
+            address = "127.0.0.1:1081"
+
+            [router]
+            default_outbound = "direct"
+
+            [[router.rules]]
+            outbound = "proxy"
+            rule_sets = ["streaming"]
+
+            [[router.rules]]
+            outbound = "block"
+            ip_cidr = ["10.0.0.0/8"]
+            domains = {{ domain_suffix = ["ads.example"] }}
+            "#,
+            path = path.display().to_string()
+        );
+        // 其余部分取默认配置
+        let mut table: toml::Table = toml::from_str(&toml::to_string(&Config::default()).unwrap()).unwrap();
+        table.extend(toml::from_str::<toml::Table>(&fixture).unwrap());
+        let config: Config = table.try_into().unwrap();
+        config.validate().unwrap();
+
+        let router = build_router(&config).await.unwrap();
+        assert_eq!(router.route_domain("www.netflix.com").outbound, "proxy");
+        assert_eq!(router.route_domain("fast.com").outbound, "proxy");
+        assert_eq!(router.route_ip("198.38.100.1".parse().unwrap()).outbound, "proxy");
+        assert_eq!(router.route_domain("cdn.ads.example").outbound, "block");
+        assert_eq!(router.route_ip("10.1.2.3".parse().unwrap()).outbound, "block");
+        assert_eq!(router.route_domain("example.com").outbound, "direct");
+        assert_eq!(router.route_ip("8.8.8.8".parse().unwrap()).outbound, "direct");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_srs_rule_set_routes() {
+        let mut config = Config {
+            rule_sets: vec![RuleSetConfig {
+                tag: "geo".to_string(),
+                kind: RuleSetType::Local,
+                path: Some(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/sample.srs").to_string()),
+                url: None,
+                format: RuleSetFormat::Srs,
+                update_interval_secs: None,
+                signature_url: None,
+                public_key: None,
+            }],
+            ..Config::default()
+        };
+        config.router.rules = vec![RouterRuleConfig {
+            outbound: "block".to_string(),
+            action: RouteAction::Forward,
+            outbound_chain: Vec::new(),
+            rule_sets: vec!["geo".to_string()],
+            domains: Default::default(),
+            ip_cidr: Vec::new(),
+            dscp: None,
+            latency_mode: false,
+            rewrite_to: None,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
+        }];
+        config.validate().unwrap();
+
+        let router = build_router(&config).await.unwrap();
+        assert_eq!(router.route_domain("maps.googleapis.com").outbound, "block");
+        assert_eq!(router.route_domain("music.youtube.com").outbound, "block");
+        assert_eq!(router.route_ip("10.0.2.9".parse().unwrap()).outbound, "block");
+        assert_eq!(router.route_domain("port-only.example").outbound, "direct");
+        assert_eq!(router.route_ip("10.0.3.1".parse().unwrap()).outbound, "direct");
+    }
+
+    #[tokio::test]
+    async fn test_port_and_network_rules() {
+        let router_config = r#"
+            default_outbound = "direct"
+
+            [[rules]]
+            outbound = "block"
+            port = [25]
+            port_range = ["465-587"]
+
+            # 拦截 QUIC，浏览器退回 TCP
+            [[rules]]
+            outbound = "block"
+            network = "udp"
+            port = [443]
+        "#;
+        let mut config = Config { router: toml::from_str(router_config).unwrap(), ..Config::default() };
+        config.validate().unwrap();
+
+        let router = build_router(&config).await.unwrap();
+        let context = |port, network| RouteContext::domain("mail.example").with_port(port).with_network(network);
+        assert_eq!(router.select_outbound(&context(25, Network::Tcp)), "block");
+        assert_eq!(router.select_outbound(&context(587, Network::Tcp)), "block");
+        assert_eq!(router.select_outbound(&context(443, Network::Udp)), "block");
+        assert_eq!(router.select_outbound(&context(443, Network::Tcp)), "direct");
+        let ip = RouteContext::ip("192.0.2.1".parse().unwrap()).with_port(465).with_network(Network::Tcp);
+        assert_eq!(router.select_outbound(&ip), "block");
+        // 没有端口信息时，只带端口条件的规则不匹配
+        assert_eq!(router.route_domain("mail.example").outbound, "direct");
+
+        config.router.rules[0].port_range = vec!["587-465".to_string()];
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("Rule for block: Invalid port range"), "{}", err);
+        config.router.rules[0].port_range.clear();
+        config.router.rules[0].source_ip_cidr = vec!["192.168.1.0/33".to_string()];
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("Rule for block: Invalid source_ip_cidr"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_geoip_rules() {
+        let router_config = format!(
+            r#"
+            default_outbound = "direct"
+            geoip_path = "{}/tests/fixtures/sample.mmdb"
+
+            [[rules]]
+            outbound = "block"
+            ip_geoip = ["CN", "private"]
+        "#,
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let mut config = Config { router: toml::from_str(&router_config).unwrap(), ..Config::default() };
+        config.validate().unwrap();
+
+        let router = build_router(&config).await.unwrap();
+        for (ip, outbound) in [("1.0.1.9", "block"), ("2001:da8::8", "block"), ("10.0.0.1", "block"), ("8.8.8.8", "direct")] {
+            assert_eq!(router.route_ip(ip.parse().unwrap()).outbound, outbound, "{}", ip);
+        }
+        // 国家代码只对 IP 目标生效
+        assert_eq!(router.route_domain("example.cn").outbound, "direct");
+        // 再次查询命中匹配缓存
+        let hits = router.get_cache_stats().hits;
+        assert_eq!(router.route_ip("1.0.1.9".parse().unwrap()).outbound, "block");
+        assert_eq!(router.get_cache_stats().hits, hits + 1);
+
+        config.router.geoip_path = Some("/nonexistent/GeoLite2-Country.mmdb".to_string());
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("router.geoip_path /nonexistent/GeoLite2-Country.mmdb"), "{}", err);
+        config.router.geoip_path = None;
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("Rule for block: ip_geoip \"CN\" needs router.geoip_path"), "{}", err);
+        config.router.rules[0].ip_geoip = vec!["private".to_string()];
+        config.validate().unwrap();
+        config.router.rules[0].ip_geoip = vec!["china".to_string()];
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("invalid ip_geoip \"china\""), "{}", err);
+    }
+
+    #[test]
+    fn test_rule_set_validation() {
+        let local = |tag: &str| RuleSetConfig {
+            tag: tag.to_string(),
+            kind: RuleSetType::Local,
+            path: Some("sets/a.json".to_string()),
+            url: None,
+            format: RuleSetFormat::Source,
+            update_interval_secs: None,
+            signature_url: None,
+            public_key: None,
+        };
+        let mut config = Config {
+            rule_sets: vec![local("a"), local("a")],
+            ..Config::default()
+        };
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("Duplicate rule set tag: a"), "{}", err);
+
+        config.rule_sets = vec![local("a")];
+        config.router.rules.push(RouterRuleConfig {
+            outbound: "block".to_string(),
+            action: RouteAction::Forward,
+            outbound_chain: Vec::new(),
+            rule_sets: vec!["a".to_string(), "missing".to_string()],
+            domains: Default::default(),
+            ip_cidr: Vec::new(),
+            dscp: None,
+            latency_mode: false,
+            rewrite_to: None,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
+        });
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("unknown rule set: missing"), "{}", err);
+
+        config.router.rules[0].rule_sets.pop();
+        assert!(config.validate().is_ok());
+
+        config.rule_sets[0].kind = RuleSetType::Remote;
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("remote rule sets need a url"), "{}", err);
+
+        config.rule_sets[0].url = Some("https://rules.example/a.json".to_string());
+        config.rule_sets[0].signature_url = Some("https://rules.example/a.json.sig".to_string());
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("signature_url and public_key must be set together"), "{}", err);
+        config.rule_sets[0].public_key = Some("not-a-key".to_string());
+        assert!(config.validate().is_err());
+        config.rule_sets[0].public_key = Some("11".repeat(32));
+        assert!(config.validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_failing_rule_sets_are_aggregated() {
+        let dir = std::env::temp_dir().join(format!("anybls-rule-set-errors-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("good.json"), SOURCE_JSON).unwrap();
+        std::fs::write(dir.join("bad-regex.json"), r#"{"rules": [{"domain_regex": "(unclosed"}]}"#).unwrap();
+
+        let local = |tag: &str, file: &str| RuleSetConfig {
+            tag: tag.to_string(),
+            kind: RuleSetType::Local,
+            path: Some(dir.join(file).display().to_string()),
+            url: None,
+            format: RuleSetFormat::Source,
+            update_interval_secs: None,
+            signature_url: None,
+            public_key: None,
+        };
+        let rule = |outbound: &str, tag: &str| RouterRuleConfig {
+            outbound: outbound.to_string(),
+            action: RouteAction::Forward,
+            outbound_chain: Vec::new(),
+            rule_sets: vec![tag.to_string()],
+            domains: Default::default(),
+            ip_cidr: Vec::new(),
+            dscp: None,
+            latency_mode: false,
+            rewrite_to: None,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
+        };
+        let mut config = Config {
+            rule_sets: vec![local("good", "good.json"), local("missing", "missing.json"), local("bad-regex", "bad-regex.json")],
+            ..Config::default()
+        };
+        config.router.rules = vec![rule("block", "bad-regex"), rule("block", "missing"), rule("proxy", "good")];
+
+        // 读取失败在加载阶段汇总，正则错误在构建阶段汇总
+        let err = build_router(&config).await.err().unwrap();
+        assert!(matches!(&err, ProxyError::RuleSetsFailed(failures) if failures.len() == 1), "{}", err);
+        assert!(err.to_string().contains("Rule set missing:"), "{}", err);
+        std::fs::write(dir.join("missing.json"), r#"{"rules": [{"domain_regex": ["[z-a]"]}]}"#).unwrap();
+        let err = build_router(&config).await.err().unwrap().to_string();
+        assert!(err.starts_with("2 rule sets failed"), "{}", err);
+        assert!(err.contains("Rule set missing:") && err.contains("Rule set bad-regex:"), "{}", err);
+
+        config.router.on_rule_set_error = RuleSetErrorPolicy::Skip;
+        let router = build_router(&config).await.unwrap();
+        assert_eq!(router.route_domain("www.netflix.com").outbound, "proxy");
+        assert_eq!(router.route_domain("unclosed").outbound, "direct");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_profiles_share_rule_sets() {
+        let dir = std::env::temp_dir().join(format!("anybls-profiles-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("streaming.json"), SOURCE_JSON).unwrap();
+        let rule = |outbound: &str, rule_sets: &[&str], suffix: &[&str]| RouterRuleConfig {
+            outbound: outbound.to_string(),
+            action: RouteAction::Forward,
+            outbound_chain: Vec::new(),
+            rule_sets: rule_sets.iter().map(|tag| tag.to_string()).collect(),
+            domains: crate::config::DomainLists {
+                domain_suffix: suffix.iter().map(|s| s.to_string()).collect(),
+                ..Default::default()
+            },
+            ip_cidr: Vec::new(),
+            dscp: None,
+            latency_mode: false,
+            rewrite_to: None,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
+        };
+        let mut config = Config {
+            rule_sets: vec![RuleSetConfig {
+                tag: "streaming".to_string(),
+                kind: RuleSetType::Local,
+                path: Some(dir.join("streaming.json").display().to_string()),
+                url: None,
+                format: RuleSetFormat::Source,
+                update_interval_secs: None,
+                signature_url: None,
+                public_key: None,
+            }],
+            outbounds: vec![crate::config::OutboundConfig::direct("direct"), crate::config::OutboundConfig::direct("proxy")],
+            ..Config::default()
+        };
+        config.router.rules = vec![rule("proxy", &["streaming"], &[])];
+        config.profiles.insert(
+            "kids".to_string(),
+            crate::config::RoutingProfileConfig {
+                default_outbound: "proxy".to_string(),
+                rules: vec![rule("block", &["streaming"], &["games.example"])],
+            },
+        );
+        config.validate().unwrap();
+
+        let router = build_router(&config).await.unwrap();
+        let kids = router.profile("kids").unwrap();
+        assert_eq!(router.profile_names(), ["kids"]);
+        assert!(router.profile("missing").is_none());
+        assert_eq!(router.route_domain("www.netflix.com").outbound, "proxy");
+        assert_eq!(kids.route_domain("www.netflix.com").outbound, "block");
+        assert_eq!(router.route_domain("games.example").outbound, "direct");
+        assert_eq!(kids.route_domain("games.example").outbound, "block");
+        assert_eq!(kids.route_domain("example.com").outbound, "proxy");
+        assert_eq!(kids.rule_stats()[0].hits, 2);
+        assert_eq!(router.rule_stats()[0].hits, 1);
+
+        config.server.profile = Some("adults".to_string());
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("unknown profile: adults"), "{}", err);
+        config.server.profile = None;
+        config.profiles.get_mut("kids").unwrap().default_outbound = "missing".to_string();
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("unknown outbound: missing"), "{}", err);
+        config.profiles.get_mut("kids").unwrap().default_outbound = "direct".to_string();
+        config.profiles.get_mut("kids").unwrap().rules[0].rule_sets.push("missing".to_string());
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("unknown rule set: missing"), "{}", err);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,19 +1,71 @@
 // 高性能匹配器
-use crate::error::{ProxyError, Result};
+//
+// 只依赖匹配算法本身（不依赖 tokio 和代理运行时），关闭默认特性即可作为纯匹配库使用
+use crate::routing::mmdb::MaxMindDb;
+use crate::routing::rule_sets::{DomainRuleSet, IpRuleSet};
 use aho_corasick::AhoCorasick;
 use fst::{Set, SetBuilder};
 use ipnet::IpNet;
-use radix_trie::Trie;
+use radix_trie::{Trie, TrieCommon};
 use regex::RegexSet;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::path::Path;
 use std::sync::Arc;
+#[cfg(feature = "runtime")]
+use crate::error::ProxyError;
+#[cfg(feature = "runtime")]
+use crate::routing::rule_sets::RuleSetManager;
+#[cfg(feature = "runtime")]
+use std::time::{Duration, Instant};
+#[cfg(feature = "runtime")]
+use tokio::sync::Semaphore;
+
+/// Error building a matcher or reading a rule set document
+#[derive(Debug, thiserror::Error)]
+pub enum MatcherError {
+    #[error("FST error: {0}")]
+    Fst(String),
+    #[error("AC error: {0}")]
+    Keyword(String),
+    #[error("Invalid regex: {0}")]
+    Regex(String),
+    #[error("Invalid CIDR {cidr}: {reason}")]
+    Cidr { cidr: String, reason: String },
+    #[error("Invalid {kind} JSON: {reason}")]
+    Json { kind: &'static str, reason: String },
+    #[error("Invalid SRS rule set: {0}")]
+    Srs(String),
+    #[error("Invalid GeoIP database: {0}")]
+    GeoIp(String),
+}
+
+pub type Result<T> = std::result::Result<T, MatcherError>;
 
 /// 匹配结果
 #[derive(Debug, Clone, PartialEq)]
 pub enum MatcherResult {
     Match,
     NoMatch,
+    /// 路由缓存用：命中的规则及其中匹配的规则集合下标
+    Rule { rule: usize, set: Option<usize> },
+}
+
+/// Which entry of a matcher matched
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "entry", rename_all = "lowercase")]
+pub enum MatchDetail {
+    /// Exact domain
+    Exact(String),
+    /// The suffix the domain ends with at a label boundary
+    Suffix(String),
+    /// The keyword the domain contains
+    Keyword(String),
+    /// The regular expression the domain matches
+    Regex(String),
+    /// The network the address is in
+    Cidr(IpNet),
 }
 
 /// 域名匹配器 - 使用多种高性能算法
@@ -26,6 +78,8 @@ pub struct DomainMatcher {
 
     // 关键字匹配 - AC自动机
     keyword_matcher: AhoCorasick,
+    // 关键字原文，按 AC 自动机的模式编号
+    keywords: Vec<String>,
 
     // 正则匹配 - RegexSet
     regex_matcher: RegexSet,
@@ -39,39 +93,54 @@ impl DomainMatcher {
         keyword_domains: Vec<String>,
         regex_domains: Vec<String>,
     ) -> Result<Self> {
-        // 构建完整域名FST
+        // 构建完整域名FST（FST要求按字典序插入且不重复）
+        let mut exact_domains = exact_domains;
+        exact_domains.sort_unstable();
+        exact_domains.dedup();
         let mut exact_builder = SetBuilder::memory();
         for domain in &exact_domains {
-            exact_builder.insert(domain)
-                .map_err(|e| ProxyError::Protocol(format!("FST error: {}", e)))?;
+            exact_builder.insert(domain).map_err(|e| MatcherError::Fst(e.to_string()))?;
         }
         let exact_domains = exact_builder.into_set();
 
         // 构建后缀域名FST（反向域名）
+        let mut reversed_suffixes: Vec<String> = suffix_domains.iter().map(|d| Self::reverse_domain(d)).collect();
+        reversed_suffixes.sort_unstable();
+        reversed_suffixes.dedup();
         let mut suffix_builder = SetBuilder::memory();
-        for domain in &suffix_domains {
-            let reversed = Self::reverse_domain(domain);
-            suffix_builder.insert(&reversed)
-                .map_err(|e| ProxyError::Protocol(format!("FST error: {}", e)))?;
+        for reversed in &reversed_suffixes {
+            suffix_builder.insert(reversed).map_err(|e| MatcherError::Fst(e.to_string()))?;
         }
         let suffix_domains = suffix_builder.into_set();
 
         // 构建关键字AC自动机
-        let keyword_matcher = AhoCorasick::new(&keyword_domains)
-            .map_err(|e| ProxyError::Protocol(format!("AC error: {}", e)))?;
+        let keyword_matcher = AhoCorasick::new(&keyword_domains).map_err(|e| MatcherError::Keyword(e.to_string()))?;
 
         // 构建正则表达式集合
-        let regex_matcher = RegexSet::new(&regex_domains)
-            .map_err(|e| crate::error::ProxyError::Protocol(format!("Invalid regex: {}", e)))?;
+        let regex_matcher = RegexSet::new(&regex_domains).map_err(|e| MatcherError::Regex(e.to_string()))?;
 
         Ok(Self {
             exact_domains,
             suffix_domains,
             keyword_matcher,
+            keywords: keyword_domains,
             regex_matcher,
         })
     }
 
+    /// Build the matcher of a domain rule set
+    ///
+    /// ```
+    /// use anybls::routing::{DomainMatcher, DomainRuleSet, MatchDetail};
+    ///
+    /// let set = DomainRuleSet::builder("streaming").suffix("netflix.com").keyword("youtube").build();
+    /// let matcher = DomainMatcher::from_rule_set(&set).unwrap();
+    /// assert_eq!(matcher.matches_detailed("www.netflix.com"), Some(MatchDetail::Suffix("netflix.com".to_string())));
+    /// ```
+    pub fn from_rule_set(set: &DomainRuleSet) -> Result<Self> {
+        Self::new(set.domain.clone(), set.domain_suffix.clone(), set.domain_keyword.clone(), set.domain_regex.clone())
+    }
+
     /// 匹配域名 - 按性能优化顺序
     pub fn matches(&self, domain: &str) -> MatcherResult {
         // 1. 完整域名匹配（最快）
@@ -80,8 +149,7 @@ impl DomainMatcher {
         }
 
         // 2. 后缀匹配
-        let reversed = Self::reverse_domain(domain);
-        if self.suffix_domains.contains(&reversed) {
+        if self.matched_suffix(domain).is_some() {
             return MatcherResult::Match;
         }
 
@@ -98,6 +166,30 @@ impl DomainMatcher {
         MatcherResult::NoMatch
     }
 
+    /// Like `matches`, naming the entry that matched; checked in the same
+    /// order, so the first matching category wins
+    pub fn matches_detailed(&self, domain: &str) -> Option<MatchDetail> {
+        if self.exact_domains.contains(domain) {
+            return Some(MatchDetail::Exact(domain.to_string()));
+        }
+        if let Some(suffix) = self.matched_suffix(domain) {
+            return Some(MatchDetail::Suffix(suffix));
+        }
+        if let Some(found) = self.keyword_matcher.find(domain) {
+            return Some(MatchDetail::Keyword(self.keywords[found.pattern().as_usize()].clone()));
+        }
+        let regex = self.regex_matcher.matches(domain).into_iter().next()?;
+        Some(MatchDetail::Regex(self.regex_matcher.patterns()[regex].clone()))
+    }
+
+    /// 后缀匹配：依次检查反向域名在每个标签边界处的前缀，返回最短的命中后缀
+    fn matched_suffix(&self, domain: &str) -> Option<String> {
+        let reversed = Self::reverse_domain(domain);
+        let label_ends = reversed.match_indices('.').map(|(i, _)| i).chain(std::iter::once(reversed.len()));
+        let end = label_ends.into_iter().find(|end| self.suffix_domains.contains(&reversed[..*end]))?;
+        Some(Self::reverse_domain(&reversed[..end]))
+    }
+
     /// 反向域名（用于后缀匹配）
     fn reverse_domain(domain: &str) -> String {
         domain.split('.').rev().collect::<Vec<_>>().join(".")
@@ -106,7 +198,8 @@ impl DomainMatcher {
 
 /// IP匹配器 - 使用radix_trie和HashMap
 pub struct IpMatcher {
-    ipv4_trie: Trie<u32, ()>,
+    /// 键为网络前缀的逐位展开（每字节一位），祖先查找即最长前缀匹配
+    ipv4_trie: Trie<Vec<u8>, IpNet>,
     ipv6_networks: Vec<IpNet>, // IPv6使用简单的Vec，因为radix_trie不支持u128
 }
 
@@ -117,14 +210,15 @@ impl IpMatcher {
         let mut ipv6_networks = Vec::new();
 
         for cidr_str in &ip_cidrs {
-            let cidr: IpNet = cidr_str.parse()
-                .map_err(|e| ProxyError::Protocol(format!("Invalid CIDR {}: {}", cidr_str, e)))?;
+            let cidr: IpNet = cidr_str
+                .parse()
+                .map_err(|e: ipnet::AddrParseError| MatcherError::Cidr { cidr: cidr_str.clone(), reason: e.to_string() })?;
 
             match cidr {
                 IpNet::V4(net) => {
                     // 将IPv4网络转换为前缀
                     let prefix = Self::ipv4_to_prefix(net.addr(), net.prefix_len());
-                    ipv4_trie.insert(prefix, ());
+                    ipv4_trie.insert(prefix, cidr.trunc());
                 }
                 IpNet::V6(_) => {
                     // IPv6直接存储网络
@@ -139,40 +233,97 @@ impl IpMatcher {
         })
     }
 
+    /// Build the matcher of an IP rule set
+    pub fn from_rule_set(set: &IpRuleSet) -> Result<Self> {
+        Self::new(set.ip_cidr.clone())
+    }
+
     /// 匹配IP地址
     pub fn matches(&self, ip: IpAddr) -> MatcherResult {
+        match self.matches_detailed(ip) {
+            Some(_) => MatcherResult::Match,
+            None => MatcherResult::NoMatch,
+        }
+    }
+
+    /// Like `matches`, naming the network the address is in: for IPv4 the
+    /// most specific one, for IPv6 the first listed
+    pub fn matches_detailed(&self, ip: IpAddr) -> Option<MatchDetail> {
         match ip {
             IpAddr::V4(ipv4) => {
                 let prefix = Self::ipv4_to_prefix(ipv4, 32);
-                if self.ipv4_trie.get_ancestor(&prefix).is_some() {
-                    MatcherResult::Match
-                } else {
-                    MatcherResult::NoMatch
-                }
-            }
-            IpAddr::V6(ipv6) => {
-                // IPv6使用简单的线性搜索
-                for network in &self.ipv6_networks {
-                    if let IpNet::V6(net) = network {
-                        if net.contains(&ipv6) {
-                            return MatcherResult::Match;
-                        }
-                    }
-                }
-                MatcherResult::NoMatch
+                let network = self.ipv4_trie.get_ancestor(&prefix)?.value()?;
+                Some(MatchDetail::Cidr(*network))
             }
+            // IPv6使用简单的线性搜索
+            IpAddr::V6(_) => self.ipv6_networks.iter().find(|net| net.contains(&ip)).map(|net| MatchDetail::Cidr(*net)),
         }
     }
 
-    /// 将IPv4地址和前缀长度转换为前缀
-    fn ipv4_to_prefix(addr: std::net::Ipv4Addr, prefix_len: u8) -> u32 {
+    /// 将IPv4地址和前缀长度转换为前缀的位序列
+    fn ipv4_to_prefix(addr: std::net::Ipv4Addr, prefix_len: u8) -> Vec<u8> {
         let ip = u32::from(addr);
-        let mask = if prefix_len == 0 {
-            0
-        } else {
-            !((1u32 << (32 - prefix_len)) - 1)
-        };
-        ip & mask
+        (0..prefix_len.min(32)).map(|bit| (ip >> (31 - bit)) as u8 & 1).collect()
+    }
+}
+
+/// GeoIP 国家匹配器 - 查 MaxMind DB 得到国家代码
+///
+/// `private`（内网、回环、链路本地地址）不查库，没有数据库时也能用。
+pub struct GeoIpMatcher {
+    db: Option<MaxMindDb>,
+}
+
+impl GeoIpMatcher {
+    /// 不需要数据库的代码
+    pub const PRIVATE: &'static str = "private";
+
+    /// 读取 `.mmdb` 文件
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let buf = std::fs::read(path).map_err(|e| MatcherError::GeoIp(format!("{}: {}", path.display(), e)))?;
+        Self::from_bytes(buf).map_err(|e| match e {
+            MatcherError::GeoIp(reason) => MatcherError::GeoIp(format!("{}: {}", path.display(), reason)),
+            other => other,
+        })
+    }
+
+    pub fn from_bytes(buf: Vec<u8>) -> Result<Self> {
+        Ok(Self { db: Some(MaxMindDb::from_bytes(buf)?) })
+    }
+
+    /// 只能匹配 `private` 的匹配器
+    pub fn without_database() -> Self {
+        Self { db: None }
+    }
+
+    pub fn has_database(&self) -> bool {
+        self.db.is_some()
+    }
+
+    /// 地址所在国家的 ISO 3166 代码（小写）；库中没有或无法解码时返回 None
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        self.db.as_ref()?.country(ip).ok().flatten()
+    }
+
+    /// 地址是否属于 `codes` 中任一国家，代码不区分大小写
+    pub fn matches(&self, ip: IpAddr, codes: &[String]) -> bool {
+        let ip = ip.to_canonical();
+        if codes.iter().any(|code| code.eq_ignore_ascii_case(Self::PRIVATE)) && Self::is_private(ip) {
+            return true;
+        }
+        self.country(ip).is_some_and(|country| codes.iter().any(|code| code.eq_ignore_ascii_case(&country)))
+    }
+
+    /// 内网（RFC 1918 / IPv6 ULA）、回环和链路本地地址
+    pub fn is_private(ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(v4) => v4.is_private() || v4.is_loopback() || v4.is_link_local(),
+            IpAddr::V6(v6) => {
+                let first = v6.segments()[0];
+                v6.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+            }
+        }
     }
 }
 
@@ -226,6 +377,107 @@ impl MatcherCache {
     }
 }
 
+/// 单个匹配器的构建耗时
+#[cfg(feature = "runtime")]
+#[derive(Debug, Clone)]
+pub struct MatcherBuildTiming {
+    pub tag: String,
+    /// "domain" 或 "ip"
+    pub kind: &'static str,
+    pub entries: usize,
+    pub duration: Duration,
+}
+
+/// 预构建结果
+#[cfg(feature = "runtime")]
+#[derive(Debug, Default)]
+pub struct MatcherBuildReport {
+    pub timings: Vec<MatcherBuildTiming>,
+    /// 构建失败的规则集合，错误中带有标签
+    pub failures: Vec<ProxyError>,
+    /// 全部构建的总耗时
+    pub elapsed: Duration,
+}
+
+#[cfg(feature = "runtime")]
+impl MatcherBuildReport {
+    /// 构建失败的规则集合标签
+    pub fn failed_tags(&self) -> Vec<&str> {
+        self.failures
+            .iter()
+            .filter_map(|e| match e {
+                ProxyError::RuleSet { tag, .. } => Some(tag.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "runtime")]
+enum BuiltMatcher {
+    Domain(Arc<DomainMatcher>),
+    Ip(Arc<IpMatcher>),
+}
+
+#[cfg(feature = "runtime")]
+impl MatcherCache {
+    /// 并发预构建规则集合管理器中所有集合的匹配器
+    ///
+    /// FST、AC自动机和正则的构建是CPU密集的，放在阻塞线程池上执行，
+    /// 最多同时构建 `parallelism` 个；一个集合失败不影响其他集合。
+    pub async fn prebuild(manager: &RuleSetManager, parallelism: usize) -> (Self, MatcherBuildReport) {
+        let started = Instant::now();
+        let permits = Arc::new(Semaphore::new(parallelism.max(1)));
+        let mut jobs = Vec::new();
+
+        for set in manager.all_domain_sets().values() {
+            let set = set.clone();
+            let entries = set.domain.len() + set.domain_suffix.len() + set.domain_keyword.len() + set.domain_regex.len();
+            let permit = permits.clone().acquire_owned().await.expect("semaphore is never closed");
+            jobs.push((set.id.clone(), "domain", entries, tokio::task::spawn_blocking(move || {
+                let _permit = permit;
+                let started = Instant::now();
+                let matcher = DomainMatcher::from_rule_set(&set);
+                (matcher.map(|m| BuiltMatcher::Domain(Arc::new(m))).map_err(|e| e.to_string()), started.elapsed())
+            })));
+        }
+        for set in manager.all_ip_sets().values() {
+            let set = set.clone();
+            let entries = set.ip_cidr.len();
+            let permit = permits.clone().acquire_owned().await.expect("semaphore is never closed");
+            jobs.push((set.id.clone(), "ip", entries, tokio::task::spawn_blocking(move || {
+                let _permit = permit;
+                let started = Instant::now();
+                let matcher = IpMatcher::from_rule_set(&set);
+                (matcher.map(|m| BuiltMatcher::Ip(Arc::new(m))).map_err(|e| e.to_string()), started.elapsed())
+            })));
+        }
+
+        let mut cache = Self::new();
+        let mut report = MatcherBuildReport::default();
+        for (tag, kind, entries, job) in jobs {
+            let (built, duration) = match job.await {
+                Ok(result) => result,
+                Err(e) => (Err(format!("build task failed: {}", e)), Duration::ZERO),
+            };
+            match built {
+                Ok(BuiltMatcher::Domain(matcher)) => {
+                    cache.domain_matchers.insert(tag.clone(), matcher);
+                }
+                Ok(BuiltMatcher::Ip(matcher)) => {
+                    cache.ip_matchers.insert(tag.clone(), matcher);
+                }
+                Err(e) => {
+                    report.failures.push(ProxyError::RuleSet { tag: tag.clone(), reason: e });
+                }
+            }
+            report.timings.push(MatcherBuildTiming { tag, kind, entries, duration });
+        }
+        report.elapsed = started.elapsed();
+        (cache, report)
+    }
+}
+
 impl Default for MatcherCache {
     fn default() -> Self {
         Self::new()
@@ -236,6 +488,54 @@ impl Default for MatcherCache {
 mod tests {
     use super::*;
 
+    #[cfg(feature = "runtime")]
+    fn regex_set(id: &str, count: usize) -> DomainRuleSet {
+        DomainRuleSet {
+            id: id.to_string(),
+            domain: Vec::new(),
+            domain_suffix: Vec::new(),
+            domain_keyword: Vec::new(),
+            domain_regex: (0..count).map(|i| format!(r"^(www|cdn|api)-{}\.{}\.example\.(com|net)$", i, id)).collect(),
+        }
+    }
+
+    #[cfg(feature = "runtime")]
+    #[tokio::test]
+    async fn test_prebuild_runs_sets_concurrently() {
+        let mut manager = RuleSetManager::new();
+        for i in 0..8 {
+            manager.add_domain_set(regex_set(&format!("set{}", i), 300));
+        }
+        manager.add_ip_set(IpRuleSet { id: "lan".to_string(), ip_cidr: vec!["10.0.0.0/8".to_string()] });
+
+        let (cache, report) = MatcherCache::prebuild(&manager, 4).await;
+        assert!(report.failures.is_empty());
+        assert_eq!(report.timings.len(), 9);
+        assert!(report.timings.iter().any(|t| t.tag == "set3" && t.kind == "domain" && t.entries == 300));
+        let sum: Duration = report.timings.iter().map(|t| t.duration).sum();
+        assert!(report.elapsed < sum, "elapsed {:?}, sum of builds {:?}", report.elapsed, sum);
+
+        assert_eq!(cache.domain_matchers["set5"].matches("cdn-7.set5.example.net"), MatcherResult::Match);
+        assert_eq!(cache.ip_matchers["lan"].matches("10.1.1.1".parse().unwrap()), MatcherResult::Match);
+    }
+
+    #[cfg(feature = "runtime")]
+    #[tokio::test]
+    async fn test_prebuild_reports_every_failing_set() {
+        let mut manager = RuleSetManager::new();
+        manager.add_domain_set(regex_set("good", 3));
+        let mut broken = regex_set("broken", 1);
+        broken.domain_regex.push("(".to_string());
+        manager.add_domain_set(broken);
+        manager.add_ip_set(IpRuleSet { id: "bad-cidr".to_string(), ip_cidr: vec!["10.0.0.0/33".to_string()] });
+
+        let (cache, report) = MatcherCache::prebuild(&manager, 2).await;
+        let mut failed = report.failed_tags();
+        failed.sort_unstable();
+        assert_eq!(failed, ["bad-cidr", "broken"]);
+        assert!(cache.domain_matchers.contains_key("good"));
+    }
+
     #[test]
     fn test_domain_matcher() {
         let matcher = DomainMatcher::new(
@@ -263,4 +563,36 @@ mod tests {
         assert_eq!(matcher.matches("10.1.1.1".parse().unwrap()), MatcherResult::Match);
         assert_eq!(matcher.matches("8.8.8.8".parse().unwrap()), MatcherResult::NoMatch);
     }
+
+    fn codes(codes: &[&str]) -> Vec<String> {
+        codes.iter().map(|code| code.to_string()).collect()
+    }
+
+    #[test]
+    fn test_geoip_matcher() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/sample.mmdb");
+        let matcher = GeoIpMatcher::open(path).unwrap();
+
+        assert_eq!(matcher.country("1.0.1.1".parse().unwrap()).as_deref(), Some("cn"));
+        assert_eq!(matcher.country("::ffff:8.8.8.8".parse().unwrap()).as_deref(), Some("us"));
+        assert_eq!(matcher.country("2001:da8::1".parse().unwrap()).as_deref(), Some("cn"));
+        assert_eq!(matcher.country("9.9.9.9".parse().unwrap()), None);
+
+        assert!(matcher.matches("1.0.1.1".parse().unwrap(), &codes(&["CN"])));
+        assert!(!matcher.matches("8.8.8.8".parse().unwrap(), &codes(&["cn"])));
+        assert!(matcher.matches("8.8.8.8".parse().unwrap(), &codes(&["cn", "us"])));
+        assert!(matcher.matches("192.168.1.1".parse().unwrap(), &codes(&["private"])));
+        assert!(!matcher.matches("9.9.9.9".parse().unwrap(), &codes(&["private"])));
+    }
+
+    #[test]
+    fn test_geoip_private_without_database() {
+        let matcher = GeoIpMatcher::without_database();
+        for ip in ["10.1.2.3", "172.16.0.1", "127.0.0.1", "169.254.1.1", "::1", "fd00::1", "fe80::1", "::ffff:192.168.0.1"] {
+            assert!(matcher.matches(ip.parse().unwrap(), &codes(&["private"])), "{}", ip);
+        }
+        assert!(!matcher.matches("1.0.1.1".parse().unwrap(), &codes(&["private", "cn"])));
+        assert!(matches!(GeoIpMatcher::from_bytes(b"not a database".to_vec()), Err(MatcherError::GeoIp(_))));
+        assert!(GeoIpMatcher::open("/nonexistent/GeoLite2-Country.mmdb").is_err());
+    }
 }
@@ -0,0 +1,271 @@
+// MaxMind DB（.mmdb）读取：二叉搜索树 + 数据段 + 元数据，只解码查国家代码所需的类型
+//
+// 格式见 https://maxmind.github.io/MaxMind-DB/
+use crate::routing::matchers::{MatcherError, Result};
+use std::net::IpAddr;
+
+/// 元数据段起始标记，取文件中最后一次出现的位置
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+/// 搜索树与数据段之间的 16 字节分隔
+const DATA_SEPARATOR: usize = 16;
+/// 嵌套解码的最大深度，防止恶意文件中的指针环
+const MAX_DEPTH: usize = 16;
+
+fn invalid(reason: impl Into<String>) -> MatcherError {
+    MatcherError::GeoIp(reason.into())
+}
+
+/// 解码出的值；查询只用到字符串、整数和映射
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Uint(u64),
+    Map(Vec<(String, Value)>),
+    Other,
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_uint(&self) -> Option<u64> {
+        match self {
+            Value::Uint(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+/// 数据段解码器；偏移相对于传入的切片
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&'a [u8]> {
+        self.data
+            .get(offset..offset.checked_add(len).ok_or_else(|| invalid("offset overflow"))?)
+            .ok_or_else(|| invalid("data section truncated"))
+    }
+
+    fn uint(&self, offset: usize, len: usize) -> Result<u64> {
+        if len > 8 {
+            return Err(invalid(format!("integer of {} bytes", len)));
+        }
+        Ok(self.bytes(offset, len)?.iter().fold(0, |n, b| (n << 8) | u64::from(*b)))
+    }
+
+    /// 解码 `offset` 处的值，返回值和其后的偏移
+    fn decode(&self, offset: usize, depth: usize) -> Result<(Value, usize)> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("data nested too deeply"));
+        }
+        let control = *self.bytes(offset, 1)?.first().unwrap_or(&0);
+        let mut offset = offset + 1;
+        let mut kind = control >> 5;
+        if kind == 1 {
+            // 指针：跳到目标处解码，之后从指针后继续
+            let size = usize::from((control >> 3) & 0x3);
+            let low = u64::from(control & 0x7);
+            let pointer = match size {
+                0 => (low << 8) | self.uint(offset, 1)?,
+                1 => ((low << 16) | self.uint(offset, 2)?) + 2048,
+                2 => ((low << 24) | self.uint(offset, 3)?) + 526_336,
+                _ => self.uint(offset, 4)?,
+            };
+            let (value, _) = self.decode(pointer as usize, depth + 1)?;
+            return Ok((value, offset + size + 1));
+        }
+        if kind == 0 {
+            kind = 7 + self.bytes(offset, 1)?[0];
+            offset += 1;
+        }
+        let mut size = usize::from(control & 0x1f);
+        match size {
+            29 => {
+                size = 29 + self.uint(offset, 1)? as usize;
+                offset += 1;
+            }
+            30 => {
+                size = 285 + self.uint(offset, 2)? as usize;
+                offset += 2;
+            }
+            31 => {
+                size = 65_821 + self.uint(offset, 3)? as usize;
+                offset += 3;
+            }
+            _ => {}
+        }
+
+        match kind {
+            2 => {
+                let text = std::str::from_utf8(self.bytes(offset, size)?).map_err(|_| invalid("string is not UTF-8"))?;
+                Ok((Value::String(text.to_string()), offset + size))
+            }
+            5 | 6 | 9 | 10 => {
+                // uint128 超出 u64 时按其他类型跳过
+                let value = if size <= 8 { Value::Uint(self.uint(offset, size)?) } else { Value::Other };
+                self.bytes(offset, size)?;
+                Ok((value, offset + size))
+            }
+            7 => {
+                let mut entries = Vec::with_capacity(size.min(64));
+                for _ in 0..size {
+                    let (key, next) = self.decode(offset, depth + 1)?;
+                    let Value::String(key) = key else {
+                        return Err(invalid("map key is not a string"));
+                    };
+                    let (value, next) = self.decode(next, depth + 1)?;
+                    entries.push((key, value));
+                    offset = next;
+                }
+                Ok((Value::Map(entries), offset))
+            }
+            11 => {
+                for _ in 0..size {
+                    offset = self.decode(offset, depth + 1)?.1;
+                }
+                Ok((Value::Other, offset))
+            }
+            3 => Ok((Value::Other, offset + 8)),
+            15 => Ok((Value::Other, offset + 4)),
+            4 | 8 => Ok((Value::Other, offset + size)),
+            // 布尔值的 size 就是值本身，没有负载
+            14 => Ok((Value::Other, offset)),
+            other => Err(invalid(format!("unsupported data type {}", other))),
+        }
+    }
+}
+
+/// 载入内存的 MaxMind DB
+pub struct MaxMindDb {
+    buf: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    /// 数据段起始偏移（搜索树之后再跳过分隔）
+    data_start: usize,
+    /// IPv6 树中 IPv4 地址（::/96）所在的节点
+    ipv4_start: usize,
+    pub database_type: String,
+}
+
+impl MaxMindDb {
+    pub fn from_bytes(buf: Vec<u8>) -> Result<Self> {
+        let marker = buf
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or_else(|| invalid("metadata marker not found, not a MaxMind DB file"))?;
+        let metadata_start = marker + METADATA_MARKER.len();
+        let (metadata, _) = Decoder { data: &buf[metadata_start..] }.decode(0, 0)?;
+        let field = |key: &str| {
+            metadata.get(key).and_then(Value::as_uint).ok_or_else(|| invalid(format!("metadata has no {}", key)))
+        };
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+        if !matches!(record_size, 24 | 28 | 32) {
+            return Err(invalid(format!("unsupported record size {}", record_size)));
+        }
+        if !matches!(ip_version, 4 | 6) {
+            return Err(invalid(format!("unsupported IP version {}", ip_version)));
+        }
+        let tree_size = node_count
+            .checked_mul(record_size / 4)
+            .filter(|size| size + DATA_SEPARATOR <= marker)
+            .ok_or_else(|| invalid("search tree larger than the file"))?;
+        let database_type = metadata.get("database_type").and_then(Value::as_str).unwrap_or_default().to_string();
+
+        let mut db = Self {
+            buf,
+            node_count,
+            record_size,
+            ip_version,
+            data_start: tree_size + DATA_SEPARATOR,
+            ipv4_start: 0,
+            database_type,
+        };
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = db.record(node, 0)?;
+            }
+            db.ipv4_start = node;
+        }
+        Ok(db)
+    }
+
+    /// 节点的左（bit = 0）或右记录
+    fn record(&self, node: usize, bit: u8) -> Result<usize> {
+        let node_bytes = self.record_size / 4;
+        let base = node * node_bytes;
+        let bytes = self.buf.get(base..base + node_bytes).ok_or_else(|| invalid("search tree truncated"))?;
+        let be = |range: std::ops::Range<usize>| bytes[range].iter().fold(0usize, |n, b| (n << 8) | usize::from(*b));
+        Ok(match (self.record_size, bit) {
+            (24, 0) => be(0..3),
+            (24, _) => be(3..6),
+            (28, 0) => (usize::from(bytes[3] & 0xf0) << 20) | be(0..3),
+            (28, _) => (usize::from(bytes[3] & 0x0f) << 24) | be(4..7),
+            (_, 0) => be(0..4),
+            _ => be(4..8),
+        })
+    }
+
+    /// 查找地址对应的数据；没有记录时返回 None
+    fn lookup(&self, ip: IpAddr) -> Result<Option<Value>> {
+        let (bits, mut node) = match ip.to_canonical() {
+            IpAddr::V4(v4) if self.ip_version == 6 => (u128::from(u32::from(v4)) << 96, self.ipv4_start),
+            IpAddr::V4(v4) => (u128::from(u32::from(v4)) << 96, 0),
+            IpAddr::V6(_) if self.ip_version == 4 => return Ok(None),
+            IpAddr::V6(v6) => (u128::from(v6), 0),
+        };
+        let depth = if matches!(ip.to_canonical(), IpAddr::V4(_)) { 32 } else { 128 };
+        for i in 0..depth {
+            if node >= self.node_count {
+                break;
+            }
+            let bit = ((bits >> (127 - i)) & 1) as u8;
+            node = self.record(node, bit)?;
+        }
+        if node == self.node_count {
+            return Ok(None);
+        }
+        if node < self.node_count {
+            return Err(invalid("search tree deeper than the address"));
+        }
+        let offset = node - self.node_count - DATA_SEPARATOR;
+        let data = &self.buf[self.data_start..];
+        Ok(Some(Decoder { data }.decode(offset, 0)?.0))
+    }
+
+    /// ISO 3166 国家代码（小写）
+    ///
+    /// 支持 GeoIP2/GeoLite2 Country 和 City 库（`country.iso_code`，没有时用
+    /// `registered_country`），以及数据直接是代码字符串的库（如 sing-box geoip）。
+    pub fn country(&self, ip: IpAddr) -> Result<Option<String>> {
+        let Some(value) = self.lookup(ip)? else {
+            return Ok(None);
+        };
+        let code = match &value {
+            Value::String(code) => Some(code.as_str()),
+            _ => ["country", "registered_country"]
+                .iter()
+                .find_map(|key| value.get(key)?.get("iso_code")?.as_str()),
+        };
+        Ok(code.map(str::to_ascii_lowercase))
+    }
+}
@@ -1,10 +1,29 @@
 // 高性能路由系统
+//
+// matchers、mmdb 与 rule_sets 不依赖代理运行时，关闭默认特性 runtime 时只编译这几部分
+// （srs 需要 runtime 带来的 zlib 解压）
+#[cfg(feature = "runtime")]
 pub mod cache;
+#[cfg(feature = "runtime")]
+pub mod diff;
+#[cfg(feature = "runtime")]
+pub mod loader;
 pub mod matchers;
+pub mod mmdb;
+#[cfg(feature = "runtime")]
 pub mod router;
 pub mod rule_sets;
+#[cfg(feature = "runtime")]
+pub mod srs;
 
+#[cfg(feature = "runtime")]
 pub use cache::{CacheKey, MatchCache};
-pub use matchers::{DomainMatcher, IpMatcher, MatcherResult};
-pub use router::{HighPerformanceRouter, RouteRule};
-pub use rule_sets::{DomainRuleSet, IpRuleSet, RuleSet};
+pub use matchers::{DomainMatcher, GeoIpMatcher, IpMatcher, MatchDetail, MatcherError, MatcherResult};
+#[cfg(feature = "runtime")]
+pub use loader::{build_router, start_rule_set_updates};
+#[cfg(feature = "runtime")]
+pub use router::{
+    get_global_router, set_global_router, HighPerformanceRouter, RouteContext, RouteDecision, RouteExplanation,
+    RouteHost, RouteRule, RouteRuleBuilder, RouterDump,
+};
+pub use rule_sets::{DomainRuleSet, DomainRuleSetBuilder, IpRuleSet, RuleSet, RuleSetManager, RuleSetSnapshot};
@@ -1,118 +1,667 @@
 // 高性能路由器
+use crate::config::{Network, RewriteTarget, RouteAction};
 use crate::routing::{
     cache::{CacheStats, MatchCache},
-    matchers::{MatcherCache, MatcherResult},
+    matchers::{GeoIpMatcher, MatcherBuildReport, MatcherCache, MatcherResult},
     rule_sets::{RuleSetId, RuleSetManager},
 };
-use std::net::IpAddr;
-use std::sync::{Arc, RwLock};
+use log::info;
+use ipnet::IpNet;
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant};
 
 /// 路由规则
 #[derive(Debug, Clone)]
 pub struct RouteRule {
     pub rule_sets: Vec<RuleSetId>, // 规则集合ID列表（OR关系）
-    pub outbound: String,          // 出站名称
+    pub outbound: String,          // 出站名称（代理链时为各跳以 > 连接的标签，拒绝时为动作名）
+    pub action: RouteAction,       // 转发、拒绝或静默丢弃
+    pub outbound_chain: Vec<String>, // 规则级代理链，非空时按顺序逐跳连接
+    pub dscp: Option<u8>,          // 覆盖出站的 DSCP 标记
+    pub latency_mode: bool,        // 低延迟模式
+    pub rewrite_to: Option<RewriteTarget>, // 改写目标地址/端口
+    pub port: Vec<u16>,            // 目标端口，与 port_range 合起来任一命中即可
+    pub port_range: Vec<RangeInclusive<u16>>, // 目标端口范围
+    pub network: Option<Network>,  // 传输协议
+    pub source_ip_cidr: Vec<IpNet>, // 客户端地址
+    pub inbound: Vec<String>,      // 接受连接的入站标签
+    pub ip_geoip: Vec<String>,     // 目标 IP 所在国家（小写 ISO 代码或 private），与规则集合任一命中即可
+}
+
+impl RouteRule {
+    /// Start building a rule routing to `outbound`
+    ///
+    /// ```
+    /// use anybls::routing::RouteRule;
+    ///
+    /// let rule = RouteRule::builder("proxy").rule_set("streaming").latency_mode(true).build();
+    /// assert_eq!(rule.rule_sets, vec!["streaming"]);
+    /// ```
+    pub fn builder(outbound: impl Into<String>) -> RouteRuleBuilder {
+        RouteRuleBuilder {
+            rule: RouteRule {
+                rule_sets: Vec::new(),
+                outbound: outbound.into(),
+                action: RouteAction::Forward,
+                outbound_chain: Vec::new(),
+                dscp: None,
+                latency_mode: false,
+                rewrite_to: None,
+                port: Vec::new(),
+                port_range: Vec::new(),
+                network: None,
+                source_ip_cidr: Vec::new(),
+                inbound: Vec::new(),
+                ip_geoip: Vec::new(),
+            },
+        }
+    }
+
+    /// 规则指纹 - 结构相同的规则指纹相同，用于热重载时保留计数
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.rule_sets.hash(&mut hasher);
+        self.outbound.hash(&mut hasher);
+        if self.has_conditions() {
+            self.port.hash(&mut hasher);
+            self.port_range.hash(&mut hasher);
+            self.network.hash(&mut hasher);
+            self.source_ip_cidr.hash(&mut hasher);
+            self.inbound.hash(&mut hasher);
+        }
+        if !self.ip_geoip.is_empty() {
+            self.ip_geoip.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// 端口条件的文本形式，端口范围写作 `first-last`
+    pub fn port_labels(&self) -> Vec<String> {
+        let ranges = self.port_range.iter().map(|range| format!("{}-{}", range.start(), range.end()));
+        self.port.iter().map(u16::to_string).chain(ranges).collect()
+    }
+
+    /// 规则是否带有目标以外的条件（端口、传输协议、来源地址、入站）
+    pub fn has_conditions(&self) -> bool {
+        !self.port.is_empty()
+            || !self.port_range.is_empty()
+            || self.network.is_some()
+            || !self.source_ip_cidr.is_empty()
+            || !self.inbound.is_empty()
+    }
+
+    /// 目标以外的条件是否都满足；上下文缺少对应信息时带该条件的规则不匹配
+    fn conditions_match(&self, context: &RouteContext) -> bool {
+        let port_matches = (self.port.is_empty() && self.port_range.is_empty())
+            || context.port.is_some_and(|port| {
+                self.port.contains(&port) || self.port_range.iter().any(|range| range.contains(&port))
+            });
+        let network_matches = self.network.is_none() || self.network == context.network;
+        // 映射到 IPv6 的 IPv4 客户端按 IPv4 地址匹配
+        let source_matches = self.source_ip_cidr.is_empty()
+            || context.source.is_some_and(|source| {
+                let ip = source.ip().to_canonical();
+                self.source_ip_cidr.iter().any(|net| net.contains(&ip))
+            });
+        let inbound_matches =
+            self.inbound.is_empty() || context.inbound.is_some_and(|tag| self.inbound.iter().any(|t| t == tag));
+        port_matches && network_matches && source_matches && inbound_matches
+    }
+}
+
+/// Builder for [`RouteRule`]
+#[derive(Debug, Clone)]
+pub struct RouteRuleBuilder {
+    rule: RouteRule,
+}
+
+impl RouteRuleBuilder {
+    /// Match the rule set with this id (any listed set may match)
+    pub fn rule_set(mut self, id: impl Into<RuleSetId>) -> Self {
+        self.rule.rule_sets.push(id.into());
+        self
+    }
+
+    /// Route through these outbounds in turn instead of the single outbound
+    pub fn chain(mut self, hops: &[&str]) -> Self {
+        self.rule.outbound_chain = hops.iter().map(|hop| hop.to_string()).collect();
+        self.rule.outbound = crate::config::chain_label(&self.rule.outbound_chain);
+        self
+    }
+
+    /// Reject or drop matching connections instead of forwarding them
+    pub fn action(mut self, action: RouteAction) -> Self {
+        self.rule.action = action;
+        if !action.is_forward() {
+            self.rule.outbound = action.as_str().to_string();
+        }
+        self
+    }
+
+    /// DSCP code point overriding the outbound's
+    pub fn dscp(mut self, dscp: u8) -> Self {
+        self.rule.dscp = Some(dscp);
+        self
+    }
+
+    pub fn latency_mode(mut self, enabled: bool) -> Self {
+        self.rule.latency_mode = enabled;
+        self
+    }
+
+    /// Only match connections to this destination port
+    pub fn port(mut self, port: u16) -> Self {
+        self.rule.port.push(port);
+        self
+    }
+
+    /// Only match connections to a destination port in `range`
+    pub fn port_range(mut self, range: RangeInclusive<u16>) -> Self {
+        self.rule.port_range.push(range);
+        self
+    }
+
+    /// Only match connections over this transport
+    pub fn network(mut self, network: Network) -> Self {
+        self.rule.network = Some(network);
+        self
+    }
+
+    /// Only match connections from clients in `net`
+    pub fn source_ip_cidr(mut self, net: IpNet) -> Self {
+        self.rule.source_ip_cidr.push(net);
+        self
+    }
+
+    /// Only match connections accepted by the inbound tagged `tag`
+    pub fn geoip(mut self, code: impl Into<String>) -> Self {
+        self.rule.ip_geoip.push(code.into().to_ascii_lowercase());
+        self
+    }
+
+    pub fn inbound(mut self, tag: impl Into<String>) -> Self {
+        self.rule.inbound.push(tag.into());
+        self
+    }
+
+    /// Send matching connections to another host and/or port
+    pub fn rewrite_to(mut self, target: RewriteTarget) -> Self {
+        self.rule.rewrite_to = Some(target);
+        self
+    }
+
+    pub fn build(self) -> RouteRule {
+        self.rule
+    }
+}
+
+/// 路由目标：域名或 IP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteHost<'a> {
+    Domain(&'a str),
+    Ip(IpAddr),
+}
+
+/// 路由输入：目标、目标端口、传输协议，以及连接的来源
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RouteContext<'a> {
+    pub host: RouteHost<'a>,
+    /// 目标端口；None 时带端口条件的规则不匹配
+    pub port: Option<u16>,
+    /// 传输协议；None 时带传输协议条件的规则不匹配
+    pub network: Option<Network>,
+    /// 客户端地址；None 时带来源条件的规则不匹配
+    pub source: Option<SocketAddr>,
+    /// 接受连接的入站标签；None 时带入站条件的规则不匹配
+    pub inbound: Option<&'a str>,
+}
+
+impl<'a> RouteContext<'a> {
+    /// Context with only the destination domain
+    pub fn domain(domain: &'a str) -> Self {
+        Self::host(RouteHost::Domain(domain))
+    }
+
+    /// Context with only the destination IP
+    pub fn ip(ip: IpAddr) -> Self {
+        Self::host(RouteHost::Ip(ip))
+    }
+
+    /// Context with only the destination
+    pub fn host(host: RouteHost<'a>) -> Self {
+        Self { host, port: None, network: None, source: None, inbound: None }
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    pub fn with_network(mut self, network: Network) -> Self {
+        self.network = Some(network);
+        self
+    }
+
+    pub fn with_source(mut self, source: SocketAddr) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    pub fn with_inbound(mut self, tag: &'a str) -> Self {
+        self.inbound = Some(tag);
+        self
+    }
+}
+
+/// 路由结果：选中的出站及命中的规则
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteDecision {
+    pub outbound: String,
+    /// 命中规则的动作；拒绝或丢弃时不连接 `outbound`
+    pub action: RouteAction,
+    /// 规则级代理链的各跳，为空时直接使用 `outbound`
+    pub outbound_chain: Vec<String>,
+    /// 命中规则的下标，None 表示走默认出站
+    pub rule: Option<usize>,
+    /// 命中规则指定的 DSCP，优先于出站配置
+    pub dscp: Option<u8>,
+    /// 命中规则要求低延迟模式（出站也可单独开启）
+    pub latency_mode: bool,
+    /// 命中规则改写的目标，改写后不再重新路由
+    pub rewrite_to: Option<RewriteTarget>,
+}
+
+/// 路由解释：决定及命中的规则集合
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteExplanation {
+    pub decision: RouteDecision,
+    /// 命中规则中第一个匹配的规则集合；走默认出站或命中只有端口/传输协议条件的规则时为 None
+    pub rule_set: Option<RuleSetId>,
+}
+
+impl std::fmt::Display for RouteExplanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.decision.rule, &self.rule_set) {
+            (Some(index), Some(rule_set)) => write!(f, "rule #{} ({}) -> {}", index, rule_set, self.decision.outbound),
+            (Some(index), None) => write!(f, "rule #{} -> {}", index, self.decision.outbound),
+            (None, _) => write!(f, "default -> {}", self.decision.outbound),
+        }
+    }
+}
+
+/// 路由表导出：结构与内容确定，可序列化，用于比较两份配置
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RouterDump {
+    pub rules: Vec<RuleDump>,
+    pub default_outbound: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, RouterDump>,
+}
+
+/// 导出的单条规则
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RuleDump {
+    pub index: usize,
+    pub outbound: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dscp: Option<u8>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub latency_mode: bool,
+    /// 端口条件，端口范围写作 `first-last`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<Network>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub source_ip_cidr: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub inbound: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ip_geoip: Vec<String>,
+    pub rule_sets: Vec<RuleSetDump>,
+}
+
+/// 导出的规则集合：条目数与内容哈希
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RuleSetDump {
+    pub tag: RuleSetId,
+    /// 规则集合未加载（例如加载失败被跳过）时为 false
+    pub loaded: bool,
+    pub entries: usize,
+    /// 排序后条目的 SHA-256 前 8 字节（十六进制），与条目顺序无关
+    pub hash: String,
+}
+
+impl std::fmt::Display for RuleSetDump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.loaded {
+            return write!(f, "{} (not loaded)", self.tag);
+        }
+        write!(f, "{} ({} entries, {})", self.tag, self.entries, self.hash)
+    }
+}
+
+/// 单条规则的命中计数
+struct RuleCounter {
+    fingerprint: u64,
+    hits: AtomicU64,
+    rule_set_hits: Vec<AtomicU64>,
+    /// 最后命中时间（相对路由器创建时间的毫秒数 + 1，0 表示从未命中）
+    last_hit_ms: AtomicU64,
+}
+
+impl RuleCounter {
+    fn new(rule: &RouteRule) -> Self {
+        Self {
+            fingerprint: rule.fingerprint(),
+            hits: AtomicU64::new(0),
+            rule_set_hits: rule.rule_sets.iter().map(|_| AtomicU64::new(0)).collect(),
+            last_hit_ms: AtomicU64::new(0),
+        }
+    }
+
+    fn reset(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+        self.last_hit_ms.store(0, Ordering::Relaxed);
+        for hits in &self.rule_set_hits {
+            hits.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// 规则命中统计
+#[derive(Debug, Clone)]
+pub struct RuleStats {
+    pub index: usize,
+    pub outbound: String,
+    pub fingerprint: u64,
+    pub hits: u64,
+    /// 每个规则集合的命中次数
+    pub rule_set_hits: Vec<(RuleSetId, u64)>,
+    /// 距最后一次命中的时间
+    pub last_hit_ago: Option<Duration>,
 }
 
 /// 高性能路由器
 pub struct HighPerformanceRouter {
-    rule_manager: RuleSetManager,
+    rule_manager: Arc<RuleSetManager>,
     matcher_cache: Arc<RwLock<MatcherCache>>,
     match_cache: Arc<RwLock<MatchCache>>,
+    geoip: Arc<GeoIpMatcher>,
     rules: Vec<RouteRule>,
+    /// 有规则带目标以外的条件时，决定不只取决于目标，不使用匹配缓存
+    conditional_rules: bool,
+    counters: Vec<RuleCounter>,
+    created_at: Instant,
     default_outbound: String,
+    /// 命名路由配置，与本路由器共享规则集合和匹配器
+    profiles: HashMap<String, Arc<HighPerformanceRouter>>,
 }
 
 impl HighPerformanceRouter {
     /// 创建新的路由器
     pub fn new(default_outbound: String) -> Self {
         Self {
-            rule_manager: RuleSetManager::new(),
+            rule_manager: Arc::new(RuleSetManager::new()),
             matcher_cache: Arc::new(RwLock::new(MatcherCache::new())),
             match_cache: Arc::new(RwLock::new(MatchCache::new(10000))),
+            geoip: Arc::new(GeoIpMatcher::without_database()),
             rules: Vec::new(),
+            conditional_rules: false,
+            counters: Vec::new(),
+            created_at: Instant::now(),
             default_outbound,
+            profiles: HashMap::new(),
         }
     }
 
     /// 添加路由规则
     pub fn add_rule(&mut self, rule: RouteRule) {
+        self.counters.push(RuleCounter::new(&rule));
+        self.conditional_rules |= rule.has_conditions();
         self.rules.push(rule);
     }
 
     /// 设置规则集合管理器
     pub fn set_rule_manager(&mut self, manager: RuleSetManager) {
-        self.rule_manager = manager;
+        self.rule_manager = Arc::new(manager);
+    }
+
+    /// 设置 `ip_geoip` 规则使用的 GeoIP 数据库，需在添加路由配置之前调用
+    pub fn set_geoip(&mut self, geoip: GeoIpMatcher) {
+        self.geoip = Arc::new(geoip);
+    }
+
+    /// 添加命名路由配置
+    ///
+    /// 配置共享本路由器的规则集合和已构建的匹配器，需在预构建匹配器之后调用；
+    /// 命中计数和匹配缓存各自独立。
+    pub fn add_profile(&mut self, name: String, default_outbound: String, rules: Vec<RouteRule>) {
+        let mut profile = Self {
+            rule_manager: self.rule_manager.clone(),
+            matcher_cache: self.matcher_cache.clone(),
+            match_cache: Arc::new(RwLock::new(self.match_cache.read().unwrap().empty_copy())),
+            geoip: self.geoip.clone(),
+            ..Self::new(default_outbound)
+        };
+        for rule in rules {
+            profile.add_rule(rule);
+        }
+        self.profiles.insert(name, Arc::new(profile));
+    }
+
+    /// 命名路由配置
+    pub fn profile(&self, name: &str) -> Option<Arc<HighPerformanceRouter>> {
+        self.profiles.get(name).cloned()
+    }
+
+    /// 所有路由配置名称（已排序）
+    pub fn profile_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// 并发预构建所有规则集合的匹配器，返回每个集合的耗时和失败
+    ///
+    /// 构建失败的集合保留在路由器中，由调用方决定是否移除。
+    pub async fn prebuild_matchers(&mut self, parallelism: usize) -> MatcherBuildReport {
+        let (cache, report) = MatcherCache::prebuild(&self.rule_manager, parallelism).await;
+        self.matcher_cache = Arc::new(RwLock::new(cache));
+        report
+    }
+
+    /// 移除规则集合，引用它的规则不再匹配它
+    pub fn remove_rule_set(&mut self, id: &str) {
+        self.rule_manager_mut().remove(id);
+    }
+
+    /// 规则集合管理器的可变引用；已与路由配置共享时先复制一份
+    fn rule_manager_mut(&mut self) -> &mut RuleSetManager {
+        Arc::make_mut(&mut self.rule_manager)
     }
 
     /// 选择出站 - 域名匹配
     pub fn select_outbound_for_domain(&self, domain: &str) -> String {
-        // 检查缓存
-        if let Some(cached_result) = self.match_cache.read().unwrap().get_domain(domain) {
-            if *cached_result == MatcherResult::Match {
-                return self.find_matching_outbound_for_domain(domain);
-            }
+        self.route_domain(domain).outbound
+    }
+
+    /// 选择出站 - IP匹配
+    pub fn select_outbound_for_ip(&self, ip: IpAddr) -> String {
+        self.route_ip(ip).outbound
+    }
+
+    /// 选择出站 - 按目标、端口和传输协议匹配
+    pub fn select_outbound(&self, context: &RouteContext) -> String {
+        self.route(context).outbound
+    }
+
+    /// 域名路由，同时返回命中的规则
+    pub fn route_domain(&self, domain: &str) -> RouteDecision {
+        self.route(&RouteContext::domain(domain))
+    }
+
+    /// IP路由，同时返回命中的规则
+    pub fn route_ip(&self, ip: IpAddr) -> RouteDecision {
+        self.route(&RouteContext::ip(ip))
+    }
+
+    /// 路由，同时返回命中的规则
+    pub fn route(&self, context: &RouteContext) -> RouteDecision {
+        if self.conditional_rules {
+            return self.decide_matched(self.first_match(context));
+        }
+        // 检查缓存：命中的规则（包括黑洞规则）只需一次查找
+        let cached = match context.host {
+            RouteHost::Domain(domain) => self.match_cache.write().unwrap().get_domain(domain),
+            RouteHost::Ip(ip) => self.match_cache.write().unwrap().get_ip(&ip),
+        };
+        if let Some(decision) = self.cached_decision(cached) {
+            return decision;
         }
 
         // 遍历规则
-        for rule in &self.rules {
-            if self.matches_domain_rule(domain, rule) {
-                // 缓存匹配结果
-                self.match_cache.write().unwrap().set_domain(domain.to_string(), MatcherResult::Match);
-                return rule.outbound.clone();
+        let matched = self.first_match(context);
+        let entry = Self::cache_entry(matched);
+        match context.host {
+            RouteHost::Domain(domain) => self.match_cache.write().unwrap().set_domain(domain.to_string(), entry),
+            RouteHost::Ip(ip) => self.match_cache.write().unwrap().set_ip(ip, entry),
+        }
+        self.decide_matched(matched)
+    }
+
+    /// 由缓存条目得出决定并记录命中；没有可用条目时返回 None
+    fn cached_decision(&self, cached: Option<MatcherResult>) -> Option<RouteDecision> {
+        match cached? {
+            MatcherResult::NoMatch => Some(self.decide(None)),
+            MatcherResult::Rule { rule, set } => {
+                let matched = self.rules.get(rule)?;
+                self.record_hit(rule, set);
+                Some(self.decide(Some((rule, matched))))
             }
+            MatcherResult::Match => None,
         }
+    }
 
-        // 缓存未匹配结果
-        self.match_cache.write().unwrap().set_domain(domain.to_string(), MatcherResult::NoMatch);
-        self.default_outbound.clone()
+    fn cache_entry(matched: Option<(usize, Option<usize>)>) -> MatcherResult {
+        match matched {
+            Some((rule, set)) => MatcherResult::Rule { rule, set },
+            None => MatcherResult::NoMatch,
+        }
     }
 
-    /// 选择出站 - IP匹配
-    pub fn select_outbound_for_ip(&self, ip: IpAddr) -> String {
-        // 检查缓存
-        if let Some(cached_result) = self.match_cache.read().unwrap().get_ip(&ip) {
-            if *cached_result == MatcherResult::Match {
-                return self.find_matching_outbound_for_ip(ip);
-            }
+    fn decide_matched(&self, matched: Option<(usize, Option<usize>)>) -> RouteDecision {
+        self.decide(matched.map(|(rule, _)| (rule, &self.rules[rule])))
+    }
+
+    /// 解释域名的路由决定：不经过匹配缓存，也不计入命中统计
+    pub fn explain_domain(&self, domain: &str) -> RouteExplanation {
+        self.explain(&RouteContext::domain(domain))
+    }
+
+    /// 解释IP的路由决定：不经过匹配缓存，也不计入命中统计
+    pub fn explain_ip(&self, ip: IpAddr) -> RouteExplanation {
+        self.explain(&RouteContext::ip(ip))
+    }
+
+    /// 解释路由决定：不经过匹配缓存，也不计入命中统计
+    pub fn explain(&self, context: &RouteContext) -> RouteExplanation {
+        let matched = self
+            .rules
+            .iter()
+            .enumerate()
+            .find_map(|(index, rule)| Some((index, rule, self.matching_set(context, rule)?)));
+        RouteExplanation {
+            decision: self.decide(matched.map(|(index, rule, _)| (index, rule))),
+            rule_set: matched.and_then(|(_, rule, set_index)| Some(rule.rule_sets[set_index?].clone())),
         }
+    }
 
-        // 遍历规则
-        for rule in &self.rules {
-            if self.matches_ip_rule(ip, rule) {
-                // 缓存匹配结果
-                self.match_cache.write().unwrap().set_ip(ip, MatcherResult::Match);
-                return rule.outbound.clone();
-            }
+    fn decide(&self, matched: Option<(usize, &RouteRule)>) -> RouteDecision {
+        match matched {
+            Some((index, rule)) => RouteDecision {
+                outbound: rule.outbound.clone(),
+                action: rule.action,
+                outbound_chain: rule.outbound_chain.clone(),
+                rule: Some(index),
+                dscp: rule.dscp,
+                latency_mode: rule.latency_mode,
+                rewrite_to: rule.rewrite_to.clone(),
+            },
+            None => RouteDecision {
+                outbound: self.default_outbound.clone(),
+                action: RouteAction::Forward,
+                outbound_chain: Vec::new(),
+                rule: None,
+                dscp: None,
+                latency_mode: false,
+                rewrite_to: None,
+            },
         }
+    }
 
-        // 缓存未匹配结果
-        self.match_cache.write().unwrap().set_ip(ip, MatcherResult::NoMatch);
-        self.default_outbound.clone()
+    /// 查找第一条匹配的规则并记录命中，返回规则及规则集合下标
+    fn first_match(&self, context: &RouteContext) -> Option<(usize, Option<usize>)> {
+        self.rules.iter().enumerate().find_map(|(index, rule)| {
+            let set_index = self.matching_set(context, rule)?;
+            self.record_hit(index, set_index);
+            Some((index, set_index))
+        })
     }
 
-    /// 检查域名是否匹配规则
-    fn matches_domain_rule(&self, domain: &str, rule: &RouteRule) -> bool {
-        for rule_set_id in &rule.rule_sets {
-            if let Some(domain_set) = self.rule_manager.get_domain_set(rule_set_id) {
-                if self.matches_domain_set(domain, domain_set) {
-                    return true;
-                }
-            }
+    /// 规则是否匹配：先检查端口和传输协议，再找第一个匹配目标的规则集合
+    ///
+    /// 没有规则集合但带条件的规则匹配任意目标，此时返回 `Some(None)`。
+    fn matching_set(&self, context: &RouteContext, rule: &RouteRule) -> Option<Option<usize>> {
+        if !rule.conditions_match(context) {
+            return None;
+        }
+        if rule.rule_sets.is_empty() && rule.ip_geoip.is_empty() {
+            return rule.has_conditions().then_some(None);
+        }
+        match context.host {
+            RouteHost::Domain(domain) => self.matching_domain_set(domain, rule).map(Some),
+            // GeoIP 只对 IP 目标生效；命中时没有对应的规则集合
+            RouteHost::Ip(ip) => match self.matching_ip_set(ip, rule) {
+                Some(set_index) => Some(Some(set_index)),
+                None => (!rule.ip_geoip.is_empty() && self.geoip.matches(ip, &rule.ip_geoip)).then_some(None),
+            },
         }
-        false
     }
 
-    /// 检查IP是否匹配规则
-    fn matches_ip_rule(&self, ip: IpAddr, rule: &RouteRule) -> bool {
-        for rule_set_id in &rule.rule_sets {
-            if let Some(ip_set) = self.rule_manager.get_ip_set(rule_set_id) {
-                if self.matches_ip_set(ip, ip_set) {
-                    return true;
-                }
-            }
+    /// 返回规则中第一个匹配域名的规则集合下标
+    fn matching_domain_set(&self, domain: &str, rule: &RouteRule) -> Option<usize> {
+        rule.rule_sets.iter().position(|rule_set_id| {
+            self.rule_manager
+                .get_domain_set(rule_set_id)
+                .is_some_and(|domain_set| self.matches_domain_set(domain, domain_set))
+        })
+    }
+
+    /// 返回规则中第一个匹配IP的规则集合下标
+    fn matching_ip_set(&self, ip: IpAddr, rule: &RouteRule) -> Option<usize> {
+        rule.rule_sets.iter().position(|rule_set_id| {
+            self.rule_manager
+                .get_ip_set(rule_set_id)
+                .is_some_and(|ip_set| self.matches_ip_set(ip, ip_set))
+        })
+    }
+
+    fn record_hit(&self, rule_index: usize, set_index: Option<usize>) {
+        let counter = &self.counters[rule_index];
+        counter.hits.fetch_add(1, Ordering::Relaxed);
+        if let Some(set_index) = set_index {
+            counter.rule_set_hits[set_index].fetch_add(1, Ordering::Relaxed);
         }
-        false
+        let now_ms = self.created_at.elapsed().as_millis() as u64 + 1;
+        counter.last_hit_ms.store(now_ms, Ordering::Relaxed);
     }
 
     /// 匹配域名集合
@@ -143,24 +692,158 @@ impl HighPerformanceRouter {
         matcher.matches(ip) == MatcherResult::Match
     }
 
-    /// 查找匹配的出站（用于缓存命中时）
-    fn find_matching_outbound_for_domain(&self, domain: &str) -> String {
-        for rule in &self.rules {
-            if self.matches_domain_rule(domain, rule) {
-                return rule.outbound.clone();
+    /// 获取每条规则的命中统计
+    pub fn rule_stats(&self) -> Vec<RuleStats> {
+        let now_ms = self.created_at.elapsed().as_millis() as u64 + 1;
+        self.rules
+            .iter()
+            .zip(&self.counters)
+            .enumerate()
+            .map(|(index, (rule, counter))| {
+                let last_hit_ms = counter.last_hit_ms.load(Ordering::Relaxed);
+                RuleStats {
+                    index,
+                    outbound: rule.outbound.clone(),
+                    fingerprint: counter.fingerprint,
+                    hits: counter.hits.load(Ordering::Relaxed),
+                    rule_set_hits: rule
+                        .rule_sets
+                        .iter()
+                        .cloned()
+                        .zip(counter.rule_set_hits.iter().map(|h| h.load(Ordering::Relaxed)))
+                        .collect(),
+                    last_hit_ago: (last_hit_ms > 0)
+                        .then(|| Duration::from_millis(now_ms.saturating_sub(last_hit_ms))),
+                }
+            })
+            .collect()
+    }
+
+    /// 列出未使用的规则
+    ///
+    /// `since` 为 `None` 时返回自启动（或上次重置）以来从未命中的规则，
+    /// 否则返回最近 `since` 时间内没有命中的规则。
+    pub fn unused_rules(&self, since: Option<Duration>) -> Vec<RuleStats> {
+        self.rule_stats()
+            .into_iter()
+            .filter(|stats| match (since, stats.last_hit_ago) {
+                (_, None) => true,
+                (None, Some(_)) => false,
+                (Some(window), Some(ago)) => ago > window,
+            })
+            .collect()
+    }
+
+    /// 重置所有规则命中计数
+    pub fn reset_rule_stats(&self) {
+        for counter in &self.counters {
+            counter.reset();
+        }
+    }
+
+    /// 从旧路由器继承结构相同规则的命中计数（热重载时使用）
+    pub fn inherit_rule_stats(&mut self, previous: &HighPerformanceRouter) {
+        for (name, profile) in &mut self.profiles {
+            if let (Some(profile), Some(old)) = (Arc::get_mut(profile), previous.profiles.get(name)) {
+                profile.inherit_rule_stats(old);
+            }
+        }
+        let mut taken = vec![false; previous.counters.len()];
+        for counter in &self.counters {
+            let Some(old_index) = previous
+                .counters
+                .iter()
+                .enumerate()
+                .position(|(i, old)| !taken[i] && old.fingerprint == counter.fingerprint)
+            else {
+                continue;
+            };
+            taken[old_index] = true;
+
+            let old = &previous.counters[old_index];
+            counter.hits.store(old.hits.load(Ordering::Relaxed), Ordering::Relaxed);
+            for (new_hits, old_hits) in counter.rule_set_hits.iter().zip(&old.rule_set_hits) {
+                new_hits.store(old_hits.load(Ordering::Relaxed), Ordering::Relaxed);
+            }
+            // 将最后命中时间换算到新路由器的时间基准
+            let old_last = old.last_hit_ms.load(Ordering::Relaxed);
+            if old_last > 0 {
+                let hit_at = previous.created_at + Duration::from_millis(old_last - 1);
+                let relative = hit_at.saturating_duration_since(self.created_at).as_millis() as u64;
+                counter.last_hit_ms.store(relative + 1, Ordering::Relaxed);
             }
         }
-        self.default_outbound.clone()
     }
 
-    /// 查找匹配的出站（用于缓存命中时）
-    fn find_matching_outbound_for_ip(&self, ip: IpAddr) -> String {
-        for rule in &self.rules {
-            if self.matches_ip_rule(ip, rule) {
-                return rule.outbound.clone();
+    /// 输出规则命中汇总日志
+    pub fn log_rule_stats(&self) {
+        for stats in self.rule_stats() {
+            info!(
+                "Rule #{} -> {}: {} hits ({})",
+                stats.index,
+                stats.outbound,
+                stats.hits,
+                stats
+                    .rule_set_hits
+                    .iter()
+                    .map(|(id, hits)| format!("{}={}", id, hits))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+    }
+
+    /// 导出路由表：规则顺序、引用的规则集合（条目数与内容哈希）、出站和默认出站
+    pub fn dump(&self) -> RouterDump {
+        RouterDump {
+            rules: self
+                .rules
+                .iter()
+                .enumerate()
+                .map(|(index, rule)| RuleDump {
+                    index,
+                    outbound: rule.outbound.clone(),
+                    dscp: rule.dscp,
+                    latency_mode: rule.latency_mode,
+                    ports: rule.port_labels(),
+                    network: rule.network,
+                    source_ip_cidr: rule.source_ip_cidr.iter().map(IpNet::to_string).collect(),
+                    inbound: rule.inbound.clone(),
+                    ip_geoip: rule.ip_geoip.clone(),
+                    rule_sets: rule.rule_sets.iter().map(|id| self.dump_rule_set(id)).collect(),
+                })
+                .collect(),
+            default_outbound: self.default_outbound.clone(),
+            profiles: self.profiles.iter().map(|(name, profile)| (name.clone(), profile.dump())).collect(),
+        }
+    }
+
+    fn dump_rule_set(&self, id: &RuleSetId) -> RuleSetDump {
+        let domain_set = self.rule_manager.get_domain_set(id);
+        let ip_set = self.rule_manager.get_ip_set(id);
+        let mut entries = Vec::new();
+        if let Some(set) = domain_set {
+            let lists = [
+                ("domain", &set.domain),
+                ("domain_suffix", &set.domain_suffix),
+                ("domain_keyword", &set.domain_keyword),
+                ("domain_regex", &set.domain_regex),
+            ];
+            for (kind, list) in lists {
+                entries.extend(list.iter().map(|entry| format!("{}:{}", kind, entry)));
             }
         }
-        self.default_outbound.clone()
+        if let Some(set) = ip_set {
+            entries.extend(set.ip_cidr.iter().map(|cidr| format!("ip_cidr:{}", cidr)));
+        }
+        entries.sort_unstable();
+        let digest = ring::digest::digest(&ring::digest::SHA256, entries.join("\n").as_bytes());
+        RuleSetDump {
+            tag: id.clone(),
+            loaded: domain_set.is_some() || ip_set.is_some(),
+            entries: entries.len(),
+            hash: digest.as_ref()[..8].iter().map(|b| format!("{:02x}", b)).collect(),
+        }
     }
 
     /// 获取缓存统计
@@ -168,9 +851,29 @@ impl HighPerformanceRouter {
         self.match_cache.read().unwrap().stats()
     }
 
-    /// 清除缓存
+    /// 使匹配缓存失效（增加代数，不逐条清除）
     pub fn clear_cache(&self) {
-        self.match_cache.write().unwrap().clear();
+        self.match_cache.write().unwrap().invalidate();
+    }
+
+    /// 替换匹配缓存（容量、TTL）
+    pub fn set_match_cache(&mut self, cache: MatchCache) {
+        self.match_cache = Arc::new(RwLock::new(cache));
+    }
+
+    /// 规则列表（按匹配顺序）
+    pub fn rules(&self) -> &[RouteRule] {
+        &self.rules
+    }
+
+    /// 规则集合管理器
+    pub fn rule_manager(&self) -> &RuleSetManager {
+        &self.rule_manager
+    }
+
+    /// 未命中任何规则时的出站
+    pub fn default_outbound(&self) -> &str {
+        &self.default_outbound
     }
 
     /// 获取规则数量
@@ -190,6 +893,26 @@ impl Default for HighPerformanceRouter {
     }
 }
 
+static GLOBAL_ROUTER: OnceLock<RwLock<Arc<HighPerformanceRouter>>> = OnceLock::new();
+
+fn global_router_slot() -> &'static RwLock<Arc<HighPerformanceRouter>> {
+    GLOBAL_ROUTER.get_or_init(|| RwLock::new(Arc::new(HighPerformanceRouter::default())))
+}
+
+/// Install the router used for new connections
+///
+/// Rule hit counters carry over from the router it replaces.
+pub fn set_global_router(mut router: HighPerformanceRouter) {
+    let mut current = global_router_slot().write().unwrap();
+    router.inherit_rule_stats(&current);
+    *current = Arc::new(router);
+}
+
+/// Get the global router (routes everything to "direct" until one is set)
+pub fn get_global_router() -> Arc<HighPerformanceRouter> {
+    global_router_slot().read().unwrap().clone()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -207,12 +930,23 @@ mod tests {
             domain_keyword: vec!["google".to_string()],
             domain_regex: vec![],
         };
-        router.rule_manager.add_domain_set(domain_set);
+        router.rule_manager_mut().add_domain_set(domain_set);
 
         // 添加路由规则
         let rule = RouteRule {
             rule_sets: vec!["google_domains".to_string()],
             outbound: "proxy".to_string(),
+            action: RouteAction::Forward,
+            outbound_chain: Vec::new(),
+            dscp: None,
+            latency_mode: false,
+            rewrite_to: None,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
         };
         router.add_rule(rule);
 
@@ -231,12 +965,23 @@ mod tests {
             id: "private_ips".to_string(),
             ip_cidr: vec!["192.168.0.0/16".to_string(), "10.0.0.0/8".to_string()],
         };
-        router.rule_manager.add_ip_set(ip_set);
+        router.rule_manager_mut().add_ip_set(ip_set);
 
         // 添加路由规则
         let rule = RouteRule {
             rule_sets: vec!["private_ips".to_string()],
             outbound: "direct".to_string(),
+            action: RouteAction::Forward,
+            outbound_chain: Vec::new(),
+            dscp: None,
+            latency_mode: false,
+            rewrite_to: None,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
         };
         router.add_rule(rule);
 
@@ -245,4 +990,326 @@ mod tests {
         assert_eq!(router.select_outbound_for_ip("10.1.1.1".parse().unwrap()), "direct");
         assert_eq!(router.select_outbound_for_ip("8.8.8.8".parse().unwrap()), "direct");
     }
+
+    fn keyword_set(id: &str, keyword: &str) -> DomainRuleSet {
+        DomainRuleSet {
+            id: id.to_string(),
+            domain: vec![],
+            domain_suffix: vec![],
+            domain_keyword: vec![keyword.to_string()],
+            domain_regex: vec![],
+        }
+    }
+
+    fn counting_router() -> HighPerformanceRouter {
+        let mut router = HighPerformanceRouter::new("direct".to_string());
+        router.rule_manager_mut().add_domain_set(keyword_set("google", "google"));
+        router.rule_manager_mut().add_domain_set(keyword_set("youtube", "youtube"));
+        router.rule_manager_mut().add_domain_set(keyword_set("netflix", "netflix"));
+        router.add_rule(RouteRule {
+            rule_sets: vec!["google".to_string(), "youtube".to_string()],
+            outbound: "proxy".to_string(),
+            action: RouteAction::Forward,
+            outbound_chain: Vec::new(),
+            dscp: None,
+            latency_mode: false,
+            rewrite_to: None,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
+        });
+        router.add_rule(RouteRule {
+            rule_sets: vec!["netflix".to_string()],
+            outbound: "stream".to_string(),
+            action: RouteAction::Forward,
+            outbound_chain: Vec::new(),
+            dscp: None,
+            latency_mode: false,
+            rewrite_to: None,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
+        });
+        router
+    }
+
+    #[test]
+    fn test_decision_carries_latency_mode() {
+        let mut router = counting_router();
+        router.rules[1].latency_mode = true;
+
+        assert!(!router.route_domain("www.google.com").latency_mode);
+        assert!(router.route_domain("www.netflix.com").latency_mode);
+        // 缓存命中时同样带上
+        assert!(router.route_domain("www.netflix.com").latency_mode);
+        assert!(!router.route_domain("other.com").latency_mode);
+    }
+
+    #[test]
+    fn test_rule_action_in_decision() {
+        let mut router = HighPerformanceRouter::new("direct".to_string());
+        router.rule_manager_mut().add_domain_set(keyword_set("ads", "ads"));
+        router.add_rule(RouteRule::builder("").action(RouteAction::Reject).rule_set("ads").build());
+        router.add_rule(RouteRule::builder("").action(RouteAction::Drop).port(23).build());
+
+        let rejected = router.route_domain("ads.example");
+        assert_eq!((rejected.action, rejected.outbound.as_str(), rejected.rule), (RouteAction::Reject, "reject", Some(0)));
+        // 缓存命中时动作不变
+        assert_eq!(router.route_domain("ads.example").action, RouteAction::Reject);
+        let dropped = router.route(&RouteContext::domain("telnet.example").with_port(23));
+        assert_eq!((dropped.action, dropped.outbound.as_str()), (RouteAction::Drop, "reject-drop"));
+        assert_eq!(router.route_domain("www.example").action, RouteAction::Forward);
+    }
+
+    #[test]
+    fn test_port_and_network_conditions() {
+        let mut router = HighPerformanceRouter::new("direct".to_string());
+        router.rule_manager_mut().add_domain_set(keyword_set("google", "google"));
+        router.rule_manager_mut().add_domain_set(keyword_set("netflix", "netflix"));
+        router.add_rule(RouteRule::builder("block").port(25).port(465).build());
+        router.add_rule(RouteRule::builder("direct").network(Network::Udp).port(443).build());
+        router.add_rule(RouteRule::builder("alt").rule_set("netflix").port_range(8000..=8999).build());
+        router.add_rule(RouteRule::builder("proxy").rule_set("google").build());
+        router.add_rule(RouteRule::builder("stream").rule_set("netflix").build());
+        let tcp = |domain, port| RouteContext::domain(domain).with_port(port).with_network(Network::Tcp);
+
+        // 只带端口条件的规则匹配该端口上的任意目标
+        assert_eq!(router.select_outbound(&tcp("mail.example", 25)), "block");
+        let ip = RouteContext::ip("192.0.2.1".parse().unwrap()).with_port(465).with_network(Network::Tcp);
+        assert_eq!(router.select_outbound(&ip), "block");
+        assert_eq!(router.select_outbound(&tcp("www.google.com", 443)), "proxy");
+        let quic = tcp("www.google.com", 443).with_network(Network::Udp);
+        assert_eq!(router.select_outbound(&quic), "direct");
+        assert_eq!(router.select_outbound(&tcp("www.netflix.com", 8080)), "alt");
+        assert_eq!(router.select_outbound(&tcp("www.netflix.com", 443)), "stream");
+        // 不带条件的规则照旧；上下文没有端口时带端口条件的规则不匹配
+        assert_eq!(router.select_outbound_for_domain("www.netflix.com"), "stream");
+        assert_eq!(router.select_outbound_for_domain("other.com"), "direct");
+
+        assert_eq!(router.explain(&tcp("mail.example", 25)).to_string(), "rule #0 -> block");
+        assert_eq!(router.explain(&tcp("www.netflix.com", 8080)).to_string(), "rule #2 (netflix) -> alt");
+        let stats = router.rule_stats();
+        assert_eq!((stats[0].hits, stats[1].hits, stats[2].hits), (2, 1, 1));
+        // 决定与端口有关，不按目标缓存
+        assert_eq!(router.get_cache_stats().total_size, 0);
+    }
+
+    #[test]
+    fn test_source_and_inbound_conditions() {
+        let mut router = HighPerformanceRouter::new("proxy".to_string());
+        router.rule_manager_mut().add_domain_set(keyword_set("netflix", "netflix"));
+        let tv: IpNet = "192.168.1.0/28".parse().unwrap();
+        router.add_rule(RouteRule::builder("direct").rule_set("netflix").source_ip_cidr(tv).build());
+        router.add_rule(RouteRule::builder("block").inbound("guest").build());
+        let from = |client: &str| RouteContext::domain("www.netflix.com").with_source(client.parse().unwrap());
+
+        // 同一目标按来源子网走不同出站
+        assert_eq!(router.select_outbound(&from("192.168.1.5:50000")), "direct");
+        assert_eq!(router.select_outbound(&from("[::ffff:192.168.1.5]:50000")), "direct");
+        assert_eq!(router.select_outbound(&from("192.168.1.20:50000")), "proxy");
+        assert_eq!(router.select_outbound_for_domain("www.netflix.com"), "proxy");
+        // 来源条件与规则集合同时满足才命中
+        assert_eq!(router.select_outbound(&from("192.168.1.5:50000").with_inbound("lan")), "direct");
+        let other = RouteContext::domain("example.com").with_source("192.168.1.5:50000".parse().unwrap());
+        assert_eq!(router.select_outbound(&other), "proxy");
+
+        assert_eq!(router.select_outbound(&from("192.168.1.20:50000").with_inbound("guest")), "block");
+        assert_eq!(router.select_outbound(&from("192.168.1.20:50000").with_inbound("lan")), "proxy");
+    }
+
+    #[test]
+    fn test_rule_hit_counters() {
+        let router = counting_router();
+
+        router.select_outbound_for_domain("www.google.com");
+        // 第二次走匹配缓存，仍需计数
+        router.select_outbound_for_domain("www.google.com");
+        router.select_outbound_for_domain("m.youtube.com");
+        router.select_outbound_for_domain("other.com");
+
+        let stats = router.rule_stats();
+        assert_eq!(stats[0].hits, 3);
+        assert_eq!(
+            stats[0].rule_set_hits,
+            vec![("google".to_string(), 2), ("youtube".to_string(), 1)]
+        );
+        assert!(stats[0].last_hit_ago.is_some());
+        assert_eq!(stats[1].hits, 0);
+
+        let unused = router.unused_rules(None);
+        assert_eq!(unused.len(), 1);
+        assert_eq!(unused[0].outbound, "stream");
+        assert_eq!(router.unused_rules(Some(Duration::from_secs(60))).len(), 1);
+        assert_eq!(router.unused_rules(Some(Duration::ZERO)).len(), 2);
+
+        router.reset_rule_stats();
+        assert!(router.rule_stats().iter().all(|s| s.hits == 0 && s.last_hit_ago.is_none()));
+        assert_eq!(router.unused_rules(None).len(), 2);
+    }
+
+    #[test]
+    fn test_blocked_decision_served_from_cache() {
+        let mut router = HighPerformanceRouter::new("direct".to_string());
+        router.rule_manager_mut().add_domain_set(DomainRuleSet {
+            id: "ads".to_string(),
+            domain: vec![],
+            domain_suffix: vec!["ads.example".to_string()],
+            domain_keyword: vec![],
+            domain_regex: vec![],
+        });
+        router.add_rule(RouteRule {
+            rule_sets: vec!["ads".to_string()],
+            outbound: "block".to_string(),
+            action: RouteAction::Forward,
+            outbound_chain: Vec::new(),
+            dscp: None,
+            latency_mode: false,
+            rewrite_to: None,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
+        });
+
+        for _ in 0..5 {
+            let decision = router.route_domain("tracker.ads.example");
+            assert_eq!(decision.outbound, "block");
+            assert_eq!(decision.rule, Some(0));
+        }
+        // 只有第一次遍历规则，之后都命中缓存；命中计数不受影响
+        let cache = router.get_cache_stats();
+        assert_eq!((cache.hits, cache.misses), (4, 1));
+        assert_eq!(router.rule_stats()[0].hits, 5);
+    }
+
+    #[test]
+    fn test_cached_decisions_equal_uncached() {
+        let two_rules = |cache: MatchCache| {
+            let mut router = counting_router();
+            router.rule_manager_mut().add_ip_set(IpRuleSet {
+                id: "lan".to_string(),
+                ip_cidr: vec!["192.168.0.0/16".to_string()],
+            });
+            router.rule_manager_mut().add_ip_set(IpRuleSet {
+                id: "dns".to_string(),
+                ip_cidr: vec!["8.8.8.8/32".to_string()],
+            });
+            for (set, outbound) in [("lan", "lan-out"), ("dns", "dns-out")] {
+                router.add_rule(RouteRule {
+                    rule_sets: vec![set.to_string()],
+                    outbound: outbound.to_string(),
+                    action: RouteAction::Forward,
+                    outbound_chain: Vec::new(),
+                    dscp: None,
+                    latency_mode: false,
+                    rewrite_to: None,
+                    port: Vec::new(),
+                    port_range: Vec::new(),
+                    network: None,
+                    source_ip_cidr: Vec::new(),
+                    inbound: Vec::new(),
+                    ip_geoip: Vec::new(),
+                });
+            }
+            router.set_match_cache(cache);
+            router
+        };
+        let cached = two_rules(MatchCache::new(100));
+        let uncached = two_rules(MatchCache::new(0));
+
+        // 第二轮全部命中缓存，结果（包括未命中任何规则的默认出站）与不缓存时一致
+        for _ in 0..2 {
+            for domain in ["www.google.com", "www.netflix.com", "other.com"] {
+                assert_eq!(cached.route_domain(domain), uncached.route_domain(domain));
+            }
+            for ip in ["192.168.1.1", "8.8.8.8", "1.1.1.1"] {
+                let ip: IpAddr = ip.parse().unwrap();
+                assert_eq!(cached.route_ip(ip), uncached.route_ip(ip));
+            }
+        }
+        assert_eq!(cached.select_outbound_for_domain("www.netflix.com"), "stream");
+        assert_eq!(cached.select_outbound_for_ip("8.8.8.8".parse().unwrap()), "dns-out");
+        let stats = cached.get_cache_stats();
+        assert_eq!((stats.hits, stats.misses), (8, 6));
+        assert_eq!(uncached.get_cache_stats().hits, 0);
+    }
+
+    #[test]
+    fn test_match_cache_evicts_least_recently_used() {
+        let mut router = counting_router();
+        router.set_match_cache(MatchCache::new(2));
+
+        router.select_outbound_for_domain("www.google.com");
+        router.select_outbound_for_domain("www.netflix.com");
+        // 读一次 google，netflix 成为最近最少使用的条目
+        router.select_outbound_for_domain("www.google.com");
+        router.select_outbound_for_domain("other.com");
+        let cache = router.get_cache_stats();
+        assert_eq!((cache.domain_cache_size, cache.hits, cache.misses, cache.evicted), (2, 1, 3, 1));
+
+        // google 仍在缓存中；netflix 需要重新匹配，并淘汰 other.com
+        assert_eq!(router.select_outbound_for_domain("www.google.com"), "proxy");
+        assert_eq!(router.select_outbound_for_domain("www.netflix.com"), "stream");
+        let cache = router.get_cache_stats();
+        assert_eq!((cache.hits, cache.misses, cache.evicted), (2, 4, 2));
+        router.select_outbound_for_domain("other.com");
+        assert_eq!(router.get_cache_stats().misses, 5);
+    }
+
+    #[test]
+    fn test_rule_counters_survive_reload() {
+        let old = counting_router();
+        old.select_outbound_for_domain("www.google.com");
+        old.select_outbound_for_domain("www.netflix.com");
+
+        // 新配置：第一条规则的出站改变，第二条保持不变
+        let mut new = HighPerformanceRouter::new("direct".to_string());
+        new.rule_manager_mut().add_domain_set(keyword_set("google", "google"));
+        new.rule_manager_mut().add_domain_set(keyword_set("netflix", "netflix"));
+        new.add_rule(RouteRule {
+            rule_sets: vec!["netflix".to_string()],
+            outbound: "stream".to_string(),
+            action: RouteAction::Forward,
+            outbound_chain: Vec::new(),
+            dscp: None,
+            latency_mode: false,
+            rewrite_to: None,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
+        });
+        new.add_rule(RouteRule {
+            rule_sets: vec!["google".to_string()],
+            outbound: "other".to_string(),
+            action: RouteAction::Forward,
+            outbound_chain: Vec::new(),
+            dscp: None,
+            latency_mode: false,
+            rewrite_to: None,
+            port: Vec::new(),
+            port_range: Vec::new(),
+            network: None,
+            source_ip_cidr: Vec::new(),
+            inbound: Vec::new(),
+            ip_geoip: Vec::new(),
+        });
+        new.inherit_rule_stats(&old);
+
+        let stats = new.rule_stats();
+        assert_eq!(stats[0].hits, 1);
+        assert_eq!(stats[0].rule_set_hits, vec![("netflix".to_string(), 1)]);
+        assert!(stats[0].last_hit_ago.is_some());
+        assert_eq!(stats[1].hits, 0);
+    }
 }
@@ -1,5 +1,5 @@
 // 规则集合数据结构
-use crate::error::Result;
+use crate::routing::matchers::{MatcherError, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
@@ -7,7 +7,7 @@ use std::collections::HashMap;
 pub type RuleSetId = String;
 
 /// 域名规则集合
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DomainRuleSet {
     pub id: RuleSetId,
     pub domain: Vec<String>,         // 完整域名匹配
@@ -16,13 +16,82 @@ pub struct DomainRuleSet {
     pub domain_regex: Vec<String>,   // 正则表达式匹配
 }
 
+impl DomainRuleSet {
+    /// Start building a domain rule set with the given id
+    ///
+    /// ```
+    /// use anybls::routing::DomainRuleSet;
+    ///
+    /// let set = DomainRuleSet::builder("streaming").suffix("netflix.com").keyword("youtube").build();
+    /// assert_eq!(set.domain_suffix, vec!["netflix.com"]);
+    /// ```
+    pub fn builder(id: impl Into<RuleSetId>) -> DomainRuleSetBuilder {
+        DomainRuleSetBuilder {
+            set: DomainRuleSet {
+                id: id.into(),
+                domain: Vec::new(),
+                domain_suffix: Vec::new(),
+                domain_keyword: Vec::new(),
+                domain_regex: Vec::new(),
+            },
+        }
+    }
+}
+
+/// Builder for [`DomainRuleSet`]
+#[derive(Debug, Clone)]
+pub struct DomainRuleSetBuilder {
+    set: DomainRuleSet,
+}
+
+impl DomainRuleSetBuilder {
+    /// Match this exact domain
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        self.set.domain.push(domain.into());
+        self
+    }
+
+    /// Match this domain and its subdomains
+    pub fn suffix(mut self, suffix: impl Into<String>) -> Self {
+        self.set.domain_suffix.push(suffix.into());
+        self
+    }
+
+    /// Match domains containing this keyword
+    pub fn keyword(mut self, keyword: impl Into<String>) -> Self {
+        self.set.domain_keyword.push(keyword.into());
+        self
+    }
+
+    /// Match domains against this regular expression
+    pub fn regex(mut self, regex: impl Into<String>) -> Self {
+        self.set.domain_regex.push(regex.into());
+        self
+    }
+
+    pub fn build(self) -> DomainRuleSet {
+        self.set
+    }
+}
+
 /// IP规则集合
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct IpRuleSet {
     pub id: RuleSetId,
     pub ip_cidr: Vec<String>, // IP-CIDR列表
 }
 
+impl DomainRuleSet {
+    /// Number of entries across the four lists
+    pub fn len(&self) -> usize {
+        self.domain.len() + self.domain_suffix.len() + self.domain_keyword.len() + self.domain_regex.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
 /// 规则集合枚举
 #[derive(Debug, Clone)]
 pub enum RuleSet {
@@ -40,6 +109,7 @@ impl RuleSet {
 }
 
 /// 规则集合管理器
+#[derive(Clone)]
 pub struct RuleSetManager {
     domain_sets: HashMap<RuleSetId, DomainRuleSet>,
     ip_sets: HashMap<RuleSetId, IpRuleSet>,
@@ -63,6 +133,16 @@ impl RuleSetManager {
         self.ip_sets.insert(set.id.clone(), set);
     }
 
+    /// Add the domain and IP parts of one rule set, skipping an empty part
+    pub fn add_sets(&mut self, domain: DomainRuleSet, ip: IpRuleSet) {
+        if !domain.is_empty() {
+            self.add_domain_set(domain);
+        }
+        if !ip.ip_cidr.is_empty() {
+            self.add_ip_set(ip);
+        }
+    }
+
     /// 获取域名规则集合
     pub fn get_domain_set(&self, id: &RuleSetId) -> Option<&DomainRuleSet> {
         self.domain_sets.get(id)
@@ -73,6 +153,12 @@ impl RuleSetManager {
         self.ip_sets.get(id)
     }
 
+    /// 移除一个规则集合的域名和IP部分
+    pub fn remove(&mut self, id: &str) {
+        self.domain_sets.remove(id);
+        self.ip_sets.remove(id);
+    }
+
     /// 获取所有域名规则集合
     pub fn all_domain_sets(&self) -> &HashMap<RuleSetId, DomainRuleSet> {
         &self.domain_sets
@@ -93,9 +179,8 @@ impl RuleSetManager {
             rules: Vec<DomainRuleSet>,
         }
 
-        let file: DomainJsonFile = serde_json::from_str(json_content).map_err(|e| {
-            crate::error::ProxyError::Protocol(format!("Invalid domain JSON: {}", e))
-        })?;
+        let file: DomainJsonFile = serde_json::from_str(json_content)
+            .map_err(|e| MatcherError::Json { kind: "domain", reason: e.to_string() })?;
 
         for rule in file.rules {
             self.add_domain_set(rule);
@@ -112,7 +197,7 @@ impl RuleSetManager {
         }
 
         let file: IpJsonFile = serde_json::from_str(json_content)
-            .map_err(|e| crate::error::ProxyError::Protocol(format!("Invalid IP JSON: {}", e)))?;
+            .map_err(|e| MatcherError::Json { kind: "IP", reason: e.to_string() })?;
 
         for rule in file.rules {
             self.add_ip_set(rule);
@@ -122,6 +207,112 @@ impl RuleSetManager {
     }
 }
 
+/// sing-box 字段既可以是字符串也可以是列表
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Default for OneOrMany {
+    fn default() -> Self {
+        OneOrMany::Many(Vec::new())
+    }
+}
+
+impl OneOrMany {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(item) => vec![item],
+            OneOrMany::Many(items) => items,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SourceRule {
+    domain: OneOrMany,
+    domain_suffix: OneOrMany,
+    domain_keyword: OneOrMany,
+    domain_regex: OneOrMany,
+    ip_cidr: OneOrMany,
+}
+
+#[derive(Deserialize)]
+struct SourceFile {
+    rules: Vec<SourceRule>,
+}
+
+/// Parse a sing-box source rule set (`{"version": 1, "rules": [{"domain": [...], "ip_cidr": [...]}]}`)
+///
+/// The items of all rules are merged into one domain and one IP set, both
+/// with id `tag`; other rule fields are ignored.
+pub fn parse_sing_box_source(tag: &str, content: &str) -> Result<(DomainRuleSet, IpRuleSet)> {
+    let file: SourceFile = serde_json::from_str(content)
+        .map_err(|e| MatcherError::Json { kind: "sing-box source", reason: e.to_string() })?;
+    let mut domain = DomainRuleSet::builder(tag).build();
+    let mut ip = IpRuleSet { id: tag.to_string(), ip_cidr: Vec::new() };
+    for rule in file.rules {
+        domain.domain.extend(rule.domain.into_vec());
+        // ".example.com" 只匹配子域名，这里按后缀近似处理
+        domain
+            .domain_suffix
+            .extend(rule.domain_suffix.into_vec().into_iter().map(|s| s.trim_start_matches('.').to_string()));
+        domain.domain_keyword.extend(rule.domain_keyword.into_vec());
+        domain.domain_regex.extend(rule.domain_regex.into_vec());
+        ip.ip_cidr.extend(rule.ip_cidr.into_vec());
+    }
+    Ok((domain, ip))
+}
+
+impl RuleSetManager {
+    /// Load a sing-box source rule set, such as the MetaCubeX geosite/geoip JSON files, as `tag`
+    pub fn load_sing_box_source_json(&mut self, tag: &str, content: &str) -> Result<()> {
+        let (domain, ip) = parse_sing_box_source(tag, content)?;
+        self.add_sets(domain, ip);
+        Ok(())
+    }
+}
+
+/// Every rule set of a manager in one document, sorted by id
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RuleSetSnapshot {
+    pub domain_sets: Vec<DomainRuleSet>,
+    pub ip_sets: Vec<IpRuleSet>,
+}
+
+impl RuleSetManager {
+    pub fn snapshot(&self) -> RuleSetSnapshot {
+        let mut domain_sets: Vec<DomainRuleSet> = self.domain_sets.values().cloned().collect();
+        domain_sets.sort_by(|a, b| a.id.cmp(&b.id));
+        let mut ip_sets: Vec<IpRuleSet> = self.ip_sets.values().cloned().collect();
+        ip_sets.sort_by(|a, b| a.id.cmp(&b.id));
+        RuleSetSnapshot { domain_sets, ip_sets }
+    }
+
+    pub fn from_snapshot(snapshot: RuleSetSnapshot) -> Self {
+        let mut manager = Self::new();
+        snapshot.domain_sets.into_iter().for_each(|set| manager.add_domain_set(set));
+        snapshot.ip_sets.into_iter().for_each(|set| manager.add_ip_set(set));
+        manager
+    }
+
+    /// Export every rule set as one JSON document, for tooling that matches offline
+    pub fn to_snapshot_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(&self.snapshot())
+            .map_err(|e| MatcherError::Json { kind: "snapshot", reason: e.to_string() })
+    }
+
+    /// Load a document written by `to_snapshot_json`
+    pub fn from_snapshot_json(json: &str) -> Result<Self> {
+        let snapshot = serde_json::from_str(json)
+            .map_err(|e| MatcherError::Json { kind: "snapshot", reason: e.to_string() })?;
+        Ok(Self::from_snapshot(snapshot))
+    }
+}
+
 impl Default for RuleSetManager {
     fn default() -> Self {
         Self::new()
@@ -149,4 +340,128 @@ mod tests {
         assert!(manager.get_domain_set(&"test_domain".to_string()).is_some());
         assert!(manager.get_domain_set(&"nonexistent".to_string()).is_none());
     }
+
+    #[test]
+    fn test_snapshot_round_trip_keeps_match_attribution() {
+        use crate::routing::matchers::{DomainMatcher, IpMatcher, MatchDetail};
+
+        let mut manager = RuleSetManager::new();
+        manager.add_domain_set(
+            DomainRuleSet::builder("mixed")
+                .domain("example.com")
+                .suffix("google.com")
+                .keyword("tracker")
+                .regex(r"^ads\d+\.")
+                .build(),
+        );
+        manager.add_ip_set(IpRuleSet {
+            id: "lan".to_string(),
+            ip_cidr: vec!["10.0.0.0/8".to_string(), "10.1.0.0/16".to_string(), "fd00::/8".to_string()],
+        });
+
+        let json = manager.to_snapshot_json().unwrap();
+        let restored = RuleSetManager::from_snapshot_json(&json).unwrap();
+        assert_eq!(restored.snapshot(), manager.snapshot());
+        let err = RuleSetManager::from_snapshot_json("{").err().unwrap();
+        assert!(err.to_string().starts_with("Invalid snapshot JSON"), "{}", err);
+
+        let domains = DomainMatcher::from_rule_set(restored.get_domain_set(&"mixed".to_string()).unwrap()).unwrap();
+        let detail = |domain| domains.matches_detailed(domain);
+        assert_eq!(detail("example.com"), Some(MatchDetail::Exact("example.com".to_string())));
+        assert_eq!(detail("mail.google.com"), Some(MatchDetail::Suffix("google.com".to_string())));
+        assert_eq!(detail("google.com"), Some(MatchDetail::Suffix("google.com".to_string())));
+        assert_eq!(detail("eu.tracker.net"), Some(MatchDetail::Keyword("tracker".to_string())));
+        assert_eq!(detail("ads42.example.org"), Some(MatchDetail::Regex(r"^ads\d+\.".to_string())));
+        assert_eq!(detail("notgoogle.com"), None);
+
+        let ips = IpMatcher::from_rule_set(restored.get_ip_set(&"lan".to_string()).unwrap()).unwrap();
+        let cidr = |net: &str| Some(MatchDetail::Cidr(net.parse().unwrap()));
+        assert_eq!(ips.matches_detailed("10.1.2.3".parse().unwrap()), cidr("10.1.0.0/16"));
+        assert_eq!(ips.matches_detailed("10.2.0.1".parse().unwrap()), cidr("10.0.0.0/8"));
+        assert_eq!(ips.matches_detailed("fd12::1".parse().unwrap()), cidr("fd00::/8"));
+        assert_eq!(ips.matches_detailed("192.0.2.1".parse().unwrap()), None);
+    }
+
+    /// Trimmed in the layout of MetaCubeX meta-rules-dat geo/geosite/google.json
+    const GEOSITE_GOOGLE: &str = r#"{
+  "version": 2,
+  "rules": [
+    {
+      "domain": [
+        "google.com",
+        "googleapis.cn"
+      ],
+      "domain_suffix": [
+        ".1e100.net",
+        ".google.com",
+        ".googleapis.com",
+        ".gstatic.com",
+        "youtube.com"
+      ],
+      "domain_keyword": [
+        "google"
+      ],
+      "domain_regex": [
+        "^google\\.com\\.[a-z]{2}$"
+      ]
+    }
+  ]
+}"#;
+
+    /// Trimmed in the layout of MetaCubeX meta-rules-dat geo/geoip/cn.json
+    const GEOIP_CN: &str = r#"{
+  "version": 2,
+  "rules": [
+    {
+      "ip_cidr": [
+        "1.0.1.0/24",
+        "1.0.2.0/23",
+        "1.0.8.0/21",
+        "2400:3200::/32"
+      ]
+    }
+  ]
+}"#;
+
+    #[test]
+    fn test_load_sing_box_source_json() {
+        use crate::routing::matchers::{DomainMatcher, IpMatcher};
+
+        let mut manager = RuleSetManager::new();
+        manager.load_sing_box_source_json("geosite-google", GEOSITE_GOOGLE).unwrap();
+        manager.load_sing_box_source_json("geoip-cn", GEOIP_CN).unwrap();
+
+        let google = manager.get_domain_set(&"geosite-google".to_string()).unwrap();
+        assert_eq!(google.domain, ["google.com", "googleapis.cn"]);
+        assert_eq!(google.domain_suffix, ["1e100.net", "google.com", "googleapis.com", "gstatic.com", "youtube.com"]);
+        assert!(manager.get_ip_set(&"geosite-google".to_string()).is_none());
+        let domains = DomainMatcher::from_rule_set(google).unwrap();
+        assert!(domains.matches_detailed("www.youtube.com").is_some());
+        assert!(domains.matches_detailed("google.com.hk").is_some());
+        assert!(domains.matches_detailed("example.com").is_none());
+
+        assert!(manager.get_domain_set(&"geoip-cn".to_string()).is_none());
+        let ips = IpMatcher::from_rule_set(manager.get_ip_set(&"geoip-cn".to_string()).unwrap()).unwrap();
+        assert!(ips.matches_detailed("1.0.9.1".parse().unwrap()).is_some());
+        assert!(ips.matches_detailed("2400:3200::1".parse().unwrap()).is_some());
+        assert!(ips.matches_detailed("1.0.0.1".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_sing_box_source_rules_are_merged() {
+        let content = r#"{"version": 1, "rules": [
+            {"domain_suffix": "netflix.com", "ip_cidr": ["198.38.96.0/19"]},
+            {"domain": ["fast.com"], "ip_cidr": "2a00:86c0::/32"}
+        ]}"#;
+        let mut manager = RuleSetManager::new();
+        manager.load_sing_box_source_json("netflix", content).unwrap();
+        let domain = manager.get_domain_set(&"netflix".to_string()).unwrap();
+        assert_eq!(domain.domain, ["fast.com"]);
+        assert_eq!(domain.domain_suffix, ["netflix.com"]);
+        let ip = manager.get_ip_set(&"netflix".to_string()).unwrap();
+        assert_eq!(ip.ip_cidr, ["198.38.96.0/19", "2a00:86c0::/32"]);
+
+        let err = manager.load_sing_box_source_json("bad", r#"{"version": 1}"#).unwrap_err();
+        assert!(err.to_string().starts_with("Invalid sing-box source JSON"), "{}", err);
+    }
 }
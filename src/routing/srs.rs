@@ -0,0 +1,436 @@
+// sing-box 二进制规则集（.srs）解析：文件头 "SRS" 和版本号之后是 zlib 压缩的规则列表。
+//
+// 只取出规则中的 domain/domain_suffix/domain_keyword/domain_regex/ip_cidr；
+// 带有其他条件（端口、进程等）、取反或逻辑组合的规则无法表示，整条跳过而不是放宽为匹配更多流量。
+use crate::routing::matchers::{MatcherError, Result};
+use crate::routing::rule_sets::{DomainRuleSet, IpRuleSet, RuleSetManager};
+use flate2::read::ZlibDecoder;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use log::{debug, warn};
+use std::io::Read;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+const MAGIC: &[u8] = b"SRS";
+/// Newest format version understood (sing-box 1.11)
+const MAX_VERSION: u8 = 3;
+/// Decompressed size limit, against zlib bombs
+const MAX_DECOMPRESSED_SIZE: u64 = 64 * 1024 * 1024;
+/// Nesting limit of logical rules
+const MAX_DEPTH: usize = 32;
+
+const RULE_DEFAULT: u8 = 0;
+const RULE_LOGICAL: u8 = 1;
+
+const ITEM_QUERY_TYPE: u8 = 0;
+const ITEM_NETWORK: u8 = 1;
+const ITEM_DOMAIN: u8 = 2;
+const ITEM_DOMAIN_KEYWORD: u8 = 3;
+const ITEM_DOMAIN_REGEX: u8 = 4;
+const ITEM_SOURCE_IP_CIDR: u8 = 5;
+const ITEM_IP_CIDR: u8 = 6;
+const ITEM_SOURCE_PORT: u8 = 7;
+const ITEM_SOURCE_PORT_RANGE: u8 = 8;
+const ITEM_PORT: u8 = 9;
+const ITEM_PORT_RANGE: u8 = 10;
+const ITEM_PROCESS_NAME: u8 = 11;
+const ITEM_PROCESS_PATH: u8 = 12;
+const ITEM_PACKAGE_NAME: u8 = 13;
+const ITEM_WIFI_SSID: u8 = 14;
+const ITEM_WIFI_BSSID: u8 = 15;
+const ITEM_ADGUARD_DOMAIN: u8 = 16;
+const ITEM_PROCESS_PATH_REGEX: u8 = 17;
+const ITEM_NETWORK_TYPE: u8 = 18;
+const ITEM_NETWORK_IS_EXPENSIVE: u8 = 19;
+const ITEM_NETWORK_IS_CONSTRAINED: u8 = 20;
+const ITEM_FINAL: u8 = 0xFF;
+
+/// Domain matcher keys start with these after reversing: `\r` a suffix as
+/// written (".example.com"), `\n` plus "." a domain with its subdomains
+const PREFIX_LABEL: char = '\r';
+const ROOT_LABEL: char = '\n';
+
+fn srs_error(reason: impl Into<String>) -> MatcherError {
+    MatcherError::Srs(reason.into())
+}
+
+/// Parse an SRS file into its domain and IP parts, both with id `tag`
+pub fn parse_srs(tag: &str, content: &[u8]) -> Result<(DomainRuleSet, IpRuleSet)> {
+    let rest = content.strip_prefix(MAGIC).ok_or_else(|| srs_error("missing the SRS magic bytes"))?;
+    let (&version, compressed) = rest.split_first().ok_or_else(|| srs_error("missing the version"))?;
+    if version == 0 || version > MAX_VERSION {
+        return Err(srs_error(format!("unsupported version {}", version)));
+    }
+    let mut data = Vec::new();
+    ZlibDecoder::new(compressed)
+        .take(MAX_DECOMPRESSED_SIZE + 1)
+        .read_to_end(&mut data)
+        .map_err(|e| srs_error(format!("decompression failed: {}", e)))?;
+    if data.len() as u64 > MAX_DECOMPRESSED_SIZE {
+        return Err(srs_error(format!("more than {} bytes decompressed", MAX_DECOMPRESSED_SIZE)));
+    }
+
+    let mut reader = Reader { data: &data, pos: 0 };
+    let mut domain = DomainRuleSet::builder(tag).build();
+    let mut ip = IpRuleSet { id: tag.to_string(), ip_cidr: Vec::new() };
+    let mut skipped = 0;
+    for _ in 0..reader.uvarint()? {
+        match read_rule(&mut reader, 0)? {
+            Some(rule) if !rule.invert && !rule.other_conditions => {
+                domain.domain.extend(rule.domain);
+                domain.domain_suffix.extend(rule.domain_suffix);
+                domain.domain_keyword.extend(rule.domain_keyword);
+                domain.domain_regex.extend(rule.domain_regex);
+                ip.ip_cidr.extend(rule.ip_cidr);
+            }
+            _ => skipped += 1,
+        }
+    }
+    if reader.pos != data.len() {
+        debug!("Rule set {}: {} trailing bytes after the rules", tag, data.len() - reader.pos);
+    }
+    if skipped > 0 {
+        warn!("Rule set {}: skipped {} srs rules with unsupported conditions", tag, skipped);
+    }
+    for list in [&mut domain.domain, &mut domain.domain_suffix, &mut domain.domain_keyword, &mut domain.domain_regex] {
+        list.sort();
+        list.dedup();
+    }
+    Ok((domain, ip))
+}
+
+impl RuleSetManager {
+    /// Load an SRS file as the domain and IP rule sets `tag`
+    pub fn load_from_srs_file(&mut self, tag: &str, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let content = std::fs::read(path).map_err(|e| srs_error(format!("{}: {}", path.display(), e)))?;
+        let (domain, ip) = parse_srs(tag, &content)?;
+        self.add_sets(domain, ip);
+        Ok(())
+    }
+}
+
+/// The items of a default rule
+#[derive(Default)]
+struct SrsRule {
+    domain: Vec<String>,
+    domain_suffix: Vec<String>,
+    domain_keyword: Vec<String>,
+    domain_regex: Vec<String>,
+    ip_cidr: Vec<String>,
+    /// Has conditions other than the domain and destination IP lists
+    other_conditions: bool,
+    invert: bool,
+}
+
+/// Read one rule; logical rules are read past and come back as None
+fn read_rule(reader: &mut Reader, depth: usize) -> Result<Option<SrsRule>> {
+    match reader.u8()? {
+        RULE_DEFAULT => read_default_rule(reader).map(Some),
+        RULE_LOGICAL => {
+            if depth >= MAX_DEPTH {
+                return Err(srs_error("logical rules nested too deeply"));
+            }
+            let _mode = reader.u8()?;
+            for _ in 0..reader.uvarint()? {
+                read_rule(reader, depth + 1)?;
+            }
+            let _invert = reader.u8()?;
+            Ok(None)
+        }
+        other => Err(srs_error(format!("unknown rule type {}", other))),
+    }
+}
+
+fn read_default_rule(reader: &mut Reader) -> Result<SrsRule> {
+    let mut rule = SrsRule::default();
+    loop {
+        match reader.u8()? {
+            ITEM_DOMAIN => read_domain_matcher(reader, &mut rule)?,
+            ITEM_DOMAIN_KEYWORD => rule.domain_keyword.extend(reader.strings()?),
+            ITEM_DOMAIN_REGEX => rule.domain_regex.extend(reader.strings()?),
+            ITEM_IP_CIDR => rule.ip_cidr.extend(read_ip_set(reader)?),
+            ITEM_SOURCE_IP_CIDR => {
+                read_ip_set(reader)?;
+                rule.other_conditions = true;
+            }
+            ITEM_QUERY_TYPE | ITEM_SOURCE_PORT | ITEM_PORT => {
+                reader.u16_list()?;
+                rule.other_conditions = true;
+            }
+            ITEM_NETWORK | ITEM_SOURCE_PORT_RANGE | ITEM_PORT_RANGE | ITEM_PROCESS_NAME | ITEM_PROCESS_PATH
+            | ITEM_PACKAGE_NAME | ITEM_WIFI_SSID | ITEM_WIFI_BSSID | ITEM_PROCESS_PATH_REGEX => {
+                reader.strings()?;
+                rule.other_conditions = true;
+            }
+            // AdGuard 规则与域名匹配器同样是字典树，但语义不同，不按域名列表处理
+            ITEM_ADGUARD_DOMAIN => {
+                skip_adguard_matcher(reader)?;
+                rule.other_conditions = true;
+            }
+            ITEM_NETWORK_TYPE => {
+                reader.bytes()?;
+                rule.other_conditions = true;
+            }
+            // 只有类型字节，没有内容
+            ITEM_NETWORK_IS_EXPENSIVE | ITEM_NETWORK_IS_CONSTRAINED => rule.other_conditions = true,
+            ITEM_FINAL => {
+                rule.invert = reader.u8()? != 0;
+                return Ok(rule);
+            }
+            other => return Err(srs_error(format!("unsupported rule item type {}", other))),
+        }
+    }
+}
+
+/// Domain matcher: a succinct trie of the reversed domains
+fn read_domain_matcher(reader: &mut Reader, rule: &mut SrsRule) -> Result<()> {
+    let version = reader.u8()?;
+    if version != 1 {
+        return Err(srs_error(format!("unsupported domain matcher version {}", version)));
+    }
+    let leaves = reader.u64_list()?;
+    let label_bitmap = reader.u64_list()?;
+    let labels = reader.bytes()?;
+    for key in succinct_keys(&leaves, &label_bitmap, labels)? {
+        let key = String::from_utf8(key).map_err(|_| srs_error("domain is not UTF-8"))?;
+        let domain: String = key.chars().rev().collect();
+        if let Some(suffix) = domain.strip_prefix(PREFIX_LABEL) {
+            // ".example.com" 只匹配子域名，这里与 source 格式一样按后缀近似处理
+            rule.domain_suffix.push(suffix.trim_start_matches('.').to_string());
+        } else if let Some(suffix) = domain.strip_prefix(ROOT_LABEL) {
+            rule.domain_suffix.push(suffix.trim_start_matches('.').to_string());
+        } else {
+            rule.domain.push(domain);
+        }
+    }
+    Ok(())
+}
+
+/// AdGuard domain matcher, laid out like the domain matcher
+fn skip_adguard_matcher(reader: &mut Reader) -> Result<()> {
+    let version = reader.u8()?;
+    if version != 1 {
+        return Err(srs_error(format!("unsupported AdGuard matcher version {}", version)));
+    }
+    reader.u64_list()?;
+    reader.u64_list()?;
+    reader.bytes()?;
+    Ok(())
+}
+
+/// Every key of a LOUDS-encoded trie
+///
+/// Nodes are numbered breadth first from the root. Walking the label bitmap,
+/// a 0 bit is an edge from the current node to its next child (labelled by
+/// the next byte of `labels`) and a 1 bit ends the current node's children;
+/// a node whose bit is set in `leaves` ends a key.
+fn succinct_keys(leaves: &[u64], label_bitmap: &[u64], labels: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let bit = |words: &[u64], i: usize| words.get(i / 64).is_some_and(|word| word >> (i % 64) & 1 == 1);
+    let mut prefixes: Vec<Vec<u8>> = vec![Vec::new()];
+    let mut keys = Vec::new();
+    let (mut node, mut label) = (0, 0);
+    for i in 0..label_bitmap.len() * 64 {
+        if node == prefixes.len() {
+            break;
+        }
+        if bit(label_bitmap, i) {
+            let prefix = std::mem::take(&mut prefixes[node]);
+            if bit(leaves, node) {
+                keys.push(prefix);
+            }
+            node += 1;
+        } else {
+            let &byte = labels.get(label).ok_or_else(|| srs_error("domain matcher labels truncated"))?;
+            label += 1;
+            let mut child = prefixes[node].clone();
+            child.push(byte);
+            prefixes.push(child);
+        }
+    }
+    if node != prefixes.len() {
+        return Err(srs_error("domain matcher bitmap truncated"));
+    }
+    Ok(keys)
+}
+
+/// IP set: address ranges, turned into the CIDRs covering them
+fn read_ip_set(reader: &mut Reader) -> Result<Vec<String>> {
+    let version = reader.u8()?;
+    if version != 1 {
+        return Err(srs_error(format!("unsupported IP set version {}", version)));
+    }
+    let count = reader.u64()?;
+    let mut cidrs = Vec::new();
+    for _ in 0..count {
+        let (from, to) = (reader.bytes()?, reader.bytes()?);
+        match (from.len(), to.len()) {
+            (4, 4) => {
+                let from = u32::from_be_bytes(from.try_into().unwrap());
+                let to = u32::from_be_bytes(to.try_into().unwrap());
+                for (start, prefix) in range_cidrs(from.into(), to.into(), 32)? {
+                    cidrs.push(IpNet::V4(Ipv4Net::new(Ipv4Addr::from(start as u32), prefix).unwrap()).to_string());
+                }
+            }
+            (16, 16) => {
+                let from = u128::from_be_bytes(from.try_into().unwrap());
+                let to = u128::from_be_bytes(to.try_into().unwrap());
+                for (start, prefix) in range_cidrs(from, to, 128)? {
+                    cidrs.push(IpNet::V6(Ipv6Net::new(Ipv6Addr::from(start), prefix).unwrap()).to_string());
+                }
+            }
+            (from, to) => return Err(srs_error(format!("IP range of {} and {} byte addresses", from, to))),
+        }
+    }
+    Ok(cidrs)
+}
+
+/// The fewest CIDRs exactly covering `from..=to`, as (network, prefix length)
+fn range_cidrs(from: u128, to: u128, bits: u32) -> Result<Vec<(u128, u8)>> {
+    if from > to {
+        return Err(srs_error("IP range ends before it starts"));
+    }
+    let host_mask = |size: u32| if size == 128 { u128::MAX } else { (1u128 << size) - 1 };
+    let mut cidrs = Vec::new();
+    let mut start = from;
+    loop {
+        // 从 start 开始、对齐且不超出 to 的最大块
+        let mut size = start.trailing_zeros().min(bits);
+        while start + host_mask(size) > to {
+            size -= 1;
+        }
+        cidrs.push((start, (bits - size) as u8));
+        let end = start + host_mask(size);
+        if end >= to {
+            return Ok(cidrs);
+        }
+        start = end + 1;
+    }
+}
+
+/// Reads the varint-framed values of the decompressed rule list
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.data.len()).ok_or_else(|| srs_error("truncated"))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn uvarint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(srs_error("varint overflows 64 bits"))
+    }
+
+    /// A count of items at least `item_size` bytes each, checked against the bytes left
+    fn count(&mut self, item_size: usize) -> Result<usize> {
+        let count = self.uvarint()?;
+        let left = (self.data.len() - self.pos) / item_size;
+        usize::try_from(count).ok().filter(|&count| count <= left).ok_or_else(|| srs_error("truncated"))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.count(1)?;
+        self.take(len)
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>> {
+        (0..self.count(1)?)
+            .map(|_| String::from_utf8(self.bytes()?.to_vec()).map_err(|_| srs_error("string is not UTF-8")))
+            .collect()
+    }
+
+    fn u16_list(&mut self) -> Result<Vec<u16>> {
+        (0..self.count(2)?).map(|_| Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))).collect()
+    }
+
+    fn u64_list(&mut self) -> Result<Vec<u64>> {
+        (0..self.count(8)?).map(|_| self.u64()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Built the way sing-box writes rule sets, version 1, with the rules
+    /// 0. domain example.com, domain_suffix google.com .cn googleapis.com,
+    ///    domain_keyword youtube, domain_regex ^ads\.
+    /// 1. ip_cidr 8.8.8.0/24, 10.0.0.0-10.0.2.255 (as a range), 2001:db8::/32
+    /// 2. port 443 with domain_keyword port-only
+    /// 3. inverted domain_keyword inverted
+    /// 4. a logical and of two domain_keyword logical rules
+    const FIXTURE: &[u8] = include_bytes!("../../tests/fixtures/sample.srs");
+
+    /// Version 3, each rule a domain_keyword named after what else it holds:
+    /// 0. kept, 1. adguard_domain, 2. network_type wifi, 3. network_is_expensive,
+    /// 4. network_is_constrained, 5. also-kept
+    const FIXTURE_V3: &[u8] = include_bytes!("../../tests/fixtures/sample_v3.srs");
+
+    #[test]
+    fn test_parse_fixture() {
+        let (domain, ip) = parse_srs("sample", FIXTURE).unwrap();
+        assert_eq!(domain.id, "sample");
+        assert_eq!(domain.domain, ["example.com"]);
+        assert_eq!(domain.domain_suffix, ["cn", "google.com", "googleapis.com"]);
+        assert_eq!(domain.domain_keyword, ["youtube"]);
+        assert_eq!(domain.domain_regex, [r"^ads\."]);
+        assert_eq!(ip.id, "sample");
+        assert_eq!(ip.ip_cidr, ["8.8.8.0/24", "10.0.0.0/23", "10.0.2.0/24", "2001:db8::/32"]);
+    }
+
+    #[test]
+    fn test_v3_items_drop_only_their_rules() {
+        let (domain, ip) = parse_srs("v3", FIXTURE_V3).unwrap();
+        assert_eq!(domain.domain_keyword, ["also-kept", "kept"]);
+        assert!(domain.domain.is_empty() && ip.ip_cidr.is_empty());
+    }
+
+    #[test]
+    fn test_rejects_malformed_files() {
+        assert!(parse_srs("bad", b"").is_err());
+        assert!(parse_srs("bad", b"{\"rules\": []}").is_err());
+        assert!(parse_srs("bad", b"SRS\x09").is_err());
+        // 截断的压缩数据
+        assert!(parse_srs("bad", &FIXTURE[..FIXTURE.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn test_range_cidrs() {
+        assert_eq!(range_cidrs(0, u32::MAX.into(), 32).unwrap(), [(0, 0)]);
+        assert_eq!(range_cidrs(0, u128::MAX, 128).unwrap(), [(0, 0)]);
+        assert_eq!(range_cidrs(5, 5, 32).unwrap(), [(5, 32)]);
+        assert_eq!(range_cidrs(1, 6, 32).unwrap(), [(1, 32), (2, 31), (4, 31), (6, 32)]);
+        assert!(range_cidrs(6, 1, 32).is_err());
+    }
+
+    #[test]
+    fn test_load_from_srs_file() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/sample.srs");
+        let mut manager = RuleSetManager::new();
+        manager.load_from_srs_file("geosite", path).unwrap();
+        assert_eq!(manager.get_domain_set(&"geosite".to_string()).unwrap().domain, ["example.com"]);
+        assert_eq!(manager.get_ip_set(&"geosite".to_string()).unwrap().ip_cidr.len(), 4);
+        assert!(manager.load_from_srs_file("missing", "/nonexistent/missing.srs").is_err());
+    }
+}
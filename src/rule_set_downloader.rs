@@ -1,11 +1,40 @@
 // 规则集下载器和缓存系统
+use crate::config::RuleSetFormat;
 use crate::error::{ProxyError, Result};
+use crate::integrity::{sha256_hex, SignatureSource};
+use log::error;
 use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use serde::{Deserialize, Serialize};
-use tokio::fs as async_fs;
+
+/// 默认缓存有效期：24小时
+pub const DEFAULT_MAX_CACHE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// 默认下载大小上限：64MB
+pub const DEFAULT_MAX_DOWNLOAD_BYTES: u64 = 64 * 1024 * 1024;
+
+/// 默认最多跟随的重定向次数
+pub const DEFAULT_MAX_REDIRECTS: usize = 5;
+
+/// Limits applied to every download
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DownloadLimits {
+    /// Largest body accepted, in bytes
+    pub max_bytes: u64,
+    /// Most redirects followed; an HTTPS download never follows one to plain HTTP
+    pub max_redirects: usize,
+}
+
+impl Default for DownloadLimits {
+    fn default() -> Self {
+        Self { max_bytes: DEFAULT_MAX_DOWNLOAD_BYTES, max_redirects: DEFAULT_MAX_REDIRECTS }
+    }
+}
+
+/// 下载内容及其 ETag、Last-Modified
+type Download = (Vec<u8>, Option<String>, Option<String>);
 
 /// 规则集缓存信息
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -17,6 +46,35 @@ pub struct RuleSetCacheInfo {
     pub file_path: PathBuf,
     pub download_time: u64,
     pub file_size: u64,
+    /// SHA-256 of the content as downloaded; absent in caches written by older versions
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// The content passed its detached signature check
+    #[serde(default)]
+    pub signature_verified: bool,
+}
+
+/// Result of re-checking one cached rule set against its recorded hash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheCheck {
+    Ok,
+    /// The cached file is gone or unreadable
+    Missing,
+    /// No hash was recorded for the file
+    Unrecorded,
+    /// The file changed after it was downloaded
+    Mismatch { actual: String },
+}
+
+impl std::fmt::Display for CacheCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheCheck::Ok => write!(f, "ok"),
+            CacheCheck::Missing => write!(f, "missing"),
+            CacheCheck::Unrecorded => write!(f, "no recorded hash"),
+            CacheCheck::Mismatch { actual } => write!(f, "hash mismatch (now {})", actual),
+        }
+    }
 }
 
 /// 规则集下载器
@@ -24,11 +82,21 @@ pub struct RuleSetDownloader {
     cache_dir: PathBuf,
     cache_info: HashMap<String, RuleSetCacheInfo>,
     cache_file: PathBuf,
+    limits: DownloadLimits,
+    client: reqwest::Client,
 }
 
 impl RuleSetDownloader {
     /// 创建新的规则集下载器
     pub fn new(cache_dir: impl AsRef<Path>) -> Result<Self> {
+        Self::with_limits(cache_dir, DownloadLimits::default())
+    }
+
+    /// 创建规则集下载器，按 limits 限制下载大小和重定向
+    pub fn with_limits(cache_dir: impl AsRef<Path>, limits: DownloadLimits) -> Result<Self> {
+        let client = Self::client_builder(limits)
+            .build()
+            .map_err(|e| ProxyError::Protocol(format!("Failed to create HTTP client: {}", e)))?;
         let cache_dir = cache_dir.as_ref().to_path_buf();
         let cache_file = cache_dir.join("rule_sets_cache.json");
         
@@ -43,8 +111,27 @@ impl RuleSetDownloader {
             cache_dir,
             cache_info,
             cache_file,
+            limits,
+            client,
         })
     }
+
+    /// HTTP 客户端：最多跟随 max_redirects 次重定向，HTTPS 不降级到 HTTP
+    fn client_builder(limits: DownloadLimits) -> reqwest::ClientBuilder {
+        let policy = reqwest::redirect::Policy::custom(move |attempt| {
+            let https = attempt.previous().first().is_some_and(|url| url.scheme() == "https");
+            if attempt.previous().len() > limits.max_redirects {
+                let error = format!("more than {} redirects", limits.max_redirects);
+                attempt.error(error)
+            } else if https && attempt.url().scheme() != "https" {
+                let error = format!("redirect from HTTPS to {}", attempt.url());
+                attempt.error(error)
+            } else {
+                attempt.follow()
+            }
+        });
+        reqwest::Client::builder().redirect(policy)
+    }
     
     /// 加载缓存信息
     fn load_cache_info(cache_file: &Path) -> Result<HashMap<String, RuleSetCacheInfo>> {
@@ -74,9 +161,30 @@ impl RuleSetDownloader {
     
     /// 下载规则集
     pub async fn download_rule_set(&mut self, tag: &str, url: &str) -> Result<PathBuf> {
-        // 检查是否已有缓存
+        self.download_rule_set_with_max_age(tag, url, DEFAULT_MAX_CACHE_AGE).await
+    }
+
+    /// 下载规则集，缓存未超过 max_age 时直接使用缓存
+    pub async fn download_rule_set_with_max_age(&mut self, tag: &str, url: &str, max_age: Duration) -> Result<PathBuf> {
+        self.download_rule_set_signed(tag, url, None, max_age, None).await
+    }
+
+    /// 下载规则集，检查内容是否符合声明的格式，并在接受前校验分离签名
+    ///
+    /// 内容被拒绝（超出大小上限、不安全的重定向、格式不符）或签名校验失败时
+    /// 保留之前缓存的版本（记录错误），没有可用的旧版本才返回错误。
+    pub async fn download_rule_set_signed(
+        &mut self,
+        tag: &str,
+        url: &str,
+        format: Option<RuleSetFormat>,
+        max_age: Duration,
+        signature: Option<&SignatureSource>,
+    ) -> Result<PathBuf> {
+        // 检查是否已有缓存；要求签名时未经校验的缓存不可用
         if let Some(cache_info) = self.cache_info.get(tag) {
-            if self.is_cache_valid(cache_info, url).await? {
+            let verified = signature.is_none() || cache_info.signature_verified;
+            if verified && self.is_cache_valid(cache_info, url, max_age).await? {
                 println!("使用缓存的规则集: {} -> {}", tag, cache_info.file_path.display());
                 return Ok(cache_info.file_path.clone());
             }
@@ -85,11 +193,39 @@ impl RuleSetDownloader {
         println!("下载规则集: {} -> {}", tag, url);
         
         // 下载文件
-        let (content, etag, last_modified) = self.download_file(url).await?;
-        
+        let download = match self.download_file(tag, url, format).await {
+            Ok(download) => download,
+            Err(e @ ProxyError::DownloadRejected { .. }) => return self.keep_previous(tag, e),
+            Err(e) => return Err(e),
+        };
+        let verification = match signature {
+            Some(source) => Some(self.check_signature(tag, &download.0, source).await),
+            None => None,
+        };
+        self.store_download(tag, url, download, verification)
+    }
+
+    /// 获取分离签名并校验内容
+    async fn check_signature(&self, tag: &str, content: &[u8], source: &SignatureSource) -> Result<()> {
+        let (signature, _, _) = self.download_file(tag, &source.url, None).await?;
+        source.key.verify(content, &signature)
+    }
+
+    /// 保存下载内容和来源信息；签名校验失败时改用之前的缓存版本
+    fn store_download(
+        &mut self,
+        tag: &str,
+        url: &str,
+        (content, etag, last_modified): Download,
+        verification: Option<Result<()>>,
+    ) -> Result<PathBuf> {
+        if let Some(Err(e)) = verification {
+            return self.keep_previous(tag, e);
+        }
+
         // 保存到缓存
         let file_path = self.cache_dir.join(format!("{}.srs", tag));
-        async_fs::write(&file_path, &content).await
+        fs::write(&file_path, &content)
             .map_err(|e| ProxyError::Io(e))?;
         
         // 更新缓存信息
@@ -104,6 +240,8 @@ impl RuleSetDownloader {
                 .unwrap()
                 .as_secs(),
             file_size: content.len() as u64,
+            sha256: Some(sha256_hex(&content)),
+            signature_verified: verification.is_some(),
         };
         
         self.cache_info.insert(tag.to_string(), cache_info);
@@ -112,9 +250,58 @@ impl RuleSetDownloader {
         println!("规则集下载完成: {} ({} 字节)", tag, content.len());
         Ok(file_path)
     }
+
+    /// 拒绝新内容：沿用完好的旧缓存版本，否则返回校验错误
+    fn keep_previous(&self, tag: &str, e: ProxyError) -> Result<PathBuf> {
+        match self.cache_info.get(tag) {
+            Some(info) if Self::check_entry(info) == CacheCheck::Ok => {
+                error!(
+                    "Rule set {}: rejected download, keeping version downloaded at {}: {}",
+                    tag, info.download_time, e
+                );
+                Ok(info.file_path.clone())
+            }
+            _ => {
+                error!("Rule set {}: rejected download and no intact cached version: {}", tag, e);
+                Err(e)
+            }
+        }
+    }
+
+    /// 按记录的哈希重新检查一个缓存文件
+    fn check_entry(info: &RuleSetCacheInfo) -> CacheCheck {
+        let Ok(content) = fs::read(&info.file_path) else {
+            return CacheCheck::Missing;
+        };
+        let Some(expected) = &info.sha256 else {
+            return CacheCheck::Unrecorded;
+        };
+        let actual = sha256_hex(&content);
+        if actual == *expected {
+            CacheCheck::Ok
+        } else {
+            CacheCheck::Mismatch { actual }
+        }
+    }
+
+    /// 按记录的哈希重新检查所有缓存文件，按标签排序
+    pub fn verify_cache(&self) -> Vec<(String, CacheCheck)> {
+        let mut checks: Vec<_> = self
+            .cache_info
+            .iter()
+            .map(|(tag, info)| (tag.clone(), Self::check_entry(info)))
+            .collect();
+        checks.sort_by(|a, b| a.0.cmp(&b.0));
+        checks
+    }
+
+    /// 规则集的来源信息（URL、ETag、下载时间、SHA-256）
+    pub fn provenance(&self, tag: &str) -> Option<&RuleSetCacheInfo> {
+        self.cache_info.get(tag)
+    }
     
     /// 检查缓存是否有效
-    async fn is_cache_valid(&self, cache_info: &RuleSetCacheInfo, url: &str) -> Result<bool> {
+    async fn is_cache_valid(&self, cache_info: &RuleSetCacheInfo, url: &str, max_age: Duration) -> Result<bool> {
         // 检查文件是否存在
         if !cache_info.file_path.exists() {
             return Ok(false);
@@ -124,6 +311,12 @@ impl RuleSetDownloader {
         if cache_info.url != url {
             return Ok(false);
         }
+
+        // 下载后被改动过的文件重新下载
+        if let CacheCheck::Mismatch { actual } = Self::check_entry(cache_info) {
+            error!("Rule set {}: cached file hash changed to {}, downloading again", cache_info.tag, actual);
+            return Ok(false);
+        }
         
         // 检查ETag和Last-Modified（暂时跳过，避免网络问题）
         // TODO: 实现更稳定的远程变更检查
@@ -138,14 +331,14 @@ impl RuleSetDownloader {
         // }
         
         // 如果没有ETag和Last-Modified信息，检查文件年龄
-        // 如果文件超过24小时，重新下载
+        // 如果文件超过 max_age，重新下载
         let file_age = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs()
             - cache_info.download_time;
         
-        Ok(file_age < 24 * 60 * 60) // 24小时
+        Ok(file_age < max_age.as_secs())
     }
     
     /// 检查远程文件是否有变化
@@ -155,8 +348,7 @@ impl RuleSetDownloader {
         etag: &Option<String>,
         last_modified: &Option<String>,
     ) -> Result<bool> {
-        let client = reqwest::Client::new();
-        let mut request = client.head(url);
+        let mut request = self.client.head(url);
         
         // 添加条件请求头
         if let Some(etag) = etag {
@@ -173,11 +365,20 @@ impl RuleSetDownloader {
         Ok(response.status() == reqwest::StatusCode::NOT_MODIFIED)
     }
     
-    /// 下载文件
-    async fn download_file(&self, url: &str) -> Result<(Vec<u8>, Option<String>, Option<String>)> {
-        let client = reqwest::Client::new();
-        let response = client.get(url).send().await
-            .map_err(|e| ProxyError::Protocol(format!("Failed to download file: {}", e)))?;
+    /// 下载文件；超过大小上限、不安全的重定向和与 format 不符的内容被拒绝
+    async fn download_file(&self, tag: &str, url: &str, format: Option<RuleSetFormat>) -> Result<Download> {
+        let rejected = |reason: String| ProxyError::DownloadRejected {
+            tag: tag.to_string(),
+            url: url.to_string(),
+            reason,
+        };
+        let mut response = self.client.get(url).send().await.map_err(|e| {
+            if e.is_redirect() {
+                rejected(error_chain(&e))
+            } else {
+                ProxyError::Protocol(format!("Failed to download file: {}", e))
+            }
+        })?;
         
         if !response.status().is_success() {
             return Err(ProxyError::Protocol(format!(
@@ -186,20 +387,30 @@ impl RuleSetDownloader {
             )));
         }
         
-        let etag = response.headers()
-            .get("etag")
-            .and_then(|h| h.to_str().ok())
-            .map(|s| s.to_string());
-        
-        let last_modified = response.headers()
-            .get("last-modified")
-            .and_then(|h| h.to_str().ok())
-            .map(|s| s.to_string());
-        
-        let content = response.bytes().await
-            .map_err(|e| ProxyError::Protocol(format!("Failed to read response: {}", e)))?;
-        
-        Ok((content.to_vec(), etag, last_modified))
+        let header = |name: &str| response.headers().get(name).and_then(|h| h.to_str().ok()).map(|s| s.to_string());
+        let etag = header("etag");
+        let last_modified = header("last-modified");
+        let content_type = header("content-type");
+
+        let max_bytes = self.limits.max_bytes;
+        if let Some(length) = response.content_length().filter(|length| *length > max_bytes) {
+            return Err(rejected(format!("body of {} bytes exceeds the limit of {} bytes", length, max_bytes)));
+        }
+        // 边读边计数，服务器不声明或谎报长度时也不会超出上限
+        let mut content = Vec::new();
+        while let Some(chunk) = response.chunk().await
+            .map_err(|e| ProxyError::Protocol(format!("Failed to read response: {}", e)))?
+        {
+            if (content.len() + chunk.len()) as u64 > max_bytes {
+                return Err(rejected(format!("body exceeds the limit of {} bytes", max_bytes)));
+            }
+            content.extend_from_slice(&chunk);
+        }
+
+        if let Some(format) = format {
+            check_content(format, content_type.as_deref(), &content).map_err(rejected)?;
+        }
+        Ok((content, etag, last_modified))
     }
     
     /// 获取规则集文件路径
@@ -249,20 +460,66 @@ impl RuleSetDownloader {
             .map(|info| info.file_size)
             .sum();
         
+        let mut rule_sets: Vec<_> = self.cache_info.values().cloned().collect();
+        rule_sets.sort_by(|a, b| a.tag.cmp(&b.tag));
+
         CacheStats {
             total_files,
             total_size,
             cache_dir: self.cache_dir.clone(),
+            rule_sets,
         }
     }
 }
 
+/// 错误及其来源，重定向策略的拒绝原因在来源中
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+/// sing-box 二进制规则集的文件头
+const SRS_MAGIC: &[u8] = b"SRS";
+
+/// 检查下载内容是否像声明的格式，避免把 HTML 错误页或登录页缓存成规则集
+fn check_content(format: RuleSetFormat, content_type: Option<&str>, content: &[u8]) -> std::result::Result<(), String> {
+    let mime = content_type.and_then(|value| value.split(';').next()).map(|mime| mime.trim().to_ascii_lowercase());
+    if let Some(mime) = mime.as_deref().filter(|mime| matches!(*mime, "text/html" | "application/xhtml+xml")) {
+        return Err(format!("served as {}, expected a {:?} rule set", mime, format));
+    }
+    if format == RuleSetFormat::Srs {
+        return match content.starts_with(SRS_MAGIC) {
+            true => Ok(()),
+            false => Err("missing the SRS magic bytes".to_string()),
+        };
+    }
+
+    let text = std::str::from_utf8(content).map_err(|_| "not UTF-8 text".to_string())?;
+    if text.contains('\0') {
+        return Err("contains NUL bytes, not a text file".to_string());
+    }
+    let text = text.trim_start_matches('\u{feff}').trim_start();
+    match format {
+        RuleSetFormat::Source if !text.starts_with('{') => Err("does not start with '{', not source JSON".to_string()),
+        _ if text.starts_with('<') => Err("looks like HTML or XML".to_string()),
+        _ => Ok(()),
+    }
+}
+
 /// 缓存统计信息
 #[derive(Debug, Clone)]
 pub struct CacheStats {
     pub total_files: usize,
     pub total_size: u64,
     pub cache_dir: PathBuf,
+    /// 每个缓存规则集的来源信息
+    pub rule_sets: Vec<RuleSetCacheInfo>,
 }
 
 impl std::fmt::Display for CacheStats {
@@ -270,6 +527,256 @@ impl std::fmt::Display for CacheStats {
         write!(f, "缓存统计: {} 个文件, {} 字节, 目录: {}", 
                self.total_files, 
                self.total_size, 
-               self.cache_dir.display())
+               self.cache_dir.display())?;
+        for info in &self.rule_sets {
+            write!(
+                f,
+                "\n  {}: {} etag={} sha256={} downloaded={}{}",
+                info.tag,
+                info.url,
+                info.etag.as_deref().unwrap_or("-"),
+                info.sha256.as_deref().unwrap_or("-"),
+                info.download_time,
+                if info.signature_verified { " signed" } else { "" }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrity::tests::{keypair, public_key};
+    use std::sync::Arc;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const URL: &str = "https://rules.example/ads.json";
+    const RULES: &str = r#"{"version": 1, "rules": []}"#;
+
+    fn downloader(name: &str) -> (RuleSetDownloader, PathBuf) {
+        limited_downloader(name, DownloadLimits::default())
+    }
+
+    fn limited_downloader(name: &str, limits: DownloadLimits) -> (RuleSetDownloader, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("anybls-downloader-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        (RuleSetDownloader::with_limits(&dir, limits).unwrap(), dir)
+    }
+
+    fn download(content: &str) -> (Vec<u8>, Option<String>, Option<String>) {
+        (content.as_bytes().to_vec(), Some("\"v1\"".to_string()), None)
+    }
+
+    #[test]
+    fn test_signed_download_records_provenance() {
+        let (mut downloader, dir) = downloader("signed");
+        let pair = keypair(1);
+        let content = r#"{"rules": []}"#;
+        let verification = public_key(&pair).verify(content.as_bytes(), pair.sign(content.as_bytes()).as_ref());
+        let path = downloader.store_download("ads", URL, download(content), Some(verification)).unwrap();
+
+        let info = downloader.provenance("ads").unwrap();
+        assert_eq!((info.url.as_str(), info.etag.as_deref()), (URL, Some("\"v1\"")));
+        assert_eq!(info.sha256.as_deref(), Some(sha256_hex(content.as_bytes()).as_str()));
+        assert!(info.signature_verified);
+        assert_eq!(downloader.verify_cache(), [("ads".to_string(), CacheCheck::Ok)]);
+
+        // 来源信息随缓存文件持久化
+        let reloaded = RuleSetDownloader::new(&dir).unwrap();
+        assert_eq!(reloaded.get_cache_stats().rule_sets[0].sha256, info.sha256);
+
+        // 下载后被改动的文件被检出
+        fs::write(&path, r#"{"rules": [{"domain": ["evil.example"]}]}"#).unwrap();
+        assert!(matches!(downloader.verify_cache()[0].1, CacheCheck::Mismatch { .. }));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rejected_signature_keeps_previous_version() {
+        let (mut downloader, dir) = downloader("fallback");
+        let pair = keypair(1);
+        let key = public_key(&pair);
+
+        // 没有旧版本时拒绝即失败
+        let forged = keypair(2).sign(b"v0");
+        let err = downloader.store_download("ads", URL, download("v0"), Some(key.verify(b"v0", forged.as_ref())));
+        assert!(matches!(err, Err(ProxyError::Integrity(_))), "{:?}", err);
+        assert!(downloader.provenance("ads").is_none());
+
+        let v1 = downloader.store_download("ads", URL, download("v1"), Some(key.verify(b"v1", pair.sign(b"v1").as_ref()))).unwrap();
+        let forged = keypair(2).sign(b"v2");
+        let kept = downloader.store_download("ads", URL, download("v2"), Some(key.verify(b"v2", forged.as_ref()))).unwrap();
+        assert_eq!(kept, v1);
+        assert_eq!(fs::read_to_string(&kept).unwrap(), "v1");
+        assert_eq!(downloader.provenance("ads").unwrap().sha256.as_deref(), Some(sha256_hex(b"v1").as_str()));
+
+        // 旧版本被改动过时也不能作为后备
+        fs::write(&v1, "tampered").unwrap();
+        let forged = keypair(2).sign(b"v3");
+        assert!(downloader.store_download("ads", URL, download("v3"), Some(key.verify(b"v3", forged.as_ref()))).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Answer one request with `response`, then close
+    async fn respond(mut stream: impl AsyncRead + AsyncWrite + Unpin, response: &str) {
+        let mut request = [0u8; 2048];
+        let _ = stream.read(&mut request).await;
+        let _ = stream.write_all(response.as_bytes()).await;
+        let _ = stream.shutdown().await;
+    }
+
+    /// Plain HTTP server answering every request with `response`
+    async fn http_server(response: String) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/ads.json", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let response = response.clone();
+                tokio::spawn(async move { respond(stream, &response).await });
+            }
+        });
+        url
+    }
+
+    fn ok(content_type: &str, body: &str) -> String {
+        format!("HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}", content_type, body.len(), body)
+    }
+
+    /// Downloader holding a good cached version of "ads"
+    fn with_cached(name: &str, limits: DownloadLimits) -> (RuleSetDownloader, PathBuf, PathBuf) {
+        let (mut downloader, dir) = limited_downloader(name, limits);
+        let cached = downloader.store_download("ads", URL, download(RULES), None).unwrap();
+        (downloader, dir, cached)
+    }
+
+    /// Download "ads" ignoring the cache's age
+    async fn refresh(downloader: &mut RuleSetDownloader, url: &str) -> Result<PathBuf> {
+        downloader.download_rule_set_signed("ads", url, Some(RuleSetFormat::Source), Duration::ZERO, None).await
+    }
+
+    fn assert_rejected(err: Result<PathBuf>, reason: &str) {
+        match err {
+            Err(ProxyError::DownloadRejected { tag, reason: actual, .. }) => {
+                assert_eq!(tag, "ads");
+                assert!(actual.contains(reason), "{}", actual);
+            }
+            other => panic!("expected a rejected download, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_body_rejected() {
+        let limits = DownloadLimits { max_bytes: 64, ..DownloadLimits::default() };
+        let (mut downloader, dir, cached) = with_cached("oversized", limits);
+        let body = format!("{{\"rules\": [], \"padding\": \"{}\"}}", "x".repeat(100));
+
+        // 声明的长度超限
+        let url = http_server(ok("application/json", &body)).await;
+        assert_eq!(refresh(&mut downloader, &url).await.unwrap(), cached);
+        // 不声明长度时边读边计数
+        let url = http_server(format!("HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n{}", body)).await;
+        assert_eq!(refresh(&mut downloader, &url).await.unwrap(), cached);
+        assert_eq!(fs::read_to_string(&cached).unwrap(), RULES);
+        assert_eq!(downloader.provenance("ads").unwrap().url, URL);
+
+        // 没有旧版本时返回带原因的错误
+        let (mut empty, empty_dir) = limited_downloader("oversized-empty", limits);
+        assert_rejected(refresh(&mut empty, &url).await, "exceeds the limit of 64 bytes");
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&empty_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_html_instead_of_json_rejected() {
+        let (mut downloader, dir, cached) = with_cached("html", DownloadLimits::default());
+        let login = "<!DOCTYPE html><html><body>Please sign in</body></html>";
+
+        let url = http_server(ok("text/html; charset=utf-8", login)).await;
+        assert_eq!(refresh(&mut downloader, &url).await.unwrap(), cached);
+        // 内容类型写错时仍按内容检出
+        let url = http_server(ok("application/json", login)).await;
+        assert_eq!(refresh(&mut downloader, &url).await.unwrap(), cached);
+        assert_eq!(fs::read_to_string(&cached).unwrap(), RULES);
+
+        // 符合格式的内容替换缓存
+        let fresh = r#"{"version": 1, "rules": [{"domain": ["ads.example"]}]}"#;
+        let url = http_server(ok("application/json", fresh)).await;
+        assert_eq!(fs::read_to_string(refresh(&mut downloader, &url).await.unwrap()).unwrap(), fresh);
+        assert_eq!(downloader.provenance("ads").unwrap().url, url);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_content_matches_format() {
+        assert!(check_content(RuleSetFormat::Srs, None, b"SRS\x01\x00").is_ok());
+        assert!(check_content(RuleSetFormat::Srs, None, RULES.as_bytes()).is_err());
+        assert!(check_content(RuleSetFormat::Source, Some("application/json"), b"\xef\xbb\xbf {}").is_ok());
+        assert!(check_content(RuleSetFormat::Domains, Some("text/plain"), b"ads.example\ntracker.example\n").is_ok());
+        assert!(check_content(RuleSetFormat::Domains, None, b"ads.example\0").is_err());
+        assert!(check_content(RuleSetFormat::Clash, None, b"<html></html>").is_err());
+        assert!(check_content(RuleSetFormat::Clash, Some("text/html"), b"payload:\n  - '+.ads.example'\n").is_err());
+    }
+
+    /// HTTPS server with a self-signed certificate redirecting every request to `location`
+    async fn https_redirect(location: String) -> String {
+        use rustls::ServerConfig;
+        use tokio_rustls::TlsAcceptor;
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let key = rustls::pki_types::PrivateKeyDer::Pkcs8(cert.key_pair.serialize_der().into());
+        let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.cert.der().clone()], key)
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("https://localhost:{}/ads.json", listener.local_addr().unwrap().port());
+        let response = format!("HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n", location);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (acceptor, response) = (acceptor.clone(), response.clone());
+                tokio::spawn(async move {
+                    if let Ok(tls) = acceptor.accept(stream).await {
+                        respond(tls, &response).await;
+                    }
+                });
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_unsafe_redirects_rejected() {
+        let (mut downloader, dir, cached) = with_cached("redirect", DownloadLimits::default());
+        // 信任测试服务器的自签名证书
+        downloader.client = RuleSetDownloader::client_builder(downloader.limits)
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+
+        // 重定向目标的内容本身合法，仍因降级到 HTTP 被拒绝
+        let plain = http_server(ok("application/json", r#"{"rules": [{"domain": ["evil.example"]}]}"#)).await;
+        let url = https_redirect(plain.clone()).await;
+        assert_eq!(refresh(&mut downloader, &url).await.unwrap(), cached);
+        assert_eq!(fs::read_to_string(&cached).unwrap(), RULES);
+
+        let (mut empty, empty_dir) = limited_downloader("redirect-empty", DownloadLimits::default());
+        empty.client = downloader.client.clone();
+        assert_rejected(refresh(&mut empty, &url).await, "redirect from HTTPS to http://");
+
+        // 超过重定向次数上限
+        let limits = DownloadLimits { max_redirects: 1, ..DownloadLimits::default() };
+        let (mut limited, limited_dir) = limited_downloader("redirect-limit", limits);
+        let hop = http_server(format!("HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n", plain)).await;
+        let first = http_server(format!("HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n", hop)).await;
+        assert_rejected(refresh(&mut limited, &first).await, "more than 1 redirects");
+        assert!(refresh(&mut limited, &hop).await.is_ok());
+        for dir in [dir, empty_dir, limited_dir] {
+            fs::remove_dir_all(&dir).unwrap();
+        }
     }
 }
@@ -0,0 +1,168 @@
+// IPv6 区域（scope ID）：解析 "fe80::1%eth0" 形式的链路本地地址，接口名经 if_nametoindex 转为索引
+use crate::error::{ProxyError, Result};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+
+/// Index of the network interface called `name`
+#[cfg(unix)]
+pub fn interface_index(name: &str) -> Option<u32> {
+    nix::net::if_::if_nametoindex(name).ok().filter(|index| *index != 0)
+}
+
+#[cfg(not(unix))]
+pub fn interface_index(_name: &str) -> Option<u32> {
+    None
+}
+
+/// Resolve a zone: a numeric scope ID or an interface name
+pub fn parse_zone(zone: &str) -> Result<u32> {
+    if zone.is_empty() {
+        return Err(ProxyError::Protocol("empty IPv6 zone".to_string()));
+    }
+    if let Ok(index) = zone.parse::<u32>() {
+        return Ok(index);
+    }
+    interface_index(zone).ok_or_else(|| ProxyError::Protocol(format!("unknown interface {:?}", zone)))
+}
+
+/// Parse an IPv6 literal with an optional `%zone` suffix
+///
+/// A missing zone gives scope ID 0, as in `SocketAddrV6`.
+pub fn parse_scoped_ipv6(s: &str) -> Result<(Ipv6Addr, u32)> {
+    let (ip, zone) = match s.split_once('%') {
+        Some((ip, zone)) => (ip, Some(zone)),
+        None => (s, None),
+    };
+    let ip = ip
+        .parse::<Ipv6Addr>()
+        .map_err(|_| ProxyError::Protocol(format!("invalid IPv6 address {:?}", s)))?;
+    let scope_id = zone.map(parse_zone).transpose()?.unwrap_or(0);
+    Ok((ip, scope_id))
+}
+
+/// IP address that may carry an IPv6 zone, for config fields
+///
+/// Written as `192.0.2.1`, `2001:db8::1` or `fe80::1%eth0` (`fe80::1%2`);
+/// brackets around IPv6 are accepted. Interface names are resolved when the
+/// value is parsed and displayed as their index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ScopedIp {
+    pub ip: IpAddr,
+    /// IPv6 scope ID, 0 when unscoped
+    pub scope_id: u32,
+}
+
+impl ScopedIp {
+    pub fn socket_addr(&self, port: u16) -> SocketAddr {
+        match self.ip {
+            IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, self.scope_id)),
+            ip => SocketAddr::new(ip, port),
+        }
+    }
+}
+
+impl From<IpAddr> for ScopedIp {
+    fn from(ip: IpAddr) -> Self {
+        Self { ip, scope_id: 0 }
+    }
+}
+
+impl From<SocketAddr> for ScopedIp {
+    fn from(addr: SocketAddr) -> Self {
+        let scope_id = match addr {
+            SocketAddr::V6(v6) => v6.scope_id(),
+            SocketAddr::V4(_) => 0,
+        };
+        Self { ip: addr.ip(), scope_id }
+    }
+}
+
+impl std::str::FromStr for ScopedIp {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bare = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(s);
+        if let Ok(ip) = bare.parse::<IpAddr>() {
+            return Ok(ip.into());
+        }
+        if !bare.contains('%') {
+            return Err(ProxyError::Protocol(format!("invalid IP address {:?}", s)));
+        }
+        let (ip, scope_id) = parse_scoped_ipv6(bare)?;
+        Ok(Self { ip: IpAddr::V6(ip), scope_id })
+    }
+}
+
+impl TryFrom<String> for ScopedIp {
+    type Error = ProxyError;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<ScopedIp> for String {
+    fn from(ip: ScopedIp) -> Self {
+        ip.to_string()
+    }
+}
+
+impl std::fmt::Display for ScopedIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.scope_id {
+            0 => write!(f, "{}", self.ip),
+            scope_id => write!(f, "{}%{}", self.ip, scope_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Index of the loopback interface under its usual name
+    fn loopback() -> Option<(&'static str, u32)> {
+        ["lo", "lo0"].into_iter().find_map(|name| interface_index(name).map(|index| (name, index)))
+    }
+
+    #[test]
+    fn test_parse_scoped_ip() {
+        let ip: ScopedIp = "fe80::1%7".parse().unwrap();
+        assert_eq!((ip.ip, ip.scope_id), ("fe80::1".parse().unwrap(), 7));
+        assert_eq!(ip.to_string(), "fe80::1%7");
+        assert_eq!("[fe80::1%7]".parse::<ScopedIp>().unwrap(), ip);
+        assert_eq!(ip.socket_addr(443).to_string(), "[fe80::1%7]:443");
+
+        let plain: ScopedIp = "192.0.2.1".parse().unwrap();
+        assert_eq!((plain.to_string(), plain.scope_id), ("192.0.2.1".to_string(), 0));
+        assert_eq!("[2001:db8::1]".parse::<ScopedIp>().unwrap().to_string(), "2001:db8::1");
+
+        for bad in ["192.0.2.1%1", "fe80::1%", "fe80::1%no-such-interface0", "example.com", "fe80::1%1%2"] {
+            assert!(bad.parse::<ScopedIp>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_interface_name_zone() {
+        let Some((name, index)) = loopback() else {
+            return;
+        };
+        let ip: ScopedIp = format!("fe80::1%{}", name).parse().unwrap();
+        assert_eq!(ip.scope_id, index);
+        // 名称解析后以索引显示，再次解析得到同一地址
+        assert_eq!(ip.to_string().parse::<ScopedIp>().unwrap(), ip);
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        #[derive(Serialize, Deserialize)]
+        struct Wrapper {
+            host: ScopedIp,
+        }
+        let parsed: Wrapper = toml::from_str(r#"host = "fe80::1%3""#).unwrap();
+        assert_eq!(parsed.host.scope_id, 3);
+        assert_eq!(toml::to_string(&parsed).unwrap().trim(), r#"host = "fe80::1%3""#);
+        assert!(toml::from_str::<Wrapper>(r#"host = "fe80::1%""#).is_err());
+    }
+}
@@ -0,0 +1,126 @@
+// splice 中继后端（仅 Linux）：两端都是普通 TcpStream 时，每个方向经一个管道用 splice(2) 在内核里
+// 搬运，数据不经过用户态缓冲；读写就绪沿用 tokio 反应器里这两个套接字已有的注册
+use crate::connection_registry::TrackedConnection;
+use crate::error::{ProxyError, Result};
+use crate::zero_copy::{RelayActivity, RelayDirection};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::time::Duration;
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+/// How one spliced direction ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Spliced {
+    /// The source reached EOF (or the destination stopped taking bytes)
+    /// after this many bytes; shutting down the destination is up to the caller
+    Finished(u64),
+    /// The kernel refused to splice from the source before any byte moved
+    Unsupported,
+}
+
+/// Nonblocking pipe carrying one direction
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+    /// Bytes the pipe holds; one splice moves at most this much
+    capacity: usize,
+}
+
+impl Pipe {
+    /// The kernel rounds `size` up to whole pages; when it refuses the size
+    /// (above `/proc/sys/fs/pipe-max-size` without CAP_SYS_RESOURCE) the
+    /// default capacity stays
+    fn new(size: usize) -> io::Result<Self> {
+        let mut fds: [RawFd; 2] = [-1; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // pipe2 刚返回的两个描述符归这里独占
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        let requested = libc::c_int::try_from(size).unwrap_or(libc::c_int::MAX);
+        let mut capacity = unsafe { libc::fcntl(write.as_raw_fd(), libc::F_SETPIPE_SZ, requested) };
+        if capacity < 0 {
+            log::debug!("Failed to resize splice pipe to {} bytes: {}", size, io::Error::last_os_error());
+            capacity = unsafe { libc::fcntl(write.as_raw_fd(), libc::F_GETPIPE_SZ) };
+        }
+        let capacity = usize::try_from(capacity).map_err(|_| io::Error::last_os_error())?;
+        Ok(Self { read, write, capacity })
+    }
+}
+
+/// splice(2) without offsets; EAGAIN means the socket end is not ready
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    let n = unsafe { libc::splice(from, std::ptr::null_mut(), to, std::ptr::null_mut(), len, flags) };
+    usize::try_from(n).map_err(|_| io::Error::last_os_error())
+}
+
+/// Move `source` to `dest` through a pipe of about `pipe_size` bytes until
+/// `source` reaches EOF
+///
+/// Counts bytes into `tracker` and `activity` like the buffered loop, and a
+/// write blocked longer than `write_stall` fails the direction.
+pub(crate) async fn splice_data(
+    source: &TcpStream,
+    dest: &TcpStream,
+    pipe_size: usize,
+    write_stall: Option<Duration>,
+    tracker: Option<&TrackedConnection>,
+    direction: RelayDirection,
+    activity: &RelayActivity,
+) -> Result<Spliced> {
+    let pipe = Pipe::new(pipe_size)?;
+    let mut total_bytes = 0u64;
+
+    loop {
+        // 套接字 → 管道：每轮开始时管道是空的，EAGAIN 只可能来自套接字
+        let read = source
+            .async_io(Interest::READABLE, || splice(source.as_raw_fd(), pipe.write.as_raw_fd(), pipe.capacity))
+            .await;
+        let mut pending = match read {
+            Ok(0) => {
+                log::debug!("{}: source closed, total bytes spliced: {}", direction, total_bytes);
+                return Ok(Spliced::Finished(total_bytes));
+            }
+            Ok(n) => n,
+            // 管道还是空的，调用方可以换成缓冲循环接着搬
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) && total_bytes == 0 => {
+                log::debug!("{}: splice unsupported: {}", direction, e);
+                return Ok(Spliced::Unsupported);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        total_bytes += pending as u64;
+        activity.touch();
+        if let Some(tracker) = tracker {
+            match direction {
+                RelayDirection::ClientToTarget => tracker.add_upload(pending as u64),
+                RelayDirection::TargetToClient => tracker.add_download(pending as u64),
+            }
+        }
+
+        // 管道 → 套接字，直到管道排空
+        while pending > 0 {
+            let write = dest.async_io(Interest::WRITABLE, || splice(pipe.read.as_raw_fd(), dest.as_raw_fd(), pending));
+            let written = match write_stall {
+                Some(limit) => tokio::time::timeout(limit, write).await.map_err(|_| {
+                    ProxyError::WriteStalled(format!(
+                        "{}: write blocked for {:?}, total bytes: {}",
+                        direction, limit, total_bytes
+                    ))
+                })??,
+                None => write.await?,
+            };
+            if written == 0 {
+                log::debug!("{}: destination closed, total bytes spliced: {}", direction, total_bytes);
+                return Ok(Spliced::Finished(total_bytes));
+            }
+            pending -= written;
+            // 慢速接收端一点点取走也算活动
+            activity.touch();
+        }
+    }
+}
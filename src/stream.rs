@@ -0,0 +1,176 @@
+// 出站连接的流类型：明文TCP、TLS，以及其余协议封装（加密、分帧）的装箱流
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+
+/// A stream an outbound wraps around its connection, e.g. an encryption layer
+pub trait OutboundStream: AsyncRead + AsyncWrite + Send + Sync + Unpin {
+    /// The socket underneath, so socket options still reach it
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+/// Connection returned by an outbound
+///
+/// Plain sockets and TLS are variants of their own so the relay does not go
+/// through dynamic dispatch for them; only other wrappers are boxed.
+pub enum ProxyStream {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Boxed(Box<dyn OutboundStream>),
+}
+
+impl ProxyStream {
+    /// Box a wrapped stream
+    pub fn boxed(stream: impl OutboundStream + 'static) -> Self {
+        Self::Boxed(Box::new(stream))
+    }
+
+    /// The socket the stream runs over, if any
+    pub fn tcp_stream(&self) -> Option<&TcpStream> {
+        match self {
+            Self::Tcp(stream) => Some(stream),
+            Self::Tls(stream) => Some(stream.get_ref().0),
+            Self::Boxed(stream) => stream.tcp_stream(),
+        }
+    }
+
+    /// The plain socket, when nothing is layered on top of it
+    pub fn into_tcp(self) -> std::result::Result<TcpStream, Self> {
+        match self {
+            Self::Tcp(stream) => Ok(stream),
+            other => Err(other),
+        }
+    }
+
+    pub fn is_tcp(&self) -> bool {
+        matches!(self, Self::Tcp(_))
+    }
+}
+
+impl OutboundStream for TcpStream {
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+impl From<TcpStream> for ProxyStream {
+    fn from(stream: TcpStream) -> Self {
+        Self::Tcp(stream)
+    }
+}
+
+impl From<TlsStream<TcpStream>> for ProxyStream {
+    fn from(stream: TlsStream<TcpStream>) -> Self {
+        Self::Tls(Box::new(stream))
+    }
+}
+
+impl std::fmt::Debug for ProxyStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(stream) => f.debug_tuple("Tcp").field(stream).finish(),
+            Self::Tls(stream) => f.debug_tuple("Tls").field(stream.get_ref().0).finish(),
+            Self::Boxed(stream) => f.debug_tuple("Boxed").field(&stream.tcp_stream()).finish(),
+        }
+    }
+}
+
+impl OutboundStream for ProxyStream {
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        ProxyStream::tcp_stream(self)
+    }
+}
+
+impl AsyncRead for ProxyStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Boxed(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ProxyStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Boxed(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Self::Tls(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Self::Boxed(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Tcp(stream) => stream.is_write_vectored(),
+            Self::Tls(stream) => stream.is_write_vectored(),
+            Self::Boxed(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Boxed(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Boxed(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::net::TcpListener;
+
+    impl OutboundStream for DuplexStream {}
+
+    #[tokio::test]
+    async fn test_variants_relay_bytes_and_expose_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(listener.local_addr().unwrap()), listener.accept());
+        let (mut peer, _) = accepted.unwrap();
+
+        let mut tcp = ProxyStream::from(client.unwrap());
+        assert!(tcp.is_tcp());
+        assert!(tcp.tcp_stream().is_some());
+        tcp.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let (inner, mut other) = duplex(64);
+        let mut boxed = ProxyStream::boxed(inner);
+        assert!(!boxed.is_tcp());
+        assert!(boxed.tcp_stream().is_none());
+        other.write_all(b"pong").await.unwrap();
+        boxed.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+        assert!(boxed.into_tcp().is_err());
+    }
+}
@@ -0,0 +1,426 @@
+// 任务追踪：按子系统分组派生 tokio 任务，统计存活/累计数量，限制并发并支持按组取消
+use crate::error::{ProxyError, Result};
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Subsystem a spawned task belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TaskGroup {
+    /// One task per accepted client connection
+    InboundConns,
+    /// Accept loops of listeners
+    Listeners,
+    PoolCleanup,
+    RulesetRefresh,
+    HealthChecks,
+    UdpSessions,
+    /// Access log writer
+    AccessLog,
+    /// Background refresh of popular DNS answers
+    DnsPrefetch,
+    /// Periodic purge of expired DNS answers
+    DnsCacheCleanup,
+    /// Config reloads on SIGHUP
+    ConfigReload,
+    /// Short answers to clients turned away at `server.max_connections`
+    Refusals,
+}
+
+impl TaskGroup {
+    pub const ALL: [TaskGroup; 11] = [
+        TaskGroup::InboundConns,
+        TaskGroup::Listeners,
+        TaskGroup::PoolCleanup,
+        TaskGroup::RulesetRefresh,
+        TaskGroup::HealthChecks,
+        TaskGroup::UdpSessions,
+        TaskGroup::AccessLog,
+        TaskGroup::DnsPrefetch,
+        TaskGroup::DnsCacheCleanup,
+        TaskGroup::ConfigReload,
+        TaskGroup::Refusals,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TaskGroup::InboundConns => "inbound-conns",
+            TaskGroup::Listeners => "listeners",
+            TaskGroup::PoolCleanup => "pool-cleanup",
+            TaskGroup::RulesetRefresh => "ruleset-refresh",
+            TaskGroup::HealthChecks => "health-checks",
+            TaskGroup::UdpSessions => "udp-sessions",
+            TaskGroup::AccessLog => "access-log",
+            TaskGroup::DnsPrefetch => "dns-prefetch",
+            TaskGroup::DnsCacheCleanup => "dns-cache-cleanup",
+            TaskGroup::ConfigReload => "config-reload",
+            TaskGroup::Refusals => "refusals",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for TaskGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Counters of one task group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskGroupStats {
+    pub group: TaskGroup,
+    /// Tasks currently running
+    pub live: usize,
+    /// Tasks spawned since start
+    pub spawned: u64,
+    /// Spawns refused because the group was full or shut down
+    pub rejected: u64,
+    pub limit: Option<usize>,
+}
+
+struct GroupState {
+    live: AtomicUsize,
+    spawned: AtomicU64,
+    rejected: AtomicU64,
+    /// 0 表示不限制
+    limit: AtomicUsize,
+    cancel: CancellationToken,
+    /// 存活数降到 0 时唤醒 wait_idle
+    idle: Notify,
+    /// 有任务结束或上限放宽时唤醒等待空位的 reserve_waiting
+    freed: Notify,
+}
+
+impl GroupState {
+    fn new() -> Self {
+        Self {
+            live: AtomicUsize::new(0),
+            spawned: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            limit: AtomicUsize::new(0),
+            cancel: CancellationToken::new(),
+            idle: Notify::new(),
+            freed: Notify::new(),
+        }
+    }
+}
+
+/// Releases a task's slot when the task finishes, panics or is aborted
+struct LiveGuard(Arc<GroupState>);
+
+impl Drop for LiveGuard {
+    fn drop(&mut self) {
+        if self.0.live.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.idle.notify_waiters();
+        }
+        self.0.freed.notify_waiters();
+    }
+}
+
+/// A place taken in a task group, spent by [`TaskTracker::spawn_reserved`]
+///
+/// Dropping it unspent gives the place back.
+pub struct TaskSlot {
+    guard: LiveGuard,
+}
+
+/// Inventory of the tasks the proxy spawns, by subsystem
+///
+/// Every long-lived or per-connection task goes through `spawn`, so leaks
+/// show up as a live count that keeps climbing instead of as memory growth.
+pub struct TaskTracker {
+    groups: Vec<Arc<GroupState>>,
+}
+
+impl TaskTracker {
+    pub fn new() -> Self {
+        Self {
+            groups: TaskGroup::ALL.iter().map(|_| Arc::new(GroupState::new())).collect(),
+        }
+    }
+
+    fn state(&self, group: TaskGroup) -> &Arc<GroupState> {
+        &self.groups[group.index()]
+    }
+
+    /// Cap the number of live tasks in `group` (None removes the cap)
+    pub fn set_limit(&self, group: TaskGroup, limit: Option<usize>) {
+        let state = self.state(group);
+        state.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+        state.freed.notify_waiters();
+    }
+
+    /// Take a place in `group` if it is below its limit
+    fn try_take(state: &Arc<GroupState>) -> Option<TaskSlot> {
+        let limit = state.limit.load(Ordering::Relaxed);
+        state
+            .live
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |live| {
+                (limit == 0 || live < limit).then_some(live + 1)
+            })
+            .ok()?;
+        Some(TaskSlot { guard: LiveGuard(state.clone()) })
+    }
+
+    /// Take a place in `group` for a task spawned later
+    ///
+    /// Fails when the group is at its limit or has been cancelled, counting
+    /// the refusal like `spawn` does.
+    pub fn reserve(&self, group: TaskGroup) -> Result<TaskSlot> {
+        let state = self.state(group);
+        if state.cancel.is_cancelled() {
+            state.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(ProxyError::TaskGroupClosed(group.name()));
+        }
+        Self::try_take(state).ok_or_else(|| {
+            state.rejected.fetch_add(1, Ordering::Relaxed);
+            ProxyError::TaskLimit { group: group.name(), limit: state.limit.load(Ordering::Relaxed) }
+        })
+    }
+
+    /// Like [`reserve`](Self::reserve), but waits for a task of a full
+    /// group to finish instead of failing
+    pub async fn reserve_waiting(&self, group: TaskGroup) -> Result<TaskSlot> {
+        let state = self.state(group);
+        loop {
+            let freed = state.freed.notified();
+            tokio::pin!(freed);
+            freed.as_mut().enable();
+            if state.cancel.is_cancelled() {
+                state.rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(ProxyError::TaskGroupClosed(group.name()));
+            }
+            if let Some(slot) = Self::try_take(state) {
+                return Ok(slot);
+            }
+            tokio::select! {
+                _ = freed => {}
+                _ = state.cancel.cancelled() => {}
+            }
+        }
+    }
+
+    /// Spawn `future` as part of `group`
+    ///
+    /// Fails without spawning when the group is at its limit or has been
+    /// cancelled; the future is dropped in that case, which for connection
+    /// tasks closes the client connection. The task resolves to None when the
+    /// group is cancelled before the future completes.
+    pub fn spawn<F>(&self, group: TaskGroup, future: F) -> Result<JoinHandle<Option<F::Output>>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let slot = self.reserve(group)?;
+        Ok(self.spawn_reserved(slot, future))
+    }
+
+    /// Spawn `future` in the place `slot` holds
+    ///
+    /// The task resolves to None when the group is cancelled before the
+    /// future completes.
+    pub fn spawn_reserved<F>(&self, slot: TaskSlot, future: F) -> JoinHandle<Option<F::Output>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let guard = slot.guard;
+        let state = &guard.0;
+        state.spawned.fetch_add(1, Ordering::Relaxed);
+        let cancel = state.cancel.clone();
+        tokio::spawn(async move {
+            let _guard = guard;
+            tokio::select! {
+                output = future => Some(output),
+                _ = cancel.cancelled() => None,
+            }
+        })
+    }
+
+    /// Stop every task of `group` and refuse new ones
+    pub fn cancel(&self, group: TaskGroup) {
+        self.state(group).cancel.cancel();
+    }
+
+    /// Wait until no task of `group` is running
+    pub async fn wait_idle(&self, group: TaskGroup) {
+        let state = self.state(group);
+        loop {
+            let notified = state.idle.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if state.live.load(Ordering::Acquire) == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    pub fn live(&self, group: TaskGroup) -> usize {
+        self.state(group).live.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> Vec<TaskGroupStats> {
+        TaskGroup::ALL
+            .iter()
+            .map(|&group| {
+                let state = self.state(group);
+                let limit = state.limit.load(Ordering::Relaxed);
+                TaskGroupStats {
+                    group,
+                    live: state.live.load(Ordering::Relaxed),
+                    spawned: state.spawned.load(Ordering::Relaxed),
+                    rejected: state.rejected.load(Ordering::Relaxed),
+                    limit: (limit > 0).then_some(limit),
+                }
+            })
+            .collect()
+    }
+}
+
+impl Default for TaskTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static GLOBAL_TASK_TRACKER: OnceLock<TaskTracker> = OnceLock::new();
+
+/// Get the global task tracker
+pub fn get_global_task_tracker() -> &'static TaskTracker {
+    GLOBAL_TASK_TRACKER.get_or_init(TaskTracker::new)
+}
+
+/// Per-group task counters of the global tracker
+pub fn task_stats() -> Vec<TaskGroupStats> {
+    get_global_task_tracker().stats()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{duplex, AsyncReadExt, DuplexStream};
+
+    /// Task that serves a mock connection until the peer closes it
+    async fn serve_mock(mut stream: DuplexStream) {
+        let mut buf = [0u8; 16];
+        while stream.read(&mut buf).await.unwrap_or(0) > 0 {}
+    }
+
+    async fn wait_for_live(tracker: &TaskTracker, group: TaskGroup, expected: usize) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while tracker.live(group) != expected {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("{} live tasks, expected {}", tracker.live(group), expected));
+    }
+
+    #[tokio::test]
+    async fn test_counts_follow_connections() {
+        let tracker = TaskTracker::new();
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let (client, server) = duplex(64);
+            tracker.spawn(TaskGroup::InboundConns, serve_mock(server)).unwrap();
+            clients.push(client);
+        }
+        assert_eq!(tracker.live(TaskGroup::InboundConns), 3);
+
+        clients.pop();
+        wait_for_live(&tracker, TaskGroup::InboundConns, 2).await;
+        clients.clear();
+        wait_for_live(&tracker, TaskGroup::InboundConns, 0).await;
+
+        let stats = tracker.stats();
+        let conns = stats.iter().find(|s| s.group == TaskGroup::InboundConns).unwrap();
+        assert_eq!((conns.live, conns.spawned, conns.rejected), (0, 3, 0));
+        assert!(stats.iter().filter(|s| s.group != TaskGroup::InboundConns).all(|s| s.spawned == 0));
+    }
+
+    #[tokio::test]
+    async fn test_limit_rejects_excess() {
+        let tracker = TaskTracker::new();
+        tracker.set_limit(TaskGroup::InboundConns, Some(2));
+        let (first, server) = duplex(64);
+        tracker.spawn(TaskGroup::InboundConns, serve_mock(server)).unwrap();
+        let (_second, server) = duplex(64);
+        tracker.spawn(TaskGroup::InboundConns, serve_mock(server)).unwrap();
+
+        let (mut rejected, server) = duplex(64);
+        let err = tracker.spawn(TaskGroup::InboundConns, serve_mock(server)).unwrap_err();
+        assert!(matches!(err, ProxyError::TaskLimit { limit: 2, .. }), "{}", err);
+        // 被拒绝的连接随未执行的 future 一起关闭
+        assert_eq!(rejected.read(&mut [0u8; 1]).await.unwrap(), 0);
+
+        drop(first);
+        wait_for_live(&tracker, TaskGroup::InboundConns, 1).await;
+        let (_third, server) = duplex(64);
+        tracker.spawn(TaskGroup::InboundConns, serve_mock(server)).unwrap();
+
+        let stats = tracker.stats();
+        let conns = stats.iter().find(|s| s.group == TaskGroup::InboundConns).unwrap();
+        assert_eq!((conns.live, conns.spawned, conns.rejected, conns.limit), (2, 3, 1, Some(2)));
+    }
+
+    #[tokio::test]
+    async fn test_reserve_waiting_takes_freed_place() {
+        let tracker = TaskTracker::new();
+        tracker.set_limit(TaskGroup::InboundConns, Some(1));
+        let (first, server) = duplex(64);
+        tracker.spawn(TaskGroup::InboundConns, serve_mock(server)).unwrap();
+
+        let waiting = tracker.reserve_waiting(TaskGroup::InboundConns);
+        tokio::pin!(waiting);
+        assert!(tokio::time::timeout(Duration::from_millis(50), waiting.as_mut()).await.is_err());
+        drop(first);
+        let slot = tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+        assert_eq!(tracker.live(TaskGroup::InboundConns), 1);
+
+        // 未使用的位置归还后可以再次占用
+        drop(slot);
+        assert_eq!(tracker.live(TaskGroup::InboundConns), 0);
+        let (_second, server) = duplex(64);
+        tracker.spawn_reserved(tracker.reserve(TaskGroup::InboundConns).unwrap(), serve_mock(server));
+        let stats = tracker.stats();
+        let conns = stats.iter().find(|s| s.group == TaskGroup::InboundConns).unwrap();
+        assert_eq!((conns.live, conns.spawned, conns.rejected), (1, 2, 0));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_stops_group_promptly() {
+        let tracker = TaskTracker::new();
+        let mut handles = Vec::new();
+        for _ in 0..4 {
+            handles.push(tracker.spawn(TaskGroup::PoolCleanup, std::future::pending::<()>()).unwrap());
+        }
+        let other = tracker.spawn(TaskGroup::HealthChecks, std::future::pending::<()>()).unwrap();
+
+        tracker.cancel(TaskGroup::PoolCleanup);
+        tokio::time::timeout(Duration::from_millis(500), tracker.wait_idle(TaskGroup::PoolCleanup))
+            .await
+            .expect("cancelled group did not stop");
+        for handle in handles {
+            assert_eq!(handle.await.unwrap(), None);
+        }
+        assert!(matches!(
+            tracker.spawn(TaskGroup::PoolCleanup, async {}),
+            Err(ProxyError::TaskGroupClosed("pool-cleanup"))
+        ));
+
+        // 其他分组不受影响
+        assert_eq!(tracker.live(TaskGroup::HealthChecks), 1);
+        other.abort();
+        wait_for_live(&tracker, TaskGroup::HealthChecks, 0).await;
+    }
+}
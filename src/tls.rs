@@ -0,0 +1,422 @@
+// 出站TLS封装：SNI覆盖（域前置）、会话恢复与0-RTT
+use crate::error::{ProxyError, Result};
+use log::debug;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::Resumption;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, HandshakeKind, RootCertStore, SignatureScheme};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+/// Default number of cached TLS sessions per outbound
+pub const DEFAULT_SESSION_CACHE_SIZE: usize = 256;
+
+/// TLS settings shared by TLS-capable outbounds
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsClientOptions {
+    /// Name used for SNI and certificate verification
+    #[serde(default)]
+    pub server_name: Option<String>,
+    /// SNI to present instead of `server_name` (domain fronting)
+    #[serde(default)]
+    pub override_sni: Option<String>,
+    /// Skip certificate and hostname verification
+    #[serde(default)]
+    pub insecure: bool,
+    /// Sessions kept for resumption (0 disables resumption)
+    #[serde(default = "default_session_cache_size")]
+    pub session_cache_size: usize,
+    /// Send TLS 1.3 early data on resumed sessions; only safe for
+    /// protocols whose first flight tolerates replay
+    #[serde(default)]
+    pub early_data: bool,
+}
+
+fn default_session_cache_size() -> usize {
+    DEFAULT_SESSION_CACHE_SIZE
+}
+
+impl Default for TlsClientOptions {
+    fn default() -> Self {
+        Self {
+            server_name: None,
+            override_sni: None,
+            insecure: false,
+            session_cache_size: DEFAULT_SESSION_CACHE_SIZE,
+            early_data: false,
+        }
+    }
+}
+
+impl TlsClientOptions {
+    /// The name carried in the ClientHello, which is also the name the
+    /// certificate is verified against
+    pub fn effective_sni<'a>(&'a self, fallback: &'a str) -> &'a str {
+        self.override_sni
+            .as_deref()
+            .or(self.server_name.as_deref())
+            .unwrap_or(fallback)
+    }
+}
+
+/// TLS handshake counters
+#[derive(Debug, Default)]
+struct HandshakeCounters {
+    resumed: AtomicU64,
+    full: AtomicU64,
+}
+
+impl HandshakeCounters {
+    const fn new() -> Self {
+        Self {
+            resumed: AtomicU64::new(0),
+            full: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, kind: Option<HandshakeKind>) {
+        match kind {
+            Some(HandshakeKind::Resumed) => self.resumed.fetch_add(1, Ordering::Relaxed),
+            _ => self.full.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    fn snapshot(&self) -> TlsStats {
+        TlsStats {
+            resumed: self.resumed.load(Ordering::Relaxed),
+            full: self.full.load(Ordering::Relaxed),
+        }
+    }
+}
+
+static GLOBAL_HANDSHAKES: HandshakeCounters = HandshakeCounters::new();
+
+/// Session resumption statistics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlsStats {
+    /// Handshakes that resumed a cached session
+    pub resumed: u64,
+    /// Full handshakes (resumption misses)
+    pub full: u64,
+}
+
+/// Resumption statistics across all TLS outbounds
+pub fn tls_stats() -> TlsStats {
+    GLOBAL_HANDSHAKES.snapshot()
+}
+
+/// TLS client for one outbound
+///
+/// Built once per outbound config so all of its connections share the
+/// session cache.
+pub struct TlsClient {
+    options: TlsClientOptions,
+    connector: TlsConnector,
+    handshakes: HandshakeCounters,
+}
+
+impl TlsClient {
+    pub fn new(options: TlsClientOptions) -> Result<Self> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| ProxyError::Protocol(format!("Failed to build TLS config: {}", e)))?;
+
+        let mut config = if options.insecure {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+                .with_no_client_auth()
+        } else {
+            let roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            builder.with_root_certificates(roots).with_no_client_auth()
+        };
+
+        config.resumption = if options.session_cache_size == 0 {
+            Resumption::disabled()
+        } else {
+            Resumption::in_memory_sessions(options.session_cache_size)
+        };
+        config.enable_early_data = options.early_data;
+
+        let connector = TlsConnector::from(Arc::new(config)).early_data(options.early_data);
+        Ok(Self {
+            options,
+            connector,
+            handshakes: HandshakeCounters::new(),
+        })
+    }
+
+    pub fn options(&self) -> &TlsClientOptions {
+        &self.options
+    }
+
+    /// Resumption statistics for this outbound
+    pub fn stats(&self) -> TlsStats {
+        self.handshakes.snapshot()
+    }
+
+    /// Wrap an established connection in TLS
+    ///
+    /// `fallback_name` is used for SNI when neither `override_sni` nor
+    /// `server_name` is configured, typically the outbound's server host.
+    pub async fn connect<S>(&self, stream: S, fallback_name: &str) -> Result<TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let sni = self.options.effective_sni(fallback_name);
+        let server_name = ServerName::try_from(sni.to_string())
+            .map_err(|e| ProxyError::Protocol(format!("Invalid TLS server name {}: {}", sni, e)))?;
+
+        debug!("TLS handshake with SNI {}", sni);
+        let tls = self
+            .connector
+            .connect(server_name, stream)
+            .await
+            .map_err(|e| ProxyError::ConnectionFailed(format!("TLS handshake with {} failed: {}", sni, e)))?;
+
+        let kind = tls.get_ref().1.handshake_kind();
+        self.handshakes.record(kind);
+        GLOBAL_HANDSHAKES.record(kind);
+        Ok(tls)
+    }
+}
+
+/// Certificate verifier for `insecure` outbounds: accepts any certificate
+/// but still checks handshake signatures
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::server::{ServerSessionMemoryCache, StoresServerSessions};
+    use rustls::ServerConfig;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::TlsAcceptor;
+
+    /// Extract the server_name extension from a raw TLS ClientHello record
+    fn parse_client_hello_sni(record: &[u8]) -> Option<String> {
+        // record header(5) + handshake header(4) + version(2) + random(32)
+        let mut pos = 5 + 4 + 2 + 32;
+        let session_id_len = *record.get(pos)? as usize;
+        pos += 1 + session_id_len;
+        let cipher_len = u16::from_be_bytes([*record.get(pos)?, *record.get(pos + 1)?]) as usize;
+        pos += 2 + cipher_len;
+        let compression_len = *record.get(pos)? as usize;
+        pos += 1 + compression_len;
+        let extensions_end = pos + 2 + u16::from_be_bytes([*record.get(pos)?, *record.get(pos + 1)?]) as usize;
+        pos += 2;
+
+        while pos + 4 <= extensions_end {
+            let ext_type = u16::from_be_bytes([record[pos], record[pos + 1]]);
+            let ext_len = u16::from_be_bytes([record[pos + 2], record[pos + 3]]) as usize;
+            pos += 4;
+            if ext_type == 0 {
+                // list length(2) + name type(1) + name length(2)
+                let name_len = u16::from_be_bytes([record[pos + 3], record[pos + 4]]) as usize;
+                return String::from_utf8(record[pos + 5..pos + 5 + name_len].to_vec()).ok();
+            }
+            pos += ext_len;
+        }
+        None
+    }
+
+    /// Accept one connection, capture the ClientHello SNI, then hang up
+    async fn capture_sni(options: TlsClientOptions, fallback: &str) -> Option<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut header = [0u8; 5];
+            stream.read_exact(&mut header).await.unwrap();
+            let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+            let mut record = header.to_vec();
+            record.resize(5 + len, 0);
+            stream.read_exact(&mut record[5..]).await.unwrap();
+            parse_client_hello_sni(&record)
+        });
+
+        let client = TlsClient::new(options).unwrap();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        assert!(client.connect(stream, fallback).await.is_err());
+        server.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_client_hello_carries_override_sni() {
+        let options = TlsClientOptions {
+            server_name: Some("origin.example.com".to_string()),
+            override_sni: Some("front.example.net".to_string()),
+            ..TlsClientOptions::default()
+        };
+        let sni = capture_sni(options, "203.0.113.1").await;
+        assert_eq!(sni.as_deref(), Some("front.example.net"));
+    }
+
+    #[tokio::test]
+    async fn test_client_hello_falls_back_to_server_name() {
+        let options = TlsClientOptions {
+            server_name: Some("origin.example.com".to_string()),
+            ..TlsClientOptions::default()
+        };
+        let sni = capture_sni(options, "server.example.org").await;
+        assert_eq!(sni.as_deref(), Some("origin.example.com"));
+
+        let sni = capture_sni(TlsClientOptions::default(), "server.example.org").await;
+        assert_eq!(sni.as_deref(), Some("server.example.org"));
+    }
+
+    /// Server session store that counts successful resumption lookups
+    #[derive(Debug)]
+    struct CountingStore {
+        inner: Arc<dyn StoresServerSessions>,
+        resumed: AtomicU64,
+    }
+
+    impl StoresServerSessions for CountingStore {
+        fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+            self.inner.put(key, value)
+        }
+
+        fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+            let value = self.inner.get(key);
+            if value.is_some() {
+                self.resumed.fetch_add(1, Ordering::Relaxed);
+            }
+            value
+        }
+
+        fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+            let value = self.inner.take(key);
+            if value.is_some() {
+                self.resumed.fetch_add(1, Ordering::Relaxed);
+            }
+            value
+        }
+
+        fn can_cache(&self) -> bool {
+            self.inner.can_cache()
+        }
+    }
+
+    /// Local TLS server answering each connection with "ok"
+    async fn spawn_tls_server() -> (SocketAddr, Arc<CountingStore>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["tls.test".to_string()]).unwrap();
+        let key = rustls::pki_types::PrivateKeyDer::Pkcs8(cert.key_pair.serialize_der().into());
+        let store = Arc::new(CountingStore {
+            inner: ServerSessionMemoryCache::new(64),
+            resumed: AtomicU64::new(0),
+        });
+
+        let mut config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.cert.der().clone()], key)
+            .unwrap();
+        config.session_storage = store.clone();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut tls = acceptor.accept(stream).await.unwrap();
+                    tls.write_all(b"ok").await.unwrap();
+                    tls.shutdown().await.unwrap();
+                });
+            }
+        });
+        (addr, store)
+    }
+
+    async fn round_trip(client: &TlsClient, addr: SocketAddr) {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut tls = client.connect(stream, "tls.test").await.unwrap();
+        // 读到EOF，以便处理服务端发来的会话票据
+        let mut reply = Vec::new();
+        tls.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"ok");
+    }
+
+    fn insecure(session_cache_size: usize) -> TlsClientOptions {
+        TlsClientOptions {
+            insecure: true,
+            session_cache_size,
+            ..TlsClientOptions::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_second_connection_resumes_session() {
+        let (addr, store) = spawn_tls_server().await;
+        let client = TlsClient::new(insecure(DEFAULT_SESSION_CACHE_SIZE)).unwrap();
+
+        round_trip(&client, addr).await;
+        assert_eq!(client.stats(), TlsStats { resumed: 0, full: 1 });
+
+        round_trip(&client, addr).await;
+        assert_eq!(client.stats(), TlsStats { resumed: 1, full: 1 });
+        assert_eq!(store.resumed.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_cache_forces_full_handshakes() {
+        let (addr, store) = spawn_tls_server().await;
+        let client = TlsClient::new(insecure(0)).unwrap();
+
+        round_trip(&client, addr).await;
+        round_trip(&client, addr).await;
+        assert_eq!(client.stats(), TlsStats { resumed: 0, full: 2 });
+        assert_eq!(store.resumed.load(Ordering::Relaxed), 0);
+    }
+}
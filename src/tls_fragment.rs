@@ -0,0 +1,168 @@
+// TLS ClientHello 分片：把首个TLS握手包拆成多个TCP段发出，绕过基于SNI的阻断
+use crate::error::{ProxyError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// TLS record content type of handshake messages
+const TLS_HANDSHAKE: u8 = 0x16;
+
+/// Per-outbound ClientHello fragmentation settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsFragmentConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Inclusive bounds on the bytes written per segment
+    #[serde(default = "default_size_range")]
+    pub size_range: [usize; 2],
+    /// Inclusive bounds on the pause between segments, in milliseconds
+    #[serde(default = "default_delay_ms_range")]
+    pub delay_ms_range: [u64; 2],
+}
+
+fn default_size_range() -> [usize; 2] {
+    [8, 64]
+}
+
+fn default_delay_ms_range() -> [u64; 2] {
+    [1, 10]
+}
+
+impl Default for TlsFragmentConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            size_range: default_size_range(),
+            delay_ms_range: default_delay_ms_range(),
+        }
+    }
+}
+
+impl TlsFragmentConfig {
+    pub fn validate(&self) -> Result<()> {
+        let [min_size, max_size] = self.size_range;
+        if min_size == 0 || min_size > max_size {
+            return Err(ProxyError::Protocol(format!(
+                "tls_fragment.size_range must be [min, max] with 0 < min <= max, got {:?}",
+                self.size_range
+            )));
+        }
+        let [min_delay, max_delay] = self.delay_ms_range;
+        if min_delay > max_delay {
+            return Err(ProxyError::Protocol(format!(
+                "tls_fragment.delay_ms_range must be [min, max] with min <= max, got {:?}",
+                self.delay_ms_range
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Whether `data` starts with a TLS handshake record header
+///
+/// Checks the content type and a record version of SSL 3.0 to TLS 1.3
+/// (0x0300-0x0304); anything else is relayed untouched.
+pub fn is_tls_handshake(data: &[u8]) -> bool {
+    data.len() >= 5 && data[0] == TLS_HANDSHAKE && data[1] == 0x03 && data[2] <= 0x04
+}
+
+/// Write `data` in randomly sized segments with random pauses in between
+///
+/// Flushes after every segment so each one leaves as its own write; the
+/// caller should have TCP_NODELAY on so the kernel does not merge them.
+/// Returns the number of segments written.
+pub async fn write_fragmented<W>(dest: &mut W, data: &[u8], config: &TlsFragmentConfig) -> std::io::Result<usize>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut segments = 0;
+    let mut rest = data;
+    while !rest.is_empty() {
+        if segments > 0 {
+            let [min_delay, max_delay] = config.delay_ms_range;
+            let delay = random_between(min_delay, max_delay);
+            if delay > 0 {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+            }
+        }
+        let [min_size, max_size] = config.size_range;
+        let size = (random_between(min_size as u64, max_size as u64) as usize).clamp(1, rest.len());
+        let (segment, remaining) = rest.split_at(size);
+        dest.write_all(segment).await?;
+        dest.flush().await?;
+        rest = remaining;
+        segments += 1;
+    }
+    Ok(segments)
+}
+
+fn random_between(min: u64, max: u64) -> u64 {
+    // 与端口选择相同：RandomState 每次都有新的随机密钥
+    let span = max.saturating_sub(min).saturating_add(1);
+    min + RandomState::new().build_hasher().finish() % span
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Writer that records every write it receives
+    #[derive(Default)]
+    struct RecordingWriter {
+        writes: Vec<Vec<u8>>,
+    }
+
+    impl AsyncWrite for RecordingWriter {
+        fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+            self.writes.push(buf.to_vec());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_handshake_detection() {
+        assert!(is_tls_handshake(&[0x16, 0x03, 0x01, 0x02, 0x00, 0x01]));
+        assert!(is_tls_handshake(&[0x16, 0x03, 0x03, 0x00, 0x10]));
+        assert!(!is_tls_handshake(b"GET / HTTP/1.1\r\n"));
+        assert!(!is_tls_handshake(&[0x17, 0x03, 0x03, 0x00, 0x10]));
+        assert!(!is_tls_handshake(&[0x16, 0x03]));
+        assert!(!is_tls_handshake(&[0x16, 0x02, 0x00, 0x00, 0x10]));
+    }
+
+    #[test]
+    fn test_invalid_ranges_rejected() {
+        let valid = TlsFragmentConfig { enabled: true, ..Default::default() };
+        assert!(valid.validate().is_ok());
+        assert!(TlsFragmentConfig { size_range: [0, 10], ..valid }.validate().is_err());
+        assert!(TlsFragmentConfig { size_range: [20, 10], ..valid }.validate().is_err());
+        assert!(TlsFragmentConfig { delay_ms_range: [5, 1], ..valid }.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_segments_respect_size_bounds() {
+        let config = TlsFragmentConfig { enabled: true, size_range: [3, 7], delay_ms_range: [0, 1] };
+        let data: Vec<u8> = (0..=255).collect();
+        let mut writer = RecordingWriter::default();
+
+        let segments = write_fragmented(&mut writer, &data, &config).await.unwrap();
+        assert_eq!(segments, writer.writes.len());
+        assert!(segments >= data.len() / 7);
+        assert_eq!(writer.writes.concat(), data);
+        // 最后一段可能因剩余字节不足而更短
+        let (last, rest) = writer.writes.split_last().unwrap();
+        assert!(rest.iter().all(|w| (3..=7).contains(&w.len())), "{:?}", writer.writes);
+        assert!((1..=7).contains(&last.len()));
+    }
+}
@@ -1,7 +1,10 @@
 use crate::error::{ProxyError, Result};
 use log::{debug, warn};
+use serde::{Deserialize, Serialize};
 use socket2::{Domain, Protocol, Socket, Type};
 use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::time::Duration;
 use tokio::net::TcpStream;
 
 /// Traffic marking configuration
@@ -75,33 +78,230 @@ pub fn apply_traffic_mark(socket: &Socket, config: &TrafficMarkConfig) -> Result
     Ok(())
 }
 
-/// Create a new TCP stream with traffic marking applied
-pub async fn create_marked_tcp_stream(
-    target_addr: SocketAddr,
-    config: &TrafficMarkConfig,
-) -> Result<TcpStream> {
-    // Create socket with appropriate domain
-    let domain = match target_addr {
-        SocketAddr::V4(_) => Domain::IPV4,
-        SocketAddr::V6(_) => Domain::IPV6,
+/// Largest DSCP code point (6 bits)
+pub const MAX_DSCP: u8 = 63;
+
+/// Check that a DSCP value fits in 6 bits
+pub fn validate_dscp(dscp: u8) -> Result<()> {
+    if dscp > MAX_DSCP {
+        return Err(ProxyError::Protocol(format!(
+            "Invalid DSCP value {}: must be between 0 and {}",
+            dscp, MAX_DSCP
+        )));
+    }
+    Ok(())
+}
+
+/// Accepted `tcp_mss` range: the IPv4 minimum MSS up to a full Ethernet segment
+pub const MIN_TCP_MSS: u16 = 536;
+pub const MAX_TCP_MSS: u16 = 1460;
+
+/// Check that an MSS clamp lies in `MIN_TCP_MSS..=MAX_TCP_MSS`
+pub fn validate_tcp_mss(mss: u16) -> Result<()> {
+    if !(MIN_TCP_MSS..=MAX_TCP_MSS).contains(&mss) {
+        return Err(ProxyError::Protocol(format!(
+            "Invalid tcp_mss {}: must be between {} and {}",
+            mss, MIN_TCP_MSS, MAX_TCP_MSS
+        )));
+    }
+    Ok(())
+}
+
+/// Clamp the MSS of a socket before it connects (TCP_MAXSEG)
+///
+/// The kernel advertises the clamp in the SYN and never sends larger
+/// segments. The value is read back where the platform reports it; a kernel
+/// keeping a larger one is logged.
+pub fn apply_tcp_mss(socket: &Socket, target: &SocketAddr, mss: u16) -> Result<()> {
+    validate_tcp_mss(mss)?;
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    {
+        socket.set_mss(u32::from(mss))?;
+        match socket.mss() {
+            Ok(applied) if applied > u32::from(mss) => {
+                warn!("TCP_MAXSEG {} for {} not honored, socket reports {}", mss, target, applied)
+            }
+            Ok(applied) => debug!("Applied TCP_MAXSEG {} to socket for {} (reads back {})", mss, target, applied),
+            Err(e) => debug!("Applied TCP_MAXSEG {} to socket for {}, not readable: {}", mss, target, e),
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    {
+        let _ = socket;
+        warn!("TCP_MAXSEG not supported on this platform, not clamping connection to {}", target);
+    }
+    Ok(())
+}
+
+/// Per-connection socket options chosen by routing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DialOptions {
+    /// DSCP code point written to IP_TOS / IPV6_TCLASS
+    pub dscp: Option<u8>,
+    /// Linux SO_MARK of the outbound the connection goes through
+    pub so_mark: Option<u32>,
+    /// TCP_MAXSEG clamp of the outbound the connection goes through
+    pub tcp_mss: Option<u16>,
+    /// Health check or probe: dial even addresses the negative cache suppresses
+    pub probe: bool,
+    /// Give up connecting after this long; without one the caller bounds the dial
+    pub connect_timeout: Option<Duration>,
+}
+
+/// Apply a DSCP code point to a socket of the given address family
+///
+/// DSCP occupies the upper six bits of the TOS / traffic class byte.
+pub fn apply_dscp(socket: &Socket, target: &SocketAddr, dscp: u8) -> Result<()> {
+    validate_dscp(dscp)?;
+    let tos = u32::from(dscp) << 2;
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    {
+        match target {
+            SocketAddr::V4(_) => socket.set_tos(tos)?,
+            SocketAddr::V6(_) => socket.set_tclass_v6(tos)?,
+        }
+        debug!("Applied DSCP {} to socket for {}", dscp, target);
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    {
+        let _ = (socket, tos);
+        warn!("DSCP not supported on this platform, not marking connection to {}", target);
+    }
+    Ok(())
+}
+
+/// SO_LINGER policy: what closing a socket does with data not yet sent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LingerPolicy {
+    /// OS default: close returns at once and the kernel still sends what is buffered
+    #[default]
+    Off,
+    /// Zero linger: close resets the connection (RST) and leaves no TIME_WAIT
+    Reset,
+    /// close waits up to this many seconds for buffered data, then resets
+    Timed(u64),
+}
+
+impl LingerPolicy {
+    pub fn validate(&self) -> Result<()> {
+        if *self == LingerPolicy::Timed(0) {
+            return Err(ProxyError::Protocol("linger timed(0) resets connections, use \"reset\"".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Apply a linger policy to a connected stream
+pub fn apply_linger(stream: &TcpStream, policy: LingerPolicy) -> Result<()> {
+    let linger = match policy {
+        LingerPolicy::Off => return Ok(()),
+        LingerPolicy::Reset => Duration::ZERO,
+        LingerPolicy::Timed(secs) => Duration::from_secs(secs),
     };
+    socket2::SockRef::from(stream).set_linger(Some(linger))?;
+    Ok(())
+}
 
-    let socket =
-        Socket::new(domain, Type::STREAM, Some(Protocol::TCP)).map_err(ProxyError::Io)?;
+/// Turn on TCP_NODELAY for a latency-mode connection, skipping the call when already on
+pub fn enable_nodelay(stream: &TcpStream) -> Result<()> {
+    if !stream.nodelay()? {
+        stream.set_nodelay(true)?;
+    }
+    Ok(())
+}
 
-    // Apply traffic marking before connecting
-    apply_traffic_mark(&socket, config)?;
+/// Re-arms TCP_QUICKACK on one socket after each read (Linux only)
+///
+/// The kernel drops back to delayed ACKs on its own, so the flag has to be
+/// set again after every read. The fd is captured once up front; the stream
+/// must outlive the `QuickAck`.
+#[derive(Debug, Clone, Copy)]
+pub struct QuickAck {
+    #[cfg(target_os = "linux")]
+    fd: std::os::fd::RawFd,
+}
 
-    // Connect to target
-    socket.connect(&target_addr.into())
-        .map_err(|e| ProxyError::Io(e))?;
+impl QuickAck {
+    #[cfg(target_os = "linux")]
+    pub fn new(stream: &TcpStream) -> Option<Self> {
+        use std::os::fd::AsRawFd;
+        Some(Self { fd: stream.as_raw_fd() })
+    }
 
-    // Convert to tokio TcpStream
-    let std_stream = socket.into();
-    let stream = TcpStream::from_std(std_stream).map_err(ProxyError::Io)?;
+    #[cfg(not(target_os = "linux"))]
+    pub fn new(_stream: &TcpStream) -> Option<Self> {
+        None
+    }
 
-    debug!("Created marked TCP stream to {}", target_addr);
-    Ok(stream)
+    /// Ask the kernel to ACK the data just read right away
+    pub fn rearm(&self) {
+        #[cfg(target_os = "linux")]
+        {
+            let on: libc::c_int = 1;
+            // 尽力而为：失败只意味着退回延迟 ACK
+            unsafe {
+                libc::setsockopt(
+                    self.fd,
+                    libc::IPPROTO_TCP,
+                    libc::TCP_QUICKACK,
+                    &on as *const _ as *const libc::c_void,
+                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                );
+            }
+        }
+    }
+}
+
+/// Connect a prepared socket without blocking the runtime
+async fn connect_socket(socket: Socket, target_addr: SocketAddr) -> Result<TcpStream> {
+    socket.set_nonblocking(true)?;
+    let socket = tokio::net::TcpSocket::from_std_stream(socket.into());
+    Ok(socket.connect(target_addr).await?)
+}
+
+/// Dial an outbound TCP connection with per-connection socket options
+///
+/// `[traffic_mark]` applies to every dial; an outbound's `routing_mark`
+/// replaces its SO_MARK.
+pub async fn dial_tcp(target_addr: SocketAddr, options: &DialOptions) -> Result<TcpStream> {
+    dial_tcp_with(target_addr, options, get_global_traffic_mark_config()).await
+}
+
+async fn dial_tcp_with(target_addr: SocketAddr, options: &DialOptions, global: Option<&TrafficMarkConfig>) -> Result<TcpStream> {
+    let Some(connect_timeout) = options.connect_timeout else {
+        return dial_socket(target_addr, options, global).await;
+    };
+    tokio::time::timeout(connect_timeout, dial_socket(target_addr, options, global))
+        .await
+        .map_err(|_| ProxyError::ConnectionFailed(format!("Connect to {} timed out after {:?}", target_addr, connect_timeout)))?
+}
+
+async fn dial_socket(target_addr: SocketAddr, options: &DialOptions, global: Option<&TrafficMarkConfig>) -> Result<TcpStream> {
+    let mark = TrafficMarkConfig::new(
+        options.so_mark.or_else(|| global.and_then(|global| global.so_mark)).filter(|_| cfg!(target_os = "linux")),
+        // 只有 macOS 支持；其他平台不必每次拨号都告警
+        global.and_then(|global| global.net_service_type).filter(|_| cfg!(target_os = "macos")),
+    );
+    let marked = mark.so_mark.is_some() || mark.net_service_type.is_some();
+    if options.dscp.is_none() && options.tcp_mss.is_none() && !marked {
+        return Ok(TcpStream::connect(target_addr).await?);
+    }
+    let domain = match target_addr {
+        SocketAddr::V4(_) => Domain::IPV4,
+        SocketAddr::V6(_) => Domain::IPV6,
+    };
+    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    if let Some(dscp) = options.dscp {
+        apply_dscp(&socket, &target_addr, dscp)?;
+    }
+    if marked {
+        apply_traffic_mark(&socket, &mark)?;
+    }
+    if let Some(mss) = options.tcp_mss {
+        apply_tcp_mss(&socket, &target_addr, mss)?;
+    }
+    connect_socket(socket, target_addr).await
 }
 
 /// Apply traffic marking to an existing TcpStream
@@ -126,14 +326,10 @@ pub fn mark_existing_stream(stream: TcpStream, config: &TrafficMarkConfig) -> Re
 #[cfg(target_os = "linux")]
 pub mod platform {
     use super::*;
-    use nix::sys::socket::{setsockopt, sockopt};
-    use std::os::unix::io::AsRawFd;
 
     /// Apply Linux-specific SO_MARK to a socket
     pub fn apply_so_mark(socket: &Socket, mark: u32) -> Result<()> {
-        let fd = socket.as_raw_fd();
-        setsockopt(fd, &sockopt::SO_MARK, &mark)
-            .map_err(|e| ProxyError::Io(io::Error::from_raw_os_error(e as i32)))?;
+        socket.set_mark(mark).map_err(ProxyError::Io)?;
         debug!("Applied SO_MARK {} to socket", mark);
         Ok(())
     }
@@ -171,18 +367,41 @@ pub mod platform {
 }
 
 /// Global traffic marking configuration
-static mut GLOBAL_TRAFFIC_MARK_CONFIG: Option<TrafficMarkConfig> = None;
+static GLOBAL_TRAFFIC_MARK_CONFIG: OnceLock<TrafficMarkConfig> = OnceLock::new();
+
+/// Set the configured SO_MARK on a throwaway socket, so a missing CAP_NET_ADMIN
+/// fails at startup instead of on every outbound dial
+fn check_traffic_mark(config: &TrafficMarkConfig) -> Result<()> {
+    // 其他平台上 dial_socket 不会设置 SO_MARK
+    let Some(mark) = config.so_mark.filter(|_| cfg!(target_os = "linux")) else {
+        return Ok(());
+    };
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
+    apply_traffic_mark(&socket, &TrafficMarkConfig::with_so_mark(mark)).map_err(|e| {
+        ProxyError::Protocol(format!(
+            "Cannot set traffic_mark.so_mark = {}: {} (needs CAP_NET_ADMIN; set traffic_mark.so_mark = 0 to run without it)",
+            mark, e
+        ))
+    })
+}
 
-/// Initialize global traffic marking configuration
-pub fn init_global_traffic_mark_config(config: TrafficMarkConfig) {
-    unsafe {
-        GLOBAL_TRAFFIC_MARK_CONFIG = Some(config);
+/// Initialize global traffic marking configuration; later calls keep the first one.
+/// Fails when the configured SO_MARK cannot be set by this process.
+pub fn init_global_traffic_mark_config(config: TrafficMarkConfig) -> Result<()> {
+    if GLOBAL_TRAFFIC_MARK_CONFIG.get().is_some() {
+        warn!("Traffic marking already configured, ignoring new settings");
+        return Ok(());
+    }
+    check_traffic_mark(&config)?;
+    if GLOBAL_TRAFFIC_MARK_CONFIG.set(config).is_err() {
+        warn!("Traffic marking already configured, ignoring new settings");
     }
+    Ok(())
 }
 
-/// Get global traffic marking configuration
+/// Get global traffic marking configuration (None before it is initialized)
 pub fn get_global_traffic_mark_config() -> Option<&'static TrafficMarkConfig> {
-    unsafe { GLOBAL_TRAFFIC_MARK_CONFIG.as_ref() }
+    GLOBAL_TRAFFIC_MARK_CONFIG.get()
 }
 
 #[cfg(test)]
@@ -190,6 +409,25 @@ mod tests {
     use super::*;
     use std::net::{IpAddr, Ipv4Addr};
 
+    #[test]
+    fn test_global_config_kept_after_first_init() {
+        assert!(get_global_traffic_mark_config().is_none());
+        init_global_traffic_mark_config(TrafficMarkConfig::new(None, None)).unwrap();
+        init_global_traffic_mark_config(TrafficMarkConfig::with_so_mark(255)).unwrap();
+        assert_eq!(get_global_traffic_mark_config().unwrap().so_mark, None);
+    }
+
+    #[test]
+    fn test_startup_check_reports_missing_capability() {
+        assert!(check_traffic_mark(&TrafficMarkConfig::new(None, None)).is_ok());
+        // 有 CAP_NET_ADMIN 时通过，否则报错并提示如何关闭
+        if let Err(e) = check_traffic_mark(&TrafficMarkConfig::with_so_mark(255)) {
+            let message = e.to_string();
+            assert!(message.contains("CAP_NET_ADMIN"), "{}", message);
+            assert!(message.contains("traffic_mark.so_mark = 0"), "{}", message);
+        }
+    }
+
     #[test]
     fn test_traffic_mark_config_creation() {
         let config = TrafficMarkConfig::with_so_mark(255);
@@ -210,4 +448,142 @@ mod tests {
         assert_eq!(config.so_mark, Some(255));
         assert_eq!(config.net_service_type, Some(1));
     }
+
+    #[test]
+    fn test_dscp_range() {
+        assert!(validate_dscp(0).is_ok());
+        assert!(validate_dscp(MAX_DSCP).is_ok());
+        let err = validate_dscp(64).unwrap_err().to_string();
+        assert!(err.contains("Invalid DSCP value 64"), "{}", err);
+    }
+
+    #[test]
+    fn test_tcp_mss_range() {
+        assert!(validate_tcp_mss(MIN_TCP_MSS).is_ok());
+        assert!(validate_tcp_mss(MAX_TCP_MSS).is_ok());
+        for mss in [0, 535, 1461, 9000] {
+            let err = validate_tcp_mss(mss).unwrap_err().to_string();
+            assert!(err.contains(&format!("Invalid tcp_mss {}", mss)), "{}", err);
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[tokio::test]
+    async fn test_dial_clamps_mss() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+
+        let options = DialOptions { tcp_mss: Some(1200), ..DialOptions::default() };
+        let stream = dial_tcp(target, &options).await.unwrap();
+        // 连接后内核报告协商结果，可能再扣除 TCP 选项占用的字节
+        let mss = socket2::SockRef::from(&stream).mss().unwrap();
+        assert!((1100..=1200).contains(&mss), "{}", mss);
+
+        let plain = dial_tcp(target, &DialOptions::default()).await.unwrap();
+        assert!(socket2::SockRef::from(&plain).mss().unwrap() > 1200);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_dial_applies_global_and_outbound_mark() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let global = TrafficMarkConfig::with_so_mark(100);
+
+        let stream = match dial_tcp_with(target, &DialOptions::default(), Some(&global)).await {
+            Ok(stream) => stream,
+            // 设置 SO_MARK 需要 CAP_NET_ADMIN
+            Err(ProxyError::Io(e)) if e.kind() == std::io::ErrorKind::PermissionDenied => return,
+            Err(e) => panic!("{}", e),
+        };
+        assert_eq!(socket2::SockRef::from(&stream).mark().unwrap(), 100);
+
+        // 出站上的 routing_mark 覆盖全局值
+        let options = DialOptions { so_mark: Some(7), ..DialOptions::default() };
+        let stream = dial_tcp_with(target, &options, Some(&global)).await.unwrap();
+        assert_eq!(socket2::SockRef::from(&stream).mark().unwrap(), 7);
+
+        let plain = dial_tcp_with(target, &DialOptions::default(), None).await.unwrap();
+        assert_eq!(socket2::SockRef::from(&plain).mark().unwrap(), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_dial_times_out_without_blocking() {
+        let ticks = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    ticks.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                }
+            }
+        });
+
+        // 接受队列占满的监听端口：Linux 丢弃新的 SYN，连接像发往不可达地址一样挂起
+        let listener = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).unwrap();
+        listener.bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)).into()).unwrap();
+        listener.listen(0).unwrap();
+        let target = listener.local_addr().unwrap().as_socket().unwrap();
+        let _queued: Vec<_> = (0..2).filter_map(|_| std::net::TcpStream::connect_timeout(&target, Duration::from_millis(200)).ok()).collect();
+
+        // 普通连接（ConnectionHandler 的拨号）与设置了套接字选项的连接都受限时
+        for dscp in [None, Some(10)] {
+            let options = DialOptions { dscp, connect_timeout: Some(Duration::from_millis(500)), ..DialOptions::default() };
+            let started = std::time::Instant::now();
+            let result = dial_tcp_with(target, &options, None).await;
+            let elapsed = started.elapsed();
+
+            let err = result.unwrap_err().to_string();
+            assert!(err.contains("timed out"), "{}", err);
+            assert!(elapsed >= Duration::from_millis(500) && elapsed < Duration::from_secs(2), "{:?}", elapsed);
+        }
+        ticker.abort();
+        // 单线程运行时上其他任务照常运行
+        assert!(ticks.load(std::sync::atomic::Ordering::Relaxed) >= 20);
+    }
+
+    #[tokio::test]
+    async fn test_zero_linger_close_resets_peer() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+
+        apply_linger(&stream, LingerPolicy::Reset).unwrap();
+        assert_eq!(socket2::SockRef::from(&stream).linger().unwrap(), Some(Duration::ZERO));
+        drop(stream);
+
+        let mut buf = [0u8; 16];
+        let err = tokio::io::AsyncReadExt::read(&mut peer, &mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+        assert!(LingerPolicy::Timed(0).validate().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_dial_sets_tos_for_ipv4() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+
+        let options = DialOptions { dscp: Some(46), ..DialOptions::default() };
+        let stream = dial_tcp(target, &options).await.unwrap();
+        assert_eq!(socket2::SockRef::from(&stream).tos().unwrap(), 46 << 2);
+
+        let plain = dial_tcp(target, &DialOptions::default()).await.unwrap();
+        assert_eq!(socket2::SockRef::from(&plain).tos().unwrap(), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_dial_sets_tclass_for_ipv6() {
+        // 没有 IPv6 回环的环境直接跳过
+        let Ok(listener) = tokio::net::TcpListener::bind("[::1]:0").await else {
+            return;
+        };
+        let target = listener.local_addr().unwrap();
+
+        let stream = dial_tcp(target, &DialOptions { dscp: Some(10), ..DialOptions::default() }).await.unwrap();
+        assert_eq!(socket2::SockRef::from(&stream).tclass_v6().unwrap(), 10 << 2);
+    }
 }
@@ -0,0 +1,341 @@
+// UDP-over-TCP（sing-box UoT v2）：在可靠流上承载数据报
+//
+// 会话以请求头开始：is_connect(1) + 目标地址；之后每个数据报为
+//   connect模式：长度(u16) + 负载
+//   非connect模式：地址 + 长度(u16) + 负载
+// 地址编码：族(0x00 IPv4 / 0x01 IPv6 / 0x02 域名) + 地址 + 端口(u16)
+use crate::dns::try_get_global_dns_resolver;
+use crate::error::{ProxyError, Result};
+use crate::protocol::{Address, AddressFormat};
+use crate::protocol_util::FramedRead;
+use crate::protocols::DatagramTransport;
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use log::debug;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+
+/// Destination that asks a UoT-capable server to open a UoT v2 session
+pub const MAGIC_ADDRESS: &str = "sp.v2.udp-over-tcp.arpa";
+/// Largest datagram one frame can carry
+pub const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
+
+/// Whether a CONNECT target asks for a UoT session instead of a TCP tunnel
+pub fn is_uot_request(address: &Address) -> bool {
+    matches!(address, Address::Domain(domain) if domain.eq_ignore_ascii_case(MAGIC_ADDRESS))
+}
+
+/// Session header sent once at the start of the stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UotRequest {
+    /// Connected sessions only talk to `destination` and omit per-datagram addresses
+    pub is_connect: bool,
+    pub destination: Address,
+    pub port: u16,
+}
+
+impl UotRequest {
+    pub fn connect(target: SocketAddr) -> Self {
+        Self {
+            is_connect: true,
+            destination: Address::from(target.ip()),
+            port: target.port(),
+        }
+    }
+
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        let mut buf = BytesMut::with_capacity(32);
+        buf.put_u8(self.is_connect as u8);
+        put_address(&mut buf, &self.destination, self.port)?;
+        writer.write_all(&buf).await?;
+        Ok(())
+    }
+
+    pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self> {
+        let mut framed = FramedRead::new(reader);
+        let is_connect = framed.read_u8().await? != 0;
+        let (destination, port) = Address::read_framed(&mut framed, AddressFormat::UOT).await?;
+        Ok(Self {
+            is_connect,
+            destination,
+            port,
+        })
+    }
+}
+
+/// One decoded datagram
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UotFrame {
+    pub payload: Bytes,
+    /// Peer address, present only in non-connect sessions
+    pub address: Option<(Address, u16)>,
+}
+
+fn put_address(buf: &mut BytesMut, address: &Address, port: u16) -> Result<()> {
+    address.write_with(buf, port, AddressFormat::UOT)
+}
+
+/// Write one datagram frame; `address` is required in non-connect sessions
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    payload: &[u8],
+    address: Option<(&Address, u16)>,
+) -> Result<()> {
+    if payload.len() > MAX_DATAGRAM_SIZE {
+        return Err(ProxyError::Protocol(format!(
+            "Datagram of {} bytes exceeds the UoT limit of {} bytes",
+            payload.len(),
+            MAX_DATAGRAM_SIZE
+        )));
+    }
+    let mut buf = BytesMut::with_capacity(payload.len() + 24);
+    if let Some((address, port)) = address {
+        put_address(&mut buf, address, port)?;
+    }
+    buf.put_u16(payload.len() as u16);
+    buf.put_slice(payload);
+    writer.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Read one datagram frame, None when the stream ended cleanly between frames
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, is_connect: bool) -> Result<Option<UotFrame>> {
+    let mut framed = FramedRead::new(&mut *reader);
+    // 帧首字节处的EOF视为会话正常结束
+    let first = match framed.read_u8().await {
+        Ok(byte) => byte,
+        Err(e) if e.io_kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let (address, len) = if is_connect {
+        (None, u16::from_be_bytes([first, framed.read_u8().await?]))
+    } else {
+        let address = Address::read_body(first, &mut framed, AddressFormat::UOT).await?;
+        let port = framed.read_u16().await?;
+        (Some((address, port)), framed.read_u16().await?)
+    };
+    // 负载直接读进自己的缓冲区，长度不超过 u16
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(UotFrame {
+        payload: payload.into(),
+        address,
+    }))
+}
+
+/// Client side of a connected UoT session
+pub struct UotTransport<S> {
+    stream: S,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> UotTransport<S> {
+    /// Start a connected session to `target` over an established stream
+    pub async fn connect(mut stream: S, target: SocketAddr) -> Result<Self> {
+        UotRequest::connect(target).write_to(&mut stream).await?;
+        Ok(Self { stream })
+    }
+}
+
+#[async_trait]
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> DatagramTransport for UotTransport<S> {
+    async fn send(&mut self, payload: &[u8]) -> Result<()> {
+        write_frame(&mut self.stream, payload, None).await
+    }
+
+    async fn recv(&mut self) -> Result<Option<Bytes>> {
+        Ok(read_frame(&mut self.stream, true).await?.map(|frame| frame.payload))
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+}
+
+/// Server side: decapsulate a UoT session and forward its datagrams directly
+///
+/// Returns when the client closes the stream; the UDP socket is dropped with it.
+pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(stream: S) -> Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let request = UotRequest::read_from(&mut reader).await?;
+    debug!(
+        "UoT session to {}:{} (connect: {})",
+        request.destination, request.port, request.is_connect
+    );
+
+    let target = match request.is_connect {
+        true => Some(resolve(&request.destination, request.port).await?),
+        false => None,
+    };
+    let bind: SocketAddr = match (&target, &request.destination) {
+        (Some(SocketAddr::V6(_)), _) | (None, Address::V6(..)) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        _ => (Ipv4Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    if let Some(target) = target {
+        socket.connect(target).await?;
+    }
+
+    // 两个方向各自循环，任一方向结束即结束会话
+    let upstream = async {
+        while let Some(frame) = read_frame(&mut reader, request.is_connect).await? {
+            match &frame.address {
+                Some((address, port)) => {
+                    let target = resolve(address, *port).await?;
+                    socket.send_to(&frame.payload, target).await?;
+                }
+                None => {
+                    socket.send(&frame.payload).await?;
+                }
+            }
+        }
+        debug!("UoT session to {}:{} closed", request.destination, request.port);
+        Ok(())
+    };
+    let downstream = async {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buf).await?;
+            let source = Address::from(peer.ip());
+            let address = (!request.is_connect).then_some((&source, peer.port()));
+            write_frame(&mut writer, &buf[..len], address).await?;
+        }
+    };
+
+    tokio::select! {
+        result = upstream => result,
+        result = downstream => result,
+    }
+}
+
+async fn resolve(address: &Address, port: u16) -> Result<SocketAddr> {
+    match address {
+        Address::Domain(domain) => try_get_global_dns_resolver()?.resolve_domain(domain, port).await,
+        _ => address.to_socket_addr(port),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::{Protocol, Socks5Protocol};
+    use crate::proxy::ConnectionHandler;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// UDP echo server on loopback
+    async fn spawn_udp_echo() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                socket.send_to(&buf[..len], peer).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_frame_round_trip() {
+        let (mut client, mut server) = tokio::io::duplex(1 << 18);
+        let domain = Address::Domain("example.com".to_string());
+
+        UotRequest { is_connect: false, destination: domain.clone(), port: 53 }
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        write_frame(&mut client, b"query", Some((&domain, 53))).await.unwrap();
+        write_frame(&mut client, &[], Some((&Address::V6(Ipv6Addr::LOCALHOST, 0), 443))).await.unwrap();
+        drop(client);
+
+        let request = UotRequest::read_from(&mut server).await.unwrap();
+        assert_eq!(request, UotRequest { is_connect: false, destination: domain.clone(), port: 53 });
+        let frame = read_frame(&mut server, false).await.unwrap().unwrap();
+        assert_eq!(frame, UotFrame { payload: Bytes::from_static(b"query"), address: Some((domain, 53)) });
+        let frame = read_frame(&mut server, false).await.unwrap().unwrap();
+        assert!(frame.payload.is_empty());
+        assert_eq!(frame.address, Some((Address::V6(Ipv6Addr::LOCALHOST, 0), 443)));
+        assert_eq!(read_frame(&mut server, false).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_datagram_rejected() {
+        let (mut client, mut server) = tokio::io::duplex(1 << 18);
+
+        let err = write_frame(&mut client, &vec![0u8; MAX_DATAGRAM_SIZE + 1], None).await.unwrap_err();
+        assert!(err.to_string().contains("exceeds the UoT limit"));
+
+        // 被拒绝的数据报不写入任何字节，最大长度仍可发送
+        write_frame(&mut client, &vec![7u8; MAX_DATAGRAM_SIZE], None).await.unwrap();
+        drop(client);
+        let frame = read_frame(&mut server, true).await.unwrap().unwrap();
+        assert_eq!(frame.payload.len(), MAX_DATAGRAM_SIZE);
+        assert_eq!(read_frame(&mut server, true).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_loopback_session_and_teardown() {
+        let echo = spawn_udp_echo().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream).await
+        });
+
+        let stream = TcpStream::connect(server_addr).await.unwrap();
+        let mut transport = UotTransport::connect(stream, echo).await.unwrap();
+        for payload in [&b"ping"[..], &[0xAB; 1400][..]] {
+            transport.send(payload).await.unwrap();
+            assert_eq!(transport.recv().await.unwrap().unwrap(), payload);
+        }
+
+        // 关闭客户端后服务端会话结束
+        transport.close().await.unwrap();
+        server.await.unwrap().unwrap();
+        assert_eq!(transport.recv().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_unconnected_session_reports_source() {
+        let echo = spawn_udp_echo().await;
+        let (mut client, server_side) = tokio::io::duplex(1 << 18);
+        let server = tokio::spawn(serve(server_side));
+
+        let destination = Address::from(echo.ip());
+        UotRequest { is_connect: false, destination: destination.clone(), port: echo.port() }
+            .write_to(&mut client)
+            .await
+            .unwrap();
+        write_frame(&mut client, b"hello", Some((&destination, echo.port()))).await.unwrap();
+        let frame = read_frame(&mut client, false).await.unwrap().unwrap();
+        assert_eq!(frame.payload, Bytes::from_static(b"hello"));
+        assert_eq!(frame.address, Some((destination, echo.port())));
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks5_outbound_to_uot_inbound() {
+        let echo = spawn_udp_echo().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, client_addr) = listener.accept().await.unwrap();
+            ConnectionHandler::new(stream, client_addr).handle().await
+        });
+
+        let outbound = Socks5Protocol::with_server(proxy_addr).with_udp_over_tcp(true);
+        let mut transport = outbound.open_datagram(echo).await.unwrap();
+        transport.send(b"via socks5").await.unwrap();
+        assert_eq!(transport.recv().await.unwrap().unwrap(), &b"via socks5"[..]);
+        transport.close().await.unwrap();
+
+        let plain = Socks5Protocol::with_server(proxy_addr);
+        assert!(plain.open_datagram(echo).await.is_err());
+    }
+}
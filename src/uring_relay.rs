@@ -0,0 +1,652 @@
+// io_uring 中继后端（实验性）：两端都是普通 TcpStream 时，把两个 socket 注册进专用 uring 线程的
+// 固定文件表，用 read/write 提交完成双向搬运；有序关闭和结果统计回到 tokio 侧，与缓冲后端共用
+use crate::connection_registry::TrackedConnection;
+use crate::error::{ProxyError, Result};
+use crate::zero_copy::{AdaptiveBuffer, RelayActivity, RelayDirection, RelayOptions, RELAY_BUFFER_METER};
+use io_uring::{opcode, squeue, types, IoUring};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, OnceLock};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+
+/// Threads of the relay runtime when it starts before `init_global_uring_runtime`
+pub const DEFAULT_URING_THREADS: usize = 1;
+/// Submission queue entries per ring
+const RING_ENTRIES: u32 = 1024;
+/// Registered file slots per ring; each relay takes two, so this bounds the
+/// relays one thread carries (further relays are declined and stay buffered)
+const FILE_SLOTS: u32 = 16 * 1024;
+/// user_data of the eventfd read that wakes a ring for new relays
+const WAKE: u64 = u64::MAX;
+
+/// Operation kinds, kept in the low bits of user_data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Read = 0,
+    Write = 1,
+    Shutdown = 2,
+    LinkTimeout = 3,
+    Cancel = 4,
+}
+
+/// user_data layout: relay index << 8 | half << 4 | op
+fn user_data(relay: usize, half: usize, op: Op) -> u64 {
+    (relay as u64) << 8 | (half as u64) << 4 | op as u64
+}
+
+fn decode(user_data: u64) -> (usize, usize, Op) {
+    let op = match user_data & 0xf {
+        0 => Op::Read,
+        1 => Op::Write,
+        2 => Op::Shutdown,
+        3 => Op::LinkTimeout,
+        _ => Op::Cancel,
+    };
+    ((user_data >> 8) as usize, (user_data >> 4 & 0xf) as usize, op)
+}
+
+/// Sockets of one relay, shared with the tokio side so it can cut the relay short
+struct Sockets {
+    client: OwnedFd,
+    target: OwnedFd,
+}
+
+impl Sockets {
+    /// Wake every pending operation: reads see EOF, writes fail
+    fn shutdown(&self) {
+        for fd in [&self.client, &self.target] {
+            let _ = socket2::SockRef::from(fd).shutdown(std::net::Shutdown::Both);
+        }
+    }
+}
+
+/// How the ring thread answers a relay
+enum Completion {
+    /// Both copy loops ended; the error is the one that ended them early
+    Finished(Result<()>),
+    /// No free file slots or registration failed; nothing was read or written
+    Declined,
+}
+
+/// A relay handed to a ring thread
+struct Job {
+    sockets: Arc<Sockets>,
+    /// client -> target, target -> client
+    buffers: [AdaptiveBuffer<'static>; 2],
+    tracker: Option<Arc<TrackedConnection>>,
+    activity: Arc<RelayActivity>,
+    write_stall: Option<Duration>,
+    done: oneshot::Sender<Completion>,
+}
+
+/// Dedicated threads each driving one io_uring instance
+pub struct UringRuntime {
+    workers: Vec<Worker>,
+    next: AtomicUsize,
+}
+
+struct Worker {
+    jobs: mpsc::Sender<Box<Job>>,
+    wake: File,
+}
+
+impl UringRuntime {
+    /// Set up `threads` rings with sparse file tables and start their threads
+    ///
+    /// Fails on kernels without io_uring or sparse file registration (5.19+),
+    /// or where io_uring is disabled.
+    pub fn start(threads: usize) -> io::Result<Self> {
+        let mut workers = Vec::with_capacity(threads);
+        for index in 0..threads.max(1) {
+            let ring = IoUring::new(RING_ENTRIES)?;
+            ring.submitter().register_files_sparse(FILE_SLOTS)?;
+            let wake = eventfd()?;
+            let (jobs, receiver) = mpsc::channel();
+            let ring_loop = RingLoop::new(ring, receiver, wake.try_clone()?);
+            std::thread::Builder::new()
+                .name(format!("uring-relay-{}", index))
+                .spawn(move || ring_loop.run())?;
+            workers.push(Worker { jobs, wake });
+        }
+        Ok(Self {
+            workers,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Number of ring threads
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Hand a relay to the next ring; gives it back when that thread is gone
+    fn submit(&self, job: Box<Job>) -> std::result::Result<(), Box<Job>> {
+        let worker = &self.workers[self.next.fetch_add(1, Ordering::Relaxed) % self.workers.len()];
+        worker.jobs.send(job).map_err(|e| e.0)?;
+        if let Err(e) = (&worker.wake).write_all(&1u64.to_ne_bytes()) {
+            log::warn!("Failed to wake io_uring relay thread: {}", e);
+        }
+        Ok(())
+    }
+}
+
+fn eventfd() -> io::Result<File> {
+    let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: eventfd just returned this descriptor and nothing else owns it
+    Ok(File::from(unsafe { OwnedFd::from_raw_fd(fd) }))
+}
+
+/// One direction of a relay on the ring
+struct Half {
+    direction: RelayDirection,
+    source: types::Fixed,
+    dest: types::Fixed,
+    buffer: AdaptiveBuffer<'static>,
+    /// Bytes of the current read already written out
+    written: usize,
+    total: u64,
+    /// Operation in flight, the one to cancel when the other half fails
+    pending: Option<Op>,
+    done: bool,
+}
+
+/// Submissions waiting for room in the queue; a linked pair goes in together
+type Backlog = VecDeque<(squeue::Entry, Option<squeue::Entry>)>;
+
+struct Relay {
+    index: usize,
+    /// Keeps the descriptors open while they are registered
+    _sockets: Arc<Sockets>,
+    halves: [Half; 2],
+    tracker: Option<Arc<TrackedConnection>>,
+    activity: Arc<RelayActivity>,
+    /// Stall limit and the timespec linked to every write; boxed with the
+    /// relay so the pointer stays valid until the kernel reads it
+    write_stall: Option<(Duration, types::Timespec)>,
+    /// Submissions not completed yet; buffers must outlive all of them
+    inflight: usize,
+    failed: bool,
+    result: Result<()>,
+    done: Option<oneshot::Sender<Completion>>,
+}
+
+impl Relay {
+    fn push(&mut self, backlog: &mut Backlog, entry: squeue::Entry, linked: Option<squeue::Entry>) {
+        self.inflight += 1 + linked.is_some() as usize;
+        backlog.push_back((entry, linked));
+    }
+
+    fn read(&mut self, h: usize, backlog: &mut Backlog) {
+        let half = &mut self.halves[h];
+        let size = half.buffer.size();
+        let buffer = half.buffer.bytes_mut();
+        buffer.clear();
+        buffer.reserve(size);
+        let entry = opcode::Read::new(half.source, buffer.spare_capacity_mut().as_mut_ptr().cast(), size as u32)
+            .build()
+            .user_data(user_data(self.index, h, Op::Read));
+        half.pending = Some(Op::Read);
+        self.push(backlog, entry, None);
+    }
+
+    fn write(&mut self, h: usize, backlog: &mut Backlog) {
+        let half = &mut self.halves[h];
+        let remaining = &half.buffer.bytes_mut()[half.written..];
+        // send 而非 write：对端已关闭时返回 EPIPE，不触发 SIGPIPE
+        let entry = opcode::Send::new(half.dest, remaining.as_ptr(), remaining.len() as u32)
+            .flags(libc::MSG_NOSIGNAL)
+            .build()
+            .user_data(user_data(self.index, h, Op::Write));
+        half.pending = Some(Op::Write);
+        match &self.write_stall {
+            Some((_, timespec)) => {
+                let timeout = opcode::LinkTimeout::new(timespec)
+                    .build()
+                    .user_data(user_data(self.index, h, Op::LinkTimeout));
+                self.push(backlog, entry.flags(squeue::Flags::IO_LINK), Some(timeout));
+            }
+            None => self.push(backlog, entry, None),
+        }
+    }
+
+    /// Pass the source's EOF on to the destination (half close)
+    fn shutdown(&mut self, h: usize, backlog: &mut Backlog) {
+        let half = &mut self.halves[h];
+        let entry = opcode::Shutdown::new(half.dest, libc::SHUT_WR)
+            .build()
+            .user_data(user_data(self.index, h, Op::Shutdown));
+        half.pending = Some(Op::Shutdown);
+        self.push(backlog, entry, None);
+    }
+
+    /// Stop both halves with `error`, like dropping the other copy loop
+    fn fail(&mut self, h: usize, error: ProxyError, backlog: &mut Backlog) {
+        self.failed = true;
+        self.result = Err(error);
+        self.halves[h].done = true;
+        let other = 1 - h;
+        if self.halves[other].done {
+            return;
+        }
+        match self.halves[other].pending {
+            Some(op) => {
+                let entry = opcode::AsyncCancel::new(user_data(self.index, other, op))
+                    .build()
+                    .user_data(user_data(self.index, other, Op::Cancel));
+                self.push(backlog, entry, None);
+            }
+            None => self.halves[other].done = true,
+        }
+    }
+
+    fn on_read(&mut self, h: usize, res: i32, backlog: &mut Backlog) {
+        self.halves[h].pending = None;
+        if self.failed {
+            self.halves[h].done = true;
+            return;
+        }
+        let half = &mut self.halves[h];
+        match res {
+            0 => {
+                log::debug!(
+                    "{}: source closed, total bytes: {}, high water: {}",
+                    half.direction, half.total, half.buffer.high_water()
+                );
+                self.shutdown(h, backlog);
+            }
+            n if n > 0 => {
+                // SAFETY: the kernel initialized the first n bytes of the spare capacity
+                unsafe { half.buffer.bytes_mut().set_len(n as usize) };
+                half.total += n as u64;
+                half.written = 0;
+                self.activity.touch();
+                if let Some(tracker) = &self.tracker {
+                    match half.direction {
+                        RelayDirection::ClientToTarget => tracker.add_upload(n as u64),
+                        RelayDirection::TargetToClient => tracker.add_download(n as u64),
+                    }
+                }
+                self.write(h, backlog);
+            }
+            err => self.fail(h, io::Error::from_raw_os_error(-err).into(), backlog),
+        }
+    }
+
+    fn on_write(&mut self, h: usize, res: i32, backlog: &mut Backlog) {
+        self.halves[h].pending = None;
+        if self.failed {
+            self.halves[h].done = true;
+            return;
+        }
+        let half = &mut self.halves[h];
+        match res {
+            0 => {
+                log::debug!("{}: destination closed, total bytes: {}", half.direction, half.total);
+                half.done = true;
+            }
+            n if n > 0 => {
+                half.written += n as usize;
+                self.activity.touch();
+                let read = half.buffer.bytes_mut().len();
+                if half.written < read {
+                    self.write(h, backlog);
+                } else {
+                    half.buffer.bytes_mut().clear();
+                    half.buffer.record_read(read);
+                    self.read(h, backlog);
+                }
+            }
+            // 只有挂在写上的超时会取消它：对端停止读取
+            err if -err == libc::ECANCELED && self.write_stall.is_some() => {
+                let limit = self.write_stall.as_ref().map(|(limit, _)| *limit).unwrap_or_default();
+                let error = ProxyError::WriteStalled(format!(
+                    "{}: write blocked for {:?}, total bytes: {}",
+                    half.direction, limit, half.total
+                ));
+                self.fail(h, error, backlog);
+            }
+            err => self.fail(h, io::Error::from_raw_os_error(-err).into(), backlog),
+        }
+    }
+
+    fn finished(&self) -> bool {
+        self.inflight == 0 && self.halves.iter().all(|half| half.done)
+    }
+}
+
+/// Event loop of one ring thread
+struct RingLoop {
+    ring: IoUring,
+    jobs: mpsc::Receiver<Box<Job>>,
+    wake: File,
+    wake_buf: Box<[u8; 8]>,
+    relays: Vec<Option<Box<Relay>>>,
+    free: Vec<usize>,
+    backlog: Backlog,
+}
+
+impl RingLoop {
+    fn new(ring: IoUring, jobs: mpsc::Receiver<Box<Job>>, wake: File) -> Self {
+        Self {
+            ring,
+            jobs,
+            wake,
+            wake_buf: Box::new([0; 8]),
+            relays: Vec::new(),
+            free: Vec::new(),
+            backlog: Backlog::new(),
+        }
+    }
+
+    fn run(mut self) {
+        self.arm_wake();
+        loop {
+            self.flush_backlog();
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {}
+                // 完成队列溢出时先收割
+                Err(e) if matches!(e.raw_os_error(), Some(libc::EINTR) | Some(libc::EBUSY)) => {}
+                Err(e) => {
+                    log::error!("io_uring relay thread stopped: {}", e);
+                    // 内核可能仍持有缓冲区，不能释放
+                    std::mem::forget(self.relays);
+                    return;
+                }
+            }
+            let completions: Vec<(u64, i32)> =
+                self.ring.completion().map(|cqe| (cqe.user_data(), cqe.result())).collect();
+            for (user_data, res) in completions {
+                self.complete(user_data, res);
+            }
+        }
+    }
+
+    fn arm_wake(&mut self) {
+        let entry = opcode::Read::new(types::Fd(self.wake.as_raw_fd()), self.wake_buf.as_mut_ptr(), 8)
+            .build()
+            .user_data(WAKE);
+        self.backlog.push_back((entry, None));
+    }
+
+    /// Move queued submissions into the ring as far as they fit
+    fn flush_backlog(&mut self) {
+        let mut queue = self.ring.submission();
+        while let Some((entry, linked)) = self.backlog.front() {
+            // SAFETY: buffers and timespecs live in boxed relays that are only
+            // freed once every submission of theirs has completed
+            let pushed = unsafe {
+                match linked {
+                    Some(linked) => queue.push_multiple(&[entry.clone(), linked.clone()]),
+                    None => queue.push(entry),
+                }
+            };
+            if pushed.is_err() {
+                break;
+            }
+            self.backlog.pop_front();
+        }
+    }
+
+    fn complete(&mut self, user_data: u64, res: i32) {
+        if user_data == WAKE {
+            while let Ok(job) = self.jobs.try_recv() {
+                self.start(job);
+            }
+            self.arm_wake();
+            return;
+        }
+        let (index, h, op) = decode(user_data);
+        let backlog = &mut self.backlog;
+        let Some(relay) = self.relays.get_mut(index).and_then(Option::as_mut) else {
+            return;
+        };
+        relay.inflight -= 1;
+        match op {
+            Op::Read => relay.on_read(h, res, backlog),
+            Op::Write => relay.on_write(h, res, backlog),
+            Op::Shutdown => {
+                relay.halves[h].pending = None;
+                relay.halves[h].done = true;
+            }
+            Op::LinkTimeout | Op::Cancel => {}
+        }
+        if relay.finished() {
+            self.finish(index);
+        }
+    }
+
+    fn start(&mut self, job: Box<Job>) {
+        let Job { sockets, buffers, tracker, activity, write_stall, done } = *job;
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None if self.relays.len() < (FILE_SLOTS / 2) as usize => {
+                self.relays.push(None);
+                self.relays.len() - 1
+            }
+            None => {
+                drop(sockets);
+                let _ = done.send(Completion::Declined);
+                return;
+            }
+        };
+        let slot = (index * 2) as u32;
+        let fds = [sockets.client.as_raw_fd(), sockets.target.as_raw_fd()];
+        if let Err(e) = self.ring.submitter().register_files_update(slot, &fds) {
+            log::debug!("io_uring file registration failed: {}", e);
+            self.free.push(index);
+            drop(sockets);
+            let _ = done.send(Completion::Declined);
+            return;
+        }
+
+        let (client, target) = (types::Fixed(slot), types::Fixed(slot + 1));
+        let [upload, download] = buffers;
+        let half = |direction, source, dest, buffer| Half {
+            direction,
+            source,
+            dest,
+            buffer,
+            written: 0,
+            total: 0,
+            pending: None,
+            done: false,
+        };
+        let mut relay = Box::new(Relay {
+            index,
+            _sockets: sockets,
+            halves: [
+                half(RelayDirection::ClientToTarget, client, target, upload),
+                half(RelayDirection::TargetToClient, target, client, download),
+            ],
+            tracker,
+            activity,
+            write_stall: write_stall.map(|limit| (limit, types::Timespec::from(limit))),
+            inflight: 0,
+            failed: false,
+            result: Ok(()),
+            done: Some(done),
+        });
+        relay.read(0, &mut self.backlog);
+        relay.read(1, &mut self.backlog);
+        self.relays[index] = Some(relay);
+    }
+
+    fn finish(&mut self, index: usize) {
+        let Some(mut relay) = self.relays[index].take() else {
+            return;
+        };
+        if let Err(e) = self.ring.submitter().register_files_update((index * 2) as u32, &[-1, -1]) {
+            log::debug!("io_uring file unregistration failed: {}", e);
+        }
+        self.free.push(index);
+        for half in &relay.halves {
+            log::debug!("{}: relay completed, total bytes: {}", half.direction, half.total);
+        }
+        let done = relay.done.take();
+        let result = std::mem::replace(&mut relay.result, Ok(()));
+        // 先放掉 socket 的引用，tokio 侧才能取回
+        drop(relay);
+        if let Some(done) = done {
+            let _ = done.send(Completion::Finished(result));
+        }
+    }
+}
+
+/// Result of handing a relay to the io_uring runtime
+pub(crate) enum UringRelay {
+    /// Copying is done; the sockets are back for the orderly close
+    Relayed { client: TcpStream, target: TcpStream, result: Result<()> },
+    /// The connection was killed; the ring finishes with the sockets shut down
+    Killed,
+    /// Neither direction moved a byte for `idle_timeout`; shut down like a kill
+    Idle,
+    /// Nothing was relayed; use the buffered loops instead
+    Declined { client: TcpStream, target: TcpStream },
+}
+
+/// Shuts both sockets down when the relay is dropped or killed mid-copy
+struct ShutdownOnDrop(Option<Arc<Sockets>>);
+
+impl ShutdownOnDrop {
+    fn disarm(mut self) -> Arc<Sockets> {
+        self.0.take().expect("armed guard")
+    }
+}
+
+impl Drop for ShutdownOnDrop {
+    fn drop(&mut self) {
+        if let Some(sockets) = &self.0 {
+            sockets.shutdown();
+        }
+    }
+}
+
+/// Take a socket off tokio's reactor and make it blocking, so io_uring waits
+/// on it instead of returning EAGAIN
+fn into_blocking(stream: TcpStream) -> io::Result<OwnedFd> {
+    let stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    Ok(stream.into())
+}
+
+fn restore(sockets: Arc<Sockets>) -> Result<(TcpStream, TcpStream)> {
+    let Sockets { client, target } = Arc::try_unwrap(sockets)
+        .map_err(|_| ProxyError::Protocol("io_uring relay sockets still in use".to_string()))?;
+    let register = |fd: OwnedFd| {
+        let stream = std::net::TcpStream::from(fd);
+        stream.set_nonblocking(true)?;
+        TcpStream::from_std(stream)
+    };
+    Ok((register(client)?, register(target)?))
+}
+
+/// Run both copy loops of a relay on the io_uring runtime
+///
+/// Byte counts go to `tracker` as data is read, and a kill or the idle
+/// timeout ends the relay right away, as with the buffered loops.
+pub(crate) async fn relay(
+    client: TcpStream,
+    target: TcpStream,
+    options: &RelayOptions,
+    tracker: Option<Arc<TrackedConnection>>,
+) -> Result<UringRelay> {
+    let Some(runtime) = get_global_uring_runtime() else {
+        return Ok(UringRelay::Declined { client, target });
+    };
+    let sockets = Arc::new(Sockets {
+        client: into_blocking(client)?,
+        target: into_blocking(target)?,
+    });
+    let (done, completion) = oneshot::channel();
+    let activity = Arc::new(RelayActivity::new());
+    let job = Box::new(Job {
+        sockets: sockets.clone(),
+        buffers: [
+            AdaptiveBuffer::new(*options, &RELAY_BUFFER_METER),
+            AdaptiveBuffer::new(*options, &RELAY_BUFFER_METER),
+        ],
+        tracker: tracker.clone(),
+        activity: activity.clone(),
+        write_stall: options.write_stall,
+        done,
+    });
+    let guard = ShutdownOnDrop(Some(sockets));
+    if let Err(job) = runtime.submit(job) {
+        drop(job);
+        let (client, target) = restore(guard.disarm())?;
+        return Ok(UringRelay::Declined { client, target });
+    }
+
+    let killed = async {
+        match tracker.as_deref() {
+            Some(t) => t.killed().await,
+            None => std::future::pending().await,
+        }
+    };
+    let completion = tokio::select! {
+        completion = completion => completion,
+        _ = killed => return Ok(UringRelay::Killed),
+        _ = activity.idle(options.idle_timeout) => return Ok(UringRelay::Idle),
+    };
+    match completion {
+        Ok(Completion::Finished(result)) => {
+            let (client, target) = restore(guard.disarm())?;
+            Ok(UringRelay::Relayed { client, target, result })
+        }
+        Ok(Completion::Declined) => {
+            let (client, target) = restore(guard.disarm())?;
+            Ok(UringRelay::Declined { client, target })
+        }
+        Err(_) => Err(ProxyError::Protocol("io_uring relay thread exited".to_string())),
+    }
+}
+
+static GLOBAL_URING_RUNTIME: OnceLock<Option<UringRuntime>> = OnceLock::new();
+
+fn start_runtime(threads: usize) -> Option<UringRuntime> {
+    match UringRuntime::start(threads) {
+        Ok(runtime) => {
+            log::info!("io_uring relay runtime started with {} threads", runtime.threads());
+            Some(runtime)
+        }
+        Err(e) => {
+            log::warn!("io_uring relay runtime unavailable, relays stay buffered: {}", e);
+            None
+        }
+    }
+}
+
+/// Start the io_uring relay runtime with `threads` rings
+pub fn init_global_uring_runtime(threads: usize) {
+    let _ = GLOBAL_URING_RUNTIME.set(start_runtime(threads));
+}
+
+/// The io_uring relay runtime, started with default settings on first use;
+/// None when this kernel cannot run it
+pub fn get_global_uring_runtime() -> Option<&'static UringRuntime> {
+    GLOBAL_URING_RUNTIME
+        .get_or_init(|| start_runtime(DEFAULT_URING_THREADS))
+        .as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_data_round_trips() {
+        for (relay, half, op) in [(0, 0, Op::Read), (8191, 1, Op::LinkTimeout), (42, 1, Op::Cancel), (7, 0, Op::Shutdown)] {
+            let encoded = user_data(relay, half, op);
+            assert_ne!(encoded, WAKE);
+            assert_eq!(decode(encoded), (relay, half, op));
+        }
+    }
+}
@@ -0,0 +1,317 @@
+// 慢连接看门狗：周期扫描连接注册表，记录并可选终止停滞的连接
+use crate::config::WatchdogConfig;
+use crate::connection_registry::{get_global_connection_registry, ConnectionPhase, ConnectionRegistry, ConnectionSnapshot};
+use crate::tasks::{get_global_task_tracker, TaskGroup};
+use log::{info, warn};
+use std::time::Duration;
+
+/// Why a connection was reported as slow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowReason {
+    /// Established for a while without transferring a single byte
+    NoTraffic,
+    /// No bytes in either direction for a while
+    Idle,
+}
+
+/// Most bytes a stalled connection may have sent and received and still
+/// look like a PMTU blackhole
+const PMTU_SUSPECT_MAX_UPLOAD: u64 = 8 * 1024;
+const PMTU_SUSPECT_MAX_DOWNLOAD: u64 = 4 * 1024;
+
+/// A connection flagged by the watchdog
+#[derive(Debug, Clone)]
+pub struct SlowConnection {
+    pub connection: ConnectionSnapshot,
+    pub reason: SlowReason,
+    /// Whether the watchdog killed it because it exceeded `stall_kill_secs`
+    pub killed: bool,
+    /// Stalled the way full-size segments being dropped on the path stall a download
+    pub possible_pmtu_blackhole: bool,
+}
+
+/// Whether an idle relayed connection stopped like a path MTU blackhole stops one
+///
+/// The handshake and a few small packets back got through, then the download
+/// died: the path drops segments above its MTU without telling the sender,
+/// so only the small ones arrive. Clamping the outbound's `tcp_mss` helps.
+pub fn possible_pmtu_blackhole(conn: &ConnectionSnapshot) -> bool {
+    conn.phase == ConnectionPhase::Relaying
+        && (1..=PMTU_SUSPECT_MAX_UPLOAD).contains(&conn.upload)
+        && (1..=PMTU_SUSPECT_MAX_DOWNLOAD).contains(&conn.download)
+}
+
+pub struct Watchdog<'r> {
+    registry: &'r ConnectionRegistry,
+    config: WatchdogConfig,
+}
+
+impl<'r> Watchdog<'r> {
+    pub fn new(registry: &'r ConnectionRegistry, config: WatchdogConfig) -> Self {
+        Self { registry, config }
+    }
+
+    /// List slow connections without acting on them
+    pub fn slow_connections(&self) -> Vec<SlowConnection> {
+        let no_traffic = Duration::from_secs(self.config.no_traffic_secs);
+        let idle = Duration::from_secs(self.config.idle_secs);
+
+        self.registry
+            .connections()
+            .iter()
+            // 已应答的客户端探测只是等客户端关闭，不算慢连接
+            .filter(|conn| !conn.is_probe())
+            .filter_map(|conn| {
+                let reason = if conn.total_bytes() == 0 && conn.age() >= no_traffic {
+                    SlowReason::NoTraffic
+                } else if conn.idle_for() >= idle {
+                    SlowReason::Idle
+                } else {
+                    return None;
+                };
+                let connection = conn.snapshot();
+                Some(SlowConnection {
+                    possible_pmtu_blackhole: reason == SlowReason::Idle && possible_pmtu_blackhole(&connection),
+                    connection,
+                    reason,
+                    killed: false,
+                })
+            })
+            .collect()
+    }
+
+    /// Log slow connections and kill those stalled beyond the hard limit
+    ///
+    /// The hard limit applies to every connection, also when it is below the
+    /// report thresholds; connections killed without being reported are
+    /// added to the result.
+    pub fn scan(&self) -> Vec<SlowConnection> {
+        let mut slow = self.slow_connections();
+        let kill_after = self.config.stall_kill_secs.map(Duration::from_secs);
+
+        for entry in &mut slow {
+            let conn = &entry.connection;
+            let hint = if entry.possible_pmtu_blackhole {
+                format!(
+                    "; possible PMTU blackhole, consider tcp_mss on outbound {}",
+                    conn.outbound.as_deref().unwrap_or("-")
+                )
+            } else {
+                String::new()
+            };
+            warn!(
+                "Slow connection #{} ({:?}): client {}, target {}, outbound {}, phase {}, age {:?}, idle {:?}, up {} B, down {} B{}",
+                conn.id,
+                entry.reason,
+                conn.client,
+                conn.target.as_deref().unwrap_or("-"),
+                conn.outbound.as_deref().unwrap_or("-"),
+                conn.phase,
+                conn.age,
+                conn.idle,
+                conn.upload,
+                conn.download,
+                hint,
+            );
+        }
+
+        let Some(limit) = kill_after else {
+            return slow;
+        };
+        for conn in self.registry.connections() {
+            let idle = conn.idle_for();
+            if conn.is_probe() || idle < limit {
+                continue;
+            }
+            conn.kill();
+            info!("Killed connection #{} stalled for {:?}", conn.id(), idle);
+            match slow.iter_mut().find(|entry| entry.connection.id == conn.id()) {
+                Some(entry) => entry.killed = true,
+                None => slow.push(SlowConnection {
+                    connection: conn.snapshot(),
+                    reason: if conn.total_bytes() == 0 { SlowReason::NoTraffic } else { SlowReason::Idle },
+                    killed: true,
+                    possible_pmtu_blackhole: false,
+                }),
+            }
+        }
+        slow
+    }
+}
+
+/// Start the periodic watchdog over the global connection registry
+pub fn start_watchdog(config: WatchdogConfig) {
+    if config.interval_secs == 0 {
+        return;
+    }
+
+    let spawned = get_global_task_tracker().spawn(TaskGroup::HealthChecks, async move {
+        let watchdog = Watchdog::new(get_global_connection_registry(), config);
+        let mut interval = tokio::time::interval(Duration::from_secs(watchdog.config.interval_secs));
+        loop {
+            interval.tick().await;
+            watchdog.scan();
+        }
+    });
+    if let Err(e) = spawned {
+        warn!("Watchdog not started: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection_registry::ConnectionPhase;
+    use crate::zero_copy::{available_relay_backends, RelayOptions, ZeroCopyRelay};
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn config(stall_kill_secs: Option<u64>) -> WatchdogConfig {
+        WatchdogConfig {
+            interval_secs: 1,
+            no_traffic_secs: 10,
+            idle_secs: 60,
+            stall_kill_secs,
+        }
+    }
+
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (client.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_silent_connection_reported_as_slow() {
+        let registry = ConnectionRegistry::new();
+        let stalled = registry.register("127.0.0.1:4000".parse().unwrap());
+        stalled.set_phase(ConnectionPhase::Connecting);
+        let active = registry.register("127.0.0.1:4001".parse().unwrap());
+        // 已应答的客户端探测从不计为慢连接
+        let probe = registry.register("127.0.0.1:4002".parse().unwrap());
+        probe.set_probe();
+
+        let watchdog = Watchdog::new(&registry, config(None));
+        assert!(watchdog.slow_connections().is_empty());
+
+        tokio::time::advance(Duration::from_secs(11)).await;
+        active.add_upload(1);
+
+        let slow = watchdog.scan();
+        assert_eq!(slow.len(), 1);
+        assert_eq!(slow[0].connection.id, stalled.id());
+        assert_eq!(slow[0].reason, SlowReason::NoTraffic);
+        assert_eq!(slow[0].connection.phase, ConnectionPhase::Connecting);
+        assert!(!stalled.is_killed());
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        let slow = watchdog.slow_connections();
+        assert_eq!(slow.len(), 2);
+        assert!(slow.iter().any(|s| s.connection.id == active.id() && s.reason == SlowReason::Idle));
+    }
+
+    fn transfer(phase: ConnectionPhase, upload: u64, download: u64) -> ConnectionSnapshot {
+        ConnectionSnapshot {
+            id: 1,
+            client: "127.0.0.1:4000".parse().unwrap(),
+            target: Some("example.com:443".to_string()),
+            rewritten_to: None,
+            outbound: Some("vpn".to_string()),
+            user: None,
+            authenticated: false,
+            dry_run: None,
+            probe: false,
+            profile: None,
+            relay_result: None,
+            phase,
+            age: Duration::from_secs(120),
+            idle: Duration::from_secs(90),
+            connect_latency: Some(Duration::from_millis(30)),
+            upload,
+            download,
+        }
+    }
+
+    #[test]
+    fn test_pmtu_blackhole_heuristic() {
+        // ClientHello 过去，只收到几个小包后下载停滞
+        assert!(possible_pmtu_blackhole(&transfer(ConnectionPhase::Relaying, 517, 1200)));
+        assert!(possible_pmtu_blackhole(&transfer(ConnectionPhase::Relaying, 2048, 4096)));
+        // 没有收到任何数据、已下载大量数据、大量上传或尚未转发的连接不算
+        assert!(!possible_pmtu_blackhole(&transfer(ConnectionPhase::Relaying, 517, 0)));
+        assert!(!possible_pmtu_blackhole(&transfer(ConnectionPhase::Relaying, 517, 512 * 1024)));
+        assert!(!possible_pmtu_blackhole(&transfer(ConnectionPhase::Relaying, 64 * 1024, 1200)));
+        assert!(!possible_pmtu_blackhole(&transfer(ConnectionPhase::Connecting, 517, 1200)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_small_transfer_flagged() {
+        let registry = ConnectionRegistry::new();
+        let stalled = registry.register("127.0.0.1:4000".parse().unwrap());
+        stalled.set_phase(ConnectionPhase::Relaying);
+        stalled.add_upload(517);
+        stalled.add_download(1300);
+        let bulk = registry.register("127.0.0.1:4001".parse().unwrap());
+        bulk.set_phase(ConnectionPhase::Relaying);
+        bulk.add_upload(517);
+        bulk.add_download(2 * 1024 * 1024);
+
+        tokio::time::advance(Duration::from_secs(61)).await;
+        let slow = Watchdog::new(&registry, config(None)).scan();
+        assert_eq!(slow.len(), 2);
+        for entry in slow {
+            assert_eq!(entry.reason, SlowReason::Idle);
+            assert_eq!(entry.possible_pmtu_blackhole, entry.connection.id == stalled.id());
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_kill_limit_below_report_thresholds() {
+        let registry = ConnectionRegistry::new();
+        let stalled = registry.register("127.0.0.1:4000".parse().unwrap());
+        stalled.set_phase(ConnectionPhase::Relaying);
+        stalled.add_upload(100);
+        stalled.add_download(100);
+        let active = registry.register("127.0.0.1:4001".parse().unwrap());
+        active.add_upload(1);
+
+        // stall_kill_secs 低于 idle_secs：传过数据后空闲的连接也在硬上限时终止
+        tokio::time::advance(Duration::from_secs(21)).await;
+        active.add_download(1);
+        let slow = Watchdog::new(&registry, config(Some(20))).scan();
+        assert_eq!(slow.len(), 1);
+        assert_eq!((slow[0].connection.id, slow[0].reason, slow[0].killed), (stalled.id(), SlowReason::Idle, true));
+        assert!(stalled.is_killed());
+        assert!(!active.is_killed());
+    }
+
+    #[tokio::test]
+    async fn test_stalled_relay_killed_at_hard_limit() {
+        for backend in available_relay_backends() {
+            stalled_relay_killed(RelayOptions { backend, ..RelayOptions::default() }).await;
+        }
+    }
+
+    async fn stalled_relay_killed(options: RelayOptions) {
+        let registry = ConnectionRegistry::new();
+        let (client, _client_peer) = tcp_pair().await;
+        let (target, _target_peer) = tcp_pair().await;
+
+        let guard = registry.register(client.local_addr().unwrap());
+        guard.set_phase(ConnectionPhase::Relaying);
+        let relay = ZeroCopyRelay::with_options(client, target, options).with_tracker(guard.connection().clone());
+        let relay = tokio::spawn(relay.start());
+
+        tokio::time::pause();
+        tokio::time::advance(Duration::from_secs(30)).await;
+
+        let watchdog = Watchdog::new(&registry, config(Some(20)));
+        let slow = watchdog.scan();
+        assert_eq!(slow.len(), 1);
+        assert!(slow[0].killed);
+        assert!(guard.is_killed());
+
+        tokio::time::resume();
+        relay.await.unwrap().unwrap();
+    }
+}
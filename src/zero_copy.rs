@@ -1,86 +1,982 @@
+use crate::buffer_pool::{get_global_buffer_pool, BufferPool, BufferPoolStats};
+use crate::capture::{Capture, CaptureDirection};
+use crate::config::{PerformanceConfig, RelayBackend, RelayBackendMode};
+use crate::connection_registry::TrackedConnection;
 use crate::error::Result;
+use crate::stream::ProxyStream;
+use crate::tls_fragment::{is_tls_handshake, write_fragmented, TlsFragmentConfig};
+use crate::traffic_mark::{enable_nodelay, QuickAck};
 use bytes::{Buf, BytesMut};
 use futures::future::try_join;
+use futures::FutureExt;
 use std::io::Result as IoResult;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::split;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+/// Smallest relay buffer, used for new connections when adaptive sizing is on
+pub const ADAPTIVE_MIN_BUFFER_SIZE: usize = 4 * 1024;
+/// Consecutive reads that fill the buffer before it doubles
+pub const ADAPTIVE_GROW_AFTER_FULL_READS: u32 = 2;
+/// Consecutive under-utilized reads before the buffer halves
+pub const ADAPTIVE_SHRINK_AFTER_IDLE_READS: u32 = 16;
+/// A read is under-utilized when it uses less than 1/N of the buffer
+pub const ADAPTIVE_LOW_UTILIZATION_DIVISOR: usize = 4;
+/// How long a finished relay waits for the peers' FINs after shutting down both writers
+pub const RELAY_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a target must stay silent after the client closed before a
+/// reusable relay hands it back for pooling
+pub const POOL_DRAIN_QUIET: Duration = Duration::from_millis(200);
+
+/// Relay buffer settings
+#[derive(Debug, Clone, Copy)]
+pub struct RelayOptions {
+    /// Maximum (or, with adaptive sizing off, fixed) buffer size per direction
+    pub buffer_size: usize,
+    /// Grow and shrink buffers based on observed throughput
+    pub adaptive_buffers: bool,
+    /// Abort when a single write stays blocked this long
+    pub write_stall: Option<Duration>,
+    /// End the relay when neither direction has moved a byte this long
+    pub idle_timeout: Option<Duration>,
+    /// Split the client's first TLS handshake packet into small segments
+    pub tls_fragment: Option<TlsFragmentConfig>,
+    /// Bound on the orderly close once relaying is done
+    pub close_timeout: Duration,
+    /// Interactive connection: TCP_NODELAY on both sockets, TCP_QUICKACK after
+    /// every read, and every read written out on its own
+    pub latency_mode: bool,
+    /// Copy loop implementation; relays that tee, fragment or run in latency
+    /// mode always use the buffered loop
+    pub backend: RelayBackend,
+}
+
+impl RelayOptions {
+    pub fn from_config(config: &PerformanceConfig) -> Self {
+        Self {
+            buffer_size: config.buffer_size,
+            adaptive_buffers: config.adaptive_buffers,
+            write_stall: config.write_stall_secs.map(Duration::from_secs),
+            idle_timeout: config.relay_idle_timeout_secs.map(Duration::from_secs),
+            tls_fragment: None,
+            close_timeout: RELAY_CLOSE_TIMEOUT,
+            latency_mode: false,
+            backend: select_relay_backend(config),
+        }
+    }
+}
+
+impl Default for RelayOptions {
+    fn default() -> Self {
+        Self {
+            buffer_size: 64 * 1024,
+            adaptive_buffers: true,
+            write_stall: None,
+            idle_timeout: None,
+            tls_fragment: None,
+            close_timeout: RELAY_CLOSE_TIMEOUT,
+            latency_mode: false,
+            backend: RelayBackend::Buffered,
+        }
+    }
+}
+
+/// Whether relays can use `backend` in this build and on this kernel
+///
+/// Checking io_uring starts the relay runtime on first use.
+pub fn relay_backend_available(backend: RelayBackend) -> bool {
+    match backend {
+        RelayBackend::Buffered => true,
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        RelayBackend::Uring => crate::uring_relay::get_global_uring_runtime().is_some(),
+        #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+        RelayBackend::Uring => false,
+        RelayBackend::Splice => cfg!(target_os = "linux"),
+    }
+}
+
+/// Every backend relays can use here, buffered last
+pub fn available_relay_backends() -> Vec<RelayBackend> {
+    [RelayBackend::Uring, RelayBackend::Splice, RelayBackend::Buffered]
+        .into_iter()
+        .filter(|backend| relay_backend_available(*backend))
+        .collect()
+}
+
+/// Backend picked by `performance.relay_backend`: the configured one, or for
+/// `auto` the first available in `relay_backend_order`, falling back to buffered
+pub fn select_relay_backend(config: &PerformanceConfig) -> RelayBackend {
+    let candidates = match config.relay_backend {
+        RelayBackendMode::Auto => config.relay_backend_order.as_slice(),
+        RelayBackendMode::Uring => &[RelayBackend::Uring],
+        RelayBackendMode::Splice => &[RelayBackend::Splice],
+        RelayBackendMode::Buffered => &[RelayBackend::Buffered],
+    };
+    candidates
+        .iter()
+        .copied()
+        .find(|backend| relay_backend_available(*backend))
+        .unwrap_or(RelayBackend::Buffered)
+}
+
+/// Start the backend chosen by the configuration and report the choice
+pub fn init_relay_backend(config: &PerformanceConfig) {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    {
+        let wants_uring = match config.relay_backend {
+            RelayBackendMode::Auto => config.relay_backend_order.contains(&RelayBackend::Uring),
+            RelayBackendMode::Uring => true,
+            RelayBackendMode::Splice | RelayBackendMode::Buffered => false,
+        };
+        if wants_uring {
+            crate::uring_relay::init_global_uring_runtime(config.uring_threads);
+        }
+    }
+    let backend = select_relay_backend(config);
+    if config.relay_backend == RelayBackendMode::Uring && backend != RelayBackend::Uring {
+        log::warn!("io_uring relay backend unavailable (needs Linux 5.19+ and the io-uring feature), relaying with {}", backend);
+    }
+    if config.relay_backend == RelayBackendMode::Splice && backend != RelayBackend::Splice {
+        log::warn!("splice relay backend unavailable (needs Linux), relaying with {}", backend);
+    }
+    log::info!("Relay backend: {}", backend);
+}
+
+/// How a relay ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelayResult {
+    /// Both directions reached EOF and both sides were closed in order
+    Completed,
+    /// A peer reset or failed its connection; the other side was still closed in order
+    PeerAborted(String),
+    /// We ended the relay (connection killed); both sockets were dropped as
+    /// they were
+    Aborted(String),
+    /// No bytes moved in either direction for this long; both sockets were
+    /// dropped as they were
+    IdleTimeout(Duration),
+}
+
+/// When either direction of a relay last moved bytes
+pub(crate) struct RelayActivity {
+    started: Instant,
+    /// 距 started 的毫秒数
+    last: AtomicU64,
+    /// 缓冲循环已读入、尚未写出的字节数
+    unsent: AtomicUsize,
+}
+
+impl RelayActivity {
+    pub(crate) fn new() -> Self {
+        Self { started: Instant::now(), last: AtomicU64::new(0), unsent: AtomicUsize::new(0) }
+    }
+
+    pub(crate) fn touch(&self) {
+        self.last.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn queue(&self, bytes: usize) {
+        self.unsent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn sent(&self, bytes: usize) {
+        self.unsent.fetch_sub(bytes, Ordering::Relaxed);
+        self.touch();
+    }
+
+    /// Resolves once no bytes have moved for `quiet` and none are left
+    /// waiting in a buffered loop for a slow reader
+    async fn drained(&self, quiet: Duration) {
+        loop {
+            self.idle(Some(quiet)).await;
+            if self.unsent.load(Ordering::Relaxed) == 0 {
+                return;
+            }
+            tokio::time::sleep(quiet).await;
+        }
+    }
+
+    /// Resolves once no bytes have moved for `limit`; never without a limit
+    pub(crate) async fn idle(&self, limit: Option<Duration>) {
+        let Some(limit) = limit else {
+            return std::future::pending().await;
+        };
+        loop {
+            let deadline = self.started + Duration::from_millis(self.last.load(Ordering::Relaxed)) + limit;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
+
+/// Count and log a relay ended for being idle
+pub(crate) fn idle_timed_out(limit: Duration) -> RelayResult {
+    RELAY_IDLE_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+    log::info!("Relay closed: idle for {:?}", limit);
+    RelayResult::IdleTimeout(limit)
+}
+
+/// Byte counter for relay buffer memory
+#[derive(Debug, Default)]
+pub struct BufferMeter {
+    bytes: AtomicUsize,
+    buffers: AtomicUsize,
+}
+
+impl BufferMeter {
+    pub const fn new() -> Self {
+        Self {
+            bytes: AtomicUsize::new(0),
+            buffers: AtomicUsize::new(0),
+        }
+    }
+
+    /// Bytes currently allocated by live buffers
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Number of live buffers
+    pub fn buffers(&self) -> usize {
+        self.buffers.load(Ordering::Relaxed)
+    }
+}
+
+pub(crate) static RELAY_BUFFER_METER: BufferMeter = BufferMeter::new();
+static RELAY_WRITE_STALLS: AtomicU64 = AtomicU64::new(0);
+static RELAY_PEER_ABORTS: AtomicU64 = AtomicU64::new(0);
+static RELAY_IDLE_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+
+/// Relay statistics snapshot
+#[derive(Debug, Clone)]
+pub struct RelayStats {
+    /// Buffers currently held by active relays
+    pub live_buffers: usize,
+    /// Total bytes held by those buffers
+    pub buffer_bytes: usize,
+    /// Relays aborted because the receiving side stopped reading
+    pub write_stalls: u64,
+    /// Relays ended by a peer resetting or failing its connection
+    pub peer_aborts: u64,
+    /// Relays closed after `relay_idle_timeout_secs` without traffic
+    pub idle_timeouts: u64,
+    /// Reuse counters of the buffer pool
+    pub pool: BufferPoolStats,
+}
+
+/// Get relay buffer statistics for all active relays
+pub fn relay_stats() -> RelayStats {
+    RelayStats {
+        live_buffers: RELAY_BUFFER_METER.buffers(),
+        buffer_bytes: RELAY_BUFFER_METER.bytes(),
+        write_stalls: RELAY_WRITE_STALLS.load(Ordering::Relaxed),
+        peer_aborts: RELAY_PEER_ABORTS.load(Ordering::Relaxed),
+        idle_timeouts: RELAY_IDLE_TIMEOUTS.load(Ordering::Relaxed),
+        pool: get_global_buffer_pool().stats(),
+    }
+}
+
+/// Relay buffer that grows under sustained load and shrinks when idle
+pub struct AdaptiveBuffer<'m> {
+    buffer: BytesMut,
+    size: usize,
+    max_size: usize,
+    adaptive: bool,
+    full_reads: u32,
+    idle_reads: u32,
+    high_water: usize,
+    meter: &'m BufferMeter,
+    pool: &'m BufferPool,
+}
+
+impl<'m> AdaptiveBuffer<'m> {
+    pub fn new(options: RelayOptions, meter: &'m BufferMeter) -> Self {
+        Self::with_pool(options, meter, get_global_buffer_pool())
+    }
+
+    /// Buffer drawn from (and returned to) `pool`
+    pub fn with_pool(options: RelayOptions, meter: &'m BufferMeter, pool: &'m BufferPool) -> Self {
+        let max_size = options.buffer_size.max(1);
+        let size = if options.adaptive_buffers {
+            ADAPTIVE_MIN_BUFFER_SIZE.min(max_size)
+        } else {
+            max_size
+        };
+        meter.bytes.fetch_add(size, Ordering::Relaxed);
+        meter.buffers.fetch_add(1, Ordering::Relaxed);
+
+        Self {
+            buffer: pool.get(size),
+            size,
+            max_size,
+            adaptive: options.adaptive_buffers,
+            full_reads: 0,
+            idle_reads: 0,
+            high_water: 0,
+            meter,
+            pool,
+        }
+    }
+
+    /// The buffer itself, for backends that fill it outside of `read_buf`
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub(crate) fn bytes_mut(&mut self) -> &mut BytesMut {
+        &mut self.buffer
+    }
+
+    /// Current buffer size
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Largest single read observed
+    pub fn high_water(&self) -> usize {
+        self.high_water
+    }
+
+    /// Record a read of `n` bytes and resize the buffer if the pattern warrants it
+    ///
+    /// Must be called once the buffer has been drained.
+    pub fn record_read(&mut self, n: usize) {
+        self.high_water = self.high_water.max(n);
+        if !self.adaptive {
+            return;
+        }
+
+        if n >= self.size {
+            self.full_reads += 1;
+            self.idle_reads = 0;
+            if self.full_reads >= ADAPTIVE_GROW_AFTER_FULL_READS && self.size < self.max_size {
+                self.resize((self.size * 2).min(self.max_size));
+            }
+        } else if n < self.size / ADAPTIVE_LOW_UTILIZATION_DIVISOR {
+            self.idle_reads += 1;
+            self.full_reads = 0;
+            if self.idle_reads >= ADAPTIVE_SHRINK_AFTER_IDLE_READS && self.size > ADAPTIVE_MIN_BUFFER_SIZE {
+                self.resize((self.size / 2).max(ADAPTIVE_MIN_BUFFER_SIZE));
+            }
+        } else {
+            self.full_reads = 0;
+            self.idle_reads = 0;
+        }
+    }
+
+    fn resize(&mut self, new_size: usize) {
+        if new_size > self.size {
+            self.meter.bytes.fetch_add(new_size - self.size, Ordering::Relaxed);
+        } else {
+            self.meter.bytes.fetch_sub(self.size - new_size, Ordering::Relaxed);
+        }
+        let old = std::mem::replace(&mut self.buffer, self.pool.get(new_size));
+        self.pool.put(old);
+        self.size = new_size;
+        self.full_reads = 0;
+        self.idle_reads = 0;
+    }
+}
+
+impl Drop for AdaptiveBuffer<'_> {
+    fn drop(&mut self) {
+        self.meter.bytes.fetch_sub(self.size, Ordering::Relaxed);
+        self.meter.buffers.fetch_sub(1, Ordering::Relaxed);
+        self.pool.put(std::mem::take(&mut self.buffer));
+    }
+}
+
+/// Direction of one half of a relay
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RelayDirection {
+    ClientToTarget,
+    TargetToClient,
+}
+
+impl std::fmt::Display for RelayDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RelayDirection::ClientToTarget => f.write_str("client -> target"),
+            RelayDirection::TargetToClient => f.write_str("target -> client"),
+        }
+    }
+}
+
+impl From<RelayDirection> for CaptureDirection {
+    fn from(direction: RelayDirection) -> Self {
+        match direction {
+            RelayDirection::ClientToTarget => CaptureDirection::ClientToTarget,
+            RelayDirection::TargetToClient => CaptureDirection::TargetToClient,
+        }
+    }
+}
+
+/// How one direction of a relay reads and writes
+#[derive(Clone, Copy, Default)]
+struct HalfOptions<'a> {
+    write_stall: Option<Duration>,
+    /// Gather bytes that are already readable into the same write
+    coalesce: bool,
+    /// Re-armed on the source socket after every read
+    quickack: Option<QuickAck>,
+    /// Receives a copy of every read
+    capture: Option<&'a Capture>,
+    /// Touched whenever bytes are read or written
+    activity: Option<&'a RelayActivity>,
+    /// Leave `dest` open when the source closes, so it can be reused
+    keep_dest_open: bool,
+}
+
+impl<'a> HalfOptions<'a> {
+    fn new(options: &RelayOptions, quickack: Option<QuickAck>) -> Self {
+        Self {
+            write_stall: options.write_stall,
+            coalesce: !options.latency_mode,
+            quickack,
+            capture: None,
+            activity: None,
+            keep_dest_open: false,
+        }
+    }
+
+    fn with_capture(mut self, capture: Option<&'a Capture>) -> Self {
+        self.capture = capture;
+        self
+    }
+
+    fn with_activity(mut self, activity: &'a RelayActivity) -> Self {
+        self.activity = Some(activity);
+        self
+    }
+
+    fn keeping_dest_open(mut self) -> Self {
+        self.keep_dest_open = true;
+        self
+    }
+}
 
 /// Zero-copy bidirectional data relay
 /// This structure efficiently forwards data between two streams without copying
+///
+/// The target side is whatever the outbound returned; socket options go to
+/// the socket underneath it, and only a plain socket can use io_uring.
 pub struct ZeroCopyRelay {
-    client_read: ReadHalf<tokio::net::TcpStream>,
-    client_write: WriteHalf<tokio::net::TcpStream>,
-    target_read: ReadHalf<tokio::net::TcpStream>,
-    target_write: WriteHalf<tokio::net::TcpStream>,
+    client: TcpStream,
+    target: ProxyStream,
+    options: RelayOptions,
+    tracker: Option<Arc<TrackedConnection>>,
+    capture: Option<Arc<Capture>>,
+    client_quickack: Option<QuickAck>,
+    target_quickack: Option<QuickAck>,
 }
 
 impl ZeroCopyRelay {
-    pub fn new(client_stream: tokio::net::TcpStream, target_stream: tokio::net::TcpStream) -> Self {
-        let (client_read, client_write) = split(client_stream);
-        let (target_read, target_write) = split(target_stream);
+    pub fn new(client_stream: TcpStream, target_stream: impl Into<ProxyStream>) -> Self {
+        Self::with_options(client_stream, target_stream, RelayOptions::default())
+    }
+
+    pub fn with_options(client_stream: TcpStream, target_stream: impl Into<ProxyStream>, options: RelayOptions) -> Self {
+        let target_stream = target_stream.into();
+        let target_socket = target_stream.tcp_stream();
+        if options.tls_fragment.is_some() {
+            // 分片依赖每段单独发出，不能让 Nagle 合并
+            if let Some(Err(e)) = target_socket.map(|socket| socket.set_nodelay(true)) {
+                log::debug!("Failed to set TCP_NODELAY for TLS fragmentation: {}", e);
+            }
+        }
+        let (mut client_quickack, mut target_quickack) = (None, None);
+        if options.latency_mode {
+            for stream in [Some(&client_stream), target_socket].into_iter().flatten() {
+                if let Err(e) = enable_nodelay(stream) {
+                    log::debug!("Failed to set TCP_NODELAY for latency mode: {}", e);
+                }
+            }
+            client_quickack = QuickAck::new(&client_stream);
+            target_quickack = target_socket.and_then(QuickAck::new);
+        }
 
         Self {
-            client_read,
-            client_write,
-            target_read,
-            target_write,
+            client: client_stream,
+            target: target_stream,
+            options,
+            tracker: None,
+            capture: None,
+            client_quickack,
+            target_quickack,
         }
     }
 
+    /// Report traffic to a registry entry and stop when it is killed
+    pub fn with_tracker(mut self, tracker: Arc<TrackedConnection>) -> Self {
+        self.tracker = Some(tracker);
+        self
+    }
+
+    /// Tee both directions into a traffic capture
+    pub fn with_capture(mut self, capture: Option<Arc<Capture>>) -> Self {
+        self.capture = capture;
+        self
+    }
+
     /// Start the zero-copy relay between client and target
-    pub async fn start(self) -> Result<()> {
-        // Create two futures for bidirectional data transfer
-        let client_to_target =
-            Self::relay_data(self.client_read, self.target_write, "client -> target");
-
-        let target_to_client =
-            Self::relay_data(self.target_read, self.client_write, "target -> client");
-
-        // Run both relays concurrently
-        // If either side closes, the relay stops
-        match try_join(client_to_target, target_to_client).await {
-            Ok((_, _)) => {
-                log::info!("Relay completed successfully");
+    ///
+    /// Once both directions are done, both writers are shut down and the
+    /// peers' FINs awaited (up to `close_timeout`) so no side is reset with
+    /// data still buffered. A stalled write is returned as an error.
+    pub async fn start(self) -> Result<RelayResult> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if self.options.backend == RelayBackend::Uring && self.kernel_copy_eligible() {
+            return self.start_uring().await;
+        }
+        #[cfg(target_os = "linux")]
+        if self.options.backend == RelayBackend::Splice && self.kernel_copy_eligible() {
+            return self.start_splice().await;
+        }
+        self.start_buffered().await
+    }
+
+    /// Whether the copy loops are plain byte shuffling that io_uring or
+    /// splice can take over
+    #[cfg(target_os = "linux")]
+    fn kernel_copy_eligible(&self) -> bool {
+        self.target.is_tcp() && self.capture.is_none() && self.options.tls_fragment.is_none() && !self.options.latency_mode
+    }
+
+    /// Copy on the io_uring runtime, then close in order here like the buffered loops
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    async fn start_uring(self) -> Result<RelayResult> {
+        use crate::uring_relay::UringRelay;
+
+        let Self { client, target, options, tracker, capture, .. } = self;
+        let Ok(target) = target.into_tcp() else {
+            unreachable!("kernel_copy_eligible only accepts a plain target socket")
+        };
+        match crate::uring_relay::relay(client, target, &options, tracker.clone()).await? {
+            UringRelay::Relayed { mut client, mut target, result } => {
+                let (mut client_read, mut client_write) = client.split();
+                let (mut target_read, mut target_write) = target.split();
+                let client = (&mut client_read, &mut client_write);
+                let target = (&mut target_read, &mut target_write);
+                Self::conclude(result, options.close_timeout, client, target).await
+            }
+            UringRelay::Killed => {
+                log::info!("Relay killed");
+                Ok(RelayResult::Aborted("killed".to_string()))
+            }
+            UringRelay::Idle => Ok(idle_timed_out(options.idle_timeout.unwrap_or_default())),
+            UringRelay::Declined { client, target } => {
+                log::debug!("io_uring runtime declined the relay, using the buffered loops");
+                let options = RelayOptions { backend: RelayBackend::Buffered, ..options };
+                let mut relay = Self::with_options(client, target, options).with_capture(capture);
+                relay.tracker = tracker;
+                relay.start_buffered().await
+            }
+        }
+    }
+
+    /// Splice both directions through kernel pipes, then close in order like
+    /// the buffered loops
+    #[cfg(target_os = "linux")]
+    async fn start_splice(self) -> Result<RelayResult> {
+        let Self { mut client, target, options, tracker, .. } = self;
+        let Ok(mut target) = target.into_tcp() else {
+            unreachable!("kernel_copy_eligible only accepts a plain target socket")
+        };
+        let (mut client_read, mut client_write) = client.split();
+        let (mut target_read, mut target_write) = target.split();
+        let tracker = tracker.as_deref();
+        let activity = RelayActivity::new();
+        let result = {
+            let client_to_target =
+                Self::splice_half(&mut client_read, &mut target_write, &options, tracker, RelayDirection::ClientToTarget, &activity);
+            let target_to_client =
+                Self::splice_half(&mut target_read, &mut client_write, &options, tracker, RelayDirection::TargetToClient, &activity);
+            let killed = async {
+                match tracker {
+                    Some(t) => t.killed().await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                result = try_join(client_to_target, target_to_client) => result.map(|_| ()),
+                _ = killed => {
+                    log::info!("Relay killed");
+                    return Ok(RelayResult::Aborted("killed".to_string()));
+                }
+                _ = activity.idle(options.idle_timeout) => {
+                    return Ok(idle_timed_out(options.idle_timeout.unwrap_or_default()));
+                }
+            }
+        };
+        let client = (&mut client_read, &mut client_write);
+        let target = (&mut target_read, &mut target_write);
+        Self::conclude(result, options.close_timeout, client, target).await
+    }
+
+    /// One spliced direction; sockets the kernel will not splice continue on
+    /// the buffered loop
+    #[cfg(target_os = "linux")]
+    async fn splice_half(
+        source: &mut tokio::net::tcp::ReadHalf<'_>,
+        dest: &mut tokio::net::tcp::WriteHalf<'_>,
+        options: &RelayOptions,
+        tracker: Option<&TrackedConnection>,
+        direction: RelayDirection,
+        activity: &RelayActivity,
+    ) -> Result<()> {
+        use crate::splice_relay::{splice_data, Spliced};
+
+        let half = HalfOptions::new(options, None).with_activity(activity);
+        let spliced =
+            splice_data(source.as_ref(), dest.as_ref(), options.buffer_size, half.write_stall, tracker, direction, activity).await?;
+        match spliced {
+            Spliced::Finished(_) => {
+                // 把EOF传给对端（半关闭），与缓冲循环一致
+                let _ = dest.shutdown().await;
                 Ok(())
             }
+            Spliced::Unsupported => {
+                let buffer = AdaptiveBuffer::new(*options, &RELAY_BUFFER_METER);
+                Self::relay_data(source, dest, buffer, tracker, direction, half, None).await
+            }
+        }
+    }
+
+    /// Like [`start`](Self::start), but when the client closes first the
+    /// target is left open and returned so another client can use it
+    ///
+    /// Only a plain target socket is returned; it is relayed with the buffered
+    /// loops. After the client closes, the response still on its way is relayed
+    /// until the target has been silent for [`POOL_DRAIN_QUIET`]; a target still
+    /// sending after `close_timeout`, or closing meanwhile, is closed instead.
+    pub async fn start_reusable(self) -> Result<(RelayResult, Option<ProxyStream>)> {
+        if !self.target.is_tcp() {
+            return Ok((self.start().await?, None));
+        }
+        let Self { client, target, options, tracker, capture, client_quickack, target_quickack } = self;
+        let Ok(target) = target.into_tcp() else {
+            unreachable!("checked to be a plain socket above")
+        };
+        let (mut client_read, mut client_write) = split(client);
+        let (mut target_read, mut target_write) = split(target);
+        let tracker = tracker.as_deref();
+        let capture = capture.as_deref();
+        let activity = RelayActivity::new();
+        let reusable = {
+            let client_to_target = Self::relay_data(
+                &mut client_read,
+                &mut target_write,
+                AdaptiveBuffer::new(options, &RELAY_BUFFER_METER),
+                tracker,
+                RelayDirection::ClientToTarget,
+                HalfOptions::new(&options, client_quickack)
+                    .with_capture(capture)
+                    .with_activity(&activity)
+                    .keeping_dest_open(),
+                options.tls_fragment.as_ref(),
+            );
+            let target_to_client = Self::relay_data(
+                &mut target_read,
+                &mut client_write,
+                AdaptiveBuffer::new(options, &RELAY_BUFFER_METER),
+                tracker,
+                RelayDirection::TargetToClient,
+                HalfOptions::new(&options, target_quickack).with_capture(capture).with_activity(&activity),
+                None,
+            );
+            let killed = async {
+                match tracker {
+                    Some(t) => t.killed().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::pin!(client_to_target, target_to_client, killed);
+
+            // 先结束的方向：true 为客户端
+            let (client_first, result) = tokio::select! {
+                result = &mut client_to_target => (true, result),
+                result = &mut target_to_client => (false, result),
+                _ = &mut killed => {
+                    log::info!("Relay killed");
+                    return Ok((RelayResult::Aborted("killed".to_string()), None));
+                }
+                _ = activity.idle(options.idle_timeout) => {
+                    return Ok((idle_timed_out(options.idle_timeout.unwrap_or_default()), None));
+                }
+            };
+            match (client_first, result) {
+                (_, Err(e)) => Err(e),
+                // 客户端先发完：继续转发响应，目标安静下来才交还，最多等 close_timeout
+                (true, Ok(())) => tokio::select! {
+                    // 目标随后关闭，连接不能再用
+                    result = &mut target_to_client => result.map(|()| false),
+                    _ = activity.drained(POOL_DRAIN_QUIET) => Ok(true),
+                    _ = tokio::time::sleep(options.close_timeout) => {
+                        log::debug!("Relay close: target still sending after {:?}, not reusing it", options.close_timeout);
+                        Ok(false)
+                    }
+                    _ = &mut killed => {
+                        log::info!("Relay killed");
+                        return Ok((RelayResult::Aborted("killed".to_string()), None));
+                    }
+                },
+                // 目标先关闭，连接不能再用：等客户端发完后照常关闭两端
+                (false, Ok(())) => tokio::select! {
+                    result = &mut client_to_target => result.map(|()| false),
+                    _ = &mut killed => {
+                        log::info!("Relay killed");
+                        return Ok((RelayResult::Aborted("killed".to_string()), None));
+                    }
+                    _ = activity.idle(options.idle_timeout) => {
+                        return Ok((idle_timed_out(options.idle_timeout.unwrap_or_default()), None));
+                    }
+                },
+            }
+        };
+        if let Ok(true) = reusable {
+            // 只关闭客户端一侧，目标连接原样交还
+            let closed = tokio::time::timeout(options.close_timeout, close_in_order(&mut client_write, &mut client_read)).await;
+            if closed.is_err() {
+                log::debug!("Relay close: client FIN not seen within {:?}", options.close_timeout);
+            }
+            log::info!("Relay completed, client closed first, target left open");
+            return Ok((RelayResult::Completed, Some(target_read.unsplit(target_write).into())));
+        }
+        let client = (&mut client_read, &mut client_write);
+        let target = (&mut target_read, &mut target_write);
+        let result = Self::conclude(reusable.map(|_| ()), options.close_timeout, client, target).await?;
+        Ok((result, None))
+    }
+
+    async fn start_buffered(self) -> Result<RelayResult> {
+        let Self { client, target, options, tracker, capture, client_quickack, target_quickack } = self;
+        let quickacks = (client_quickack, target_quickack);
+        // 明文套接字单独实例化一份复制循环，热路径上不经过 ProxyStream 的分派
+        match target.into_tcp() {
+            Ok(target) => Self::relay_buffered(client, target, options, tracker, capture, quickacks).await,
+            Err(target) => Self::relay_buffered(client, target, options, tracker, capture, quickacks).await,
+        }
+    }
+
+    async fn relay_buffered<T>(
+        client: TcpStream,
+        target: T,
+        options: RelayOptions,
+        tracker: Option<Arc<TrackedConnection>>,
+        capture: Option<Arc<Capture>>,
+        (client_quickack, target_quickack): (Option<QuickAck>, Option<QuickAck>),
+    ) -> Result<RelayResult>
+    where
+        T: AsyncRead + AsyncWrite,
+    {
+        let (mut client_read, mut client_write) = split(client);
+        let (mut target_read, mut target_write) = split(target);
+        let tracker = tracker.as_deref();
+        let capture = capture.as_deref();
+        let activity = RelayActivity::new();
+        let result = {
+            // Create two futures for bidirectional data transfer
+            let client_to_target = Self::relay_data(
+                &mut client_read,
+                &mut target_write,
+                AdaptiveBuffer::new(options, &RELAY_BUFFER_METER),
+                tracker,
+                RelayDirection::ClientToTarget,
+                HalfOptions::new(&options, client_quickack).with_capture(capture).with_activity(&activity),
+                options.tls_fragment.as_ref(),
+            );
+
+            let target_to_client = Self::relay_data(
+                &mut target_read,
+                &mut client_write,
+                AdaptiveBuffer::new(options, &RELAY_BUFFER_METER),
+                tracker,
+                RelayDirection::TargetToClient,
+                HalfOptions::new(&options, target_quickack).with_capture(capture).with_activity(&activity),
+                None,
+            );
+
+            let killed = async {
+                match tracker {
+                    Some(t) => t.killed().await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            // Run both relays concurrently
+            // A direction that reaches EOF half-closes its peer and the other
+            // keeps flowing; only an error stops the other direction
+            tokio::select! {
+                result = try_join(client_to_target, target_to_client) => result.map(|_| ()),
+                _ = killed => {
+                    log::info!("Relay killed");
+                    return Ok(RelayResult::Aborted("killed".to_string()));
+                }
+                _ = activity.idle(options.idle_timeout) => {
+                    return Ok(idle_timed_out(options.idle_timeout.unwrap_or_default()));
+                }
+            }
+        };
+        let client = (&mut client_read, &mut client_write);
+        let target = (&mut target_read, &mut target_write);
+        Self::conclude(result, options.close_timeout, client, target).await
+    }
+
+    /// Count how the copy loops ended and close both sides in order
+    async fn conclude<CR, CW, TR, TW>(
+        result: Result<()>,
+        close_timeout: Duration,
+        (client_read, client_write): (&mut CR, &mut CW),
+        (target_read, target_write): (&mut TR, &mut TW),
+    ) -> Result<RelayResult>
+    where
+        CR: AsyncRead + Unpin,
+        CW: AsyncWrite + Unpin,
+        TR: AsyncRead + Unpin,
+        TW: AsyncWrite + Unpin,
+    {
+        let outcome = match result {
+            Ok(()) => {
+                log::info!("Relay completed successfully");
+                RelayResult::Completed
+            }
+            Err(e @ crate::error::ProxyError::WriteStalled(_)) => {
+                RELAY_WRITE_STALLS.fetch_add(1, Ordering::Relaxed);
+                log::warn!("Relay aborted: {}", e);
+                return Err(e);
+            }
             Err(e) => {
+                RELAY_PEER_ABORTS.fetch_add(1, Ordering::Relaxed);
                 log::debug!("Relay ended: {}", e);
-                Ok(())
+                RelayResult::PeerAborted(e.to_string())
             }
+        };
+
+        // 有序关闭：两端都发 FIN，再读到对端 FIN 为止；带着未读数据关闭会触发 RST
+        let closed = tokio::time::timeout(
+            close_timeout,
+            futures::future::join(close_in_order(client_write, client_read), close_in_order(target_write, target_read)),
+        )
+        .await;
+        if closed.is_err() {
+            log::debug!("Relay close: peer FIN not seen within {:?}", close_timeout);
         }
+        Ok(outcome)
     }
 
     /// Relay data from source to destination with zero-copy optimization
-    async fn relay_data<R, W>(mut source: R, mut dest: W, direction: &str) -> Result<()>
+    async fn relay_data<R, W>(
+        mut source: R,
+        mut dest: W,
+        mut buffer: AdaptiveBuffer<'_>,
+        tracker: Option<&TrackedConnection>,
+        direction: RelayDirection,
+        half: HalfOptions<'_>,
+        mut tls_fragment: Option<&TlsFragmentConfig>,
+    ) -> Result<()>
     where
         R: AsyncRead + Unpin,
         W: AsyncWrite + Unpin,
     {
-        // Use larger buffer for better performance
-        let mut buffer = BytesMut::with_capacity(64 * 1024); // 64KB buffer
         let mut total_bytes = 0u64;
 
         loop {
             // Read data from source with zero-copy optimization
-            let bytes_read = source.read_buf(&mut buffer).await?;
+            let mut bytes_read = source.read_buf(&mut buffer.buffer).await?;
+            if let Some(quickack) = &half.quickack {
+                quickack.rearm();
+            }
             if bytes_read == 0 {
-                log::debug!("{}: source closed, total bytes: {}", direction, total_bytes);
+                log::debug!(
+                    "{}: source closed, total bytes: {}, high water: {}",
+                    direction, total_bytes, buffer.high_water()
+                );
+                // 把EOF传给对端（半关闭），否则对端等不到结束，另一方向永远不会完成
+                if !half.keep_dest_open {
+                    let _ = dest.shutdown().await;
+                }
                 break;
             }
 
+            // 合并已经可读的数据，减少写次数；不等待新数据，首个待分片的包除外
+            let mut source_closed = false;
+            if half.coalesce && tls_fragment.is_none() {
+                while buffer.buffer.len() < buffer.size() {
+                    match source.read_buf(&mut buffer.buffer).now_or_never() {
+                        Some(Ok(0)) => {
+                            source_closed = true;
+                            break;
+                        }
+                        Some(Ok(n)) => bytes_read += n,
+                        Some(Err(e)) => return Err(e.into()),
+                        None => break,
+                    }
+                }
+            }
+
             total_bytes += bytes_read as u64;
+            if let Some(activity) = half.activity {
+                activity.touch();
+            }
+            // 记录的是从源端读到的原始字节，分片等改写之前
+            if let Some(capture) = half.capture {
+                capture.record(direction.into(), &buffer.buffer);
+            }
+            if let Some(tracker) = tracker {
+                match direction {
+                    RelayDirection::ClientToTarget => tracker.add_upload(bytes_read as u64),
+                    RelayDirection::TargetToClient => tracker.add_download(bytes_read as u64),
+                }
+            }
+
+            // 只处理第一个包：是TLS握手就分片写出，否则原样转发
+            if let Some(config) = tls_fragment.take() {
+                if is_tls_handshake(&buffer.buffer) {
+                    let segments = write_fragmented(&mut dest, &buffer.buffer, config).await?;
+                    log::debug!("{}: split {} byte TLS handshake into {} segments", direction, bytes_read, segments);
+                    buffer.buffer.clear();
+                }
+            }
+            if let Some(activity) = half.activity {
+                activity.queue(buffer.buffer.len());
+            }
 
             // Write data to destination with zero-copy optimization
-            while buffer.has_remaining() {
-                let bytes_written = dest.write_buf(&mut buffer).await?;
+            while buffer.buffer.has_remaining() {
+                let write = dest.write_buf(&mut buffer.buffer);
+                let bytes_written = match half.write_stall {
+                    Some(limit) => tokio::time::timeout(limit, write).await.map_err(|_| {
+                        crate::error::ProxyError::WriteStalled(format!(
+                            "{}: write blocked for {:?}, total bytes: {}",
+                            direction, limit, total_bytes
+                        ))
+                    })??,
+                    None => write.await?,
+                };
                 if bytes_written == 0 {
                     log::debug!("{}: destination closed, total bytes: {}", direction, total_bytes);
                     return Ok(());
                 }
+                // 慢速接收端一点点取走大块数据也算活动
+                if let Some(activity) = half.activity {
+                    activity.sent(bytes_written);
+                }
             }
 
             // Clear the buffer for next iteration
-            buffer.clear();
+            buffer.buffer.clear();
+            buffer.record_read(bytes_read);
+            if source_closed {
+                log::debug!("{}: source closed, total bytes: {}", direction, total_bytes);
+                if !half.keep_dest_open {
+                    let _ = dest.shutdown().await;
+                }
+                break;
+            }
         }
 
         log::debug!("{}: relay completed, total bytes: {}", direction, total_bytes);
@@ -88,6 +984,52 @@ impl ZeroCopyRelay {
     }
 }
 
+/// Shut down `write` and discard whatever `read` still gets until the peer's FIN
+async fn close_in_order<R, W>(write: &mut W, read: &mut R)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let _ = write.shutdown().await;
+    let mut discard = [0u8; 4096];
+    while let Ok(n) = read.read(&mut discard).await {
+        if n == 0 {
+            break;
+        }
+    }
+}
+
+/// Relay one direction from `source` to `dest` with the relay's buffering
+///
+/// Useful for stream types other than `TcpStream`, e.g. in-memory streams in
+/// benchmarks.
+pub async fn relay_one_way<R, W>(source: R, dest: W, options: RelayOptions) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    relay_one_way_with_pool(source, dest, options, get_global_buffer_pool()).await
+}
+
+/// Same as [`relay_one_way`], drawing the buffer from `pool`
+pub async fn relay_one_way_with_pool<R, W>(source: R, dest: W, options: RelayOptions, pool: &BufferPool) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let buffer = AdaptiveBuffer::with_pool(options, &RELAY_BUFFER_METER, pool);
+    ZeroCopyRelay::relay_data(
+        source,
+        dest,
+        buffer,
+        None,
+        RelayDirection::ClientToTarget,
+        HalfOptions::new(&options, None),
+        options.tls_fragment.as_ref(),
+    )
+    .await
+}
+
 /// High-performance circular buffer for zero-copy operations
 pub struct ZeroCopyBuffer {
     data: Vec<u8>,
@@ -209,33 +1151,747 @@ pub struct OptimizedCopier;
 
 impl OptimizedCopier {
     /// Copy data from source to destination with system-level optimizations
+    ///
+    /// Uses the relay buffer settings of the default configuration; see
+    /// [`copy_with_options`](Self::copy_with_options) to follow
+    /// `performance.buffer_size`.
     pub async fn copy<R, W>(source: &mut R, dest: &mut W) -> Result<u64>
     where
         R: AsyncRead + Unpin,
         W: AsyncWrite + Unpin,
     {
-        let mut buffer = BytesMut::with_capacity(8192);
+        Self::copy_with_options(source, dest, RelayOptions::default()).await
+    }
+
+    /// Copy with the relay's buffering: up to `options.buffer_size` bytes,
+    /// adaptive when enabled, drawn from and returned to the global buffer pool
+    pub async fn copy_with_options<R, W>(source: &mut R, dest: &mut W, options: RelayOptions) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut buffer = AdaptiveBuffer::new(options, &RELAY_BUFFER_METER);
         let mut total_copied = 0u64;
 
         loop {
-            let bytes_read = source.read_buf(&mut buffer).await?;
+            let bytes_read = source.read_buf(&mut buffer.buffer).await?;
             if bytes_read == 0 {
                 break;
             }
 
-            let mut remaining = buffer.len();
-            while remaining > 0 {
-                let bytes_written = dest.write_buf(&mut buffer).await?;
+            while buffer.buffer.has_remaining() {
+                let bytes_written = dest.write_buf(&mut buffer.buffer).await?;
                 if bytes_written == 0 {
                     return Err(crate::error::ProxyError::Io(
                         std::io::Error::new(std::io::ErrorKind::WriteZero, "Write zero")
                     ));
                 }
-                remaining -= bytes_written;
                 total_copied += bytes_written as u64;
             }
+            // 清空后 read_buf 从分配起点重新填充，不会重新分配
+            buffer.buffer.clear();
+            buffer.record_read(bytes_read);
         }
 
         Ok(total_copied)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    fn options(buffer_size: usize) -> RelayOptions {
+        RelayOptions {
+            buffer_size,
+            adaptive_buffers: true,
+            write_stall: None,
+            idle_timeout: None,
+            tls_fragment: None,
+            close_timeout: RELAY_CLOSE_TIMEOUT,
+            latency_mode: false,
+            backend: RelayBackend::Buffered,
+        }
+    }
+
+    /// Relay options for each backend this build and kernel can run
+    fn backend_options() -> Vec<RelayOptions> {
+        available_relay_backends()
+            .into_iter()
+            .map(|backend| RelayOptions { backend, ..RelayOptions::default() })
+            .collect()
+    }
+
+    #[test]
+    fn test_buffer_grows_after_consecutive_full_reads() {
+        let meter = BufferMeter::new();
+        let mut buffer = AdaptiveBuffer::new(options(64 * 1024), &meter);
+        assert_eq!(buffer.size(), ADAPTIVE_MIN_BUFFER_SIZE);
+
+        for _ in 0..ADAPTIVE_GROW_AFTER_FULL_READS - 1 {
+            buffer.record_read(buffer.size());
+        }
+        assert_eq!(buffer.size(), ADAPTIVE_MIN_BUFFER_SIZE);
+
+        buffer.record_read(buffer.size());
+        assert_eq!(buffer.size(), ADAPTIVE_MIN_BUFFER_SIZE * 2);
+    }
+
+    #[test]
+    fn test_buffer_shrinks_after_idle_reads() {
+        let meter = BufferMeter::new();
+        let mut buffer = AdaptiveBuffer::new(options(64 * 1024), &meter);
+        while buffer.size() < 32 * 1024 {
+            buffer.record_read(buffer.size());
+        }
+
+        let small = buffer.size() / ADAPTIVE_LOW_UTILIZATION_DIVISOR - 1;
+        for _ in 0..ADAPTIVE_SHRINK_AFTER_IDLE_READS - 1 {
+            buffer.record_read(small);
+        }
+        assert_eq!(buffer.size(), 32 * 1024);
+
+        buffer.record_read(small);
+        assert_eq!(buffer.size(), 16 * 1024);
+        assert_eq!(buffer.high_water(), 16 * 1024);
+    }
+
+    #[test]
+    fn test_fixed_buffers_when_adaptive_disabled() {
+        let meter = BufferMeter::new();
+        let fixed = RelayOptions {
+            buffer_size: 64 * 1024,
+            adaptive_buffers: false,
+            write_stall: None,
+            idle_timeout: None,
+            tls_fragment: None,
+            close_timeout: RELAY_CLOSE_TIMEOUT,
+            latency_mode: false,
+            backend: RelayBackend::Buffered,
+        };
+        let mut buffer = AdaptiveBuffer::new(fixed, &meter);
+        assert_eq!(buffer.size(), 64 * 1024);
+
+        for _ in 0..ADAPTIVE_SHRINK_AFTER_IDLE_READS * 2 {
+            buffer.record_read(1);
+        }
+        assert_eq!(buffer.size(), 64 * 1024);
+    }
+
+    #[test]
+    fn test_memory_accounting_matches_live_buffers() {
+        let meter = BufferMeter::new();
+        let mut first = AdaptiveBuffer::new(options(64 * 1024), &meter);
+        let second = AdaptiveBuffer::new(options(64 * 1024), &meter);
+        for _ in 0..ADAPTIVE_GROW_AFTER_FULL_READS * 3 {
+            first.record_read(first.size());
+        }
+
+        assert_eq!(meter.buffers(), 2);
+        assert_eq!(meter.bytes(), first.size() + second.size());
+
+        drop(first);
+        assert_eq!(meter.bytes(), second.size());
+        drop(second);
+        assert_eq!(meter.bytes(), 0);
+        assert_eq!(meter.buffers(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_trickle_connection_stays_at_minimum() {
+        let meter = BufferMeter::new();
+        let (mut writer, source) = duplex(1024);
+        let (dest, mut sink) = duplex(1024);
+
+        let relay = async {
+            let buffer = AdaptiveBuffer::new(options(64 * 1024), &meter);
+            ZeroCopyRelay::relay_data(source, dest, buffer, None, RelayDirection::ClientToTarget, HalfOptions::default(), None)
+                .await
+                .unwrap();
+        };
+        let feed = async {
+            for _ in 0..50 {
+                writer.write_all(b"ping").await.unwrap();
+                let mut reply = [0u8; 4];
+                sink.read_exact(&mut reply).await.unwrap();
+                assert_eq!(meter.bytes(), ADAPTIVE_MIN_BUFFER_SIZE);
+            }
+            drop(writer);
+        };
+        tokio::join!(relay, feed);
+        assert_eq!(meter.bytes(), 0);
+    }
+
+    /// Writer that accepts `capacity` bytes and then blocks forever
+    struct StallingWriter {
+        capacity: usize,
+    }
+
+    impl AsyncWrite for StallingWriter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<IoResult<usize>> {
+            if self.capacity == 0 {
+                return std::task::Poll::Pending;
+            }
+            let n = buf.len().min(self.capacity);
+            self.capacity -= n;
+            std::task::Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<IoResult<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<IoResult<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_write_stall_detected_at_threshold() {
+        let meter = BufferMeter::new();
+        let (mut writer, source) = duplex(1024);
+        writer.write_all(&[0u8; 100]).await.unwrap();
+
+        let relay = tokio::spawn(async move {
+            let buffer = AdaptiveBuffer::new(options(64 * 1024), &meter);
+            ZeroCopyRelay::relay_data(
+                source,
+                StallingWriter { capacity: 40 },
+                buffer,
+                None,
+                RelayDirection::TargetToClient,
+                HalfOptions { write_stall: Some(Duration::from_secs(10)), ..HalfOptions::default() },
+                None,
+            )
+            .await
+        });
+
+        tokio::time::sleep(Duration::from_millis(9_900)).await;
+        assert!(!relay.is_finished());
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let err = relay.await.unwrap().unwrap_err();
+        assert!(matches!(err, crate::error::ProxyError::WriteStalled(_)), "{}", err);
+        drop(writer);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_transfer_grows_to_cap() {
+        let meter = BufferMeter::new();
+        let cap = 32 * 1024;
+        let (mut writer, source) = duplex(cap * 4);
+        let (dest, mut sink) = duplex(cap * 4);
+
+        let relay = async {
+            let mut buffer = AdaptiveBuffer::new(options(cap), &meter);
+            let (mut source, mut dest) = (source, dest);
+            // Drive the relay loop by hand so the final size can be observed
+            loop {
+                let n = source.read_buf(&mut buffer.buffer).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                dest.write_all_buf(&mut buffer.buffer).await.unwrap();
+                buffer.buffer.clear();
+                buffer.record_read(n);
+            }
+            buffer.size()
+        };
+        let feed = async {
+            let chunk = vec![0xAB; cap];
+            for _ in 0..32 {
+                writer.write_all(&chunk).await.unwrap();
+            }
+            drop(writer);
+        };
+        let drain = async {
+            let mut received = Vec::new();
+            sink.read_to_end(&mut received).await.unwrap();
+            received.len()
+        };
+
+        let (final_size, _, received) = tokio::join!(relay, feed, drain);
+        assert_eq!(final_size, cap);
+        assert_eq!(received, cap * 32);
+    }
+
+    #[tokio::test]
+    async fn test_relays_reuse_pooled_buffers() {
+        let pool = BufferPool::new(16 * 1024, 8);
+        for round in 0..3 {
+            let (mut writer, source) = duplex(64 * 1024);
+            let (dest, mut sink) = duplex(64 * 1024);
+            let payload: Vec<u8> = (0..40_000u32).map(|i| (i * 7 + round) as u8).collect();
+            writer.write_all(&payload).await.unwrap();
+            drop(writer);
+
+            relay_one_way_with_pool(source, dest, options(16 * 1024), &pool).await.unwrap();
+            let mut received = Vec::new();
+            sink.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, payload, "round {}", round);
+        }
+
+        let stats = pool.stats();
+        assert_eq!(stats.outstanding, 0);
+        assert!(stats.pooled <= 8);
+        // 第一轮按尺寸逐级分配，之后各级都能命中
+        assert!(stats.hits >= 2 * stats.misses, "{:?}", stats);
+    }
+
+    #[tokio::test]
+    async fn test_copier_follows_configured_buffer_size() {
+        let (mut writer, mut source) = duplex(128 * 1024);
+        let (mut dest, mut sink) = duplex(128 * 1024);
+        let payload: Vec<u8> = (0..100_000u32).map(|i| (i % 241) as u8).collect();
+        writer.write_all(&payload).await.unwrap();
+        drop(writer);
+
+        let options = RelayOptions { adaptive_buffers: false, ..options(8 * 1024) };
+        let copied = OptimizedCopier::copy_with_options(&mut source, &mut dest, options).await.unwrap();
+        drop(dest);
+        assert_eq!(copied, payload.len() as u64);
+        let mut received = Vec::new();
+        sink.read_to_end(&mut received).await.unwrap();
+        assert!(received == payload);
+    }
+
+    /// Relay `payload` from a client through a TLS-fragmenting relay and
+    /// return the reads the target saw
+    async fn relay_to_recording_target(payload: &[u8], fragment: TlsFragmentConfig) -> Vec<Vec<u8>> {
+        use tokio::net::{TcpListener, TcpStream};
+
+        let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+        let target = tokio::spawn(async move {
+            let (mut stream, _) = target_listener.accept().await.unwrap();
+            let mut reads = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    return reads;
+                }
+                reads.push(buf[..n].to_vec());
+            }
+        });
+
+        let client_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(client_listener.local_addr().unwrap()).await.unwrap();
+        let (client_side, _) = client_listener.accept().await.unwrap();
+        let target_side = TcpStream::connect(target_addr).await.unwrap();
+
+        let options = RelayOptions { tls_fragment: Some(fragment), ..RelayOptions::default() };
+        let relay = tokio::spawn(ZeroCopyRelay::with_options(client_side, target_side, options).start());
+        client.write_all(payload).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let reads = target.await.unwrap();
+        drop(client);
+        relay.await.unwrap().unwrap();
+        reads
+    }
+
+    #[tokio::test]
+    async fn test_client_hello_fragmented_within_bounds() {
+        // 记录头 + 伪造的ClientHello正文
+        let mut hello = vec![0x16, 0x03, 0x01, 0x00, 0xc0];
+        hello.extend((0..0xc0).map(|i| i as u8));
+        let fragment = TlsFragmentConfig { enabled: true, size_range: [16, 40], delay_ms_range: [5, 10] };
+
+        let reads = relay_to_recording_target(&hello, fragment).await;
+        assert_eq!(reads.concat(), hello);
+        assert!(reads.len() > 1, "{:?}", reads.iter().map(Vec::len).collect::<Vec<_>>());
+        assert!(reads.iter().all(|r| r.len() <= 40), "{:?}", reads.iter().map(Vec::len).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_non_tls_traffic_untouched() {
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(8);
+        let fragment = TlsFragmentConfig { enabled: true, size_range: [1, 2], delay_ms_range: [5, 10] };
+
+        let reads = relay_to_recording_target(&request, fragment).await;
+        assert_eq!(reads.concat(), request);
+        // 非TLS数据不分片：不会出现每段至多2字节的读
+        assert!(reads.iter().any(|r| r.len() > 2));
+    }
+
+    async fn tcp_pair(listener: &tokio::net::TcpListener) -> (tokio::net::TcpStream, tokio::net::TcpStream) {
+        let outer = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (inner, _) = listener.accept().await.unwrap();
+        (outer, inner)
+    }
+
+    #[tokio::test]
+    async fn test_orderly_close_delivers_buffered_bytes_to_slow_reader() {
+        for options in backend_options() {
+            orderly_close_delivers_buffered_bytes(options).await;
+        }
+    }
+
+    async fn orderly_close_delivers_buffered_bytes(options: RelayOptions) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, client_side) = tcp_pair(&listener).await;
+        let (mut target, target_side) = tcp_pair(&listener).await;
+        let relay = tokio::spawn(ZeroCopyRelay::with_options(client_side, target_side, options).start());
+
+        // 目标写完立即关闭，大部分数据还在缓冲区里等慢速客户端读取
+        let payload: Vec<u8> = (0..4 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let expected = payload.clone();
+        let writer = tokio::spawn(async move {
+            target.write_all(&payload).await.unwrap();
+            drop(target);
+        });
+        client.shutdown().await.unwrap();
+
+        let mut received = Vec::new();
+        let mut chunk = vec![0u8; 64 * 1024];
+        loop {
+            let n = client.read(&mut chunk).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            received.extend_from_slice(&chunk[..n]);
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        writer.await.unwrap();
+        assert_eq!(received.len(), expected.len(), "{}", options.backend);
+        assert!(received == expected);
+        assert_eq!(relay.await.unwrap().unwrap(), RelayResult::Completed);
+    }
+
+    #[tokio::test]
+    async fn test_client_half_close_gets_full_response() {
+        for options in backend_options() {
+            client_half_close_gets_full_response(options).await;
+        }
+    }
+
+    async fn client_half_close_gets_full_response(options: RelayOptions) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, client_side) = tcp_pair(&listener).await;
+        let (mut target, target_side) = tcp_pair(&listener).await;
+        let relay = tokio::spawn(ZeroCopyRelay::with_options(client_side, target_side, options).start());
+
+        // 服务端读完整个请求（直到EOF）才开始回应
+        let response: Vec<u8> = (0..512 * 1024u32).map(|i| (i % 253) as u8).collect();
+        let expected = response.clone();
+        let server = tokio::spawn(async move {
+            let mut request = Vec::new();
+            target.read_to_end(&mut request).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            target.write_all(&response).await.unwrap();
+            target.shutdown().await.unwrap();
+            request
+        });
+
+        client.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(server.await.unwrap(), b"GET / HTTP/1.0\r\n\r\n", "{}", options.backend);
+        assert_eq!(received.len(), expected.len(), "{}", options.backend);
+        assert!(received == expected);
+        assert_eq!(relay.await.unwrap().unwrap(), RelayResult::Completed);
+    }
+
+    #[tokio::test]
+    async fn test_reusable_relay_drains_response_before_pooling() {
+        use crate::connection_pool::{ConnectionPool, PoolKey, PooledConnection};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, client_side) = tcp_pair(&listener).await;
+        let (mut target, target_side) = tcp_pair(&listener).await;
+        let relay = tokio::spawn(ZeroCopyRelay::with_options(client_side, target_side, options(16 * 1024)).start_reusable());
+
+        // 客户端在响应发到一半时半关闭，后一半稍后才到
+        let response: Vec<u8> = (0..256 * 1024u32).map(|i| (i % 241) as u8).collect();
+        let expected = response.clone();
+        let server = tokio::spawn(async move {
+            let mut request = [0u8; 4];
+            target.read_exact(&mut request).await.unwrap();
+            let (first, rest) = response.split_at(response.len() / 2);
+            target.write_all(first).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            target.write_all(rest).await.unwrap();
+            target
+        });
+        client.write_all(b"ping").await.unwrap();
+        let mut first = vec![0u8; 1024];
+        client.read_exact(&mut first).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = first;
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), expected.len());
+        assert!(received == expected);
+
+        let (result, target_stream) = relay.await.unwrap().unwrap();
+        assert_eq!(result, RelayResult::Completed);
+        let mut target = server.await.unwrap();
+
+        // 交还后的连接没有残留字节，下一个客户端拿到的是干净的连接
+        let pool = ConnectionPool::new(10, 100, Duration::from_secs(5), Duration::from_secs(30));
+        let key = PoolKey::new("direct", target.local_addr().unwrap().into());
+        pool.return_connection(PooledConnection::new(target_stream.expect("target pooled"), key.clone())).await;
+        let mut pooled = pool.checkout(&key).await.expect("clean connection pooled");
+        target.write_all(b"next").await.unwrap();
+        let mut next = [0u8; 4];
+        pooled.stream_mut().read_exact(&mut next).await.unwrap();
+        assert_eq!(&next, b"next");
+    }
+
+    #[tokio::test]
+    async fn test_reusable_relay_closes_target_still_sending() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, client_side) = tcp_pair(&listener).await;
+        let (mut target, target_side) = tcp_pair(&listener).await;
+        let options = RelayOptions { close_timeout: Duration::from_millis(300), ..options(16 * 1024) };
+        let relay = tokio::spawn(ZeroCopyRelay::with_options(client_side, target_side, options).start_reusable());
+
+        // 目标一直在发，过了 close_timeout 也没安静下来
+        let server = tokio::spawn(async move {
+            while target.write_all(b"tick").await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+        client.shutdown().await.unwrap();
+        let mut sink = Vec::new();
+        client.read_to_end(&mut sink).await.unwrap();
+
+        let (result, target_stream) = relay.await.unwrap().unwrap();
+        assert_eq!(result, RelayResult::Completed);
+        assert!(target_stream.is_none());
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_peer_reset_reported_and_other_side_closed() {
+        for options in backend_options() {
+            peer_reset_reported(options).await;
+        }
+    }
+
+    async fn peer_reset_reported(options: RelayOptions) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, client_side) = tcp_pair(&listener).await;
+        let (target, target_side) = tcp_pair(&listener).await;
+        let relay = tokio::spawn(ZeroCopyRelay::with_options(client_side, target_side, options).start());
+
+        crate::traffic_mark::apply_linger(&target, crate::traffic_mark::LingerPolicy::Reset).unwrap();
+        drop(target);
+
+        // 客户端收到有序的 FIN 而不是 RST
+        let mut buf = [0u8; 16];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        drop(client);
+        let result = relay.await.unwrap().unwrap();
+        assert!(matches!(result, RelayResult::PeerAborted(_)), "{}: {:?}", options.backend, result);
+    }
+
+    #[tokio::test]
+    async fn test_byte_counters_and_kill_on_every_backend() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let registry = crate::connection_registry::ConnectionRegistry::new();
+        for options in backend_options() {
+            let (mut client, client_side) = tcp_pair(&listener).await;
+            let (mut target, target_side) = tcp_pair(&listener).await;
+            let guard = registry.register(client.local_addr().unwrap());
+            let relay = ZeroCopyRelay::with_options(client_side, target_side, options).with_tracker(guard.connection().clone());
+            let relay = tokio::spawn(relay.start());
+
+            let mut buf = vec![0u8; 64 * 1024];
+            client.write_all(&[1u8; 3000]).await.unwrap();
+            target.read_exact(&mut buf[..3000]).await.unwrap();
+            target.write_all(&[2u8; 70_000]).await.unwrap();
+            client.read_exact(&mut vec![0u8; 70_000]).await.unwrap();
+            let snapshot = guard.connection().snapshot();
+            assert_eq!((snapshot.upload, snapshot.download), (3000, 70_000), "{}", options.backend);
+
+            // 连接被杀掉时立即结束，两端都看到连接关闭
+            guard.kill();
+            assert_eq!(relay.await.unwrap().unwrap(), RelayResult::Aborted("killed".to_string()));
+            assert!(matches!(client.read(&mut buf).await, Ok(0) | Err(_)), "{}", options.backend);
+            assert!(matches!(target.read(&mut buf).await, Ok(0) | Err(_)), "{}", options.backend);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_stall_on_every_backend() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        for options in backend_options() {
+            let options = RelayOptions { write_stall: Some(Duration::from_millis(300)), ..options };
+            let (client, client_side) = tcp_pair(&listener).await;
+            let (_target, target_side) = tcp_pair(&listener).await;
+            let relay = tokio::spawn(ZeroCopyRelay::with_options(client_side, target_side, options).start());
+
+            // 目标从不读取：写满内核缓冲区后写阻塞
+            let (_client_read, mut client_write) = client.into_split();
+            let feed = tokio::spawn(async move {
+                let chunk = vec![0u8; 64 * 1024];
+                while client_write.write_all(&chunk).await.is_ok() {}
+            });
+            let err = tokio::time::timeout(Duration::from_secs(10), relay).await.unwrap().unwrap().unwrap_err();
+            assert!(matches!(err, crate::error::ProxyError::WriteStalled(_)), "{}: {}", options.backend, err);
+            assert!(err.to_string().contains("client -> target: write blocked for 300ms"), "{}", err);
+            feed.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_on_every_backend() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        for options in backend_options() {
+            let options = RelayOptions { idle_timeout: Some(Duration::from_millis(300)), ..options };
+            let (mut client, client_side) = tcp_pair(&listener).await;
+            let (mut target, target_side) = tcp_pair(&listener).await;
+            let relay = tokio::spawn(ZeroCopyRelay::with_options(client_side, target_side, options).start());
+
+            // 持续有流量的连接不会被断开，哪怕只有一个方向
+            let mut buf = [0u8; 16];
+            for _ in 0..6 {
+                tokio::time::sleep(Duration::from_millis(150)).await;
+                client.write_all(b"ping").await.unwrap();
+                target.read_exact(&mut buf[..4]).await.unwrap();
+            }
+            assert!(!relay.is_finished(), "{}", options.backend);
+
+            let result = tokio::time::timeout(Duration::from_secs(5), relay).await.unwrap().unwrap().unwrap();
+            assert_eq!(result, RelayResult::IdleTimeout(Duration::from_millis(300)), "{}", options.backend);
+            assert!(matches!(client.read(&mut buf).await, Ok(0) | Err(_)), "{}", options.backend);
+            assert!(matches!(target.read(&mut buf).await, Ok(0) | Err(_)), "{}", options.backend);
+        }
+        assert!(relay_stats().idle_timeouts >= backend_options().len() as u64);
+
+        let mut config = PerformanceConfig::default();
+        assert_eq!(RelayOptions::from_config(&config).idle_timeout, None);
+        config.relay_idle_timeout_secs = Some(600);
+        assert_eq!(RelayOptions::from_config(&config).idle_timeout, Some(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn test_backend_selection_follows_mode_and_order() {
+        let mut config = PerformanceConfig::default();
+        let uring = relay_backend_available(RelayBackend::Uring);
+        let splice = relay_backend_available(RelayBackend::Splice);
+        assert_eq!(splice, cfg!(target_os = "linux"));
+        let fallback = if splice { RelayBackend::Splice } else { RelayBackend::Buffered };
+        // 默认顺序 splice 在前，uring 仍属实验性
+        let preferred = if splice {
+            RelayBackend::Splice
+        } else if uring {
+            RelayBackend::Uring
+        } else {
+            RelayBackend::Buffered
+        };
+        assert_eq!(select_relay_backend(&config), preferred);
+        assert_eq!(available_relay_backends().last(), Some(&RelayBackend::Buffered));
+
+        config.relay_backend_order = vec![RelayBackend::Uring, RelayBackend::Splice, RelayBackend::Buffered];
+        assert_eq!(select_relay_backend(&config), if uring { RelayBackend::Uring } else { fallback });
+
+        config.relay_backend_order = vec![RelayBackend::Buffered, RelayBackend::Uring];
+        assert_eq!(select_relay_backend(&config), RelayBackend::Buffered);
+        config.relay_backend_order.clear();
+        assert_eq!(select_relay_backend(&config), RelayBackend::Buffered);
+
+        config.relay_backend = RelayBackendMode::Uring;
+        assert_eq!(select_relay_backend(&config), if uring { RelayBackend::Uring } else { RelayBackend::Buffered });
+        config.relay_backend = RelayBackendMode::Splice;
+        assert_eq!(select_relay_backend(&config), fallback);
+        config.relay_backend = RelayBackendMode::Buffered;
+        config.relay_backend_order = vec![RelayBackend::Uring];
+        assert_eq!(select_relay_backend(&config), RelayBackend::Buffered);
+        assert_eq!(RelayOptions::from_config(&config).backend, RelayBackend::Buffered);
+    }
+
+    /// Reader with every chunk ready at once, one chunk per read
+    struct ChunkedReader {
+        chunks: std::collections::VecDeque<&'static [u8]>,
+    }
+
+    impl AsyncRead for ChunkedReader {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<IoResult<()>> {
+            if let Some(chunk) = self.chunks.pop_front() {
+                buf.put_slice(chunk);
+            }
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Writer that records the size of every write
+    #[derive(Default)]
+    struct CountingWriter {
+        writes: Vec<usize>,
+    }
+
+    impl AsyncWrite for CountingWriter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<IoResult<usize>> {
+            self.writes.push(buf.len());
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<IoResult<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<IoResult<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_latency_mode_skips_coalescing() {
+        let writes = |latency_mode: bool| async move {
+            let source = ChunkedReader { chunks: [&b"ls\n"[..], b"pwd\n", b"id\n"].into() };
+            let mut dest = CountingWriter::default();
+            let options = RelayOptions { latency_mode, ..RelayOptions::default() };
+            relay_one_way(source, &mut dest, options).await.unwrap();
+            dest.writes
+        };
+        // 默认把已就绪的三段合并成一次写，低延迟模式每次读都单独写出
+        assert_eq!(writes(false).await, [10]);
+        assert_eq!(writes(true).await, [3, 4, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_latency_mode_sets_nodelay_on_both_ends() {
+        use std::os::fd::{AsRawFd, BorrowedFd};
+
+        let nodelay = |fd| socket2::SockRef::from(&unsafe { BorrowedFd::borrow_raw(fd) }).nodelay().unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        for latency_mode in [false, true] {
+            let (_client, client_side) = tcp_pair(&listener).await;
+            let (_target, target_side) = tcp_pair(&listener).await;
+            let fds = [client_side.as_raw_fd(), target_side.as_raw_fd()];
+            let options = RelayOptions { latency_mode, ..RelayOptions::default() };
+            let relay = ZeroCopyRelay::with_options(client_side, target_side, options);
+            assert_eq!(fds.map(nodelay), [latency_mode; 2]);
+            drop(relay);
+        }
+    }
+}
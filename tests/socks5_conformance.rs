@@ -0,0 +1,306 @@
+// SOCKS5服务端协议一致性：按字节脚本驱动真实的连接处理函数
+#![cfg(feature = "runtime")]
+use anybls::config::Config;
+use anybls::inbound::InboundContext;
+use anybls::loadgen::spawn_echo_server;
+use anybls::outbound::OutboundManager;
+use anybls::protocol::{Address, Socks5Response};
+use anybls::proxy::Socks5Proxy;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// How long a case may take before the server task is considered stuck
+const CASE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One step of a scripted exchange, from the client's point of view
+enum Step {
+    /// Write bytes in one go
+    Send(Vec<u8>),
+    /// Write bytes one at a time, flushing and yielding between them
+    Trickle(Vec<u8>),
+    /// Read exactly these bytes
+    Expect(Vec<u8>),
+    /// The server closes the connection without sending anything more
+    ExpectEof,
+    /// Close the client side entirely
+    Close,
+}
+
+use Step::*;
+
+struct Case {
+    name: &'static str,
+    steps: Vec<Step>,
+}
+
+fn context() -> InboundContext {
+    let config = Config::default();
+    let outbounds = OutboundManager::from_configs(&config.outbounds).unwrap();
+    InboundContext::new(Box::leak(Box::new(config)), Arc::new(outbounds))
+}
+
+/// CONNECT request bytes for an address family
+fn connect(atyp: u8, addr: &[u8], port: u16) -> Vec<u8> {
+    let mut request = vec![0x05, 0x01, 0x00, atyp];
+    request.extend_from_slice(addr);
+    request.extend_from_slice(&port.to_be_bytes());
+    request
+}
+
+/// Reply carrying the bound address the server reports for an IPv4 target
+fn reply_v4(rep: u8, ip: [u8; 4], port: u16) -> Vec<u8> {
+    Socks5Response::new(rep, Address::V4(ip.into()), port).to_bytes().unwrap().to_vec()
+}
+
+/// Failure replies carry the unspecified IPv4 address
+fn failure(rep: u8) -> Vec<u8> {
+    reply_v4(rep, [0, 0, 0, 0], 0)
+}
+
+/// Run one case against a freshly accepted connection and check the server
+/// task finishes and leaves no tasks behind
+async fn run_case(context: InboundContext, case: Case) {
+    let metrics = tokio::runtime::Handle::current().metrics();
+    let baseline = metrics.num_alive_tasks();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = listener.local_addr().unwrap();
+    let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+    let (stream, client_addr) = listener.accept().await.unwrap();
+    let server = tokio::spawn(Socks5Proxy::handle_connection(stream, client_addr, context));
+
+    for step in case.steps {
+        match step {
+            Send(bytes) => client.write_all(&bytes).await.unwrap(),
+            Trickle(bytes) => {
+                for byte in bytes {
+                    client.write_all(&[byte]).await.unwrap();
+                    client.flush().await.unwrap();
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            }
+            Expect(expected) => {
+                let mut actual = vec![0u8; expected.len()];
+                tokio::time::timeout(CASE_TIMEOUT, client.read_exact(&mut actual))
+                    .await
+                    .unwrap_or_else(|_| panic!("{}: timed out waiting for {:02x?}", case.name, expected))
+                    .unwrap_or_else(|e| panic!("{}: read failed: {}", case.name, e));
+                assert_eq!(actual, expected, "{}", case.name);
+            }
+            ExpectEof => {
+                let mut rest = Vec::new();
+                let read = tokio::time::timeout(CASE_TIMEOUT, client.read_to_end(&mut rest))
+                    .await
+                    .unwrap_or_else(|_| panic!("{}: server kept the connection open", case.name));
+                // 服务端关闭时若还有未读的请求字节，内核会发RST而不是FIN
+                if let Err(e) = read {
+                    assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset, "{}", case.name);
+                }
+                assert!(rest.is_empty(), "{}: unexpected trailing bytes {:02x?}", case.name, rest);
+            }
+            Close => break,
+        }
+    }
+    // 出错路径服务端已先关闭；其余情况由客户端结束会话
+    drop(client);
+
+    // 错误路径返回Err是预期行为，这里只要求任务结束且未panic
+    let _outcome = tokio::time::timeout(CASE_TIMEOUT, server)
+        .await
+        .unwrap_or_else(|_| panic!("{}: server task did not terminate", case.name))
+        .unwrap_or_else(|e| panic!("{}: server task panicked: {}", case.name, e));
+
+    // 中继等派生任务也须随连接结束
+    let deadline = tokio::time::Instant::now() + CASE_TIMEOUT;
+    while metrics.num_alive_tasks() > baseline {
+        assert!(tokio::time::Instant::now() < deadline, "{}: leaked tasks", case.name);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+}
+
+#[tokio::test]
+async fn socks5_conformance() {
+    let context = context();
+    let echo = spawn_echo_server().await.unwrap();
+    let echo_port = echo.port();
+    let closed_port = {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    };
+    let ipv6_echo = match TcpListener::bind("[::1]:0").await {
+        Ok(listener) => {
+            let addr = listener.local_addr().unwrap();
+            drop(listener);
+            Some(addr)
+        }
+        Err(_) => None,
+    };
+
+    let greeting = || Send(vec![0x05, 0x01, 0x00]);
+    let no_auth = || Expect(vec![0x05, 0x00]);
+    let connect_echo = connect(0x01, &[127, 0, 0, 1], echo_port);
+
+    let mut cases = vec![
+        Case {
+            name: "greeting with several methods picks no-auth",
+            steps: vec![
+                Send(vec![0x05, 0x03, 0x02, 0x01, 0x00]),
+                no_auth(),
+                Close,
+            ],
+        },
+        Case {
+            name: "greeting offering only GSSAPI is refused",
+            steps: vec![Send(vec![0x05, 0x01, 0x01]), Expect(vec![0x05, 0xFF]), ExpectEof],
+        },
+        Case {
+            name: "greeting with zero methods is refused",
+            steps: vec![Send(vec![0x05, 0x00]), Expect(vec![0x05, 0xFF]), ExpectEof],
+        },
+        Case {
+            name: "nmethods larger than the methods sent waits, then ends on close",
+            steps: vec![Send(vec![0x05, 0xFF, 0x00, 0x01, 0x02]), Close],
+        },
+        Case {
+            name: "SOCKS4 greeting is dropped without a reply",
+            steps: vec![Send(vec![0x04, 0x01, 0x00, 0x50]), ExpectEof],
+        },
+        Case {
+            name: "CONNECT IPv4",
+            steps: vec![
+                greeting(),
+                no_auth(),
+                Send(connect_echo.clone()),
+                Expect(reply_v4(0x00, [127, 0, 0, 1], echo_port)),
+                Send(b"ping".to_vec()),
+                Expect(b"ping".to_vec()),
+            ],
+        },
+        Case {
+            name: "CONNECT domain carrying an IP literal",
+            steps: vec![
+                greeting(),
+                no_auth(),
+                Send(connect(0x03, b"\x09127.0.0.1", echo_port)),
+                Expect(reply_v4(0x00, [127, 0, 0, 1], echo_port)),
+                Send(b"ping".to_vec()),
+                Expect(b"ping".to_vec()),
+            ],
+        },
+        Case {
+            name: "CONNECT to a closed port reports connection refused",
+            steps: vec![
+                greeting(),
+                no_auth(),
+                Send(connect(0x01, &[127, 0, 0, 1], closed_port)),
+                Expect(failure(0x05)),
+                ExpectEof,
+            ],
+        },
+        Case {
+            name: "zero-length domain is rejected",
+            steps: vec![greeting(), no_auth(), Send(connect(0x03, &[0x00], 80)), Expect(failure(0x08)), ExpectEof],
+        },
+        Case {
+            name: "unknown ATYP is rejected",
+            steps: vec![greeting(), no_auth(), Send(connect(0x02, &[1, 2, 3, 4], 80)), Expect(failure(0x08)), ExpectEof],
+        },
+        Case {
+            name: "BIND is not supported",
+            steps: vec![
+                greeting(),
+                no_auth(),
+                Send(vec![0x05, 0x02, 0x00, 0x01, 127, 0, 0, 1, 0, 80]),
+                Expect(failure(0x07)),
+                ExpectEof,
+            ],
+        },
+        Case {
+            name: "UDP ASSOCIATE is not supported",
+            steps: vec![
+                greeting(),
+                no_auth(),
+                Send(vec![0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0]),
+                Expect(failure(0x07)),
+                ExpectEof,
+            ],
+        },
+        Case {
+            name: "greeting and request split into single-byte writes",
+            steps: vec![
+                Trickle(vec![0x05, 0x02, 0x02, 0x00]),
+                no_auth(),
+                Trickle(connect_echo.clone()),
+                Expect(reply_v4(0x00, [127, 0, 0, 1], echo_port)),
+                Send(b"pong".to_vec()),
+                Expect(b"pong".to_vec()),
+            ],
+        },
+        Case {
+            name: "greeting and request pipelined in one write",
+            steps: vec![
+                Send([vec![0x05, 0x01, 0x00], connect_echo.clone(), b"data".to_vec()].concat()),
+                no_auth(),
+                Expect(reply_v4(0x00, [127, 0, 0, 1], echo_port)),
+                Expect(b"data".to_vec()),
+            ],
+        },
+        Case { name: "close before greeting", steps: vec![Close] },
+        Case { name: "close mid-greeting", steps: vec![Send(vec![0x05]), Close] },
+        Case { name: "close after greeting", steps: vec![greeting(), no_auth(), Close] },
+        Case {
+            name: "close mid-request",
+            steps: vec![greeting(), no_auth(), Send(connect_echo[..6].to_vec()), Close],
+        },
+        Case {
+            name: "close mid-domain",
+            steps: vec![greeting(), no_auth(), Send(vec![0x05, 0x01, 0x00, 0x03, 0x0b, b'e', b'x']), Close],
+        },
+        Case {
+            name: "close while relaying",
+            steps: vec![
+                greeting(),
+                no_auth(),
+                Send(connect_echo.clone()),
+                Expect(reply_v4(0x00, [127, 0, 0, 1], echo_port)),
+                Close,
+            ],
+        },
+    ];
+
+    if let Some(addr) = ipv6_echo {
+        // 回环IPv6可用时，连接一个已关闭端口验证IPv6地址解析与错误应答
+        let mut ip = [0u8; 16];
+        ip[15] = 1;
+        cases.push(Case {
+            name: "CONNECT IPv6",
+            steps: vec![
+                greeting(),
+                no_auth(),
+                Send(connect(0x04, &ip, addr.port())),
+                Expect(failure(0x05)),
+                ExpectEof,
+            ],
+        });
+    }
+
+    for case in cases {
+        run_case(context.clone(), case).await;
+    }
+}
+
+#[tokio::test]
+async fn handler_is_callable_directly() {
+    // 直接驱动处理函数：错误通过返回值报告，而不是只写日志
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    let (stream, client_addr) = listener.accept().await.unwrap();
+
+    client.write_all(&[0x05, 0x01, 0x01]).await.unwrap();
+    let result = Socks5Proxy::handle_connection(stream, client_addr, context()).await;
+    assert!(matches!(result, Err(anybls::ProxyError::AuthFailed)));
+}